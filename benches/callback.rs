@@ -0,0 +1,150 @@
+//! Times the stages of an output callback at small block sizes, to tell how much headroom a
+//! buffer size leaves before xruns: the ring buffers, the effect chains applied to the monitor
+//! feed, and the sample format conversions.
+//!
+//! Run with `cargo bench --bench callback`. Each benchmark gives the time a block takes, to
+//! compare with its duration at 48 kHz: 667 µs at 32 frames, 2.67 ms at 128 and 21.3 ms at 1024.
+//! `cargo test --benches` runs every case once instead, as a check that they all still build and
+//! run.
+
+use std::hint::black_box;
+
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use ringbuf::traits::{Consumer, Producer, Split};
+use ringbuf::HeapRb;
+use rust_dsp_experiments::compressor::{Compressor, CompressorSettings};
+use rust_dsp_experiments::dither::{Dither, DitherMode};
+use rust_dsp_experiments::effects::{Biquad, EffectChain, FilterKind, Fir, Gain, BUTTERWORTH_Q};
+use rust_dsp_experiments::mixer::Mixer;
+use rust_dsp_experiments::simd;
+
+const CHANNELS: usize = 2;
+const SAMPLE_RATE: u32 = 48_000;
+
+fn callback(c: &mut Criterion) {
+    let mut group = c.benchmark_group("callback");
+    for frames in [32, 128, 1024] {
+        group.throughput(Throughput::Elements(frames as u64));
+        let source: Vec<f32> = (0..frames * CHANNELS)
+            .map(|x| (x as f32 * 0.01).sin() * 0.5)
+            .collect();
+
+        let (mut producer, mut consumer) = HeapRb::<f32>::new(frames * CHANNELS * 4).split();
+        bench(&mut group, "ring: push + pop", &source, |data| {
+            producer.push_slice(data);
+            consumer.pop_slice(data);
+        });
+        let (mut producer, consumer) = HeapRb::<f32>::new(frames * CHANNELS * 4).split();
+        let mut mixer = Mixer::new(vec![(consumer, 1.0)]);
+        mixer.prepare(frames * CHANNELS);
+        bench(&mut group, "ring: push + mix", &source, |data| {
+            producer.push_slice(data);
+            mixer.mix(data, |_| {});
+        });
+
+        let mut chain: EffectChain = EffectChain::new(CHANNELS);
+        bench(&mut group, "chain: empty", &source, |data| {
+            chain.process(data)
+        });
+        let mut chain = gain_and_eq();
+        bench(&mut group, "chain: gain + EQ", &source, |data| {
+            chain.process(data)
+        });
+        let mut chain = gain_and_eq();
+        let mut compressor = compressor();
+        bench(
+            &mut group,
+            "chain: gain + EQ + compressor",
+            &source,
+            |data| {
+                chain.process(data);
+                compressor.process(data, None);
+            },
+        );
+        let mut chain = convolution();
+        bench(&mut group, "chain: 1 s convolution", &source, |data| {
+            chain.process(data)
+        });
+
+        let mut integers = vec![0i16; source.len()];
+        bench(&mut group, "f32 to i16", &source, |data| {
+            simd::f32_to_i16(data, &mut integers)
+        });
+        let mut dither = Dither::new(DitherMode::Tpdf, CHANNELS);
+        bench(&mut group, "f32 to i16, dithered", &source, |data| {
+            dither.convert(data, &mut integers)
+        });
+        let mut dither = Dither::new(DitherMode::Shaped, CHANNELS);
+        bench(&mut group, "f32 to i16, noise shaped", &source, |data| {
+            dither.convert(data, &mut integers)
+        });
+        bench(&mut group, "i16 to f32", &source, |data| {
+            simd::i16_to_f32(&integers, data)
+        });
+    }
+    group.finish();
+}
+
+fn gain_and_eq() -> EffectChain {
+    let rate = SAMPLE_RATE as f32;
+    let mut chain = EffectChain::new(CHANNELS);
+    chain.push(Biquad::new(
+        FilterKind::HighPass,
+        80.0,
+        BUTTERWORTH_Q,
+        rate,
+        CHANNELS,
+    ));
+    chain.push(Biquad::new(
+        FilterKind::LowPass,
+        12_000.0,
+        BUTTERWORTH_Q,
+        rate,
+        CHANNELS,
+    ));
+    chain.push(Gain::new(-6.0));
+    chain
+}
+
+fn compressor() -> Compressor {
+    let settings = CompressorSettings {
+        threshold_db: -20.0,
+        ratio: 4.0,
+        attack_ms: 5.0,
+        release_ms: 100.0,
+    };
+    Compressor::new(settings, CHANNELS, SAMPLE_RATE)
+}
+
+/// A chain convolving with a decaying impulse response of 1 s, as of a room.
+fn convolution() -> EffectChain {
+    let taps: Vec<f32> = (0..SAMPLE_RATE)
+        .map(|n| (-(n as f32) / 8_000.0).exp() * if n % 7 == 0 { 0.01 } else { -0.005 })
+        .collect();
+    let mut chain = EffectChain::new(CHANNELS);
+    chain.push(Fir::new(&[taps.clone(), taps], CHANNELS));
+    chain
+}
+
+/// Times `process` on copies of `source`, so that repeated processing doesn't decay the signal.
+fn bench(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    source: &[f32],
+    mut process: impl FnMut(&mut [f32]),
+) {
+    let mut data = source.to_vec();
+    let id = BenchmarkId::new(name, source.len() / CHANNELS);
+    group.bench_function(id, |b| {
+        b.iter(|| {
+            data.copy_from_slice(source);
+            process(black_box(&mut data));
+        })
+    });
+}
+
+criterion_group!(benches, callback);
+criterion_main!(benches);
@@ -0,0 +1,84 @@
+//! Compares the effect chain, which deinterleaves blocks into dasp frame slices, with the plain
+//! interleaved loops it replaced, and the f32 chain with the f64 one.
+//!
+//! Run with `cargo bench --bench chain`, and with `-- --save-baseline <name>` then
+//! `-- --baseline <name>` to compare a change against a saved run.
+
+use std::hint::black_box;
+
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
+use rust_dsp_experiments::effects::{Biquad, EffectChain, FilterKind, Gain, BUTTERWORTH_Q};
+use rust_dsp_experiments::level;
+use rust_dsp_experiments::sample::Sample;
+
+const CHANNELS: usize = 2;
+const SAMPLE_RATE: f32 = 48_000.0;
+
+fn chain(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chain");
+    for frames in [32, 128, 1024] {
+        let source: Vec<f32> = (0..frames * CHANNELS)
+            .map(|x| (x as f32 * 0.01).sin())
+            .collect();
+        group.throughput(Throughput::Elements(source.len() as u64));
+
+        let gain = level::db_to_gain(-6.0);
+        bench(&mut group, "interleaved gain", &source, |data| {
+            for sample in data.iter_mut() {
+                *sample *= gain;
+            }
+        });
+
+        let mut chain: EffectChain = EffectChain::new(CHANNELS);
+        chain.push(Gain::new(-6.0));
+        bench(&mut group, "gain", &source, |data| chain.process(data));
+
+        let mut chain = high_pass_and_gain::<f32>();
+        bench(&mut group, "high-pass + gain", &source, |data| {
+            chain.process(data)
+        });
+
+        let mut chain = high_pass_and_gain::<f64>();
+        bench(&mut group, "f64 high-pass + gain", &source, |data| {
+            chain.process(data)
+        });
+    }
+    group.finish();
+}
+
+fn high_pass_and_gain<S: Sample>() -> EffectChain<S> {
+    let mut chain = EffectChain::new(CHANNELS);
+    let kind = FilterKind::HighPass;
+    chain.push(Biquad::new(
+        kind,
+        80.0,
+        BUTTERWORTH_Q,
+        SAMPLE_RATE,
+        CHANNELS,
+    ));
+    chain.push(Gain::new(-6.0));
+    chain
+}
+
+/// Times `process` on copies of `source`, so that repeated processing doesn't decay the signal.
+fn bench(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    source: &[f32],
+    mut process: impl FnMut(&mut [f32]),
+) {
+    let mut data = source.to_vec();
+    let id = BenchmarkId::new(name, source.len() / CHANNELS);
+    group.bench_function(id, |b| {
+        b.iter(|| {
+            data.copy_from_slice(source);
+            process(black_box(&mut data));
+        })
+    });
+}
+
+criterion_group!(benches, chain);
+criterion_main!(benches);
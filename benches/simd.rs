@@ -0,0 +1,94 @@
+//! Compares the vectorised kernels with their scalar reference versions.
+//!
+//! Run with `cargo bench --bench simd`. Each kernel is a group with a "scalar" and a "simd" run
+//! at every size. With `--no-default-features` both run the scalar versions.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_dsp_experiments::simd::{self, scalar};
+
+type Kernel<'a> = &'a dyn Fn(&mut [f32], &[f32], &mut [i16]);
+
+fn kernels(c: &mut Criterion) {
+    for samples in [128, 1024] {
+        let source: Vec<f32> = (0..samples).map(|x| (x as f32 * 0.01).sin()).collect();
+        let integers: Vec<i16> = source.iter().map(|&x| (x * 32_767.0) as i16).collect();
+
+        let kernels: [(&str, Kernel, Kernel); 8] = [
+            (
+                "gain",
+                &|data, _, _| scalar::apply_gain(data, -1.0),
+                &|data, _, _| simd::apply_gain(data, -1.0),
+            ),
+            (
+                "add scaled",
+                &|data, source, _| scalar::add_scaled(data, source, 1e-9),
+                &|data, source, _| simd::add_scaled(data, source, 1e-9),
+            ),
+            (
+                "dry/wet",
+                &|data, source, _| scalar::dry_wet(data, source, 0.5, 0.5),
+                &|data, source, _| simd::dry_wet(data, source, 0.5, 0.5),
+            ),
+            (
+                "peak",
+                &|data, _, _| {
+                    black_box(scalar::peak(data));
+                },
+                &|data, _, _| {
+                    black_box(simd::peak(data));
+                },
+            ),
+            (
+                "sum of squares",
+                &|data, _, _| {
+                    black_box(scalar::sum_of_squares(data));
+                },
+                &|data, _, _| {
+                    black_box(simd::sum_of_squares(data));
+                },
+            ),
+            (
+                "dot",
+                &|data, source, _| {
+                    black_box(scalar::dot(data, source));
+                },
+                &|data, source, _| {
+                    black_box(simd::dot(data, source));
+                },
+            ),
+            (
+                "f32 to i16",
+                &|data, _, integers| scalar::f32_to_i16(data, integers),
+                &|data, _, integers| simd::f32_to_i16(data, integers),
+            ),
+            (
+                "i16 to f32",
+                &|data, _, integers| scalar::i16_to_f32(integers, data),
+                &|data, _, integers| simd::i16_to_f32(integers, data),
+            ),
+        ];
+        for (name, scalar, vectorised) in kernels {
+            let mut group = c.benchmark_group(name);
+            group.throughput(Throughput::Elements(samples as u64));
+            for (version, kernel) in [("scalar", scalar), ("simd", vectorised)] {
+                let mut data = source.clone();
+                let mut integers = integers.clone();
+                group.bench_function(BenchmarkId::new(version, samples), |b| {
+                    b.iter(|| {
+                        kernel(
+                            black_box(&mut data),
+                            black_box(&source),
+                            black_box(&mut integers),
+                        )
+                    })
+                });
+            }
+            group.finish();
+        }
+    }
+}
+
+criterion_group!(benches, kernels);
+criterion_main!(benches);
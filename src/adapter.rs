@@ -0,0 +1,315 @@
+//! Adaptation of interleaved sample streams between two stream configurations.
+//!
+//! The input callbacks convert their blocks to the channel count and sample rate of each output
+//! before pushing them into that output's ring buffer, so the output callbacks only ever see
+//! samples in their own format.
+
+use cpal::StreamConfig;
+
+use crate::binaural::Binaural;
+use crate::downmix::Downmix;
+use crate::routing::Router;
+
+/// Maps frames of `from` channels to frames of `to` channels.
+///
+/// Channels present on both sides are copied. Extra output channels repeat the input channels
+/// cyclically (so mono goes to every output channel and stereo to each pair), and when
+/// downmixing to mono, all input channels are averaged. Other extra input channels are dropped.
+#[derive(Clone, Copy)]
+pub struct ChannelAdapter {
+    from: usize,
+    to: usize,
+}
+
+impl ChannelAdapter {
+    pub fn new(from: usize, to: usize) -> Self {
+        ChannelAdapter { from, to }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.from == self.to
+    }
+
+    /// Appends the frames of `input` mapped to the output channel count to `output`.
+    pub fn process(&self, input: &[f32], output: &mut Vec<f32>) {
+        for frame in input.chunks_exact(self.from) {
+            if self.to == 1 {
+                output.push(frame.iter().sum::<f32>() / self.from as f32);
+            } else {
+                output.extend((0..self.to).map(|channel| frame[channel % self.from]));
+            }
+        }
+    }
+}
+
+/// Converts an interleaved stream between two sample rates by linear interpolation.
+///
+/// The resampler is streaming: the last frame of each block is kept so that interpolation is
+/// continuous across blocks of any size.
+pub struct Resampler {
+    channels: usize,
+    /// Input frames advanced per output frame.
+    step: f64,
+    /// Position of the next output frame, in input frames relative to the start of the next
+    /// block. The last frame of the previous block is at -1.
+    position: f64,
+    previous: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, from_rate: u32, to_rate: u32) -> Self {
+        Resampler {
+            channels,
+            step: from_rate as f64 / to_rate as f64,
+            position: 0.0,
+            previous: vec![0.0; channels],
+        }
+    }
+
+    /// Changes the input frames advanced per output frame, e.g. to follow the drift between two
+    /// clocks.
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.step = ratio;
+    }
+
+    /// Appends the resampled frames of `input` to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let frames = input.len() / self.channels;
+        if frames == 0 {
+            return;
+        }
+        let frame = |index: isize| -> &[f32] {
+            if index < 0 {
+                &self.previous
+            } else {
+                let start = index as usize * self.channels;
+                &input[start..start + self.channels]
+            }
+        };
+
+        while self.position < (frames - 1) as f64 {
+            let index = self.position.floor();
+            let fraction = (self.position - index) as f32;
+            let (a, b) = (frame(index as isize), frame(index as isize + 1));
+            output.extend(a.iter().zip(b).map(|(a, b)| a + (b - a) * fraction));
+            self.position += self.step;
+        }
+
+        self.position -= frames as f64;
+        let last = (frames - 1) * self.channels;
+        self.previous
+            .copy_from_slice(&input[last..last + self.channels]);
+    }
+}
+
+/// Converts blocks from an input stream's configuration to an output stream's configuration.
+pub struct Converter {
+    /// Mixes the input down to stereo first, which `channels` then maps.
+    downmix: Option<Downmix>,
+    /// Or renders it binaurally to stereo.
+    binaural: Option<Binaural>,
+    downmixed: Vec<f32>,
+    channels: ChannelAdapter,
+    /// Swaps the routing of the channels during the run.
+    router: Option<Router>,
+    resampler: Option<Resampler>,
+    mapped: Vec<f32>,
+    resampled: Vec<f32>,
+}
+
+impl Converter {
+    pub fn new(input: &StreamConfig, output: &StreamConfig) -> Self {
+        let resampler = (input.sample_rate != output.sample_rate).then(|| {
+            Resampler::new(
+                output.channels as usize,
+                input.sample_rate.0,
+                output.sample_rate.0,
+            )
+        });
+        Converter {
+            downmix: None,
+            binaural: None,
+            downmixed: Vec::new(),
+            channels: ChannelAdapter::new(input.channels as usize, output.channels as usize),
+            router: None,
+            resampler,
+            mapped: Vec::new(),
+            resampled: Vec::new(),
+        }
+    }
+
+    /// Mixes the input down to stereo with `downmix` before mapping it to the output channels.
+    pub fn downmixing(mut self, downmix: Downmix) -> Self {
+        self.channels = ChannelAdapter::new(2, self.channels.to);
+        self.downmix = Some(downmix);
+        self
+    }
+
+    /// Renders the input binaurally with `binaural` before mapping it to the output channels.
+    pub fn rendering_binaural(mut self, binaural: Binaural) -> Self {
+        self.channels = ChannelAdapter::new(2, self.channels.to);
+        self.binaural = Some(binaural);
+        self
+    }
+
+    /// Routes the input channels with `router` whenever its routing isn't the normal one.
+    pub fn routing(mut self, router: Router) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    /// Converts from `input` Hz to `output` Hz from now on, e.g. once a device changed its rate.
+    pub fn set_rates(&mut self, input: u32, output: u32) {
+        self.resampler = (input != output).then(|| Resampler::new(self.channels.to, input, output));
+    }
+
+    /// Whether the conversion changes anything at all.
+    pub fn is_identity(&self) -> bool {
+        self.downmix.is_none()
+            && self.binaural.is_none()
+            && self.channels.is_identity()
+            && self.router.is_none()
+            && self.resampler.is_none()
+    }
+
+    /// Converts `input`, returning the converted samples.
+    ///
+    /// The internal buffers only grow when a larger block than ever before comes in, so after
+    /// the first few callbacks this doesn't allocate.
+    pub fn process<'a>(&'a mut self, input: &'a [f32]) -> &'a [f32] {
+        let mut data = input;
+        if let Some(downmix) = &self.downmix {
+            self.downmixed.clear();
+            downmix.process(data, &mut self.downmixed);
+            data = &self.downmixed;
+        } else if let Some(binaural) = &mut self.binaural {
+            self.downmixed.clear();
+            binaural.process(data, &mut self.downmixed);
+            data = &self.downmixed;
+        }
+        if !self.channels.is_identity() {
+            self.mapped.clear();
+            self.channels.process(data, &mut self.mapped);
+            data = &self.mapped;
+        }
+        if let Some(router) = &mut self.router {
+            data = router.process(input, data);
+        }
+        if let Some(resampler) = &mut self.resampler {
+            self.resampled.clear();
+            resampler.process(data, &mut self.resampled);
+            data = &self.resampled;
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cpal::SampleRate;
+
+    fn config(channels: u16, sample_rate: u32) -> StreamConfig {
+        StreamConfig {
+            channels,
+            sample_rate: SampleRate(sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        }
+    }
+
+    fn map(from: usize, to: usize, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        ChannelAdapter::new(from, to).process(input, &mut output);
+        output
+    }
+
+    #[test]
+    fn channels_repeat_cyclically_and_average_down_to_mono() {
+        assert_eq!(map(1, 2, &[0.5, -0.5]), [0.5, 0.5, -0.5, -0.5]);
+        assert_eq!(map(2, 4, &[0.1, 0.2]), [0.1, 0.2, 0.1, 0.2]);
+        assert_eq!(map(2, 1, &[1.0, 0.5, -1.0, 0.0]), [0.75, -0.5]);
+        // Extra input channels are dropped.
+        assert_eq!(map(4, 2, &[0.1, 0.2, 0.3, 0.4]), [0.1, 0.2]);
+    }
+
+    /// Resamples a ramp from 48 kHz to `rate` in blocks of `block` frames.
+    fn resample_ramp(rate: u32, block: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(1, 48_000, rate);
+        let ramp: Vec<f32> = (0..480).map(|x| x as f32).collect();
+        let mut output = Vec::new();
+        for chunk in ramp.chunks(block) {
+            resampler.process(chunk, &mut output);
+        }
+        output
+    }
+
+    #[test]
+    fn resampling_interpolates_between_frames() {
+        let doubled = resample_ramp(96_000, 480);
+        assert!((doubled.len() as i64 - 960).abs() <= 2, "{}", doubled.len());
+        // The first frame comes from the silence before the stream, the others from the ramp.
+        for (i, pair) in doubled[1..].windows(2).enumerate() {
+            assert!((pair[1] - pair[0] - 0.5).abs() < 1e-3, "{} {:?}", i, pair);
+        }
+        let halved = resample_ramp(24_000, 480);
+        assert!((halved.len() as i64 - 240).abs() <= 1, "{}", halved.len());
+    }
+
+    #[test]
+    fn resampling_doesnt_depend_on_the_block_size() {
+        for rate in [44_100, 96_000] {
+            let whole = resample_ramp(rate, 480);
+            for block in [1, 7, 64] {
+                assert_eq!(resample_ramp(rate, block), whole, "{} Hz, {}", rate, block);
+            }
+        }
+    }
+
+    #[test]
+    fn conversions_map_the_channels_then_resample() {
+        let mut converter = Converter::new(&config(1, 48_000), &config(2, 96_000));
+        assert!(!converter.is_identity());
+        let output = converter.process(&[0.5; 48]).to_vec();
+        assert!(
+            (output.len() as i64 - 2 * 95).abs() <= 4,
+            "{}",
+            output.len()
+        );
+        assert!(output[4..].iter().all(|x| *x == 0.5));
+
+        let mut same = Converter::new(&config(2, 48_000), &config(2, 48_000));
+        assert!(same.is_identity());
+        assert_eq!(same.process(&[0.1, 0.2]), [0.1, 0.2]);
+    }
+
+    #[test]
+    fn multichannel_input_is_mixed_down_before_mapping() {
+        let downmix = crate::downmix::Downmix::equal(6);
+        let mut converter =
+            Converter::new(&config(6, 48_000), &config(4, 48_000)).downmixing(downmix);
+        assert!(!converter.is_identity());
+        let output = converter.process(&[0.6, 0.6, 0.6, 0.6, 0.6, 0.6]);
+        assert_eq!(output.len(), 4);
+        assert!(
+            output.iter().all(|x| (x - 0.6).abs() < 1e-6),
+            "{:?}",
+            output
+        );
+    }
+
+    #[test]
+    fn binaural_rendering_replaces_the_channel_mapping() {
+        use crate::binaural::{Binaural, HrirSet};
+
+        let binaural = Binaural::new(&HrirSet::Model, 1, 48_000).unwrap();
+        let mut converter =
+            Converter::new(&config(1, 48_000), &config(2, 48_000)).rendering_binaural(binaural);
+        assert!(!converter.is_identity());
+        let output = converter.process(&[0.5; 1_024]).to_vec();
+        assert_eq!(output.len(), 2_048);
+        // A speaker in front reaches both ears alike.
+        assert!(output.chunks(2).all(|x| x[0] == x[1]));
+        assert!(output.iter().any(|x| *x != 0.0));
+    }
+}
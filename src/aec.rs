@@ -0,0 +1,342 @@
+//! Acoustic echo cancellation of the playback picked up by the input.
+//!
+//! When the output plays through speakers and the input is a microphone in the same room, the
+//! input picks up what was played. The first output sends its final signal back to the first
+//! input's callback as a reference, through a ring buffer, converted to a mono stream at the
+//! input's rate. An adaptive [`Nlms`] filter per input channel models the path from the speakers
+//! to the microphone, and its estimate of the echo is subtracted from the input before it goes
+//! anywhere else.
+//!
+//! While someone is talking into the microphone, the input holds more than the echo, and adapting
+//! to it would make the filter diverge. A Geigel double-talk detector freezes adaptation whenever
+//! the input is louder than the echo could be, and for a short while after.
+//!
+//! When the input changes its rate, the filters keep the echo path they learned, resampled to
+//! the new rate like the taps of a FIR filter.
+
+use std::sync::Arc;
+
+use ringbuf::traits::{Consumer, Observer};
+use ringbuf::HeapCons;
+
+use crate::effects::resample_taps;
+use crate::simd;
+use crate::stats::AtomicF32;
+
+/// Length of the modelled echo path.
+pub const ECHO_PATH_SECONDS: f32 = 0.25;
+/// Adaptation step of the filters, between 0 and 2.
+const STEP: f32 = 0.5;
+/// The input counts as double-talk when louder than this share of the recent reference peak,
+/// which assumes the echo path attenuates by at least 6 dB.
+const GEIGEL_THRESHOLD: f32 = 0.5;
+/// How long adaptation stays frozen after double-talk, in seconds.
+const HANGOVER_SECONDS: f32 = 0.03;
+/// Reference peak under which there's nothing to learn from.
+const SILENCE: f32 = 1e-4;
+/// Time constant of the power averages from which the ERLE is computed, in seconds.
+const ERLE_SMOOTHING_SECONDS: f32 = 0.5;
+/// Reference the ring may hold beyond the current block before skipping ahead, in seconds.
+const MAX_LEAD_SECONDS: f32 = 0.05;
+
+/// A normalised least-mean-squares adaptive FIR filter.
+///
+/// It estimates a signal from the recent samples of a reference, and adapts its taps towards
+/// the actual signal by a step normalised by the energy of the reference. It doesn't allocate
+/// once created.
+pub struct Nlms {
+    weights: Vec<f32>,
+    /// The reference history written twice in a row, so that the taps' window of it is always
+    /// contiguous, newest first, at `position`.
+    history: Vec<f32>,
+    position: usize,
+    /// Energy of the reference in the window.
+    energy: f32,
+    step: f32,
+    regularization: f32,
+}
+
+impl Nlms {
+    /// Creates a filter of `length` taps, all zero.
+    pub fn new(length: usize, step: f32) -> Self {
+        assert!(length > 0, "NLMS filters need at least one tap");
+        Nlms {
+            weights: vec![0.0; length],
+            history: vec![0.0; length * 2],
+            position: 0,
+            energy: 0.0,
+            step,
+            // Keeps the step bounded while the reference is nearly silent.
+            regularization: length as f32 * 1e-6,
+        }
+    }
+
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    /// A filter of `length` taps, at `to` Hz, modelling the same path as this one at `from`
+    /// Hz, with an empty history.
+    pub fn resampled(&self, length: usize, from: u32, to: u32) -> Self {
+        let mut filter = Nlms::new(length, self.step);
+        let weights = resample_taps(&self.weights, from, to);
+        let taken = weights.len().min(length);
+        filter.weights[..taken].copy_from_slice(&weights[..taken]);
+        filter
+    }
+
+    /// Pushes the next reference sample, returning the estimate of the signal.
+    #[inline]
+    pub fn push(&mut self, reference: f32) -> f32 {
+        let length = self.weights.len();
+        self.position = match self.position {
+            0 => length - 1,
+            position => position - 1,
+        };
+        let oldest = self.history[self.position + length];
+        self.history[self.position] = reference;
+        self.history[self.position + length] = reference;
+        if self.position == 0 {
+            // Recomputed once per turn, so that rounding errors don't pile up.
+            self.energy = simd::sum_of_squares(&self.history[..length]);
+        } else {
+            self.energy = (self.energy + reference * reference - oldest * oldest).max(0.0);
+        }
+        simd::dot(&self.weights, self.window())
+    }
+
+    /// Adapts the taps to `error`, the signal minus the estimate [`push`](Self::push) returned.
+    #[inline]
+    pub fn adapt(&mut self, error: f32) {
+        let gain = self.step * error / (self.energy + self.regularization);
+        let length = self.weights.len();
+        let window = &self.history[self.position..self.position + length];
+        simd::add_scaled(&mut self.weights, window, gain);
+    }
+
+    fn window(&self) -> &[f32] {
+        &self.history[self.position..self.position + self.weights.len()]
+    }
+}
+
+/// Cancels the echo of the reference in the blocks of an input stream.
+pub struct EchoCanceller {
+    channels: usize,
+    sample_rate: u32,
+    filters: Vec<Nlms>,
+    reference: HeapCons<f32>,
+    /// The reference of the current block.
+    block: Vec<f32>,
+    max_lead: usize,
+    /// Recent peak of the reference, for double-talk detection.
+    peak: f32,
+    peak_decay: f32,
+    /// Frames left during which adaptation stays frozen.
+    frozen: usize,
+    hangover: usize,
+    /// Average powers of the input and of what's left of it, while adapting.
+    input_power: f32,
+    residual_power: f32,
+    smoothing: f32,
+    erle: Arc<AtomicF32>,
+}
+
+impl EchoCanceller {
+    /// Creates a canceller for an input of `channels` channels at `sample_rate`, taking the
+    /// reference from `reference`, and publishing its echo return loss enhancement to `erle`.
+    pub fn new(
+        channels: usize,
+        sample_rate: u32,
+        reference: HeapCons<f32>,
+        erle: Arc<AtomicF32>,
+    ) -> Self {
+        let length = (ECHO_PATH_SECONDS * sample_rate as f32) as usize;
+        let mut canceller = EchoCanceller {
+            channels,
+            sample_rate,
+            filters: (0..channels).map(|_| Nlms::new(length, STEP)).collect(),
+            reference,
+            block: Vec::new(),
+            max_lead: 0,
+            peak: 0.0,
+            peak_decay: 0.0,
+            frozen: 0,
+            hangover: 0,
+            input_power: 0.0,
+            residual_power: 0.0,
+            smoothing: 0.0,
+            erle,
+        };
+        canceller.derive();
+        canceller
+    }
+
+    /// Derives the times in frames, and the decays per frame, for the rate of the input.
+    fn derive(&mut self) {
+        let sample_rate = self.sample_rate as f32;
+        let length = (ECHO_PATH_SECONDS * sample_rate) as usize;
+        self.max_lead = (MAX_LEAD_SECONDS * sample_rate) as usize;
+        // The peak decays over the length of the echo path.
+        self.peak_decay = (-1.0 / length as f32).exp();
+        self.hangover = (HANGOVER_SECONDS * sample_rate) as usize;
+        self.smoothing = 1.0 - (-1.0 / (ERLE_SMOOTHING_SECONDS * sample_rate)).exp();
+    }
+
+    /// Cancels the echo of an input at `sample_rate` from now on, with the echo path learned so
+    /// far resampled to it. Allocates the filters again.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let length = (ECHO_PATH_SECONDS * sample_rate as f32) as usize;
+        for filter in &mut self.filters {
+            *filter = filter.resampled(length, self.sample_rate, sample_rate);
+        }
+        self.sample_rate = sample_rate;
+        self.derive();
+        self.frozen = 0;
+    }
+
+    /// Allocates what blocks of up to `max_frames` need, so that the callback doesn't. The frames
+    /// of a larger block past `max_frames` are left as they are.
+    pub fn prepare(&mut self, max_frames: usize) {
+        if self.block.len() < max_frames {
+            self.block.resize(max_frames, 0.0);
+        }
+    }
+
+    /// Removes the echo from the interleaved block `data` in place.
+    pub fn process(&mut self, data: &mut [f32]) {
+        let frames = (data.len() / self.channels).min(self.block.len());
+        let block = &mut self.block[..frames];
+        // Missing reference is silence, e.g. before the output starts.
+        let popped = self.reference.pop_slice(block);
+        block[popped..].fill(0.0);
+        let lead = self.reference.occupied_len();
+        if lead > self.max_lead {
+            self.reference.skip(lead);
+        }
+
+        for (frame, &reference) in data.chunks_exact_mut(self.channels).zip(block.iter()) {
+            self.peak = reference.abs().max(self.peak * self.peak_decay);
+            if frame.iter().any(|x| x.abs() > GEIGEL_THRESHOLD * self.peak) {
+                self.frozen = self.hangover;
+            }
+            let adapting = self.frozen == 0 && self.peak > SILENCE;
+            self.frozen = self.frozen.saturating_sub(1);
+            for (x, filter) in frame.iter_mut().zip(&mut self.filters) {
+                let error = *x - filter.push(reference);
+                if adapting {
+                    filter.adapt(error);
+                    self.input_power += self.smoothing * (*x * *x - self.input_power);
+                    self.residual_power += self.smoothing * (error * error - self.residual_power);
+                }
+                *x = error;
+            }
+        }
+        let erle = 10.0 * ((self.input_power + 1e-20) / (self.residual_power + 1e-20)).log10();
+        self.erle.store(erle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ringbuf::traits::{Producer, Split};
+    use ringbuf::HeapRb;
+
+    /// Runs `canceller` over `frames` of noise picked up through a path that delays it by
+    /// `delay` frames, returning the power of what's left over the last tenth.
+    fn cancel(
+        canceller: &mut EchoCanceller,
+        producer: &mut impl Producer<Item = f32>,
+        frames: usize,
+        delay: usize,
+    ) -> f32 {
+        let mut state = 0x9e37_79b9u32;
+        let mut reference = vec![0.0f32; delay];
+        let mut residual = 0.0;
+        for block in 0..frames / 256 {
+            let noise: Vec<f32> = (0..256)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+                })
+                .collect();
+            producer.push_slice(&noise);
+            reference.extend(noise.iter().map(|x| x * 0.25));
+            let mut input: Vec<f32> = reference.drain(..256).collect();
+            canceller.process(&mut input);
+            if block >= frames / 256 * 9 / 10 {
+                residual += input.iter().map(|x| x * x).sum::<f32>();
+            }
+        }
+        residual / (frames / 10) as f32
+    }
+
+    #[test]
+    fn the_filter_learns_the_path_it_models() {
+        let path = [0.0, 0.5, 0.0, -0.25];
+        let mut filter = Nlms::new(8, STEP);
+        let mut history = [0.0f32; 4];
+        let mut state = 0x1234_5678u32;
+        for _ in 0..4_000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let reference = (state >> 8) as f32 / (1 << 24) as f32 - 0.5;
+            history.rotate_right(1);
+            history[0] = reference;
+            let signal: f32 = path.iter().zip(&history).map(|(a, b)| a * b).sum();
+            let estimate = filter.push(reference);
+            filter.adapt(signal - estimate);
+        }
+        for (i, weight) in filter.weights().iter().enumerate() {
+            let expected = path.get(i).copied().unwrap_or(0.0);
+            assert!((weight - expected).abs() < 1e-4, "{}: {}", i, weight);
+        }
+    }
+
+    #[test]
+    fn the_echo_is_cancelled_and_the_erle_published() {
+        let (mut producer, consumer) = HeapRb::<f32>::new(48_000).split();
+        let erle = Arc::new(AtomicF32::default());
+        let mut canceller = EchoCanceller::new(1, 8_000, consumer, erle.clone());
+        canceller.prepare(256);
+        let residual = cancel(&mut canceller, &mut producer, 16_000, 40);
+        assert!(residual < 1e-4, "{}", residual);
+        assert!(erle.load() > 20.0, "{}", erle.load());
+    }
+
+    #[test]
+    fn double_talk_freezes_adaptation() {
+        let (mut producer, consumer) = HeapRb::<f32>::new(48_000).split();
+        let mut canceller = EchoCanceller::new(1, 8_000, consumer, Arc::default());
+        canceller.prepare(256);
+        producer.push_slice(&[0.1; 256]);
+        // Someone talking, far louder than the echo of the reference could be.
+        let mut input = vec![0.8; 256];
+        canceller.process(&mut input);
+        assert!(canceller.filters[0].weights().iter().all(|x| *x == 0.0));
+        assert_eq!(canceller.frozen, canceller.hangover - 1);
+    }
+
+    #[test]
+    fn the_echo_path_learned_follows_a_change_of_rate() {
+        let (mut producer, consumer) = HeapRb::<f32>::new(48_000).split();
+        let erle = Arc::new(AtomicF32::default());
+        let mut canceller = EchoCanceller::new(1, 8_000, consumer, erle.clone());
+        canceller.prepare(256);
+        let residual = cancel(&mut canceller, &mut producer, 16_000, 40);
+        assert!(residual < 1e-4, "{}", residual);
+
+        canceller.set_sample_rate(16_000);
+        let weights = canceller.filters[0].weights();
+        assert_eq!(weights.len(), 4_000);
+        let peak = (0..weights.len())
+            .max_by(|a, b| weights[*a].abs().total_cmp(&weights[*b].abs()))
+            .unwrap();
+        assert_eq!(peak, 80);
+        assert_eq!(canceller.hangover, 480);
+    }
+}
@@ -0,0 +1,315 @@
+//! Band solo: listening to a narrow band of the monitor feed alone, e.g. to hunt a resonance.
+//!
+//! While the band is soloed, each output plays its mix through a band-pass, the [`Biquad`] of the
+//! chain's filters, instead of through the chain, and then through the limiter of the channel
+//! strip if it has one, so that a loud band still can't jump out. The chain keeps running
+//! underneath, and switching in and out crossfades over [`CROSSFADE`], so neither clicks.
+//!
+//! It's a mode of the engine rather than an effect of the chain: the band isn't a parameter, so
+//! automation, LFOs and presets never see it, and a preset saved while soloing can't bring it back.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Biquad, Effect, FilterKind, Limiter, LimiterSpec, ScratchArena};
+use crate::stats::AtomicF32;
+
+/// How long soloing a band, or going back, takes.
+pub const CROSSFADE: Duration = Duration::from_millis(50);
+/// How far a sweep moves the center, in octaves.
+pub const SWEEP_OCTAVES: f32 = 1.0 / 6.0;
+/// How much narrowing or widening the band multiplies or divides its Q by.
+pub const Q_STEP: f32 = std::f32::consts::SQRT_2;
+
+const MIN_CENTER: f32 = 20.0;
+const MAX_CENTER: f32 = 20_000.0;
+const MIN_Q: f32 = 0.3;
+const MAX_Q: f32 = 30.0;
+
+/// A band, `<center-Hz>[:<Q>]` on the command line, e.g. "450:1.0", with a Q of 1 by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    pub center: f32,
+    pub q: f32,
+}
+
+impl FromStr for Band {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (center, q) = s.split_once(':').unwrap_or((s, "1"));
+        let center = center
+            .parse::<f32>()
+            .ok()
+            .filter(|x| (MIN_CENTER..=MAX_CENTER).contains(x))
+            .ok_or_else(|| {
+                format!(
+                    "invalid band center \"{}\", expected {} to {} Hz",
+                    center, MIN_CENTER, MAX_CENTER
+                )
+            })?;
+        let q = q
+            .parse::<f32>()
+            .ok()
+            .filter(|x| (MIN_Q..=MAX_Q).contains(x))
+            .ok_or_else(|| format!("invalid band Q \"{}\", expected {} to {}", q, MIN_Q, MAX_Q))?;
+        Ok(Band { center, q })
+    }
+}
+
+/// Whether a band is soloed and which, shared between the controls and the outputs.
+pub struct BandSolo {
+    on: AtomicBool,
+    center: AtomicF32,
+    q: AtomicF32,
+}
+
+impl Default for BandSolo {
+    fn default() -> Self {
+        BandSolo {
+            on: AtomicBool::new(false),
+            center: AtomicF32::new(1_000.0),
+            q: AtomicF32::new(1.0),
+        }
+    }
+}
+
+impl BandSolo {
+    /// Solos `band`, or moves the soloed band to it.
+    pub fn solo(&self, band: Band) {
+        self.center.store(band.center);
+        self.q.store(band.q);
+        self.on.store(true, Ordering::Relaxed);
+    }
+
+    /// Goes back to normal monitoring, keeping the band for the next solo.
+    pub fn off(&self) {
+        self.on.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::Relaxed)
+    }
+
+    pub fn band(&self) -> Band {
+        Band {
+            center: self.center.load(),
+            q: self.q.load(),
+        }
+    }
+
+    /// Moves the center by `octaves`, up or down, multiplies the Q by `factor`, narrowing the
+    /// band above 1, and solos the band, returning it.
+    pub fn nudge(&self, octaves: f32, factor: f32) -> Band {
+        let band = self.band();
+        let band = Band {
+            center: (band.center * octaves.exp2()).clamp(MIN_CENTER, MAX_CENTER),
+            q: (band.q * factor).clamp(MIN_Q, MAX_Q),
+        };
+        self.solo(band);
+        band
+    }
+
+    /// The field of the status line, e.g. "band: 450 Hz Q 1.0".
+    pub fn describe(&self) -> String {
+        match self.is_on() {
+            true => format!("band: {}", describe(self.band())),
+            false => "band: off".to_string(),
+        }
+    }
+}
+
+/// A band for people, e.g. "450 Hz Q 1.0".
+pub fn describe(band: Band) -> String {
+    format!("{:.0} Hz Q {:.1}", band.center, band.q)
+}
+
+/// The band solo of one output, around its chain.
+pub struct Audition {
+    shared: Arc<BandSolo>,
+    sample_rate: f32,
+    filter: Biquad,
+    limiter: Option<Limiter>,
+    /// The band being soloed, which the filter glides to.
+    band: Band,
+    buffer: AudioBuffer,
+    scratch: ScratchArena,
+    /// The mix, before the chain.
+    dry: Vec<f32>,
+    /// How much of the output is the band, from 0 to 1.
+    mix: f32,
+    /// Change of `mix` per frame while fading.
+    step: f32,
+}
+
+impl Audition {
+    /// Creates the band solo of an output of `channels` channels at `sample_rate`, ending with a
+    /// limiter of `limiter` if given.
+    pub fn new(
+        shared: Arc<BandSolo>,
+        channels: usize,
+        sample_rate: u32,
+        limiter: Option<LimiterSpec>,
+    ) -> Self {
+        let sample_rate = sample_rate as f32;
+        let band = shared.band();
+        let center = band.center.min(sample_rate * 0.45);
+        Audition {
+            shared,
+            sample_rate,
+            filter: Biquad::new(FilterKind::BandPass, center, band.q, sample_rate, channels),
+            limiter: limiter.map(|x| Limiter::new(x, sample_rate)),
+            band,
+            buffer: AudioBuffer::new(channels, 0),
+            scratch: ScratchArena::new(0),
+            dry: Vec::new(),
+            mix: 0.0,
+            step: 1.0 / (CROSSFADE.as_secs_f32() * sample_rate),
+        }
+    }
+
+    /// Filters and fades at `sample_rate` from now on.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate as f32;
+        Effect::<f32>::set_sample_rate(&mut self.filter, self.sample_rate);
+        self.filter
+            .set_param(0, self.band.center.min(self.sample_rate * 0.45));
+        if let Some(limiter) = &mut self.limiter {
+            Effect::<f32>::set_sample_rate(limiter, self.sample_rate);
+        }
+        self.step = 1.0 / (CROSSFADE.as_secs_f32() * self.sample_rate);
+    }
+
+    /// Allocates what the band solo needs for blocks of up to `max_frames`. Past them, a larger
+    /// block is the chain's alone.
+    pub fn prepare(&mut self, max_frames: usize) {
+        let channels = self.buffer.channels();
+        if self.buffer.capacity() < max_frames {
+            self.buffer = AudioBuffer::new(channels, max_frames);
+        }
+        if self.dry.len() < max_frames * channels {
+            self.dry.resize(max_frames * channels, 0.0);
+        }
+    }
+
+    /// Runs `chain` over the interleaved block `data` in place, but with the band in its place
+    /// while it's soloed.
+    pub fn process(&mut self, data: &mut [f32], chain: &mut impl FnMut(&mut [f32])) {
+        let on = self.shared.is_on();
+        if self.mix == 0.0 && !on {
+            chain(data);
+            return;
+        }
+        let band = self.shared.band();
+        if band != self.band {
+            self.band = band;
+            self.filter
+                .set_param(0, band.center.min(self.sample_rate * 0.45));
+            self.filter.set_param(1, band.q);
+        }
+        let length = data.len().min(self.dry.len());
+        let dry = &mut self.dry[..length];
+        dry.copy_from_slice(&data[..length]);
+        chain(data);
+        self.buffer.deinterleave(dry);
+        self.filter.process(&mut self.buffer, &mut self.scratch);
+        if let Some(limiter) = &mut self.limiter {
+            Effect::<f32>::process(limiter, &mut self.buffer, &mut self.scratch);
+        }
+        self.buffer.interleave(dry);
+        let channels = self.buffer.channels();
+        let step = if on { self.step } else { -self.step };
+        for (out, band) in data
+            .chunks_exact_mut(channels)
+            .zip(dry.chunks_exact(channels))
+        {
+            self.mix = (self.mix + step).clamp(0.0, 1.0);
+            for (x, &y) in out.iter_mut().zip(band) {
+                *x += (y - *x) * self.mix;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn rms(x: &[f32]) -> f32 {
+        (x.iter().map(|x| x * x).sum::<f32>() / x.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn bands_parse_with_a_q_of_one_by_default() {
+        let band = |center, q| Band { center, q };
+        assert_eq!("450".parse(), Ok(band(450.0, 1.0)));
+        assert_eq!("450:4".parse(), Ok(band(450.0, 4.0)));
+        assert_eq!(
+            "10".parse::<Band>(),
+            Err("invalid band center \"10\", expected 20 to 20000 Hz".to_string())
+        );
+        assert_eq!(
+            "450:wide".parse::<Band>(),
+            Err("invalid band Q \"wide\", expected 0.3 to 30".to_string())
+        );
+    }
+
+    #[test]
+    fn nudges_solo_the_band_within_its_limits() {
+        let solo = BandSolo::default();
+        assert_eq!(solo.describe(), "band: off");
+        assert_eq!(
+            solo.nudge(1.0, Q_STEP),
+            Band {
+                center: 2_000.0,
+                q: Q_STEP
+            }
+        );
+        assert!(solo.is_on());
+        assert_eq!(solo.describe(), "band: 2000 Hz Q 1.4");
+        let band = solo.nudge(10.0, 100.0);
+        assert_eq!((band.center, band.q), (MAX_CENTER, MAX_Q));
+        let band = solo.nudge(-20.0, 0.0);
+        assert_eq!((band.center, band.q), (MIN_CENTER, MIN_Q));
+        solo.off();
+        assert_eq!(solo.band(), band);
+        assert_eq!(solo.describe(), "band: off");
+    }
+
+    #[test]
+    fn soloing_crossfades_from_the_chain_to_the_band_and_back() {
+        let shared = Arc::new(BandSolo::default());
+        let mut audition = Audition::new(shared.clone(), 1, SAMPLE_RATE, None);
+        audition.prepare(4_800);
+        let omega = std::f32::consts::TAU * 1_000.0 / SAMPLE_RATE as f32;
+        let sine: Vec<f32> = (0..4_800).map(|n| 0.5 * (omega * n as f32).sin()).collect();
+        // The chain silences everything, so what comes out is the band.
+        let mut chain = |data: &mut [f32]| data.fill(0.0);
+
+        let mut data = sine.clone();
+        audition.process(&mut data, &mut chain);
+        assert!(data.iter().all(|x| *x == 0.0));
+
+        shared.solo(Band {
+            center: 1_000.0,
+            q: 1.0,
+        });
+        let mut data = sine.clone();
+        audition.process(&mut data, &mut chain);
+        // Faded in over the crossfade, then the tone at its center as it is.
+        assert!(data[..100].iter().all(|x| x.abs() < 0.05));
+        let gain = rms(&data[2_400..]) / rms(&sine[2_400..]);
+        assert!((gain - 1.0).abs() < 0.05, "{}", gain);
+
+        shared.off();
+        let mut data = sine.clone();
+        audition.process(&mut data, &mut chain);
+        assert!(rms(&data[..100]) > 0.3);
+        assert!(data[2_400..].iter().all(|x| *x == 0.0));
+    }
+}
@@ -0,0 +1,304 @@
+//! Scripted parameter changes, read from a CSV file of timed moves.
+//!
+//! Each row is `time_seconds,parameter,value`, e.g. `5.0,gain,-12`, with parameters named as in
+//! [`ParamLayout`]. Empty lines and lines starting with `#` are skipped, and so is a header row.
+//! Moves are applied in time order from the start of the streams, by publishing new parameter
+//! snapshots, so the effects glide to the new values as they do for any other change.
+//!
+//! The [`Cursor`] only deals in times, so the same file can drive a stream in real time, as
+//! [`Automation::play`] does, or block by block from a sample count.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::params::{ParamLayout, ParamWriter};
+
+/// A change of one parameter at some time.
+#[derive(Clone, Debug)]
+pub struct Move {
+    /// Seconds from the start of the streams.
+    pub time: f64,
+    /// Position of the parameter in the layout.
+    pub param: usize,
+    pub value: f32,
+}
+
+/// Moves sorted by time.
+#[derive(Debug, Default)]
+pub struct Automation {
+    moves: Vec<Move>,
+}
+
+impl Automation {
+    /// Reads the moves of the file at `path`, resolving the parameters against `layout`.
+    pub fn load(path: &Path, layout: &ParamLayout) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read \"{}\"", path.display()))?;
+        Automation::parse(&text, layout).with_context(|| format!("in \"{}\"", path.display()))
+    }
+
+    /// Reads a preset, a file of `parameter,value` rows such as `gain,-6`, as moves all at time 0.
+    pub fn load_preset(path: &Path, layout: &ParamLayout) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read \"{}\"", path.display()))?;
+        let rows: Vec<String> = text
+            .lines()
+            .map(|line| match line.trim() {
+                row if row.is_empty() || row.starts_with('#') => String::new(),
+                row => format!("0,{}", row),
+            })
+            .collect();
+        Automation::parse(&rows.join("\n"), layout)
+            .with_context(|| format!("in \"{}\"", path.display()))
+    }
+
+    /// Parses the rows of an automation file.
+    ///
+    /// Rows out of time order are sorted, keeping the file order of rows at the same time. All
+    /// unknown parameters are reported together, with the names that exist.
+    pub fn parse(text: &str, layout: &ParamLayout) -> anyhow::Result<Self> {
+        let mut moves = Vec::new();
+        let mut unknown: Vec<&str> = Vec::new();
+        let mut first_row = true;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let [time, param, value] = fields[..] else {
+                bail!(
+                    "line {}: expected `time,parameter,value`, got \"{}\"",
+                    number + 1,
+                    line
+                );
+            };
+            let header = std::mem::replace(&mut first_row, false);
+            let time: f64 = match time.parse() {
+                Ok(time) => time,
+                Err(_) if header => continue,
+                Err(_) => bail!("line {}: invalid time \"{}\"", number + 1, time),
+            };
+            if !(time >= 0.0 && time.is_finite()) {
+                bail!("line {}: invalid time \"{}\"", number + 1, fields[0]);
+            }
+            let value: f32 = value
+                .parse()
+                .map_err(|_| anyhow!("line {}: invalid value \"{}\"", number + 1, value))?;
+            match layout.find(param) {
+                Some(param) => moves.push(Move { time, param, value }),
+                None if !unknown.contains(&param) => unknown.push(param),
+                None => {}
+            }
+        }
+        if !unknown.is_empty() {
+            bail!(
+                "unknown parameters {}; the parameters of the effect chain are {}",
+                quoted(unknown.iter().copied()),
+                match layout.is_empty() {
+                    true => "none".to_string(),
+                    false => quoted(layout.names().iter().map(String::as_str)),
+                }
+            );
+        }
+        moves.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Ok(Automation { moves })
+    }
+
+    pub fn moves(&self) -> &[Move] {
+        &self.moves
+    }
+
+    /// Drops the moves later than `duration` seconds, returning how many were dropped.
+    pub fn truncate(&mut self, duration: f64) -> usize {
+        let count = self.moves.len();
+        self.moves.retain(|x| x.time <= duration);
+        count - self.moves.len()
+    }
+
+    pub fn cursor(&self) -> Cursor<'_> {
+        Cursor {
+            moves: &self.moves,
+            next: 0,
+        }
+    }
+
+    /// Applies the moves in real time from `start` to `start + duration`, or until `stop` is set,
+    /// publishing them to every writer. Moves due at the same time are published together.
+    ///
+    /// `tick` runs between the moves, at least every 50 milliseconds.
+    pub fn play(
+        &self,
+        writers: &Mutex<Vec<ParamWriter<Vec<f32>>>>,
+        start: Instant,
+        duration: Duration,
+        stop: &AtomicBool,
+        mut tick: impl FnMut(),
+    ) {
+        let mut cursor = self.cursor();
+        loop {
+            let moves = cursor.due(start.elapsed().as_secs_f64());
+            if !moves.is_empty() {
+                publish(writers, moves);
+            }
+            tick();
+            let next = cursor
+                .next_time()
+                .map_or(duration, Duration::from_secs_f64)
+                .min(duration);
+            let elapsed = start.elapsed();
+            if elapsed >= duration || stop.load(Ordering::Relaxed) {
+                break;
+            }
+            // Sleep in short steps, so that stopping doesn't wait for the next move.
+            let step = next.saturating_sub(elapsed).min(Duration::from_millis(50));
+            std::thread::sleep(step);
+        }
+    }
+}
+
+/// Publishes `moves` together to every writer.
+pub fn publish(writers: &Mutex<Vec<ParamWriter<Vec<f32>>>>, moves: &[Move]) {
+    for writer in writers.lock().unwrap().iter_mut() {
+        writer.update(|values| {
+            for x in moves {
+                values[x.param] = x.value;
+            }
+        });
+    }
+}
+
+/// Position in an [`Automation`], advanced as time passes.
+pub struct Cursor<'a> {
+    moves: &'a [Move],
+    next: usize,
+}
+
+impl<'a> Cursor<'a> {
+    /// Time of the next move not applied yet.
+    pub fn next_time(&self) -> Option<f64> {
+        self.moves.get(self.next).map(|x| x.time)
+    }
+
+    /// Returns the moves due by `time`, in order, that weren't returned before.
+    pub fn due(&mut self, time: f64) -> &'a [Move] {
+        let start = self.next;
+        while self.next < self.moves.len() && self.moves[self.next].time <= time {
+            self.next += 1;
+        }
+        &self.moves[start..self.next]
+    }
+}
+
+fn quoted<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    names
+        .map(|x| format!("\"{}\"", x))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::params::ParamStore;
+
+    fn layout() -> ParamLayout {
+        let mut layout = ParamLayout::default();
+        layout.add("gain", 0, &["db"]);
+        layout.add("filter", 0, &["freq", "q"]);
+        layout
+    }
+
+    fn times(automation: &Automation) -> Vec<(f64, usize, f32)> {
+        let moves = automation.moves().iter();
+        moves.map(|x| (x.time, x.param, x.value)).collect()
+    }
+
+    #[test]
+    fn rows_are_sorted_by_time_keeping_the_file_order() {
+        let text = "time,parameter,value\n\
+                    # fade out, then open the filter\n\
+                    5.0, gain, -12\n\
+                    \n\
+                    1.5,filter.freq,2000\n\
+                    5.0,filter.q,2\n\
+                    0,gain.0.db,0\n";
+        let automation = Automation::parse(text, &layout()).unwrap();
+        assert_eq!(
+            times(&automation),
+            [
+                (0.0, 0, 0.0),
+                (1.5, 1, 2000.0),
+                (5.0, 0, -12.0),
+                (5.0, 2, 2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_rows_name_their_line() {
+        let err = |text| Automation::parse(text, &layout()).unwrap_err().to_string();
+        assert_eq!(
+            err("1,gain,0\n2,gain"),
+            "line 2: expected `time,parameter,value`, got \"2,gain\""
+        );
+        assert_eq!(
+            err("1,gain,0\nsoon,gain,0"),
+            "line 2: invalid time \"soon\""
+        );
+        assert_eq!(err("-1,gain,0"), "line 1: invalid time \"-1\"");
+        assert_eq!(err("inf,gain,0"), "line 1: invalid time \"inf\"");
+        assert_eq!(err("1,gain,loud"), "line 1: invalid value \"loud\"");
+    }
+
+    #[test]
+    fn unknown_parameters_are_reported_together() {
+        let err = Automation::parse("1,volume,0\n2,delay,1\n3,volume,2", &layout()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown parameters \"volume\", \"delay\"; the parameters of the effect chain are \
+             \"gain.0.db\", \"filter.0.freq\", \"filter.0.q\""
+        );
+        let err = Automation::parse("1,gain,0", &ParamLayout::default()).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("the parameters of the effect chain are none"));
+    }
+
+    #[test]
+    fn cursors_return_each_move_once_when_due() {
+        let text = "1,gain,-1\n2,gain,-2\n2,filter,100\n3,gain,-3";
+        let mut automation = Automation::parse(text, &layout()).unwrap();
+        let mut cursor = automation.cursor();
+        assert!(cursor.due(0.5).is_empty());
+        assert_eq!(cursor.next_time(), Some(1.0));
+        assert_eq!(cursor.due(2.0).len(), 3);
+        assert!(cursor.due(2.5).is_empty());
+        assert_eq!(cursor.due(10.0)[0].value, -3.0);
+        assert_eq!(cursor.next_time(), None);
+
+        assert_eq!(automation.truncate(2.0), 1);
+        assert_eq!(automation.moves().len(), 3);
+    }
+
+    #[test]
+    fn playing_publishes_the_moves_due_and_stops_at_the_end() {
+        let text = "0,gain,-6\n0,filter.q,2\n60,gain,0";
+        let automation = Automation::parse(text, &layout()).unwrap();
+        let (writer, mut reader) = ParamStore::new(vec![0.0, 1_000.0, 0.7]).split();
+        let writers = Mutex::new(vec![writer]);
+        let mut ticks = 0;
+        let duration = Duration::from_millis(120);
+        let stop = AtomicBool::new(false);
+        automation.play(&writers, Instant::now(), duration, &stop, || ticks += 1);
+        // Both moves at 0 came in one snapshot, and the one at 60 s never came.
+        assert_eq!(reader.load().generation(), 1);
+        assert_eq!(reader.load().value(), &[-6.0, 1_000.0, 2.0]);
+        assert!(ticks >= 3, "{}", ticks);
+    }
+}
@@ -0,0 +1,490 @@
+//! Binaural rendering of multichannel inputs for headphones: every channel is convolved with
+//! the left and right ear impulse responses, HRIRs, of a speaker at its nominal angle, and the
+//! ears summed into stereo.
+//!
+//! Angles are azimuths in degrees counterclockwise from the front, as in SOFA files: 30 is the
+//! front left speaker, -30 the front right one, 110 the left surround. The LFE has no angle and
+//! goes to both ears alike.
+//!
+//! The built-in set is a spherical head model rather than measurements: each ear is delayed by
+//! the path around the head to it, after Woodworth, and shadowed by a shelf which cuts the highs
+//! of a far ear and lifts those of a near one, after Brown and Duda. A directory of measured
+//! HRIRs can stand in for it, with a stereo WAV file per angle named after it, e.g. "-110.wav",
+//! holding the left ear in its first channel and the right one in its second.
+
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::convolve::{Convolver, PARTITION};
+use crate::wav;
+
+/// Radius of the head, in metres.
+const HEAD_RADIUS: f64 = 0.0875;
+/// Speed of sound, in metres per second.
+const SPEED_OF_SOUND: f64 = 343.0;
+/// High-frequency gain of the shelf for a sound arriving from straight behind the ear's axis,
+/// where the head shadows it most.
+const SHADOW_MIN_GAIN: f64 = 0.1;
+/// Angle from the ear's axis the shadow is deepest at, in degrees.
+const SHADOW_MIN_ANGLE: f64 = 150.0;
+/// Frames every ear of the model is delayed by, leaving room for the start of the fractional
+/// delay.
+const MODEL_PRE_DELAY: usize = 16;
+/// Taps of the model's HRIRs, which fit in one partition of the convolver.
+const MODEL_TAPS: usize = PARTITION;
+/// Gain of the LFE in both ears, -3 dB.
+const LFE_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// The left and right ear responses to a speaker.
+#[derive(Clone, Debug)]
+pub struct Hrir {
+    pub left: Vec<f32>,
+    pub right: Vec<f32>,
+}
+
+/// Where the HRIRs of each angle come from.
+pub enum HrirSet {
+    /// The spherical head model, at any angle.
+    Model,
+    /// Measured pairs loaded from a directory, all at the same sample rate.
+    Loaded {
+        directory: PathBuf,
+        sample_rate: u32,
+        pairs: Vec<(f32, Hrir)>,
+    },
+}
+
+impl HrirSet {
+    /// Loads every "<azimuth>.wav" file of `directory`.
+    pub fn load(directory: &Path) -> anyhow::Result<Self> {
+        let entries = std::fs::read_dir(directory)
+            .with_context(|| format!("failed to list \"{}\"", directory.display()))?;
+        let mut pairs = Vec::new();
+        let mut sample_rate = None;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some("wav") {
+                continue;
+            }
+            let Some(azimuth) = path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.parse::<f32>().ok())
+                .filter(|x| x.is_finite())
+            else {
+                continue;
+            };
+            let data = wav::read(&path)
+                .with_context(|| format!("failed to read \"{}\"", path.display()))?;
+            if data.channels != 2 {
+                anyhow::bail!(
+                    "\"{}\" has {} channels, expected the left and right ears",
+                    path.display(),
+                    data.channels
+                );
+            }
+            match sample_rate {
+                Some(rate) if rate != data.sample_rate => anyhow::bail!(
+                    "\"{}\" is at {} Hz, unlike the other HRIRs at {} Hz",
+                    path.display(),
+                    data.sample_rate,
+                    rate
+                ),
+                _ => sample_rate = Some(data.sample_rate),
+            }
+            let ear = |channel: usize| data.samples.iter().skip(channel).step_by(2).copied();
+            pairs.push((
+                normalize_azimuth(azimuth),
+                Hrir {
+                    left: ear(0).collect(),
+                    right: ear(1).collect(),
+                },
+            ));
+        }
+        let Some(sample_rate) = sample_rate else {
+            anyhow::bail!(
+                "no HRIRs in \"{}\", expected stereo WAV files named after their azimuths, \
+                 e.g. \"30.wav\"",
+                directory.display()
+            );
+        };
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(HrirSet::Loaded {
+            directory: directory.to_path_buf(),
+            sample_rate,
+            pairs,
+        })
+    }
+
+    /// The pair of a speaker at `azimuth`, for a stream at `sample_rate`.
+    pub fn pair(&self, azimuth: f32, sample_rate: u32) -> anyhow::Result<Hrir> {
+        match self {
+            HrirSet::Model => Ok(model(azimuth, sample_rate)),
+            HrirSet::Loaded {
+                directory,
+                sample_rate: rate,
+                pairs,
+            } => {
+                if *rate != sample_rate {
+                    anyhow::bail!(
+                        "the HRIRs in \"{}\" are at {} Hz, but the input is at {} Hz",
+                        directory.display(),
+                        rate,
+                        sample_rate
+                    );
+                }
+                let azimuth = normalize_azimuth(azimuth);
+                pairs
+                    .iter()
+                    .find(|(x, _)| (x - azimuth).abs() < 0.5)
+                    .map(|(_, pair)| pair.clone())
+                    .ok_or_else(|| {
+                        let angles: Vec<String> =
+                            pairs.iter().map(|(x, _)| format!("{}", x)).collect();
+                        anyhow::anyhow!(
+                            "no HRIR for a speaker at {}° in \"{}\", which has {}",
+                            azimuth,
+                            directory.display(),
+                            angles.join(", ")
+                        )
+                    })
+            }
+        }
+    }
+}
+
+/// `azimuth` in degrees within (-180, 180].
+fn normalize_azimuth(azimuth: f32) -> f32 {
+    let wrapped = azimuth.rem_euclid(360.0);
+    if wrapped > 180.0 {
+        wrapped - 360.0
+    } else {
+        wrapped
+    }
+}
+
+/// The speaker angles of the channels of an input, in the WAVE channel order, with `None` for
+/// the LFE, or an error for numbers of channels of no known layout.
+pub fn speaker_azimuths(channels: usize) -> Result<Vec<Option<f32>>, String> {
+    let azimuths: &[Option<f32>] = match channels {
+        1 => &[Some(0.0)],
+        2 => &[Some(30.0), Some(-30.0)],
+        // Quad.
+        4 => &[Some(45.0), Some(-45.0), Some(135.0), Some(-135.0)],
+        // 5.0.
+        5 => &[
+            Some(30.0),
+            Some(-30.0),
+            Some(0.0),
+            Some(110.0),
+            Some(-110.0),
+        ],
+        // 5.1.
+        6 => &[
+            Some(30.0),
+            Some(-30.0),
+            Some(0.0),
+            None,
+            Some(110.0),
+            Some(-110.0),
+        ],
+        // 7.1, with the backs before the sides.
+        8 => &[
+            Some(30.0),
+            Some(-30.0),
+            Some(0.0),
+            None,
+            Some(150.0),
+            Some(-150.0),
+            Some(90.0),
+            Some(-90.0),
+        ],
+        _ => {
+            return Err(format!(
+                "no speaker layout of {} channels to render binaurally, expected 1, 2, \
+                 quad, 5.0, 5.1 or 7.1",
+                channels
+            ))
+        }
+    };
+    Ok(azimuths.to_vec())
+}
+
+/// The model's pair for a speaker at `azimuth`.
+pub fn model(azimuth: f32, sample_rate: u32) -> Hrir {
+    let azimuth = (azimuth as f64).to_radians();
+    // The ears face either side, at 90 and -90 degrees.
+    let incidence = |ear: f64| {
+        let angle = (azimuth - ear).rem_euclid(2.0 * PI);
+        angle.min(2.0 * PI - angle)
+    };
+    Hrir {
+        left: model_ear(incidence(PI / 2.0), sample_rate as f64),
+        right: model_ear(incidence(-PI / 2.0), sample_rate as f64),
+    }
+}
+
+/// The response of an ear to a sound arriving `incidence` radians from the ear's axis.
+fn model_ear(incidence: f64, rate: f64) -> Vec<f32> {
+    let radius = HEAD_RADIUS / SPEED_OF_SOUND;
+    // Around the head to the ear: straight for the near half, then along the surface.
+    let delay = match incidence < PI / 2.0 {
+        true => radius * (1.0 - incidence.cos()),
+        false => radius * (1.0 + incidence - PI / 2.0),
+    };
+    let delay = MODEL_PRE_DELAY as f64 + delay * rate;
+
+    // A windowed sinc for the fractional delay.
+    let half = MODEL_PRE_DELAY as f64;
+    let impulse = (0..MODEL_TAPS).map(|n| {
+        let t = n as f64 - delay;
+        if t.abs() >= half {
+            return 0.0;
+        }
+        let sinc = if t == 0.0 {
+            1.0
+        } else {
+            (PI * t).sin() / (PI * t)
+        };
+        sinc * (0.5 + 0.5 * (PI * t / half).cos())
+    });
+
+    // The shadow: (alpha s + beta) / (s + beta), with unity gain at DC and alpha in the highs,
+    // by the bilinear transform.
+    let alpha = (1.0 + SHADOW_MIN_GAIN / 2.0)
+        + (1.0 - SHADOW_MIN_GAIN / 2.0) * (incidence / SHADOW_MIN_ANGLE.to_radians() * PI).cos();
+    let beta = 2.0 / radius;
+    let k = 2.0 * rate;
+    let b0 = (alpha * k + beta) / (k + beta);
+    let b1 = (beta - alpha * k) / (k + beta);
+    let a1 = (beta - k) / (k + beta);
+    let (mut x1, mut y1) = (0.0, 0.0);
+    impulse
+        .map(|x| {
+            let y = b0 * x + b1 * x1 - a1 * y1;
+            (x1, y1) = (x, y);
+            y as f32
+        })
+        .collect()
+}
+
+/// Where a channel goes.
+enum Route {
+    /// Through the HRIRs of its speaker, left then right.
+    Speaker(Box<[Convolver; 2]>),
+    /// To both ears at a gain, through a unit impulse to stay aligned with the speakers.
+    Lfe(Box<Convolver>),
+}
+
+/// Renders interleaved frames of a layout to binaural stereo.
+pub struct Binaural {
+    paths: Vec<Route>,
+}
+
+impl Binaural {
+    /// Renders inputs of `channels` at `sample_rate`, from the HRIRs of `set`.
+    pub fn new(set: &HrirSet, channels: usize, sample_rate: u32) -> anyhow::Result<Self> {
+        let azimuths = speaker_azimuths(channels).map_err(anyhow::Error::msg)?;
+        let paths = azimuths
+            .into_iter()
+            .map(|azimuth| match azimuth {
+                Some(azimuth) => {
+                    let pair = set.pair(azimuth, sample_rate)?;
+                    Ok(Route::Speaker(Box::new([
+                        Convolver::new(&pair.left),
+                        Convolver::new(&pair.right),
+                    ])))
+                }
+                None => Ok(Route::Lfe(Box::new(Convolver::new(&[LFE_GAIN])))),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Binaural { paths })
+    }
+
+    /// Channels of the frames it renders.
+    pub fn channels(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Frames the rendering delays its input by, in the convolution alone: the HRIRs add
+    /// their own delays to the ears.
+    pub fn latency(&self) -> usize {
+        PARTITION
+    }
+
+    /// Appends the stereo frames of the interleaved `input` to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        for frame in input.chunks_exact(self.paths.len()) {
+            let (mut left, mut right) = (0.0, 0.0);
+            for (x, path) in frame.iter().zip(&mut self.paths) {
+                match path {
+                    Route::Speaker(ears) => {
+                        left += ears[0].process_sample(*x);
+                        right += ears[1].process_sample(*x);
+                    }
+                    Route::Lfe(convolver) => {
+                        let y = convolver.process_sample(*x);
+                        left += y;
+                        right += y;
+                    }
+                }
+            }
+            output.extend_from_slice(&[left, right]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::wav::WavWriter;
+
+    /// Writes the pairs of `azimuths` to a directory of their own, each a unit impulse in one
+    /// ear, the left one for positive angles.
+    fn directory(name: &str, azimuths: &[&str], sample_rate: u32) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        for azimuth in azimuths {
+            let path = directory.join(format!("{}.wav", azimuth));
+            let mut writer = WavWriter::create(&path, 2, sample_rate).unwrap();
+            let impulse = match azimuth.starts_with('-') {
+                true => [0.0, 1.0, 0.0, 0.0],
+                false => [1.0, 0.0, 0.0, 0.0],
+            };
+            writer.write(&impulse).unwrap();
+            writer.finish().unwrap();
+        }
+        directory
+    }
+
+    fn peak(x: &[f32]) -> usize {
+        (0..x.len())
+            .max_by(|a, b| x[*a].abs().total_cmp(&x[*b].abs()))
+            .unwrap()
+    }
+
+    #[test]
+    fn azimuths_wrap_around_the_head() {
+        assert_eq!(normalize_azimuth(30.0), 30.0);
+        assert_eq!(normalize_azimuth(270.0), -90.0);
+        assert_eq!(normalize_azimuth(-180.0), 180.0);
+        assert_eq!(normalize_azimuth(-390.0), -30.0);
+    }
+
+    #[test]
+    fn known_layouts_have_their_speaker_angles() {
+        assert_eq!(speaker_azimuths(2), Ok(vec![Some(30.0), Some(-30.0)]));
+        let surround = speaker_azimuths(6).unwrap();
+        assert_eq!(surround[3], None);
+        assert_eq!(surround[5], Some(-110.0));
+        assert_eq!(speaker_azimuths(8).unwrap().len(), 8);
+        assert_eq!(
+            speaker_azimuths(3),
+            Err(
+                "no speaker layout of 3 channels to render binaurally, expected 1, 2, quad, \
+                 5.0, 5.1 or 7.1"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn the_model_delays_and_shadows_the_far_ear() {
+        let front = model(0.0, 48_000);
+        assert_eq!(front.left, front.right);
+        assert_eq!(front.left.len(), MODEL_TAPS);
+
+        let left = model(90.0, 48_000);
+        assert_eq!(peak(&left.left), MODEL_PRE_DELAY);
+        // Around half the head, about 0.66 ms.
+        let far = peak(&left.right);
+        assert!(
+            (far as i64 - (MODEL_PRE_DELAY + 31) as i64).abs() <= 1,
+            "{}",
+            far
+        );
+        // Both ears pass the lows alike, and the near one more of the highs.
+        for ear in [&left.left, &left.right] {
+            assert!((ear.iter().sum::<f32>() - 1.0).abs() < 0.01);
+        }
+        let energy = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>();
+        assert!(energy(&left.left) > 4.0 * energy(&left.right));
+
+        let right = model(-90.0, 48_000);
+        assert_eq!(right.left, left.right);
+    }
+
+    #[test]
+    fn measured_pairs_are_loaded_by_their_angles() {
+        let path = directory("hrir-load", &["30", "-30", "390.5"], 48_000);
+        std::fs::write(path.join("notes.txt"), "").unwrap();
+        let set = HrirSet::load(&path).unwrap();
+        let pair = set.pair(-330.0, 48_000).unwrap();
+        assert_eq!(pair.left, [1.0, 0.0]);
+        assert_eq!(pair.right, [0.0, 0.0]);
+        assert!(set.pair(30.3, 48_000).is_ok());
+
+        let error = set.pair(110.0, 48_000).unwrap_err();
+        let expected = format!(
+            "no HRIR for a speaker at 110° in \"{}\", which has -30, 30, 30.5",
+            path.display()
+        );
+        assert_eq!(error.to_string(), expected);
+        let error = set.pair(30.0, 44_100).unwrap_err();
+        let expected = format!(
+            "the HRIRs in \"{}\" are at 48000 Hz, but the input is at 44100 Hz",
+            path.display()
+        );
+        assert_eq!(error.to_string(), expected);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn directories_of_other_files_are_refused() {
+        let path = directory("hrir-empty", &[], 48_000);
+        let error = HrirSet::load(&path).err().unwrap();
+        let expected = format!(
+            "no HRIRs in \"{}\", expected stereo WAV files named after their azimuths, e.g. \
+             \"30.wav\"",
+            path.display()
+        );
+        assert_eq!(error.to_string(), expected);
+
+        let mut writer = WavWriter::create(&path.join("0.wav"), 1, 48_000).unwrap();
+        writer.write(&[1.0]).unwrap();
+        writer.finish().unwrap();
+        let error = HrirSet::load(&path).err().unwrap();
+        let expected = format!(
+            "\"{}\" has 1 channels, expected the left and right ears",
+            path.join("0.wav").display()
+        );
+        assert_eq!(error.to_string(), expected);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn speakers_are_rendered_through_their_pairs() {
+        let path = directory("hrir-render", &["30", "-30"], 48_000);
+        let set = HrirSet::load(&path).unwrap();
+        std::fs::remove_dir_all(&path).unwrap();
+        let mut binaural = Binaural::new(&set, 2, 48_000).unwrap();
+        assert_eq!(binaural.channels(), 2);
+        let input: Vec<f32> = (0..2 * PARTITION)
+            .flat_map(|x| [x as f32, -(x as f32)])
+            .collect();
+        let mut output = Vec::new();
+        binaural.process(&input, &mut output);
+        let latency = binaural.latency() * 2;
+        assert_eq!(output.len(), input.len());
+        assert!(output[..latency].iter().all(|x| *x == 0.0));
+        for (x, y) in output[latency..].iter().zip(&input) {
+            assert!((x - y).abs() < 1e-3, "{} against {}", x, y);
+        }
+
+        let error = Binaural::new(&set, 6, 48_000).err().unwrap();
+        assert!(error.to_string().starts_with("no HRIR for a speaker at 0°"));
+    }
+}
@@ -0,0 +1,197 @@
+//! Deinterleaved audio blocks processed by the effect chain.
+//!
+//! Each channel is stored as its own contiguous slice of mono dasp frames, so effects can use the
+//! `dasp::slice` operations on whole channels instead of striding through interleaved samples.
+//! Interleaved blocks are always f32, and are converted to the buffer's sample type on the way in
+//! and out.
+
+use crate::sample::Sample;
+
+/// Largest block, in frames, that what runs in the callbacks is sized for before they start,
+/// unless the device fixes a larger block size. A larger block only partly goes through.
+pub const MAX_BLOCK_FRAMES: usize = 8_192;
+
+/// A block of audio with one buffer per channel.
+pub struct AudioBuffer<S = f32> {
+    channels: Vec<Vec<S>>,
+    frames: usize,
+}
+
+impl<S: Sample> AudioBuffer<S> {
+    /// Creates a silent buffer that holds up to `max_frames` frames without reallocating.
+    pub fn new(channels: usize, max_frames: usize) -> Self {
+        AudioBuffer {
+            channels: vec![vec![S::default(); max_frames]; channels],
+            frames: 0,
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Frames the buffer holds without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.channels.first().map_or(0, |x| x.len())
+    }
+
+    /// Number of frames in the current block.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn channel(&self, index: usize) -> &[S] {
+        &self.channels[index][..self.frames]
+    }
+
+    pub fn channel_mut(&mut self, index: usize) -> &mut [S] {
+        &mut self.channels[index][..self.frames]
+    }
+
+    /// Highest absolute sample of the block.
+    pub fn peak(&self) -> f32 {
+        (0..self.channels())
+            .flat_map(|x| self.channel(x))
+            .fold(0.0, |peak, x| peak.max(x.to_sample::<f32>().abs()))
+    }
+
+    /// Whether every sample of the block is finite.
+    pub fn is_finite(&self) -> bool {
+        (0..self.channels()).all(|x| S::all_finite(self.channel(x)))
+    }
+
+    pub fn channels_mut(&mut self) -> impl Iterator<Item = &mut [S]> {
+        let frames = self.frames;
+        self.channels.iter_mut().map(move |x| &mut x[..frames])
+    }
+
+    /// Loads an interleaved block, growing the channel buffers if it's larger than any before.
+    pub fn deinterleave(&mut self, data: &[f32]) {
+        self.frames = data.len() / self.channels.len();
+        for buffer in &mut self.channels {
+            if buffer.len() < self.frames {
+                buffer.resize(self.frames, S::default());
+            }
+        }
+        // Fixed-size frames let the compiler vectorise the common layouts.
+        match self.channels.len() {
+            1 => self.deinterleave_frames::<1>(data),
+            2 => self.deinterleave_frames::<2>(data),
+            4 => self.deinterleave_frames::<4>(data),
+            6 => self.deinterleave_frames::<6>(data),
+            8 => self.deinterleave_frames::<8>(data),
+            channels => {
+                for (channel, buffer) in self.channels.iter_mut().enumerate() {
+                    for (index, sample) in buffer[..self.frames].iter_mut().enumerate() {
+                        *sample = S::from_sample(data[index * channels + channel]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Loads a copy of the block in `other`, which has as many channels, growing the channel
+    /// buffers if it's larger than any before.
+    pub fn copy_from(&mut self, other: &AudioBuffer<S>) {
+        self.frames = other.frames;
+        for (buffer, source) in self.channels.iter_mut().zip(&other.channels) {
+            if buffer.len() < self.frames {
+                buffer.resize(self.frames, S::default());
+            }
+            buffer[..self.frames].copy_from_slice(&source[..self.frames]);
+        }
+    }
+
+    /// Writes the current block back to an interleaved slice of the same length.
+    pub fn interleave(&self, data: &mut [f32]) {
+        match self.channels.len() {
+            1 => self.interleave_frames::<1>(data),
+            2 => self.interleave_frames::<2>(data),
+            4 => self.interleave_frames::<4>(data),
+            6 => self.interleave_frames::<6>(data),
+            8 => self.interleave_frames::<8>(data),
+            channels => {
+                for (channel, buffer) in self.channels.iter().enumerate() {
+                    for (index, &sample) in buffer[..self.frames].iter().enumerate() {
+                        data[index * channels + channel] = sample.to_sample();
+                    }
+                }
+            }
+        }
+    }
+
+    fn deinterleave_frames<const N: usize>(&mut self, data: &[f32]) {
+        let (frames, _) = data.as_chunks::<N>();
+        for (channel, buffer) in self.channels.iter_mut().enumerate() {
+            for (sample, frame) in buffer.iter_mut().zip(frames) {
+                *sample = S::from_sample(frame[channel]);
+            }
+        }
+    }
+
+    fn interleave_frames<const N: usize>(&self, data: &mut [f32]) {
+        let (frames, _) = data.as_chunks_mut::<N>();
+        for (channel, buffer) in self.channels.iter().enumerate() {
+            for (frame, &sample) in frames.iter_mut().zip(buffer) {
+                frame[channel] = sample.to_sample();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_come_back_interleaved_as_they_went_in() {
+        for channels in 1..=8 {
+            let data: Vec<f32> = (0..channels * 5).map(|x| x as f32 / 64.0).collect();
+            let mut buffer: AudioBuffer = AudioBuffer::new(channels, 16);
+            buffer.deinterleave(&data);
+            assert_eq!(buffer.frames(), 5);
+            for channel in 0..channels {
+                let expected: Vec<f32> = (0..5).map(|x| data[x * channels + channel]).collect();
+                assert_eq!(buffer.channel(channel), expected, "{} channels", channels);
+            }
+            let mut back = vec![0.0; data.len()];
+            buffer.interleave(&mut back);
+            assert_eq!(back, data, "{} channels", channels);
+        }
+    }
+
+    #[test]
+    fn f64_buffers_round_trip_f32_blocks_exactly() {
+        let data = [0.1, -0.7, 1.0, f32::MIN_POSITIVE];
+        let mut buffer: AudioBuffer<f64> = AudioBuffer::new(2, 2);
+        buffer.deinterleave(&data);
+        assert_eq!(buffer.channel(0), [0.1f32 as f64, 1.0]);
+        let mut back = [0.0; 4];
+        buffer.interleave(&mut back);
+        assert_eq!(back, data);
+    }
+
+    #[test]
+    fn blocks_larger_than_any_before_grow_the_buffer() {
+        let mut buffer: AudioBuffer = AudioBuffer::new(2, 4);
+        assert_eq!(buffer.capacity(), 4);
+        buffer.deinterleave(&[0.5; 20]);
+        assert_eq!((buffer.frames(), buffer.capacity()), (10, 10));
+        // A smaller block only uses part of it.
+        buffer.deinterleave(&[-0.25; 4]);
+        assert_eq!((buffer.frames(), buffer.capacity()), (2, 10));
+        assert_eq!(buffer.channel(1), [-0.25, -0.25]);
+        assert_eq!(buffer.peak(), 0.25);
+    }
+
+    #[test]
+    fn copies_take_the_block_of_the_other_buffer() {
+        let mut source: AudioBuffer = AudioBuffer::new(2, 4);
+        source.deinterleave(&[0.1, 0.2, 0.3, 0.4, 0.5, 0.6]);
+        let mut copy: AudioBuffer = AudioBuffer::new(2, 1);
+        copy.copy_from(&source);
+        assert_eq!(copy.frames(), 3);
+        assert_eq!(copy.channel(0), [0.1, 0.3, 0.5]);
+        assert_eq!(copy.channel(1), [0.2, 0.4, 0.6]);
+    }
+}
@@ -0,0 +1,253 @@
+//! Automatic bypass of an effect too heavy for the machine, so that xruns cost the effect rather
+//! than the whole monitor feed.
+//!
+//! [`BypassPolicy`] decides from the loads of the effects, as their profiler measures them, and
+//! the xrun counters alone. An effect is bypassed once it has taken more than a share of the
+//! block duration for a hold time, with xruns during it. Only one effect goes at a time, the
+//! heaviest, and the next decision waits another hold time, to see whether that was enough.
+//!
+//! Bypassed effects never come back by themselves: the user re-enables them, and each one is
+//! then spared for a grace period that doubles every time, so that an effect the machine can't
+//! run doesn't flap between bypassed and not. A load has to drop well under the share, not just
+//! under it, to count as back to normal, for the same reason.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::profile::ProfileStats;
+use crate::stats::XrunCounters;
+
+/// Share of the share a load has to drop under before its time over it starts again.
+const HYSTERESIS: f32 = 0.8;
+/// How long an effect re-enabled for the first time is spared, in hold times.
+const GRACE_HOLDS: f64 = 10.0;
+
+/// When to bypass an effect.
+#[derive(Clone, Copy, Debug)]
+pub struct BypassSettings {
+    /// Share of the block duration, from 0 to 1.
+    pub share: f32,
+    /// How long an effect has to stay over it, in seconds.
+    pub hold: f64,
+}
+
+#[derive(Clone, Default)]
+struct EffectState {
+    /// Since when the load has been over the share.
+    over_since: Option<f64>,
+    bypassed: bool,
+    /// Until when the effect can't be bypassed, after it was re-enabled.
+    spared_until: f64,
+    enabled: u32,
+}
+
+/// The decisions for the effects of one chain, from their loads and the xruns, polled with the
+/// time.
+pub struct BypassPolicy {
+    settings: BypassSettings,
+    effects: Vec<EffectState>,
+    xruns: usize,
+    /// The last time the xruns went up.
+    xrun_at: Option<f64>,
+    /// Until when no effect is bypassed, after one was.
+    settling_until: f64,
+}
+
+impl BypassPolicy {
+    /// Creates the policy of a chain of `effects` effects, none of them bypassed.
+    pub fn new(settings: BypassSettings, effects: usize) -> Self {
+        BypassPolicy {
+            settings,
+            effects: vec![EffectState::default(); effects],
+            xruns: 0,
+            xrun_at: None,
+            settling_until: 0.0,
+        }
+    }
+
+    /// Takes the `loads` of the effects at `now` seconds, and the `xruns` counted so far,
+    /// returning the effect to bypass, if one should be.
+    pub fn poll(&mut self, now: f64, loads: &[f32], xruns: usize) -> Option<usize> {
+        if xruns > self.xruns {
+            self.xruns = xruns;
+            self.xrun_at = Some(now);
+        }
+        let share = self.settings.share;
+        for (effect, &load) in self.effects.iter_mut().zip(loads) {
+            if load > share {
+                effect.over_since.get_or_insert(now);
+            } else if load < share * HYSTERESIS {
+                effect.over_since = None;
+            }
+        }
+        let hold = self.settings.hold;
+        let xrunning = self.xrun_at.is_some_and(|x| now - x <= hold);
+        if !xrunning || now < self.settling_until {
+            return None;
+        }
+        let (index, _) = self
+            .effects
+            .iter()
+            .zip(loads)
+            .enumerate()
+            .filter(|(_, (effect, _))| {
+                !effect.bypassed
+                    && now >= effect.spared_until
+                    && effect.over_since.is_some_and(|x| now - x >= hold)
+            })
+            .max_by(|(_, (_, a)), (_, (_, b))| a.total_cmp(b))?;
+        self.effects[index].bypassed = true;
+        self.settling_until = now + hold;
+        for effect in &mut self.effects {
+            effect.over_since = None;
+        }
+        Some(index)
+    }
+
+    /// Notes that the user re-enabled the effect at `index` at `now` seconds.
+    pub fn enable(&mut self, index: usize, now: f64) {
+        let effect = &mut self.effects[index];
+        effect.bypassed = false;
+        effect.over_since = None;
+        effect.spared_until =
+            now + self.settings.hold * GRACE_HOLDS * 2f64.powi(effect.enabled as i32);
+        effect.enabled += 1;
+    }
+
+    pub fn is_bypassed(&self, index: usize) -> bool {
+        self.effects[index].bypassed
+    }
+}
+
+/// The policy of the chain of one output, acting on the bypasses of its profile.
+pub struct AutoBypass {
+    stats: Arc<ProfileStats>,
+    xruns: Arc<XrunCounters>,
+    policy: BypassPolicy,
+    start: Instant,
+    loads: Vec<f32>,
+}
+
+impl AutoBypass {
+    pub fn new(
+        settings: BypassSettings,
+        stats: Arc<ProfileStats>,
+        xruns: Arc<XrunCounters>,
+    ) -> Self {
+        let effects = stats.len();
+        AutoBypass {
+            stats,
+            xruns,
+            policy: BypassPolicy::new(settings, effects),
+            start: Instant::now(),
+            loads: vec![0.0; effects],
+        }
+    }
+
+    /// Bypasses an effect if the policy says so, returning its name.
+    pub fn poll(&mut self) -> Option<String> {
+        let now = self.start.elapsed().as_secs_f64();
+        for (index, load) in self.loads.iter_mut().enumerate() {
+            if self.policy.is_bypassed(index) && !self.stats.is_bypassed(index) {
+                self.policy.enable(index, now);
+            }
+            *load = self.stats.load(index);
+        }
+        let xruns = self.xruns.overruns.load(Ordering::Relaxed)
+            + self.xruns.underruns.load(Ordering::Relaxed);
+        let index = self.policy.poll(now, &self.loads, xruns)?;
+        self.stats.bypass(index, true);
+        Some(self.stats.name(index).to_string())
+    }
+
+    pub fn stats(&self) -> &Arc<ProfileStats> {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTINGS: BypassSettings = BypassSettings {
+        share: 0.5,
+        hold: 1.0,
+    };
+
+    /// Polls `policy` every 250 ms from `from` to `to` seconds with the same `loads`, with
+    /// another xrun at every poll if `xrunning`, returning the bypasses, with their times.
+    fn run(
+        policy: &mut BypassPolicy,
+        from: f64,
+        to: f64,
+        loads: &[f32],
+        xrunning: bool,
+    ) -> Vec<(f64, usize)> {
+        let mut bypassed = Vec::new();
+        let mut now = from;
+        while now <= to {
+            let xruns = policy.xruns + xrunning as usize;
+            if let Some(index) = policy.poll(now, loads, xruns) {
+                bypassed.push((now, index));
+            }
+            now += 0.25;
+        }
+        bypassed
+    }
+
+    #[test]
+    fn heavy_effects_stay_without_xruns() {
+        let mut policy = BypassPolicy::new(SETTINGS, 2);
+        assert!(run(&mut policy, 0.0, 5.0, &[0.9, 0.1], false).is_empty());
+    }
+
+    #[test]
+    fn the_heaviest_effect_over_the_share_for_the_hold_goes_first() {
+        let mut policy = BypassPolicy::new(SETTINGS, 3);
+        let loads = [0.6, 0.1, 0.8];
+        assert_eq!(run(&mut policy, 0.0, 0.75, &loads, true), []);
+        assert_eq!(run(&mut policy, 1.0, 1.0, &loads, true), [(1.0, 2)]);
+        assert!(policy.is_bypassed(2));
+        // The next one waits another hold, over which it has to stay over the share again.
+        assert_eq!(run(&mut policy, 1.25, 3.0, &loads, true), [(2.25, 0)]);
+    }
+
+    #[test]
+    fn xruns_only_count_for_a_hold() {
+        let mut policy = BypassPolicy::new(SETTINGS, 1);
+        assert_eq!(run(&mut policy, 0.0, 0.0, &[0.9], true), []);
+        assert_eq!(run(&mut policy, 0.25, 1.0, &[0.9], false), [(1.0, 0)]);
+
+        let mut policy = BypassPolicy::new(SETTINGS, 1);
+        assert_eq!(run(&mut policy, 0.0, 0.0, &[0.1], true), []);
+        assert_eq!(run(&mut policy, 0.25, 5.0, &[0.9], false), []);
+    }
+
+    #[test]
+    fn only_loads_well_under_the_share_start_their_time_again() {
+        let mut policy = BypassPolicy::new(SETTINGS, 1);
+        assert_eq!(run(&mut policy, 0.0, 0.5, &[0.6], true), []);
+        // Just under the share still counts as over it.
+        assert_eq!(run(&mut policy, 0.75, 0.75, &[0.45], true), []);
+        assert_eq!(run(&mut policy, 1.0, 1.0, &[0.6], true), [(1.0, 0)]);
+
+        let mut policy = BypassPolicy::new(SETTINGS, 1);
+        assert_eq!(run(&mut policy, 0.0, 0.5, &[0.6], true), []);
+        assert_eq!(run(&mut policy, 0.75, 0.75, &[0.3], true), []);
+        assert_eq!(run(&mut policy, 1.0, 2.0, &[0.6], true), [(2.0, 0)]);
+    }
+
+    #[test]
+    fn re_enabled_effects_are_spared_twice_as_long_every_time() {
+        let mut policy = BypassPolicy::new(SETTINGS, 1);
+        assert_eq!(run(&mut policy, 0.0, 1.0, &[0.9], true), [(1.0, 0)]);
+        policy.enable(0, 2.0);
+        assert!(!policy.is_bypassed(0));
+        // Spared for ten holds.
+        assert_eq!(run(&mut policy, 2.0, 12.0, &[0.9], true), [(12.0, 0)]);
+        policy.enable(0, 13.0);
+        // Then for twenty.
+        assert_eq!(run(&mut policy, 13.0, 33.0, &[0.9], true), [(33.0, 0)]);
+    }
+}
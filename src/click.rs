@@ -0,0 +1,219 @@
+//! A metronome click mixed into an output, for practising without a DAW.
+//!
+//! Beats are scheduled from the count of frames played, not from the clock, so the click never
+//! drifts: beat `n` of a tempo starts exactly `round(n · 60 · rate / BPM)` frames after the first
+//! one. The tempo can change during the run through a shared value, which the audio thread picks
+//! up at the start of a block. The next beat then comes one beat of the new tempo after the last
+//! one.
+
+use std::f64::consts::TAU;
+use std::sync::Arc;
+
+use crate::level;
+use crate::stats::AtomicF32;
+
+/// Length of a click, in seconds.
+const LENGTH: f64 = 0.03;
+/// Pitch of the clicks, and of the accented first beat of a bar, in Hz.
+const PITCH: f64 = 1_000.0;
+const ACCENT_PITCH: f64 = 1_500.0;
+
+/// A metronome playing into one stream.
+pub struct Click {
+    /// Beats per minute, which the control thread may change.
+    tempo: Arc<AtomicF32>,
+    bpm: f32,
+    sample_rate: f64,
+    beats_per_bar: u64,
+    gain: f32,
+    normal: Vec<f32>,
+    accent: Vec<f32>,
+    /// Frames played so far.
+    frame: u64,
+    /// Frame of the first beat at the current tempo, and how many beats of it were played.
+    origin: u64,
+    beats: u64,
+    /// Beats played since the start, which tells the first beats of the bars.
+    count: u64,
+    /// The click playing, and how far into it.
+    playing: Option<(bool, usize)>,
+}
+
+impl Click {
+    /// Creates a metronome for a stream at `sample_rate`, at the tempo in `tempo` and `level_db`,
+    /// accenting one beat in `beats_per_bar`, or none if it's at most 1.
+    pub fn new(tempo: Arc<AtomicF32>, beats_per_bar: u32, level_db: f32, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f64;
+        let beats_per_bar = beats_per_bar.max(1) as u64;
+        Click {
+            bpm: tempo.load(),
+            tempo,
+            sample_rate,
+            beats_per_bar,
+            gain: level::db_to_gain(level_db),
+            normal: click(PITCH, sample_rate),
+            accent: click(accent_pitch(beats_per_bar), sample_rate),
+            frame: 0,
+            origin: 0,
+            beats: 0,
+            count: 0,
+            playing: None,
+        }
+    }
+
+    /// Plays on at `sample_rate`, counting the beats again from the next frame.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate as f64;
+        self.normal = click(PITCH, self.sample_rate);
+        self.accent = click(accent_pitch(self.beats_per_bar), self.sample_rate);
+        self.origin = self.frame;
+        self.beats = 0;
+        self.playing = None;
+    }
+
+    /// Frame of beat `n` of the current tempo.
+    fn beat_frame(&self, n: u64) -> u64 {
+        self.origin + (n as f64 * 60.0 * self.sample_rate / self.bpm as f64).round() as u64
+    }
+
+    /// Adds the click to the interleaved block `data`, on every channel.
+    pub fn mix_into(&mut self, data: &mut [f32], channels: usize) {
+        let bpm = self.tempo.load();
+        if bpm != self.bpm && bpm > 0.0 {
+            // Restart the count from the last beat, or from now if the new beat is already due.
+            // Before the first beat, it still comes at the origin.
+            let last = self.beats.checked_sub(1).map(|x| self.beat_frame(x));
+            self.bpm = bpm;
+            if let Some(last) = last {
+                self.origin = last;
+                self.beats = 1;
+                if self.beat_frame(1) < self.frame {
+                    self.origin = self.frame;
+                    self.beats = 0;
+                }
+            }
+        }
+        for frame in data.chunks_exact_mut(channels) {
+            if self.frame == self.beat_frame(self.beats) {
+                self.playing = Some((self.count.is_multiple_of(self.beats_per_bar), 0));
+                self.beats += 1;
+                self.count += 1;
+            }
+            if let Some((accent, position)) = &mut self.playing {
+                let click = if *accent { &self.accent } else { &self.normal };
+                let x = click[*position] * self.gain;
+                frame.iter_mut().for_each(|y| *y += x);
+                *position += 1;
+                if *position == click.len() {
+                    self.playing = None;
+                }
+            }
+            self.frame += 1;
+        }
+    }
+}
+
+/// The pitch of the first beats of bars of `beats_per_bar`, which are only accented above 1.
+fn accent_pitch(beats_per_bar: u64) -> f64 {
+    match beats_per_bar > 1 {
+        true => ACCENT_PITCH,
+        false => PITCH,
+    }
+}
+
+/// A click at `pitch`, at `sample_rate`.
+fn click(pitch: f64, sample_rate: f64) -> Vec<f32> {
+    let frames = (LENGTH * sample_rate) as usize;
+    (0..frames)
+        .map(|n| {
+            let t = n as f64 / sample_rate;
+            // A fast exponential decay, silent by the end of the click.
+            ((TAU * pitch * t).sin() * (-t * 8.0 / LENGTH).exp()) as f32
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn metronome(bpm: f32, beats_per_bar: u32) -> (Click, Arc<AtomicF32>) {
+        let tempo = Arc::new(AtomicF32::default());
+        tempo.store(bpm);
+        (
+            Click::new(tempo.clone(), beats_per_bar, 0.0, SAMPLE_RATE),
+            tempo,
+        )
+    }
+
+    /// Plays `frames` frame by frame, returning the frames the beats start on, and whether each
+    /// was accented.
+    fn beats(click: &mut Click, frames: u64) -> Vec<(u64, bool)> {
+        let mut beats = Vec::new();
+        for _ in 0..frames {
+            let frame = click.frame;
+            click.mix_into(&mut [0.0], 1);
+            if let Some((accent, 1)) = click.playing {
+                beats.push((frame, accent));
+            }
+        }
+        beats
+    }
+
+    #[test]
+    fn beats_fall_on_whole_intervals_without_drift() {
+        let (mut click, _) = metronome(120.0, 1);
+        let frames: Vec<u64> = beats(&mut click, 240_000).iter().map(|x| x.0).collect();
+        assert_eq!(frames, (0..10).map(|n| n * 24_000).collect::<Vec<_>>());
+
+        // 137 BPM isn't a whole number of frames, but the beats never wander from the exact
+        // times by more than the rounding.
+        let (mut click, _) = metronome(137.0, 1);
+        let beats = beats(&mut click, 1_000_000);
+        for (n, (frame, _)) in beats.iter().enumerate() {
+            let exact = n as f64 * 60.0 * SAMPLE_RATE as f64 / 137.0;
+            assert!((*frame as f64 - exact).abs() <= 0.5, "{}: {}", n, frame);
+        }
+    }
+
+    #[test]
+    fn the_first_beat_of_every_bar_is_accented() {
+        let (mut click, _) = metronome(240.0, 3);
+        let accents: Vec<bool> = beats(&mut click, 48_000 * 2).iter().map(|x| x.1).collect();
+        assert_eq!(
+            accents,
+            [true, false, false, true, false, false, true, false]
+        );
+        assert_eq!(accent_pitch(1), PITCH);
+    }
+
+    #[test]
+    fn a_new_tempo_counts_from_the_last_beat() {
+        let (mut click, tempo) = metronome(120.0, 1);
+        beats(&mut click, 24_001);
+        tempo.store(60.0);
+        let frames: Vec<u64> = beats(&mut click, 120_000).iter().map(|x| x.0).collect();
+        assert_eq!(frames, [72_000, 120_000]);
+
+        // Unless that beat is already past, when it comes right away.
+        let (mut click, tempo) = metronome(60.0, 1);
+        beats(&mut click, 40_000);
+        tempo.store(240.0);
+        let frames: Vec<u64> = beats(&mut click, 12_001).iter().map(|x| x.0).collect();
+        assert_eq!(frames, [40_000, 52_000]);
+    }
+
+    #[test]
+    fn the_click_is_added_to_every_channel_at_its_level() {
+        let tempo = Arc::new(AtomicF32::default());
+        tempo.store(120.0);
+        let mut click = Click::new(tempo, 1, -6.0, SAMPLE_RATE);
+        let mut data = vec![0.25; 64 * 2];
+        click.mix_into(&mut data, 2);
+        let expected = click.normal[10] * level::db_to_gain(-6.0) + 0.25;
+        assert_eq!((data[20], data[21]), (expected, expected));
+        assert_eq!(click.normal.len(), (LENGTH * SAMPLE_RATE as f64) as usize);
+    }
+}
@@ -0,0 +1,224 @@
+//! Compression of the monitor feed.
+//!
+//! A feed-forward compressor: the detector follows the peak level of a key signal with the attack
+//! and release times, and the signal is turned down by the amount the level goes over the
+//! threshold, divided by the ratio. The key is the signal itself unless a
+//! [sidechain](crate::sidechain) provides another one.
+
+use std::str::FromStr;
+
+use crate::effects::RawEffect;
+use crate::envelope::EnvelopeFollower;
+use crate::level;
+
+/// Compressor settings, `<threshold-dBFS>:<ratio>:<attack-ms>:<release-ms>` on the command line,
+/// e.g. "-20:4:5:100".
+#[derive(Clone, Copy, Debug)]
+pub struct CompressorSettings {
+    pub threshold_db: f32,
+    pub ratio: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl FromStr for CompressorSettings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "expected `<threshold-dBFS>:<ratio>:<attack-ms>:<release-ms>`, got \"{}\"",
+                s
+            )
+        };
+        let values: Vec<f32> = s
+            .split(':')
+            .map(|x| x.parse().ok().filter(|x: &f32| x.is_finite()))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+        let [threshold_db, ratio, attack_ms, release_ms] = values[..] else {
+            return Err(invalid());
+        };
+        if ratio < 1.0 {
+            return Err(format!(
+                "compressor ratio must be at least 1, got {}",
+                ratio
+            ));
+        }
+        if attack_ms < 0.0 || release_ms < 0.0 {
+            return Err(invalid());
+        }
+        Ok(CompressorSettings {
+            threshold_db,
+            ratio,
+            attack_ms,
+            release_ms,
+        })
+    }
+}
+
+/// The static curve of a compressor: how far a level over the threshold is turned down.
+#[derive(Clone, Copy, Debug)]
+pub struct GainComputer {
+    threshold_db: f32,
+    /// Fraction of the level over the threshold that is taken off.
+    slope: f32,
+}
+
+impl GainComputer {
+    pub fn new(threshold_db: f32, ratio: f32) -> Self {
+        GainComputer {
+            threshold_db,
+            slope: 1.0 - 1.0 / ratio,
+        }
+    }
+
+    /// Gain reduction at a level of `level_db`, in dB as a positive number.
+    pub fn reduction_db(&self, level_db: f32) -> f32 {
+        (level_db - self.threshold_db).max(0.0) * self.slope
+    }
+
+    pub fn threshold_db(&self) -> f32 {
+        self.threshold_db
+    }
+
+    pub fn set_threshold_db(&mut self, threshold_db: f32) {
+        self.threshold_db = threshold_db;
+    }
+}
+
+/// Turns a signal down as its key goes over a threshold.
+pub struct Compressor {
+    channels: usize,
+    computer: GainComputer,
+    detector: EnvelopeFollower,
+    sample_rate: u32,
+}
+
+impl Compressor {
+    pub fn new(settings: CompressorSettings, channels: usize, sample_rate: u32) -> Self {
+        Compressor {
+            channels,
+            computer: GainComputer::new(settings.threshold_db, settings.ratio),
+            detector: EnvelopeFollower::new(settings.attack_ms, settings.release_ms, sample_rate),
+            sample_rate,
+        }
+    }
+
+    /// Keeps the attack and release times on a signal now at `sample_rate`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.detector.change_rate(self.sample_rate, sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    /// Compresses the interleaved `data`, keyed by the peaks of its own frames, or by `sidechain`
+    /// over the frames it covers.
+    pub fn process(&mut self, data: &mut [f32], sidechain: Option<&[f32]>) {
+        for (index, frame) in data.chunks_exact_mut(self.channels).enumerate() {
+            let key = match sidechain {
+                Some(key) if index < key.len() => key[index].abs(),
+                Some(_) => break,
+                None => frame.iter().fold(0.0f32, |peak, x| peak.max(x.abs())),
+            };
+            let envelope = self.detector.process(key);
+            let reduction = self.computer.reduction_db(level::gain_to_db(envelope));
+            if reduction <= 0.0 {
+                continue;
+            }
+            let gain = level::db_to_gain(-reduction);
+            for x in frame {
+                *x *= gain;
+            }
+        }
+    }
+
+    /// Current gain reduction, in dB as a positive number.
+    pub fn reduction_db(&self) -> f32 {
+        self.computer
+            .reduction_db(level::gain_to_db(self.detector.value()))
+    }
+}
+
+/// In a chain, e.g. as a stage of a [channel strip](crate::strip), the compressor keys from its
+/// own signal, and its threshold moves with the parameters.
+impl RawEffect for Compressor {
+    fn process_interleaved(&mut self, data: &mut [f32], _channels: usize) {
+        self.process(data, None);
+    }
+
+    fn name(&self) -> &'static str {
+        "compressor"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &["threshold"]
+    }
+
+    fn param(&self, _index: usize) -> f32 {
+        self.computer.threshold_db()
+    }
+
+    fn set_param(&mut self, _index: usize, value: f32) {
+        self.computer.set_threshold_db(value);
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        Compressor::set_sample_rate(self, sample_rate as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_parse_and_reject_ratios_under_one() {
+        let settings: CompressorSettings = "-20:4:5:100".parse().unwrap();
+        assert_eq!(settings.threshold_db, -20.0);
+        assert_eq!(settings.ratio, 4.0);
+        assert_eq!((settings.attack_ms, settings.release_ms), (5.0, 100.0));
+        assert_eq!(
+            "-20:0.5:5:100".parse::<CompressorSettings>().unwrap_err(),
+            "compressor ratio must be at least 1, got 0.5"
+        );
+        for text in ["-20:4:5", "-20:4:-5:100", "-20:4:5:nan"] {
+            assert!(text.parse::<CompressorSettings>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn levels_over_the_threshold_are_divided_by_the_ratio() {
+        let computer = GainComputer::new(-20.0, 4.0);
+        assert_eq!(computer.reduction_db(-30.0), 0.0);
+        assert_eq!(computer.reduction_db(-20.0), 0.0);
+        assert_eq!(computer.reduction_db(-8.0), 9.0);
+        assert_eq!(GainComputer::new(-20.0, 1.0).reduction_db(0.0), 0.0);
+    }
+
+    #[test]
+    fn a_loud_signal_settles_at_the_curve() {
+        let mut compressor = Compressor::new("-20:4:1:100".parse().unwrap(), 2, 48_000);
+        let mut data = vec![0.5; 9_600];
+        compressor.process(&mut data, None);
+        // 0.5 is -6 dBFS, 14 dB over, so it's turned down by three quarters of that.
+        let reduction = (level::gain_to_db(0.5) + 20.0) * 0.75;
+        let expected = 0.5 * level::db_to_gain(-reduction);
+        assert!((data[9_599] - expected).abs() < 1e-4, "{}", data[9_599]);
+        assert!((compressor.reduction_db() - reduction).abs() < 0.01);
+        // A quiet signal goes through.
+        let mut compressor = Compressor::new("-20:4:1:100".parse().unwrap(), 2, 48_000);
+        let mut data = vec![0.05; 960];
+        compressor.process(&mut data, None);
+        assert!(data.iter().all(|x| *x == 0.05));
+    }
+
+    #[test]
+    fn a_sidechain_keys_the_frames_it_covers() {
+        let mut compressor = Compressor::new("-20:4:0:100".parse().unwrap(), 1, 48_000);
+        let mut data = vec![0.05; 4];
+        compressor.process(&mut data, Some(&[1.0, 1.0]));
+        let expected = 0.05 * level::db_to_gain(-15.0);
+        assert!((data[0] - expected).abs() < 1e-5 && (data[1] - expected).abs() < 1e-5);
+        assert_eq!(data[2..], [0.05, 0.05]);
+    }
+}
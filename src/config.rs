@@ -0,0 +1,1028 @@
+//! Negotiation of the stream configuration of each device.
+//!
+//! Input and output streams don't have to share a configuration: each one is chosen from its own
+//! device's supported configurations, and the [`adapter`](crate::adapter) bridges the channel
+//! counts and sample rates around the ring buffers.
+//!
+//! The first input and output are negotiated together by [`negotiate`], which looks for the best
+//! configuration both devices support and only falls back to a config that needs adapting when
+//! there's none. Later devices are fitted to the first ones by [`choose_config`].
+//!
+//! Some devices advertise configurations they then fail to build a stream with, e.g. an f32
+//! default that only opens in i16. A stream that fails to build over its configuration climbs
+//! the [`fallback_ladder`] instead, converting to the configuration negotiated around it.
+
+use std::cmp::Reverse;
+use std::fmt;
+
+use cpal::traits::DeviceTrait;
+use cpal::{
+    BufferSize, SampleFormat, SampleRate, StreamConfig, SupportedBufferSize, SupportedStreamConfig,
+    SupportedStreamConfigRange,
+};
+
+use crate::error::EngineError;
+use crate::events;
+
+/// Sample rate used when neither the other device nor the device's default can be honoured.
+const FALLBACK_SAMPLE_RATE: SampleRate = SampleRate(48_000);
+
+/// What the user asked for, applied to every stream where the device allows it.
+pub struct Preferences {
+    /// Requested buffer size, in frames.
+    pub buffer_size: u32,
+    /// Sample rate every stream must run at, if any.
+    pub sample_rate: Option<SampleRate>,
+    /// Fewest channels the output streams can have, e.g. to feed every way of a crossover.
+    pub output_channels: u16,
+}
+
+/// Sample formats in order of preference. cpal has no 24-bit format: devices of 24 bits take
+/// them in 32-bit containers.
+pub const FORMAT_PREFERENCE: [SampleFormat; 3] =
+    [SampleFormat::F32, SampleFormat::I32, SampleFormat::I16];
+/// Sample rates tried on every device, highest first.
+pub const STANDARD_RATES: [u32; 7] = [192_000, 176_400, 96_000, 88_200, 48_000, 44_100, 32_000];
+
+/// The configurations the input and output streams will be built with.
+pub struct StreamConfigs {
+    pub input: StreamConfig,
+    pub output: StreamConfig,
+    pub input_format: SampleFormat,
+    pub output_format: SampleFormat,
+    /// What has to be converted between the streams, in the order the input's blocks go through.
+    pub adaptations: Vec<Adaptation>,
+}
+
+/// A conversion between the two streams, which a configuration common to both would do without.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Adaptation {
+    /// Samples of a stream, "input" or "output", converted from or to f32.
+    ConvertFormat {
+        stream: &'static str,
+        format: SampleFormat,
+    },
+    MapChannels {
+        from: u16,
+        to: u16,
+    },
+    Resample {
+        from: u32,
+        to: u32,
+    },
+}
+
+impl fmt::Display for Adaptation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Adaptation::ConvertFormat { stream, format } => {
+                write!(f, "converting the {} samples of the {}", format, stream)
+            }
+            Adaptation::MapChannels { from, to } => {
+                write!(f, "mapping {} channels to {}", from, to)
+            }
+            Adaptation::Resample { from, to } => {
+                write!(f, "resampling from {} Hz to {} Hz", from, to)
+            }
+        }
+    }
+}
+
+/// What a device supports.
+pub struct Capabilities<'a> {
+    /// Names the device in errors, e.g. "input device \"USB Audio\"".
+    pub name: &'a str,
+    pub supported: &'a [SupportedStreamConfigRange],
+    pub default: Option<&'a SupportedStreamConfig>,
+}
+
+/// Chooses a configuration for each of the two devices, in the best formats they have by
+/// [`FORMAT_PREFERENCE`]. The streams convert the formats other than f32, which the engine runs
+/// in.
+pub fn negotiate_configs(
+    input_device: &cpal::Device,
+    output_device: &cpal::Device,
+    prefs: &Preferences,
+) -> Result<StreamConfigs, EngineError> {
+    let input_supported: Vec<_> = input_device.supported_input_configs()?.collect();
+    let output_supported: Vec<_> = output_device.supported_output_configs()?.collect();
+    let (input_default, output_default) = (
+        input_device.default_input_config().ok(),
+        output_device.default_output_config().ok(),
+    );
+    let input_name = format!("input device \"{}\"", device_name(input_device));
+    let output_name = format!("output device \"{}\"", device_name(output_device));
+    let input = Capabilities {
+        name: &input_name,
+        supported: &input_supported,
+        default: input_default.as_ref(),
+    };
+    let output = Capabilities {
+        name: &output_name,
+        supported: &output_supported,
+        default: output_default.as_ref(),
+    };
+    negotiate(&input, &output, prefs, &FORMAT_PREFERENCE)
+}
+
+/// A way to run a device: one of its ranges, at one of its formats and rates.
+#[derive(Clone, Copy)]
+struct Candidate<'a> {
+    range: &'a SupportedStreamConfigRange,
+    rate: SampleRate,
+    /// Position of the format in the formats allowed, 0 for the best.
+    rank: usize,
+    /// Whether the range has the channel count of the device's default configuration.
+    default_channels: bool,
+}
+
+/// Chooses the configurations of an input and an output, taking `formats` in order of
+/// preference, e.g. [`FORMAT_PREFERENCE`].
+///
+/// The preferences go in order: one sample rate for both, so that nothing is resampled; the best
+/// format that both support, or failing that the best either does; the default channel counts
+/// of the devices; and the highest rate among [`STANDARD_RATES`]. Ranges that hold none of them
+/// run at their own highest or lowest rate. A rate in `prefs` is the only one tried, and the
+/// output needs `prefs.output_channels` at least, but any channel count is mapped to any other.
+pub fn negotiate(
+    input: &Capabilities,
+    output: &Capabilities,
+    prefs: &Preferences,
+    formats: &[SampleFormat],
+) -> Result<StreamConfigs, EngineError> {
+    // The edges of every range count, so that a device of an exotic rate can find it on the other.
+    let rates: Vec<SampleRate> = match prefs.sample_rate {
+        Some(rate) => vec![rate],
+        None => STANDARD_RATES
+            .iter()
+            .map(|x| SampleRate(*x))
+            .chain(
+                input
+                    .supported
+                    .iter()
+                    .chain(output.supported)
+                    .flat_map(|x| [x.max_sample_rate(), x.min_sample_rate()]),
+            )
+            .collect(),
+    };
+    let inputs = candidates(input, &rates, prefs, formats, 1)?;
+    let outputs = candidates(output, &rates, prefs, formats, prefs.output_channels)?;
+    let score = |a: &Candidate, b: &Candidate| {
+        (
+            a.rate == b.rate,
+            Reverse(a.rank.max(b.rank)),
+            Reverse(a.rank + b.rank),
+            a.default_channels as u8 + b.default_channels as u8,
+            a.rate.min(b.rate),
+            a.rate.max(b.rate),
+        )
+    };
+    let mut best: Option<(Candidate, Candidate)> = None;
+    for a in &inputs {
+        for b in &outputs {
+            if best.is_none_or(|(x, y)| score(a, b) > score(&x, &y)) {
+                best = Some((*a, *b));
+            }
+        }
+    }
+    // Both lists hold a candidate at least, or `candidates` would have failed.
+    let (a, b) = best.expect("no candidates");
+    let (input, output) = (stream_config(&a, prefs), stream_config(&b, prefs));
+
+    let mut adaptations = Vec::new();
+    if a.range.sample_format() != SampleFormat::F32 {
+        adaptations.push(Adaptation::ConvertFormat {
+            stream: "input",
+            format: a.range.sample_format(),
+        });
+    }
+    if input.channels != output.channels {
+        adaptations.push(Adaptation::MapChannels {
+            from: input.channels,
+            to: output.channels,
+        });
+    }
+    if input.sample_rate != output.sample_rate {
+        adaptations.push(Adaptation::Resample {
+            from: input.sample_rate.0,
+            to: output.sample_rate.0,
+        });
+    }
+    if b.range.sample_format() != SampleFormat::F32 {
+        adaptations.push(Adaptation::ConvertFormat {
+            stream: "output",
+            format: b.range.sample_format(),
+        });
+    }
+    Ok(StreamConfigs {
+        input,
+        output,
+        input_format: a.range.sample_format(),
+        output_format: b.range.sample_format(),
+        adaptations,
+    })
+}
+
+/// The ways to run `device` at `rates` in `formats` with `min_channels` at least, failing if
+/// there are none.
+fn candidates<'a>(
+    device: &Capabilities<'a>,
+    rates: &[SampleRate],
+    prefs: &Preferences,
+    formats: &[SampleFormat],
+    min_channels: u16,
+) -> Result<Vec<Candidate<'a>>, EngineError> {
+    let unsupported = |requested: String, supported: String| EngineError::UnsupportedConfig {
+        device: device.name.to_string(),
+        requested,
+        supported,
+    };
+    let ranges: Vec<&SupportedStreamConfigRange> = device
+        .supported
+        .iter()
+        .filter(|x| formats.contains(&x.sample_format()))
+        .collect();
+    if ranges.is_empty() {
+        let requested: Vec<String> = formats.iter().map(|x| x.to_string()).collect();
+        return Err(unsupported(
+            format!("{} samples", requested.join(" or ")),
+            describe_formats(device.supported),
+        ));
+    }
+    let wide: Vec<&SupportedStreamConfigRange> = ranges
+        .iter()
+        .copied()
+        .filter(|x| x.channels() >= min_channels)
+        .collect();
+    if wide.is_empty() {
+        return Err(unsupported(
+            format!("at least {} channels", min_channels),
+            describe_rates(&ranges),
+        ));
+    }
+
+    let mut candidates = Vec::new();
+    for range in &wide {
+        let rank = formats
+            .iter()
+            .position(|x| *x == range.sample_format())
+            .unwrap_or(formats.len());
+        let default_channels = device
+            .default
+            .is_some_and(|x| x.channels() == range.channels());
+        for &rate in rates.iter().filter(|x| supports_rate(range, **x)) {
+            candidates.push(Candidate {
+                range,
+                rate,
+                rank,
+                default_channels,
+            });
+        }
+    }
+    match (candidates.is_empty(), prefs.sample_rate) {
+        (true, Some(rate)) => Err(unsupported(format!("{} Hz", rate.0), describe_rates(&wide))),
+        _ => Ok(candidates),
+    }
+}
+
+/// The configuration of a candidate, with `prefs.buffer_size` clamped to what its range
+/// accepts.
+fn stream_config(candidate: &Candidate, prefs: &Preferences) -> StreamConfig {
+    let buffer_size = match candidate.range.buffer_size() {
+        SupportedBufferSize::Range { min, max } => prefs.buffer_size.clamp(*min, *max),
+        SupportedBufferSize::Unknown => prefs.buffer_size,
+    };
+    StreamConfig {
+        channels: candidate.range.channels(),
+        sample_rate: candidate.rate,
+        buffer_size: BufferSize::Fixed(buffer_size),
+    }
+}
+
+/// Chooses the configuration of an input device, preferring `sample_rate` if given.
+pub fn input_config(
+    device: &cpal::Device,
+    prefs: &Preferences,
+    sample_rate: Option<SampleRate>,
+) -> Result<StreamConfig, EngineError> {
+    let supported: Vec<_> = device.supported_input_configs()?.collect();
+    let default = device.default_input_config().ok();
+    let name = format!("input device \"{}\"", device_name(device));
+    choose_config(&name, &supported, default.as_ref(), prefs, sample_rate, 1)
+}
+
+/// Chooses the configuration of an output device, preferring `sample_rate` if given.
+pub fn output_config(
+    device: &cpal::Device,
+    prefs: &Preferences,
+    sample_rate: Option<SampleRate>,
+) -> Result<StreamConfig, EngineError> {
+    let supported: Vec<_> = device.supported_output_configs()?.collect();
+    let default = device.default_output_config().ok();
+    let name = format!("output device \"{}\"", device_name(device));
+    let channels = prefs.output_channels;
+    choose_config(
+        &name,
+        &supported,
+        default.as_ref(),
+        prefs,
+        sample_rate,
+        channels,
+    )
+}
+
+/// Chooses a configuration among the `supported` ranges of one device.
+///
+/// Only f32 ranges with at least `min_channels` channels are considered. The channel count is the
+/// default configuration's if some range has it. The sample rate is `prefs.sample_rate` if set,
+/// which fails with the list of supported rates if no range contains it. Otherwise it's, in order
+/// of preference: `sample_rate`, the default configuration's, or the fallback rate clamped to the
+/// first range. The buffer size is `prefs.buffer_size` clamped to what the chosen range accepts.
+/// `device` names the device in the errors, e.g. "input device \"USB Audio\"".
+pub fn choose_config(
+    device: &str,
+    supported: &[SupportedStreamConfigRange],
+    default: Option<&SupportedStreamConfig>,
+    prefs: &Preferences,
+    sample_rate: Option<SampleRate>,
+    min_channels: u16,
+) -> Result<StreamConfig, EngineError> {
+    let unsupported = |requested: String, supported: String| EngineError::UnsupportedConfig {
+        device: device.to_string(),
+        requested,
+        supported,
+    };
+    let f32_ranges: Vec<&SupportedStreamConfigRange> = supported
+        .iter()
+        .filter(|x| x.sample_format() == SampleFormat::F32)
+        .collect();
+    if f32_ranges.is_empty() {
+        return Err(unsupported(
+            "f32 samples".to_string(),
+            describe_formats(supported),
+        ));
+    }
+    let wide: Vec<&SupportedStreamConfigRange> = f32_ranges
+        .iter()
+        .copied()
+        .filter(|x| x.channels() >= min_channels)
+        .collect();
+    if wide.is_empty() {
+        return Err(unsupported(
+            format!("at least {} channels", min_channels),
+            describe_rates(&f32_ranges),
+        ));
+    }
+    let f32_ranges = wide;
+    let mut candidates = f32_ranges.clone();
+
+    if let Some(default) = default {
+        if candidates
+            .iter()
+            .any(|x| x.channels() == default.channels())
+        {
+            candidates.retain(|x| x.channels() == default.channels());
+        }
+    }
+
+    let (range, rate) = if let Some(rate) = prefs.sample_rate {
+        // Keep the preferred channel count if possible, but a forced rate takes precedence.
+        let range = candidates
+            .iter()
+            .chain(&f32_ranges)
+            .find(|x| supports_rate(x, rate))
+            .ok_or_else(|| unsupported(format!("{} Hz", rate.0), describe_rates(&f32_ranges)))?;
+        (*range, rate)
+    } else {
+        preferred_range(&candidates, default, sample_rate)
+    };
+
+    let buffer_size = match range.buffer_size() {
+        SupportedBufferSize::Range { min, max } => prefs.buffer_size.clamp(*min, *max),
+        SupportedBufferSize::Unknown => prefs.buffer_size,
+    };
+    Ok(StreamConfig {
+        channels: range.channels(),
+        sample_rate: rate,
+        buffer_size: BufferSize::Fixed(buffer_size),
+    })
+}
+
+/// Picks a range and rate when no rate is forced: `sample_rate` if some candidate supports it,
+/// then the default configuration's rate, then the fallback rate clamped to the first candidate.
+fn preferred_range<'a>(
+    candidates: &[&'a SupportedStreamConfigRange],
+    default: Option<&SupportedStreamConfig>,
+    sample_rate: Option<SampleRate>,
+) -> (&'a SupportedStreamConfigRange, SampleRate) {
+    let rates = [sample_rate, default.map(|x| x.sample_rate())];
+    rates
+        .into_iter()
+        .flatten()
+        .find_map(|rate| {
+            candidates
+                .iter()
+                .find(|x| supports_rate(x, rate))
+                .map(|&x| (x, rate))
+        })
+        .unwrap_or_else(|| {
+            let range = candidates[0];
+            let rate = FALLBACK_SAMPLE_RATE
+                .0
+                .clamp(range.min_sample_rate().0, range.max_sample_rate().0);
+            (range, SampleRate(rate))
+        })
+}
+
+/// Lists the sample formats of `ranges`, e.g. "i16 samples, i32 samples".
+fn describe_formats(ranges: &[SupportedStreamConfigRange]) -> String {
+    let mut formats: Vec<String> = Vec::new();
+    for range in ranges {
+        let format = format!("{} samples", range.sample_format());
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    formats.join(", ")
+}
+
+/// Lists the sample rates of `ranges`, e.g. "44100-96000 Hz (2 channels), 48000 Hz (1 channels)".
+fn describe_rates(ranges: &[&SupportedStreamConfigRange]) -> String {
+    let mut descriptions: Vec<String> = Vec::new();
+    for range in ranges {
+        let (min, max) = (range.min_sample_rate().0, range.max_sample_rate().0);
+        let rates = if min == max {
+            format!("{} Hz", min)
+        } else {
+            format!("{}-{} Hz", min, max)
+        };
+        let description = format!("{} ({} channels)", rates, range.channels());
+        if !descriptions.contains(&description) {
+            descriptions.push(description);
+        }
+    }
+    descriptions.join(", ")
+}
+
+/// Describes a configuration for the startup output, e.g. "2 channels at 48000 Hz, 128 frames".
+pub fn describe(config: &StreamConfig) -> String {
+    let buffer_size = match config.buffer_size {
+        BufferSize::Fixed(frames) => format!("{} frames", frames),
+        BufferSize::Default => "default buffer size".to_string(),
+    };
+    format!(
+        "{} channels at {} Hz, {}",
+        config.channels, config.sample_rate.0, buffer_size
+    )
+}
+
+/// A configuration to build a stream with, and the format of its samples.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rung {
+    pub config: StreamConfig,
+    pub format: SampleFormat,
+}
+
+impl fmt::Display for Rung {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} samples, {}", self.format, describe(&self.config))
+    }
+}
+
+/// The configurations to build a stream of `first` with, `first` then its fallbacks among the
+/// `supported` ranges of its device, in order of preference:
+///
+/// 1. `first` in the other formats the device has it in, by [`FORMAT_PREFERENCE`].
+/// 2. The other channel counts at the rate of `first`, the closest from above first, then from
+///    below, each by format. The rate stays, since the streams around are clocked by it.
+/// 3. All of the above again with the device's default buffer size.
+///
+/// The engine runs in f32 and at the channel count of `first` whatever the rung, and the
+/// stream converts between the two.
+pub fn fallback_ladder(first: &Rung, supported: &[SupportedStreamConfigRange]) -> Vec<Rung> {
+    let (channels, rate) = (first.config.channels, first.config.sample_rate);
+    let mut ladder = vec![first.clone()];
+    ladder.extend(
+        FORMAT_PREFERENCE
+            .into_iter()
+            .filter(|format| {
+                supported.iter().any(|x| {
+                    x.sample_format() == *format
+                        && x.channels() == channels
+                        && supports_rate(x, rate)
+                })
+            })
+            .map(|format| Rung {
+                config: first.config.clone(),
+                format,
+            }),
+    );
+    let rank = |format: SampleFormat| FORMAT_PREFERENCE.iter().position(|x| *x == format);
+    let mut ranges: Vec<&SupportedStreamConfigRange> = supported
+        .iter()
+        .filter(|x| supports_rate(x, rate) && rank(x.sample_format()).is_some())
+        .collect();
+    ranges.sort_by_key(|x| {
+        (
+            x.channels() < channels,
+            x.channels().abs_diff(channels),
+            rank(x.sample_format()),
+        )
+    });
+    ladder.extend(ranges.into_iter().map(|range| {
+        let buffer_size = match (first.config.buffer_size, range.buffer_size()) {
+            (BufferSize::Fixed(frames), SupportedBufferSize::Range { min, max }) => {
+                BufferSize::Fixed(frames.clamp(*min, *max))
+            }
+            (buffer_size, _) => buffer_size,
+        };
+        Rung {
+            config: StreamConfig {
+                channels: range.channels(),
+                sample_rate: rate,
+                buffer_size,
+            },
+            format: range.sample_format(),
+        }
+    }));
+    let defaults: Vec<Rung> = ladder
+        .iter()
+        .map(|rung| Rung {
+            config: StreamConfig {
+                buffer_size: BufferSize::Default,
+                ..rung.config.clone()
+            },
+            format: rung.format,
+        })
+        .collect();
+    let mut rungs: Vec<Rung> = Vec::new();
+    for rung in ladder.into_iter().chain(defaults) {
+        if !rungs.contains(&rung) {
+            rungs.push(rung);
+        }
+    }
+    rungs
+}
+
+/// Builds the stream `label` with the first rung of `ladder` that `build` succeeds with, and
+/// says which if it's not the first. A failure that isn't about the configuration, e.g. of a
+/// device gone, stops the climb, and so does the end of the ladder, with the failure of the
+/// first rung.
+pub fn build_with_fallback<T>(
+    label: &str,
+    ladder: &[Rung],
+    mut build: impl FnMut(&Rung) -> Result<T, cpal::BuildStreamError>,
+) -> Result<(T, Rung), cpal::BuildStreamError> {
+    let mut first_err = None;
+    for rung in ladder {
+        match build(rung) {
+            Ok(stream) => {
+                if let Some(err) = first_err {
+                    tracing::warn!(
+                        "the {} failed to build with {} ({}), falling back to {}",
+                        label,
+                        ladder[0],
+                        err,
+                        rung
+                    );
+                }
+                return Ok((stream, rung.clone()));
+            }
+            Err(err) if !events::is_config_failure(&err) => return Err(err),
+            Err(err) => {
+                tracing::debug!(stream = label, "failed to build with {}: {}", rung, err);
+                first_err.get_or_insert(err);
+            }
+        }
+    }
+    Err(first_err.unwrap_or(cpal::BuildStreamError::StreamConfigNotSupported))
+}
+
+fn supports_rate(range: &SupportedStreamConfigRange, rate: SampleRate) -> bool {
+    range.min_sample_rate() <= rate && rate <= range.max_sample_rate()
+}
+
+fn device_name(device: &cpal::Device) -> String {
+    device.name().unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USB: &str = "input device \"USB Audio\"";
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Range { min: 64, max: 4096 },
+            format,
+        )
+    }
+
+    fn default(channels: u16, rate: u32) -> SupportedStreamConfig {
+        let buffer_size = SupportedBufferSize::Range { min: 64, max: 4096 };
+        SupportedStreamConfig::new(channels, SampleRate(rate), buffer_size, SampleFormat::F32)
+    }
+
+    fn prefs(buffer_size: u32) -> Preferences {
+        Preferences {
+            buffer_size,
+            sample_rate: None,
+            output_channels: 1,
+        }
+    }
+
+    fn choose(
+        supported: &[SupportedStreamConfigRange],
+        default: Option<&SupportedStreamConfig>,
+        prefs: &Preferences,
+        sample_rate: Option<u32>,
+    ) -> Result<String, EngineError> {
+        choose_config(
+            USB,
+            supported,
+            default,
+            prefs,
+            sample_rate.map(SampleRate),
+            1,
+        )
+        .map(|x| describe(&x))
+    }
+
+    #[test]
+    fn a_device_keeps_its_default_channels_and_rate() {
+        let supported = [
+            range(8, 44_100, 96_000, SampleFormat::F32),
+            range(2, 44_100, 96_000, SampleFormat::F32),
+        ];
+        let chosen = choose(&supported, Some(&default(2, 44_100)), &prefs(256), None);
+        assert_eq!(chosen.unwrap(), "2 channels at 44100 Hz, 256 frames");
+    }
+
+    #[test]
+    fn the_rate_of_the_other_stream_comes_first() {
+        let supported = [range(2, 44_100, 96_000, SampleFormat::F32)];
+        let default = default(2, 44_100);
+        let chosen = choose(&supported, Some(&default), &prefs(256), Some(96_000));
+        assert_eq!(chosen.unwrap(), "2 channels at 96000 Hz, 256 frames");
+        // Unless the device can't run at it.
+        let chosen = choose(&supported, Some(&default), &prefs(256), Some(192_000));
+        assert_eq!(chosen.unwrap(), "2 channels at 44100 Hz, 256 frames");
+    }
+
+    #[test]
+    fn without_a_default_the_fallback_rate_is_clamped_to_the_device() {
+        let supported = [range(1, 8_000, 16_000, SampleFormat::F32)];
+        let chosen = choose(&supported, None, &prefs(256), None);
+        assert_eq!(chosen.unwrap(), "1 channels at 16000 Hz, 256 frames");
+        let supported = [range(2, 44_100, 96_000, SampleFormat::F32)];
+        let chosen = choose(&supported, None, &prefs(256), None);
+        assert_eq!(chosen.unwrap(), "2 channels at 48000 Hz, 256 frames");
+    }
+
+    #[test]
+    fn the_buffer_size_is_clamped_to_the_device() {
+        let supported = [range(2, 48_000, 48_000, SampleFormat::F32)];
+        let chosen = |frames| choose(&supported, None, &prefs(frames), None).unwrap();
+        assert_eq!(chosen(16), "2 channels at 48000 Hz, 64 frames");
+        assert_eq!(chosen(8192), "2 channels at 48000 Hz, 4096 frames");
+    }
+
+    #[test]
+    fn devices_without_f32_samples_are_unsupported() {
+        let supported = [
+            range(2, 48_000, 48_000, SampleFormat::I16),
+            range(2, 48_000, 48_000, SampleFormat::I32),
+        ];
+        let err = choose(&supported, None, &prefs(256), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "input device \"USB Audio\" doesn't support f32 samples, only i16 samples, i32 samples"
+        );
+    }
+
+    #[test]
+    fn outputs_get_at_least_the_channels_the_routing_needs() {
+        let supported = [
+            range(2, 48_000, 48_000, SampleFormat::F32),
+            range(4, 48_000, 48_000, SampleFormat::F32),
+        ];
+        let default = default(2, 48_000);
+        let chosen = choose_config(USB, &supported, Some(&default), &prefs(256), None, 4);
+        assert_eq!(
+            describe(&chosen.unwrap()),
+            "4 channels at 48000 Hz, 256 frames"
+        );
+        let err = choose_config(USB, &supported, Some(&default), &prefs(256), None, 6);
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "input device \"USB Audio\" doesn't support at least 6 channels, \
+             only 48000 Hz (2 channels), 48000 Hz (4 channels)"
+        );
+    }
+
+    fn forcing(rate: u32) -> Preferences {
+        Preferences {
+            sample_rate: Some(SampleRate(rate)),
+            ..prefs(256)
+        }
+    }
+
+    #[test]
+    fn a_forced_rate_wins_over_the_other_stream_and_the_default() {
+        let supported = [range(2, 44_100, 192_000, SampleFormat::F32)];
+        let chosen = choose(
+            &supported,
+            Some(&default(2, 48_000)),
+            &forcing(88_200),
+            Some(96_000),
+        );
+        assert_eq!(chosen.unwrap(), "2 channels at 88200 Hz, 256 frames");
+    }
+
+    #[test]
+    fn a_forced_rate_takes_other_channels_if_it_must() {
+        let supported = [
+            range(2, 44_100, 48_000, SampleFormat::F32),
+            range(4, 96_000, 96_000, SampleFormat::F32),
+        ];
+        let default = default(2, 48_000);
+        let chosen = choose(&supported, Some(&default), &forcing(48_000), None);
+        assert_eq!(chosen.unwrap(), "2 channels at 48000 Hz, 256 frames");
+        let chosen = choose(&supported, Some(&default), &forcing(96_000), None);
+        assert_eq!(chosen.unwrap(), "4 channels at 96000 Hz, 256 frames");
+    }
+
+    #[test]
+    fn a_forced_rate_no_range_has_lists_the_supported_ones() {
+        let supported = [
+            range(2, 44_100, 48_000, SampleFormat::F32),
+            range(1, 16_000, 16_000, SampleFormat::F32),
+            range(2, 8_000, 192_000, SampleFormat::I16),
+        ];
+        let err = choose(&supported, None, &forcing(96_000), None).unwrap_err();
+        assert!(matches!(err, EngineError::UnsupportedConfig { .. }));
+        assert_eq!(
+            err.to_string(),
+            "input device \"USB Audio\" doesn't support 96000 Hz, \
+             only 44100-48000 Hz (2 channels), 16000 Hz (1 channels)"
+        );
+    }
+
+    fn capabilities<'a>(
+        name: &'a str,
+        supported: &'a [SupportedStreamConfigRange],
+        default: Option<&'a SupportedStreamConfig>,
+    ) -> Capabilities<'a> {
+        Capabilities {
+            name,
+            supported,
+            default,
+        }
+    }
+
+    fn negotiated(configs: &StreamConfigs) -> (String, String, Vec<String>) {
+        let adaptations = configs.adaptations.iter().map(|x| x.to_string());
+        (
+            describe(&configs.input),
+            describe(&configs.output),
+            adaptations.collect(),
+        )
+    }
+
+    #[test]
+    fn devices_share_their_highest_common_rate() {
+        let input = [range(2, 44_100, 96_000, SampleFormat::F32)];
+        let output = [range(2, 48_000, 192_000, SampleFormat::F32)];
+        let configs = negotiate(
+            &capabilities(USB, &input, None),
+            &capabilities("output device", &output, None),
+            &prefs(256),
+            &FORMAT_PREFERENCE,
+        )
+        .unwrap();
+        assert_eq!(
+            negotiated(&configs),
+            (
+                "2 channels at 96000 Hz, 256 frames".to_string(),
+                "2 channels at 96000 Hz, 256 frames".to_string(),
+                vec![]
+            )
+        );
+    }
+
+    #[test]
+    fn a_common_rate_wins_over_the_default_channels() {
+        let input = [
+            range(1, 44_100, 44_100, SampleFormat::F32),
+            range(2, 48_000, 48_000, SampleFormat::F32),
+        ];
+        let output = [range(2, 48_000, 48_000, SampleFormat::F32)];
+        let default = default(1, 44_100);
+        let configs = negotiate(
+            &capabilities(USB, &input, Some(&default)),
+            &capabilities("output device", &output, None),
+            &prefs(256),
+            &FORMAT_PREFERENCE,
+        )
+        .unwrap();
+        assert_eq!(
+            describe(&configs.input),
+            "2 channels at 48000 Hz, 256 frames"
+        );
+        assert!(configs.adaptations.is_empty());
+    }
+
+    #[test]
+    fn exotic_rates_are_found_on_both_devices() {
+        let input = [range(2, 22_050, 22_050, SampleFormat::F32)];
+        let output = [range(2, 8_000, 22_050, SampleFormat::F32)];
+        let configs = negotiate(
+            &capabilities(USB, &input, None),
+            &capabilities("output device", &output, None),
+            &prefs(256),
+            &FORMAT_PREFERENCE,
+        )
+        .unwrap();
+        assert_eq!(configs.output.sample_rate, SampleRate(22_050));
+    }
+
+    #[test]
+    fn devices_with_nothing_in_common_are_adapted() {
+        let input = [range(1, 44_100, 44_100, SampleFormat::I16)];
+        let output = [range(2, 48_000, 48_000, SampleFormat::F32)];
+        let configs = negotiate(
+            &capabilities(USB, &input, None),
+            &capabilities("output device", &output, None),
+            &prefs(256),
+            &FORMAT_PREFERENCE,
+        )
+        .unwrap();
+        assert_eq!(configs.input_format, SampleFormat::I16);
+        assert_eq!(
+            negotiated(&configs).2,
+            [
+                "converting the i16 samples of the input",
+                "mapping 1 channels to 2",
+                "resampling from 44100 Hz to 48000 Hz",
+            ]
+        );
+    }
+
+    #[test]
+    fn every_device_takes_the_best_format_it_has() {
+        let input = [
+            range(2, 48_000, 48_000, SampleFormat::I16),
+            range(2, 48_000, 48_000, SampleFormat::I32),
+        ];
+        let output = [
+            range(2, 48_000, 48_000, SampleFormat::I32),
+            range(2, 48_000, 48_000, SampleFormat::F32),
+        ];
+        let configs = negotiate(
+            &capabilities(USB, &input, None),
+            &capabilities("output device", &output, None),
+            &prefs(256),
+            &FORMAT_PREFERENCE,
+        )
+        .unwrap();
+        assert_eq!(
+            (configs.input_format, configs.output_format),
+            (SampleFormat::I32, SampleFormat::F32)
+        );
+    }
+
+    #[test]
+    fn devices_that_support_nothing_say_so() {
+        let output = [range(2, 48_000, 48_000, SampleFormat::F32)];
+        let err = negotiate(
+            &capabilities(USB, &[], None),
+            &capabilities("output device", &output, None),
+            &prefs(256),
+            &FORMAT_PREFERENCE,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "input device \"USB Audio\" doesn't support f32 or i32 or i16 samples, nor anything else"
+        );
+    }
+
+    fn rung(channels: u16, buffer_size: BufferSize, format: SampleFormat) -> Rung {
+        Rung {
+            config: StreamConfig {
+                channels,
+                sample_rate: SampleRate(48_000),
+                buffer_size,
+            },
+            format,
+        }
+    }
+
+    #[test]
+    fn the_ladder_tries_other_formats_then_other_channels_then_the_default_buffer() {
+        let supported = [
+            range(1, 44_100, 96_000, SampleFormat::F32),
+            range(2, 44_100, 96_000, SampleFormat::I16),
+            range(2, 44_100, 96_000, SampleFormat::F32),
+            range(4, 44_100, 96_000, SampleFormat::I32),
+            // Not at the rate, or not in a format the engine converts.
+            range(8, 8_000, 16_000, SampleFormat::F32),
+            range(2, 44_100, 96_000, SampleFormat::U8),
+        ];
+        let first = rung(2, BufferSize::Fixed(256), SampleFormat::F32);
+        let ladder: Vec<String> = fallback_ladder(&first, &supported)
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(
+            ladder,
+            [
+                "f32 samples, 2 channels at 48000 Hz, 256 frames",
+                "i16 samples, 2 channels at 48000 Hz, 256 frames",
+                "i32 samples, 4 channels at 48000 Hz, 256 frames",
+                "f32 samples, 1 channels at 48000 Hz, 256 frames",
+                "f32 samples, 2 channels at 48000 Hz, default buffer size",
+                "i16 samples, 2 channels at 48000 Hz, default buffer size",
+                "i32 samples, 4 channels at 48000 Hz, default buffer size",
+                "f32 samples, 1 channels at 48000 Hz, default buffer size",
+            ]
+        );
+    }
+
+    #[test]
+    fn other_channel_counts_clamp_the_buffer_size_to_their_range() {
+        let supported = [range(4, 48_000, 48_000, SampleFormat::F32)];
+        let first = rung(2, BufferSize::Fixed(8192), SampleFormat::F32);
+        let ladder = fallback_ladder(&first, &supported);
+        assert_eq!(
+            ladder[1],
+            rung(4, BufferSize::Fixed(4096), SampleFormat::F32)
+        );
+    }
+
+    #[test]
+    fn building_climbs_the_ladder_past_config_failures() {
+        let ladder = [
+            rung(2, BufferSize::Fixed(256), SampleFormat::F32),
+            rung(2, BufferSize::Fixed(256), SampleFormat::I16),
+            rung(2, BufferSize::Default, SampleFormat::I16),
+        ];
+        let mut tried = 0;
+        let built = build_with_fallback("output stream", &ladder, |x| {
+            tried += 1;
+            match x.format {
+                SampleFormat::I16 => Ok("stream"),
+                _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+            }
+        });
+        assert_eq!(built.unwrap(), ("stream", ladder[1].clone()));
+        assert_eq!(tried, 2);
+    }
+
+    #[test]
+    fn building_stops_at_failures_that_are_not_about_the_config() {
+        let ladder = [
+            rung(2, BufferSize::Fixed(256), SampleFormat::F32),
+            rung(2, BufferSize::Fixed(256), SampleFormat::I16),
+        ];
+        let mut tried = 0;
+        let built: Result<((), Rung), _> = build_with_fallback("input stream", &ladder, |_| {
+            tried += 1;
+            Err(cpal::BuildStreamError::DeviceNotAvailable)
+        });
+        assert!(matches!(
+            built,
+            Err(cpal::BuildStreamError::DeviceNotAvailable)
+        ));
+        assert_eq!(tried, 1);
+    }
+
+    #[test]
+    fn building_fails_with_the_first_error_at_the_end_of_the_ladder() {
+        let ladder = [
+            rung(2, BufferSize::Fixed(256), SampleFormat::F32),
+            rung(2, BufferSize::Fixed(256), SampleFormat::I16),
+        ];
+        let mut errors = vec![
+            cpal::BuildStreamError::InvalidArgument,
+            cpal::BuildStreamError::StreamConfigNotSupported,
+        ]
+        .into_iter();
+        let built: Result<((), Rung), _> =
+            build_with_fallback("input stream", &ladder, |_| Err(errors.next().unwrap()));
+        assert!(matches!(
+            built,
+            Err(cpal::BuildStreamError::InvalidArgument)
+        ));
+    }
+}
@@ -0,0 +1,148 @@
+//! Commands typed on the terminal during a run, one per line, e.g. `n` or `t 140`.
+//!
+//! Each command is a name, optionally followed by an argument. A thread reads the lines of the
+//! standard input and runs the matching actions, which talk to the audio threads through shared
+//! atomics, so typing never blocks the audio.
+
+use std::io::{BufRead, IsTerminal};
+use std::sync::atomic::AtomicBool;
+#[cfg(unix)]
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+#[cfg(unix)]
+use std::sync::OnceLock;
+
+/// An action, given the argument of the command, or an empty string.
+type Action = Box<dyn FnMut(&str) -> Result<(), String> + Send>;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    action: Action,
+}
+
+/// The commands of a run.
+#[derive(Default)]
+pub struct Controls {
+    commands: Vec<Command>,
+}
+
+impl Controls {
+    /// Adds the command `name`, shown as `usage` with its `help`, e.g. "t <BPM>" and "change the
+    /// tempo of the click", or not at all with an empty `usage`, e.g. when another command's
+    /// covers it. An action failing logs its message as a warning.
+    pub fn add(
+        &mut self,
+        name: &'static str,
+        usage: &'static str,
+        help: &'static str,
+        action: impl FnMut(&str) -> Result<(), String> + Send + 'static,
+    ) {
+        self.commands.push(Command {
+            name,
+            usage,
+            help,
+            action: Box::new(action),
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Prints the commands if `listed` and starts reading them, if the standard input is a
+    /// terminal.
+    pub fn spawn(mut self, listed: bool) {
+        if self.is_empty() || !std::io::stdin().is_terminal() {
+            return;
+        }
+        if listed {
+            println!("Commands, followed by Enter:");
+            for command in self.commands.iter().filter(|x| !x.usage.is_empty()) {
+                println!("  {:<10} {}", command.usage, command.help);
+            }
+        }
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                self.run(line.trim());
+            }
+        });
+    }
+
+    fn run(&mut self, line: &str) {
+        if line.is_empty() {
+            return;
+        }
+        let (name, argument) = line.split_once(' ').unwrap_or((line, ""));
+        match self.commands.iter_mut().find(|x| x.name == name) {
+            Some(command) => {
+                if let Err(message) = (command.action)(argument.trim()) {
+                    tracing::warn!("{}", message);
+                }
+            }
+            None => tracing::warn!("unknown command \"{}\"", name),
+        }
+    }
+}
+
+#[cfg(unix)]
+static STOP: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: libc::c_int) {
+    if let Some(stop) = STOP.get() {
+        stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Sets `stop` on SIGTERM and SIGINT, e.g. Ctrl+C, so that the run ends cleanly instead of being
+/// cut off.
+#[cfg(unix)]
+pub fn stop_on_signals(stop: Arc<AtomicBool>) {
+    let _ = STOP.set(stop);
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // Safety: the handler only does an atomic store.
+        unsafe {
+            libc::signal(signal, on_signal as *const () as libc::sighandler_t);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn commands_run_with_their_argument() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut controls = Controls::default();
+        for name in ["t", "n"] {
+            let calls = calls.clone();
+            controls.add(name, name, "", move |argument| {
+                calls.lock().unwrap().push(format!("{} {}", name, argument));
+                Ok(())
+            });
+        }
+        controls.add("f", "f", "", |_| Err("failed".to_string()));
+        for line in ["t  140", "n", "", "x 1", "f"] {
+            controls.run(line);
+        }
+        assert_eq!(*calls.lock().unwrap(), ["t 140", "n "]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signals_stop_the_run() {
+        let stop = Arc::new(AtomicBool::new(false));
+        stop_on_signals(stop.clone());
+        // Safety: the handler was just installed, and only sets `stop`.
+        unsafe {
+            libc::raise(libc::SIGTERM);
+        }
+        assert!(stop.load(Ordering::Relaxed));
+    }
+}
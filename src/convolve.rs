@@ -0,0 +1,175 @@
+//! Convolution with long filters, by uniformly partitioned FFT convolution.
+//!
+//! The filter is cut into partitions of [`PARTITION`] taps, each transformed once up front. The
+//! input is gathered into blocks of as many frames, and every block is transformed into a
+//! frequency-domain delay line of the past blocks. An output block is then the inverse transform
+//! of the sum of each partition's spectrum times the spectrum of the block as old as the
+//! partition is far into the filter, overlap-save style. The cost per frame grows with the
+//! number of partitions, not with a full-length transform, and nothing is allocated once set up.
+//!
+//! Output comes out one block late, so a convolver always adds [`PARTITION`] frames of latency.
+
+use crate::fft::Fft;
+
+/// Taps per partition, and frames of latency.
+pub const PARTITION: usize = 256;
+
+/// A spectrum of `2 · PARTITION` bins.
+struct Spectrum {
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl Spectrum {
+    fn zero() -> Self {
+        Spectrum {
+            re: vec![0.0; PARTITION * 2],
+            im: vec![0.0; PARTITION * 2],
+        }
+    }
+}
+
+/// Convolves one channel with a fixed filter.
+pub struct Convolver {
+    fft: Fft,
+    partitions: Vec<Spectrum>,
+    /// Spectra of the last input blocks, as many as there are partitions.
+    history: Vec<Spectrum>,
+    /// Position of the newest block in `history`.
+    newest: usize,
+    /// The previous input block followed by the one being gathered.
+    input: Vec<f32>,
+    /// Frames gathered in the current block.
+    filled: usize,
+    /// The output block being played out.
+    output: Vec<f32>,
+    sum: Spectrum,
+}
+
+impl Convolver {
+    /// Sets up the convolution with `taps`, which must not be empty.
+    pub fn new(taps: &[f32]) -> Self {
+        assert!(!taps.is_empty(), "a filter needs at least one tap");
+        let fft = Fft::new(PARTITION * 2);
+        let partitions: Vec<Spectrum> = taps
+            .chunks(PARTITION)
+            .map(|chunk| {
+                let mut spectrum = Spectrum::zero();
+                spectrum.re[..chunk.len()].copy_from_slice(chunk);
+                fft.forward(&mut spectrum.re, &mut spectrum.im);
+                spectrum
+            })
+            .collect();
+        let history = partitions.iter().map(|_| Spectrum::zero()).collect();
+        Convolver {
+            fft,
+            partitions,
+            history,
+            newest: 0,
+            input: vec![0.0; PARTITION * 2],
+            filled: 0,
+            output: vec![0.0; PARTITION],
+            sum: Spectrum::zero(),
+        }
+    }
+
+    /// Replaces `data` with its convolution with the filter, [`PARTITION`] frames late.
+    pub fn process(&mut self, data: &mut [f32]) {
+        for x in data {
+            *x = self.process_sample(*x);
+        }
+    }
+
+    /// Takes the next input sample, and returns the output sample [`PARTITION`] frames behind.
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        self.input[PARTITION + self.filled] = x;
+        let y = self.output[self.filled];
+        self.filled += 1;
+        if self.filled == PARTITION {
+            self.next_block();
+            self.filled = 0;
+        }
+        y
+    }
+
+    /// Computes the output of the block just gathered.
+    fn next_block(&mut self) {
+        let count = self.partitions.len();
+        self.newest = (self.newest + 1) % count;
+        let block = &mut self.history[self.newest];
+        block.re.copy_from_slice(&self.input);
+        block.im.fill(0.0);
+        self.fft.forward(&mut block.re, &mut block.im);
+
+        let sum = &mut self.sum;
+        sum.re.fill(0.0);
+        sum.im.fill(0.0);
+        for (age, partition) in self.partitions.iter().enumerate() {
+            let block = &self.history[(self.newest + count - age) % count];
+            for bin in 0..PARTITION * 2 {
+                let (a, b) = (partition.re[bin], partition.im[bin]);
+                let (c, d) = (block.re[bin], block.im[bin]);
+                sum.re[bin] += a * c - b * d;
+                sum.im[bin] += a * d + b * c;
+            }
+        }
+        self.fft.inverse(&mut sum.re, &mut sum.im);
+        // The first half wrapped around the circular convolution; the second half is exact.
+        self.output.copy_from_slice(&sum.re[PARTITION..]);
+        self.input.copy_within(PARTITION.., 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(length: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 8) as f32 / (1 << 23) as f32 - 1.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_output_is_the_direct_convolution_a_partition_late() {
+        let input = noise(6_000, 0x9e37_79b9);
+        for length in [1, PARTITION - 1, PARTITION, 3 * PARTITION + 17] {
+            let taps = noise(length, 0x1234_5678);
+            let mut convolver = Convolver::new(&taps);
+            let mut output = input.clone();
+            // In uneven blocks, across the partitions.
+            let mut rest = output.as_mut_slice();
+            for block in [1, 100, 255, 256, 257, 1_000].iter().cycle() {
+                let (data, tail) = rest.split_at_mut((*block).min(rest.len()));
+                convolver.process(data);
+                rest = tail;
+                if rest.is_empty() {
+                    break;
+                }
+            }
+            let mut error = 0f64;
+            let mut peak = 0f64;
+            for n in PARTITION..input.len() {
+                let expected: f64 = (0..length.min(n - PARTITION + 1))
+                    .map(|k| taps[k] as f64 * input[n - PARTITION - k] as f64)
+                    .sum();
+                error = error.max((output[n] as f64 - expected).abs());
+                peak = peak.max(expected.abs());
+            }
+            assert!(error < 1e-6 * peak.max(1.0), "{}: {}", length, error);
+            assert!(output[..PARTITION].iter().all(|x| *x == 0.0));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "a filter needs at least one tap")]
+    fn filters_need_taps() {
+        Convolver::new(&[]);
+    }
+}
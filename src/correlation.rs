@@ -0,0 +1,258 @@
+//! Correlation meter of the first two channels of the first input, e.g. of a pair of mics.
+//!
+//! The coefficient goes from +1, for the same signal in both channels, through 0, for unrelated
+//! ones, to -1, for a channel the inverse of the other, whose sum cancels out. Below 0 the
+//! channels fight in phase.
+//!
+//! The input callback sends the pairs of samples to a thread of its own through a ring buffer,
+//! which keeps the sums of the coefficient over a sliding window, and feeds the goniometer.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use ringbuf::traits::{Consumer, Observer, Producer};
+use ringbuf::{HeapCons, HeapProd};
+
+use crate::goniometer::Goniometer;
+use crate::stats::AtomicF32;
+
+/// Length of the sliding window, in seconds.
+pub const WINDOW_SECONDS: f64 = 0.4;
+/// Mean square of both channels under which the window counts as silent, about -80 dBFS.
+const SILENCE: f64 = 1e-8;
+
+/// The coefficient over the last `window` frames, in constant time and memory per frame.
+pub struct Correlation {
+    /// The frames of the window, as a circular buffer.
+    frames: Vec<[f32; 2]>,
+    next: usize,
+    filled: usize,
+    /// Products of the samples over the window: left by right, left by left, right by right.
+    sums: [f64; 3],
+}
+
+impl Correlation {
+    pub fn new(window: usize) -> Self {
+        Correlation {
+            frames: vec![[0.0; 2]; window.max(1)],
+            next: 0,
+            filled: 0,
+            sums: [0.0; 3],
+        }
+    }
+
+    pub fn push(&mut self, left: f32, right: f32) {
+        let [old_left, old_right] = std::mem::replace(&mut self.frames[self.next], [left, right]);
+        let (l, r) = (left as f64, right as f64);
+        let (old_l, old_r) = (old_left as f64, old_right as f64);
+        self.sums[0] += l * r - old_l * old_r;
+        self.sums[1] += l * l - old_l * old_l;
+        self.sums[2] += r * r - old_r * old_r;
+        self.next += 1;
+        self.filled = (self.filled + 1).min(self.frames.len());
+        if self.next == self.frames.len() {
+            self.next = 0;
+            // Adding and subtracting leaves rounding errors, which summing afresh once per
+            // window keeps from adding up.
+            self.sums = [0.0; 3];
+            for [left, right] in &self.frames {
+                let (l, r) = (*left as f64, *right as f64);
+                self.sums[0] += l * r;
+                self.sums[1] += l * l;
+                self.sums[2] += r * r;
+            }
+        }
+    }
+
+    /// The coefficient, or `None` if both channels are silent. A silent channel beside a sound
+    /// one is uncorrelated.
+    pub fn value(&self) -> Option<f32> {
+        let [product, left, right] = self.sums;
+        let frames = self.filled.max(1) as f64;
+        if (left + right) / (2.0 * frames) <= SILENCE {
+            return None;
+        }
+        let norm = (left * right).sqrt();
+        Some(match norm {
+            x if x <= f64::MIN_POSITIVE => 0.0,
+            x => (product / x).clamp(-1.0, 1.0) as f32,
+        })
+    }
+}
+
+/// Shared with the status line.
+pub struct CorrelationStats {
+    /// The coefficient, or NaN while silent.
+    pub value: AtomicF32,
+    /// Blocks that didn't fit in the ring buffer.
+    pub dropped: AtomicU64,
+}
+
+impl Default for CorrelationStats {
+    fn default() -> Self {
+        CorrelationStats {
+            value: AtomicF32::new(f32::NAN),
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+impl CorrelationStats {
+    /// The field of the status line, and whether it's a warning.
+    pub fn describe(&self) -> (String, bool) {
+        match self.value.load() {
+            x if x.is_nan() => ("corr: silent".to_string(), false),
+            x => (format!("corr: {:+.2}", x), x < 0.0),
+        }
+    }
+}
+
+/// Sends the first two channels of each block of the input callback to the meter thread.
+pub struct CorrelationTap {
+    producer: HeapProd<f32>,
+    channels: usize,
+    stats: Arc<CorrelationStats>,
+}
+
+impl CorrelationTap {
+    /// Taps interleaved blocks of `channels` channels, which must be at least 2.
+    pub fn new(producer: HeapProd<f32>, channels: usize, stats: Arc<CorrelationStats>) -> Self {
+        CorrelationTap {
+            producer,
+            channels,
+            stats,
+        }
+    }
+
+    /// Sends the block whole, as pairs of left and right samples, or not at all.
+    pub fn send(&mut self, data: &[f32]) {
+        let frames = data.len() / self.channels;
+        if self.producer.vacant_len() < frames * 2 {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let pairs = data
+            .chunks_exact(self.channels)
+            .flat_map(|frame| [frame[0], frame[1]]);
+        self.producer.push_iter(pairs);
+    }
+}
+
+/// Starts the thread metering the pairs of samples in `consumer`, and passing them on to the
+/// goniometer, until the producer is dropped and the buffer is empty.
+pub fn spawn(
+    mut consumer: HeapCons<f32>,
+    mut meter: Correlation,
+    stats: Arc<CorrelationStats>,
+    mut goniometer: Option<Goniometer>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut pairs = vec![0.0; 8_192];
+        loop {
+            // Whole pairs only, in case a block is still being pushed.
+            let available = (consumer.occupied_len() & !1).min(pairs.len());
+            let popped = consumer.pop_slice(&mut pairs[..available]);
+            if popped == 0 {
+                if !consumer.write_is_held() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(5));
+                continue;
+            }
+            for pair in pairs[..popped].chunks_exact(2) {
+                meter.push(pair[0], pair[1]);
+                if let Some(goniometer) = &mut goniometer {
+                    goniometer.push(pair[0], pair[1]);
+                }
+            }
+            stats.value.store(meter.value().unwrap_or(f32::NAN));
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ringbuf::traits::Split;
+    use ringbuf::HeapRb;
+
+    /// The coefficient of `frames` frames of `left` and `right`, given the frame index.
+    fn correlate(frames: usize, mut frame: impl FnMut(usize) -> [f32; 2]) -> Option<f32> {
+        let mut meter = Correlation::new(1_000);
+        for n in 0..frames {
+            let [left, right] = frame(n);
+            meter.push(left, right);
+        }
+        meter.value()
+    }
+
+    fn sine(n: usize, period: f32) -> f32 {
+        (std::f32::consts::TAU * n as f32 / period).sin()
+    }
+
+    #[test]
+    fn the_coefficient_goes_from_the_same_to_the_inverse() {
+        let same = correlate(3_500, |n| [sine(n, 50.0), 0.5 * sine(n, 50.0)]);
+        assert!((same.unwrap() - 1.0).abs() < 1e-6);
+        let inverse = correlate(3_500, |n| [sine(n, 50.0), -sine(n, 50.0)]);
+        assert!((inverse.unwrap() + 1.0).abs() < 1e-6);
+        // A quarter of a period apart, over whole periods.
+        let quadrature = correlate(3_500, |n| [sine(n, 40.0), sine(n + 10, 40.0)]);
+        assert!(quadrature.unwrap().abs() < 1e-4, "{:?}", quadrature);
+    }
+
+    #[test]
+    fn silence_has_no_coefficient() {
+        assert_eq!(correlate(100, |_| [0.0, 0.0]), None);
+        assert_eq!(correlate(0, |_| [0.0, 0.0]), None);
+        assert_eq!(correlate(100, |n| [sine(n, 50.0), 0.0]), Some(0.0));
+    }
+
+    #[test]
+    fn the_window_forgets_older_frames() {
+        // In phase, then inverted for longer than the window.
+        let value = correlate(5_000, |n| match n < 2_500 {
+            true => [sine(n, 50.0), sine(n, 50.0)],
+            false => [sine(n, 50.0), -sine(n, 50.0)],
+        });
+        assert!((value.unwrap() + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn inverted_channels_are_flagged() {
+        let stats = CorrelationStats::default();
+        assert_eq!(stats.describe(), ("corr: silent".to_string(), false));
+        stats.value.store(0.5);
+        assert_eq!(stats.describe(), ("corr: +0.50".to_string(), false));
+        stats.value.store(-0.404);
+        assert_eq!(stats.describe(), ("corr: -0.40".to_string(), true));
+    }
+
+    #[test]
+    fn the_thread_meters_the_first_two_channels_tapped() {
+        let (producer, consumer) = HeapRb::<f32>::new(256).split();
+        let stats = Arc::new(CorrelationStats::default());
+        let thread = spawn(consumer, Correlation::new(100), stats.clone(), None);
+        let mut tap = CorrelationTap::new(producer, 3, stats.clone());
+        for block in 0..20 {
+            // The third channel, which is left out, is unrelated.
+            let data: Vec<f32> = (0..32)
+                .flat_map(|n| {
+                    let x = sine(block * 32 + n, 20.0);
+                    [x, -x, 1.0]
+                })
+                .collect();
+            while tap.producer.vacant_len() < 64 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            tap.send(&data);
+        }
+        drop(tap);
+        thread.join().unwrap();
+        assert!((stats.value.load() + 1.0).abs() < 1e-6);
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 0);
+    }
+}
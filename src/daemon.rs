@@ -0,0 +1,550 @@
+//! Headless operation: runtime control over a Unix domain socket instead of the terminal.
+//!
+//! Clients send one JSON object per line, and get one back per line, e.g.
+//!
+//! ```text
+//! {"command":"set","param":"gain","value":-6}
+//! {"ok":true,"param":"gain.0.db","value":-6}
+//! ```
+//!
+//! The commands are `status`, `set` with a `param` and a `value`, `mute`, `unmute`, `preset`
+//! with the `path` of a preset file, `goniometer` with an optional `mode` of "ms" or "lr", `solo`
+//! with the `channel` of the first input to solo or un-solo, from 1, `band-solo` with the `band`
+//! to listen to alone, e.g. "450:1.0", or "off", and `shutdown`. Setting `output-device` or
+//! `input-device` to the name or index of a device moves the first output or input to it,
+//! answering before the move is done. Failures answer `"ok":false` with an `error` message. Each
+//! connection is served by its own thread, so a client that stays
+//! connected doesn't keep the others out.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{bail, Context};
+
+use crate::audition::{self, Band, BandSolo};
+use crate::automation::{self, Automation, Move};
+use crate::goniometer::{self, GoniometerFrame};
+use crate::json::Value;
+use crate::params::{ParamLayout, ParamWriter};
+use crate::routing::Solo;
+use crate::status::StatusLine;
+use crate::switch::{Direction, SwitchRequest};
+
+/// What the commands act on.
+pub struct Engine {
+    /// The parameter writers of the effect chains, one per output.
+    pub writers: Arc<Mutex<Vec<ParamWriter<Vec<f32>>>>>,
+    pub layout: ParamLayout,
+    pub muted: Arc<AtomicBool>,
+    pub status: Arc<StatusLine>,
+    pub goniometer: Option<Arc<GoniometerFrame>>,
+    /// Set to end the run.
+    pub stop: Arc<AtomicBool>,
+    /// Where moves to other devices are sent.
+    pub switches: Sender<SwitchRequest>,
+    /// The solos of the first input, if it has channels to solo.
+    pub solo: Option<Arc<Mutex<Solo>>>,
+    pub band_solo: Arc<BandSolo>,
+    pub start: Instant,
+}
+
+impl Engine {
+    /// Runs the request on one line, returning the response.
+    pub fn handle(&self, line: &str) -> Value {
+        let response = Value::parse(line)
+            .map_err(|x| format!("invalid request: {}", x))
+            .and_then(|request| self.run(&request));
+        match response {
+            Ok(mut response) => {
+                if let Value::Object(members) = &mut response {
+                    members.insert(0, ("ok".to_string(), Value::Bool(true)));
+                }
+                response
+            }
+            Err(message) => Value::object([("ok", false.into()), ("error", message.into())]),
+        }
+    }
+
+    fn run(&self, request: &Value) -> Result<Value, String> {
+        let command = request
+            .get("command")
+            .and_then(Value::as_str)
+            .ok_or("the request has no \"command\"")?;
+        let string = |key: &str| {
+            request
+                .get(key)
+                .and_then(Value::as_str)
+                .ok_or_else(|| format!("\"{}\" needs a \"{}\" string", command, key))
+        };
+        match command {
+            "status" => Ok(self.describe()),
+            "set" => {
+                let name = string("param")?;
+                let direction = match name {
+                    "output-device" => Some(Direction::Output),
+                    "input-device" => Some(Direction::Input),
+                    _ => None,
+                };
+                if let Some(direction) = direction {
+                    let device = string("value")?;
+                    let request = SwitchRequest {
+                        direction,
+                        device: device.to_string(),
+                    };
+                    self.switches
+                        .send(request)
+                        .map_err(|_| "the run is over".to_string())?;
+                    return Ok(Value::object([
+                        ("param", name.into()),
+                        ("value", device.into()),
+                    ]));
+                }
+                let value = request
+                    .get("value")
+                    .and_then(Value::as_f64)
+                    .ok_or("\"set\" needs a \"value\" number")?;
+                let param = self
+                    .layout
+                    .find(name)
+                    .ok_or_else(|| format!("unknown parameter \"{}\"", name))?;
+                let value = value as f32;
+                automation::publish(
+                    &self.writers,
+                    &[Move {
+                        time: 0.0,
+                        param,
+                        value,
+                    }],
+                );
+                Ok(Value::object([
+                    ("param", self.layout.names()[param].as_str().into()),
+                    ("value", (value as f64).into()),
+                ]))
+            }
+            "mute" | "unmute" => {
+                self.muted.store(command == "mute", Ordering::Relaxed);
+                Ok(Value::object([("muted", (command == "mute").into())]))
+            }
+            "preset" => {
+                let path = string("path")?;
+                let preset = Automation::load_preset(Path::new(path), &self.layout)
+                    .map_err(|x| format!("{:#}", x))?;
+                automation::publish(&self.writers, preset.moves());
+                Ok(Value::object([(
+                    "loaded",
+                    (preset.moves().len() as f64).into(),
+                )]))
+            }
+            "goniometer" => {
+                let frame = self
+                    .goniometer
+                    .as_ref()
+                    .ok_or("the goniometer is off, run with `--goniometer`")?;
+                let rotate = match request.get("mode").and_then(Value::as_str) {
+                    None | Some("ms") => true,
+                    Some("lr") => false,
+                    Some(mode) => return Err(format!("unknown goniometer mode \"{}\"", mode)),
+                };
+                let points = frame
+                    .points()
+                    .into_iter()
+                    .map(|x| {
+                        if rotate {
+                            goniometer::to_mid_side(x)
+                        } else {
+                            x
+                        }
+                    })
+                    .map(|[x, y]| Value::Array(vec![(x as f64).into(), (y as f64).into()]))
+                    .collect();
+                Ok(Value::object([("points", Value::Array(points))]))
+            }
+            "solo" => {
+                let solo = self
+                    .solo
+                    .as_ref()
+                    .ok_or("the first input has a single channel, with nothing to solo")?;
+                let channel = request
+                    .get("channel")
+                    .and_then(Value::as_f64)
+                    .filter(|x| x.fract() == 0.0 && *x >= 0.0)
+                    .ok_or("\"solo\" needs a \"channel\" number")?;
+                let mut solo = solo.lock().unwrap();
+                solo.toggle(channel as usize)?;
+                let soloed = solo.soloed().iter().map(|x| (*x as f64).into()).collect();
+                Ok(Value::object([("soloed", Value::Array(soloed))]))
+            }
+            "band-solo" => {
+                match string("band")? {
+                    "off" => self.band_solo.off(),
+                    band => self.band_solo.solo(band.parse::<Band>()?),
+                }
+                let band = match self.band_solo.is_on() {
+                    true => audition::describe(self.band_solo.band()).into(),
+                    false => Value::Null,
+                };
+                Ok(Value::object([("band", band)]))
+            }
+            "shutdown" => {
+                self.stop.store(true, Ordering::Relaxed);
+                Ok(Value::object([]))
+            }
+            _ => Err(format!("unknown command \"{}\"", command)),
+        }
+    }
+
+    fn describe(&self) -> Value {
+        let values = self
+            .writers
+            .lock()
+            .unwrap()
+            .first()
+            .map(|x| x.get().clone());
+        let params = self
+            .layout
+            .names()
+            .iter()
+            .zip(values.unwrap_or_default())
+            .map(|(name, value)| (name.clone(), Value::Number(value as f64)))
+            .collect();
+        Value::object([
+            ("uptime", self.start.elapsed().as_secs_f64().into()),
+            ("muted", self.muted.load(Ordering::Relaxed).into()),
+            ("status", self.status.line().into()),
+            ("params", Value::Object(params)),
+        ])
+    }
+
+    /// Answers the requests of one connection until the client closes it.
+    pub fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            writeln!(writer, "{}", self.handle(&line))?;
+        }
+        Ok(())
+    }
+}
+
+/// The listening socket, whose file is removed when it's dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Listens on `path` with permissions `mode`, e.g. 0o660, serving every connection on its
+    /// own thread. A socket file left over by a previous run is removed, but not one that
+    /// another instance still listens on.
+    pub fn bind(path: &Path, mode: u32, engine: Arc<Engine>) -> anyhow::Result<Self> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                bail!("\"{}\" exists and is not a socket", path.display());
+            }
+            if UnixStream::connect(path).is_ok() {
+                bail!("another instance is listening on \"{}\"", path.display());
+            }
+            fs::remove_file(path)
+                .with_context(|| format!("failed to remove the stale \"{}\"", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("cannot listen on \"{}\"", path.display()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set the permissions of \"{}\"", path.display()))?;
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let engine = engine.clone();
+                std::thread::spawn(move || engine.serve(stream));
+            }
+        });
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Sends one request to the instance listening on `path`, and returns its response.
+pub fn request(path: &Path, request: &Value) -> anyhow::Result<Value> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("cannot connect to \"{}\"", path.display()))?;
+    writeln!(stream, "{}", request)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Value::parse(&line).map_err(|x| anyhow::anyhow!("invalid response: {}", x))
+}
+
+/// Parses permissions in octal, e.g. "660".
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|x| *x <= 0o777)
+        .ok_or_else(|| {
+            format!(
+                "expected permissions in octal such as \"660\", got \"{}\"",
+                s
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use crate::params::{ParamReader, ParamStore};
+
+    /// An engine of a gain and a filter, with the reader of its one output.
+    fn engine() -> (Arc<Engine>, ParamReader<Vec<f32>>) {
+        let mut layout = ParamLayout::default();
+        layout.add("gain", 0, &["db"]);
+        layout.add("filter", 0, &["freq", "q"]);
+        let (writer, reader) = ParamStore::new(vec![0.0, 1_000.0, 0.7]).split();
+        let engine = Engine {
+            writers: Arc::new(Mutex::new(vec![writer])),
+            layout,
+            muted: Arc::new(AtomicBool::new(false)),
+            status: Arc::new(StatusLine::default()),
+            goniometer: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            switches: mpsc::channel().0,
+            solo: None,
+            band_solo: Arc::new(BandSolo::default()),
+            start: Instant::now(),
+        };
+        (Arc::new(engine), reader)
+    }
+
+    #[test]
+    fn setting_a_parameter_publishes_it() {
+        let (engine, mut reader) = engine();
+        let response = engine.handle(r#"{"command":"set","param":"gain","value":-6}"#);
+        assert_eq!(
+            response.to_string(),
+            r#"{"ok":true,"param":"gain.0.db","value":-6}"#
+        );
+        assert_eq!(reader.load().value(), &[-6.0, 1_000.0, 0.7]);
+
+        let status = engine.handle(r#"{"command":"status"}"#);
+        let params = status.get("params").unwrap().to_string();
+        assert_eq!(
+            params,
+            r#"{"gain.0.db":-6,"filter.0.freq":1000,"filter.0.q":0.699999988079071}"#
+        );
+        assert_eq!(status.get("muted").and_then(Value::as_bool), Some(false));
+    }
+
+    #[test]
+    fn bad_requests_answer_an_error() {
+        let (engine, _) = engine();
+        let error = |line: &str| {
+            let response = engine.handle(line);
+            assert_eq!(response.get("ok").and_then(Value::as_bool), Some(false));
+            response.get("error").unwrap().as_str().unwrap().to_string()
+        };
+        assert_eq!(error("{"), "invalid request: expected a key at byte 1");
+        assert_eq!(error("{}"), "the request has no \"command\"");
+        assert_eq!(error(r#"{"command":"fly"}"#), "unknown command \"fly\"");
+        assert_eq!(
+            error(r#"{"command":"set","value":1}"#),
+            "\"set\" needs a \"param\" string"
+        );
+        assert_eq!(
+            error(r#"{"command":"set","param":"gain"}"#),
+            "\"set\" needs a \"value\" number"
+        );
+        assert_eq!(
+            error(r#"{"command":"set","param":"reverb","value":1}"#),
+            "unknown parameter \"reverb\""
+        );
+    }
+
+    #[test]
+    fn presets_are_loaded_all_at_once() {
+        let (engine, mut reader) = engine();
+        let path = std::env::temp_dir().join(format!("preset-{}.csv", std::process::id()));
+        fs::write(&path, "# warm\ngain,-3\nfilter.freq,800\n").unwrap();
+        let request = Value::object([
+            ("command", "preset".into()),
+            ("path", path.to_string_lossy().as_ref().into()),
+        ]);
+        let response = engine.handle(&request.to_string());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(response.to_string(), r#"{"ok":true,"loaded":2}"#);
+        assert_eq!(reader.load().generation(), 1);
+        assert_eq!(reader.load().value(), &[-3.0, 800.0, 0.7]);
+    }
+
+    #[test]
+    fn muting_and_shutting_down_set_their_flags() {
+        let (engine, _) = engine();
+        let response = engine.handle(r#"{"command":"mute"}"#);
+        assert_eq!(response.to_string(), r#"{"ok":true,"muted":true}"#);
+        assert!(engine.muted.load(Ordering::Relaxed));
+        engine.handle(r#"{"command":"unmute"}"#);
+        assert!(!engine.muted.load(Ordering::Relaxed));
+
+        let response = engine.handle(r#"{"command":"shutdown"}"#);
+        assert_eq!(response.to_string(), r#"{"ok":true}"#);
+        assert!(engine.stop.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn clients_are_answered_over_the_socket() {
+        let (engine, _) = engine();
+        let path = std::env::temp_dir().join(format!("control-{}.sock", std::process::id()));
+        let socket = ControlSocket::bind(&path, 0o600, engine.clone()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mute = Value::object([("command", "mute".into())]);
+        let response = request(&path, &mute).unwrap();
+        assert_eq!(response.to_string(), r#"{"ok":true,"muted":true}"#);
+
+        let error = ControlSocket::bind(&path, 0o600, engine.clone())
+            .err()
+            .unwrap();
+        let expected = format!("another instance is listening on \"{}\"", path.display());
+        assert_eq!(error.to_string(), expected);
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn other_files_are_not_replaced() {
+        let (engine, _) = engine();
+        let path = std::env::temp_dir().join(format!("control-{}.txt", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let error = ControlSocket::bind(&path, 0o600, engine).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        let expected = format!("\"{}\" exists and is not a socket", path.display());
+        assert_eq!(error.to_string(), expected);
+    }
+
+    #[test]
+    fn modes_are_octal() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(
+            parse_mode("999"),
+            Err("expected permissions in octal such as \"660\", got \"999\"".to_string())
+        );
+        assert!(parse_mode("1777").is_err());
+    }
+
+    #[test]
+    fn the_goniometer_answers_its_points() {
+        let (engine, _) = engine();
+        let error = engine.handle(r#"{"command":"goniometer"}"#);
+        assert_eq!(
+            error.get("error").and_then(Value::as_str),
+            Some("the goniometer is off, run with `--goniometer`")
+        );
+
+        let frame = Arc::new(GoniometerFrame::default());
+        let mut goniometer = goniometer::Goniometer::new(20, frame.clone());
+        goniometer.push(0.5, 0.5);
+        let engine = Engine {
+            goniometer: Some(frame),
+            ..Arc::into_inner(engine).unwrap()
+        };
+        let response = engine.handle(r#"{"command":"goniometer","mode":"lr"}"#);
+        assert_eq!(response.to_string(), r#"{"ok":true,"points":[[0.5,0.5]]}"#);
+        let response = engine.handle(r#"{"command":"goniometer"}"#);
+        let [side, mid] = goniometer::to_mid_side([0.5, 0.5]).map(|x| x as f64);
+        let expected = format!(r#"{{"ok":true,"points":[[{},{}]]}}"#, side, mid);
+        assert_eq!(response.to_string(), expected);
+        let response = engine.handle(r#"{"command":"goniometer","mode":"xy"}"#);
+        assert_eq!(
+            response.get("error").and_then(Value::as_str),
+            Some("unknown goniometer mode \"xy\"")
+        );
+    }
+
+    #[test]
+    fn setting_a_device_requests_a_switch() {
+        let (engine, _) = engine();
+        let (switches, requests) = mpsc::channel();
+        let engine = Engine {
+            switches,
+            ..Arc::into_inner(engine).unwrap()
+        };
+        let response = engine.handle(r#"{"command":"set","param":"output-device","value":"2"}"#);
+        assert_eq!(
+            response.to_string(),
+            r#"{"ok":true,"param":"output-device","value":"2"}"#
+        );
+        let request = requests.try_recv().unwrap();
+        assert_eq!(
+            (request.direction, request.device.as_str()),
+            (Direction::Output, "2")
+        );
+
+        drop(requests);
+        let response = engine.handle(r#"{"command":"set","param":"input-device","value":"USB"}"#);
+        assert_eq!(
+            response.get("error").and_then(Value::as_str),
+            Some("the run is over")
+        );
+    }
+
+    #[test]
+    fn soloing_toggles_a_channel_of_the_first_input() {
+        let (engine, _) = engine();
+        let error = engine.handle(r#"{"command":"solo","channel":1}"#);
+        assert_eq!(
+            error.get("error").and_then(Value::as_str),
+            Some("the first input has a single channel, with nothing to solo")
+        );
+
+        let engine = Engine {
+            solo: Some(Arc::new(Mutex::new(Solo::new(2)))),
+            ..Arc::into_inner(engine).unwrap()
+        };
+        let response = engine.handle(r#"{"command":"solo","channel":2}"#);
+        assert_eq!(response.to_string(), r#"{"ok":true,"soloed":[2]}"#);
+        let response = engine.handle(r#"{"command":"solo","channel":2}"#);
+        assert_eq!(response.to_string(), r#"{"ok":true,"soloed":[]}"#);
+        let error = engine.handle(r#"{"command":"solo","channel":1.5}"#);
+        assert_eq!(
+            error.get("error").and_then(Value::as_str),
+            Some("\"solo\" needs a \"channel\" number")
+        );
+        let error = engine.handle(r#"{"command":"solo","channel":3}"#);
+        assert_eq!(
+            error.get("error").and_then(Value::as_str),
+            Some("no input channel 3 to solo, expected 1 to 2")
+        );
+    }
+
+    #[test]
+    fn band_solo_sets_the_band_or_turns_it_off() {
+        let (engine, _) = engine();
+        let response = engine.handle(r#"{"command":"band-solo","band":"450:2"}"#);
+        assert_eq!(response.to_string(), r#"{"ok":true,"band":"450 Hz Q 2.0"}"#);
+        assert!(engine.band_solo.is_on());
+        let response = engine.handle(r#"{"command":"band-solo","band":"off"}"#);
+        assert_eq!(response.to_string(), r#"{"ok":true,"band":null}"#);
+        assert!(!engine.band_solo.is_on());
+        let error = engine.handle(r#"{"command":"band-solo","band":"5"}"#);
+        assert_eq!(
+            error.get("error").and_then(Value::as_str),
+            Some("invalid band center \"5\", expected 20 to 20000 Hz")
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Protection against denormal numbers in the audio threads.
+//!
+//! When the input goes silent, feedback paths such as filter states decay towards zero and end up
+//! in the subnormal range, where x86 CPUs are many times slower. Two layers guard against it:
+//! [`protect_thread`] sets the flush-to-zero modes of the FPU on the calling thread where the
+//! target has them, and feedback paths call [`flush`] on their state, which also covers the other
+//! targets and code running with the default FPU modes.
+
+use std::cell::Cell;
+
+use crate::sample::Sample;
+
+/// Magnitude under which [`flush`] sets a value to zero, far below audibility at any bit depth
+/// but well above the subnormal range of f32.
+pub const THRESHOLD: f64 = 1e-30;
+
+thread_local! {
+    static PROTECTED: Cell<Option<bool>> = const { Cell::new(None) };
+}
+
+/// Enables flush-to-zero on the calling thread if it isn't already, returning whether the target
+/// supports it.
+///
+/// Cheap enough to call at the start of every audio callback, since backends don't always say on
+/// which thread they call back.
+pub fn protect_thread() -> bool {
+    PROTECTED.with(|protected| match protected.get() {
+        Some(supported) => supported,
+        None => {
+            // Safety: the flush-to-zero modes only change how subnormal values are rounded.
+            let supported = unsafe { enable_flush_to_zero() };
+            protected.set(Some(supported));
+            supported
+        }
+    })
+}
+
+/// Returns `value`, or zero if its magnitude is below [`THRESHOLD`].
+#[inline]
+pub fn flush<S: Sample>(value: S) -> S {
+    let threshold = S::from_sample(THRESHOLD);
+    if value < threshold && value > -threshold {
+        S::default()
+    } else {
+        value
+    }
+}
+
+/// Sets the FTZ (flush results to zero) and DAZ (treat inputs as zero) bits of MXCSR.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn enable_flush_to_zero() -> bool {
+    const FTZ: u32 = 1 << 15;
+    const DAZ: u32 = 1 << 6;
+    let mut mxcsr: u32 = 0;
+    std::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack));
+    mxcsr |= FTZ | DAZ;
+    std::arch::asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, readonly));
+    true
+}
+
+/// Sets the FZ bit of FPCR, which flushes both inputs and results.
+#[cfg(target_arch = "aarch64")]
+unsafe fn enable_flush_to_zero() -> bool {
+    const FZ: u64 = 1 << 24;
+    let mut fpcr: u64;
+    std::arch::asm!("mrs {}, fpcr", out(reg) fpcr, options(nomem, nostack));
+    fpcr |= FZ;
+    std::arch::asm!("msr fpcr, {}", in(reg) fpcr, options(nomem, nostack));
+    true
+}
+
+/// Other targets rely on [`flush`] alone.
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+unsafe fn enable_flush_to_zero() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_below_the_threshold_flush_to_zero() {
+        assert_eq!(flush(1e-31f32), 0.0);
+        assert_eq!(flush(-1e-31f64), 0.0);
+        assert_eq!(flush(f32::MIN_POSITIVE / 2.0), 0.0);
+        assert_eq!(flush(1e-29f32), 1e-29);
+        assert_eq!(flush(-0.5f64), -0.5);
+    }
+
+    #[test]
+    fn protected_threads_flush_subnormal_results() {
+        std::thread::spawn(|| {
+            let supported = protect_thread();
+            assert_eq!(protect_thread(), supported);
+            let tiny = std::hint::black_box(f32::MIN_POSITIVE);
+            let product = tiny * std::hint::black_box(0.5f32);
+            if supported {
+                assert_eq!(product, 0.0);
+            } else {
+                assert!(product.is_subnormal());
+            }
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,552 @@
+//! Device lookup by name or index.
+//!
+//! Names match exactly or as a unique case-insensitive substring. When no device matches at all,
+//! the closest names are offered instead:
+//! interactively as a numbered picker if stdin is a terminal, or in the error message otherwise.
+//!
+//! Indices refer to the enumeration order printed by `--list-devices`.
+//!
+//! Hosts, the audio APIs such as JACK or WASAPI, are named by `--driver` among those cpal finds
+//! available at runtime, which `--list-drivers` prints.
+
+use std::io::{BufRead, IsTerminal, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::HostId;
+
+use crate::error::EngineError;
+
+/// Maximum number of candidates offered when a device name isn't found.
+const MAX_SUGGESTIONS: usize = 5;
+
+/// Whether names that aren't found may be picked from the suggestions on the terminal.
+static PICKER: AtomicBool = AtomicBool::new(true);
+
+/// How a device is chosen on the command line.
+#[derive(Clone, Debug)]
+pub enum DeviceSelector {
+    /// The host's default device: `default`.
+    Default,
+    /// The Nth enumerated device: `#3` or `3`.
+    Index(usize),
+    /// The device with this name.
+    Name(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "default" {
+            return Ok(DeviceSelector::Default);
+        }
+        let digits = s.strip_prefix('#').unwrap_or(s);
+        if !digits.is_empty() && digits.bytes().all(|x| x.is_ascii_digit()) {
+            return digits
+                .parse()
+                .map(DeviceSelector::Index)
+                .map_err(|_| format!("invalid device index \"{}\"", s));
+        }
+        if digits.len() != s.len() {
+            return Err(format!("invalid device index \"{}\"", s));
+        }
+        Ok(DeviceSelector::Name(s.to_string()))
+    }
+}
+
+/// The host `driver` names, case-insensitively, e.g. "jack", or the default host for "default".
+pub fn resolve_host(driver: &str) -> Result<cpal::Host, EngineError> {
+    let available = cpal::available_hosts();
+    match resolve_host_id(driver, &available)? {
+        Some(id) => cpal::host_from_id(id).map_err(|_| host_unavailable(driver, &available)),
+        None => Ok(cpal::default_host()),
+    }
+}
+
+/// The host among `available` that `driver` names, or `None` for the default host.
+pub fn resolve_host_id(driver: &str, available: &[HostId]) -> Result<Option<HostId>, EngineError> {
+    if driver.eq_ignore_ascii_case("default") {
+        return Ok(None);
+    }
+    match available
+        .iter()
+        .find(|x| x.name().eq_ignore_ascii_case(driver))
+    {
+        Some(id) => Ok(Some(*id)),
+        None => Err(host_unavailable(driver, available)),
+    }
+}
+
+fn host_unavailable(driver: &str, available: &[HostId]) -> EngineError {
+    EngineError::HostUnavailable {
+        name: driver.to_string(),
+        available: available.iter().map(|x| x.name().to_lowercase()).collect(),
+    }
+}
+
+/// Prints the hosts available on this machine, by the names `--driver` takes.
+pub fn list_drivers() {
+    let default = cpal::default_host().id();
+    println!("Drivers:");
+    for id in cpal::available_hosts() {
+        let marker = if id == default { " (default)" } else { "" };
+        println!("  {}{}", id.name().to_lowercase(), marker);
+    }
+}
+
+/// Prints the input and output devices of `host` with the indices accepted by the selectors.
+pub fn list_devices(host: &cpal::Host) -> Result<(), EngineError> {
+    let default_input = host.default_input_device().and_then(|x| x.name().ok());
+    let default_output = host.default_output_device().and_then(|x| x.name().ok());
+
+    println!("Input devices:");
+    print_devices(&enumerate(host.input_devices()?), default_input.as_deref());
+    println!("Output devices:");
+    print_devices(
+        &enumerate(host.output_devices()?),
+        default_output.as_deref(),
+    );
+    Ok(())
+}
+
+/// Finds the input device matching `selector` on `host`.
+pub fn input_device(
+    host: &cpal::Host,
+    selector: &DeviceSelector,
+) -> Result<cpal::Device, EngineError> {
+    match selector {
+        DeviceSelector::Default => match host.default_input_device() {
+            Some(device) => Ok(device),
+            None => Err(no_default(host.input_devices()?, "input")),
+        },
+        selector => find_device(host.input_devices()?, selector, "input"),
+    }
+}
+
+/// Finds the output device matching `selector` on `host`.
+pub fn output_device(
+    host: &cpal::Host,
+    selector: &DeviceSelector,
+) -> Result<cpal::Device, EngineError> {
+    match selector {
+        DeviceSelector::Default => match host.default_output_device() {
+            Some(device) => Ok(device),
+            None => Err(no_default(host.output_devices()?, "output")),
+        },
+        selector => find_device(host.output_devices()?, selector, "output"),
+    }
+}
+
+/// The error for a host without a default device, listing the others.
+fn no_default(devices: impl Iterator<Item = cpal::Device>, kind: &'static str) -> EngineError {
+    EngineError::DeviceNotFound {
+        kind,
+        name: "default".to_string(),
+        candidates: enumerate(devices).into_iter().map(|(x, _)| x).collect(),
+        closest: false,
+    }
+}
+
+/// Finds the device matching `selector` among `devices`, which must not be the default selector.
+///
+/// Names are resolved with [`resolve_name`], and names that aren't found at all fall back to
+/// fuzzy suggestions. `kind` is only used in messages,
+/// e.g. "input" or "output".
+pub fn find_device(
+    devices: impl Iterator<Item = cpal::Device>,
+    selector: &DeviceSelector,
+    kind: &'static str,
+) -> Result<cpal::Device, EngineError> {
+    let mut devices = enumerate(devices);
+    let name = match selector {
+        DeviceSelector::Default => {
+            return Err(EngineError::InvalidArgument(format!(
+                "the default {} device can't be looked up by name",
+                kind
+            )))
+        }
+        DeviceSelector::Index(index) => {
+            let index = check_index(*index, devices.len(), kind)?;
+            return Ok(devices.swap_remove(index).1);
+        }
+        DeviceSelector::Name(name) => name,
+    };
+    let names: Vec<&str> = devices.iter().map(|(x, _)| x.as_str()).collect();
+    match resolve_name(name, &names) {
+        NameResolution::Exact(index) => return Ok(devices.swap_remove(index).1),
+        NameResolution::Substring(index) => {
+            tracing::info!(
+                device = names[index],
+                "matched the {} device \"{}\" to \"{}\"",
+                kind,
+                name,
+                names[index]
+            );
+            return Ok(devices.swap_remove(index).1);
+        }
+        NameResolution::Ambiguous(indices) => {
+            return Err(EngineError::InvalidArgument(format!(
+                "{} device \"{}\" is ambiguous, it matches {}; use a longer name or an index",
+                kind,
+                name,
+                indices
+                    .iter()
+                    .map(|&i| format!("#{} \"{}\"", i, names[i]))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )))
+        }
+        NameResolution::NotFound => {}
+    }
+
+    let suggestions = closest_matches(name, &names);
+    let not_found = |candidates: Vec<usize>, closest| EngineError::DeviceNotFound {
+        kind,
+        name: name.clone(),
+        candidates: candidates
+            .into_iter()
+            .map(|i| names[i].to_string())
+            .collect(),
+        closest,
+    };
+    if suggestions.is_empty() {
+        return Err(not_found((0..names.len()).collect(), false));
+    }
+
+    if !PICKER.load(Ordering::Relaxed) || !std::io::stdin().is_terminal() {
+        return Err(not_found(suggestions, true));
+    }
+
+    let Some(choice) = pick(name, kind, suggestions.iter().map(|&i| names[i]))? else {
+        return Err(not_found(suggestions, true));
+    };
+    Ok(devices.swap_remove(suggestions[choice]).1)
+}
+
+/// Stops offering the picker, e.g. once the terminal reads commands instead.
+pub fn disable_picker() {
+    PICKER.store(false, Ordering::Relaxed);
+}
+
+/// Outcome of looking up a device name among the enumerated devices.
+#[derive(Debug, PartialEq)]
+pub enum NameResolution {
+    /// Exactly one device has this name.
+    Exact(usize),
+    /// Exactly one device contains the name, ignoring case.
+    Substring(usize),
+    /// Several devices match equally well.
+    Ambiguous(Vec<usize>),
+    /// No device matches.
+    NotFound,
+}
+
+/// Looks up `query` among `names`, by exact name first and then by case-insensitive substring.
+///
+/// When several names contain the query but exactly one of them equals it ignoring case, that one
+/// wins, so that "usb audio" still selects "USB Audio" next to "USB Audio Device". Identical names
+/// are ambiguous: only an index can tell them apart.
+pub fn resolve_name(query: &str, names: &[&str]) -> NameResolution {
+    let exact: Vec<usize> = matching(names, |x| x == query);
+    match exact.len() {
+        0 => {}
+        1 => return NameResolution::Exact(exact[0]),
+        _ => return NameResolution::Ambiguous(exact),
+    }
+
+    let query = query.to_lowercase();
+    let substring = matching(names, |x| x.to_lowercase().contains(&query));
+    let equal = matching(names, |x| x.to_lowercase() == query);
+    match (substring.len(), equal.len()) {
+        (0, _) => NameResolution::NotFound,
+        (1, _) => NameResolution::Substring(substring[0]),
+        (_, 1) => NameResolution::Substring(equal[0]),
+        (_, 0) => NameResolution::Ambiguous(substring),
+        _ => NameResolution::Ambiguous(equal),
+    }
+}
+
+fn matching(names: &[&str], predicate: impl Fn(&str) -> bool) -> Vec<usize> {
+    (0..names.len()).filter(|&i| predicate(names[i])).collect()
+}
+
+/// Validates a device index against the number of enumerated devices.
+fn check_index(index: usize, count: usize, kind: &'static str) -> Result<usize, EngineError> {
+    match index < count {
+        true => Ok(index),
+        false => Err(EngineError::DeviceIndexOutOfRange { kind, index, count }),
+    }
+}
+
+/// Returns the indices of the names closest to `query`, best match first.
+///
+/// Matching is case-insensitive. A name is a candidate if it contains `query` as a substring,
+/// allowing a small number of edits proportional to the length of the query, so that typos in
+/// a partial name (e.g. "scralett" for "Focusrite Scarlett 2i2 USB") are still found.
+pub fn closest_matches(query: &str, names: &[&str]) -> Vec<usize> {
+    let query: Vec<char> = query.to_lowercase().chars().collect();
+    let tolerance = query.len() / 4;
+
+    let mut candidates: Vec<(usize, usize, usize)> = names
+        .iter()
+        .enumerate()
+        .filter_map(|(index, name)| {
+            let name: Vec<char> = name.to_lowercase().chars().collect();
+            let distance = edit_distance(&query, &name, true);
+            (distance <= tolerance).then(|| (distance, edit_distance(&query, &name, false), index))
+        })
+        .collect();
+    candidates.sort_unstable();
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, _, index)| index)
+        .collect()
+}
+
+/// Levenshtein distance between `query` and `text`.
+///
+/// If `within` is set, `query` may match anywhere inside `text` for free, i.e. this is the
+/// distance between `query` and the closest substring of `text`.
+fn edit_distance(query: &[char], text: &[char], within: bool) -> usize {
+    let mut previous: Vec<usize> = (0..=text.len())
+        .map(|j| if within { 0 } else { j })
+        .collect();
+    let mut current = vec![0; text.len() + 1];
+    for (i, q) in query.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, t) in text.iter().enumerate() {
+            let substitution = previous[j] + usize::from(q != t);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    if within {
+        previous.into_iter().min().unwrap_or(0)
+    } else {
+        previous[text.len()]
+    }
+}
+
+/// Asks the user to choose one of `candidates` on stdin, returning its position, or `None` if
+/// they chose none.
+fn pick<'a>(
+    name: &str,
+    kind: &str,
+    candidates: impl Iterator<Item = &'a str>,
+) -> Result<Option<usize>, EngineError> {
+    println!("No {} device named \"{}\". Did you mean:", kind, name);
+    let mut count = 0;
+    for (position, candidate) in candidates.enumerate() {
+        println!("  {}) {}", position + 1, candidate);
+        count += 1;
+    }
+    print!("Select a device [1-{}], or press Enter to abort: ", count);
+    std::io::stdout().flush()?;
+
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    match line.parse::<usize>() {
+        Ok(choice) if (1..=count).contains(&choice) => Ok(Some(choice - 1)),
+        _ => Err(EngineError::InvalidArgument(format!(
+            "invalid selection \"{}\"",
+            line
+        ))),
+    }
+}
+
+/// Pairs devices with their names, in enumeration order.
+///
+/// Devices whose name can't be read are skipped, both here and in `--list-devices`, so that
+/// indices stay consistent between the two.
+fn enumerate(devices: impl Iterator<Item = cpal::Device>) -> Vec<(String, cpal::Device)> {
+    devices
+        .filter_map(|device| device.name().ok().map(|x| (x, device)))
+        .collect()
+}
+
+fn print_devices(devices: &[(String, cpal::Device)], default: Option<&str>) {
+    if devices.is_empty() {
+        println!("  (none)");
+    }
+    for (index, (name, _)) in devices.iter().enumerate() {
+        let marker = if Some(name.as_str()) == default {
+            " (default)"
+        } else {
+            ""
+        };
+        println!("  #{} \"{}\"{}", index, name, marker);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device names as ALSA, WASAPI and JACK report them.
+    const ALSA: [&str; 6] = [
+        "default",
+        "pipewire",
+        "hw:CARD=PCH,DEV=0",
+        "plughw:CARD=PCH,DEV=0",
+        "hw:CARD=USB,DEV=0",
+        "sysdefault:CARD=USB",
+    ];
+    const WASAPI: [&str; 4] = [
+        "Speakers (Realtek(R) Audio)",
+        "Microphone (Realtek(R) Audio)",
+        "Line 1/2 (Focusrite Scarlett 2i2 USB)",
+        "Realtek Digital Output",
+    ];
+    const JACK: [&str; 2] = ["cpal_client_in", "cpal_client_out"];
+
+    #[test]
+    fn selectors_parse_indices_with_or_without_a_hash() {
+        let parse = |x: &str| x.parse::<DeviceSelector>();
+        assert!(matches!(parse("default"), Ok(DeviceSelector::Default)));
+        assert!(matches!(parse("3"), Ok(DeviceSelector::Index(3))));
+        assert!(matches!(parse("#12"), Ok(DeviceSelector::Index(12))));
+        assert!(
+            matches!(parse("hw:CARD=USB,DEV=0"), Ok(DeviceSelector::Name(x)) if x == "hw:CARD=USB,DEV=0")
+        );
+        // Names that only start with digits are names.
+        assert!(matches!(parse("2i2 USB"), Ok(DeviceSelector::Name(_))));
+        assert_eq!(parse("#").unwrap_err(), "invalid device index \"#\"");
+        assert_eq!(parse("#2i2").unwrap_err(), "invalid device index \"#2i2\"");
+        assert!(parse("99999999999999999999999").is_err());
+    }
+
+    #[test]
+    fn indices_past_the_devices_are_out_of_range() {
+        assert_eq!(check_index(2, 3, "input").unwrap(), 2);
+        let err = check_index(3, 3, "input").unwrap_err();
+        assert!(matches!(
+            err,
+            EngineError::DeviceIndexOutOfRange {
+                index: 3,
+                count: 3,
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "input device #3 is out of range: valid indices are #0 to #2 (see --list-devices)"
+        );
+        let err = check_index(0, 0, "output").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "output device #0 is out of range: there are no output devices"
+        );
+    }
+
+    use NameResolution::{Ambiguous, Exact, NotFound, Substring};
+
+    #[test]
+    fn exact_names_win_over_substrings() {
+        assert_eq!(resolve_name("default", &ALSA), Exact(0));
+        assert_eq!(resolve_name("hw:CARD=PCH,DEV=0", &ALSA), Exact(2));
+        assert_eq!(resolve_name("cpal_client_in", &JACK), Exact(0));
+    }
+
+    #[test]
+    fn unique_substrings_match_ignoring_case() {
+        assert_eq!(resolve_name("scarlett", &WASAPI), Substring(2));
+        assert_eq!(resolve_name("PIPE", &ALSA), Substring(1));
+        assert_eq!(resolve_name("digital", &WASAPI), Substring(3));
+    }
+
+    #[test]
+    fn a_name_equal_ignoring_case_wins_over_longer_ones() {
+        let names = ["USB Audio Device", "USB Audio", "USB Audio CODEC"];
+        assert_eq!(resolve_name("usb audio", &names), Substring(1));
+        assert_eq!(resolve_name("usb", &names), Ambiguous(vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn several_matches_are_ambiguous() {
+        assert_eq!(resolve_name("realtek", &WASAPI), Ambiguous(vec![0, 1, 3]));
+        assert_eq!(resolve_name("CARD=USB", &ALSA), Ambiguous(vec![4, 5]));
+        assert_eq!(resolve_name("cpal_client", &JACK), Ambiguous(vec![0, 1]));
+        // Identical names can only be told apart by index.
+        let names = ["USB Audio", "USB Audio"];
+        assert_eq!(resolve_name("USB Audio", &names), Ambiguous(vec![0, 1]));
+        assert_eq!(resolve_name("usb audio", &names), Ambiguous(vec![0, 1]));
+    }
+
+    #[test]
+    fn names_no_device_contains_are_not_found() {
+        assert_eq!(resolve_name("scralett", &WASAPI), NotFound);
+        assert_eq!(resolve_name("anything", &[]), NotFound);
+    }
+
+    #[test]
+    fn typos_in_part_of_a_name_are_found() {
+        assert_eq!(closest_matches("scralett", &WASAPI), [2]);
+        assert_eq!(closest_matches("Focusrit Scarlet", &WASAPI), [2]);
+        assert_eq!(closest_matches("CARD=UBS", &ALSA), [4, 5]);
+        assert_eq!(closest_matches("cpal_clinet", &JACK), [0, 1]);
+    }
+
+    #[test]
+    fn the_closest_name_comes_first() {
+        // All of them contain "realtek": the one that differs least as a whole leads.
+        assert_eq!(closest_matches("realtek", &WASAPI), [3, 0, 1]);
+        assert_eq!(closest_matches("hw:card=pch", &ALSA), [2, 3]);
+    }
+
+    #[test]
+    fn names_too_far_from_any_offer_nothing() {
+        assert!(closest_matches("behringer", &WASAPI).is_empty());
+        // Short queries allow no edits at all.
+        assert!(closest_matches("usx", &ALSA).is_empty());
+        assert_eq!(closest_matches("usb", &ALSA), [4, 5]);
+    }
+
+    #[test]
+    fn at_most_a_handful_of_names_are_offered() {
+        let names: Vec<String> = (0..8).map(|x| format!("hw:CARD=USB,DEV={}", x)).collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        assert_eq!(closest_matches("card=usb", &names).len(), MAX_SUGGESTIONS);
+    }
+
+    #[test]
+    fn edit_distances_count_insertions_deletions_and_substitutions() {
+        let chars = |x: &str| x.chars().collect::<Vec<char>>();
+        assert_eq!(edit_distance(&chars("kitten"), &chars("sitting"), false), 3);
+        assert_eq!(edit_distance(&chars("usb"), &chars("a usb mic"), true), 0);
+        assert_eq!(edit_distance(&chars("usb"), &chars("a usb mic"), false), 6);
+        assert_eq!(edit_distance(&chars(""), &chars("mic"), false), 3);
+    }
+
+    #[test]
+    fn drivers_are_named_ignoring_case() {
+        let id = cpal::ALL_HOSTS[0];
+        let name = id.name().to_uppercase();
+        assert_eq!(resolve_host_id(&name, &[id]).unwrap(), Some(id));
+        assert_eq!(resolve_host_id("Default", &[id]).unwrap(), None);
+    }
+
+    #[test]
+    fn drivers_not_available_list_the_others() {
+        let id = cpal::ALL_HOSTS[0];
+        let err = resolve_host_id("asio-ish", &[id]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "the \"asio-ish\" driver isn't available; available drivers: \"default\", \"{}\"",
+                id.name().to_lowercase()
+            )
+        );
+        let err = resolve_host_id("asio-ish", &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the \"asio-ish\" driver isn't available; there are no drivers"
+        );
+    }
+}
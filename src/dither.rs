@@ -0,0 +1,161 @@
+//! Dithering of the conversion of f32 samples to 16-bit integers.
+//!
+//! Rounding a quiet signal to 16 bits gives an error that follows the signal, heard as
+//! distortion, e.g. on the tail of a reverb. TPDF dither adds the sum of two independent uniform
+//! noises of 1 LSB each before rounding, which makes the error plain noise, independent of the
+//! signal, at the cost of a little more of it. Noise shaping also feeds each sample's error back
+//! into the next one, moving the noise up to the treble, where hearing is less sensitive.
+//!
+//! 24-bit and float conversions have noise far below anything audible, so they aren't dithered.
+
+/// Scale of a full-scale sample in 16 bits.
+const SCALE: f32 = 32_768.0;
+const MIN: f32 = i16::MIN as f32;
+const MAX: f32 = i16::MAX as f32;
+
+/// How the conversion to 16 bits treats the rounding error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DitherMode {
+    /// Plain rounding.
+    Off,
+    /// TPDF dither.
+    Tpdf,
+    /// TPDF dither with first-order noise shaping.
+    Shaped,
+}
+
+/// Converts interleaved f32 samples to 16 bits, with the state the dither keeps across blocks.
+pub struct Dither {
+    mode: DitherMode,
+    /// State of the xorshift generator of the noise.
+    random: u32,
+    /// The last rounding error of each channel, in LSBs, for the noise shaping.
+    errors: Vec<f32>,
+}
+
+impl Dither {
+    pub fn new(mode: DitherMode, channels: usize) -> Self {
+        Dither {
+            mode,
+            random: 0x9e37_79b9,
+            errors: vec![0.0; channels.max(1)],
+        }
+    }
+
+    pub fn mode(&self) -> DitherMode {
+        self.mode
+    }
+
+    /// Converts one sample of `channel`, clipping it to full scale.
+    pub fn quantize(&mut self, x: f32, channel: usize) -> i16 {
+        let scaled = x * SCALE;
+        let value = match self.mode {
+            DitherMode::Off => scaled,
+            DitherMode::Tpdf => scaled + self.triangular(),
+            DitherMode::Shaped => {
+                let target = scaled - self.errors[channel];
+                let value = target + self.triangular();
+                // The error leaves out clipping, which the next samples can't make up for.
+                let rounded = value.round().clamp(MIN, MAX);
+                self.errors[channel] = rounded - target.clamp(MIN, MAX);
+                return rounded as i16;
+            }
+        };
+        value.round().clamp(MIN, MAX) as i16
+    }
+
+    /// Converts interleaved samples, over the length of the shorter slice.
+    pub fn convert(&mut self, source: &[f32], data: &mut [i16]) {
+        let channels = self.errors.len();
+        for (index, (x, y)) in source.iter().zip(data).enumerate() {
+            *y = self.quantize(*x, index % channels);
+        }
+    }
+
+    /// Noise from -1 to 1 LSB, densest at 0.
+    fn triangular(&mut self) -> f32 {
+        self.uniform() + self.uniform()
+    }
+
+    /// Noise from -0.5 to 0.5 LSB.
+    fn uniform(&mut self) -> f32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        (self.random >> 8) as f32 / (1 << 24) as f32 - 0.5
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The error of converting `x` `count` times, in LSBs.
+    fn errors(mode: DitherMode, x: f32, count: usize) -> Vec<f32> {
+        let mut dither = Dither::new(mode, 1);
+        let source = vec![x; count];
+        let mut data = vec![0; count];
+        dither.convert(&source, &mut data);
+        data.iter().map(|y| *y as f32 - x * SCALE).collect()
+    }
+
+    fn mean(x: &[f32]) -> f32 {
+        x.iter().sum::<f32>() / x.len() as f32
+    }
+
+    #[test]
+    fn plain_rounding_clips_to_full_scale() {
+        let mut dither = Dither::new(DitherMode::Off, 1);
+        assert_eq!(dither.quantize(0.5, 0), 16_384);
+        assert_eq!(dither.quantize(-1.0, 0), i16::MIN);
+        assert_eq!(dither.quantize(1.0, 0), i16::MAX);
+        assert_eq!(dither.quantize(-3.0, 0), i16::MIN);
+        assert_eq!(dither.quantize(0.2 / SCALE, 0), 0);
+    }
+
+    #[test]
+    fn dither_keeps_levels_below_one_lsb() {
+        // Rounding loses a signal of 0.3 LSB entirely, while dither keeps it on average.
+        let x = 0.3 / SCALE;
+        assert!(errors(DitherMode::Off, x, 1_000).iter().all(|e| *e == -0.3));
+        for mode in [DitherMode::Tpdf, DitherMode::Shaped] {
+            let errors = errors(mode, x, 100_000);
+            assert!(mean(&errors).abs() < 0.02, "{:?}: {}", mode, mean(&errors));
+        }
+        let errors = errors(DitherMode::Tpdf, x, 100_000);
+        assert!(errors.iter().all(|e| e.abs() <= 1.5));
+    }
+
+    #[test]
+    fn shaping_moves_the_noise_out_of_the_bass() {
+        // The power of the error summed over 16 samples, a crude low-pass.
+        let low = |mode| {
+            let errors = errors(mode, 0.123_4, 64_000);
+            let sums: Vec<f32> = errors.chunks(16).map(|x| x.iter().sum()).collect();
+            mean(&sums.iter().map(|x| x * x).collect::<Vec<_>>())
+        };
+        let tpdf = low(DitherMode::Tpdf);
+        let shaped = low(DitherMode::Shaped);
+        assert!(shaped < tpdf / 4.0, "{} against {}", shaped, tpdf);
+    }
+
+    #[test]
+    fn channels_are_shaped_apart() {
+        let mut dither = Dither::new(DitherMode::Shaped, 2);
+        let source: Vec<f32> = (0..20_000)
+            .map(|x| {
+                if x % 2 == 0 {
+                    0.25 / SCALE
+                } else {
+                    -0.75 / SCALE
+                }
+            })
+            .collect();
+        let mut data = vec![0; source.len()];
+        dither.convert(&source, &mut data);
+        let left: Vec<f32> = data.iter().step_by(2).map(|x| *x as f32).collect();
+        let right: Vec<f32> = data.iter().skip(1).step_by(2).map(|x| *x as f32).collect();
+        assert!((mean(&left) - 0.25).abs() < 0.01, "{}", mean(&left));
+        assert!((mean(&right) + 0.75).abs() < 0.01, "{}", mean(&right));
+    }
+}
@@ -0,0 +1,231 @@
+//! Downmixing of multichannel inputs to stereo, e.g. 5.1 from an HDMI capture.
+//!
+//! Inputs are in the WAVE channel order: front left, front right, centre, LFE, then the
+//! surrounds. The centre goes to both sides at -3 dB, and each surround to its side at -3 dB,
+//! as in ITU-R BS.775. The LFE is left out unless it's given a gain. The coefficients are then
+//! scaled down together until neither side can clip, whatever the input.
+
+use std::str::FromStr;
+
+use crate::level;
+
+/// Gain of the centre and surround channels in each side, -3 dB.
+const SIDE_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// A multichannel layout, by its usual name on the command line: "quad", "5.0", "5.1" or "7.1".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// Left, right, surround left and surround right.
+    Quad,
+    /// Left, right, centre, surround left and surround right.
+    FiveZero,
+    /// Left, right, centre, LFE, surround left and surround right.
+    FiveOne,
+    /// Left, right, centre, LFE, back left and right, and side left and right.
+    SevenOne,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "quad" | "4.0" => Ok(Layout::Quad),
+            "5.0" => Ok(Layout::FiveZero),
+            "5.1" => Ok(Layout::FiveOne),
+            "7.1" => Ok(Layout::SevenOne),
+            _ => Err(format!(
+                "unknown channel layout \"{}\", expected quad, 5.0, 5.1 or 7.1",
+                s
+            )),
+        }
+    }
+}
+
+/// What a channel carries, which sets its gains in the sides.
+#[derive(Clone, Copy)]
+enum Role {
+    Left,
+    Right,
+    Centre,
+    Lfe,
+    SurroundLeft,
+    SurroundRight,
+}
+
+impl Layout {
+    fn roles(self) -> &'static [Role] {
+        use Role::*;
+        match self {
+            Layout::Quad => &[Left, Right, SurroundLeft, SurroundRight],
+            Layout::FiveZero => &[Left, Right, Centre, SurroundLeft, SurroundRight],
+            Layout::FiveOne => &[Left, Right, Centre, Lfe, SurroundLeft, SurroundRight],
+            Layout::SevenOne => &[
+                Left,
+                Right,
+                Centre,
+                Lfe,
+                SurroundLeft,
+                SurroundRight,
+                SurroundLeft,
+                SurroundRight,
+            ],
+        }
+    }
+
+    pub fn channels(self) -> usize {
+        self.roles().len()
+    }
+}
+
+/// The gains of every input channel in the left and right outputs.
+#[derive(Clone, Debug)]
+pub struct Downmix {
+    coefficients: Vec<[f32; 2]>,
+}
+
+impl Downmix {
+    /// The downmix of `layout`, with the LFE at `lfe_db` in both sides, or left out.
+    pub fn new(layout: Layout, lfe_db: Option<f32>) -> Self {
+        let lfe = lfe_db.map_or(0.0, level::db_to_gain);
+        let coefficients = layout
+            .roles()
+            .iter()
+            .map(|role| match role {
+                Role::Left => [1.0, 0.0],
+                Role::Right => [0.0, 1.0],
+                Role::Centre => [SIDE_GAIN, SIDE_GAIN],
+                Role::Lfe => [lfe, lfe],
+                Role::SurroundLeft => [SIDE_GAIN, 0.0],
+                Role::SurroundRight => [0.0, SIDE_GAIN],
+            })
+            .collect();
+        Downmix::normalized(coefficients)
+    }
+
+    /// Every one of `channels` channels in both sides alike, for layouts that aren't known.
+    pub fn equal(channels: usize) -> Self {
+        Downmix::normalized(vec![[1.0, 1.0]; channels.max(1)])
+    }
+
+    /// Scales `coefficients` so that the gains of each side add up to 1 at most: a side can
+    /// then only reach full scale if every channel in it does.
+    fn normalized(mut coefficients: Vec<[f32; 2]>) -> Self {
+        let sum = |side: usize| coefficients.iter().map(|x| x[side].abs()).sum::<f32>();
+        let scale = sum(0).max(sum(1));
+        if scale > 1.0 {
+            for gains in &mut coefficients {
+                *gains = gains.map(|x| x / scale);
+            }
+        }
+        Downmix { coefficients }
+    }
+
+    /// Channels of the frames it mixes.
+    pub fn channels(&self) -> usize {
+        self.coefficients.len()
+    }
+
+    /// The gains of each input channel in the left and right outputs.
+    pub fn coefficients(&self) -> &[[f32; 2]] {
+        &self.coefficients
+    }
+
+    /// Appends the stereo frames of the interleaved `input` to `output`.
+    pub fn process(&self, input: &[f32], output: &mut Vec<f32>) {
+        for frame in input.chunks_exact(self.coefficients.len()) {
+            let (mut left, mut right) = (0.0, 0.0);
+            for (x, [to_left, to_right]) in frame.iter().zip(&self.coefficients) {
+                left += x * to_left;
+                right += x * to_right;
+            }
+            output.extend_from_slice(&[left, right]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mix(downmix: &Downmix, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        downmix.process(input, &mut output);
+        output
+    }
+
+    #[test]
+    fn layouts_are_parsed_by_their_names() {
+        assert_eq!("4.0".parse(), Ok(Layout::Quad));
+        assert_eq!("5.1".parse::<Layout>().map(Layout::channels), Ok(6));
+        assert_eq!("7.1".parse::<Layout>().map(Layout::channels), Ok(8));
+        assert_eq!(
+            "6.1".parse::<Layout>(),
+            Err("unknown channel layout \"6.1\", expected quad, 5.0, 5.1 or 7.1".to_string())
+        );
+    }
+
+    #[test]
+    fn centre_and_surrounds_go_in_at_minus_3_db() {
+        let downmix = Downmix::new(Layout::FiveOne, None);
+        let scale = 1.0 + 2.0 * SIDE_GAIN;
+        let expected = [
+            [1.0, 0.0],
+            [0.0, 1.0],
+            [SIDE_GAIN, SIDE_GAIN],
+            [0.0, 0.0],
+            [SIDE_GAIN, 0.0],
+            [0.0, SIDE_GAIN],
+        ];
+        for (gains, expected) in downmix.coefficients().iter().zip(expected) {
+            let expected = expected.map(|x| x / scale);
+            assert!((gains[0] - expected[0]).abs() < 1e-6, "{:?}", gains);
+            assert!((gains[1] - expected[1]).abs() < 1e-6, "{:?}", gains);
+        }
+        // The centre alone lands in the middle, 3 dB under either side alone.
+        let [left, right] = mix(&downmix, &[0.0, 0.0, 1.0, 0.0, 0.0, 0.0])[..] else {
+            unreachable!()
+        };
+        assert_eq!(left, right);
+        assert!((left * scale - SIDE_GAIN).abs() < 1e-6);
+    }
+
+    #[test]
+    fn no_input_can_clip_the_sides() {
+        for layout in [
+            Layout::Quad,
+            Layout::FiveZero,
+            Layout::FiveOne,
+            Layout::SevenOne,
+        ] {
+            let downmix = Downmix::new(layout, Some(0.0));
+            let output = mix(&downmix, &vec![1.0; layout.channels()]);
+            assert!(
+                output.iter().all(|x| *x <= 1.0 + 1e-6),
+                "{:?}: {:?}",
+                layout,
+                output
+            );
+            assert!(
+                output.iter().any(|x| *x > 1.0 - 1e-6),
+                "{:?}: {:?}",
+                layout,
+                output
+            );
+        }
+        let output = mix(&Downmix::equal(3), &[1.0, 1.0, 1.0, 0.3, 0.3, 0.3]);
+        assert_eq!(output.len(), 4);
+        assert!((output[0] - 1.0).abs() < 1e-6 && (output[3] - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn the_lfe_goes_in_only_with_a_gain() {
+        let lfe = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+        assert_eq!(mix(&Downmix::new(Layout::FiveOne, None), &lfe), [0.0, 0.0]);
+        let downmix = Downmix::new(Layout::FiveOne, Some(-10.0));
+        let output = mix(&downmix, &lfe);
+        let scale = 1.0 + 2.0 * SIDE_GAIN + level::db_to_gain(-10.0);
+        assert!((output[0] * scale - level::db_to_gain(-10.0)).abs() < 1e-6);
+        assert_eq!(output[0], output[1]);
+    }
+}
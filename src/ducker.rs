@@ -0,0 +1,179 @@
+//! Ducking of the playback file under the live input.
+//!
+//! The envelope of the live signal, or of a [sidechain](crate::sidechain), keys the ducker: while it's above the threshold, the playback
+//! signal is attenuated by the configured amount, entering and leaving the reduction with the
+//! attack and release times. Only the playback signal is changed.
+
+use std::str::FromStr;
+
+use crate::envelope::EnvelopeFollower;
+use crate::level;
+use crate::sidechain::Key;
+
+/// Release of the key envelope, just long enough to bridge the gaps between the peaks of speech.
+const KEY_RELEASE_MS: f32 = 50.0;
+
+/// Ducker settings, `<threshold-dBFS>:<amount-dB>:<attack-ms>:<release-ms>` on the command line,
+/// e.g. "-35:-10:10:400".
+#[derive(Clone, Copy, Debug)]
+pub struct DuckSettings {
+    pub threshold_db: f32,
+    /// Gain applied to the playback while ducked, in dB, e.g. -10.
+    pub amount_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl FromStr for DuckSettings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "expected `<threshold-dBFS>:<amount-dB>:<attack-ms>:<release-ms>`, got \"{}\"",
+                s
+            )
+        };
+        let values: Vec<f32> = s
+            .split(':')
+            .map(|x| x.parse().ok().filter(|x: &f32| x.is_finite()))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+        let [threshold_db, amount_db, attack_ms, release_ms] = values[..] else {
+            return Err(invalid());
+        };
+        if attack_ms < 0.0 || release_ms < 0.0 {
+            return Err(invalid());
+        }
+        Ok(DuckSettings {
+            threshold_db,
+            // Either sign means a reduction.
+            amount_db: -amount_db.abs(),
+            attack_ms,
+            release_ms,
+        })
+    }
+}
+
+/// Attenuates a signal while a key signal is active.
+pub struct Ducker {
+    channels: usize,
+    threshold: f32,
+    amount_db: f32,
+    /// Envelope of the key.
+    key: EnvelopeFollower,
+    /// Current gain reduction, in dB as a positive number.
+    reduction: EnvelopeFollower,
+    sample_rate: u32,
+}
+
+impl Ducker {
+    pub fn new(settings: DuckSettings, channels: usize, sample_rate: u32) -> Self {
+        Ducker {
+            channels,
+            threshold: level::db_to_gain(settings.threshold_db),
+            amount_db: settings.amount_db,
+            key: EnvelopeFollower::new(0.0, KEY_RELEASE_MS, sample_rate),
+            reduction: EnvelopeFollower::new(settings.attack_ms, settings.release_ms, sample_rate),
+            sample_rate,
+        }
+    }
+
+    /// Keeps the attack and release times on signals now at `sample_rate`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.key.change_rate(self.sample_rate, sample_rate);
+        self.reduction.change_rate(self.sample_rate, sample_rate);
+        self.sample_rate = sample_rate;
+    }
+
+    /// Applies the gain reduction keyed by `key` to the interleaved `data`, over the frames of
+    /// the shorter one.
+    pub fn process(&mut self, key: Key, data: &mut [f32]) {
+        let frames = key.frames(self.channels);
+        for (index, frame) in data
+            .chunks_exact_mut(self.channels)
+            .take(frames)
+            .enumerate()
+        {
+            let level = key.level(index, self.channels);
+            let active = self.key.process(level) >= self.threshold;
+            let reduction = self
+                .reduction
+                .process(if active { -self.amount_db } else { 0.0 });
+            if reduction < 1e-4 {
+                continue;
+            }
+            let gain = level::db_to_gain(-reduction);
+            for x in frame {
+                *x *= gain;
+            }
+        }
+    }
+
+    /// Current gain reduction, in dB as a positive number.
+    pub fn reduction_db(&self) -> f32 {
+        self.reduction.value()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> DuckSettings {
+        "-30:10:5:50".parse().unwrap()
+    }
+
+    /// Ducks a second of a constant stereo signal under a key of `level`, at 1 kHz, in blocks of
+    /// 10 ms, returning the gain of the last frame.
+    fn duck(ducker: &mut Ducker, level: f32, ms: usize) -> f32 {
+        let mut gain = 1.0;
+        for _ in 0..ms / 10 {
+            let key = [level; 10];
+            let mut data = [1.0; 20];
+            ducker.process(Key::Mono(&key), &mut data);
+            gain = data[19];
+        }
+        gain
+    }
+
+    #[test]
+    fn settings_parse_a_reduction_of_either_sign() {
+        let settings = settings();
+        assert_eq!(settings.threshold_db, -30.0);
+        assert_eq!(settings.amount_db, -10.0);
+        assert_eq!((settings.attack_ms, settings.release_ms), (5.0, 50.0));
+        let negative: DuckSettings = "-30:-10:5:50".parse().unwrap();
+        assert_eq!(negative.amount_db, -10.0);
+        for text in ["-30:10:5", "-30:10:-5:50", "-30:10:5:inf", "-30:ten:5:50"] {
+            assert!(text.parse::<DuckSettings>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn the_signal_ducks_while_the_key_is_active() {
+        let mut ducker = Ducker::new(settings(), 2, 1_000);
+        assert_eq!(duck(&mut ducker, 0.001, 100), 1.0);
+        let ducked = duck(&mut ducker, 0.5, 100);
+        assert!(
+            (level::gain_to_db(ducked) + 10.0).abs() < 0.01,
+            "{}",
+            ducked
+        );
+        assert!((ducker.reduction_db() - 10.0).abs() < 0.01);
+        // The key envelope bridges short gaps, then the reduction releases.
+        assert!(duck(&mut ducker, 0.0, 20) < 0.5);
+        let released = duck(&mut ducker, 0.0, 1_000);
+        assert!((released - 1.0).abs() < 1e-3, "{}", released);
+    }
+
+    #[test]
+    fn only_the_frames_the_key_covers_are_ducked() {
+        let mut ducker = Ducker::new("-30:10:0:50".parse().unwrap(), 1, 1_000);
+        let mut data = [1.0; 4];
+        ducker.process(Key::Frames(&[0.5, 0.5]), &mut data);
+        let ducked = level::db_to_gain(-10.0);
+        assert!((data[0] - ducked).abs() < 1e-4 && (data[1] - ducked).abs() < 1e-4);
+        assert_eq!(data[2..], [1.0, 1.0]);
+    }
+}
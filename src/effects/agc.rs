@@ -0,0 +1,343 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Effect, ScratchArena};
+use crate::level;
+use crate::sample::Sample;
+use crate::stats::AtomicF32;
+
+/// Time constant of the level the gain adapts to, in seconds.
+const SLOW_SECONDS: f32 = 0.3;
+/// Time constant of the level the gain is limited by, in seconds.
+const FAST_SECONDS: f32 = 0.01;
+/// How far over the target the fast level may go before the gain is cut at once, in dB.
+const FAST_MARGIN_DB: f32 = 6.0;
+/// Largest cut, for input far over the target, in dB.
+const MAX_CUT_DB: f32 = 40.0;
+/// Frames the gain is computed once for, and ramped over.
+const CONTROL_FRAMES: usize = 32;
+
+/// AGC settings, colon-separated `key=value` pairs on the command line, e.g.
+/// `target=-20:max=18`:
+///
+/// - `target`: RMS level held, in dBFS, -20 by default.
+/// - `max`: largest gain, in dB, 20 by default.
+/// - `rate`: fastest the gain adapts, in dB per second, 6 by default.
+/// - `gate`: RMS level under which the gain is held instead of raised, in dBFS, -50 by default.
+#[derive(Clone, Copy, Debug)]
+pub struct AgcSpec {
+    pub target_db: f32,
+    pub max_gain_db: f32,
+    pub rate_db: f32,
+    pub gate_db: f32,
+}
+
+impl Default for AgcSpec {
+    fn default() -> Self {
+        AgcSpec {
+            target_db: -20.0,
+            max_gain_db: 20.0,
+            rate_db: 6.0,
+            gate_db: -50.0,
+        }
+    }
+}
+
+impl FromStr for AgcSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut spec = AgcSpec::default();
+        for pair in s.split(':').filter(|x| !x.is_empty()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!("expected `key=value` in AGC, got \"{}\"", pair));
+            };
+            let value = value
+                .parse::<f32>()
+                .ok()
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| format!("invalid AGC {} \"{}\"", key, value))?;
+            match key {
+                "target" => spec.target_db = value,
+                "max" => spec.max_gain_db = value,
+                "rate" => spec.rate_db = value,
+                "gate" => spec.gate_db = value,
+                _ => return Err(format!("unknown AGC setting \"{}\"", key)),
+            }
+        }
+        if spec.rate_db <= 0.0 {
+            return Err(format!("AGC rate must be positive, got {}", spec.rate_db));
+        }
+        if spec.max_gain_db < 0.0 {
+            return Err(format!(
+                "AGC max gain must not be negative, got {}",
+                spec.max_gain_db
+            ));
+        }
+        Ok(spec)
+    }
+}
+
+/// Shared with the status line.
+pub struct AgcStats {
+    /// Gain applied, in dB.
+    pub gain_db: AtomicF32,
+    /// Whether the input is under the gate, and the gain held.
+    pub gated: AtomicBool,
+}
+
+impl Default for AgcStats {
+    fn default() -> Self {
+        AgcStats {
+            gain_db: AtomicF32::new(0.0),
+            gated: AtomicBool::new(false),
+        }
+    }
+}
+
+impl AgcStats {
+    pub fn describe(&self) -> String {
+        let gain = self.gain_db.load();
+        match self.gated.load(Ordering::Relaxed) {
+            true => format!("AGC: {:+.1} dB, gated", gain),
+            false => format!("AGC: {:+.1} dB", gain),
+        }
+    }
+}
+
+/// Holds the RMS level of a signal at a target, e.g. of speech from speakers near and far.
+///
+/// The gain follows the target minus the level over the last few hundred milliseconds, at most
+/// `rate` dB per second either way, within the largest gain and cut. Input suddenly much louder
+/// is cut at once, as far as keeps its level over the last 10 ms within 6 dB of the target.
+/// Under the gate the gain is held, so that pauses aren't raised into hiss.
+pub struct Agc {
+    spec: AgcSpec,
+    slow: f32,
+    fast: f32,
+    /// Mean square of the frames, over the slow and fast time constants.
+    slow_level: f32,
+    fast_level: f32,
+    /// Gain in dB, and the linear gain reached at the end of the last control period.
+    gain_db: f32,
+    gain: f32,
+    /// Largest change of the gain per control period, in dB.
+    step_db: f32,
+    /// Frames into the current control period.
+    position: usize,
+    stats: Option<Arc<AgcStats>>,
+}
+
+impl Agc {
+    /// Creates an AGC for a stream at `sample_rate`, publishing its gain to `stats`.
+    pub fn new(spec: AgcSpec, sample_rate: f32, stats: Option<Arc<AgcStats>>) -> Self {
+        let mut agc = Agc {
+            spec,
+            slow: 0.0,
+            fast: 0.0,
+            slow_level: 0.0,
+            fast_level: 0.0,
+            gain_db: 0.0,
+            gain: 1.0,
+            step_db: 0.0,
+            position: 0,
+            stats,
+        };
+        agc.derive(sample_rate);
+        agc
+    }
+
+    /// Derives the smoothing and the largest step of the gain for a stream at `sample_rate`.
+    fn derive(&mut self, sample_rate: f32) {
+        let coefficient = |seconds: f32| 1.0 - (-1.0 / (seconds * sample_rate)).exp();
+        self.slow = coefficient(SLOW_SECONDS);
+        self.fast = coefficient(FAST_SECONDS);
+        self.step_db = self.spec.rate_db * CONTROL_FRAMES as f32 / sample_rate;
+    }
+
+    /// Moves the gain for the next control period, from the levels so far.
+    fn update(&mut self) {
+        let slow_db = 10.0 * self.slow_level.max(f32::MIN_POSITIVE).log10();
+        let fast_db = 10.0 * self.fast_level.max(f32::MIN_POSITIVE).log10();
+        // Either level, so that the gain stops as soon as the input does, without waiting for
+        // the slow level to fall to the gate.
+        let gated = slow_db.min(fast_db) < self.spec.gate_db;
+        if !gated {
+            let wanted = (self.spec.target_db - slow_db).clamp(-MAX_CUT_DB, self.spec.max_gain_db);
+            self.gain_db += (wanted - self.gain_db).clamp(-self.step_db, self.step_db);
+        }
+        let limit = self.spec.target_db + FAST_MARGIN_DB - fast_db;
+        self.gain_db = self.gain_db.min(limit).max(-MAX_CUT_DB);
+        if let Some(stats) = &self.stats {
+            stats.gain_db.store(self.gain_db);
+            stats.gated.store(gated, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S: Sample> Effect<S> for Agc {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        let channels = buffer.channels().max(1);
+        let mut frame = 0;
+        while frame < buffer.frames() {
+            let frames = (CONTROL_FRAMES - self.position).min(buffer.frames() - frame);
+            for n in frame..frame + frames {
+                let square = (0..buffer.channels())
+                    .map(|x| buffer.channel(x)[n].to_sample::<f32>().powi(2))
+                    .sum::<f32>()
+                    / channels as f32;
+                self.slow_level += (square - self.slow_level) * self.slow;
+                self.fast_level += (square - self.fast_level) * self.fast;
+            }
+            // The gain of a control period reaches its value at the end of the last one, ramped
+            // from where the previous period left it.
+            let start = self.gain;
+            let end = level::db_to_gain(self.gain_db);
+            let step = (end - start) / CONTROL_FRAMES as f32;
+            for channel in buffer.channels_mut() {
+                for (offset, x) in channel[frame..frame + frames].iter_mut().enumerate() {
+                    let gain = start + step * (self.position + offset + 1) as f32;
+                    *x = *x * S::from_sample(gain);
+                }
+            }
+            self.position += frames;
+            frame += frames;
+            if self.position == CONTROL_FRAMES {
+                self.position = 0;
+                self.gain = end;
+                self.update();
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "agc"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &["target"]
+    }
+
+    fn param(&self, _index: usize) -> f32 {
+        self.spec.target_db
+    }
+
+    fn set_param(&mut self, _index: usize, value: f32) {
+        self.spec.target_db = value;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.derive(sample_rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 8_000.0;
+
+    fn from_spec(spec: &str) -> (Agc, Arc<AgcStats>) {
+        let stats = Arc::new(AgcStats::default());
+        let agc = Agc::new(spec.parse().unwrap(), SAMPLE_RATE, Some(stats.clone()));
+        (agc, stats)
+    }
+
+    /// Runs `seconds` of a sine of `rms_db` dBFS RMS through `agc`, in blocks of 100 frames,
+    /// returning the output.
+    fn run(agc: &mut Agc, rms_db: f32, seconds: f32) -> Vec<f32> {
+        let amplitude = level::db_to_gain(rms_db) * std::f32::consts::SQRT_2;
+        let frames = (seconds * SAMPLE_RATE) as usize;
+        let data: Vec<f32> = (0..frames)
+            .map(|n| amplitude * (std::f32::consts::TAU * 200.0 * n as f32 / SAMPLE_RATE).sin())
+            .collect();
+        let mut output = Vec::with_capacity(frames);
+        for block in data.chunks(100) {
+            let mut buffer = AudioBuffer::new(1, block.len());
+            buffer.deinterleave(block);
+            Effect::<f32>::process(agc, &mut buffer, &mut ScratchArena::new(0));
+            output.extend_from_slice(buffer.channel(0));
+        }
+        output
+    }
+
+    /// RMS of the last 100 ms of `output`, in dBFS.
+    fn rms_db(output: &[f32]) -> f32 {
+        let tail = &output[output.len() - 800..];
+        let power = tail.iter().map(|x| x * x).sum::<f32>() / tail.len() as f32;
+        10.0 * power.log10()
+    }
+
+    #[test]
+    fn specs_are_parsed_with_defaults() {
+        let spec: AgcSpec = "target=-16:gate=-60".parse().unwrap();
+        assert_eq!(spec.target_db, -16.0);
+        assert_eq!(spec.max_gain_db, 20.0);
+        assert_eq!(spec.rate_db, 6.0);
+        assert_eq!(spec.gate_db, -60.0);
+        let error = |s: &str| s.parse::<AgcSpec>().unwrap_err();
+        assert_eq!(
+            error("target"),
+            "expected `key=value` in AGC, got \"target\""
+        );
+        assert_eq!(error("max=loud"), "invalid AGC max \"loud\"");
+        assert_eq!(error("speed=1"), "unknown AGC setting \"speed\"");
+        assert_eq!(error("rate=0"), "AGC rate must be positive, got 0");
+        assert_eq!(error("max=-1"), "AGC max gain must not be negative, got -1");
+    }
+
+    #[test]
+    fn quiet_input_is_raised_to_the_target() {
+        let (mut agc, stats) = from_spec("rate=60");
+        let output = run(&mut agc, -35.0, 1.0);
+        assert!((rms_db(&output) + 20.0).abs() < 0.5, "{}", rms_db(&output));
+        assert!((stats.gain_db.load() - 15.0).abs() < 0.5);
+        let expected = format!("AGC: {:+.1} dB", stats.gain_db.load());
+        assert_eq!(stats.describe(), expected);
+
+        // Halfway at a tenth of the speed.
+        let (mut agc, stats) = from_spec("rate=6");
+        run(&mut agc, -35.0, 1.0);
+        assert!(
+            (stats.gain_db.load() - 6.0).abs() < 0.5,
+            "{}",
+            stats.gain_db.load()
+        );
+    }
+
+    #[test]
+    fn the_gain_is_capped_at_the_max() {
+        let (mut agc, stats) = from_spec("rate=60:max=10:gate=-60");
+        let output = run(&mut agc, -45.0, 1.0);
+        assert!((rms_db(&output) + 35.0).abs() < 0.5, "{}", rms_db(&output));
+        assert_eq!(stats.gain_db.load(), 10.0);
+    }
+
+    #[test]
+    fn pauses_under_the_gate_hold_the_gain() {
+        let (mut agc, stats) = from_spec("rate=60");
+        run(&mut agc, -30.0, 1.0);
+        // The fast level takes a few milliseconds to fall under the gate.
+        run(&mut agc, -70.0, 0.1);
+        let gain = stats.gain_db.load();
+        assert!((gain - 10.0).abs() < 1.0, "{}", gain);
+        run(&mut agc, -70.0, 1.0);
+        assert_eq!(stats.gain_db.load(), gain);
+        assert!(stats.gated.load(Ordering::Relaxed));
+        assert_eq!(stats.describe(), format!("AGC: {:+.1} dB, gated", gain));
+    }
+
+    #[test]
+    fn sudden_loud_input_is_cut_at_once() {
+        let (mut agc, stats) = from_spec("rate=60");
+        run(&mut agc, -35.0, 1.0);
+        let output = run(&mut agc, -5.0, 0.05);
+        // Within 6 dB over the target after a few time constants of the fast level.
+        let tail = &output[200..];
+        let db = 10.0 * (tail.iter().map(|x| x * x).sum::<f32>() / tail.len() as f32).log10();
+        assert!(db < -20.0 + FAST_MARGIN_DB + 0.5, "{}", db);
+        assert!(stats.gain_db.load() < -8.0, "{}", stats.gain_db.load());
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::bail;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Cascade, Effect, FilterKind, ScratchArena, BUTTERWORTH_Q};
+use crate::sample::Sample;
+
+/// Bass management: the low end of every channel, summed into a subwoofer channel.
+///
+/// The sub gets the sum of the other channels through a Linkwitz–Riley 24 dB/octave low-pass,
+/// then its own gain and polarity. The other channels can go through the matching high-pass, so
+/// that the mains and the sub still sum to a flat response.
+pub struct BassManager<S = f32> {
+    sub: usize,
+    lowpass: Cascade<S>,
+    /// Linear gain of the sub, negative when its polarity is inverted.
+    gain: S,
+    /// The high-passes of the other channels, `None` for the sub and when the mains stay
+    /// full-range.
+    highpasses: Vec<Option<Cascade<S>>>,
+}
+
+impl<S: Sample> BassManager<S> {
+    /// Creates the bass management of a stream of `channels` channels at `sample_rate`, with the
+    /// sub on channel `sub`, failing if the stream doesn't have it.
+    pub fn new(
+        sub: usize,
+        frequency: f32,
+        gain: f32,
+        highpass_mains: bool,
+        sample_rate: f32,
+        channels: usize,
+    ) -> anyhow::Result<Self> {
+        if sub >= channels {
+            bail!(
+                "cannot send the sub to channel {}: the output stream has {} channels",
+                sub,
+                channels
+            );
+        }
+        let linkwitz_riley = |kind| {
+            let mut cascade = Cascade::new();
+            cascade.design(kind, frequency, BUTTERWORTH_Q, sample_rate);
+            cascade.design(kind, frequency, BUTTERWORTH_Q, sample_rate);
+            cascade
+        };
+        let highpasses = (0..channels)
+            .map(|channel| {
+                (highpass_mains && channel != sub).then(|| linkwitz_riley(FilterKind::HighPass))
+            })
+            .collect();
+        Ok(BassManager {
+            sub,
+            lowpass: linkwitz_riley(FilterKind::LowPass),
+            gain: S::from_sample(gain),
+            highpasses,
+        })
+    }
+}
+
+impl<S: Sample> Effect<S> for BassManager<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        for frame in 0..buffer.frames() {
+            let sum = (0..buffer.channels())
+                .filter(|channel| *channel != self.sub)
+                .map(|channel| buffer.channel(channel)[frame])
+                .fold(S::default(), |sum, x| sum + x);
+            buffer.channel_mut(self.sub)[frame] = sum;
+        }
+        let sub = buffer.channel_mut(self.sub);
+        self.lowpass.process(sub);
+        S::apply_gain(sub, self.gain);
+        for (data, highpass) in buffer.channels_mut().zip(&mut self.highpasses) {
+            if let Some(highpass) = highpass {
+                highpass.process(data);
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "bass"
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.lowpass.set_sample_rate(sample_rate);
+        for highpass in self.highpasses.iter_mut().flatten() {
+            highpass.set_sample_rate(sample_rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// Runs a sine of `frequency` on every channel through `bass`, returning the peak of each
+    /// channel past the transient.
+    fn peaks(bass: &mut BassManager, channels: usize, frequency: f32) -> Vec<f32> {
+        let frames = 19_200;
+        let data: Vec<f32> = (0..frames)
+            .flat_map(|i| {
+                let x = (2.0 * std::f32::consts::PI * frequency * i as f32 / SAMPLE_RATE).sin();
+                std::iter::repeat_n(0.5 * x, channels)
+            })
+            .collect();
+        let mut buffer = AudioBuffer::new(channels, frames);
+        buffer.deinterleave(&data);
+        bass.process(&mut buffer, &mut ScratchArena::new(0));
+        (0..channels)
+            .map(|channel| {
+                let tail = &buffer.channel(channel)[frames / 2..];
+                tail.iter().fold(0f32, |x, y| x.max(y.abs()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_sub_must_be_a_channel_of_the_stream() {
+        let err = BassManager::<f32>::new(2, 80.0, 1.0, false, SAMPLE_RATE, 2)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "cannot send the sub to channel 2: the output stream has 2 channels"
+        );
+    }
+
+    #[test]
+    fn the_sub_gets_the_sum_of_the_lows_of_the_other_channels() {
+        let mut bass = BassManager::new(2, 80.0, 0.5, false, SAMPLE_RATE, 3).unwrap();
+        let at_20 = peaks(&mut bass, 3, 20.0);
+        // Two mains of 0.5 sum to 1, and the sub's gain halves that.
+        assert!((at_20[2] - 0.5).abs() < 0.01, "{:?}", at_20);
+        // Full-range mains go through.
+        assert!((at_20[0] - 0.5).abs() < 1e-3 && (at_20[1] - 0.5).abs() < 1e-3);
+        let mut bass = BassManager::new(2, 80.0, 0.5, false, SAMPLE_RATE, 3).unwrap();
+        let at_2k = peaks(&mut bass, 3, 2_000.0);
+        assert!(at_2k[2] < 1e-3, "{:?}", at_2k);
+    }
+
+    #[test]
+    fn high_passed_mains_lose_their_lows() {
+        let mut bass = BassManager::new(0, 80.0, 1.0, true, SAMPLE_RATE, 3).unwrap();
+        let at_20 = peaks(&mut bass, 3, 20.0);
+        assert!(at_20[1] < 0.01 && at_20[2] < 0.01, "{:?}", at_20);
+        let mut bass = BassManager::new(0, 80.0, 1.0, true, SAMPLE_RATE, 3).unwrap();
+        let at_2k = peaks(&mut bass, 3, 2_000.0);
+        assert!((at_2k[1] - 0.5).abs() < 0.01, "{:?}", at_2k);
+    }
+}
@@ -0,0 +1,507 @@
+//! Speaker cabinet simulation for guitar: the response of a cabinet through a mic, as an impulse
+//! response convolved directly, so that it adds no latency.
+//!
+//! A cabinet has the IRs of two mic positions, close to the cone and further back, and the blend
+//! morphs from the first to the second. Crossfading the IRs in time would sum two responses a
+//! little apart in time, notching the sum like a comb filter, so the blend is made per bin of
+//! their spectra instead: the magnitude is interpolated linearly, and the phase goes from the
+//! close one by the blend's share of the unwrapped difference to the far one. Between coherent
+//! IRs, such as a close and a far mic of one speaker, that moves the delay between them along
+//! rather than summing both, and the magnitude never dips below the lower of the two. Blends of
+//! 0 and 1 are the IRs as they are.
+
+use std::f32::consts::PI;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::buffer::AudioBuffer;
+use crate::effects::fir::DirectLine;
+use crate::effects::{Effect, FirTaps, ScratchArena};
+use crate::fft::Fft;
+use crate::sample::Sample;
+use crate::simd;
+use crate::wav;
+
+/// Longest IR, at the stream's rate. Longer ones are cut, since every tap costs every sample.
+pub const MAX_CAB_TAPS: usize = 2_048;
+
+/// The built-in cabinets, with the close mic in the first channel and the far one in the second.
+const BUILT_IN: [(&str, &[u8]); 2] = [
+    ("v30", include_bytes!("cabs/v30.wav")),
+    ("greenback", include_bytes!("cabs/greenback.wav")),
+];
+
+/// Names of the built-in cabinets.
+pub fn built_in() -> impl Iterator<Item = &'static str> {
+    BUILT_IN.iter().map(|(name, _)| *name)
+}
+
+/// Half the taps of the windowed sinc resampling the IRs, at the lower of the two rates.
+const RESAMPLE_HALF_TAPS: f64 = 16.0;
+
+/// Where the IRs of a cabinet come from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CabSource {
+    BuiltIn(&'static str),
+    /// A WAV or text file of one filter, or two for the close and far mics, as for `--fir`.
+    File(PathBuf),
+}
+
+/// A cabinet on the command line: `<cab>[:<mic>]`, e.g. "v30:close", where `<cab>` is a
+/// built-in cabinet or a file, and `<mic>` is "close", "far", or a blend from 0, close, to 1,
+/// far. Close by default.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CabSpec {
+    pub source: CabSource,
+    pub blend: f32,
+}
+
+/// The blend of a mic position, or of a number from 0 to 1.
+fn parse_mic(s: &str) -> Option<f32> {
+    match s {
+        "close" => Some(0.0),
+        "far" => Some(1.0),
+        _ => s.parse().ok().filter(|x| (0.0..=1.0).contains(x)),
+    }
+}
+
+impl FromStr for CabSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Whatever follows the last colon is part of a path unless it's a mic position.
+        let (cab, blend) = match s.rsplit_once(':') {
+            Some((cab, mic)) => match parse_mic(mic) {
+                Some(blend) => (cab, blend),
+                None if built_in().any(|x| x == cab) => {
+                    return Err(format!(
+                        "invalid mic \"{}\", expected close, far, or a blend from 0 to 1",
+                        mic
+                    ))
+                }
+                None => (s, 0.0),
+            },
+            None => (s, 0.0),
+        };
+        if cab.is_empty() {
+            return Err("expected a cabinet, e.g. \"v30:close\"".to_string());
+        }
+        let source = match built_in().find(|x| *x == cab) {
+            Some(name) => CabSource::BuiltIn(name),
+            None => CabSource::File(PathBuf::from(cab)),
+        };
+        Ok(CabSpec { source, blend })
+    }
+}
+
+/// The IRs of the mic positions of a cabinet.
+#[derive(Clone, Debug)]
+pub struct CabIrs {
+    pub close: Vec<f32>,
+    pub far: Option<Vec<f32>>,
+    /// Rate the IRs were recorded at, when known.
+    pub sample_rate: Option<u32>,
+}
+
+impl CabIrs {
+    pub fn load(source: &CabSource) -> anyhow::Result<Self> {
+        let taps = match source {
+            CabSource::BuiltIn(name) => {
+                let (_, bytes) = BUILT_IN.iter().find(|(x, _)| x == name).unwrap();
+                let data = wav::parse(bytes).context("the built-in cabinet is broken")?;
+                let channels = data.channels as usize;
+                FirTaps {
+                    channels: (0..channels)
+                        .map(|channel| data.samples.iter().skip(channel).step_by(channels))
+                        .map(|x| x.copied().collect())
+                        .collect(),
+                    sample_rate: Some(data.sample_rate),
+                }
+            }
+            CabSource::File(path) => FirTaps::load(path)?,
+        };
+        let mut channels = taps.channels.into_iter();
+        match (channels.next(), channels.next(), channels.next()) {
+            (Some(close), far, None) => Ok(CabIrs {
+                close,
+                far,
+                sample_rate: taps.sample_rate,
+            }),
+            _ => bail!("a cabinet holds the IRs of one or two mic positions"),
+        }
+    }
+}
+
+/// The spectra of the two IRs, from which blends are made.
+struct Morph {
+    fft: Fft,
+    close: Vec<f32>,
+    far: Vec<f32>,
+    close_magnitude: Vec<f32>,
+    far_magnitude: Vec<f32>,
+    close_phase: Vec<f32>,
+    /// The phase of the far IR less that of the close one, unwrapped along the bins.
+    difference: Vec<f32>,
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl Morph {
+    fn new(close: Vec<f32>, far: Vec<f32>) -> Self {
+        let taps = close.len().max(far.len());
+        // Room for the delays the phases move between, so that blends don't wrap around.
+        let size = (taps * 2).next_power_of_two();
+        let fft = Fft::new(size);
+        let spectrum = |taps: &[f32]| {
+            let (mut re, mut im) = (vec![0.0; size], vec![0.0; size]);
+            re[..taps.len()].copy_from_slice(taps);
+            fft.forward(&mut re, &mut im);
+            (re, im)
+        };
+        let ((close_re, close_im), (far_re, far_im)) = (spectrum(&close), spectrum(&far));
+        let bins = size / 2 + 1;
+        let magnitude = |re: &[f32], im: &[f32]| -> Vec<f32> {
+            (0..bins).map(|k| re[k].hypot(im[k])).collect()
+        };
+        let mut difference = Vec::with_capacity(bins);
+        let mut last = 0.0;
+        for k in 0..bins {
+            // The angle of far · conj(close).
+            let re = far_re[k] * close_re[k] + far_im[k] * close_im[k];
+            let im = far_im[k] * close_re[k] - far_re[k] * close_im[k];
+            let wrapped = im.atan2(re);
+            let unwrapped = match difference.last() {
+                Some(previous) => previous + wrap(wrapped - last),
+                None => wrapped,
+            };
+            difference.push(unwrapped);
+            last = wrapped;
+        }
+        Morph {
+            close_magnitude: magnitude(&close_re, &close_im),
+            far_magnitude: magnitude(&far_re, &far_im),
+            close_phase: (0..bins).map(|k| close_im[k].atan2(close_re[k])).collect(),
+            difference,
+            close,
+            far,
+            re: vec![0.0; size],
+            im: vec![0.0; size],
+            fft,
+        }
+    }
+
+    /// Longest of the IRs, which every blend is cut to.
+    fn taps(&self) -> usize {
+        self.close.len().max(self.far.len())
+    }
+
+    /// Writes the IR of `blend` to `taps`, without allocating.
+    fn blend(&mut self, blend: f32, taps: &mut [f32]) {
+        taps.fill(0.0);
+        if blend <= 0.0 {
+            taps[..self.close.len()].copy_from_slice(&self.close);
+            return;
+        }
+        if blend >= 1.0 {
+            taps[..self.far.len()].copy_from_slice(&self.far);
+            return;
+        }
+        let size = self.re.len();
+        for k in 0..=size / 2 {
+            let magnitude =
+                self.close_magnitude[k] + (self.far_magnitude[k] - self.close_magnitude[k]) * blend;
+            let phase = self.close_phase[k] + self.difference[k] * blend;
+            let (sin, cos) = phase.sin_cos();
+            self.re[k] = magnitude * cos;
+            self.im[k] = magnitude * sin;
+            // The spectrum of a real IR is conjugate-symmetric.
+            if k > 0 && k < size / 2 {
+                self.re[size - k] = self.re[k];
+                self.im[size - k] = -self.im[k];
+            }
+        }
+        self.fft.inverse(&mut self.re, &mut self.im);
+        taps.copy_from_slice(&self.re[..taps.len()]);
+    }
+}
+
+/// `x` wrapped to [-π, π).
+fn wrap(x: f32) -> f32 {
+    (x + PI).rem_euclid(2.0 * PI) - PI
+}
+
+/// The IR of `taps` at `from` Hz resampled to `to` Hz, with the same gain, by a windowed sinc
+/// band-limited to the lower of the two rates, and cut to [`MAX_CAB_TAPS`].
+fn resample(taps: &[f32], from: u32, to: u32) -> Vec<f32> {
+    let ratio = to as f64 / from as f64;
+    let length = ((taps.len() as f64 * ratio).ceil() as usize).min(MAX_CAB_TAPS);
+    if from == to {
+        return taps[..length].to_vec();
+    }
+    let cutoff = ratio.min(1.0);
+    let half = RESAMPLE_HALF_TAPS / cutoff;
+    (0..length)
+        .map(|n| {
+            let position = n as f64 / ratio;
+            let first = (position - half).ceil().max(0.0) as usize;
+            let last = ((position + half).floor() as usize).min(taps.len() - 1);
+            let sum: f64 = (first..=last)
+                .map(|k| {
+                    let x = position - k as f64;
+                    let sinc = match x == 0.0 {
+                        true => 1.0,
+                        false => {
+                            (std::f64::consts::PI * cutoff * x).sin() / (std::f64::consts::PI * x)
+                        }
+                    };
+                    let window = 0.5 + 0.5 * (std::f64::consts::PI * x / half).cos();
+                    taps[k] as f64 * sinc * window
+                })
+                .sum();
+            (sum / ratio) as f32
+        })
+        .collect()
+}
+
+/// Convolves every channel with the IR of a cabinet, at a blend of its mic positions that
+/// automation can move: a new blend crossfades to its IR over one block.
+pub struct CabSim<S = f32> {
+    irs: CabIrs,
+    morph: Option<Morph>,
+    blend: f32,
+    /// The blend of `taps`.
+    applied: f32,
+    /// The reversed taps of the IR, and of the next one while crossfading to it.
+    taps: Vec<f32>,
+    next: Vec<f32>,
+    lines: Vec<DirectLine>,
+    _sample: PhantomData<S>,
+}
+
+impl<S: Sample> CabSim<S> {
+    /// Creates the cabinet of `irs` at `blend` for a stream of `channels` channels at
+    /// `sample_rate`, failing for a blend of a cabinet with a single mic position.
+    pub fn new(irs: CabIrs, blend: f32, sample_rate: f32, channels: usize) -> anyhow::Result<Self> {
+        if irs.far.is_none() && blend > 0.0 {
+            bail!("the cabinet has a single mic position, so it can't be blended");
+        }
+        let mut cab = CabSim {
+            irs,
+            morph: None,
+            blend,
+            applied: blend,
+            taps: Vec::new(),
+            next: Vec::new(),
+            lines: (0..channels).map(|_| DirectLine::new(0)).collect(),
+            _sample: PhantomData,
+        };
+        Effect::<S>::set_sample_rate(&mut cab, sample_rate);
+        Ok(cab)
+    }
+
+    /// Taps of the IR, at the stream's rate.
+    pub fn len(&self) -> usize {
+        self.taps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.taps.is_empty()
+    }
+}
+
+impl<S: Sample> Effect<S> for CabSim<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        let fading = match &mut self.morph {
+            Some(morph) if self.blend != self.applied => {
+                morph.blend(self.blend, &mut self.next);
+                self.next.reverse();
+                self.applied = self.blend;
+                true
+            }
+            _ => false,
+        };
+        let step = 1.0 / buffer.frames().max(1) as f32;
+        for (data, line) in buffer.channels_mut().zip(&mut self.lines) {
+            for (n, x) in data.iter_mut().enumerate() {
+                let inputs = line.push(x.to_sample());
+                let mut y = simd::dot(&self.taps, inputs);
+                if fading {
+                    let gain = (n + 1) as f32 * step;
+                    y += (simd::dot(&self.next, inputs) - y) * gain;
+                }
+                *x = S::from_sample(y);
+            }
+        }
+        if fading {
+            std::mem::swap(&mut self.taps, &mut self.next);
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "cab"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &["blend"]
+    }
+
+    fn param(&self, _index: usize) -> f32 {
+        self.blend
+    }
+
+    fn set_param(&mut self, _index: usize, value: f32) {
+        // A cabinet of a single mic position stays at it.
+        if self.morph.is_some() {
+            self.blend = value.clamp(0.0, 1.0);
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let rate = sample_rate as u32;
+        let from = self.irs.sample_rate.unwrap_or(rate);
+        let close = resample(&self.irs.close, from, rate);
+        let far = self.irs.far.as_ref().map(|x| resample(x, from, rate));
+        let mut morph = far.map(|far| Morph::new(close.clone(), far));
+        let taps = morph.as_ref().map_or(close.len(), Morph::taps);
+        self.taps = vec![0.0; taps];
+        match &mut morph {
+            Some(morph) => morph.blend(self.blend, &mut self.taps),
+            None => self.taps.copy_from_slice(&close),
+        }
+        self.taps.reverse();
+        self.next = vec![0.0; taps];
+        self.applied = self.blend;
+        self.morph = morph;
+        self.lines = (0..self.lines.len())
+            .map(|_| DirectLine::new(taps))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The response of `cab` to an impulse, over `frames`.
+    fn impulse_response(cab: &mut CabSim, frames: usize) -> Vec<f32> {
+        let mut impulse = vec![0.0; frames];
+        impulse[0] = 1.0;
+        let mut buffer = AudioBuffer::new(1, frames);
+        buffer.deinterleave(&impulse);
+        cab.process(&mut buffer, &mut ScratchArena::new(0));
+        buffer.channel(0).to_vec()
+    }
+
+    #[test]
+    fn cabinets_parse_with_their_mic() {
+        let spec = |s: &str| s.parse::<CabSpec>();
+        let built_in = |name, blend| CabSpec {
+            source: CabSource::BuiltIn(name),
+            blend,
+        };
+        assert_eq!(spec("v30"), Ok(built_in("v30", 0.0)));
+        assert_eq!(spec("v30:far"), Ok(built_in("v30", 1.0)));
+        assert_eq!(spec("greenback:0.25"), Ok(built_in("greenback", 0.25)));
+        assert_eq!(
+            spec("v30:loud"),
+            Err("invalid mic \"loud\", expected close, far, or a blend from 0 to 1".to_string())
+        );
+        assert_eq!(
+            spec(":far"),
+            Err("expected a cabinet, e.g. \"v30:close\"".to_string())
+        );
+        // What isn't a mic is part of the path.
+        assert_eq!(
+            spec("C:\\cabs\\4x12.wav"),
+            Ok(CabSpec {
+                source: CabSource::File(PathBuf::from("C:\\cabs\\4x12.wav")),
+                blend: 0.0,
+            })
+        );
+    }
+
+    #[test]
+    fn the_mic_positions_are_the_irs_as_they_are() {
+        for name in built_in() {
+            let irs = CabIrs::load(&CabSource::BuiltIn(name)).unwrap();
+            assert_eq!(irs.sample_rate, Some(48_000));
+            let far = irs.far.clone().unwrap();
+            let mut cab = CabSim::new(irs.clone(), 0.0, 48_000.0, 1).unwrap();
+            assert_eq!(cab.len(), irs.close.len());
+            let response = impulse_response(&mut cab, irs.close.len());
+            assert!(response
+                .iter()
+                .zip(&irs.close)
+                .all(|(x, y)| (x - y).abs() < 1e-6));
+            let mut cab = CabSim::new(irs, 1.0, 48_000.0, 1).unwrap();
+            let response = impulse_response(&mut cab, far.len());
+            assert!(response.iter().zip(&far).all(|(x, y)| (x - y).abs() < 1e-6));
+        }
+    }
+
+    #[test]
+    fn blends_move_the_delay_between_the_mics_along() {
+        let close = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let far = [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+        let mut morph = Morph::new(close.to_vec(), far.to_vec());
+        let mut taps = [0.0; 9];
+        morph.blend(0.5, &mut taps);
+        for (n, x) in taps.iter().enumerate() {
+            let expected = if n == 4 { 1.0 } else { 0.0 };
+            assert!((x - expected).abs() < 1e-4, "{:?}", taps);
+        }
+    }
+
+    #[test]
+    fn a_new_blend_crossfades_over_a_block() {
+        let irs = CabIrs {
+            close: vec![1.0, 0.0],
+            far: Some(vec![0.0, 0.5]),
+            sample_rate: None,
+        };
+        let mut cab = CabSim::new(irs, 0.0, 48_000.0, 1).unwrap();
+        Effect::<f32>::set_param(&mut cab, 0, 1.0);
+        let mut buffer = AudioBuffer::new(1, 4);
+        buffer.deinterleave(&[1.0; 4]);
+        cab.process(&mut buffer, &mut ScratchArena::new(0));
+        assert_eq!(buffer.channel(0), [0.75, 0.75, 0.625, 0.5]);
+        assert_eq!(Effect::<f32>::param(&cab, 0), 1.0);
+    }
+
+    #[test]
+    fn cabinets_of_a_single_mic_cannot_be_blended() {
+        let irs = CabIrs {
+            close: vec![1.0, 0.5],
+            far: None,
+            sample_rate: None,
+        };
+        let err = CabSim::<f32>::new(irs.clone(), 0.5, 48_000.0, 1)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the cabinet has a single mic position, so it can't be blended"
+        );
+        let mut cab = CabSim::<f32>::new(irs, 0.0, 48_000.0, 1).unwrap();
+        Effect::<f32>::set_param(&mut cab, 0, 1.0);
+        assert_eq!(Effect::<f32>::param(&cab, 0), 0.0);
+    }
+
+    #[test]
+    fn irs_are_resampled_at_the_same_gain() {
+        let irs = CabIrs::load(&CabSource::BuiltIn("v30")).unwrap();
+        let gain = |taps: &[f32]| taps.iter().sum::<f32>();
+        let resampled = resample(&irs.close, 48_000, 96_000);
+        assert_eq!(resampled.len(), MAX_CAB_TAPS.min(2 * irs.close.len()));
+        assert!((gain(&resampled) - gain(&irs.close)).abs() < 0.01 * gain(&irs.close).abs());
+        let resampled = resample(&irs.close, 48_000, 44_100);
+        assert_eq!(
+            resampled.len(),
+            (irs.close.len() as f64 * 0.91875).ceil() as usize
+        );
+        assert!((gain(&resampled) - gain(&irs.close)).abs() < 0.01 * gain(&irs.close).abs());
+    }
+}
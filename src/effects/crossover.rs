@@ -0,0 +1,362 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Cascade, Effect, FilterKind, ScratchArena, BUTTERWORTH_Q};
+use crate::level;
+use crate::sample::Sample;
+
+/// Split frequencies of a crossover: `2way:<Hz>` or `3way:<low-Hz>:<high-Hz>` on the command
+/// line.
+#[derive(Clone, Debug)]
+pub struct CrossoverSpec {
+    /// Increasing frequencies between the bands, in Hz.
+    pub frequencies: Vec<f32>,
+}
+
+impl CrossoverSpec {
+    /// Names of the bands, from low to high.
+    pub fn bands(&self) -> &'static [&'static str] {
+        match self.frequencies.len() {
+            1 => &["low", "high"],
+            _ => &["low", "mid", "high"],
+        }
+    }
+
+    /// Resolves the routing of every band. By default band `n` goes to channels `2n` and
+    /// `2n + 1` at unity gain, and `bands` override that for the bands they name.
+    pub fn routes(&self, bands: &[BandSpec]) -> anyhow::Result<Vec<Route>> {
+        let names = self.bands();
+        let mut routes: Vec<Route> = (0..names.len())
+            .map(|band| Route {
+                band,
+                channels: vec![band * 2, band * 2 + 1],
+                gain: 1.0,
+            })
+            .collect();
+        for spec in bands {
+            let Some(band) = names.iter().position(|x| *x == spec.band) else {
+                bail!(
+                    "unknown crossover band \"{}\", the bands are {}",
+                    spec.band,
+                    names.join(", ")
+                );
+            };
+            let route = &mut routes[band];
+            if let Some(channels) = &spec.channels {
+                route.channels = channels.clone();
+            }
+            let sign = if spec.invert { -1.0 } else { 1.0 };
+            route.gain = sign * level::db_to_gain(spec.gain_db);
+        }
+        let mut used = Vec::new();
+        for route in &routes {
+            for &channel in &route.channels {
+                if used.contains(&channel) {
+                    bail!("output channel {} is fed by two crossover bands", channel);
+                }
+                used.push(channel);
+            }
+        }
+        Ok(routes)
+    }
+}
+
+impl FromStr for CrossoverSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "expected `2way:<Hz>` or `3way:<low-Hz>:<high-Hz>`, got \"{}\"",
+                s
+            )
+        };
+        let mut fields = s.split(':');
+        let splits = match fields.next() {
+            Some("2way") => 1,
+            Some("3way") => 2,
+            _ => return Err(invalid()),
+        };
+        let frequencies: Vec<f32> = fields
+            .map(|x| x.parse().ok().filter(|x: &f32| *x > 0.0))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+        if frequencies.len() != splits {
+            return Err(invalid());
+        }
+        if frequencies.windows(2).any(|x| x[0] >= x[1]) {
+            return Err(format!(
+                "crossover frequencies must increase, got \"{}\"",
+                s
+            ));
+        }
+        Ok(CrossoverSpec { frequencies })
+    }
+}
+
+/// How one band of a crossover is fed: `<band>[:channels=<n>,...][:gain=<dB>][:invert]` on the
+/// command line, e.g. `high:channels=2,3:gain=-1.5:invert`.
+#[derive(Clone, Debug)]
+pub struct BandSpec {
+    pub band: String,
+    pub channels: Option<Vec<usize>>,
+    pub gain_db: f32,
+    pub invert: bool,
+}
+
+impl FromStr for BandSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(':');
+        let band = fields.next().unwrap_or_default().to_string();
+        let mut spec = BandSpec {
+            band,
+            channels: None,
+            gain_db: 0.0,
+            invert: false,
+        };
+        for field in fields {
+            match field.split_once('=') {
+                None if field == "invert" => spec.invert = true,
+                Some(("channels", value)) => {
+                    let channels = value
+                        .split(',')
+                        .map(|x| x.parse().ok())
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| format!("invalid band channels \"{}\"", value))?;
+                    spec.channels = Some(channels);
+                }
+                Some(("gain", value)) => {
+                    spec.gain_db = value
+                        .parse()
+                        .ok()
+                        .filter(|x: &f32| x.is_finite())
+                        .ok_or_else(|| format!("invalid band gain \"{}\"", value))?;
+                }
+                _ => return Err(format!("unknown band setting \"{}\"", field)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// Which band goes to which output channels, and how loud.
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub band: usize,
+    pub channels: Vec<usize>,
+    /// Linear gain, negative when the polarity is inverted.
+    pub gain: f32,
+}
+
+impl Route {
+    /// Number of output channels `routes` need.
+    pub fn channels_needed(routes: &[Route]) -> usize {
+        routes
+            .iter()
+            .flat_map(|x| &x.channels)
+            .map(|x| x + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Splits the signal into bands with Linkwitz–Riley 24 dB/octave filters, each band going to its
+/// own output channels.
+///
+/// The outputs expect every channel to start with a copy of the monitor feed, which the channel
+/// adapter provides by repeating the input channels. Each routed channel is then filtered down to
+/// its band, and the others are muted. The high band of a 3-way split also goes through the
+/// high-pass of the lower split, and the low band through the all-pass of the upper one, so that
+/// the bands still sum to a flat response.
+pub struct Crossover<S = f32> {
+    /// The filters and gain of each output channel, `None` for the muted ones.
+    channels: Vec<Option<(Cascade<S>, S)>>,
+}
+
+impl<S: Sample> Crossover<S> {
+    /// Creates a crossover for `channels` output channels, failing if a route needs more.
+    pub fn new(
+        spec: &CrossoverSpec,
+        routes: &[Route],
+        sample_rate: f32,
+        channels: usize,
+    ) -> anyhow::Result<Self> {
+        let needed = Route::channels_needed(routes);
+        if needed > channels {
+            bail!(
+                "the crossover feeds {} output channels, but the output stream has {}",
+                needed,
+                channels
+            );
+        }
+        let frequencies = &spec.frequencies;
+        let band_filter = |band: usize| {
+            let mut cascade = Cascade::new();
+            // Splitting as a tree, the bands above a split all go through its high-pass.
+            for &frequency in &frequencies[..band] {
+                cascade.design(FilterKind::HighPass, frequency, BUTTERWORTH_Q, sample_rate);
+                cascade.design(FilterKind::HighPass, frequency, BUTTERWORTH_Q, sample_rate);
+            }
+            if let Some(&frequency) = frequencies.get(band) {
+                cascade.design(FilterKind::LowPass, frequency, BUTTERWORTH_Q, sample_rate);
+                cascade.design(FilterKind::LowPass, frequency, BUTTERWORTH_Q, sample_rate);
+            }
+            // A Linkwitz–Riley pair sums to a second-order all-pass, which the bands under a
+            // split they don't go through must share.
+            for &frequency in frequencies.iter().skip(band + 1) {
+                cascade.design(FilterKind::AllPass, frequency, BUTTERWORTH_Q, sample_rate);
+            }
+            cascade
+        };
+        let mut outputs: Vec<Option<(Cascade<S>, S)>> = (0..channels).map(|_| None).collect();
+        for route in routes {
+            for &channel in &route.channels {
+                outputs[channel] = Some((band_filter(route.band), S::from_sample(route.gain)));
+            }
+        }
+        Ok(Crossover { channels: outputs })
+    }
+}
+
+impl<S: Sample> Effect<S> for Crossover<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        for (data, output) in buffer.channels_mut().zip(&mut self.channels) {
+            match output {
+                Some((cascade, gain)) => {
+                    cascade.process(data);
+                    S::apply_gain(data, *gain);
+                }
+                None => data.fill(S::default()),
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "crossover"
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        for (cascade, _) in self.channels.iter_mut().flatten() {
+            cascade.set_sample_rate(sample_rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    fn crossover(spec: &str, channels: usize) -> Crossover {
+        let spec: CrossoverSpec = spec.parse().unwrap();
+        let routes = spec.routes(&[]).unwrap();
+        Crossover::new(&spec, &routes, SAMPLE_RATE, channels).unwrap()
+    }
+
+    /// Runs an impulse on every channel through `crossover`, returning each channel's response.
+    fn impulse(crossover: &mut Crossover, channels: usize, frames: usize) -> Vec<Vec<f32>> {
+        let mut buffer = AudioBuffer::new(channels, frames);
+        let mut data = vec![0.0; channels * frames];
+        data[..channels].fill(1.0);
+        buffer.deinterleave(&data);
+        crossover.process(&mut buffer, &mut ScratchArena::new(0));
+        (0..channels).map(|x| buffer.channel(x).to_vec()).collect()
+    }
+
+    fn energy(data: &[f32]) -> f32 {
+        data.iter().map(|x| x * x).sum()
+    }
+
+    #[test]
+    fn specs_parse_and_must_increase() {
+        let spec: CrossoverSpec = "3way:200:2000".parse().unwrap();
+        assert_eq!(spec.frequencies, [200.0, 2_000.0]);
+        assert_eq!(spec.bands(), ["low", "mid", "high"]);
+        assert_eq!(
+            "3way:2000:200".parse::<CrossoverSpec>().unwrap_err(),
+            "crossover frequencies must increase, got \"3way:2000:200\""
+        );
+        for text in ["2way", "2way:100:200", "4way:1:2:3", "2way:-100"] {
+            assert_eq!(
+                text.parse::<CrossoverSpec>().unwrap_err(),
+                format!(
+                    "expected `2way:<Hz>` or `3way:<low-Hz>:<high-Hz>`, got \"{}\"",
+                    text
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn bands_go_to_pairs_of_channels_unless_told_otherwise() {
+        let spec: CrossoverSpec = "2way:1000".parse().unwrap();
+        let routes = spec.routes(&[]).unwrap();
+        assert_eq!(routes[0].channels, [0, 1]);
+        assert_eq!(routes[1].channels, [2, 3]);
+        assert_eq!(Route::channels_needed(&routes), 4);
+
+        let band: BandSpec = "high:channels=4:gain=-6:invert".parse().unwrap();
+        let routes = spec.routes(&[band]).unwrap();
+        assert_eq!(routes[1].channels, [4]);
+        assert!((routes[1].gain + level::db_to_gain(-6.0)).abs() < 1e-6);
+        assert_eq!(Route::channels_needed(&routes), 5);
+    }
+
+    #[test]
+    fn bad_routings_are_rejected() {
+        let spec: CrossoverSpec = "2way:1000".parse().unwrap();
+        let err = spec.routes(&["mid".parse().unwrap()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown crossover band \"mid\", the bands are low, high"
+        );
+        let err = spec
+            .routes(&["high:channels=1".parse().unwrap()])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "output channel 1 is fed by two crossover bands"
+        );
+        assert_eq!(
+            "low:volume=3".parse::<BandSpec>().unwrap_err(),
+            "unknown band setting \"volume=3\""
+        );
+        let routes = spec.routes(&[]).unwrap();
+        let err = Crossover::<f32>::new(&spec, &routes, SAMPLE_RATE, 2)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the crossover feeds 4 output channels, but the output stream has 2"
+        );
+    }
+
+    #[test]
+    fn the_bands_sum_back_to_an_all_pass() {
+        for (spec, channels) in [("2way:1000", 4), ("3way:200:2000", 6)] {
+            let responses = impulse(&mut crossover(spec, channels), channels, 48_000);
+            let sum: Vec<f32> = (0..48_000)
+                .map(|i| (0..channels).step_by(2).map(|x| responses[x][i]).sum())
+                .collect();
+            assert!(
+                (energy(&sum) - 1.0).abs() < 1e-4,
+                "{}: {}",
+                spec,
+                energy(&sum)
+            );
+        }
+    }
+
+    #[test]
+    fn channels_no_band_feeds_are_muted() {
+        let responses = impulse(&mut crossover("2way:1000", 6), 6, 64);
+        assert_eq!(responses[0], responses[1]);
+        assert!(energy(&responses[0]) > 0.0);
+        assert!(responses[4].iter().chain(&responses[5]).all(|x| *x == 0.0));
+    }
+}
@@ -0,0 +1,214 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Effect, ScratchArena};
+use crate::sample::Sample;
+
+/// Delay of one output channel: `<channel>:<milliseconds>` on the command line, e.g. `2:5.8`.
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelDelaySpec {
+    pub channel: usize,
+    pub milliseconds: f32,
+}
+
+impl FromStr for ChannelDelaySpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected `<channel>:<milliseconds>`, got \"{}\"", s);
+        let (channel, milliseconds) = s.split_once(':').ok_or_else(invalid)?;
+        let channel = channel.parse().map_err(|_| invalid())?;
+        let milliseconds = milliseconds
+            .parse()
+            .ok()
+            .filter(|x: &f32| *x >= 0.0 && x.is_finite())
+            .ok_or_else(invalid)?;
+        Ok(ChannelDelaySpec {
+            channel,
+            milliseconds,
+        })
+    }
+}
+
+/// Delays some output channels by fractional numbers of frames, to time-align speakers at
+/// different distances.
+///
+/// Each delayed channel keeps a circular history allocated up front for the maximum delay, and
+/// reads it back between two frames by linear interpolation, as the resampler does. The other
+/// channels go through untouched.
+pub struct ChannelDelay<S = f32> {
+    lines: Vec<Option<DelayLine<S>>>,
+    /// The delay of each channel, and the longest, in milliseconds.
+    milliseconds: Vec<Option<f32>>,
+    max_milliseconds: f32,
+}
+
+impl<S: Sample> ChannelDelay<S> {
+    /// Creates the delays of a stream of `channels` channels at `sample_rate`, failing if one is
+    /// longer than `max_milliseconds` or for a channel the stream doesn't have. A channel given
+    /// twice gets the last delay.
+    pub fn new(
+        delays: &[ChannelDelaySpec],
+        max_milliseconds: f32,
+        sample_rate: f32,
+        channels: usize,
+    ) -> anyhow::Result<Self> {
+        let mut milliseconds = vec![None; channels];
+        for delay in delays {
+            if delay.channel >= channels {
+                bail!(
+                    "cannot delay channel {}: the output stream has {} channels",
+                    delay.channel,
+                    channels
+                );
+            }
+            if delay.milliseconds > max_milliseconds {
+                bail!(
+                    "the delay of channel {} is {} ms, more than the maximum of {} ms",
+                    delay.channel,
+                    delay.milliseconds,
+                    max_milliseconds
+                );
+            }
+            milliseconds[delay.channel] = Some(delay.milliseconds);
+        }
+        let mut delay = ChannelDelay {
+            lines: Vec::new(),
+            milliseconds,
+            max_milliseconds,
+        };
+        delay.allocate(sample_rate);
+        Ok(delay)
+    }
+
+    /// Allocates the lines of the delayed channels, empty, for a stream at `sample_rate`.
+    fn allocate(&mut self, sample_rate: f32) {
+        let frames = |milliseconds: f32| milliseconds as f64 / 1_000.0 * sample_rate as f64;
+        let max_frames = frames(self.max_milliseconds);
+        self.lines = self
+            .milliseconds
+            .iter()
+            .map(|x| x.map(|x| DelayLine::new(frames(x), max_frames)))
+            .collect();
+    }
+}
+
+impl<S: Sample> Effect<S> for ChannelDelay<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        for (data, line) in buffer.channels_mut().zip(&mut self.lines) {
+            if let Some(line) = line {
+                line.process(data);
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "delay"
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.allocate(sample_rate);
+    }
+}
+
+/// A circular history of one channel read back a fixed, possibly fractional, delay later.
+struct DelayLine<S> {
+    history: Vec<S>,
+    /// Where the next sample is written.
+    position: usize,
+    /// The whole part of the delay, and the weight of the older of the two frames read.
+    whole: usize,
+    fraction: S,
+}
+
+impl<S: Sample> DelayLine<S> {
+    fn new(frames: f64, max_frames: f64) -> Self {
+        DelayLine {
+            // Room for the frame after the whole delay too, which interpolation reads.
+            history: vec![S::default(); max_frames.ceil() as usize + 2],
+            position: 0,
+            whole: frames.floor() as usize,
+            fraction: S::from_sample(frames.fract()),
+        }
+    }
+
+    fn process(&mut self, data: &mut [S]) {
+        let len = self.history.len();
+        let one = S::from_sample(1.0);
+        for x in data {
+            self.history[self.position] = *x;
+            let newer = self.history[(self.position + len - self.whole) % len];
+            let older = self.history[(self.position + len - self.whole - 1) % len];
+            *x = newer * (one - self.fraction) + older * self.fraction;
+            self.position = (self.position + 1) % len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delay(delays: &[&str], channels: usize) -> anyhow::Result<ChannelDelay> {
+        let delays: Vec<ChannelDelaySpec> = delays.iter().map(|x| x.parse().unwrap()).collect();
+        ChannelDelay::new(&delays, 20.0, 1_000.0, channels)
+    }
+
+    /// Runs `data`, split into blocks of `block` frames, through `delay`.
+    fn run(delay: &mut ChannelDelay, channels: usize, data: &mut [f32], block: usize) {
+        let mut buffer = AudioBuffer::new(channels, block);
+        for data in data.chunks_mut(block * channels) {
+            buffer.deinterleave(data);
+            delay.process(&mut buffer, &mut ScratchArena::new(0));
+            buffer.interleave(data);
+        }
+    }
+
+    #[test]
+    fn specs_parse_and_reject_negative_delays() {
+        let spec: ChannelDelaySpec = "2:5.8".parse().unwrap();
+        assert_eq!((spec.channel, spec.milliseconds), (2, 5.8));
+        for text in ["2", "2:-1", "x:5", "2:inf"] {
+            assert_eq!(
+                text.parse::<ChannelDelaySpec>().unwrap_err(),
+                format!("expected `<channel>:<milliseconds>`, got \"{}\"", text)
+            );
+        }
+    }
+
+    #[test]
+    fn delays_past_the_maximum_or_the_channels_are_rejected() {
+        let err = delay(&["2:1"], 2).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "cannot delay channel 2: the output stream has 2 channels"
+        );
+        let err = delay(&["1:25"], 2).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the delay of channel 1 is 25 ms, more than the maximum of 20 ms"
+        );
+    }
+
+    #[test]
+    fn delayed_channels_come_later_across_blocks_and_others_go_through() {
+        // At 1 kHz, a millisecond is a frame.
+        let mut delay = delay(&["1:3"], 2).unwrap();
+        let mut data: Vec<f32> = (1..=8).flat_map(|x| [x as f32, x as f32]).collect();
+        run(&mut delay, 2, &mut data, 2);
+        let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+        let right: Vec<f32> = data.iter().skip(1).step_by(2).copied().collect();
+        assert_eq!(left, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        assert_eq!(right, [0.0, 0.0, 0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn fractional_delays_interpolate_and_the_last_given_wins() {
+        let mut delay = delay(&["0:1", "0:1.25"], 1).unwrap();
+        let mut data = [4.0, 0.0, 0.0, 0.0];
+        run(&mut delay, 1, &mut data, 4);
+        assert_eq!(data, [0.0, 3.0, 1.0, 0.0]);
+    }
+}
@@ -0,0 +1,495 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::bail;
+
+use crate::buffer::AudioBuffer;
+use crate::compressor::GainComputer;
+use crate::effects::{Cascade, Coefficients, Effect, FilterKind, ScratchArena};
+use crate::envelope::EnvelopeFollower;
+use crate::level;
+use crate::sample::Sample;
+use crate::stats::AtomicF32;
+
+/// Frames the gain of the dynamic bands is computed once for.
+const CONTROL_FRAMES: usize = 32;
+/// Smallest change of the gain of a dynamic band its filter is recomputed for, in dB.
+const GAIN_STEP_DB: f32 = 0.01;
+
+/// How a dynamic band moves its gain: towards the full gain of the band as the level in the band
+/// goes over the threshold, by as much as a compressor of the ratio would turn it down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dynamics {
+    pub threshold_db: f32,
+    pub ratio: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for Dynamics {
+    fn default() -> Self {
+        Dynamics {
+            threshold_db: -30.0,
+            ratio: 2.0,
+            attack_ms: 5.0,
+            release_ms: 100.0,
+        }
+    }
+}
+
+/// A band of the parametric EQ on the command line: `[peak:]<Hz>:<dB>:<Q>` for a static one,
+/// e.g. "3000:2:0.7", or `dyn:<Hz>:<dB>:<Q>` followed by `key=value` pairs for a dynamic one,
+/// e.g. "dyn:250:-6:1.4:threshold=-30:ratio=3":
+///
+/// - `threshold`: level in the band the gain starts moving at, in dBFS, -30 by default.
+/// - `ratio`: of the compressor the gain follows, 2 by default.
+/// - `attack`, `release`: of the level in the band, in milliseconds, 5 and 100 by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EqBand {
+    pub frequency: f32,
+    /// The gain of a static band, or the most a dynamic one reaches.
+    pub gain_db: f32,
+    pub q: f32,
+    /// `None` for a static band.
+    pub dynamics: Option<Dynamics>,
+}
+
+impl FromStr for EqBand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("dyn", rest)) => EqBand::parse(rest, Some(Dynamics::default())),
+            Some(("peak", rest)) => EqBand::parse(rest, None),
+            _ => EqBand::parse(s, None),
+        }
+    }
+}
+
+impl EqBand {
+    /// A static band.
+    pub const fn peak(frequency: f32, gain_db: f32, q: f32) -> Self {
+        EqBand {
+            frequency,
+            gain_db,
+            q,
+            dynamics: None,
+        }
+    }
+
+    /// A dynamic band, reaching `gain_db` at most.
+    pub const fn dynamic(frequency: f32, gain_db: f32, q: f32, dynamics: Dynamics) -> Self {
+        EqBand {
+            frequency,
+            gain_db,
+            q,
+            dynamics: Some(dynamics),
+        }
+    }
+
+    /// Parses the fields of a band after its type, with the default `dynamics` of its type.
+    fn parse(s: &str, mut dynamics: Option<Dynamics>) -> Result<Self, String> {
+        let mut fields = s.split(':');
+        let mut number = |name: &str| {
+            let field = fields
+                .next()
+                .ok_or_else(|| format!("missing EQ band {}", name))?;
+            field
+                .parse::<f32>()
+                .ok()
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| format!("invalid EQ band {} \"{}\"", name, field))
+        };
+        let frequency = number("frequency")?;
+        let gain_db = number("gain")?;
+        let q = number("Q")?;
+        if frequency <= 0.0 {
+            return Err(format!(
+                "EQ band frequency must be positive, got {}",
+                frequency
+            ));
+        }
+        if q <= 0.0 {
+            return Err(format!("EQ band Q must be positive, got {}", q));
+        }
+        for pair in fields.filter(|x| !x.is_empty()) {
+            let Some(dynamics) = &mut dynamics else {
+                return Err(format!(
+                    "unexpected \"{}\" after a static EQ band, only `dyn` bands take settings",
+                    pair
+                ));
+            };
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!("expected `key=value` in EQ band, got \"{}\"", pair));
+            };
+            let value = value
+                .parse::<f32>()
+                .ok()
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| format!("invalid EQ band {} \"{}\"", key, value))?;
+            match key {
+                "threshold" => dynamics.threshold_db = value,
+                "ratio" => dynamics.ratio = value,
+                "attack" => dynamics.attack_ms = value,
+                "release" => dynamics.release_ms = value,
+                _ => return Err(format!("unknown EQ band setting \"{}\"", key)),
+            }
+        }
+        if let Some(dynamics) = &dynamics {
+            if dynamics.ratio < 1.0 {
+                return Err(format!(
+                    "EQ band ratio must be at least 1, got {}",
+                    dynamics.ratio
+                ));
+            }
+            if dynamics.attack_ms < 0.0 || dynamics.release_ms < 0.0 {
+                return Err("EQ band attack and release must not be negative".to_string());
+            }
+        }
+        Ok(EqBand {
+            frequency,
+            gain_db,
+            q,
+            dynamics,
+        })
+    }
+}
+
+/// The gain of a dynamic band, shared with the status line and the final stats.
+pub struct BandStats {
+    pub frequency: f32,
+    /// Gain applied now, and the furthest it went from 0 dB, in dB.
+    pub gain_db: AtomicF32,
+    pub peak_db: AtomicF32,
+}
+
+/// The gains of the dynamic bands of an EQ, in the order they were given.
+pub struct EqStats {
+    pub bands: Vec<BandStats>,
+}
+
+impl EqStats {
+    /// Stats of the dynamic bands of `bands`, or `None` if there are none.
+    pub fn new(bands: &[EqBand]) -> Option<Self> {
+        let bands: Vec<BandStats> = bands
+            .iter()
+            .filter(|x| x.dynamics.is_some())
+            .map(|x| BandStats {
+                frequency: x.frequency,
+                gain_db: AtomicF32::new(0.0),
+                peak_db: AtomicF32::new(0.0),
+            })
+            .collect();
+        (!bands.is_empty()).then_some(EqStats { bands })
+    }
+
+    /// The field of the status line, e.g. "EQ: 250 Hz -3.2 dB".
+    pub fn describe(&self) -> String {
+        let bands: Vec<String> = self
+            .bands
+            .iter()
+            .map(|x| format!("{} Hz {:+.1} dB", x.frequency, x.gain_db.load()))
+            .collect();
+        format!("EQ: {}", bands.join(", "))
+    }
+
+    /// The furthest each band went, e.g. "250 Hz -5.1 dB".
+    pub fn summary(&self) -> String {
+        let bands: Vec<String> = self
+            .bands
+            .iter()
+            .map(|x| format!("{} Hz {:+.1} dB", x.frequency, x.peak_db.load()))
+            .collect();
+        bands.join(", ")
+    }
+}
+
+/// A dynamic band: its filters, and what sets their gain.
+struct DynamicBand<S> {
+    band: EqBand,
+    computer: GainComputer,
+    /// Band-pass of every channel, the level in the band is measured on.
+    detectors: Vec<Cascade<S>>,
+    envelope: EnvelopeFollower,
+    /// Gain the filters are at, in dB.
+    gain_db: f32,
+}
+
+/// A parametric EQ of peaking bands on every channel, static or dynamic.
+///
+/// A static band boosts or cuts by its gain all the time. A dynamic band only does as the level
+/// in the band goes over its threshold: a band-pass at the frequency of the band feeds an envelope
+/// follower, the loudest channel's, and the gain computer of the compressor turns how far over
+/// the threshold it is into how much of the gain of the band is applied, e.g. -6 dB at most to
+/// tame a boomy voice only when it booms. Its filters are recomputed every 32 frames, from the
+/// smoothed level, and keep their state, so that the gain moves without clicks.
+pub struct ParametricEq<S = f32> {
+    sample_rate: f32,
+    /// The filters of every channel, one section a band, in order.
+    channels: Vec<Cascade<S>>,
+    /// Dynamic bands, with the index of their section.
+    dynamic: Vec<(usize, DynamicBand<S>)>,
+    stats: Option<Arc<EqStats>>,
+}
+
+impl<S: Sample> ParametricEq<S> {
+    /// Creates the EQ of `bands` for a stream of `channels` channels at `sample_rate`, publishing
+    /// the gains of the dynamic bands to `stats`, which [`EqStats::new`] made of the same bands.
+    /// Fails if a band is too close to the Nyquist frequency to be stable.
+    pub fn new(
+        bands: &[EqBand],
+        sample_rate: f32,
+        channels: usize,
+        stats: Option<Arc<EqStats>>,
+    ) -> anyhow::Result<Self> {
+        let mut cascade = Cascade::new();
+        let mut dynamic = Vec::new();
+        for (index, band) in bands.iter().enumerate() {
+            if band.frequency >= sample_rate * 0.49 {
+                bail!(
+                    "cannot put an EQ band at {} Hz in a stream at {} Hz",
+                    band.frequency,
+                    sample_rate
+                );
+            }
+            // Dynamic bands start flat, until the level in the band says otherwise.
+            let gain_db = match band.dynamics {
+                Some(_) => 0.0,
+                None => band.gain_db,
+            };
+            let kind = FilterKind::Peaking { gain_db };
+            cascade.design(kind, band.frequency, band.q, sample_rate);
+            let Some(dynamics) = band.dynamics else {
+                continue;
+            };
+            let mut detector = Cascade::new();
+            detector.design(FilterKind::BandPass, band.frequency, band.q, sample_rate);
+            dynamic.push((
+                index,
+                DynamicBand {
+                    band: *band,
+                    computer: GainComputer::new(dynamics.threshold_db, dynamics.ratio),
+                    detectors: vec![detector; channels],
+                    envelope: EnvelopeFollower::new(
+                        dynamics.attack_ms,
+                        dynamics.release_ms,
+                        sample_rate as u32,
+                    ),
+                    gain_db: 0.0,
+                },
+            ));
+        }
+        Ok(ParametricEq {
+            sample_rate,
+            channels: vec![cascade; channels],
+            dynamic,
+            stats,
+        })
+    }
+
+    /// Measures the level in the dynamic bands over `frames` of `buffer` from `start`, and moves
+    /// their filters to the gains it calls for.
+    fn update(
+        &mut self,
+        buffer: &AudioBuffer<S>,
+        start: usize,
+        frames: usize,
+        scratch: &mut ScratchArena<S>,
+    ) {
+        for (number, (index, band)) in self.dynamic.iter_mut().enumerate() {
+            // The band-passed samples of a channel, and the level in the band of every frame.
+            let (filtered, peaks) = scratch.take_both(frames);
+            for (channel, detector) in band.detectors.iter_mut().enumerate() {
+                filtered.copy_from_slice(&buffer.channel(channel)[start..start + frames]);
+                detector.process(filtered);
+                for (peak, x) in peaks.iter_mut().zip(filtered.iter()) {
+                    *peak = peak.max(x.to_sample::<f32>().abs());
+                }
+            }
+            let mut envelope = band.envelope.value();
+            for peak in peaks.iter() {
+                envelope = band.envelope.process(*peak);
+            }
+            let reduction = band.computer.reduction_db(level::gain_to_db(envelope));
+            let gain_db = match reduction > 0.0 {
+                true => reduction.min(band.band.gain_db.abs()) * band.band.gain_db.signum(),
+                false => 0.0,
+            };
+            if let Some(stats) = self.stats.as_ref().and_then(|x| x.bands.get(number)) {
+                stats.gain_db.store(gain_db);
+                if gain_db.abs() > stats.peak_db.load().abs() {
+                    stats.peak_db.store(gain_db);
+                }
+            }
+            if (gain_db - band.gain_db).abs() < GAIN_STEP_DB {
+                continue;
+            }
+            band.gain_db = gain_db;
+            set_gain(&mut self.channels, *index, band, self.sample_rate);
+        }
+    }
+}
+
+/// Moves the sections at `index` of `channels` to the gain of the dynamic `band`.
+fn set_gain<S: Sample>(
+    channels: &mut [Cascade<S>],
+    index: usize,
+    band: &DynamicBand<S>,
+    sample_rate: f32,
+) {
+    let kind = FilterKind::Peaking {
+        gain_db: band.gain_db,
+    };
+    let coefficients = Coefficients::new(kind, band.band.frequency, band.band.q, sample_rate);
+    for cascade in channels {
+        cascade.set(index, coefficients);
+    }
+}
+
+impl<S: Sample> Effect<S> for ParametricEq<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, scratch: &mut ScratchArena<S>) {
+        let mut start = 0;
+        while start < buffer.frames() {
+            let frames = CONTROL_FRAMES.min(buffer.frames() - start);
+            if !self.dynamic.is_empty() {
+                self.update(buffer, start, frames, scratch);
+            }
+            for (data, cascade) in buffer.channels_mut().zip(&mut self.channels) {
+                cascade.process(&mut data[start..start + frames]);
+            }
+            start += frames;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "eq"
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        // Dynamic bands design again at the gain they're at, not the flat one they started at.
+        for cascade in &mut self.channels {
+            cascade.set_sample_rate(sample_rate);
+        }
+        for (index, band) in &mut self.dynamic {
+            for detector in &mut band.detectors {
+                detector.set_sample_rate(sample_rate);
+            }
+            band.envelope
+                .change_rate(self.sample_rate as u32, sample_rate as u32);
+            set_gain(&mut self.channels, *index, band, sample_rate);
+        }
+        self.sample_rate = sample_rate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// Gain of `eq` on a sine of `amplitude` at `frequency`, in dB, once settled.
+    fn gain_db(eq: &mut ParametricEq, frequency: f32, amplitude: f32) -> f32 {
+        let frames = SAMPLE_RATE as usize;
+        let omega = std::f32::consts::TAU * frequency / SAMPLE_RATE;
+        let sine: Vec<f32> = (0..frames)
+            .map(|n| amplitude * (omega * n as f32).sin())
+            .collect();
+        let mut buffer = AudioBuffer::new(1, frames);
+        buffer.deinterleave(&sine);
+        let mut scratch = ScratchArena::new(CONTROL_FRAMES);
+        scratch.begin("eq", CONTROL_FRAMES);
+        eq.process(&mut buffer, &mut scratch);
+        let rms = |x: &[f32]| (x.iter().map(|x| x * x).sum::<f32>() / x.len() as f32).sqrt();
+        let half = frames / 2;
+        level::gain_to_db(rms(&buffer.channel(0)[half..]) / rms(&sine[half..]))
+    }
+
+    #[test]
+    fn bands_parse_with_their_settings() {
+        assert_eq!("3000:2:0.7".parse(), Ok(EqBand::peak(3_000.0, 2.0, 0.7)));
+        assert_eq!(
+            "peak:3000:2:0.7".parse(),
+            Ok(EqBand::peak(3_000.0, 2.0, 0.7))
+        );
+        let dynamics = Dynamics {
+            threshold_db: -20.0,
+            ratio: 3.0,
+            ..Dynamics::default()
+        };
+        assert_eq!(
+            "dyn:250:-6:1.4:threshold=-20:ratio=3".parse(),
+            Ok(EqBand::dynamic(250.0, -6.0, 1.4, dynamics))
+        );
+    }
+
+    #[test]
+    fn bad_bands_are_rejected() {
+        let error = |s: &str| s.parse::<EqBand>().unwrap_err();
+        assert_eq!(error("3000:2"), "missing EQ band Q");
+        assert_eq!(error("3000:loud:1"), "invalid EQ band gain \"loud\"");
+        assert_eq!(error("0:2:1"), "EQ band frequency must be positive, got 0");
+        assert_eq!(error("3000:2:0"), "EQ band Q must be positive, got 0");
+        assert_eq!(
+            error("3000:2:1:ratio=2"),
+            "unexpected \"ratio=2\" after a static EQ band, only `dyn` bands take settings"
+        );
+        assert_eq!(
+            error("dyn:250:-6:1:knee=2"),
+            "unknown EQ band setting \"knee\""
+        );
+        assert_eq!(
+            error("dyn:250:-6:1:ratio"),
+            "expected `key=value` in EQ band, got \"ratio\""
+        );
+        assert_eq!(
+            error("dyn:250:-6:1:ratio=0.5"),
+            "EQ band ratio must be at least 1, got 0.5"
+        );
+        assert_eq!(
+            error("dyn:250:-6:1:attack=-1"),
+            "EQ band attack and release must not be negative"
+        );
+    }
+
+    #[test]
+    fn static_bands_boost_and_cut_around_their_frequency() {
+        let bands = [
+            EqBand::peak(1_000.0, 6.0, 1.0),
+            EqBand::peak(8_000.0, -4.0, 1.0),
+        ];
+        let mut eq = ParametricEq::new(&bands, SAMPLE_RATE, 1, None).unwrap();
+        assert!((gain_db(&mut eq, 1_000.0, 0.5) - 6.0).abs() < 0.1);
+        assert!((gain_db(&mut eq, 8_000.0, 0.5) + 4.0).abs() < 0.1);
+        assert!(gain_db(&mut eq, 100.0, 0.5).abs() < 0.1);
+    }
+
+    #[test]
+    fn dynamic_bands_only_move_over_their_threshold() {
+        let bands = [EqBand::dynamic(250.0, -6.0, 1.4, Dynamics::default())];
+        let stats = Arc::new(EqStats::new(&bands).unwrap());
+        let mut eq = ParametricEq::new(&bands, SAMPLE_RATE, 1, Some(stats.clone())).unwrap();
+        // 40 dB under full scale, 10 under the threshold.
+        assert!(gain_db(&mut eq, 250.0, 0.01).abs() < 0.05);
+        assert_eq!(stats.describe(), "EQ: 250 Hz +0.0 dB");
+        // 24 dB over the threshold at a ratio of 2 would be 12 dB down, but the band stops at 6.
+        assert!((gain_db(&mut eq, 250.0, 0.5) + 6.0).abs() < 0.1);
+        assert_eq!(stats.describe(), "EQ: 250 Hz -6.0 dB");
+        // Back down, the gain returns, and the summary keeps how far it went.
+        assert!(gain_db(&mut eq, 250.0, 0.01).abs() < 0.05);
+        assert_eq!(stats.summary(), "250 Hz -6.0 dB");
+    }
+
+    #[test]
+    fn eqs_without_dynamic_bands_have_no_stats() {
+        assert!(EqStats::new(&[EqBand::peak(1_000.0, 6.0, 1.0)]).is_none());
+        let err =
+            ParametricEq::<f32>::new(&[EqBand::peak(24_000.0, 6.0, 1.0)], SAMPLE_RATE, 1, None)
+                .err()
+                .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "cannot put an EQ band at 24000 Hz in a stream at 48000 Hz"
+        );
+    }
+}
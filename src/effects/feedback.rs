@@ -0,0 +1,316 @@
+use crate::buffer::AudioBuffer;
+use crate::effects::{Biquad, Effect, FilterKind, ScratchArena};
+use crate::fft::{self, Fft};
+use crate::logging::{AudioEvent, AudioLog};
+use crate::sample::Sample;
+
+/// Samples per analysis window.
+const WINDOW: usize = 2_048;
+/// Samples between the starts of two analysis windows.
+const HOP: usize = WINDOW / 2;
+/// Number of notches that can be placed at once.
+const NOTCHES: usize = 8;
+/// Q of the notches, narrow enough to leave the programme around them alone.
+const NOTCH_Q: f32 = 30.0;
+/// Consecutive windows a peak must grow in before it counts as feedback.
+const GROWTH_WINDOWS: u32 = 5;
+/// How far above the median level of the spectrum feedback must be, in dB.
+const FEEDBACK_MARGIN_DB: f32 = 25.0;
+/// Level above the median under which a notched frequency counts as quiet again, in dB.
+const RELEASE_MARGIN_DB: f32 = 10.0;
+/// How long a notched frequency must stay quiet before its notch is released, in seconds.
+const RELEASE_TIME: f32 = 10.0;
+/// Bins around a notch in which no other notch is placed.
+const NOTCH_SPREAD: usize = 3;
+
+/// A notch of the pool, while it's placed.
+struct Notch<S> {
+    filter: Biquad<S>,
+    frequency: f32,
+    bin: usize,
+    /// Consecutive analysis windows in which the frequency was quiet.
+    quiet_windows: u32,
+}
+
+/// Detects feedback howl and suppresses it with narrow notches.
+///
+/// The signal, summed to mono, is analysed in overlapping windows. A spectral peak that grows
+/// over several consecutive windows and stands far above the median level of the spectrum is
+/// taken as feedback, and a notch from a small pool is placed at its frequency. Notches are
+/// released once their frequency has been quiet for a while. Placements and releases are logged.
+pub struct FeedbackSuppressor<S = f32> {
+    sample_rate: f32,
+    channels: usize,
+    fft: Fft,
+    window: Vec<f32>,
+    /// The last `WINDOW` mono samples, oldest first from `position`.
+    history: Vec<f32>,
+    position: usize,
+    /// Samples since the last analysis.
+    pending: usize,
+    re: Vec<f32>,
+    im: Vec<f32>,
+    levels: Vec<f32>,
+    previous: Vec<f32>,
+    sorted: Vec<f32>,
+    /// Consecutive windows each bin grew in.
+    growth: Vec<u32>,
+    notches: Vec<Notch<S>>,
+    /// Frames processed so far, for the timestamps of the log.
+    frames: u64,
+    log: AudioLog,
+}
+
+impl<S: Sample> FeedbackSuppressor<S> {
+    pub fn new(sample_rate: f32, channels: usize) -> Self {
+        let bins = WINDOW / 2;
+        FeedbackSuppressor {
+            sample_rate,
+            channels,
+            fft: Fft::new(WINDOW),
+            window: fft::hann(WINDOW),
+            history: vec![0.0; WINDOW],
+            position: 0,
+            pending: 0,
+            re: vec![0.0; WINDOW],
+            im: vec![0.0; WINDOW],
+            levels: vec![0.0; bins],
+            previous: vec![f32::NEG_INFINITY; bins],
+            sorted: vec![0.0; bins],
+            growth: vec![0; bins],
+            notches: Vec::with_capacity(NOTCHES),
+            frames: 0,
+            log: AudioLog::disabled(),
+        }
+    }
+
+    /// Reports the notches placed and released to `log`.
+    pub fn logging(mut self, log: AudioLog) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Frequencies of the notches currently placed, in Hz.
+    pub fn notches(&self) -> impl Iterator<Item = f32> + '_ {
+        self.notches.iter().map(|x| x.frequency)
+    }
+
+    /// Analyses the latest window, placing and releasing notches.
+    fn analyse(&mut self) {
+        let (first, second) = self.history.split_at(self.position);
+        for (i, &x) in second.iter().chain(first).enumerate() {
+            self.re[i] = x * self.window[i];
+            self.im[i] = 0.0;
+        }
+        self.fft.forward(&mut self.re, &mut self.im);
+        for (bin, level) in self.levels.iter_mut().enumerate() {
+            let power = self.re[bin] * self.re[bin] + self.im[bin] * self.im[bin];
+            *level = 10.0 * (power + 1e-20).log10();
+        }
+        self.sorted.copy_from_slice(&self.levels);
+        let middle = self.sorted.len() / 2;
+        let median = *self.sorted.select_nth_unstable_by(middle, f32::total_cmp).1;
+
+        for bin in 0..self.levels.len() {
+            let level = self.levels[bin];
+            self.growth[bin] = match level > self.previous[bin] {
+                true => self.growth[bin] + 1,
+                false => 0,
+            };
+            self.previous[bin] = level;
+        }
+
+        let seconds = self.frames as f32 / self.sample_rate;
+        let release_windows = (RELEASE_TIME * self.sample_rate / HOP as f32) as u32;
+        self.notches.retain_mut(|notch| {
+            if self.levels[notch.bin] < median + RELEASE_MARGIN_DB {
+                notch.quiet_windows += 1;
+            } else {
+                notch.quiet_windows = 0;
+            }
+            let release = notch.quiet_windows > release_windows;
+            if release {
+                self.log.push(AudioEvent::FeedbackReleased {
+                    seconds,
+                    frequency: notch.frequency,
+                });
+            }
+            !release
+        });
+
+        for bin in 1..self.levels.len() - 1 {
+            let level = self.levels[bin];
+            let peak = level > self.levels[bin - 1] && level >= self.levels[bin + 1];
+            if !peak || self.growth[bin] < GROWTH_WINDOWS || level < median + FEEDBACK_MARGIN_DB {
+                continue;
+            }
+            if self
+                .notches
+                .iter()
+                .any(|x| x.bin.abs_diff(bin) <= NOTCH_SPREAD)
+            {
+                continue;
+            }
+            if self.notches.len() == NOTCHES {
+                let oldest = self.notches.remove(0);
+                self.log.push(AudioEvent::FeedbackEvicted {
+                    seconds,
+                    frequency: oldest.frequency,
+                });
+            }
+            let frequency = self.peak_frequency(bin);
+            self.log.push(AudioEvent::FeedbackNotch {
+                seconds,
+                frequency,
+                notch: self.notches.len() + 1,
+                notches: NOTCHES,
+            });
+            self.notches.push(Notch {
+                filter: Biquad::new(
+                    FilterKind::Notch,
+                    frequency,
+                    NOTCH_Q,
+                    self.sample_rate,
+                    self.channels,
+                ),
+                frequency,
+                bin,
+                quiet_windows: 0,
+            });
+        }
+    }
+
+    /// Frequency of the peak at `bin`, refined between the bins by a parabola through the levels
+    /// around it.
+    fn peak_frequency(&self, bin: usize) -> f32 {
+        let (a, b, c) = (self.levels[bin - 1], self.levels[bin], self.levels[bin + 1]);
+        let denominator = a - 2.0 * b + c;
+        let offset = match denominator.abs() > f32::EPSILON {
+            true => (0.5 * (a - c) / denominator).clamp(-0.5, 0.5),
+            false => 0.0,
+        };
+        (bin as f32 + offset) * self.sample_rate / WINDOW as f32
+    }
+}
+
+impl<S: Sample> Effect<S> for FeedbackSuppressor<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, scratch: &mut ScratchArena<S>) {
+        let scale = 1.0 / buffer.channels() as f32;
+        for frame in 0..buffer.frames() {
+            let sum: f32 = (0..buffer.channels())
+                .map(|channel| buffer.channel(channel)[frame].to_sample::<f32>())
+                .sum();
+            self.history[self.position] = sum * scale;
+            self.position = (self.position + 1) % WINDOW;
+            self.pending += 1;
+            if self.pending == HOP {
+                self.pending = 0;
+                self.analyse();
+            }
+            self.frames += 1;
+        }
+        for notch in &mut self.notches {
+            notch.filter.process(buffer, scratch);
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "feedback"
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        // The notches stay on their frequencies, which other bins hold now, and the growth of
+        // the bins starts over.
+        self.frames = (self.frames as f64 * sample_rate as f64 / self.sample_rate as f64) as u64;
+        self.sample_rate = sample_rate;
+        for notch in &mut self.notches {
+            Effect::<S>::set_sample_rate(&mut notch.filter, sample_rate);
+            notch.bin = ((notch.frequency * WINDOW as f32 / sample_rate).round() as usize)
+                .clamp(1, WINDOW / 2 - 2);
+        }
+        self.previous.fill(f32::NEG_INFINITY);
+        self.growth.fill(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+    /// The centre of bin 40.
+    const HOWL: f32 = 40.0 * SAMPLE_RATE / WINDOW as f32;
+
+    /// Runs `input` through `suppressor` in blocks of a hop, returning the output.
+    fn run(suppressor: &mut FeedbackSuppressor, input: &[f32]) -> Vec<f32> {
+        let mut buffer = AudioBuffer::new(1, HOP);
+        let mut scratch = ScratchArena::new(0);
+        let mut output = vec![0.0; input.len()];
+        for (input, output) in input.chunks(HOP).zip(output.chunks_mut(HOP)) {
+            buffer.deinterleave(input);
+            suppressor.process(&mut buffer, &mut scratch);
+            buffer.interleave(output);
+        }
+        output
+    }
+
+    /// `frames` of white noise at about -60 dBFS, for the floor of the spectrum.
+    fn floor(frames: usize) -> Vec<f32> {
+        let mut state = 0x1234_5678u32;
+        (0..frames)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                ((state >> 8) as f32 / (1 << 23) as f32 - 1.0) * 1e-3
+            })
+            .collect()
+    }
+
+    /// A sine at the howl frequency over the floor, from -40 dBFS and growing by `growth_db` a hop.
+    fn howl(frames: usize, growth_db: f32) -> Vec<f32> {
+        let mut signal = floor(frames);
+        for (i, x) in signal.iter_mut().enumerate() {
+            let gain = crate::level::db_to_gain(growth_db * i as f32 / HOP as f32 - 40.0);
+            *x += gain * (2.0 * std::f32::consts::PI * HOWL * i as f32 / SAMPLE_RATE).sin();
+        }
+        signal
+    }
+
+    /// The peak of `data`, past its first `skip` samples.
+    fn peak(data: &[f32], skip: usize) -> f32 {
+        data[skip..].iter().fold(0.0, |x, y| x.max(y.abs()))
+    }
+
+    #[test]
+    fn a_growing_peak_gets_a_notch_on_its_frequency() {
+        let mut suppressor = FeedbackSuppressor::new(SAMPLE_RATE, 1);
+        let input = howl(16 * HOP, 1.0);
+        let output = run(&mut suppressor, &input);
+        let notches: Vec<f32> = suppressor.notches().collect();
+        assert_eq!(notches.len(), 1);
+        assert!((notches[0] - HOWL).abs() < 1.0, "{}", notches[0]);
+        // The howl is turned down from then on.
+        let (input, output) = (peak(&input, 14 * HOP), peak(&output, 14 * HOP));
+        assert!(output < 0.1 * input, "{} {}", output, input);
+    }
+
+    #[test]
+    fn a_steady_tone_is_left_alone() {
+        let mut suppressor = FeedbackSuppressor::new(SAMPLE_RATE, 1);
+        run(&mut suppressor, &howl(20 * HOP, 0.0));
+        assert_eq!(suppressor.notches().count(), 0);
+    }
+
+    #[test]
+    fn notches_are_released_once_quiet() {
+        let mut suppressor = FeedbackSuppressor::new(SAMPLE_RATE, 1);
+        run(&mut suppressor, &howl(10 * HOP, 1.0));
+        assert_eq!(suppressor.notches().count(), 1);
+        let release = (RELEASE_TIME * SAMPLE_RATE) as usize;
+        run(&mut suppressor, &floor(release - 2 * HOP));
+        assert_eq!(suppressor.notches().count(), 1);
+        run(&mut suppressor, &floor(4 * HOP));
+        assert_eq!(suppressor.notches().count(), 0);
+    }
+}
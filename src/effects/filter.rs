@@ -0,0 +1,338 @@
+use std::f64::consts::PI;
+
+use crate::buffer::AudioBuffer;
+use crate::denormal;
+use crate::effects::{Effect, ScratchArena};
+use crate::sample::Sample;
+
+/// Q of a second-order Butterworth response.
+pub const BUTTERWORTH_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Response of a [`Biquad`].
+#[derive(Clone, Copy)]
+pub enum FilterKind {
+    LowPass,
+    HighPass,
+    /// Rejects a narrow band around the frequency, whose width the Q sets.
+    Notch,
+    /// Passes everything at unity gain, turning the phase by 180° at the frequency.
+    AllPass,
+    /// Passes a band around the frequency, whose width the Q sets, at unity gain at its center.
+    BandPass,
+    /// Boosts or cuts a band around the frequency by `gain_db`, passing the rest.
+    Peaking {
+        gain_db: f32,
+    },
+}
+
+/// Normalised coefficients of a biquad section, from the Audio EQ Cookbook.
+///
+/// They're always designed in f64, then rounded to the sample type.
+#[derive(Clone, Copy)]
+pub struct Coefficients<S = f32> {
+    b0: S,
+    b1: S,
+    b2: S,
+    a1: S,
+    a2: S,
+}
+
+impl<S: Sample> Coefficients<S> {
+    pub fn new(kind: FilterKind, frequency: f32, q: f32, sample_rate: f32) -> Self {
+        let (frequency, q, sample_rate) = (frequency as f64, q as f64, sample_rate as f64);
+        let w0 = 2.0 * PI * frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        // The peaking filter moves its poles as well as its zeros with the gain.
+        let a = match kind {
+            FilterKind::Peaking { gain_db } => 10f64.powf(gain_db as f64 / 40.0),
+            _ => 1.0,
+        };
+        let (b0, b1, b2) = match kind {
+            FilterKind::LowPass => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0),
+            FilterKind::HighPass => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0),
+            FilterKind::Notch => (1.0, -2.0 * cos, 1.0),
+            FilterKind::AllPass => (1.0 - alpha, -2.0 * cos, 1.0 + alpha),
+            FilterKind::BandPass => (alpha, 0.0, -alpha),
+            FilterKind::Peaking { .. } => (1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a),
+        };
+        let a0 = 1.0 + alpha / a;
+        Coefficients {
+            b0: S::from_sample(b0 / a0),
+            b1: S::from_sample(b1 / a0),
+            b2: S::from_sample(b2 / a0),
+            a1: S::from_sample(-2.0 * cos / a0),
+            a2: S::from_sample((1.0 - alpha / a) / a0),
+        }
+    }
+}
+
+/// State of one channel of a biquad, in transposed direct form II.
+#[derive(Clone, Copy, Default)]
+struct State<S> {
+    s1: S,
+    s2: S,
+}
+
+impl<S: Sample> State<S> {
+    fn process(&mut self, c: &Coefficients<S>, x: S) -> S {
+        let y = c.b0 * x + self.s1;
+        self.s1 = c.b1 * x - c.a1 * y + self.s2;
+        self.s2 = c.b2 * x - c.a2 * y;
+        y
+    }
+
+    /// Flushes a decaying state to zero before it becomes subnormal.
+    fn flush(&mut self) {
+        self.s1 = denormal::flush(self.s1);
+        self.s2 = denormal::flush(self.s2);
+    }
+}
+
+/// What a section of a [`Cascade`] was designed from, to design it again at another sample rate.
+#[derive(Clone, Copy)]
+struct Design {
+    kind: FilterKind,
+    frequency: f32,
+    q: f32,
+}
+
+/// Fixed biquad sections applied one after another to a single channel, e.g. a higher-order
+/// filter.
+#[derive(Clone, Default)]
+pub struct Cascade<S = f32> {
+    sections: Vec<(Coefficients<S>, State<S>)>,
+    /// The design of each section, if it was pushed as one.
+    designs: Vec<Option<Design>>,
+}
+
+impl<S: Sample> Cascade<S> {
+    pub fn new() -> Self {
+        Cascade {
+            sections: Vec::new(),
+            designs: Vec::new(),
+        }
+    }
+
+    /// Appends a section, starting from silence.
+    pub fn push(&mut self, coefficients: Coefficients<S>) {
+        self.sections.push((coefficients, State::default()));
+        self.designs.push(None);
+    }
+
+    /// Appends a section of `kind` at `frequency` and `q`, starting from silence, which
+    /// [`set_sample_rate`](Self::set_sample_rate) designs again.
+    pub fn design(&mut self, kind: FilterKind, frequency: f32, q: f32, sample_rate: f32) {
+        self.push(Coefficients::new(kind, frequency, q, sample_rate));
+        *self.designs.last_mut().unwrap() = Some(Design { kind, frequency, q });
+    }
+
+    /// Designs the sections appended with [`design`](Self::design) again for `sample_rate`,
+    /// keeping their states, and below its Nyquist frequency.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        for ((coefficients, _), design) in self.sections.iter_mut().zip(&self.designs) {
+            if let Some(design) = design {
+                let frequency = design.frequency.min(sample_rate * 0.49);
+                *coefficients = Coefficients::new(design.kind, frequency, design.q, sample_rate);
+            }
+        }
+    }
+
+    /// Changes the coefficients of the section at `index`, keeping its state, e.g. for a filter
+    /// whose gain moves.
+    pub fn set(&mut self, index: usize, coefficients: Coefficients<S>) {
+        self.sections[index].0 = coefficients;
+    }
+
+    /// Filters one channel in place.
+    pub fn process(&mut self, data: &mut [S]) {
+        for (coefficients, state) in &mut self.sections {
+            dasp::slice::map_in_place(data, |x| state.process(coefficients, x));
+            state.flush();
+        }
+    }
+}
+
+/// Time constant of the glide to a new frequency or Q, in seconds.
+const SMOOTHING_TIME: f32 = 0.02;
+
+/// A second-order (12 dB/octave) filter applied to every channel.
+///
+/// Its frequency and Q glide exponentially to new values, recomputing the coefficients once per
+/// block while they move.
+pub struct Biquad<S = f32> {
+    kind: FilterKind,
+    sample_rate: f32,
+    frequency: f32,
+    q: f32,
+    target_frequency: f32,
+    target_q: f32,
+    coefficients: Coefficients<S>,
+    states: Vec<State<S>>,
+}
+
+impl<S: Sample> Biquad<S> {
+    pub fn new(
+        kind: FilterKind,
+        frequency: f32,
+        q: f32,
+        sample_rate: f32,
+        channels: usize,
+    ) -> Self {
+        Biquad {
+            kind,
+            sample_rate,
+            frequency,
+            q,
+            target_frequency: frequency,
+            target_q: q,
+            coefficients: Coefficients::new(kind, frequency, q, sample_rate),
+            states: vec![State::default(); channels],
+        }
+    }
+
+    /// Moves the frequency and Q one block of `frames` closer to their targets.
+    fn glide(&mut self, frames: usize) {
+        if self.frequency == self.target_frequency && self.q == self.target_q {
+            return;
+        }
+        let amount = 1.0 - (-(frames as f32) / (SMOOTHING_TIME * self.sample_rate)).exp();
+        // Frequencies glide in octaves, so that sweeps sound even.
+        self.frequency *= (self.target_frequency / self.frequency).powf(amount);
+        self.q += (self.target_q - self.q) * amount;
+        if (self.frequency / self.target_frequency - 1.0).abs() < 1e-3 {
+            self.frequency = self.target_frequency;
+        }
+        if (self.q - self.target_q).abs() < 1e-3 {
+            self.q = self.target_q;
+        }
+        self.coefficients = Coefficients::new(self.kind, self.frequency, self.q, self.sample_rate);
+    }
+}
+
+impl<S: Sample> Effect<S> for Biquad<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        self.glide(buffer.frames());
+        let coefficients = self.coefficients;
+        for (channel, state) in buffer.channels_mut().zip(&mut self.states) {
+            dasp::slice::map_in_place(channel, |x| state.process(&coefficients, x));
+            state.flush();
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "filter"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &["freq", "q"]
+    }
+
+    fn param(&self, index: usize) -> f32 {
+        match index {
+            0 => self.target_frequency,
+            _ => self.target_q,
+        }
+    }
+
+    fn set_param(&mut self, index: usize, value: f32) {
+        match index {
+            // Keep the filter stable whatever it's asked for.
+            0 => self.target_frequency = value.clamp(1.0, self.sample_rate * 0.49),
+            _ => self.target_q = value.max(0.1),
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.target_frequency = self.target_frequency.min(sample_rate * 0.49);
+        self.frequency = self.frequency.min(sample_rate * 0.49);
+        self.coefficients = Coefficients::new(self.kind, self.frequency, self.q, sample_rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// The steady-state gain of `process` at `frequency`, in dB, from the RMS of a sine through
+    /// it.
+    fn response(mut process: impl FnMut(&mut [f32]), frequency: f32) -> f32 {
+        let frames = 9_600;
+        let mut sine: Vec<f32> = (0..frames)
+            .map(|x| (2.0 * std::f32::consts::PI * frequency * x as f32 / SAMPLE_RATE).sin())
+            .collect();
+        process(&mut sine);
+        // Past the transient.
+        let tail = &sine[frames / 2..];
+        let rms = (tail.iter().map(|x| x * x).sum::<f32>() / tail.len() as f32).sqrt();
+        crate::level::gain_to_db(rms * std::f32::consts::SQRT_2)
+    }
+
+    /// The response of a Butterworth biquad of `kind` at 1 kHz.
+    fn biquad(kind: FilterKind, frequency: f32) -> f32 {
+        let mut biquad: Biquad = Biquad::new(kind, 1_000.0, BUTTERWORTH_Q, SAMPLE_RATE, 1);
+        let process = |data: &mut [f32]| {
+            let mut buffer = AudioBuffer::new(1, data.len());
+            buffer.deinterleave(data);
+            biquad.process(&mut buffer, &mut ScratchArena::new(0));
+            buffer.interleave(data);
+        };
+        response(process, frequency)
+    }
+
+    #[test]
+    fn a_low_pass_passes_the_lows_and_falls_12_db_an_octave() {
+        let at = |x| biquad(FilterKind::LowPass, x);
+        assert!(at(50.0).abs() < 0.1, "{}", at(50.0));
+        assert!((at(1_000.0) + 3.01).abs() < 0.1, "{}", at(1_000.0));
+        let (octave, two_octaves) = (at(4_000.0), at(8_000.0));
+        assert!(
+            (two_octaves - octave + 12.0).abs() < 1.5,
+            "{} {}",
+            octave,
+            two_octaves
+        );
+    }
+
+    #[test]
+    fn a_high_pass_passes_the_highs() {
+        let at = |x| biquad(FilterKind::HighPass, x);
+        assert!(at(10_000.0).abs() < 0.1, "{}", at(10_000.0));
+        assert!((at(1_000.0) + 3.01).abs() < 0.1, "{}", at(1_000.0));
+        assert!(at(100.0) < -38.0, "{}", at(100.0));
+    }
+
+    #[test]
+    fn cascaded_sections_add_up() {
+        let mut cascade: Cascade = Cascade::new();
+        for _ in 0..2 {
+            cascade.design(FilterKind::LowPass, 1_000.0, BUTTERWORTH_Q, SAMPLE_RATE);
+        }
+        let single = biquad(FilterKind::LowPass, 4_000.0);
+        let double = response(|x| cascade.process(x), 4_000.0);
+        assert!((double - 2.0 * single).abs() < 0.1, "{} {}", single, double);
+    }
+
+    #[test]
+    fn states_decaying_in_silence_end_at_zero_not_subnormal() {
+        let mut biquad: Biquad = Biquad::new(FilterKind::LowPass, 100.0, 4.0, SAMPLE_RATE, 1);
+        let mut buffer = AudioBuffer::new(1, 4_800);
+        let mut impulse = vec![0.0; 4_800];
+        impulse[0] = 1.0;
+        buffer.deinterleave(&impulse);
+        let mut scratch = ScratchArena::new(0);
+        biquad.process(&mut buffer, &mut scratch);
+        let mut blocks = 0;
+        while biquad.states[0].s1 != 0.0 || biquad.states[0].s2 != 0.0 {
+            buffer.deinterleave(&[0.0; 4_800]);
+            biquad.process(&mut buffer, &mut scratch);
+            let state = biquad.states[0];
+            assert!(!state.s1.is_subnormal() && !state.s2.is_subnormal());
+            blocks += 1;
+            assert!(blocks < 1_000, "the state never reached zero");
+        }
+    }
+}
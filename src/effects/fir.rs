@@ -0,0 +1,403 @@
+use std::marker::PhantomData;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::buffer::AudioBuffer;
+use crate::convolve::{self, Convolver};
+use crate::effects::{Effect, ScratchArena};
+use crate::sample::Sample;
+use crate::simd;
+use crate::wav;
+
+/// The coefficients of FIR filters, one filter per channel.
+#[derive(Clone, Debug)]
+pub struct FirTaps {
+    pub channels: Vec<Vec<f32>>,
+    /// Rate the filters were designed for, when the file says.
+    pub sample_rate: Option<u32>,
+}
+
+impl FirTaps {
+    /// Reads the filters of a WAV file, one per channel, or of a text file of coefficients.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let is_wav = path
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("wav"));
+        let taps = if is_wav {
+            let data = wav::read(path)
+                .with_context(|| format!("failed to read \"{}\"", path.display()))?;
+            let channels = data.channels as usize;
+            FirTaps {
+                channels: (0..channels)
+                    .map(|channel| {
+                        data.samples
+                            .iter()
+                            .skip(channel)
+                            .step_by(channels)
+                            .copied()
+                            .collect()
+                    })
+                    .collect(),
+                sample_rate: Some(data.sample_rate),
+            }
+        } else {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read \"{}\"", path.display()))?;
+            FirTaps::parse(&text).with_context(|| format!("in \"{}\"", path.display()))?
+        };
+        if taps.is_empty() {
+            bail!("\"{}\" has no filter coefficients", path.display());
+        }
+        Ok(taps)
+    }
+
+    /// Parses one coefficient per line, or comma-separated coefficients for several channels.
+    ///
+    /// Empty lines and lines starting with `#` or `*`, as in the exports of measurement tools,
+    /// are skipped.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut channels: Vec<Vec<f32>> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('*') {
+                continue;
+            }
+            let row: Vec<f32> = line
+                .split(',')
+                .map(|x| x.trim().parse().ok().filter(|x: &f32| x.is_finite()))
+                .collect::<Option<_>>()
+                .with_context(|| {
+                    format!("line {}: invalid coefficients \"{}\"", number + 1, line)
+                })?;
+            if channels.is_empty() {
+                channels = vec![Vec::new(); row.len()];
+            } else if row.len() != channels.len() {
+                bail!(
+                    "line {}: expected {} coefficients, got {}",
+                    number + 1,
+                    channels.len(),
+                    row.len()
+                );
+            }
+            for (channel, x) in channels.iter_mut().zip(row) {
+                channel.push(x);
+            }
+        }
+        Ok(FirTaps {
+            channels,
+            sample_rate: None,
+        })
+    }
+
+    /// Length of the longest filter.
+    pub fn len(&self) -> usize {
+        self.channels.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The taps of a filter designed at `from` Hz, for `to` Hz: its impulse response interpolated
+/// linearly at the new rate, and scaled for the same gain.
+pub fn resample_taps(taps: &[f32], from: u32, to: u32) -> Vec<f32> {
+    let step = from as f64 / to as f64;
+    let length = ((taps.len() as f64 / step).round() as usize).max(1);
+    (0..length)
+        .map(|m| {
+            let position = m as f64 * step;
+            let index = position.floor() as usize;
+            let fraction = (position - index as f64) as f32;
+            let a = taps.get(index).copied().unwrap_or(0.0);
+            let b = taps.get(index + 1).copied().unwrap_or(0.0);
+            (a + (b - a) * fraction) * step as f32
+        })
+        .collect()
+}
+
+/// Filters up to this many taps are convolved directly, without latency.
+pub const DIRECT_MAX_TAPS: usize = 127;
+
+/// Convolves each channel with its own FIR filter, e.g. to correct the response of a speaker.
+///
+/// Short filters are convolved directly, sample by sample. Longer ones run on the partitioned
+/// [`Convolver`], which delays every channel by [`convolve::PARTITION`] frames, those without a
+/// filter too, so that they stay aligned with the others. Both run in f32 whatever the chain's
+/// precision.
+///
+/// Filters designed at a known rate follow the stream to another one with their taps resampled
+/// by [`resample_taps`], keeping the engine, and so the latency, they started with.
+pub struct Fir<S = f32> {
+    engine: Engine,
+    /// The filters as given, the rate they're for once known, and the channels of the stream.
+    filters: Vec<Vec<f32>>,
+    design_rate: Option<u32>,
+    channels: usize,
+    _sample: PhantomData<S>,
+}
+
+enum Engine {
+    /// The reversed taps of each channel, with their inputs.
+    Direct(Vec<(Vec<f32>, DirectLine)>),
+    Partitioned(Vec<Convolver>),
+}
+
+/// The last inputs of a filter convolved directly, twice over, so that they're always
+/// contiguous.
+pub(super) struct DirectLine {
+    history: Vec<f32>,
+    position: usize,
+}
+
+impl DirectLine {
+    /// The inputs of a filter of `taps` taps, silent so far.
+    pub(super) fn new(taps: usize) -> Self {
+        DirectLine {
+            history: vec![0.0; taps.max(1) * 2],
+            position: 0,
+        }
+    }
+
+    /// Takes the next input, returning the last ones, oldest first, for reversed taps.
+    pub(super) fn push(&mut self, x: f32) -> &[f32] {
+        let len = self.history.len() / 2;
+        self.position = (self.position + 1) % len;
+        self.history[self.position] = x;
+        self.history[self.position + len] = x;
+        &self.history[self.position + 1..self.position + 1 + len]
+    }
+}
+
+impl<S: Sample> Fir<S> {
+    /// Creates the filters of a stream of `channels` channels. `filters[n]` is the filter of
+    /// channel `n`, and channels past the filters, or without taps, only get the latency.
+    pub fn new(filters: &[Vec<f32>], channels: usize) -> Self {
+        let taps = filters.iter().map(Vec::len).max().unwrap_or(0);
+        Fir {
+            engine: Engine::new(filters, channels, taps > DIRECT_MAX_TAPS),
+            filters: filters.to_vec(),
+            design_rate: None,
+            channels,
+            _sample: PhantomData,
+        }
+    }
+
+    /// Says the filters are for `sample_rate`, for them to follow the stream to another rate.
+    pub fn designed_at(mut self, sample_rate: u32) -> Self {
+        self.design_rate = Some(sample_rate);
+        self
+    }
+
+    /// Frames of latency of filters of `taps` taps.
+    pub fn latency(taps: usize) -> usize {
+        match taps <= DIRECT_MAX_TAPS {
+            true => 0,
+            false => convolve::PARTITION,
+        }
+    }
+}
+
+impl Engine {
+    /// The engine of a stream of `channels` channels for `filters`, partitioned or direct.
+    fn new(filters: &[Vec<f32>], channels: usize, partitioned: bool) -> Self {
+        let filter = |channel: usize| match filters.get(channel) {
+            Some(taps) if !taps.is_empty() => taps.as_slice(),
+            _ => &[1.0],
+        };
+        if !partitioned {
+            Engine::Direct(
+                (0..channels)
+                    .map(|channel| {
+                        let reversed: Vec<f32> = filter(channel).iter().rev().copied().collect();
+                        let line = DirectLine::new(reversed.len());
+                        (reversed, line)
+                    })
+                    .collect(),
+            )
+        } else {
+            Engine::Partitioned((0..channels).map(|x| Convolver::new(filter(x))).collect())
+        }
+    }
+}
+
+impl<S: Sample> Effect<S> for Fir<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        match &mut self.engine {
+            Engine::Direct(channels) => {
+                for (data, (taps, line)) in buffer.channels_mut().zip(channels) {
+                    for x in data {
+                        *x = S::from_sample(simd::dot(taps, line.push(x.to_sample())));
+                    }
+                }
+            }
+            Engine::Partitioned(convolvers) => {
+                for (data, convolver) in buffer.channels_mut().zip(convolvers) {
+                    for x in data {
+                        *x = S::from_sample(convolver.process_sample(x.to_sample()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "fir"
+    }
+
+    fn latency_frames(&self) -> usize {
+        match self.engine {
+            Engine::Direct(_) => 0,
+            Engine::Partitioned(_) => convolve::PARTITION,
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let Some(from) = self.design_rate else {
+            return;
+        };
+        let to = sample_rate as u32;
+        let filters: Vec<Vec<f32>> = match from == to {
+            true => self.filters.clone(),
+            false => self
+                .filters
+                .iter()
+                .map(|x| match x.is_empty() {
+                    true => Vec::new(),
+                    false => resample_taps(x, from, to),
+                })
+                .collect(),
+        };
+        let partitioned = matches!(self.engine, Engine::Partitioned(_));
+        self.engine = Engine::new(&filters, self.channels, partitioned);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coefficients_parse_in_columns_skipping_comments() {
+        let taps = FirTaps::parse("# exported\n* by a tool\n\n0.5, 1\n0.25,-1\n").unwrap();
+        assert_eq!(taps.channels, [vec![0.5, 0.25], vec![1.0, -1.0]]);
+        assert_eq!((taps.len(), taps.sample_rate), (2, None));
+        let err = FirTaps::parse("0.5, 1\n0.25\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected 2 coefficients, got 1");
+        let err = FirTaps::parse("# header\n0.5\nnan\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 3: invalid coefficients \"nan\"");
+    }
+
+    #[test]
+    fn files_without_coefficients_are_rejected() {
+        let path = std::env::temp_dir().join(format!("fir-{}.txt", std::process::id()));
+        std::fs::write(&path, "# nothing but a header\n").unwrap();
+        let err = FirTaps::load(&path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("\"{}\" has no filter coefficients", path.display())
+        );
+        std::fs::write(&path, "0.5\n0.5\n").unwrap();
+        assert_eq!(FirTaps::load(&path).unwrap().channels, [vec![0.5, 0.5]]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn channels_without_a_long_filter_stay_aligned_with_it() {
+        let mut taps = vec![0.0; 300];
+        taps[0] = 0.5;
+        let mut fir = Fir::<f32>::new(&[taps], 2);
+        assert_eq!(Effect::<f32>::latency_frames(&fir), convolve::PARTITION);
+        let frames = convolve::PARTITION + 4;
+        let mut impulse = vec![0.0; frames * 2];
+        impulse[..2].fill(1.0);
+        let mut buffer = AudioBuffer::new(2, frames);
+        buffer.deinterleave(&impulse);
+        fir.process(&mut buffer, &mut ScratchArena::new(0));
+        for (channel, gain) in [(0, 0.5), (1, 1.0)] {
+            for (i, x) in buffer.channel(channel).iter().enumerate() {
+                let expected = if i == convolve::PARTITION { gain } else { 0.0 };
+                assert!((x - expected).abs() < 1e-6, "{} {}: {}", channel, i, x);
+            }
+        }
+    }
+
+    #[test]
+    fn short_filters_are_convolved_without_latency() {
+        let mut fir = Fir::<f32>::new(&[vec![1.0 / 3.0; 3]], 1);
+        assert_eq!(Effect::<f32>::latency_frames(&fir), 0);
+        let mut buffer = AudioBuffer::new(1, 2);
+        let mut output = Vec::new();
+        for block in [[1.0, 0.0], [0.0, 0.0]] {
+            buffer.deinterleave(&block);
+            fir.process(&mut buffer, &mut ScratchArena::new(0));
+            output.extend_from_slice(buffer.channel(0));
+        }
+        assert_eq!(output, [1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0, 0.0]);
+    }
+
+    #[test]
+    fn the_engine_follows_the_longest_filter() {
+        assert_eq!(Fir::<f32>::latency(DIRECT_MAX_TAPS), 0);
+        assert_eq!(
+            Fir::<f32>::latency(DIRECT_MAX_TAPS + 1),
+            convolve::PARTITION
+        );
+        let short = vec![0.5; DIRECT_MAX_TAPS];
+        let long = vec![0.5; DIRECT_MAX_TAPS + 1];
+        let fir = Fir::<f32>::new(&[short.clone(), long], 2);
+        assert_eq!(Effect::<f32>::latency_frames(&fir), convolve::PARTITION);
+        let fir = Fir::<f64>::new(&[short], 2);
+        assert_eq!(Effect::<f64>::latency_frames(&fir), 0);
+    }
+
+    #[test]
+    fn resampled_taps_keep_the_gain_of_the_filter() {
+        // A smooth low-pass impulse response, whose sum is its gain at DC.
+        let taps: Vec<f32> = (0..64)
+            .map(|n| (-(n as f32 - 20.0).powi(2) / 50.0).exp())
+            .collect();
+        let gain: f32 = taps.iter().sum();
+        for (from, to) in [(48_000, 96_000), (48_000, 44_100), (96_000, 48_000)] {
+            let resampled = resample_taps(&taps, from, to);
+            let expected = (taps.len() as f64 * to as f64 / from as f64).round() as usize;
+            assert_eq!(resampled.len(), expected);
+            let sum: f32 = resampled.iter().sum();
+            assert!(
+                (sum / gain - 1.0).abs() < 1e-3,
+                "{} to {}: {}",
+                from,
+                to,
+                sum
+            );
+        }
+    }
+
+    #[test]
+    fn the_filter_follows_the_stream_to_another_rate() {
+        let taps = vec![0.25, 0.5, 0.25];
+        let mut fir = Fir::<f32>::new(std::slice::from_ref(&taps), 1).designed_at(48_000);
+        Effect::<f32>::set_sample_rate(&mut fir, 96_000.0);
+        let mut buffer = AudioBuffer::new(1, 16);
+        let mut impulse = vec![0.0; 16];
+        impulse[0] = 1.0;
+        buffer.deinterleave(&impulse);
+        fir.process(&mut buffer, &mut ScratchArena::new(0));
+        assert_eq!(
+            &buffer.channel(0)[..6],
+            &resample_taps(&taps, 48_000, 96_000)[..]
+        );
+        assert_eq!(Effect::<f32>::latency_frames(&fir), 0);
+    }
+
+    #[test]
+    fn filters_of_no_known_rate_stay_as_they_are() {
+        let mut fir = Fir::<f32>::new(&[vec![0.5, 0.5]], 1);
+        Effect::<f32>::set_sample_rate(&mut fir, 96_000.0);
+        let mut buffer = AudioBuffer::new(1, 4);
+        buffer.deinterleave(&[1.0, 0.0, 0.0, 0.0]);
+        fir.process(&mut buffer, &mut ScratchArena::new(0));
+        assert_eq!(buffer.channel(0), &[0.5, 0.5, 0.0, 0.0]);
+    }
+}
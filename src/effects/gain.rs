@@ -0,0 +1,59 @@
+use crate::buffer::AudioBuffer;
+use crate::effects::{Effect, ScratchArena};
+use crate::level;
+use crate::sample::Sample;
+
+/// Scales every channel by a gain, ramping linearly over one block when it changes.
+pub struct Gain<S = f32> {
+    db: f32,
+    gain: S,
+    target: S,
+}
+
+impl<S: Sample> Gain<S> {
+    pub fn new(db: f32) -> Self {
+        let gain = S::from_sample(level::db_to_gain(db));
+        Gain {
+            db,
+            gain,
+            target: gain,
+        }
+    }
+}
+
+impl<S: Sample> Effect<S> for Gain<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        if self.gain == self.target || buffer.frames() == 0 {
+            for channel in buffer.channels_mut() {
+                S::apply_gain(channel, self.target);
+            }
+        } else {
+            let step = (self.target - self.gain) / S::from_sample(buffer.frames() as f64);
+            for channel in buffer.channels_mut() {
+                let mut gain = self.gain;
+                for x in channel {
+                    gain = gain + step;
+                    *x = *x * gain;
+                }
+            }
+        }
+        self.gain = self.target;
+    }
+
+    fn name(&self) -> &'static str {
+        "gain"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &["db"]
+    }
+
+    fn param(&self, _index: usize) -> f32 {
+        self.db
+    }
+
+    fn set_param(&mut self, _index: usize, value: f32) {
+        self.db = value;
+        self.target = S::from_sample(level::db_to_gain(value));
+    }
+}
@@ -0,0 +1,160 @@
+use std::str::FromStr;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Effect, ScratchArena};
+use crate::envelope::EnvelopeFollower;
+use crate::level;
+use crate::sample::Sample;
+
+/// Noise gate settings, colon-separated `key=value` pairs on the command line, e.g.
+/// `threshold=-45:hold=80`:
+///
+/// - `threshold`: peak level the gate opens at, in dBFS, -45 by default.
+/// - `range`: how far the closed gate turns the signal down, in dB, 40 by default.
+/// - `attack`, `release`: how fast the gate opens and closes, in milliseconds, 1 and 100 by
+///   default.
+/// - `hold`: how long the gate stays open once the signal falls under the threshold, in
+///   milliseconds, 50 by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GateSpec {
+    pub threshold_db: f32,
+    pub range_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    pub hold_ms: f32,
+}
+
+impl Default for GateSpec {
+    fn default() -> Self {
+        GateSpec {
+            threshold_db: -45.0,
+            range_db: 40.0,
+            attack_ms: 1.0,
+            release_ms: 100.0,
+            hold_ms: 50.0,
+        }
+    }
+}
+
+impl FromStr for GateSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut spec = GateSpec::default();
+        for pair in s.split(':').filter(|x| !x.is_empty()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!(
+                    "expected `key=value` in the gate, got \"{}\"",
+                    pair
+                ));
+            };
+            let value = value
+                .parse::<f32>()
+                .ok()
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| format!("invalid gate {} \"{}\"", key, value))?;
+            match key {
+                "threshold" => spec.threshold_db = value,
+                "range" => spec.range_db = value,
+                "attack" => spec.attack_ms = value,
+                "release" => spec.release_ms = value,
+                "hold" => spec.hold_ms = value,
+                _ => return Err(format!("unknown gate setting \"{}\"", key)),
+            }
+        }
+        if spec.range_db < 0.0 {
+            return Err(format!(
+                "gate range must not be negative, got {}",
+                spec.range_db
+            ));
+        }
+        if spec.attack_ms < 0.0 || spec.release_ms < 0.0 || spec.hold_ms < 0.0 {
+            return Err("gate attack, release and hold must not be negative".to_string());
+        }
+        Ok(spec)
+    }
+}
+
+/// Turns the signal down while it stays under a threshold, e.g. the breaths and room noise
+/// between phrases.
+///
+/// The gate opens as soon as the peak of a frame, on any channel, reaches the threshold, and
+/// closes once it stayed under it for the hold time, which also keeps it from chattering on the
+/// slopes of low notes. Its gain moves between 0 dB and the range with the attack and release
+/// times.
+pub struct Gate {
+    spec: GateSpec,
+    /// Gain when closed.
+    floor: f32,
+    gain: EnvelopeFollower,
+    /// Frames left before the gate closes, and those the hold lasts.
+    holding: usize,
+    hold: usize,
+    sample_rate: f32,
+}
+
+impl Gate {
+    /// Creates a closed gate for a stream at `sample_rate`.
+    pub fn new(spec: GateSpec, sample_rate: f32) -> Self {
+        Gate {
+            spec,
+            floor: level::db_to_gain(-spec.range_db),
+            gain: EnvelopeFollower::new(spec.attack_ms, spec.release_ms, sample_rate as u32),
+            holding: 0,
+            hold: hold_frames(spec, sample_rate),
+            sample_rate,
+        }
+    }
+}
+
+/// Frames the hold of `spec` lasts at `sample_rate`.
+fn hold_frames(spec: GateSpec, sample_rate: f32) -> usize {
+    (spec.hold_ms / 1_000.0 * sample_rate) as usize
+}
+
+impl<S: Sample> Effect<S> for Gate {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        let threshold = level::db_to_gain(self.spec.threshold_db);
+        for n in 0..buffer.frames() {
+            let peak = (0..buffer.channels())
+                .map(|x| buffer.channel(x)[n].to_sample::<f32>().abs())
+                .fold(0.0f32, f32::max);
+            if peak >= threshold {
+                self.holding = self.hold.max(1);
+            }
+            let target = match self.holding > 0 {
+                true => 1.0,
+                false => self.floor,
+            };
+            self.holding = self.holding.saturating_sub(1);
+            let gain = S::from_sample(self.gain.process(target));
+            for channel in buffer.channels_mut() {
+                channel[n] = channel[n] * gain;
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "gate"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &["threshold"]
+    }
+
+    fn param(&self, _index: usize) -> f32 {
+        self.spec.threshold_db
+    }
+
+    fn set_param(&mut self, _index: usize, value: f32) {
+        self.spec.threshold_db = value;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.gain
+            .change_rate(self.sample_rate as u32, sample_rate as u32);
+        self.holding = (self.holding as f32 * sample_rate / self.sample_rate) as usize;
+        self.hold = hold_frames(self.spec, sample_rate);
+        self.sample_rate = sample_rate;
+    }
+}
@@ -0,0 +1,116 @@
+use std::str::FromStr;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Effect, ScratchArena};
+use crate::level;
+use crate::sample::Sample;
+
+/// Limiter settings, `<ceiling-dBFS>[:<release-ms>]` on the command line, e.g. "-1" or "-1:50",
+/// with a release of 50 ms by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LimiterSpec {
+    pub ceiling_db: f32,
+    pub release_ms: f32,
+}
+
+impl FromStr for LimiterSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ceiling, release) = s.split_once(':').unwrap_or((s, "50"));
+        let ceiling_db = ceiling
+            .parse::<f32>()
+            .ok()
+            .filter(|x| x.is_finite() && *x <= 0.0)
+            .ok_or_else(|| {
+                format!(
+                    "invalid limiter ceiling \"{}\", expected up to 0 dBFS",
+                    ceiling
+                )
+            })?;
+        let release_ms = release
+            .parse::<f32>()
+            .ok()
+            .filter(|x| x.is_finite() && *x >= 0.0)
+            .ok_or_else(|| format!("invalid limiter release \"{}\"", release))?;
+        Ok(LimiterSpec {
+            ceiling_db,
+            release_ms,
+        })
+    }
+}
+
+/// Keeps the peaks of every channel under a ceiling.
+///
+/// The gain drops at once to what keeps the loudest sample of a frame at the ceiling, and comes
+/// back up with the release time. Without lookahead, the first sample of a peak sets the gain
+/// for it, so the ceiling holds for every sample, at the cost of some distortion on the fastest
+/// peaks.
+pub struct Limiter {
+    spec: LimiterSpec,
+    ceiling: f32,
+    /// How far the gain comes back up towards 1 every frame.
+    release: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(spec: LimiterSpec, sample_rate: f32) -> Self {
+        Limiter {
+            spec,
+            ceiling: level::db_to_gain(spec.ceiling_db),
+            release: release(spec, sample_rate),
+            gain: 1.0,
+        }
+    }
+}
+
+/// How far the gain of a limiter of `spec` at `sample_rate` comes back up every frame.
+fn release(spec: LimiterSpec, sample_rate: f32) -> f32 {
+    let frames = spec.release_ms / 1_000.0 * sample_rate;
+    match frames > 0.0 {
+        true => 1.0 - (-1.0 / frames).exp(),
+        false => 1.0,
+    }
+}
+
+impl<S: Sample> Effect<S> for Limiter {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        for n in 0..buffer.frames() {
+            let peak = (0..buffer.channels())
+                .map(|x| buffer.channel(x)[n].to_sample::<f32>().abs())
+                .fold(0.0f32, f32::max);
+            self.gain += (1.0 - self.gain) * self.release;
+            if peak * self.gain > self.ceiling {
+                self.gain = self.ceiling / peak;
+            }
+            if self.gain < 1.0 {
+                let gain = S::from_sample(self.gain);
+                for channel in buffer.channels_mut() {
+                    channel[n] = channel[n] * gain;
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "limiter"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &["ceiling"]
+    }
+
+    fn param(&self, _index: usize) -> f32 {
+        self.spec.ceiling_db
+    }
+
+    fn set_param(&mut self, _index: usize, value: f32) {
+        self.spec.ceiling_db = value.min(0.0);
+        self.ceiling = level::db_to_gain(self.spec.ceiling_db);
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.release = release(self.spec, sample_rate);
+    }
+}
@@ -0,0 +1,769 @@
+//! The effect chain applied to the monitor feed of each output.
+//!
+//! Effects implement [`Effect`] and work on [`AudioBuffer`]s, one dasp frame slice per channel.
+//! Effects still written against raw interleaved samples implement [`RawEffect`] instead, and are
+//! inserted through the [`Interleaved`] shim until they're ported.
+//!
+//! Effects and chains are generic over the [`Sample`] type they run in, f32 by default.
+//!
+//! Effects can expose parameters, which the chain names in a [`ParamLayout`] and updates from the
+//! snapshots of a [`ParamStore`](crate::params::ParamStore). Effects move to new parameter values
+//! smoothly themselves, since only they know how to do it without clicks. [`Lfo`]s attached to
+//! the chain offset their targets on top of that, once per block.
+//!
+//! A chain can also time its effects, for a [`Profiler`] to average, and tells how late they make
+//! the signal, for the [`LatencyBudget`](crate::latency::LatencyBudget). A profiled chain bypasses
+//! the effects its [`ProfileStats`] say to, crossfading over [`BYPASS_FADE`], and without making
+//! up for their latency. Guarded chains check the
+//! block after each effect, to blame the first one that makes it non-finite. [`Tap`]s see the
+//! block between any two effects, e.g. to meter it.
+//!
+//! Effects take their temporary buffers from the chain's [`ScratchArena`], which
+//! [`EffectChain::prepare`] sizes for the largest block before the stream starts, so that the
+//! block path doesn't allocate.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::buffer::AudioBuffer;
+use crate::guard::GuardStats;
+use crate::lfo::Lfo;
+use crate::logging::{AudioEvent, AudioLog};
+use crate::params::{ParamLayout, ParamReader};
+use crate::profile::{ProfileStats, Profiler};
+use crate::sample::Sample;
+
+mod agc;
+mod bass;
+mod cab;
+mod crossover;
+mod delay;
+mod eq;
+mod feedback;
+mod filter;
+mod fir;
+mod gain;
+mod gate;
+mod limiter;
+mod noise;
+mod notch;
+#[cfg(feature = "clap-plugins")]
+mod plugin;
+mod rumble;
+mod scratch;
+mod script;
+mod upmix;
+
+pub use agc::{Agc, AgcSpec, AgcStats};
+pub use bass::BassManager;
+pub use cab::{CabIrs, CabSim, CabSource, CabSpec, MAX_CAB_TAPS};
+pub use crossover::{BandSpec, Crossover, CrossoverSpec, Route};
+pub use delay::{ChannelDelay, ChannelDelaySpec};
+pub use eq::{BandStats, Dynamics, EqBand, EqStats, ParametricEq};
+pub use feedback::FeedbackSuppressor;
+pub use filter::{Biquad, Cascade, Coefficients, FilterKind, BUTTERWORTH_Q};
+pub use fir::{resample_taps, Fir, FirTaps};
+pub use gain::Gain;
+pub use gate::{Gate, GateSpec};
+pub use limiter::{Limiter, LimiterSpec};
+pub use noise::{LearnTrigger, NoiseReducer};
+pub use notch::{AdaptiveNotch, NotchWindow};
+#[cfg(feature = "clap-plugins")]
+pub use plugin::{ClapPlugin, PluginSpec};
+pub use rumble::{butterworth_qs, RumbleFilter, RumbleSpec, SLOPES};
+pub use scratch::ScratchArena;
+pub use script::ScriptEffect;
+pub use upmix::{parse_rears, Upmix, UpmixLayout};
+
+/// A processing stage working on deinterleaved blocks.
+pub trait Effect<S: Sample = f32>: Send {
+    /// Processes the block in place, with its temporary buffers taken from `scratch`.
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, scratch: &mut ScratchArena<S>);
+
+    /// Kind of the effect in parameter names, e.g. "filter".
+    fn name(&self) -> &'static str {
+        "effect"
+    }
+
+    /// Names of the parameters of the effect, the first one being its main parameter.
+    fn params(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Current target of the parameter at `index` in [`params`](Effect::params).
+    fn param(&self, _index: usize) -> f32 {
+        0.0
+    }
+
+    /// Sets a new target for the parameter at `index`, which the effect glides to.
+    fn set_param(&mut self, _index: usize, _value: f32) {}
+
+    /// Frames the effect delays the whole signal by, e.g. to fill a window.
+    fn latency_frames(&self) -> usize {
+        0
+    }
+
+    /// Frames of every channel the effect takes from the [`ScratchArena`] on top of those of its
+    /// block, e.g. for a window longer than a block.
+    fn scratch_frames(&self) -> usize {
+        0
+    }
+
+    /// Re-derives everything that depends on the sample rate for one of `sample_rate`, e.g. once
+    /// the device changed it. Resizing delays may allocate, unlike [`process`](Effect::process).
+    fn set_sample_rate(&mut self, _sample_rate: f32) {}
+}
+
+/// Sees the block at a point of a chain, between two of its effects, without changing it.
+pub trait Tap<S: Sample = f32>: Send {
+    fn tap(&mut self, buffer: &AudioBuffer<S>);
+
+    /// Re-derives what depends on the sample rate, as [`Effect::set_sample_rate`] does.
+    fn set_sample_rate(&mut self, _sample_rate: u32) {}
+}
+
+/// A processing stage working on raw interleaved samples.
+///
+/// The rest mirrors [`Effect`], which the [`Interleaved`] shim passes on.
+pub trait RawEffect: Send {
+    /// Processes interleaved `data` with `channels` samples per frame in place.
+    fn process_interleaved(&mut self, data: &mut [f32], channels: usize);
+
+    fn name(&self) -> &'static str {
+        "effect"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn param(&self, _index: usize) -> f32 {
+        0.0
+    }
+
+    fn set_param(&mut self, _index: usize, _value: f32) {}
+
+    fn set_sample_rate(&mut self, _sample_rate: f32) {}
+}
+
+/// Runs a [`RawEffect`] as an [`Effect`], by interleaving the block around it.
+///
+/// The interleaved samples are f32 whatever the chain runs in.
+pub struct Interleaved<E> {
+    effect: E,
+}
+
+impl<E: RawEffect> Interleaved<E> {
+    pub fn new(effect: E) -> Self {
+        Interleaved { effect }
+    }
+}
+
+impl<S: Sample, E: RawEffect> Effect<S> for Interleaved<E> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, scratch: &mut ScratchArena<S>) {
+        let channels = buffer.channels();
+        let data = scratch.take_f32(buffer.frames() * channels);
+        buffer.interleave(data);
+        self.effect.process_interleaved(data, channels);
+        buffer.deinterleave(data);
+    }
+
+    fn name(&self) -> &'static str {
+        self.effect.name()
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        self.effect.params()
+    }
+
+    fn param(&self, index: usize) -> f32 {
+        self.effect.param(index)
+    }
+
+    fn set_param(&mut self, index: usize, value: f32) {
+        self.effect.set_param(index, value);
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.effect.set_sample_rate(sample_rate);
+    }
+}
+
+/// How long bypassing an effect, or bringing it back, takes.
+pub const BYPASS_FADE: Duration = Duration::from_millis(20);
+
+/// An ordered list of effects, applied to the interleaved blocks of one stream.
+pub struct EffectChain<S: Sample = f32> {
+    effects: Vec<Box<dyn Effect<S>>>,
+    buffer: AudioBuffer<S>,
+    layout: ParamLayout,
+    /// The effect and parameter index of each parameter in the layout.
+    params: Vec<(usize, usize)>,
+    /// Values of the parameters before modulation, from the effects or the latest snapshot.
+    values: Vec<f32>,
+    reader: Option<ParamReader<Vec<f32>>>,
+    /// Generation of the last snapshot applied.
+    generation: u64,
+    lfos: Vec<Lfo>,
+    /// Values of the parameters after modulation, only meaningful for the LFO targets.
+    modulated: Vec<f32>,
+    profiler: Option<Profiler>,
+    /// How much of each effect is heard, from 0 when it's bypassed to 1, and the change per
+    /// frame while it fades.
+    wet: Vec<f32>,
+    fade_step: f32,
+    /// The block before an effect fading in or out.
+    dry: AudioBuffer<S>,
+    guard: Option<Arc<GuardStats>>,
+    scratch: ScratchArena<S>,
+    /// The most [`Effect::scratch_frames`] of the effects.
+    scratch_frames: usize,
+    log: AudioLog,
+    /// Whether an effect overdrew the arena, which is only reported once.
+    overdrawn: bool,
+    /// The taps, each after as many effects as its index, in the order of the chain.
+    taps: Vec<(usize, Box<dyn Tap<S>>)>,
+}
+
+impl<S: Sample> EffectChain<S> {
+    /// Creates an empty chain for blocks of `channels` channels.
+    pub fn new(channels: usize) -> Self {
+        EffectChain {
+            effects: Vec::new(),
+            buffer: AudioBuffer::new(channels, 0),
+            layout: ParamLayout::default(),
+            params: Vec::new(),
+            values: Vec::new(),
+            reader: None,
+            generation: 0,
+            lfos: Vec::new(),
+            modulated: Vec::new(),
+            profiler: None,
+            wet: Vec::new(),
+            fade_step: 0.0,
+            dry: AudioBuffer::new(channels, 0),
+            guard: None,
+            scratch: ScratchArena::new(0),
+            scratch_frames: 0,
+            log: AudioLog::disabled(),
+            overdrawn: false,
+            taps: Vec::new(),
+        }
+    }
+
+    /// Reports effects overdrawing the scratch arena to `log`.
+    pub fn logging(mut self, log: AudioLog) -> Self {
+        self.log = log;
+        self
+    }
+
+    pub fn push(&mut self, effect: impl Effect<S> + 'static) {
+        let index = self.effects.len();
+        let instance = self
+            .effects
+            .iter()
+            .filter(|x| x.name() == effect.name())
+            .count();
+        self.layout.add(effect.name(), instance, effect.params());
+        for param in 0..effect.params().len() {
+            self.params.push((index, param));
+            self.values.push(effect.param(param));
+        }
+        self.modulated.resize(self.values.len(), 0.0);
+        self.scratch_frames = self.scratch_frames.max(effect.scratch_frames());
+        self.effects.push(Box::new(effect));
+    }
+
+    /// Inserts an effect without parameters at `index`, before the effect there, e.g. an insert
+    /// point after an effect pushed earlier.
+    pub fn insert(&mut self, index: usize, effect: impl Effect<S> + 'static) {
+        debug_assert!(effect.params().is_empty());
+        for (effect, _) in &mut self.params {
+            if *effect >= index {
+                *effect += 1;
+            }
+        }
+        for (at, _) in &mut self.taps {
+            if *at > index {
+                *at += 1;
+            }
+        }
+        self.scratch_frames = self.scratch_frames.max(effect.scratch_frames());
+        self.effects.insert(index, Box::new(effect));
+    }
+
+    /// Attaches `tap` after the first `index` effects, 0 for the block as it comes in. Taps at
+    /// the same point see the block in the order they're attached.
+    pub fn tap(&mut self, index: usize, tap: impl Tap<S> + 'static) {
+        let at = self.taps.partition_point(|(at, _)| *at <= index);
+        self.taps.insert(at, (index, Box::new(tap)));
+    }
+
+    /// Allocates what the chain needs for blocks of up to `max_frames`, so that it doesn't on
+    /// the first blocks. Larger blocks still work, growing the buffers once.
+    pub fn prepare(&mut self, max_frames: usize) {
+        let channels = self.buffer.channels();
+        if self.buffer.capacity() < max_frames {
+            self.buffer = AudioBuffer::new(channels, max_frames);
+        }
+        if self.profiler.is_some() && self.dry.capacity() < max_frames {
+            self.dry = AudioBuffer::new(channels, max_frames);
+        }
+        self.scratch
+            .reserve((max_frames + self.scratch_frames) * channels);
+    }
+
+    /// Index of the effect named `name`, e.g. "filter", or "filter.1" for the second one.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        let (kind, instance) = match name.split_once('.') {
+            Some((kind, instance)) => (kind, instance.parse::<usize>().ok()?),
+            None => (name, 0),
+        };
+        self.effects
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.name() == kind)
+            .nth(instance)
+            .map(|(index, _)| index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+
+    /// Attaches an LFO, whose targets must be in the layout of the chain.
+    pub fn modulate(&mut self, lfo: Lfo) {
+        self.lfos.push(lfo);
+    }
+
+    /// Names of the parameters of all effects.
+    pub fn layout(&self) -> &ParamLayout {
+        &self.layout
+    }
+
+    /// Current values of all parameters before modulation, in the order of the layout.
+    pub fn param_values(&self) -> Vec<f32> {
+        self.values.clone()
+    }
+
+    /// Makes the chain follow the snapshots of `reader`, which must have a value for every
+    /// parameter of the layout and start from [`param_values`](Self::param_values).
+    pub fn connect(&mut self, reader: ParamReader<Vec<f32>>) {
+        self.generation = 0;
+        self.reader = Some(reader);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    /// Frames all the effects delay the signal by.
+    pub fn latency_frames(&self) -> usize {
+        self.effects.iter().map(|x| x.latency_frames()).sum()
+    }
+
+    /// Times every effect from now on, on the blocks of a stream at `sample_rate`, into the
+    /// returned stats.
+    pub fn profile(&mut self, label: &'static str, sample_rate: u32) -> Arc<ProfileStats> {
+        let names = self
+            .effects
+            .iter()
+            .enumerate()
+            .map(|(index, effect)| {
+                let instance = self.effects[..index]
+                    .iter()
+                    .filter(|x| x.name() == effect.name())
+                    .count();
+                match instance {
+                    0 => effect.name().to_string(),
+                    _ => format!("{}.{}", effect.name(), instance),
+                }
+            })
+            .collect();
+        let stats = Arc::new(ProfileStats::new(label, names));
+        self.profiler = Some(Profiler::new(stats.clone(), sample_rate));
+        self.wet = vec![1.0; self.effects.len()];
+        self.dry = AudioBuffer::new(self.buffer.channels(), self.buffer.capacity());
+        self.fade_step = 1.0 / (BYPASS_FADE.as_secs_f32() * sample_rate as f32);
+        stats
+    }
+
+    /// Re-derives the state of every effect and LFO that depends on the sample rate, for a
+    /// stream now at `sample_rate`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        for effect in &mut self.effects {
+            effect.set_sample_rate(sample_rate as f32);
+        }
+        for lfo in &mut self.lfos {
+            lfo.set_sample_rate(sample_rate);
+        }
+        for (_, tap) in &mut self.taps {
+            tap.set_sample_rate(sample_rate);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.set_sample_rate(sample_rate);
+            self.fade_step = 1.0 / (BYPASS_FADE.as_secs_f32() * sample_rate as f32);
+        }
+    }
+
+    /// Checks the block after every effect from now on, blaming the first one that makes it
+    /// non-finite to `stats`.
+    pub fn guard(&mut self, stats: Arc<GuardStats>) {
+        self.guard = Some(stats);
+    }
+
+    /// Applies every effect in order to the interleaved block `data`, converting it to `S` and back.
+    pub fn process(&mut self, data: &mut [f32]) {
+        if self.effects.is_empty() && self.taps.is_empty() {
+            return;
+        }
+        self.apply_params();
+        self.buffer.deinterleave(data);
+        self.apply_lfos();
+        let (frames, channels) = (self.buffer.frames(), self.buffer.channels());
+        self.scratch
+            .reserve((frames + self.scratch_frames) * channels);
+        // Only a block that comes in finite can blame an effect.
+        let mut finite = self.guard.is_some() && self.buffer.is_finite();
+        // Out of the chain while it runs, so that blaming an effect can borrow it.
+        let mut attached = std::mem::take(&mut self.taps);
+        let mut taps = attached.iter_mut().peekable();
+        for index in 0..self.effects.len() {
+            while let Some((_, tap)) = taps.next_if(|(at, _)| *at == index) {
+                tap.tap(&self.buffer);
+            }
+            let effect = &mut self.effects[index];
+            let budget = (frames + effect.scratch_frames()) * channels;
+            self.scratch.begin(effect.name(), budget);
+            let wet = self.wet.get(index).copied().unwrap_or(1.0);
+            let target = match &self.profiler {
+                Some(profiler) if profiler.stats().is_bypassed(index) => 0.0,
+                _ => 1.0,
+            };
+            if wet == 0.0 && target == 0.0 {
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.record(index, Duration::ZERO, frames);
+                }
+                continue;
+            }
+            let fading = wet != target;
+            if fading {
+                self.dry.copy_from(&self.buffer);
+            }
+            let start = self.profiler.as_ref().map(|_| Instant::now());
+            effect.process(&mut self.buffer, &mut self.scratch);
+            if let (Some(profiler), Some(start)) = (&mut self.profiler, start) {
+                profiler.record(index, start.elapsed(), frames);
+            }
+            if fading {
+                let step = if target > wet {
+                    self.fade_step
+                } else {
+                    -self.fade_step
+                };
+                for channel in 0..channels {
+                    let (dry, out) = (self.dry.channel(channel), self.buffer.channel_mut(channel));
+                    let mut gain = wet;
+                    for (y, &x) in out.iter_mut().zip(dry) {
+                        gain = (gain + step).clamp(0.0, 1.0);
+                        *y = x + (*y - x) * S::from_sample(gain);
+                    }
+                }
+                self.wet[index] = (wet + step * frames as f32).clamp(0.0, 1.0);
+            }
+            if finite && !self.buffer.is_finite() {
+                finite = false;
+                self.blame(index);
+            }
+        }
+        for (_, tap) in taps {
+            tap.tap(&self.buffer);
+        }
+        self.taps = attached;
+        self.buffer.interleave(data);
+        if let Some((effect, samples, budget)) = self.scratch.overdrawn() {
+            if !self.overdrawn {
+                self.overdrawn = true;
+                self.log.push(AudioEvent::ScratchOverdrawn {
+                    effect,
+                    samples,
+                    budget,
+                });
+            }
+        }
+    }
+
+    /// Blames the effect at `index` for making the block non-finite.
+    fn blame(&self, index: usize) {
+        let Some(guard) = &self.guard else {
+            return;
+        };
+        let name = self.effects[index].name();
+        let instance = self.effects[..index]
+            .iter()
+            .filter(|x| x.name() == name)
+            .count();
+        guard.blame((name, instance), &self.values);
+    }
+
+    /// Passes the parameters that changed in the latest snapshot on to their effects.
+    fn apply_params(&mut self) {
+        let Some(reader) = &mut self.reader else {
+            return;
+        };
+        let snapshot = reader.load();
+        if snapshot.generation() == self.generation {
+            return;
+        }
+        self.generation = snapshot.generation();
+        for (index, (&value, current)) in snapshot.value().iter().zip(&mut self.values).enumerate()
+        {
+            if value != *current {
+                let (effect, param) = self.params[index];
+                self.effects[effect].set_param(param, value);
+                *current = value;
+            }
+        }
+    }
+
+    /// Sets the LFO targets to their modulated values for the current block, and advances the
+    /// LFOs past it.
+    fn apply_lfos(&mut self) {
+        if self.lfos.is_empty() {
+            return;
+        }
+        self.modulated.copy_from_slice(&self.values);
+        for lfo in &self.lfos {
+            if let Some(center) = lfo.center() {
+                for &target in lfo.targets() {
+                    self.modulated[target] = center;
+                }
+            }
+        }
+        for lfo in &mut self.lfos {
+            let offset = lfo.offset();
+            for &target in lfo.targets() {
+                self.modulated[target] += offset;
+            }
+            lfo.advance(self.buffer.frames());
+        }
+        for lfo in &self.lfos {
+            for &target in lfo.targets() {
+                let (effect, param) = self.params[target];
+                self.effects[effect].set_param(param, self.modulated[target]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chain of effects that all derive something from the sample rate, at `sample_rate`.
+    fn chain(sample_rate: u32) -> EffectChain {
+        let rate = sample_rate as f32;
+        let mut chain = EffectChain::new(2);
+        chain.push(Biquad::new(
+            FilterKind::LowPass,
+            2_000.0,
+            BUTTERWORTH_Q,
+            rate,
+            2,
+        ));
+        let rumble = RumbleSpec {
+            frequency: 80.0,
+            slope: 24,
+        };
+        chain.push(RumbleFilter::new(rumble, rate, 2).unwrap());
+        chain.push(Gate::new(GateSpec::default(), rate));
+        let limiter = LimiterSpec {
+            ceiling_db: -6.0,
+            release_ms: 50.0,
+        };
+        chain.push(Limiter::new(limiter, rate));
+        chain
+    }
+
+    /// Keeps the peak of every block it sees.
+    struct Peaks(Arc<std::sync::Mutex<Vec<f32>>>);
+
+    impl Tap for Peaks {
+        fn tap(&mut self, buffer: &AudioBuffer) {
+            self.0.lock().unwrap().push(buffer.peak());
+        }
+    }
+
+    #[test]
+    fn taps_see_the_block_after_the_effects_before_them() {
+        let mut chain: EffectChain = EffectChain::new(2);
+        chain.push(Gain::new(6.0));
+        chain.push(Gain::new(6.0));
+        let peaks: Vec<_> = (0..3).map(|_| Arc::default()).collect();
+        for index in [2, 0, 1] {
+            chain.tap(index, Peaks(Arc::clone(&peaks[index])));
+        }
+        let mut data = vec![0.1, -0.1, 0.05, 0.0];
+        chain.process(&mut data);
+        let peaks: Vec<f32> = peaks.iter().map(|x| x.lock().unwrap()[0]).collect();
+        assert!((peaks[0] - 0.1).abs() < 1e-6);
+        assert!((peaks[1] - 0.1 * crate::level::db_to_gain(6.0)).abs() < 1e-6);
+        assert!((peaks[2] - data[0]).abs() < 1e-6);
+    }
+
+    /// Scales the block, without parameters, as effects inserted into a chain are.
+    struct Scale(f32);
+
+    impl Effect for Scale {
+        fn process(&mut self, buffer: &mut AudioBuffer, _: &mut ScratchArena<f32>) {
+            for channel in buffer.channels_mut() {
+                f32::apply_gain(channel, self.0);
+            }
+        }
+    }
+
+    #[test]
+    fn an_effect_inserted_before_a_tap_moves_it_along() {
+        let mut chain: EffectChain = EffectChain::new(1);
+        chain.push(Gain::new(0.0));
+        chain.push(Gain::new(-6.0));
+        let (after_first, at_end) = (Arc::default(), Arc::default());
+        chain.tap(1, Peaks(Arc::clone(&after_first)));
+        chain.tap(2, Peaks(Arc::clone(&at_end)));
+        chain.insert(1, Scale(4.0));
+        chain.insert(0, Scale(0.25));
+        let mut data = vec![0.5];
+        chain.process(&mut data);
+        // After the first gain and before the second as they were pushed, and still at the end.
+        let before = after_first.lock().unwrap()[0];
+        assert!((before - 0.125).abs() < 1e-6);
+        assert!((at_end.lock().unwrap()[0] - data[0]).abs() < 1e-6);
+    }
+
+    /// Silences the first channel of interleaved blocks.
+    struct MuteLeft;
+
+    impl RawEffect for MuteLeft {
+        fn process_interleaved(&mut self, data: &mut [f32], channels: usize) {
+            for frame in data.chunks_exact_mut(channels) {
+                frame[0] = 0.0;
+            }
+        }
+    }
+
+    #[test]
+    fn raw_effects_see_interleaved_blocks() {
+        let mut chain: EffectChain = EffectChain::new(2);
+        chain.push(Gain::new(-6.0206));
+        chain.push(Interleaved::new(MuteLeft));
+        let mut data = vec![0.5, 0.5, -1.0, -1.0];
+        chain.process(&mut data);
+        for (sample, expected) in data.iter().zip([0.0, 0.25, 0.0, -0.5]) {
+            assert!((sample - expected).abs() < 1e-5, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn a_chain_moved_to_another_rate_sounds_like_one_built_there() {
+        let mut state = 0x1234_5678u32;
+        let noise: Vec<f32> = (0..8_192)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect();
+        for (from, to) in [(48_000, 96_000), (96_000, 44_100)] {
+            let mut moved = chain(from);
+            moved.set_sample_rate(to);
+            let mut built = chain(to);
+            assert_eq!(moved.latency_frames(), built.latency_frames());
+            for block in noise.chunks(512) {
+                let (mut a, mut b) = (block.to_vec(), block.to_vec());
+                moved.process(&mut a);
+                built.process(&mut b);
+                // Envelopes rescale their coefficients rather than deriving them again, which
+                // rounds differently.
+                let worst = a
+                    .iter()
+                    .zip(&b)
+                    .map(|(x, y)| (x - y).abs())
+                    .fold(0.0, f32::max);
+                assert!(worst < 1e-5, "{} to {} Hz: {}", from, to, worst);
+            }
+        }
+    }
+
+    #[test]
+    fn profiled_chains_name_every_instance() {
+        let mut chain: EffectChain = EffectChain::new(2);
+        chain.push(Gain::new(-6.0));
+        chain.push(Biquad::new(
+            FilterKind::LowPass,
+            2_000.0,
+            BUTTERWORTH_Q,
+            48_000.0,
+            2,
+        ));
+        chain.push(Gain::new(0.0));
+        let stats = chain.profile("output", 48_000);
+        let names: Vec<&str> = (0..stats.len()).map(|x| stats.name(x)).collect();
+        assert_eq!(names, ["gain", "filter", "gain.1"]);
+        chain.process(&mut [0.5; 256]);
+        assert!(stats.describe().starts_with("CPU of the output: gain: "));
+    }
+
+    #[test]
+    fn chains_add_up_the_latency_of_their_effects() {
+        let mut chain = chain(48_000);
+        assert_eq!(chain.latency_frames(), 0);
+        let trigger = LearnTrigger::default();
+        chain.push(NoiseReducer::new(20.0, 0.5, trigger.clone(), 48_000.0, 2));
+        chain.push(NoiseReducer::new(20.0, 0.5, trigger, 48_000.0, 2));
+        assert_eq!(chain.latency_frames(), 2 * NoiseReducer::LATENCY);
+    }
+
+    #[test]
+    fn guarded_chains_blame_the_first_effect_to_make_the_block_non_finite() {
+        let mut chain: EffectChain = EffectChain::new(1);
+        chain.push(Gain::new(0.0));
+        chain.push(Scale(2.0));
+        chain.push(Scale(f32::NAN));
+        chain.push(Scale(f32::NAN));
+        let stats = Arc::new(GuardStats::new("output stream", 1));
+        chain.guard(stats.clone());
+        let mut data = vec![0.5];
+        chain.process(&mut data);
+        let mut guard = crate::guard::NanGuard::new(stats.clone(), 48_000);
+        guard.scrub(&mut data, 1);
+        let diagnosis = stats.take_diagnosis().unwrap();
+        // The second instance of the default name, after the first scale.
+        assert_eq!(diagnosis.effect, Some(("effect", 1)));
+        assert_eq!(diagnosis.params, [0.0]);
+    }
+
+    #[test]
+    fn bypassed_effects_crossfade_out_and_back_in() {
+        let mut chain: EffectChain = EffectChain::new(1);
+        chain.push(Scale(0.5));
+        // 20 frames of fade at 1 kHz.
+        let stats = chain.profile("output", 1_000);
+        let mut data = [1.0; 10];
+        chain.process(&mut data);
+        assert_eq!(data, [0.5; 10]);
+
+        stats.bypass(0, true);
+        let mut data = [1.0; 30];
+        chain.process(&mut data);
+        assert!((data[0] - 0.525).abs() < 1e-6, "{}", data[0]);
+        assert!(data.windows(2).all(|x| x[1] >= x[0]));
+        assert_eq!(data[19..], [1.0; 11]);
+
+        stats.bypass(0, false);
+        let mut data = [1.0; 30];
+        chain.process(&mut data);
+        assert!((data[0] - 0.975).abs() < 1e-6, "{}", data[0]);
+        assert_eq!(data[19..], [0.5; 11]);
+    }
+}
@@ -0,0 +1,337 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Effect, ScratchArena};
+use crate::fft::{self, Fft};
+use crate::level;
+use crate::logging::{AudioEvent, AudioLog};
+use crate::sample::Sample;
+
+/// Samples per STFT window.
+const WINDOW: usize = 1_024;
+/// Samples between the starts of two windows, for 75% overlap.
+const HOP: usize = WINDOW / 4;
+/// How much more than the learned profile is subtracted, to catch the peaks of the noise.
+const OVER_SUBTRACTION: f32 = 2.0;
+/// Weight of the previous window in the gain of each bin, against musical noise.
+const MASK_SMOOTHING: f32 = 0.7;
+
+/// Requests to learn a new noise profile, shared by the noise reducers of every output.
+#[derive(Clone, Default)]
+pub struct LearnTrigger(Arc<AtomicU64>);
+
+impl LearnTrigger {
+    /// Makes every reducer learn a new profile from its next block on.
+    pub fn request(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn requests(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The STFT state of one channel.
+struct Channel {
+    /// The last `WINDOW` input samples, oldest first.
+    input: Vec<f32>,
+    /// Overlap-added output, whose first `HOP` samples are complete.
+    output: Vec<f32>,
+    /// Gain of each bin in the previous window.
+    mask: Vec<f32>,
+    /// Magnitudes summed while learning.
+    learned: Vec<f32>,
+    profile: Option<Vec<f32>>,
+}
+
+/// Reduces stationary noise by spectral subtraction.
+///
+/// Each channel is split into overlapping Hann windows. Once a noise profile, the average
+/// magnitude spectrum of a stretch of noise, has been learned, each bin of each window is turned
+/// down by the share of its magnitude the profile accounts for, at most by the reduction amount.
+/// Gains are smoothed over time so that isolated bins don't warble, and the windows are
+/// overlap-added back. Until a profile is learned the signal only goes through the STFT.
+///
+/// The STFT delays the signal by [`NoiseReducer::LATENCY`] frames.
+pub struct NoiseReducer {
+    sample_rate: f32,
+    reduction_db: f32,
+    floor: f32,
+    learn_frames: usize,
+    trigger: LearnTrigger,
+    requests: u64,
+    /// Frames left to learn from, and windows learned so far.
+    learning: Option<(usize, u32)>,
+    fft: Fft,
+    window: Vec<f32>,
+    channels: Vec<Channel>,
+    /// Samples since the last window.
+    position: usize,
+    re: Vec<f32>,
+    im: Vec<f32>,
+    log: AudioLog,
+}
+
+impl NoiseReducer {
+    /// Delay added by the STFT, in frames.
+    pub const LATENCY: usize = WINDOW;
+
+    /// Creates a reducer turning noise down by up to `reduction_db`, learning for `learn_seconds`
+    /// whenever `trigger` is requested.
+    pub fn new(
+        reduction_db: f32,
+        learn_seconds: f32,
+        trigger: LearnTrigger,
+        sample_rate: f32,
+        channels: usize,
+    ) -> Self {
+        let channel = || Channel {
+            input: vec![0.0; WINDOW],
+            output: vec![0.0; WINDOW],
+            mask: vec![1.0; WINDOW / 2 + 1],
+            learned: vec![0.0; WINDOW / 2 + 1],
+            profile: None,
+        };
+        NoiseReducer {
+            sample_rate,
+            reduction_db,
+            floor: level::db_to_gain(-reduction_db.abs()),
+            learn_frames: (learn_seconds.max(0.0) * sample_rate) as usize,
+            // Requests made before the reducer existed count, e.g. learning at startup.
+            requests: 0,
+            trigger,
+            learning: None,
+            fft: Fft::new(WINDOW),
+            window: fft::hann(WINDOW),
+            channels: (0..channels).map(|_| channel()).collect(),
+            position: 0,
+            re: vec![0.0; WINDOW],
+            im: vec![0.0; WINDOW],
+            log: AudioLog::disabled(),
+        }
+    }
+
+    /// Reports the profiles learned to `log`.
+    pub fn logging(mut self, log: AudioLog) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Transforms the latest window of every channel, learning from it or reducing its noise,
+    /// and overlap-adds it to the output.
+    fn process_window(&mut self) {
+        // Hann analysis and synthesis windows at 75% overlap sum to 1.5.
+        let scale = 1.0 / 1.5;
+        let learning = self.learning.is_some();
+        for channel in &mut self.channels {
+            for i in 0..WINDOW {
+                self.re[i] = channel.input[i] * self.window[i];
+                self.im[i] = 0.0;
+            }
+            self.fft.forward(&mut self.re, &mut self.im);
+            for bin in 0..=WINDOW / 2 {
+                let magnitude = (self.re[bin] * self.re[bin] + self.im[bin] * self.im[bin]).sqrt();
+                if learning {
+                    channel.learned[bin] += magnitude;
+                    continue;
+                }
+                let Some(profile) = &channel.profile else {
+                    break;
+                };
+                let gain =
+                    (1.0 - OVER_SUBTRACTION * profile[bin] / (magnitude + 1e-12)).max(self.floor);
+                let gain = MASK_SMOOTHING * channel.mask[bin] + (1.0 - MASK_SMOOTHING) * gain;
+                channel.mask[bin] = gain;
+                self.re[bin] *= gain;
+                self.im[bin] *= gain;
+                // The spectrum of a real signal is symmetric.
+                if bin > 0 && bin < WINDOW / 2 {
+                    self.re[WINDOW - bin] *= gain;
+                    self.im[WINDOW - bin] *= gain;
+                }
+            }
+            self.fft.inverse(&mut self.re, &mut self.im);
+            channel.output.copy_within(HOP.., 0);
+            channel.output[WINDOW - HOP..].fill(0.0);
+            for i in 0..WINDOW {
+                channel.output[i] += self.re[i] * self.window[i] * scale;
+            }
+            channel.input.copy_within(HOP.., 0);
+        }
+        if let Some((frames, windows)) = &mut self.learning {
+            *windows += 1;
+            *frames = frames.saturating_sub(HOP);
+            if *frames == 0 {
+                let windows = *windows as f32;
+                for channel in &mut self.channels {
+                    let profile = channel.learned.iter().map(|x| x / windows).collect();
+                    channel.profile = Some(profile);
+                    channel.mask.fill(1.0);
+                }
+                self.log.push(AudioEvent::NoiseLearned {
+                    seconds: windows * HOP as f32 / self.sample_rate,
+                });
+                self.learning = None;
+            }
+        }
+    }
+}
+
+impl<S: Sample> Effect<S> for NoiseReducer {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        let requests = self.trigger.requests();
+        if requests != self.requests {
+            self.requests = requests;
+            for channel in &mut self.channels {
+                channel.learned.fill(0.0);
+            }
+            self.learning = Some((self.learn_frames.max(HOP), 0));
+        }
+        for frame in 0..buffer.frames() {
+            for (index, channel) in self.channels.iter_mut().enumerate() {
+                let sample = &mut buffer.channel_mut(index)[frame];
+                channel.input[WINDOW - HOP + self.position] = sample.to_sample();
+                *sample = S::from_sample(channel.output[self.position]);
+            }
+            self.position += 1;
+            if self.position == HOP {
+                self.position = 0;
+                self.process_window();
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "noise"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &["reduction"]
+    }
+
+    fn param(&self, _index: usize) -> f32 {
+        self.reduction_db
+    }
+
+    fn set_param(&mut self, _index: usize, value: f32) {
+        self.reduction_db = value;
+        self.floor = level::db_to_gain(-value.abs());
+    }
+
+    fn latency_frames(&self) -> usize {
+        Self::LATENCY
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        // The bins of a window cover other frequencies now, which the profile is read at, and a
+        // profile being learned starts over.
+        let ratio = sample_rate / self.sample_rate;
+        for channel in &mut self.channels {
+            if let Some(profile) = &mut channel.profile {
+                *profile = (0..profile.len())
+                    .map(|bin| {
+                        let position = (bin as f32 * ratio).min((profile.len() - 1) as f32);
+                        let (below, fraction) = (position as usize, position.fract());
+                        let above = (below + 1).min(profile.len() - 1);
+                        profile[below] + (profile[above] - profile[below]) * fraction
+                    })
+                    .collect();
+            }
+            channel.mask.fill(1.0);
+            channel.learned.fill(0.0);
+        }
+        if let Some((frames, windows)) = &mut self.learning {
+            *frames = self.learn_frames.max(HOP);
+            *windows = 0;
+        }
+        self.learn_frames = (self.learn_frames as f32 * ratio) as usize;
+        self.sample_rate = sample_rate;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// `frames` of white noise with a peak of `amplitude`.
+    fn noise(frames: usize, amplitude: f32) -> Vec<f32> {
+        let mut state = 0x9e37_79b9u32;
+        (0..frames)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                ((state >> 8) as f32 / (1 << 23) as f32 - 1.0) * amplitude
+            })
+            .collect()
+    }
+
+    /// Runs the mono `input` through `reducer` in blocks of a hop, returning the output.
+    fn run(reducer: &mut NoiseReducer, input: &[f32]) -> Vec<f32> {
+        let mut buffer = AudioBuffer::<f32>::new(1, HOP);
+        let mut scratch = ScratchArena::new(0);
+        let mut output = vec![0.0; input.len()];
+        for (input, output) in input.chunks(HOP).zip(output.chunks_mut(HOP)) {
+            buffer.deinterleave(input);
+            reducer.process(&mut buffer, &mut scratch);
+            buffer.interleave(output);
+        }
+        output
+    }
+
+    fn rms(data: &[f32]) -> f32 {
+        (data.iter().map(|x| x * x).sum::<f32>() / data.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn without_a_profile_the_signal_only_goes_through_the_stft() {
+        let mut reducer = NoiseReducer::new(20.0, 0.5, LearnTrigger::default(), SAMPLE_RATE, 1);
+        let input = noise(16 * WINDOW, 0.5);
+        let output = run(&mut reducer, &input);
+        for i in 2 * WINDOW..input.len() {
+            let delayed = input[i - NoiseReducer::LATENCY];
+            assert!((output[i] - delayed).abs() < 1e-4, "{}: {}", i, output[i]);
+        }
+    }
+
+    #[test]
+    fn a_learned_noise_is_turned_down_by_the_reduction() {
+        let trigger = LearnTrigger::default();
+        // Requested before the reducer exists, as learning at startup does.
+        trigger.request();
+        let mut reducer = NoiseReducer::new(20.0, 0.5, trigger, SAMPLE_RATE, 1);
+        let learn = (0.5 * SAMPLE_RATE) as usize;
+        run(&mut reducer, &noise(learn + WINDOW, 0.1));
+        let output = run(&mut reducer, &noise(32 * WINDOW, 0.1));
+        let reduction = level::gain_to_db(rms(&output[4 * WINDOW..]) / rms(&noise(WINDOW, 0.1)));
+        assert!(reduction < -15.0 && reduction > -25.0, "{}", reduction);
+    }
+
+    #[test]
+    fn a_tone_over_the_learned_noise_goes_through() {
+        let trigger = LearnTrigger::default();
+        let mut reducer = NoiseReducer::new(20.0, 0.5, trigger.clone(), SAMPLE_RATE, 1);
+        trigger.request();
+        let learn = (0.5 * SAMPLE_RATE) as usize;
+        run(&mut reducer, &noise(learn + WINDOW, 0.01));
+        let frequency = 40.0 * SAMPLE_RATE / WINDOW as f32;
+        let mut input = noise(32 * WINDOW, 0.01);
+        for (i, x) in input.iter_mut().enumerate() {
+            *x += 0.5 * (2.0 * std::f32::consts::PI * frequency * i as f32 / SAMPLE_RATE).sin();
+        }
+        let output = run(&mut reducer, &input);
+        let tone = level::gain_to_db(rms(&output[4 * WINDOW..]) / rms(&input[4 * WINDOW..]));
+        assert!(tone.abs() < 0.5, "{}", tone);
+    }
+
+    #[test]
+    fn the_reduction_sets_the_floor() {
+        let mut reducer = NoiseReducer::new(20.0, 0.5, LearnTrigger::default(), SAMPLE_RATE, 1);
+        Effect::<f32>::set_param(&mut reducer, 0, 40.0);
+        assert_eq!(Effect::<f32>::param(&reducer, 0), 40.0);
+        assert!((reducer.floor - 0.01).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,262 @@
+use std::f64::consts::TAU;
+use std::str::FromStr;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Effect, ScratchArena};
+use crate::sample::Sample;
+
+/// Pole radius of the notch: the closer to 1, the narrower the notch and the slower it tracks.
+const POLE_RADIUS: f64 = 0.995;
+/// Adaptation step, normalised by the power of the signal.
+const STEP: f64 = 0.01;
+/// How much the notch must take off the signal for a narrowband component to count as there.
+const DETECTION_DB: f64 = 6.0;
+/// Time constant of the power estimates and of opening and closing the notch, in seconds.
+const SMOOTHING_TIME: f64 = 0.05;
+
+/// Frequency window the notch tracks in: `<low-Hz>:<high-Hz>` on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct NotchWindow {
+    pub low: f32,
+    pub high: f32,
+}
+
+impl FromStr for NotchWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected `<low-Hz>:<high-Hz>`, got \"{}\"", s);
+        let (low, high) = s.split_once(':').ok_or_else(invalid)?;
+        let low: f32 = low.parse().map_err(|_| invalid())?;
+        let high: f32 = high.parse().map_err(|_| invalid())?;
+        if !(0.0 < low && low < high) {
+            return Err(format!(
+                "the notch window must go up from a positive frequency, got {} to {} Hz",
+                low, high
+            ));
+        }
+        Ok(NotchWindow { low, high })
+    }
+}
+
+/// A notch that finds the strongest narrowband component in a frequency window and follows it.
+///
+/// The notch is a second-order IIR with its zeros on the unit circle and its poles just inside,
+/// at the same angle, so it only depends on `a = 2·cos(ω)`. A normalised LMS step on the mono
+/// sum of the channels moves `a` to minimise the output power, which puts the notch on the
+/// strongest sinusoid, and tracks it as it drifts. The same notch is applied to every channel.
+///
+/// While the notch takes off less than [`DETECTION_DB`], there's no narrowband component to
+/// remove, and it fades out to let the signal through untouched, still adapting meanwhile.
+pub struct AdaptiveNotch<S = f32> {
+    window: NotchWindow,
+    sample_rate: f64,
+    /// Bounds of `a`, from the top and the bottom of the window.
+    min_a: f64,
+    max_a: f64,
+    a: f64,
+    /// Internal state of the mono adaptation filter, `s[n - 1]` and `s[n - 2]`.
+    s1: f64,
+    s2: f64,
+    input_power: f64,
+    output_power: f64,
+    /// Power of the internal state, which normalises the step.
+    state_power: f64,
+    /// How much of the notched signal is output, from 0 (open) to 1.
+    depth: f64,
+    smoothing: f64,
+    states: Vec<(S, S)>,
+}
+
+impl<S: Sample> AdaptiveNotch<S> {
+    pub fn new(window: NotchWindow, sample_rate: f32, channels: usize) -> Self {
+        let mut notch = AdaptiveNotch {
+            window,
+            sample_rate: sample_rate as f64,
+            min_a: 0.0,
+            max_a: 0.0,
+            a: 0.0,
+            s1: 0.0,
+            s2: 0.0,
+            input_power: 0.0,
+            output_power: 0.0,
+            state_power: 0.0,
+            depth: 0.0,
+            smoothing: 0.0,
+            states: vec![(S::default(), S::default()); channels],
+        };
+        notch.derive(sample_rate as f64);
+        notch.a = (notch.min_a + notch.max_a) / 2.0;
+        notch
+    }
+
+    /// Derives the bounds of the window and the smoothing for a stream at `sample_rate`.
+    fn derive(&mut self, sample_rate: f64) {
+        let nyquist = sample_rate * 0.49;
+        let a = |frequency: f32| 2.0 * (TAU * (frequency as f64).min(nyquist) / sample_rate).cos();
+        (self.min_a, self.max_a) = (a(self.window.high), a(self.window.low));
+        self.smoothing = 1.0 - (-1.0 / (SMOOTHING_TIME * sample_rate)).exp();
+        self.sample_rate = sample_rate;
+    }
+
+    /// Frequency the notch is on, in Hz.
+    pub fn frequency(&self) -> f32 {
+        ((self.a / 2.0).clamp(-1.0, 1.0).acos() * self.sample_rate / TAU) as f32
+    }
+
+    /// Whether a narrowband component is being removed.
+    pub fn is_locked(&self) -> bool {
+        self.depth > 0.5
+    }
+
+    /// Adapts the notch to one mono sample.
+    #[inline]
+    fn adapt(&mut self, x: f64) {
+        let s = x + POLE_RADIUS * self.a * self.s1 - POLE_RADIUS * POLE_RADIUS * self.s2;
+        let e = s - self.a * self.s1 + self.s2;
+        self.input_power += self.smoothing * (x * x - self.input_power);
+        self.output_power += self.smoothing * (e * e - self.output_power);
+        self.state_power += self.smoothing * (self.s1 * self.s1 - self.state_power);
+        let step = STEP / (self.state_power + 1e-12);
+        self.a = (self.a + step * e * self.s1).clamp(self.min_a, self.max_a);
+        self.s2 = self.s1;
+        self.s1 = s;
+        let reduction = 10.0 * ((self.input_power + 1e-20) / (self.output_power + 1e-20)).log10();
+        let target = if reduction > DETECTION_DB { 1.0 } else { 0.0 };
+        self.depth += self.smoothing * (target - self.depth);
+    }
+}
+
+impl<S: Sample> Effect<S> for AdaptiveNotch<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        let scale = 1.0 / buffer.channels() as f64;
+        for frame in 0..buffer.frames() {
+            let sum: f64 = (0..buffer.channels())
+                .map(|channel| buffer.channel(channel)[frame].to_sample::<f64>())
+                .sum();
+            self.adapt(sum * scale);
+            let (a, depth) = (S::from_sample(self.a), S::from_sample(self.depth));
+            let (r, r2) = (
+                S::from_sample(POLE_RADIUS),
+                S::from_sample(POLE_RADIUS * POLE_RADIUS),
+            );
+            for (channel, (s1, s2)) in self.states.iter_mut().enumerate() {
+                let x = &mut buffer.channel_mut(channel)[frame];
+                let s = *x + r * a * *s1 - r2 * *s2;
+                let e = s - a * *s1 + *s2;
+                *s2 = *s1;
+                *s1 = s;
+                *x = *x + depth * (e - *x);
+            }
+        }
+        for (s1, s2) in &mut self.states {
+            *s1 = crate::denormal::flush(*s1);
+            *s2 = crate::denormal::flush(*s2);
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "notch"
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        // The notch stays on its frequency, as far as the window allows.
+        let frequency = self.frequency() as f64;
+        self.derive(sample_rate as f64);
+        self.a = (2.0 * (TAU * frequency / self.sample_rate).cos()).clamp(self.min_a, self.max_a);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// Runs the mono `input` through `notch` in blocks of 256, returning the output.
+    fn run(notch: &mut AdaptiveNotch, input: &[f32]) -> Vec<f32> {
+        let mut buffer = AudioBuffer::new(1, 256);
+        let mut scratch = ScratchArena::new(0);
+        let mut output = vec![0.0; input.len()];
+        for (input, output) in input.chunks(256).zip(output.chunks_mut(256)) {
+            buffer.deinterleave(input);
+            notch.process(&mut buffer, &mut scratch);
+            buffer.interleave(output);
+        }
+        output
+    }
+
+    fn sine(frequency: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| 0.5 * (TAU as f32 * frequency * i as f32 / SAMPLE_RATE).sin())
+            .collect()
+    }
+
+    fn peak(data: &[f32]) -> f32 {
+        data.iter().fold(0.0, |x, y| x.max(y.abs()))
+    }
+
+    #[test]
+    fn windows_parse_and_must_go_up() {
+        let window: NotchWindow = "100:2000".parse().unwrap();
+        assert_eq!((window.low, window.high), (100.0, 2_000.0));
+        assert_eq!(
+            "2000:100".parse::<NotchWindow>().unwrap_err(),
+            "the notch window must go up from a positive frequency, got 2000 to 100 Hz"
+        );
+        assert_eq!(
+            "100".parse::<NotchWindow>().unwrap_err(),
+            "expected `<low-Hz>:<high-Hz>`, got \"100\""
+        );
+    }
+
+    #[test]
+    fn the_notch_locks_on_a_tone_and_removes_it() {
+        let mut notch = AdaptiveNotch::new("100:2000".parse().unwrap(), SAMPLE_RATE, 1);
+        let output = run(&mut notch, &sine(700.0, 48_000));
+        assert!(notch.is_locked());
+        assert!(
+            (notch.frequency() - 700.0).abs() < 1.0,
+            "{}",
+            notch.frequency()
+        );
+        assert!(
+            peak(&output[43_200..]) < 0.01,
+            "{}",
+            peak(&output[43_200..])
+        );
+    }
+
+    #[test]
+    fn the_notch_follows_a_drifting_tone() {
+        let mut notch = AdaptiveNotch::new("100:2000".parse().unwrap(), SAMPLE_RATE, 1);
+        run(&mut notch, &sine(700.0, 48_000));
+        run(&mut notch, &sine(900.0, 48_000));
+        assert!(notch.is_locked());
+        assert!(
+            (notch.frequency() - 900.0).abs() < 1.0,
+            "{}",
+            notch.frequency()
+        );
+    }
+
+    #[test]
+    fn broadband_signals_go_through_untouched() {
+        let mut notch = AdaptiveNotch::new("100:2000".parse().unwrap(), SAMPLE_RATE, 1);
+        let mut state = 0x9e37_79b9u32;
+        let input: Vec<f32> = (0..48_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect();
+        let output = run(&mut notch, &input);
+        assert!(!notch.is_locked());
+        let error = std::iter::zip(&input, &output)
+            .skip(43_200)
+            .fold(0f32, |x, (a, b)| x.max((a - b).abs()));
+        assert!(error < 0.01, "{}", error);
+    }
+}
@@ -0,0 +1,551 @@
+//! CLAP plugins hosted as effects of the chain.
+//!
+//! The plugin at `index` in the factory of a `.clap` bundle is instantiated, activated at the
+//! sample rate of the stream for blocks of up to [`MAX_FRAMES`] frames, and processed on its
+//! main ports in 32 bits, whatever the chain runs in. It has to take and give as many channels
+//! as the stream. When the stream changes its rate, the plugin is deactivated and activated
+//! again at the new one, and passed through if it fails to.
+//!
+//! Parameters come from the params extension, under the names the plugin gives them, lowercased
+//! and with dashes for spaces: a parameter "Dry Wet" of the first plugin is `plugin.0.dry-wet`.
+//! Their values are the plugin's own, e.g. decibels if that's what it shows. Changes reach the
+//! plugin as parameter events at the start of the next block. There's no GUI.
+
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use clap_sys::audio_buffer::clap_audio_buffer;
+use clap_sys::entry::clap_plugin_entry;
+use clap_sys::events::{
+    clap_event_header, clap_event_param_value, clap_input_events, clap_output_events,
+    CLAP_CORE_EVENT_SPACE_ID, CLAP_EVENT_PARAM_VALUE,
+};
+use clap_sys::ext::audio_ports::{
+    clap_audio_port_info, clap_plugin_audio_ports, CLAP_EXT_AUDIO_PORTS,
+};
+use clap_sys::ext::latency::{clap_plugin_latency, CLAP_EXT_LATENCY};
+use clap_sys::ext::params::{
+    clap_param_info, clap_plugin_params, CLAP_EXT_PARAMS, CLAP_PARAM_IS_HIDDEN,
+    CLAP_PARAM_IS_READONLY,
+};
+use clap_sys::factory::plugin_factory::{clap_plugin_factory, CLAP_PLUGIN_FACTORY_ID};
+use clap_sys::host::clap_host;
+use clap_sys::id::clap_id;
+use clap_sys::plugin::clap_plugin;
+use clap_sys::process::{clap_process, CLAP_PROCESS_ERROR};
+use clap_sys::version::{clap_version_is_compatible, CLAP_VERSION};
+
+use super::{Effect, ScratchArena};
+use crate::buffer::AudioBuffer;
+use crate::sample::Sample;
+
+/// Largest block passed to a plugin at once. Longer blocks are processed in parts.
+pub const MAX_FRAMES: usize = 8_192;
+
+/// Which plugin to load: `path` to the bundle, optionally followed by `:index`, e.g.
+/// "reverb.clap:1", for bundles of several plugins.
+#[derive(Clone, Debug)]
+pub struct PluginSpec {
+    pub path: PathBuf,
+    pub index: u32,
+}
+
+impl FromStr for PluginSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, index) = match s.rsplit_once(':') {
+            Some((path, index))
+                if !index.is_empty() && index.bytes().all(|x| x.is_ascii_digit()) =>
+            {
+                let index = index
+                    .parse()
+                    .map_err(|_| format!("invalid plugin index \"{}\"", index))?;
+                (path, index)
+            }
+            _ => (s, 0),
+        };
+        if path.is_empty() {
+            return Err(format!("no path to the plugin in \"{}\"", s));
+        }
+        Ok(PluginSpec {
+            path: PathBuf::from(path),
+            index,
+        })
+    }
+}
+
+/// The shared library of a bundle, with its entry initialized.
+struct Bundle {
+    entry: *const clap_plugin_entry,
+    // Unloaded last.
+    _library: libloading::Library,
+}
+
+impl Bundle {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        // Loading runs the library's initializers, which a plugin bundle is trusted with.
+        let library = unsafe { libloading::Library::new(path) }
+            .with_context(|| format!("failed to load \"{}\"", path.display()))?;
+        let entry = unsafe { library.get::<*const clap_plugin_entry>(b"clap_entry\0") }
+            .with_context(|| format!("\"{}\" is not a CLAP plugin", path.display()))?;
+        let entry: *const clap_plugin_entry = *entry;
+        let version = unsafe { (*entry).clap_version };
+        if !clap_version_is_compatible(version) {
+            bail!(
+                "\"{}\" is for CLAP {}.{}, which this host doesn't support",
+                path.display(),
+                version.major,
+                version.minor
+            );
+        }
+        let path_name = CString::new(path.to_string_lossy().as_bytes())?;
+        let init = unsafe { (*entry).init }.context("the plugin entry has no init")?;
+        if !unsafe { init(path_name.as_ptr()) } {
+            bail!("\"{}\" failed to initialize", path.display());
+        }
+        Ok(Bundle {
+            entry,
+            _library: library,
+        })
+    }
+
+    fn factory(&self) -> Option<&clap_plugin_factory> {
+        let get_factory = unsafe { (*self.entry).get_factory }?;
+        let factory = unsafe { get_factory(CLAP_PLUGIN_FACTORY_ID.as_ptr()) };
+        unsafe { (factory as *const clap_plugin_factory).as_ref() }
+    }
+}
+
+impl Drop for Bundle {
+    fn drop(&mut self) {
+        if let Some(deinit) = unsafe { (*self.entry).deinit } {
+            unsafe { deinit() };
+        }
+    }
+}
+
+/// The host as plugins see it, asking for nothing.
+fn host() -> Box<clap_host> {
+    unsafe extern "C" fn get_extension(_: *const clap_host, _: *const c_char) -> *const c_void {
+        ptr::null()
+    }
+    unsafe extern "C" fn request(_: *const clap_host) {}
+    Box::new(clap_host {
+        clap_version: CLAP_VERSION,
+        host_data: ptr::null_mut(),
+        name: c"rust-dsp-experiments".as_ptr(),
+        vendor: c"".as_ptr(),
+        url: c"".as_ptr(),
+        version: c"0.1.0".as_ptr(),
+        get_extension: Some(get_extension),
+        request_restart: Some(request),
+        request_process: Some(request),
+        request_callback: Some(request),
+    })
+}
+
+/// A parameter of the plugin.
+struct Param {
+    id: clap_id,
+    cookie: *mut c_void,
+    min: f64,
+    max: f64,
+    /// Current target.
+    value: f32,
+    /// Value to send with the next block, if it changed.
+    pending: Option<f64>,
+}
+
+/// A plugin instance, activated for one stream.
+pub struct ClapPlugin {
+    plugin: *const clap_plugin,
+    name: String,
+    channels: usize,
+    latency: u32,
+    names: &'static [&'static str],
+    params: Vec<Param>,
+    /// Events of the block being processed, with room for one per parameter.
+    events: Vec<clap_event_param_value>,
+    inputs: Vec<Vec<f32>>,
+    outputs: Vec<Vec<f32>>,
+    input_pointers: Vec<*mut f32>,
+    output_pointers: Vec<*mut f32>,
+    steady_time: i64,
+    active: bool,
+    processing: bool,
+    // Dropped after the plugin is destroyed.
+    _host: Box<clap_host>,
+    _bundle: Bundle,
+}
+
+// The plugin is created on the main thread and only used from the audio thread after that, as
+// CLAP allows once it's activated.
+unsafe impl Send for ClapPlugin {}
+
+impl ClapPlugin {
+    /// Loads the plugin of `spec` for a stream of `channels` at `sample_rate`.
+    pub fn load(spec: &PluginSpec, channels: usize, sample_rate: u32) -> anyhow::Result<Self> {
+        let path = spec.path.display();
+        let bundle = Bundle::load(&spec.path)?;
+        let factory = bundle
+            .factory()
+            .with_context(|| format!("\"{}\" has no plugin factory", path))?;
+        let count = unsafe { factory.get_plugin_count.map_or(0, |f| f(factory)) };
+        if spec.index >= count {
+            bail!(
+                "\"{}\" has no plugin {}, only {} of them",
+                path,
+                spec.index,
+                count
+            );
+        }
+        let descriptor = factory
+            .get_plugin_descriptor
+            .map(|f| unsafe { f(factory, spec.index) })
+            .and_then(|x| unsafe { x.as_ref() })
+            .with_context(|| format!("\"{}\" has no descriptor for plugin {}", path, spec.index))?;
+        let name = unsafe { CStr::from_ptr(descriptor.name) }
+            .to_string_lossy()
+            .into_owned();
+        let host = host();
+        let plugin = factory.create_plugin.map_or(ptr::null(), |f| unsafe {
+            f(factory, &*host, descriptor.id)
+        });
+        if plugin.is_null() {
+            bail!(
+                "failed to instantiate the plugin \"{}\" of \"{}\"",
+                name,
+                path
+            );
+        }
+        // From here on, dropping the instance destroys the plugin.
+        let mut instance = ClapPlugin {
+            plugin,
+            name,
+            channels,
+            latency: 0,
+            names: &[],
+            params: Vec::new(),
+            events: Vec::new(),
+            inputs: vec![vec![0.0; MAX_FRAMES]; channels],
+            outputs: vec![vec![0.0; MAX_FRAMES]; channels],
+            input_pointers: Vec::new(),
+            output_pointers: Vec::new(),
+            steady_time: 0,
+            active: false,
+            processing: false,
+            _host: host,
+            _bundle: bundle,
+        };
+        let init = unsafe { (*plugin).init }.context("the plugin has no init")?;
+        if !unsafe { init(plugin) } {
+            bail!("the plugin \"{}\" failed to initialize", instance.name);
+        }
+        instance.check_ports()?;
+        instance.discover_params();
+        unsafe { (*plugin).activate }.context("the plugin has no activate")?;
+        if !instance.activate(sample_rate) {
+            bail!(
+                "the plugin \"{}\" failed to activate at {} Hz",
+                instance.name,
+                sample_rate
+            );
+        }
+        instance.input_pointers = instance.inputs.iter_mut().map(|x| x.as_mut_ptr()).collect();
+        instance.output_pointers = instance
+            .outputs
+            .iter_mut()
+            .map(|x| x.as_mut_ptr())
+            .collect();
+        Ok(instance)
+    }
+
+    /// Name of the plugin, from its descriptor.
+    pub fn plugin_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Frames the plugin delays its output by.
+    pub fn latency(&self) -> u32 {
+        self.latency
+    }
+
+    /// Activates the plugin at `sample_rate`, returning whether it is.
+    fn activate(&mut self, sample_rate: u32) -> bool {
+        let activate = unsafe { (*self.plugin).activate };
+        self.active = activate
+            .is_some_and(|f| unsafe { f(self.plugin, sample_rate as f64, 1, MAX_FRAMES as u32) });
+        // Only read once active, since it may depend on the sample rate.
+        let latency: Option<&clap_plugin_latency> = self.extension(CLAP_EXT_LATENCY);
+        self.latency = latency
+            .filter(|_| self.active)
+            .and_then(|x| x.get)
+            .map_or(0, |f| unsafe { f(self.plugin) });
+        self.active
+    }
+
+    /// Stops the processing and deactivates the plugin, if it's started and active.
+    fn deactivate(&mut self) {
+        unsafe {
+            let plugin = &*self.plugin;
+            if std::mem::replace(&mut self.processing, false) {
+                if let Some(stop) = plugin.stop_processing {
+                    stop(self.plugin);
+                }
+            }
+            if std::mem::replace(&mut self.active, false) {
+                if let Some(deactivate) = plugin.deactivate {
+                    deactivate(self.plugin);
+                }
+            }
+        }
+    }
+
+    fn extension<T>(&self, id: &CStr) -> Option<&T> {
+        let get_extension = unsafe { (*self.plugin).get_extension }?;
+        unsafe { (get_extension(self.plugin, id.as_ptr()) as *const T).as_ref() }
+    }
+
+    /// Fails unless the main ports have as many channels as the stream.
+    fn check_ports(&self) -> anyhow::Result<()> {
+        let ports: Option<&clap_plugin_audio_ports> = self.extension(CLAP_EXT_AUDIO_PORTS);
+        let main_channels = |is_input: bool| -> Option<u32> {
+            let ports = ports?;
+            if unsafe { ports.count?(self.plugin, is_input) } == 0 {
+                return None;
+            }
+            let mut info: clap_audio_port_info = unsafe { std::mem::zeroed() };
+            unsafe { ports.get?(self.plugin, 0, is_input, &mut info) }.then_some(info.channel_count)
+        };
+        let (inputs, outputs) = (main_channels(true), main_channels(false));
+        if inputs != Some(self.channels as u32) || outputs != Some(self.channels as u32) {
+            let describe = |x: Option<u32>| x.map_or("no".to_string(), |x| x.to_string());
+            bail!(
+                "the plugin \"{}\" takes {} channels and gives {}, but the output stream has {}",
+                self.name,
+                describe(inputs),
+                describe(outputs),
+                self.channels
+            );
+        }
+        Ok(())
+    }
+
+    /// Reads the parameters the plugin can be set by.
+    fn discover_params(&mut self) {
+        let Some(params) = self
+            .extension::<clap_plugin_params>(CLAP_EXT_PARAMS)
+            .copied()
+        else {
+            return;
+        };
+        let count = params.count.map_or(0, |f| unsafe { f(self.plugin) });
+        let mut names = Vec::new();
+        for index in 0..count {
+            let mut info: clap_param_info = unsafe { std::mem::zeroed() };
+            let found = params
+                .get_info
+                .is_some_and(|f| unsafe { f(self.plugin, index, &mut info) });
+            if !found || info.flags & (CLAP_PARAM_IS_HIDDEN | CLAP_PARAM_IS_READONLY) != 0 {
+                continue;
+            }
+            let mut value = info.default_value;
+            if let Some(get_value) = params.get_value {
+                unsafe { get_value(self.plugin, info.id, &mut value) };
+            }
+            let name = unsafe { CStr::from_ptr(info.name.as_ptr()) }.to_string_lossy();
+            names.push(param_name(&name, names.len()));
+            self.params.push(Param {
+                id: info.id,
+                cookie: info.cookie,
+                min: info.min_value,
+                max: info.max_value,
+                value: value as f32,
+                pending: None,
+            });
+        }
+        // Layouts name parameters by static strings, and plugins live as long as the program.
+        let names: Vec<&'static str> = names.into_iter().map(|x| &*x.leak()).collect();
+        self.names = names.leak();
+        self.events = Vec::with_capacity(self.params.len());
+    }
+
+    /// Processes `frames` frames from the inputs to the outputs, with the pending events.
+    fn process_block(&mut self, frames: usize) -> bool {
+        unsafe extern "C" fn size(list: *const clap_input_events) -> u32 {
+            let events = unsafe { &*((*list).ctx as *const Vec<clap_event_param_value>) };
+            events.len() as u32
+        }
+        unsafe extern "C" fn get(
+            list: *const clap_input_events,
+            index: u32,
+        ) -> *const clap_event_header {
+            let events = unsafe { &*((*list).ctx as *const Vec<clap_event_param_value>) };
+            events
+                .get(index as usize)
+                .map_or(ptr::null(), |x| &x.header as *const _)
+        }
+        // Events the plugin sends back, e.g. of its own GUI, are of no use without one.
+        unsafe extern "C" fn try_push(
+            _: *const clap_output_events,
+            _: *const clap_event_header,
+        ) -> bool {
+            true
+        }
+        let in_events = clap_input_events {
+            ctx: &mut self.events as *mut _ as *mut c_void,
+            size: Some(size),
+            get: Some(get),
+        };
+        let out_events = clap_output_events {
+            ctx: ptr::null_mut(),
+            try_push: Some(try_push),
+        };
+        let input = clap_audio_buffer {
+            data32: self.input_pointers.as_mut_ptr(),
+            data64: ptr::null_mut(),
+            channel_count: self.channels as u32,
+            latency: 0,
+            constant_mask: 0,
+        };
+        let mut output = clap_audio_buffer {
+            data32: self.output_pointers.as_mut_ptr(),
+            ..input
+        };
+        let process = clap_process {
+            steady_time: self.steady_time,
+            frames_count: frames as u32,
+            transport: ptr::null(),
+            audio_inputs: &input,
+            audio_outputs: &mut output,
+            audio_inputs_count: 1,
+            audio_outputs_count: 1,
+            in_events: &in_events,
+            out_events: &out_events,
+        };
+        self.steady_time += frames as i64;
+        let Some(process_fn) = (unsafe { (*self.plugin).process }) else {
+            return false;
+        };
+        let status = unsafe { process_fn(self.plugin, &process) };
+        self.events.clear();
+        status != CLAP_PROCESS_ERROR
+    }
+}
+
+impl<S: Sample> Effect<S> for ClapPlugin {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        // Plugins that fail to activate, to start, or to process, are passed through.
+        if !self.active {
+            return;
+        }
+        if !self.processing {
+            let start = unsafe { (*self.plugin).start_processing };
+            self.processing = start.is_some_and(|f| unsafe { f(self.plugin) });
+            if !self.processing {
+                return;
+            }
+        }
+        for param in &mut self.params {
+            if let Some(value) = param.pending.take() {
+                self.events.push(param_event(param, value));
+            }
+        }
+        let frames = buffer.frames();
+        let mut start = 0;
+        while start < frames {
+            let length = (frames - start).min(MAX_FRAMES);
+            for (channel, input) in self.inputs.iter_mut().enumerate() {
+                let samples = &buffer.channel(channel)[start..start + length];
+                for (x, y) in input.iter_mut().zip(samples) {
+                    *x = y.to_sample();
+                }
+            }
+            if self.process_block(length) {
+                for (channel, output) in self.outputs.iter().enumerate() {
+                    let samples = &mut buffer.channel_mut(channel)[start..start + length];
+                    for (y, x) in samples.iter_mut().zip(output) {
+                        *y = S::from_sample(*x);
+                    }
+                }
+            }
+            start += length;
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "plugin"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        self.names
+    }
+
+    fn param(&self, index: usize) -> f32 {
+        self.params[index].value
+    }
+
+    fn set_param(&mut self, index: usize, value: f32) {
+        let param = &mut self.params[index];
+        let value = (value as f64).clamp(param.min, param.max);
+        param.value = value as f32;
+        param.pending = Some(value);
+    }
+
+    fn latency_frames(&self) -> usize {
+        self.latency as usize
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        // CLAP has the main thread do this, but the streams are closed around a change of rate,
+        // and the first block at the new one is the first time anything runs the plugin since.
+        self.deactivate();
+        self.activate(sample_rate as u32);
+    }
+}
+
+impl Drop for ClapPlugin {
+    fn drop(&mut self) {
+        self.deactivate();
+        if let Some(destroy) = unsafe { (*self.plugin).destroy } {
+            unsafe { destroy(self.plugin) };
+        }
+    }
+}
+
+fn param_event(param: &Param, value: f64) -> clap_event_param_value {
+    clap_event_param_value {
+        header: clap_event_header {
+            size: std::mem::size_of::<clap_event_param_value>() as u32,
+            time: 0,
+            space_id: CLAP_CORE_EVENT_SPACE_ID,
+            type_: CLAP_EVENT_PARAM_VALUE,
+            flags: 0,
+        },
+        param_id: param.id,
+        cookie: param.cookie,
+        note_id: -1,
+        port_index: -1,
+        channel: -1,
+        key: -1,
+        value,
+    }
+}
+
+/// The name of a parameter in the layout, e.g. "dry-wet" for "Dry Wet", or its position for
+/// names with nothing to go by.
+fn param_name(name: &str, position: usize) -> String {
+    let words: Vec<String> = name
+        .split(|x: char| !x.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_lowercase())
+        .collect();
+    match words.is_empty() {
+        true => format!("param{}", position),
+        false => words.join("-"),
+    }
+}
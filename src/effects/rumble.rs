@@ -0,0 +1,191 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Cascade, Effect, FilterKind, ScratchArena};
+use crate::sample::Sample;
+
+/// The slopes the rumble filter comes in, in dB/octave.
+pub const SLOPES: [u32; 3] = [12, 24, 48];
+
+/// The rumble filter on the command line: `<Hz>[:<dB/octave>]`, e.g. "60:48", 24 dB/octave by
+/// default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RumbleSpec {
+    pub frequency: f32,
+    /// One of [`SLOPES`].
+    pub slope: u32,
+}
+
+impl RumbleSpec {
+    /// Order of the Butterworth response, 6 dB/octave each.
+    pub fn order(&self) -> usize {
+        self.slope as usize / 6
+    }
+}
+
+impl FromStr for RumbleSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (frequency, slope) = s.split_once(':').unwrap_or((s, "24"));
+        let frequency = frequency
+            .parse::<f32>()
+            .ok()
+            .filter(|x| x.is_finite() && *x > 0.0)
+            .ok_or_else(|| format!("invalid rumble filter frequency \"{}\"", frequency))?;
+        let slope = slope
+            .parse::<u32>()
+            .ok()
+            .filter(|x| SLOPES.contains(x))
+            .ok_or_else(|| {
+                format!(
+                    "invalid rumble filter slope \"{}\", expected 12, 24 or 48 dB/octave",
+                    slope
+                )
+            })?;
+        Ok(RumbleSpec { frequency, slope })
+    }
+}
+
+/// Qs of the biquad sections of a Butterworth filter of the even `order`, whose poles are spread
+/// evenly on a half circle: the section of the `k`th pair has a Q of `1 / (2 cos((2k + 1)π / 2n))`.
+pub fn butterworth_qs(order: usize) -> Vec<f32> {
+    (0..order / 2)
+        .map(|k| {
+            let angle = (2 * k + 1) as f64 * PI / (2 * order) as f64;
+            (1.0 / (2.0 * angle.cos())) as f32
+        })
+        .collect()
+}
+
+/// A steep Butterworth high-pass against rumble, e.g. footsteps and desk bumps, on every channel.
+///
+/// Each slope is a cascade of second-order sections at the same frequency, with the Qs from
+/// [`butterworth_qs`], so that together they make a maximally flat response: -3 dB at the
+/// corner, and the whole slope an octave below.
+pub struct RumbleFilter<S = f32> {
+    channels: Vec<Cascade<S>>,
+}
+
+impl<S: Sample> RumbleFilter<S> {
+    /// Creates the filter of a stream of `channels` channels at `sample_rate`, failing if the
+    /// corner is too close to the Nyquist frequency to be stable.
+    pub fn new(spec: RumbleSpec, sample_rate: f32, channels: usize) -> anyhow::Result<Self> {
+        if spec.frequency >= sample_rate * 0.49 {
+            bail!(
+                "cannot put the rumble filter at {} Hz in a stream at {} Hz",
+                spec.frequency,
+                sample_rate
+            );
+        }
+        let mut cascade = Cascade::new();
+        for q in butterworth_qs(spec.order()) {
+            cascade.design(FilterKind::HighPass, spec.frequency, q, sample_rate);
+        }
+        Ok(RumbleFilter {
+            channels: vec![cascade; channels],
+        })
+    }
+}
+
+impl<S: Sample> Effect<S> for RumbleFilter<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        for (data, cascade) in buffer.channels_mut().zip(&mut self.channels) {
+            cascade.process(data);
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "rumble"
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        for cascade in &mut self.channels {
+            cascade.set_sample_rate(sample_rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// Gain of the filter of `spec` at `frequency`, in dB, from the RMS of a sine once settled.
+    fn gain_db(spec: &str, frequency: f32) -> f32 {
+        let mut filter: RumbleFilter =
+            RumbleFilter::new(spec.parse().unwrap(), SAMPLE_RATE, 1).unwrap();
+        let frames = SAMPLE_RATE as usize * 2;
+        let omega = std::f32::consts::TAU * frequency / SAMPLE_RATE;
+        let sine: Vec<f32> = (0..frames).map(|n| (omega * n as f32).sin()).collect();
+        let mut buffer = AudioBuffer::new(1, frames);
+        buffer.deinterleave(&sine);
+        filter.process(&mut buffer, &mut ScratchArena::new(0));
+        let settled = &buffer.channel(0)[frames / 2..];
+        let rms = (settled.iter().map(|x| x * x).sum::<f32>() / settled.len() as f32).sqrt();
+        crate::level::gain_to_db(rms * std::f32::consts::SQRT_2)
+    }
+
+    #[test]
+    fn specs_parse_with_a_default_slope() {
+        assert_eq!(
+            "60".parse(),
+            Ok(RumbleSpec {
+                frequency: 60.0,
+                slope: 24
+            })
+        );
+        assert_eq!("60:48".parse::<RumbleSpec>().unwrap().order(), 8);
+        assert_eq!(
+            "60:36".parse::<RumbleSpec>(),
+            Err("invalid rumble filter slope \"36\", expected 12, 24 or 48 dB/octave".to_string())
+        );
+        assert_eq!(
+            "-60".parse::<RumbleSpec>(),
+            Err("invalid rumble filter frequency \"-60\"".to_string())
+        );
+    }
+
+    #[test]
+    fn butterworth_sections_spread_their_qs() {
+        let close = |qs: Vec<f32>, expected: &[f32]| {
+            qs.len() == expected.len() && qs.iter().zip(expected).all(|(x, y)| (x - y).abs() < 1e-4)
+        };
+        assert!(close(butterworth_qs(2), &[std::f32::consts::FRAC_1_SQRT_2]));
+        assert!(close(butterworth_qs(4), &[0.5412, 1.3066]));
+    }
+
+    #[test]
+    fn the_corner_is_3_db_down_and_the_slope_an_octave_below() {
+        for (slope, order) in [(12, 2), (24, 4), (48, 8)] {
+            let spec = format!("200:{}", slope);
+            assert!((gain_db(&spec, 200.0) + 3.01).abs() < 0.1);
+            // A Butterworth of order n is 10 log(1 + 4^n) dB down an octave below.
+            let expected = -10.0 * (1.0 + 4f32.powi(order)).log10();
+            let gain = gain_db(&spec, 100.0);
+            assert!(
+                (gain - expected).abs() < 0.2,
+                "{} against {}",
+                gain,
+                expected
+            );
+            assert!(gain_db(&spec, 2_000.0).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn corners_near_nyquist_are_rejected() {
+        let spec = "30000".parse().unwrap();
+        let err = RumbleFilter::<f32>::new(spec, SAMPLE_RATE, 2)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "cannot put the rumble filter at 30000 Hz in a stream at 48000 Hz"
+        );
+    }
+}
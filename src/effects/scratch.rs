@@ -0,0 +1,99 @@
+use crate::sample::Sample;
+
+/// The temporary buffers of the effects of a chain, allocated before the block path runs.
+///
+/// Effects take their temporaries here for the length of a call instead of growing buffers of
+/// their own, so that a chain prepared for its largest block provably doesn't allocate. During
+/// its call an effect can take the samples of its block, of every channel, plus the
+/// [`scratch_frames`](super::Effect::scratch_frames) it declared, in the chain's sample type and
+/// in f32. What it takes is zeroed. Taking more is a bug of the effect: debug builds panic, and
+/// release builds give it what it declared, for the chain to report.
+pub struct ScratchArena<S = f32> {
+    samples: Vec<S>,
+    floats: Vec<f32>,
+    /// Samples of each kind the effect running may take, and its name.
+    budget: usize,
+    effect: &'static str,
+    /// The first effect that asked for more than it could take since the last report, and how
+    /// many samples.
+    overdrawn: Option<(&'static str, usize, usize)>,
+}
+
+impl<S: Sample> ScratchArena<S> {
+    /// Creates an arena of `samples` samples of each kind.
+    pub fn new(samples: usize) -> Self {
+        ScratchArena {
+            samples: vec![S::default(); samples],
+            floats: vec![0.0; samples],
+            budget: 0,
+            effect: "",
+            overdrawn: None,
+        }
+    }
+
+    /// Samples of each kind the arena holds.
+    pub fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Grows the arena to `samples` of each kind, if it holds fewer.
+    pub fn reserve(&mut self, samples: usize) {
+        if self.samples.len() < samples {
+            self.samples.resize(samples, S::default());
+            self.floats.resize(samples, 0.0);
+        }
+    }
+
+    /// Lets `effect` take up to `budget` samples of each kind, until the next call.
+    pub fn begin(&mut self, effect: &'static str, budget: usize) {
+        debug_assert!(budget <= self.capacity());
+        self.effect = effect;
+        self.budget = budget.min(self.capacity());
+    }
+
+    /// Takes `samples` samples of the chain's type.
+    pub fn take(&mut self, samples: usize) -> &mut [S] {
+        let length = self.allow(samples);
+        let slice = &mut self.samples[..length];
+        slice.fill(S::default());
+        slice
+    }
+
+    /// Takes `samples` f32 samples.
+    pub fn take_f32(&mut self, samples: usize) -> &mut [f32] {
+        let length = self.allow(samples);
+        let slice = &mut self.floats[..length];
+        slice.fill(0.0);
+        slice
+    }
+
+    /// Takes `samples` samples of each kind at once.
+    pub fn take_both(&mut self, samples: usize) -> (&mut [S], &mut [f32]) {
+        let length = self.allow(samples);
+        let (ours, floats) = (&mut self.samples[..length], &mut self.floats[..length]);
+        ours.fill(S::default());
+        floats.fill(0.0);
+        (ours, floats)
+    }
+
+    /// The effect that overdrew the arena since the last call, the samples it asked for and
+    /// those it could take.
+    pub fn overdrawn(&mut self) -> Option<(&'static str, usize, usize)> {
+        self.overdrawn.take()
+    }
+
+    /// The samples a request of `samples` gets.
+    fn allow(&mut self, samples: usize) -> usize {
+        if samples <= self.budget {
+            return samples;
+        }
+        debug_assert!(
+            false,
+            "the {} effect took {} samples of scratch, over its {}",
+            self.effect, samples, self.budget
+        );
+        self.overdrawn
+            .get_or_insert((self.effect, samples, self.budget));
+        self.budget
+    }
+}
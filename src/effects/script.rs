@@ -0,0 +1,462 @@
+//! An effect written as a Rhai script, for experiments that shouldn't need a rebuild.
+//!
+//! The script defines `process(block, channels, sample_rate)`, which gets the interleaved samples
+//! of a block as an array of floats and returns the processed array, of the same length. Its
+//! state is `this`, a map kept from one block to the next, which an optional `init()` returns
+//! the start of:
+//!
+//! ```text
+//! fn init() { #{ z: 0.0, echo: delay(4800) } }
+//! fn params() { #{ gain: 0.5 } }
+//! fn process(block, channels, sample_rate) {
+//!     for i in 0..block.len() {
+//!         this.z += 0.1 * (block[i] - this.z);
+//!         block[i] = this.gain * (this.z + 0.5 * this.echo.process(this.z));
+//!     }
+//!     block
+//! }
+//! ```
+//!
+//! Parameters are the keys of the map an optional `params()` returns, with their defaults, in
+//! the order of their names. They're set in `this` before every block, e.g. `this.gain`, as
+//! `script.0.gain` in the layout.
+//!
+//! Besides the built-in math, scripts get `pow(x, y)` and delay lines: `delay(frames)` makes one,
+//! `d.process(x)` pushes a sample and returns the one of `frames` ago, and `d.read(n)` returns
+//! the one of `n` frames ago. `print` does nothing. The top level of the script runs before
+//! every call, so it should only hold constants, which functions read as `global::NAME`.
+//!
+//! The script is compiled once and checked on a block of silence when it's loaded. A block it
+//! spends more than [`BUDGET`] of the block's duration on is passed through as it was, and the
+//! second time, or at the first error, the script is bypassed for good.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST, FLOAT, INT};
+
+use super::{Effect, ScratchArena};
+use crate::buffer::AudioBuffer;
+use crate::logging::{AudioEvent, AudioLog};
+use crate::sample::Sample;
+
+/// Share of the duration of a block the script may take to process it.
+pub const BUDGET: f64 = 0.5;
+/// Time allowed for the check on a block of silence, and for `init()` and `params()`.
+const LOAD_BUDGET: Duration = Duration::from_secs(1);
+/// Frames of the block the script is checked on.
+const CHECK_FRAMES: usize = 256;
+/// Blocks over budget after which the script is bypassed.
+const MAX_OVERRUNS: u32 = 2;
+/// Longest delay line, in frames.
+const MAX_DELAY: usize = 1 << 22;
+/// Operations between two checks of the clock.
+const CLOCK_INTERVAL: u64 = 64;
+
+/// A delay line of a script.
+#[derive(Clone)]
+struct DelayLine {
+    samples: Vec<FLOAT>,
+    /// Index of the oldest sample.
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(frames: INT) -> Self {
+        DelayLine {
+            samples: vec![0.0; (frames.max(1) as usize).min(MAX_DELAY)],
+            position: 0,
+        }
+    }
+
+    fn process(&mut self, x: FLOAT) -> FLOAT {
+        let y = std::mem::replace(&mut self.samples[self.position], x);
+        self.position = (self.position + 1) % self.samples.len();
+        y
+    }
+
+    fn read(&mut self, frames: INT) -> FLOAT {
+        let len = self.samples.len();
+        let frames = (frames.max(1) as usize).min(len);
+        self.samples[(self.position + len - frames) % len]
+    }
+}
+
+/// An effect running a Rhai script on every block.
+pub struct ScriptEffect {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    /// State of the script, its `this`.
+    state: Dynamic,
+    channels: usize,
+    sample_rate: f32,
+    names: &'static [&'static str],
+    values: Vec<f32>,
+    /// Budget of the running call, in nanoseconds since `epoch`.
+    deadline: Arc<AtomicU64>,
+    epoch: Instant,
+    /// Samples of the block, reused from one call to the next.
+    block: Array,
+    /// Share of the duration of a block a call may take, or `None` for [`LOAD_BUDGET`].
+    budget: Option<f64>,
+    overruns: u32,
+    bypassed: bool,
+    /// Frames processed so far, for the timestamps of the log.
+    frames: u64,
+    log: AudioLog,
+}
+
+impl ScriptEffect {
+    /// Compiles the script at `path` for blocks of `channels` at `sample_rate`, and checks it.
+    pub fn load(path: &Path, channels: usize, sample_rate: f32) -> anyhow::Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read \"{}\"", path.display()))?;
+        Self::compile(&source, &path.display().to_string(), channels, sample_rate)
+    }
+
+    /// Compiles the script `source`, called `name` in errors, as [`load`](Self::load) does.
+    pub fn compile(
+        source: &str,
+        name: &str,
+        channels: usize,
+        sample_rate: f32,
+    ) -> anyhow::Result<Self> {
+        let epoch = Instant::now();
+        let deadline = Arc::new(AtomicU64::new(u64::MAX));
+        let engine = engine(epoch, deadline.clone());
+        let ast = engine
+            .compile(source)
+            .map_err(|err| anyhow::anyhow!("{}", err))
+            .with_context(|| format!("failed to compile \"{}\"", name))?;
+        let defines = |name: &str, arity: usize| {
+            ast.iter_functions()
+                .any(|x| x.name == name && x.params.len() == arity)
+        };
+        let (init, params) = (defines("init", 0), defines("params", 0));
+        if !defines("process", 3) {
+            bail!(
+                "\"{}\" defines no `process(block, channels, sample_rate)`",
+                name
+            );
+        }
+        let mut effect = ScriptEffect {
+            engine,
+            ast,
+            scope: Scope::new(),
+            state: Map::new().into(),
+            channels,
+            sample_rate,
+            names: &[],
+            values: Vec::new(),
+            deadline,
+            epoch,
+            block: Array::with_capacity(CHECK_FRAMES * channels),
+            budget: Some(BUDGET),
+            overruns: 0,
+            bypassed: false,
+            frames: 0,
+            log: AudioLog::disabled(),
+        };
+        let context = || format!("failed to run \"{}\"", name);
+        if init {
+            effect.state = effect.call_load("init").with_context(context)?;
+            if !effect.state.is_map() {
+                bail!("`init()` of \"{}\" must return a map", name);
+            }
+        }
+        if params {
+            let params = effect.call_load("params").with_context(context)?;
+            let params = params
+                .try_cast::<Map>()
+                .with_context(|| format!("`params()` of \"{}\" must return a map", name))?;
+            let mut names = Vec::new();
+            for (param, value) in params {
+                let value = number(&value).with_context(|| {
+                    format!(
+                        "the default of parameter \"{}\" of \"{}\" isn't a number",
+                        param, name
+                    )
+                })?;
+                names.push(&*param.to_string().leak());
+                effect.values.push(value as f32);
+            }
+            // Layouts name parameters by static strings, and effects live as long as the
+            // program.
+            effect.names = names.leak();
+        }
+        // On a copy of the state, so that the check leaves no trace in it.
+        let state = effect.state.clone();
+        effect
+            .block
+            .resize(CHECK_FRAMES * channels, Dynamic::from_float(0.0));
+        effect.start_call(LOAD_BUDGET);
+        effect
+            .call_process()
+            .map_err(|err| describe(*err))
+            .with_context(|| format!("\"{}\" failed on a block of silence", name))?;
+        effect.state = state;
+        Ok(effect)
+    }
+
+    /// Gives every block up to [`LOAD_BUDGET`] whatever its duration, for offline runs, whose
+    /// output mustn't depend on how fast the machine is.
+    pub fn offline(mut self) -> Self {
+        self.budget = None;
+        self
+    }
+
+    /// Reports overruns and bypasses to `log`.
+    pub fn logging(mut self, log: AudioLog) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// Gives the next call `budget` to finish in.
+    fn start_call(&self, budget: Duration) {
+        let deadline = self.epoch.elapsed() + budget;
+        self.deadline
+            .store(deadline.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn call_load(&mut self, name: &str) -> anyhow::Result<Dynamic> {
+        self.start_call(LOAD_BUDGET);
+        let options = CallFnOptions::new().bind_this_ptr(&mut self.state);
+        self.engine
+            .call_fn_with_options(options, &mut self.scope, &self.ast, name, ())
+            .map_err(|err| describe(*err))
+    }
+
+    /// Runs `process` on the block, leaving the result in it.
+    fn call_process(&mut self) -> Result<(), Box<EvalAltResult>> {
+        if let Some(state) = self.state.write_lock::<Map>().as_deref_mut() {
+            for (name, value) in self.names.iter().zip(&self.values) {
+                state.insert((*name).into(), Dynamic::from_float(*value as FLOAT));
+            }
+        }
+        let block = std::mem::take(&mut self.block);
+        let length = block.len();
+        let options = CallFnOptions::new().bind_this_ptr(&mut self.state);
+        let args = (block, self.channels as INT, self.sample_rate as FLOAT);
+        let result: Array = self.engine.call_fn_with_options(
+            options,
+            &mut self.scope,
+            &self.ast,
+            "process",
+            args,
+        )?;
+        if result.len() != length || !result.iter().all(|x| number(x).is_some()) {
+            return Err(format!(
+                "`process` must return an array of {} numbers, like the block",
+                length
+            )
+            .into());
+        }
+        self.block = result;
+        Ok(())
+    }
+}
+
+impl<S: Sample> Effect<S> for ScriptEffect {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        let frames = buffer.frames();
+        let seconds = self.frames as f32 / self.sample_rate;
+        self.frames += frames as u64;
+        if self.bypassed {
+            return;
+        }
+        self.block.clear();
+        for frame in 0..frames {
+            for channel in 0..self.channels {
+                let x: f64 = buffer.channel(channel)[frame].to_sample();
+                self.block.push(Dynamic::from_float(x as FLOAT));
+            }
+        }
+        let duration = frames as f64 / self.sample_rate as f64;
+        self.start_call(match self.budget {
+            Some(share) => Duration::from_secs_f64(duration * share),
+            None => LOAD_BUDGET,
+        });
+        match self.call_process() {
+            Ok(()) => {}
+            Err(err) if matches!(*err, EvalAltResult::ErrorTerminated(..)) => {
+                self.overruns += 1;
+                self.bypassed = self.overruns == MAX_OVERRUNS;
+                self.log.push(match self.bypassed {
+                    true => AudioEvent::ScriptBypassed { seconds },
+                    false => AudioEvent::ScriptOverrun { seconds },
+                });
+                return;
+            }
+            Err(_) => {
+                self.bypassed = true;
+                self.log.push(AudioEvent::ScriptFailed { seconds });
+                return;
+            }
+        }
+        for frame in 0..frames {
+            for channel in 0..self.channels {
+                let x = number(&self.block[frame * self.channels + channel]).unwrap_or(0.0);
+                buffer.channel_mut(channel)[frame] = S::from_sample(x);
+            }
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "script"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        self.names
+    }
+
+    fn param(&self, index: usize) -> f32 {
+        self.values[index]
+    }
+
+    fn set_param(&mut self, index: usize, value: f32) {
+        self.values[index] = value;
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        // The script gets the new rate with the next block, and re-derives its state itself.
+        self.frames = (self.frames as f64 * sample_rate as f64 / self.sample_rate as f64) as u64;
+        self.sample_rate = sample_rate;
+    }
+}
+
+/// An engine with the helpers of the scripts, stopping calls past `deadline`.
+fn engine(epoch: Instant, deadline: Arc<AtomicU64>) -> Engine {
+    let mut engine = Engine::new();
+    // Writing to stdout could block the audio thread.
+    engine.on_print(|_| {});
+    engine.on_debug(|_, _, _| {});
+    engine.on_progress(move |operations| {
+        let late = operations % CLOCK_INTERVAL == 0
+            && epoch.elapsed().as_nanos() as u64 > deadline.load(Ordering::Relaxed);
+        late.then_some(Dynamic::UNIT)
+    });
+    engine.register_fn("pow", |x: FLOAT, y: FLOAT| x.powf(y));
+    engine
+        .register_type_with_name::<DelayLine>("Delay")
+        .register_fn("delay", DelayLine::new)
+        .register_fn("process", DelayLine::process)
+        .register_fn("read", DelayLine::read);
+    engine
+}
+
+/// The value of a float or an integer of a script.
+fn number(value: &Dynamic) -> Option<f64> {
+    value
+        .as_float()
+        .ok()
+        .or_else(|| value.as_int().ok().map(|x| x as FLOAT))
+}
+
+fn describe(err: EvalAltResult) -> anyhow::Error {
+    match err {
+        EvalAltResult::ErrorTerminated(..) => anyhow::anyhow!("the script took too long"),
+        err => anyhow::anyhow!("{}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAIN: &str = "
+        fn params() { #{ gain: 0.5 } }
+        fn process(block, channels, sample_rate) {
+            for i in 0..block.len() { block[i] *= this.gain; }
+            block
+        }";
+
+    /// Runs a block of `frames` of `value` on both channels through `effect`.
+    fn run(effect: &mut ScriptEffect, value: f32, frames: usize) -> Vec<f32> {
+        let mut buffer = AudioBuffer::new(2, frames);
+        buffer.deinterleave(&vec![value; frames * 2]);
+        Effect::<f32>::process(effect, &mut buffer, &mut ScratchArena::new(0));
+        buffer.channel(0).to_vec()
+    }
+
+    /// Compiles `source` for offline runs, so that slow machines don't overrun.
+    fn compile(source: &str) -> anyhow::Result<ScriptEffect> {
+        ScriptEffect::compile(source, "test.rhai", 2, 48_000.0).map(ScriptEffect::offline)
+    }
+
+    #[test]
+    fn parameters_are_set_before_every_block() {
+        let mut effect = compile(GAIN).unwrap();
+        assert_eq!(Effect::<f32>::params(&effect), ["gain"]);
+        assert_eq!(Effect::<f32>::param(&effect, 0), 0.5);
+        assert!(run(&mut effect, 0.8, 64).iter().all(|x| *x == 0.4));
+        Effect::<f32>::set_param(&mut effect, 0, 0.25);
+        assert!(run(&mut effect, 0.8, 64).iter().all(|x| *x == 0.2));
+    }
+
+    #[test]
+    fn state_and_delay_lines_carry_over_from_block_to_block() {
+        let source = "
+            fn init() { #{ echo: delay(4) } }
+            fn process(block, channels, sample_rate) {
+                for i in 0..block.len() { block[i] = this.echo.process(block[i]); }
+                block
+            }";
+        let mut effect = compile(source).unwrap();
+        // Interleaved, a delay of four samples is two frames of stereo.
+        assert_eq!(run(&mut effect, 1.0, 3), [0.0, 0.0, 1.0]);
+        assert_eq!(run(&mut effect, 0.5, 3), [1.0, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn scripts_that_cannot_run_fail_to_load() {
+        let error = |source: &str| format!("{:#}", compile(source).err().unwrap());
+        assert_eq!(
+            error("fn process(block) { block }"),
+            "\"test.rhai\" defines no `process(block, channels, sample_rate)`"
+        );
+        assert_eq!(
+            error("fn init() { 1 } fn process(block, channels, sample_rate) { block }"),
+            "`init()` of \"test.rhai\" must return a map"
+        );
+        assert_eq!(
+            error("fn params() { #{ gain: \"loud\" } } fn process(b, c, s) { b }"),
+            "the default of parameter \"gain\" of \"test.rhai\" isn't a number"
+        );
+        assert_eq!(
+            error("fn process(block, channels, sample_rate) { [] }"),
+            "\"test.rhai\" failed on a block of silence: \
+             Runtime error: `process` must return an array of 512 numbers, like the block"
+        );
+        assert!(error("fn process(").starts_with("failed to compile \"test.rhai\": "));
+    }
+
+    #[test]
+    fn overruns_pass_the_block_through_then_bypass_the_script() {
+        let source = "
+            fn process(block, channels, sample_rate) {
+                if block[0] != 0.0 { loop {} }
+                block
+            }";
+        let mut effect = ScriptEffect::compile(source, "test.rhai", 2, 48_000.0).unwrap();
+        assert!(run(&mut effect, 0.5, 128).iter().all(|x| *x == 0.5));
+        assert!(!effect.bypassed);
+        run(&mut effect, 0.5, 128);
+        assert!(effect.bypassed);
+    }
+
+    #[test]
+    fn errors_bypass_the_script_at_once() {
+        let source = "
+            fn process(block, channels, sample_rate) {
+                if block[0] != 0.0 { throw \"no\"; }
+                block
+            }";
+        let mut effect = compile(source).unwrap();
+        assert!(run(&mut effect, 0.5, 128).iter().all(|x| *x == 0.5));
+        assert!(effect.bypassed);
+    }
+}
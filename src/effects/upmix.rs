@@ -0,0 +1,219 @@
+use std::str::FromStr;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{Cascade, Effect, FilterKind, ScratchArena, BUTTERWORTH_Q};
+use crate::level;
+use crate::sample::Sample;
+
+/// Delays of the left and right rears behind the fronts, in seconds, apart so that the two
+/// don't sound as one source between them.
+const REAR_DELAYS: [f32; 2] = [0.012, 0.0155];
+/// Cutoff of the low-pass on the rears, in Hz, above which ambience is mostly noise and
+/// sibilance.
+const REAR_CUTOFF: f32 = 7_000.0;
+
+/// Layouts a stereo feed can be spread to: only "quad" on the command line for now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpmixLayout {
+    /// Left and right fronts, and left and right rears.
+    Quad,
+}
+
+impl FromStr for UpmixLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "quad" => Ok(UpmixLayout::Quad),
+            _ => Err(format!("unknown upmix layout \"{}\", expected quad", s)),
+        }
+    }
+}
+
+/// Parses the left and right rear channels, e.g. "2,3".
+pub fn parse_rears(s: &str) -> Result<[usize; 2], String> {
+    let invalid = || {
+        format!(
+            "expected `<left>,<right>` channels, e.g. \"2,3\", got \"{}\"",
+            s
+        )
+    };
+    let (left, right) = s.split_once(',').ok_or_else(invalid)?;
+    let channels = [left, right].map(|x| x.trim().parse::<usize>().ok());
+    match channels {
+        [Some(left), Some(right)] if left == right => Err(format!(
+            "the rears must be two channels, got {} twice",
+            left
+        )),
+        // The rears are derived from the first two channels, which they'd overwrite.
+        [Some(left), Some(right)] if left < 2 || right < 2 => Err(format!(
+            "the rears can't be the front channels 0 and 1, got \"{}\"",
+            s
+        )),
+        [Some(left), Some(right)] => Ok([left, right]),
+        _ => Err(invalid()),
+    }
+}
+
+/// One of the rears.
+struct Rear<S> {
+    channel: usize,
+    /// The side signal, oldest first from `position`.
+    delay: Vec<S>,
+    position: usize,
+    filter: Cascade<S>,
+    /// Polarity of the side signal, opposite in the two rears.
+    sign: S,
+}
+
+/// Spreads the stereo of the first two channels to rear channels, which get the ambience of
+/// the signal: its side, what differs between left and right, delayed and low-passed, and in
+/// opposite polarity on either side. Centred sources, the same in both fronts, stay out of the
+/// rears.
+///
+/// The fronts are left as they come, and the other channels too, so the output expects the
+/// channel adapter to feed the first two channels with left and right.
+pub struct Upmix<S = f32> {
+    rears: [Rear<S>; 2],
+    level_db: f32,
+    gain: S,
+}
+
+impl<S: Sample> Upmix<S> {
+    /// Feeds the rears on `channels`, left then right, at `level_db` under the side signal.
+    pub fn new(channels: [usize; 2], level_db: f32, sample_rate: f32) -> Self {
+        let rear = |side: usize| {
+            let mut filter = Cascade::new();
+            filter.design(
+                FilterKind::LowPass,
+                REAR_CUTOFF.min(sample_rate * 0.45),
+                BUTTERWORTH_Q,
+                sample_rate,
+            );
+            Rear {
+                channel: channels[side],
+                delay: vec![S::default(); delay_frames(side, sample_rate)],
+                position: 0,
+                filter,
+                sign: S::from_sample(if side == 0 { 1.0 } else { -1.0 }),
+            }
+        };
+        Upmix {
+            rears: [rear(0), rear(1)],
+            level_db,
+            gain: S::from_sample(level::db_to_gain(level_db)),
+        }
+    }
+
+    /// Channels the upmix needs in the output.
+    pub fn channels_needed(channels: [usize; 2]) -> usize {
+        channels[0].max(channels[1]) + 1
+    }
+}
+
+/// Frames the rear on `side` is delayed by at `sample_rate`.
+fn delay_frames(side: usize, sample_rate: f32) -> usize {
+    ((REAR_DELAYS[side] * sample_rate) as usize).max(1)
+}
+
+impl<S: Sample> Effect<S> for Upmix<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        let half = S::from_sample(0.5);
+        for rear in &mut self.rears {
+            for frame in 0..buffer.frames() {
+                let side = (buffer.channel(0)[frame] - buffer.channel(1)[frame]) * half;
+                let delayed = std::mem::replace(&mut rear.delay[rear.position], side);
+                rear.position = (rear.position + 1) % rear.delay.len();
+                buffer.channel_mut(rear.channel)[frame] = delayed * rear.sign * self.gain;
+            }
+            rear.filter.process(buffer.channel_mut(rear.channel));
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "upmix"
+    }
+
+    fn params(&self) -> &'static [&'static str] {
+        &["rear"]
+    }
+
+    fn param(&self, _index: usize) -> f32 {
+        self.level_db
+    }
+
+    fn set_param(&mut self, _index: usize, value: f32) {
+        self.level_db = value;
+        self.gain = S::from_sample(level::db_to_gain(value));
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        for (side, rear) in self.rears.iter_mut().enumerate() {
+            rear.filter.set_sample_rate(sample_rate);
+            rear.delay = vec![S::default(); delay_frames(side, sample_rate)];
+            rear.position = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48_000.0;
+
+    /// Runs 100 ms of quad, starting with the frame `first` and silent after it, through an
+    /// upmix to the rears 2 and 3 at -6 dB, returning its channels.
+    fn run(first: [f32; 4]) -> Vec<Vec<f32>> {
+        let mut upmix: Upmix = Upmix::new([2, 3], -6.0, SAMPLE_RATE);
+        let frames = 4_800;
+        let mut data = vec![0.0; frames * 4];
+        data[..4].copy_from_slice(&first);
+        let mut buffer = AudioBuffer::new(4, frames);
+        buffer.deinterleave(&data);
+        upmix.process(&mut buffer, &mut ScratchArena::new(0));
+        (0..4).map(|x| buffer.channel(x).to_vec()).collect()
+    }
+
+    #[test]
+    fn rears_are_parsed_apart_from_the_fronts() {
+        assert_eq!("quad".parse(), Ok(UpmixLayout::Quad));
+        assert_eq!(
+            "5.1".parse::<UpmixLayout>(),
+            Err("unknown upmix layout \"5.1\", expected quad".to_string())
+        );
+        assert_eq!(parse_rears("2, 3"), Ok([2, 3]));
+        assert_eq!(parse_rears("5,4"), Ok([5, 4]));
+        assert_eq!(
+            parse_rears("3,3"),
+            Err("the rears must be two channels, got 3 twice".to_string())
+        );
+        assert_eq!(
+            parse_rears("1,2"),
+            Err("the rears can't be the front channels 0 and 1, got \"1,2\"".to_string())
+        );
+        assert!(parse_rears("2").is_err());
+        assert_eq!(Upmix::<f32>::channels_needed([5, 2]), 6);
+    }
+
+    #[test]
+    fn centred_sources_stay_out_of_the_rears() {
+        let channels = run([1.0, 1.0, 0.3, 0.3]);
+        assert_eq!(channels[0][0], 1.0);
+        assert_eq!(channels[1][0], 1.0);
+        assert!(channels[2].iter().chain(&channels[3]).all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn the_side_reaches_the_rears_late_and_in_opposite_polarity() {
+        let channels = run([1.0, 0.0, 0.0, 0.0]);
+        let onset = |x: &[f32]| x.iter().position(|x| *x != 0.0);
+        assert_eq!(onset(&channels[2]), Some(576));
+        assert_eq!(onset(&channels[3]), Some(744));
+        // Half the difference, at -6 dB, through a low-pass of unity gain at DC.
+        let sums = [&channels[2], &channels[3]].map(|x| x.iter().sum::<f32>());
+        let expected = 0.5 * level::db_to_gain(-6.0);
+        assert!((sums[0] - expected).abs() < 1e-4, "{:?}", sums);
+        assert!((sums[1] + expected).abs() < 1e-4, "{:?}", sums);
+    }
+}
@@ -0,0 +1,92 @@
+//! Envelope following with separate attack and release times.
+
+/// One-pole smoother that rises with the attack time and falls with the release time.
+///
+/// Fed with rectified samples, it follows the envelope of a signal. Fed with a gain or a level in
+/// dB, it gives that value attack and release ballistics.
+#[derive(Clone, Copy, Debug)]
+pub struct EnvelopeFollower {
+    attack: f32,
+    release: f32,
+    value: f32,
+}
+
+impl EnvelopeFollower {
+    /// Creates a follower starting at 0, with times in milliseconds for a signal at `sample_rate`.
+    pub fn new(attack_ms: f32, release_ms: f32, sample_rate: u32) -> Self {
+        EnvelopeFollower {
+            attack: coefficient(attack_ms, sample_rate),
+            release: coefficient(release_ms, sample_rate),
+            value: 0.0,
+        }
+    }
+
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+
+    /// Keeps the times of a follower whose signal goes from `from` to `to` Hz, and its value.
+    pub fn change_rate(&mut self, from: u32, to: u32) {
+        let exponent = from as f32 / to as f32;
+        self.attack = self.attack.powf(exponent);
+        self.release = self.release.powf(exponent);
+    }
+
+    /// Moves one sample towards `input`, returning the new value.
+    #[inline]
+    pub fn process(&mut self, input: f32) -> f32 {
+        let coefficient = if input > self.value {
+            self.attack
+        } else {
+            self.release
+        };
+        self.value = input + coefficient * (self.value - input);
+        self.value
+    }
+}
+
+/// Per-sample decay factor of a one-pole smoother reaching 63% of a step in `ms` milliseconds.
+fn coefficient(ms: f32, sample_rate: u32) -> f32 {
+    if ms <= 0.0 {
+        return 0.0;
+    }
+    (-1.0 / (ms / 1_000.0 * sample_rate as f32)).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples a follower takes to reach 63% of a step from 0 to 1, or back.
+    fn time_constant(follower: &mut EnvelopeFollower, target: f32) -> usize {
+        let start = follower.value();
+        let threshold = start + (target - start) * (1.0 - (-1f32).exp());
+        (1..100_000)
+            .find(|_| {
+                let value = follower.process(target);
+                (value - threshold) * (target - start).signum() >= 0.0
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn rises_with_the_attack_and_falls_with_the_release() {
+        let mut follower = EnvelopeFollower::new(10.0, 100.0, 48_000);
+        assert!((time_constant(&mut follower, 1.0) as i32 - 480).abs() <= 1);
+        for _ in 0..48_000 {
+            follower.process(1.0);
+        }
+        assert!((follower.value() - 1.0).abs() < 1e-3);
+        assert!((time_constant(&mut follower, 0.0) as i32 - 4_800).abs() <= 2);
+    }
+
+    #[test]
+    fn zero_times_follow_at_once() {
+        let mut follower = EnvelopeFollower::new(0.0, 50.0, 48_000);
+        assert_eq!(follower.process(0.8), 0.8);
+        assert!(follower.process(0.0) > 0.79);
+        let mut follower = EnvelopeFollower::new(5.0, 0.0, 48_000);
+        follower.process(1.0);
+        assert_eq!(follower.process(0.0), 0.0);
+    }
+}
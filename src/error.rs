@@ -0,0 +1,185 @@
+//! Errors of the engine by what went wrong, so that scripts around the binary can tell a missing
+//! device from a bad argument by the exit code, rather than by the message.
+//!
+//! Library functions on the way to running streams return [`EngineError`], and the binary maps
+//! every variant to an exit code. Errors that fit none of them stay `anyhow` errors.
+
+/// What stopped the engine from starting, or from running.
+#[derive(Debug, thiserror::Error)]
+pub enum EngineError {
+    /// No device has the name asked for. `candidates` are the closest names if `closest` is
+    /// set, and every name of the kind otherwise.
+    #[error(
+        "failed to find {kind} device \"{name}\"; {}",
+        describe_candidates(kind, candidates, *closest)
+    )]
+    DeviceNotFound {
+        kind: &'static str,
+        name: String,
+        candidates: Vec<String>,
+        closest: bool,
+    },
+    /// No host available at runtime has the name asked for, e.g. "asio" on Linux.
+    #[error("the \"{name}\" driver isn't available; {}", describe_hosts(available))]
+    HostUnavailable {
+        name: String,
+        available: Vec<String>,
+    },
+    /// A device index past the devices listed by `--list-devices`.
+    #[error("{kind} device #{index} is out of range: {}", describe_range(kind, *count))]
+    DeviceIndexOutOfRange {
+        kind: &'static str,
+        index: usize,
+        count: usize,
+    },
+    /// A device supports nothing like the configuration asked for, e.g. "96000 Hz".
+    #[error(
+        "{device} doesn't support {requested}, {}",
+        describe_supported(supported)
+    )]
+    UnsupportedConfig {
+        device: String,
+        requested: String,
+        supported: String,
+    },
+    /// The backend failed to list the devices.
+    #[error(transparent)]
+    Devices(#[from] cpal::DevicesError),
+    /// The backend failed to list the configurations of a device.
+    #[error(transparent)]
+    SupportedConfigs(#[from] cpal::SupportedStreamConfigsError),
+    #[error("failed to build the {stream}")]
+    StreamBuild {
+        stream: &'static str,
+        #[source]
+        source: cpal::BuildStreamError,
+    },
+    #[error("failed to start the {stream}")]
+    StreamPlay {
+        stream: &'static str,
+        #[source]
+        source: cpal::PlayStreamError,
+    },
+    /// The streams ran but failed past recovering, e.g. out of restarts.
+    #[error("{0}")]
+    StreamFailed(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// An argument is out of range, or at odds with the devices or the other arguments.
+    #[error("{0}")]
+    InvalidArgument(String),
+}
+
+fn describe_candidates(kind: &str, candidates: &[String], closest: bool) -> String {
+    let names: Vec<String> = candidates.iter().map(|x| format!("\"{}\"", x)).collect();
+    match (closest, names.is_empty()) {
+        (true, _) => format!("closest matches: {}", names.join(", ")),
+        (false, true) => format!("there are no {} devices", kind),
+        (false, false) => format!("available devices: {}", names.join(", ")),
+    }
+}
+
+fn describe_hosts(available: &[String]) -> String {
+    let names: Vec<String> = available.iter().map(|x| format!("\"{}\"", x)).collect();
+    match names.is_empty() {
+        true => "there are no drivers".to_string(),
+        false => format!("available drivers: \"default\", {}", names.join(", ")),
+    }
+}
+
+fn describe_supported(supported: &str) -> String {
+    match supported.is_empty() {
+        true => "nor anything else".to_string(),
+        false => format!("only {}", supported),
+    }
+}
+
+fn describe_range(kind: &str, count: usize) -> String {
+    match count {
+        0 => format!("there are no {} devices", kind),
+        _ => format!(
+            "valid indices are #0 to #{} (see --list-devices)",
+            count - 1
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn not_found(candidates: &[&str], closest: bool) -> String {
+        EngineError::DeviceNotFound {
+            kind: "input",
+            name: "Mic".to_string(),
+            candidates: candidates.iter().map(|x| x.to_string()).collect(),
+            closest,
+        }
+        .to_string()
+    }
+
+    #[test]
+    fn missing_devices_list_the_candidates() {
+        assert_eq!(
+            not_found(&["USB Mic", "Mic Array"], true),
+            "failed to find input device \"Mic\"; closest matches: \"USB Mic\", \"Mic Array\""
+        );
+        assert_eq!(
+            not_found(&["Line In"], false),
+            "failed to find input device \"Mic\"; available devices: \"Line In\""
+        );
+        assert_eq!(
+            not_found(&[], false),
+            "failed to find input device \"Mic\"; there are no input devices"
+        );
+    }
+
+    #[test]
+    fn indices_out_of_range_give_the_valid_ones() {
+        let out_of_range = |count| {
+            EngineError::DeviceIndexOutOfRange {
+                kind: "output",
+                index: 3,
+                count,
+            }
+            .to_string()
+        };
+        assert_eq!(
+            out_of_range(2),
+            "output device #3 is out of range: valid indices are #0 to #1 (see --list-devices)"
+        );
+        assert_eq!(
+            out_of_range(0),
+            "output device #3 is out of range: there are no output devices"
+        );
+    }
+
+    #[test]
+    fn unsupported_configs_say_what_is_supported() {
+        let err = EngineError::UnsupportedConfig {
+            device: "the input device".to_string(),
+            requested: "96000 Hz".to_string(),
+            supported: "44100 to 48000 Hz".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "the input device doesn't support 96000 Hz, only 44100 to 48000 Hz"
+        );
+    }
+
+    #[test]
+    fn stream_errors_keep_their_source() {
+        let err = EngineError::StreamBuild {
+            stream: "output stream",
+            source: cpal::BuildStreamError::DeviceNotAvailable,
+        };
+        let err = anyhow::Error::from(err);
+        assert_eq!(
+            format!("{:#}", err),
+            format!(
+                "failed to build the output stream: {}",
+                cpal::BuildStreamError::DeviceNotAvailable
+            )
+        );
+    }
+}
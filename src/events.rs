@@ -0,0 +1,290 @@
+//! Events the streams report to the main thread, and what it does about them.
+//!
+//! The error callback of every stream sends its errors, tagged with the stream, down a channel
+//! the main thread drains between its other chores. There an [`ErrorPolicy`] decides: a device
+//! gone, or one whose configuration the system changed, restarts the streams, backend errors are counted and only abort past a limit, and
+//! errors that say nothing are logged with their stream.
+
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+
+/// What a stream error means for the engine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The device was unplugged or otherwise went away.
+    DeviceGone,
+    /// The system changed the configuration of the device under the stream, e.g. its sample
+    /// rate, so the stream has to be built again.
+    ConfigChanged,
+    /// The backend failed a call, e.g. after an xrun it couldn't recover from by itself.
+    Backend,
+    /// An error without a description to go by.
+    Unknown,
+}
+
+/// Descriptions of backend errors that mean the device went away, as ALSA and others put it.
+const GONE: [&str; 3] = ["ENODEV", "No such device", "disconnected"];
+
+/// Descriptions of backend errors that mean the configuration of the device changed. WASAPI
+/// invalidates the stream, which cpal reports as the device going away.
+const CHANGED: [&str; 4] = [
+    "sample rate",
+    "format changed",
+    "UNSUPPORTED_FORMAT",
+    "configuration changed",
+];
+
+/// Sorts `err` into the kind of action it calls for.
+pub fn classify(err: &cpal::StreamError) -> ErrorKind {
+    match err {
+        cpal::StreamError::DeviceNotAvailable => ErrorKind::DeviceGone,
+        cpal::StreamError::BackendSpecific { err } => {
+            let description = err.description.trim();
+            if description.is_empty() {
+                ErrorKind::Unknown
+            } else if GONE.iter().any(|x| description.contains(x)) {
+                ErrorKind::DeviceGone
+            } else if CHANGED.iter().any(|x| description.contains(x)) {
+                ErrorKind::ConfigChanged
+            } else {
+                ErrorKind::Backend
+            }
+        }
+    }
+}
+
+/// Whether a stream failed to build because of its configuration, which another one may avoid,
+/// rather than because its device went away, which only the recovery can wait out.
+pub fn is_config_failure(err: &cpal::BuildStreamError) -> bool {
+    match err {
+        cpal::BuildStreamError::StreamConfigNotSupported
+        | cpal::BuildStreamError::InvalidArgument => true,
+        cpal::BuildStreamError::DeviceNotAvailable | cpal::BuildStreamError::StreamIdOverflow => {
+            false
+        }
+        // Backends put unsupported formats and lost devices alike here.
+        cpal::BuildStreamError::BackendSpecific { err } => {
+            !GONE.iter().any(|x| err.description.contains(x))
+        }
+    }
+}
+
+/// Something that happened to a stream.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EngineEvent {
+    /// The error callback of the stream labelled `stream` was called.
+    StreamError {
+        stream: &'static str,
+        kind: ErrorKind,
+        message: String,
+    },
+}
+
+impl EngineEvent {
+    pub fn stream_error(stream: &'static str, err: &cpal::StreamError) -> Self {
+        EngineEvent::StreamError {
+            stream,
+            kind: classify(err),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// The error callback of the stream labelled `stream`, sending its errors to `events`.
+pub fn error_callback(
+    stream: &'static str,
+    events: Sender<EngineEvent>,
+) -> impl FnMut(cpal::StreamError) + Send + 'static {
+    // The main thread may be gone while a stream shuts down, with nothing left to tell.
+    move |err| {
+        let _ = events.send(EngineEvent::stream_error(stream, &err));
+    }
+}
+
+/// What the main thread should do about an event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Nothing more than logging it.
+    Continue,
+    /// Restart the streams.
+    Restart(String),
+    /// Stop, for this reason.
+    Abort(String),
+}
+
+/// Turns events into actions, counting the backend errors of every stream.
+pub struct ErrorPolicy {
+    /// Backend errors a stream can report before the engine gives up on it.
+    backend_limit: usize,
+    backend_errors: HashMap<&'static str, usize>,
+}
+
+impl ErrorPolicy {
+    pub fn new(backend_limit: usize) -> Self {
+        ErrorPolicy {
+            backend_limit,
+            backend_errors: HashMap::new(),
+        }
+    }
+
+    /// Backend errors `stream` reported so far.
+    pub fn backend_errors(&self, stream: &str) -> usize {
+        self.backend_errors.get(stream).copied().unwrap_or(0)
+    }
+
+    /// The action `event` calls for, with the line to log about it.
+    pub fn handle(&mut self, event: &EngineEvent) -> (Action, String) {
+        match event {
+            EngineEvent::StreamError {
+                stream,
+                kind,
+                message,
+            } => match kind {
+                ErrorKind::DeviceGone => (
+                    Action::Restart(format!("the device of the {} went away", stream)),
+                    format!("the {} lost its device: {}", stream, message),
+                ),
+                ErrorKind::ConfigChanged => (
+                    Action::Restart(format!("the configuration of the {} changed", stream)),
+                    format!("the configuration of the {} changed: {}", stream, message),
+                ),
+                ErrorKind::Backend => {
+                    let count = self.backend_errors.entry(stream).or_insert(0);
+                    *count += 1;
+                    let line = format!(
+                        "backend error {} on the {}, of {} allowed: {}",
+                        count, stream, self.backend_limit, message
+                    );
+                    match *count > self.backend_limit {
+                        true => (
+                            Action::Abort(format!(
+                                "the {} reported more than {} backend errors, the last: {}",
+                                stream, self.backend_limit, message
+                            )),
+                            line,
+                        ),
+                        false => (Action::Continue, line),
+                    }
+                }
+                ErrorKind::Unknown => (
+                    Action::Continue,
+                    format!("an unknown error occurred on the {}: {:?}", stream, message),
+                ),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backend(description: &str) -> cpal::StreamError {
+        cpal::StreamError::BackendSpecific {
+            err: cpal::BackendSpecificError {
+                description: description.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn errors_are_classified_by_their_description() {
+        let gone = cpal::StreamError::DeviceNotAvailable;
+        assert_eq!(classify(&gone), ErrorKind::DeviceGone);
+        assert_eq!(
+            classify(&backend("snd_pcm_recover: ENODEV")),
+            ErrorKind::DeviceGone
+        );
+        assert_eq!(classify(&backend("buffer underrun")), ErrorKind::Backend);
+        assert_eq!(classify(&backend("  ")), ErrorKind::Unknown);
+    }
+
+    #[test]
+    fn error_callbacks_tag_their_stream() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut callback = error_callback("input stream", sender);
+        callback(cpal::StreamError::DeviceNotAvailable);
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            EngineEvent::StreamError {
+                stream: "input stream",
+                kind: ErrorKind::DeviceGone,
+                message: cpal::StreamError::DeviceNotAvailable.to_string(),
+            }
+        );
+        // Nobody left to tell.
+        drop(receiver);
+        callback(cpal::StreamError::DeviceNotAvailable);
+    }
+
+    #[test]
+    fn lost_devices_restart_the_streams() {
+        let mut policy = ErrorPolicy::new(2);
+        let event = EngineEvent::stream_error("output stream", &backend("No such device"));
+        let (action, _) = policy.handle(&event);
+        assert_eq!(
+            action,
+            Action::Restart("the device of the output stream went away".to_string())
+        );
+    }
+
+    #[test]
+    fn backend_errors_abort_past_the_limit_of_their_stream() {
+        let mut policy = ErrorPolicy::new(2);
+        let output = EngineEvent::stream_error("output stream", &backend("xrun"));
+        let input = EngineEvent::stream_error("input stream", &backend("xrun"));
+        assert_eq!(policy.handle(&output).0, Action::Continue);
+        assert_eq!(
+            policy.handle(&output),
+            (
+                Action::Continue,
+                "backend error 2 on the output stream, of 2 allowed: \
+                 A backend-specific error has occurred: xrun"
+                    .to_string()
+            )
+        );
+        assert_eq!(policy.handle(&input).0, Action::Continue);
+        assert_eq!(
+            policy.handle(&output).0,
+            Action::Abort(
+                "the output stream reported more than 2 backend errors, the last: \
+                 A backend-specific error has occurred: xrun"
+                    .to_string()
+            )
+        );
+        assert_eq!(policy.backend_errors("output stream"), 3);
+        assert_eq!(policy.backend_errors("input stream"), 1);
+    }
+
+    #[test]
+    fn unknown_errors_are_only_logged() {
+        let mut policy = ErrorPolicy::new(0);
+        let event = EngineEvent::stream_error("input stream", &backend(""));
+        assert_eq!(
+            policy.handle(&event),
+            (
+                Action::Continue,
+                "an unknown error occurred on the input stream: \"A backend-specific error has occurred: \""
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn only_failures_of_the_config_climb_the_ladder() {
+        let backend = |description: &str| cpal::BuildStreamError::BackendSpecific {
+            err: cpal::BackendSpecificError {
+                description: description.to_string(),
+            },
+        };
+        assert!(is_config_failure(
+            &cpal::BuildStreamError::StreamConfigNotSupported
+        ));
+        assert!(is_config_failure(&cpal::BuildStreamError::InvalidArgument));
+        assert!(is_config_failure(&backend("snd_pcm_hw_params: EINVAL")));
+        assert!(!is_config_failure(
+            &cpal::BuildStreamError::DeviceNotAvailable
+        ));
+        assert!(!is_config_failure(&backend("snd_pcm_open: ENODEV")));
+    }
+}
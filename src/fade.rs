@@ -0,0 +1,346 @@
+//! Fading the outputs in at the start of a run and out at its end, so that neither clicks, and
+//! draining them before the fade-out.
+//!
+//! Every output has its own [`Fader`], the last stage of its callback, which counts the fades in
+//! frames of its own stream. The faders share a [`FadeOut`] through which the main thread starts
+//! fading them all out and then waits for them to fall silent before dropping the streams.
+//!
+//! Before that, the inputs stop, and each output keeps playing what's still in its ring buffers,
+//! then the tails of its effects, such as the decay of a reverb. Its [`Tail`] tells the shared
+//! [`Drain`] once the output is silent, or after a longest tail.
+//!
+//! Fades follow a raised cosine, whose slope is zero at both ends, so that the ramp itself
+//! doesn't click. A fade-out that starts during the fade-in picks up from the gain reached.
+
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::simd;
+
+/// Peak level under which an output's tail counts as over, about -90 dBFS.
+const SILENCE: f32 = 3e-5;
+
+/// How many of the outputs are done with a stage of the end of the run.
+#[derive(Default)]
+struct Tally {
+    /// Outputs alive, and how many of them are done.
+    alive: AtomicUsize,
+    done: AtomicUsize,
+}
+
+impl Tally {
+    fn join(&self) {
+        self.alive.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn finish(&self) {
+        self.done.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// An output whose stream is gone doesn't hold up the others.
+    fn leave(&self, done: bool) {
+        if done {
+            self.done.fetch_sub(1, Ordering::AcqRel);
+        }
+        self.alive.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Waits until every output is done, or for `timeout`, returning whether they all are.
+    /// Outputs whose stream stopped calling back are never done.
+    fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.done.load(Ordering::Acquire) >= self.alive.load(Ordering::Acquire) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(2));
+        }
+    }
+}
+
+/// Shared between the main thread and the faders of the outputs.
+#[derive(Default)]
+pub struct FadeOut {
+    requested: AtomicBool,
+    /// The faders silent after the fade-out.
+    silent: Tally,
+}
+
+impl FadeOut {
+    /// Starts fading out every output.
+    pub fn start(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Waits until every output is silent, or for `timeout`, returning whether they all are.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        self.silent.wait(timeout)
+    }
+}
+
+/// Shared between the main thread and the tails of the outputs.
+#[derive(Default)]
+pub struct Drain {
+    drained: Tally,
+}
+
+impl Drain {
+    /// Waits until every output has played all its input and the tails of its effects, or for
+    /// `timeout`, returning whether they all have.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        self.drained.wait(timeout)
+    }
+}
+
+/// Watches one output for the end of its tail.
+pub struct Tail {
+    shared: Arc<Drain>,
+    /// Frames of tail left before giving up on silence.
+    remaining: usize,
+    sample_rate: u32,
+    drained: bool,
+}
+
+impl Tail {
+    /// Watches a stream at `sample_rate`, whose tail lasts at most `longest`.
+    pub fn new(longest: Duration, sample_rate: u32, shared: Arc<Drain>) -> Self {
+        shared.drained.join();
+        Tail {
+            shared,
+            remaining: (longest.as_secs_f64() * sample_rate as f64) as usize,
+            sample_rate,
+            drained: false,
+        }
+    }
+
+    /// Counts the tail left in frames at `sample_rate` from now on.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.remaining =
+            (self.remaining as f64 * sample_rate as f64 / self.sample_rate as f64) as usize;
+        self.sample_rate = sample_rate;
+    }
+
+    /// Takes a processed block of interleaved frames, once the inputs of the output are empty
+    /// for good.
+    pub fn process(&mut self, data: &[f32], channels: usize) {
+        if self.drained {
+            return;
+        }
+        let frames = data.len() / channels.max(1);
+        self.remaining = self.remaining.saturating_sub(frames);
+        if self.remaining == 0 || simd::peak(data) < SILENCE {
+            self.drained = true;
+            self.shared.drained.finish();
+        }
+    }
+}
+
+impl Drop for Tail {
+    fn drop(&mut self) {
+        self.shared.drained.leave(self.drained);
+    }
+}
+
+/// The fades of one output.
+pub struct Fader {
+    shared: Arc<FadeOut>,
+    /// How far the gain is along the curve, from 0, silent, to 1, at unity.
+    position: f32,
+    /// Steps of the position per frame of the fades.
+    step_in: f32,
+    step_out: f32,
+    fades: (Duration, Duration),
+    silent: bool,
+}
+
+impl Fader {
+    /// Creates the fader of a stream at `sample_rate`, starting silent. Fades of zero length are
+    /// a single frame.
+    pub fn new(
+        fade_in: Duration,
+        fade_out: Duration,
+        sample_rate: u32,
+        shared: Arc<FadeOut>,
+    ) -> Self {
+        shared.silent.join();
+        Fader {
+            shared,
+            position: 0.0,
+            step_in: step(fade_in, sample_rate),
+            step_out: step(fade_out, sample_rate),
+            fades: (fade_in, fade_out),
+            silent: false,
+        }
+    }
+
+    /// Fades in frames at `sample_rate` from now on, from where the gain is.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.step_in = step(self.fades.0, sample_rate);
+        self.step_out = step(self.fades.1, sample_rate);
+    }
+
+    /// Applies the fades to a block of interleaved frames.
+    pub fn process(&mut self, data: &mut [f32], channels: usize) {
+        let out = self.shared.requested.load(Ordering::Relaxed);
+        if self.silent {
+            data.fill(0.0);
+            return;
+        }
+        if !out && self.position >= 1.0 {
+            return;
+        }
+        for frame in data.chunks_mut(channels) {
+            self.position = match out {
+                true => (self.position - self.step_out).max(0.0),
+                false => (self.position + self.step_in).min(1.0),
+            };
+            let gain = gain(self.position);
+            frame.iter_mut().for_each(|x| *x *= gain);
+        }
+        if out && self.position <= 0.0 {
+            self.silent = true;
+            self.shared.silent.finish();
+        }
+    }
+}
+
+impl Drop for Fader {
+    fn drop(&mut self) {
+        self.shared.silent.leave(self.silent);
+    }
+}
+
+/// The step per frame of a fade lasting `fade` at `sample_rate`.
+fn step(fade: Duration, sample_rate: u32) -> f32 {
+    1.0 / (fade.as_secs_f32() * sample_rate as f32).max(1.0)
+}
+
+/// The gain at `position` along a fade.
+pub fn gain(position: f32) -> f32 {
+    0.5 - 0.5 * (PI * position).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fader of mono frames at 1 kHz, so that a fade of 100 ms is 100 frames.
+    fn fader(fade_in: u64, fade_out: u64) -> (Fader, Arc<FadeOut>) {
+        let shared = Arc::new(FadeOut::default());
+        let fader = Fader::new(
+            Duration::from_millis(fade_in),
+            Duration::from_millis(fade_out),
+            1_000,
+            shared.clone(),
+        );
+        (fader, shared)
+    }
+
+    /// The gains of `frames` frames.
+    fn run(fader: &mut Fader, frames: usize) -> Vec<f32> {
+        let mut data = vec![1.0; frames];
+        fader.process(&mut data, 1);
+        data
+    }
+
+    #[test]
+    fn outputs_fade_in_along_a_raised_cosine() {
+        let (mut fader, _) = fader(100, 100);
+        let gains = run(&mut fader, 50);
+        assert!((gains[0] - gain(0.01)).abs() < 1e-6);
+        assert!((gains[49] - 0.5).abs() < 1e-4, "{}", gains[49]);
+        assert!(gains.windows(2).all(|x| x[1] > x[0]));
+        let gains = run(&mut fader, 100);
+        assert!((gains[49] - 1.0).abs() < 1e-6);
+        assert!(gains[50..].iter().all(|x| *x == 1.0));
+        assert_eq!(gain(0.0), 0.0);
+    }
+
+    #[test]
+    fn fading_out_ends_in_silence() {
+        let (mut fader, shared) = fader(10, 100);
+        run(&mut fader, 10);
+        assert!(!shared.wait(Duration::ZERO));
+        shared.start();
+        let gains = run(&mut fader, 120);
+        assert!((gains[49] - 0.5).abs() < 1e-4, "{}", gains[49]);
+        assert!(gains.windows(2).all(|x| x[1] <= x[0]));
+        assert!(gains[100..].iter().all(|x| *x == 0.0));
+        assert!(shared.wait(Duration::ZERO));
+        assert!(run(&mut fader, 10).iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn a_fade_out_during_the_fade_in_starts_from_the_gain_reached() {
+        let (mut fader, shared) = fader(100, 100);
+        let reached = *run(&mut fader, 30).last().unwrap();
+        shared.start();
+        let gains = run(&mut fader, 30);
+        assert!(gains[0] < reached && gains[0] > gain(0.28), "{}", gains[0]);
+        assert_eq!(gains[29], 0.0);
+    }
+
+    #[test]
+    fn fades_of_zero_length_last_a_frame() {
+        let (mut fader, shared) = fader(0, 0);
+        assert_eq!(run(&mut fader, 2), [1.0, 1.0]);
+        shared.start();
+        assert_eq!(run(&mut fader, 2), [0.0, 0.0]);
+        assert!(shared.wait(Duration::ZERO));
+    }
+
+    #[test]
+    fn faders_that_are_gone_do_not_hold_up_the_others() {
+        let (mut first, shared) = fader(0, 10);
+        let second = Fader::new(Duration::ZERO, Duration::ZERO, 1_000, shared.clone());
+        shared.start();
+        run(&mut first, 20);
+        assert!(!shared.wait(Duration::from_millis(5)));
+        drop(second);
+        assert!(shared.wait(Duration::ZERO));
+        drop(first);
+        assert!(shared.wait(Duration::ZERO));
+    }
+
+    #[test]
+    fn changing_the_rate_keeps_the_fades_in_time() {
+        let (mut fader, _) = fader(100, 100);
+        run(&mut fader, 50);
+        fader.set_sample_rate(2_000);
+        let gains = run(&mut fader, 100);
+        assert!(gains[98] < 1.0);
+        assert_eq!(gains[99], 1.0);
+    }
+
+    #[test]
+    fn tails_are_over_once_silent() {
+        let shared = Arc::new(Drain::default());
+        let mut tail = Tail::new(Duration::from_secs(5), 1_000, shared.clone());
+        tail.process(&[0.1; 64], 2);
+        assert!(!shared.wait(Duration::ZERO));
+        tail.process(&[1e-5; 64], 2);
+        assert!(shared.wait(Duration::ZERO));
+        drop(tail);
+        assert!(shared.wait(Duration::ZERO));
+    }
+
+    #[test]
+    fn tails_give_up_after_the_longest() {
+        let shared = Arc::new(Drain::default());
+        let mut tail = Tail::new(Duration::from_millis(100), 1_000, shared.clone());
+        let mut other = Tail::new(Duration::from_millis(100), 1_000, shared.clone());
+        other.process(&[0.0; 8], 1);
+        tail.process(&[0.5; 64], 1);
+        tail.set_sample_rate(2_000);
+        // 36 frames were left at 1 kHz, and 72 are at 2 kHz.
+        tail.process(&[0.5; 71], 1);
+        assert!(!shared.wait(Duration::ZERO));
+        tail.process(&[0.5; 1], 1);
+        assert!(shared.wait(Duration::ZERO));
+    }
+}
@@ -0,0 +1,135 @@
+//! Distribution of the input stream to several output streams.
+
+use ringbuf::traits::Producer;
+
+use crate::adapter::Converter;
+
+/// Pushes every block of input samples into several ring buffers, one per output stream.
+///
+/// Each block is first converted to the configuration of the output it's for. Each ring buffer is
+/// filled independently: when one output stalls and its buffer fills up, only that output loses
+/// samples, while the others keep receiving the full stream.
+pub struct FanOut<P> {
+    outputs: Vec<(P, Converter)>,
+}
+
+impl<P: Producer<Item = f32>> FanOut<P> {
+    pub fn new(outputs: Vec<(P, Converter)>) -> Self {
+        FanOut { outputs }
+    }
+
+    /// Pushes `data` into every ring buffer.
+    ///
+    /// `fell_behind` is called with the index of each output whose buffer couldn't take all of
+    /// `data`; the samples that didn't fit are dropped for that output only. Outputs whose stream
+    /// no longer exists are skipped.
+    pub fn push(&mut self, data: &[f32], mut fell_behind: impl FnMut(usize)) {
+        for (index, (producer, converter)) in self.outputs.iter_mut().enumerate() {
+            if !producer.read_is_held() {
+                continue;
+            }
+            let data = converter.process(data);
+            if producer.push_slice(data) < data.len() {
+                fell_behind(index);
+            }
+        }
+    }
+
+    /// Converts from `input` Hz to the rate of each output in `outputs` from now on.
+    pub fn set_rates(&mut self, input: u32, outputs: &[u32]) {
+        for ((_, converter), &output) in self.outputs.iter_mut().zip(outputs) {
+            converter.set_rates(input, output);
+        }
+    }
+
+    /// Tops every ring buffer up to `prefill[index]` samples of silence, e.g. once the input
+    /// moved to another device and the rings drained while it did.
+    pub fn reprime(&mut self, prefill: &[usize]) {
+        for ((producer, _), &prefill) in self.outputs.iter_mut().zip(prefill) {
+            let missing = prefill.saturating_sub(producer.occupied_len());
+            producer.push_iter(std::iter::repeat_n(0.0, missing));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cpal::{SampleRate, StreamConfig};
+    use ringbuf::traits::{Consumer, Observer, Split};
+    use ringbuf::{HeapCons, HeapProd, HeapRb};
+
+    fn config(channels: u16, sample_rate: u32) -> StreamConfig {
+        StreamConfig {
+            channels,
+            sample_rate: SampleRate(sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        }
+    }
+
+    fn ring(capacity: usize) -> (HeapProd<f32>, HeapCons<f32>) {
+        HeapRb::<f32>::new(capacity).split()
+    }
+
+    #[test]
+    fn a_stalled_output_only_loses_its_own_samples() {
+        let stereo = config(2, 48_000);
+        let ((first, mut first_out), (second, mut second_out)) = (ring(8), ring(64));
+        let converter = || Converter::new(&stereo, &stereo);
+        let mut fanout = FanOut::new(vec![(first, converter()), (second, converter())]);
+        let mut behind = Vec::new();
+        for block in 0..4 {
+            fanout.push(&[block as f32; 4], |x| behind.push((block, x)));
+        }
+        // The first ring filled up after two blocks, the second took everything.
+        assert_eq!(behind, [(2, 0), (3, 0)]);
+        assert_eq!(first_out.occupied_len(), 8);
+        let mut taken = vec![0.0; 16];
+        assert_eq!(second_out.pop_slice(&mut taken), 16);
+        assert_eq!(&taken[12..], [3.0; 4]);
+        assert_eq!(first_out.try_pop(), Some(0.0));
+    }
+
+    #[test]
+    fn each_output_gets_its_own_configuration() {
+        let ((stereo, stereo_out), (mono, mut mono_out)) = (ring(64), ring(64));
+        let input = config(2, 48_000);
+        let mut fanout = FanOut::new(vec![
+            (stereo, Converter::new(&input, &input)),
+            (mono, Converter::new(&input, &config(1, 48_000))),
+        ]);
+        fanout.push(&[1.0, 0.0, 0.5, 0.5], |_| panic!("no output fell behind"));
+        assert_eq!(stereo_out.occupied_len(), 4);
+        let mut taken = [0.0; 2];
+        assert_eq!(mono_out.pop_slice(&mut taken), 2);
+        assert_eq!(taken, [0.5, 0.5]);
+    }
+
+    #[test]
+    fn outputs_whose_stream_is_gone_are_skipped() {
+        let stereo = config(2, 48_000);
+        let ((gone, gone_out), (kept, kept_out)) = (ring(2), ring(64));
+        drop(gone_out);
+        let mut fanout = FanOut::new(vec![
+            (gone, Converter::new(&stereo, &stereo)),
+            (kept, Converter::new(&stereo, &stereo)),
+        ]);
+        fanout.push(&[0.25; 8], |x| panic!("output {} fell behind", x));
+        assert_eq!(kept_out.occupied_len(), 8);
+    }
+
+    #[test]
+    fn repriming_tops_the_rings_up_with_silence() {
+        let stereo = config(2, 48_000);
+        let ((first, first_out), (second, second_out)) = (ring(64), ring(64));
+        let mut fanout = FanOut::new(vec![
+            (first, Converter::new(&stereo, &stereo)),
+            (second, Converter::new(&stereo, &stereo)),
+        ]);
+        fanout.push(&[0.5; 10], |_| {});
+        fanout.reprime(&[16, 4]);
+        assert_eq!(first_out.occupied_len(), 16);
+        assert_eq!(second_out.occupied_len(), 10);
+    }
+}
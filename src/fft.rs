@@ -0,0 +1,146 @@
+//! Radix-2 fast Fourier transforms of short windows, cheap enough for the audio thread once set
+//! up.
+
+use std::f64::consts::TAU;
+
+/// Forward and inverse transforms of a fixed power-of-two size, with its twiddle factors precomputed.
+pub struct Fft {
+    size: usize,
+    /// `e^(-i·2π·k/size)` for k in [0, size / 2).
+    twiddles: Vec<(f32, f32)>,
+    /// Position of each sample after the bit-reversal permutation.
+    reversed: Vec<usize>,
+}
+
+impl Fft {
+    /// Sets up transforms of `size` samples, which must be a power of two.
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two(), "FFT size must be a power of two");
+        let twiddles = (0..size / 2)
+            .map(|k| {
+                let angle = -TAU * k as f64 / size as f64;
+                (angle.cos() as f32, angle.sin() as f32)
+            })
+            .collect();
+        let bits = size.trailing_zeros();
+        let reversed = (0..size)
+            .map(|i| match bits {
+                0 => 0,
+                _ => i.reverse_bits() >> (usize::BITS - bits),
+            })
+            .collect();
+        Fft {
+            size,
+            twiddles,
+            reversed,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Transforms the complex signal `re + i·im` in place, without allocating.
+    pub fn forward(&self, re: &mut [f32], im: &mut [f32]) {
+        assert!(re.len() == self.size && im.len() == self.size);
+        for (i, &j) in self.reversed.iter().enumerate() {
+            if i < j {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+        let mut half = 1;
+        while half < self.size {
+            let stride = self.size / (half * 2);
+            for start in (0..self.size).step_by(half * 2) {
+                for k in 0..half {
+                    let (wr, wi) = self.twiddles[k * stride];
+                    let (a, b) = (start + k, start + k + half);
+                    let tr = re[b] * wr - im[b] * wi;
+                    let ti = re[b] * wi + im[b] * wr;
+                    re[b] = re[a] - tr;
+                    im[b] = im[a] - ti;
+                    re[a] += tr;
+                    im[a] += ti;
+                }
+            }
+            half *= 2;
+        }
+    }
+
+    /// Inverse of [`forward`](Self::forward), including the 1 / size scaling.
+    pub fn inverse(&self, re: &mut [f32], im: &mut [f32]) {
+        // The inverse transform is the conjugate of the forward transform of the conjugate.
+        im.iter_mut().for_each(|x| *x = -*x);
+        self.forward(re, im);
+        let scale = 1.0 / self.size as f32;
+        re.iter_mut().for_each(|x| *x *= scale);
+        im.iter_mut().for_each(|x| *x *= -scale);
+    }
+}
+
+/// A periodic Hann window of `size` samples.
+pub fn hann(size: usize) -> Vec<f32> {
+    (0..size)
+        .map(|i| (0.5 - 0.5 * (TAU * i as f64 / size as f64).cos()) as f32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_sine_centred_on_a_bin_lands_in_it() {
+        let fft = Fft::new(64);
+        let mut re: Vec<f32> = (0..64)
+            .map(|i| (TAU * 5.0 * i as f64 / 64.0).cos() as f32)
+            .collect();
+        let mut im = vec![0.0; 64];
+        fft.forward(&mut re, &mut im);
+        for bin in 0..64 {
+            let magnitude = (re[bin] * re[bin] + im[bin] * im[bin]).sqrt();
+            let expected = match bin {
+                5 | 59 => 32.0,
+                _ => 0.0,
+            };
+            assert!(
+                (magnitude - expected).abs() < 1e-3,
+                "{}: {}",
+                bin,
+                magnitude
+            );
+        }
+    }
+
+    #[test]
+    fn the_inverse_undoes_the_forward_transform() {
+        for size in [1, 2, 8, 1_024] {
+            let fft = Fft::new(size);
+            let signal: Vec<f32> = (0..size).map(|i| ((i * 7919) % 13) as f32 - 6.0).collect();
+            let mut re = signal.clone();
+            let mut im: Vec<f32> = signal.iter().rev().copied().collect();
+            fft.forward(&mut re, &mut im);
+            fft.inverse(&mut re, &mut im);
+            for (i, x) in signal.iter().enumerate() {
+                assert!((re[i] - x).abs() < 1e-3, "{}: {}", size, re[i]);
+                assert!((im[i] - signal[size - 1 - i]).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "FFT size must be a power of two")]
+    fn sizes_must_be_powers_of_two() {
+        Fft::new(48);
+    }
+
+    #[test]
+    fn the_hann_window_is_periodic() {
+        let window = hann(8);
+        assert_eq!(window[0], 0.0);
+        assert!((window[4] - 1.0).abs() < 1e-6);
+        assert!((window[2] - 0.5).abs() < 1e-6 && (window[6] - 0.5).abs() < 1e-6);
+        assert!((window[1] - window[7]).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,485 @@
+//! FLAC file writing, for recordings a fraction of the size of WAV ones.
+//!
+//! Samples are quantized to 16 or 24-bit integers and handed to the `flacenc` encoder in blocks
+//! of [`BLOCK`] frames, each encoded as soon as it's full. The compression level picks the
+//! predictors it may try: fixed polynomial ones only at level 0, with stereo decorrelation from
+//! level 1, then LPC ones of increasing orders from level 3.
+//!
+//! The metadata is written here rather than by the encoder, so that it can go first. The
+//! STREAMINFO block is written with a frame count of zero, and patched when the writer is
+//! finished with the count and the MD5 signature of the samples. So is the SEEKTABLE block after
+//! it, reserved for [`SEEK_POINTS`] points, which the writer spreads evenly over however long the
+//! recording turns out to be.
+
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, StreamInfo};
+use flacenc::config;
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Context, Fill, FrameBuf};
+
+use crate::dither::{Dither, DitherMode};
+
+/// Frames per block, except for the last one.
+pub const BLOCK: usize = 4_096;
+/// Largest sample rate a FLAC frame header can refer to.
+pub const MAX_SAMPLE_RATE: u32 = 655_350;
+/// Highest compression level, which tries LPC predictors of up to order 12.
+pub const MAX_LEVEL: u32 = 8;
+/// Points of the seek table. The unused ones are placeholders.
+pub const SEEK_POINTS: usize = 128;
+/// Bytes of a seek point: its first sample, the offset of its frame, and the frame's length.
+const SEEK_POINT_BYTES: usize = 18;
+/// Offset of the metadata blocks, after the stream marker.
+const METADATA_OFFSET: u64 = 4;
+/// Offset of the first frame: the stream marker, then STREAMINFO and SEEKTABLE, each after a
+/// header.
+const FIRST_FRAME: u64 = METADATA_OFFSET + 4 + 34 + 4 + (SEEK_POINTS * SEEK_POINT_BYTES) as u64;
+
+/// Checks that a stream can be encoded, with a message saying why not otherwise.
+pub fn check(channels: u16, sample_rate: u32, bits: u32) -> Result<(), String> {
+    if !(1..=8).contains(&channels) {
+        return Err(format!(
+            "FLAC cannot encode {} channels, at most 8",
+            channels
+        ));
+    }
+    if sample_rate == 0 || sample_rate > MAX_SAMPLE_RATE {
+        return Err(format!(
+            "FLAC cannot encode a sample rate of {} Hz, at most {} Hz",
+            sample_rate, MAX_SAMPLE_RATE
+        ));
+    }
+    if bits != 16 && bits != 24 {
+        return Err(format!(
+            "FLAC recordings are 16 or 24-bit, not {}-bit",
+            bits
+        ));
+    }
+    Ok(())
+}
+
+/// An error of the encoder, which only fails on settings or samples out of its range.
+fn encoding_error(x: impl std::fmt::Display) -> io::Error {
+    io::Error::other(x.to_string())
+}
+
+/// The settings of the encoder at compression `level`.
+fn encoder(level: u32) -> config::Encoder {
+    let mut encoder = config::Encoder::default();
+    encoder.block_size = BLOCK;
+    let stereo = &mut encoder.stereo_coding;
+    (
+        stereo.use_leftside,
+        stereo.use_rightside,
+        stereo.use_midside,
+    ) = (level > 0, level > 0, level > 0);
+    encoder.subframe_coding.use_lpc = level >= 3;
+    if level >= 3 {
+        encoder.subframe_coding.qlpc.lpc_order = 2 * (level - 2) as usize;
+    }
+    encoder
+}
+
+/// Writes interleaved f32 samples to a FLAC file.
+pub struct FlacWriter {
+    file: BufWriter<File>,
+    channels: usize,
+    bits: u32,
+    encoder: Verified<config::Encoder>,
+    /// Quantized interleaved samples of the block being gathered.
+    pending: Vec<i32>,
+    block: FrameBuf,
+    /// Signature of the samples, and the frames encoded so far, not counting the pending ones.
+    context: Context,
+    /// Counts the frames and their sizes as they're encoded.
+    info: StreamInfo,
+    blocks: u32,
+    /// Bytes written so far.
+    size: u64,
+    /// The bytes of the last block encoded, reused from block to block.
+    bitstream: ByteSink,
+    /// Dither of 16-bit samples.
+    dither: Option<Dither>,
+    /// Seek points of one block every `seek_stride`, at most twice [`SEEK_POINTS`]: the stride
+    /// doubles, dropping every other point, when they'd be more.
+    seek_points: Vec<SeekPoint>,
+    seek_stride: u32,
+}
+
+/// A block the seek table points at.
+#[derive(Clone, Copy)]
+struct SeekPoint {
+    /// The first frame of the block.
+    frame: u64,
+    /// Offset of the block from the first one, in bytes.
+    offset: u64,
+    frames: u16,
+}
+
+impl SeekPoint {
+    /// An unused point.
+    const PLACEHOLDER: SeekPoint = SeekPoint {
+        frame: u64::MAX,
+        offset: 0,
+        frames: 0,
+    };
+}
+
+impl FlacWriter {
+    /// Creates the file at `path`, replacing any existing one, for samples of `bits` bits
+    /// encoded at compression `level`, from 0 to [`MAX_LEVEL`]. 16-bit samples are quantized
+    /// with `dither`, 24-bit ones are rounded.
+    pub fn create(
+        path: &Path,
+        channels: u16,
+        sample_rate: u32,
+        bits: u32,
+        level: u32,
+        dither: DitherMode,
+    ) -> io::Result<Self> {
+        check(channels, sample_rate, bits)
+            .map_err(|x| io::Error::new(ErrorKind::InvalidInput, x))?;
+        let mut writer = FlacWriter {
+            file: BufWriter::new(File::create(path)?),
+            channels: channels as usize,
+            bits,
+            encoder: encoder(level.min(MAX_LEVEL))
+                .into_verified()
+                .map_err(|(_, x)| encoding_error(x))?,
+            pending: Vec::with_capacity(BLOCK * channels as usize),
+            block: FrameBuf::with_size(channels as usize, BLOCK).map_err(encoding_error)?,
+            context: Context::new(bits as usize, channels as usize),
+            info: StreamInfo::new(sample_rate as usize, channels as usize, bits as usize)
+                .map_err(encoding_error)?,
+            blocks: 0,
+            size: 0,
+            bitstream: ByteSink::new(),
+            dither: (bits == 16).then(|| Dither::new(dither, channels as usize)),
+            seek_points: Vec::with_capacity(2 * SEEK_POINTS),
+            seek_stride: 1,
+        };
+        writer.file.write_all(b"fLaC")?;
+        writer.file.write_all(&writer.metadata()?)?;
+        writer.size = FIRST_FRAME;
+        Ok(writer)
+    }
+
+    /// The STREAMINFO block, then the SEEKTABLE block, the last one, both with their headers.
+    fn metadata(&self) -> io::Result<Vec<u8>> {
+        let mut metadata = vec![0, 0, 0, 34];
+        metadata.extend(self.streaminfo()?);
+        metadata.push(0x80 | 3);
+        metadata.extend(&((SEEK_POINTS * SEEK_POINT_BYTES) as u32).to_be_bytes()[1..]);
+        // Evenly spread over the points kept, of which there are less than twice as many.
+        let kept = self.seek_points.len();
+        for index in 0..SEEK_POINTS {
+            let point = match kept <= SEEK_POINTS {
+                true => self.seek_points.get(index),
+                false => Some(&self.seek_points[index * kept / SEEK_POINTS]),
+            };
+            let point = point.unwrap_or(&SeekPoint::PLACEHOLDER);
+            metadata.extend(point.frame.to_be_bytes());
+            metadata.extend(point.offset.to_be_bytes());
+            metadata.extend(point.frames.to_be_bytes());
+        }
+        Ok(metadata)
+    }
+
+    fn streaminfo(&self) -> io::Result<Vec<u8>> {
+        let mut info = self.info.clone();
+        // Fixed-size blocks, of which the last may be shorter.
+        info.set_block_sizes(BLOCK, BLOCK).map_err(encoding_error)?;
+        if self.blocks == 0 {
+            info.set_frame_sizes(0, 0).map_err(encoding_error)?;
+        }
+        info.set_md5_digest(&self.context.md5_digest());
+        let mut bytes = ByteSink::new();
+        info.write(&mut bytes).map_err(encoding_error)?;
+        Ok(bytes.into_inner())
+    }
+
+    /// Appends interleaved samples, which should be whole frames.
+    pub fn write(&mut self, data: &[f32]) -> io::Result<()> {
+        let scale = (1i64 << (self.bits - 1)) as f32;
+        let max = (1i32 << (self.bits - 1)) - 1;
+        for frame in data.chunks_exact(self.channels) {
+            for (channel, x) in frame.iter().enumerate() {
+                let sample = match &mut self.dither {
+                    Some(dither) => dither.quantize(*x, channel) as i32,
+                    None => ((x * scale).round() as i32).clamp(-max - 1, max),
+                };
+                self.pending.push(sample);
+            }
+            if self.pending.len() == BLOCK * self.channels {
+                self.encode_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of frames written so far.
+    pub fn frames(&self) -> u64 {
+        (self.context.total_samples() + self.pending.len() / self.channels) as u64
+    }
+
+    /// Size of the file so far, in bytes, not counting the block being gathered.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Encodes the last block, patches the metadata and flushes the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            self.encode_block()?;
+        }
+        self.file.seek(SeekFrom::Start(METADATA_OFFSET))?;
+        self.file.write_all(&self.metadata()?)?;
+        self.file.flush()
+    }
+
+    fn encode_block(&mut self) -> io::Result<()> {
+        let encoded = self.context.total_samples() as u64;
+        let frames = self.pending.len() / self.channels;
+        (&mut self.block, &mut self.context)
+            .fill_interleaved(&self.pending)
+            .map_err(encoding_error)?;
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.encoder,
+            &self.block,
+            self.blocks as usize,
+            &self.info,
+        )
+        .map_err(encoding_error)?;
+        self.bitstream.clear();
+        frame.write(&mut self.bitstream).map_err(encoding_error)?;
+        self.info.update_frame_info(&frame);
+
+        if self.blocks.is_multiple_of(self.seek_stride) {
+            if self.seek_points.len() == 2 * SEEK_POINTS {
+                let mut index = 0;
+                self.seek_points.retain(|_| {
+                    index += 1;
+                    index % 2 == 1
+                });
+                self.seek_stride *= 2;
+            }
+            if self.blocks.is_multiple_of(self.seek_stride) {
+                self.seek_points.push(SeekPoint {
+                    frame: encoded,
+                    offset: self.size - FIRST_FRAME,
+                    frames: frames as u16,
+                });
+            }
+        }
+        self.file.write_all(self.bitstream.as_slice())?;
+        self.size += self.bitstream.as_slice().len() as u64;
+        self.blocks += 1;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use md5::{Digest, Md5};
+
+    use super::*;
+
+    /// A stream as the reference decoder reads it.
+    struct Decoded {
+        info: claxon::metadata::StreamInfo,
+        /// Interleaved samples.
+        samples: Vec<i64>,
+    }
+
+    fn decode(bytes: &[u8]) -> Decoded {
+        let mut reader = claxon::FlacReader::new(Cursor::new(bytes)).unwrap();
+        Decoded {
+            info: reader.streaminfo(),
+            samples: reader.samples().map(|x| x.unwrap() as i64).collect(),
+        }
+    }
+
+    /// The first frame, byte offset from the first block and frames of every seek point.
+    fn seek_points(bytes: &[u8]) -> Vec<(u64, u64, u16)> {
+        let table = (METADATA_OFFSET + 4 + 34) as usize;
+        assert_eq!(bytes[table], 0x80 | 3);
+        bytes[table + 4..FIRST_FRAME as usize]
+            .chunks_exact(SEEK_POINT_BYTES)
+            .map(|x| {
+                let frame = u64::from_be_bytes(x[..8].try_into().unwrap());
+                let offset = u64::from_be_bytes(x[8..16].try_into().unwrap());
+                (frame, offset, u16::from_be_bytes([x[16], x[17]]))
+            })
+            .collect()
+    }
+
+    /// The interleaved samples of the block at `offset` from the first one, decoded by seeking
+    /// there.
+    fn block_at(bytes: &[u8], offset: u64) -> Vec<i64> {
+        let mut seeked = bytes[..FIRST_FRAME as usize].to_vec();
+        seeked.extend(&bytes[(FIRST_FRAME + offset) as usize..]);
+        let mut reader = claxon::FlacReader::new(Cursor::new(seeked)).unwrap();
+        let block = reader
+            .blocks()
+            .read_next_or_eof(Vec::new())
+            .unwrap()
+            .unwrap();
+        (0..block.duration())
+            .flat_map(|n| (0..block.channels()).map(move |channel| (channel, n)))
+            .map(|(channel, n)| block.sample(channel, n) as i64)
+            .collect()
+    }
+
+    /// Writes `samples` of `bits` bits through a [`FlacWriter`] and returns the file.
+    fn encode(name: &str, samples: &[i64], channels: u16, bits: u32, level: u32) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("flac-{}-{}.flac", name, std::process::id()));
+        let mut writer =
+            FlacWriter::create(&path, channels, 48_000, bits, level, DitherMode::Off).unwrap();
+        let scale = (1i64 << (bits - 1)) as f32;
+        let data: Vec<f32> = samples.iter().map(|x| *x as f32 / scale).collect();
+        // In blocks which don't fall on those of the encoder.
+        for block in data.chunks(1_000 * channels as usize) {
+            writer.write(block).unwrap();
+        }
+        assert_eq!(writer.frames() as usize, samples.len() / channels as usize);
+        writer.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    /// Uniform noise of `bits` bits, from the xorshift generator seeded with `state`.
+    fn noise(len: usize, bits: u32, mut state: u32) -> Vec<i64> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state as i32 >> (32 - bits)) as i64
+            })
+            .collect()
+    }
+
+    /// Stereo sines, the right a little later than the left.
+    fn tone(frames: usize, bits: u32) -> Vec<i64> {
+        let scale = (1i64 << (bits - 2)) as f64;
+        (0..frames)
+            .flat_map(|n| {
+                let phase = n as f64 * 0.03;
+                [phase.sin() * scale, (phase - 0.05).sin() * scale].map(|x| x.round() as i64)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_tone_round_trips_a_fraction_of_its_size() {
+        for bits in [16, 24] {
+            let samples = tone(3 * BLOCK + 100, bits);
+            let bytes = encode("tone", &samples, 2, bits, 5);
+            assert_eq!(decode(&bytes).samples, samples);
+            assert!(bytes.len() < samples.len() * bits as usize / 8 / 2);
+        }
+    }
+
+    #[test]
+    fn full_scale_noise_round_trips_little_larger() {
+        let samples = noise(2 * BLOCK * 2, 16, 0x2545_f491);
+        let bytes = encode("noise", &samples, 2, 16, 5);
+        assert_eq!(decode(&bytes).samples, samples);
+        // Stored verbatim, after the headers of the metadata, the blocks and their channels.
+        assert!(bytes.len() < FIRST_FRAME as usize + samples.len() * 2 + 2 * 32);
+    }
+
+    #[test]
+    fn every_level_round_trips() {
+        // Quiet noise, then silence, then a tone, on three channels.
+        let mut samples = noise(BLOCK * 3, 8, 1);
+        samples.extend(vec![0; BLOCK * 3]);
+        samples.extend(tone(BLOCK, 24).chunks(2).flat_map(|x| [x[0], x[1], -x[0]]));
+        samples.extend(noise(77 * 3, 24, 7));
+        for level in 0..=MAX_LEVEL {
+            let decoded = decode(&encode("every", &samples, 3, 24, level));
+            assert_eq!(decoded.samples, samples, "at level {}", level);
+        }
+    }
+
+    #[test]
+    fn higher_levels_compress_more() {
+        // A chord, which the fixed predictors follow less closely than a single sine.
+        let samples: Vec<i64> = tone(4 * BLOCK, 16)
+            .iter()
+            .enumerate()
+            .map(|(n, x)| x + (((n / 2) as f64 * 0.41).sin() * 4_000.0) as i64)
+            .collect();
+        let sizes = [0, 3, MAX_LEVEL].map(|level| encode("levels", &samples, 2, 16, level).len());
+        assert!(sizes.windows(2).all(|x| x[0] > x[1]), "{:?}", sizes);
+    }
+
+    #[test]
+    fn streaminfo_counts_the_frames_and_signs_the_samples() {
+        let samples = tone(BLOCK + 1, 24);
+        let bytes = encode("streaminfo", &samples, 2, 24, 5);
+        let info = decode(&bytes).info;
+        assert_eq!(
+            (info.channels, info.bits_per_sample, info.sample_rate),
+            (2, 24, 48_000)
+        );
+        assert_eq!(
+            (info.min_block_size, info.max_block_size),
+            (BLOCK as u16, BLOCK as u16)
+        );
+        assert_eq!(info.samples, Some(BLOCK as u64 + 1));
+        let signed: Vec<u8> = samples
+            .iter()
+            .flat_map(|x| x.to_le_bytes()[..3].to_vec())
+            .collect();
+        assert_eq!(info.md5sum, <[u8; 16]>::from(Md5::digest(&signed)));
+    }
+
+    #[test]
+    fn the_seek_table_points_at_blocks() {
+        let samples = tone(5 * BLOCK + 10, 16);
+        let bytes = encode("seek", &samples, 2, 16, 5);
+        let points = seek_points(&bytes);
+        assert_eq!(points.len(), SEEK_POINTS);
+        let (points, placeholders) = points.split_at(6);
+        for (index, (frame, offset, frames)) in points.iter().enumerate() {
+            assert_eq!(*frame, (index * BLOCK) as u64);
+            let start = 2 * *frame as usize;
+            let block = &samples[start..(start + 2 * BLOCK).min(samples.len())];
+            assert_eq!(*frames as usize, block.len() / 2);
+            assert_eq!(block_at(&bytes, *offset), block);
+        }
+        assert!(placeholders.iter().all(|x| x.0 == u64::MAX));
+    }
+
+    #[test]
+    fn the_seek_table_spreads_over_a_long_recording() {
+        let blocks = 3 * SEEK_POINTS + 5;
+        let samples = noise(blocks * BLOCK, 4, 3);
+        let bytes = encode("long", &samples, 1, 16, 0);
+        let points = seek_points(&bytes);
+        assert_eq!(points.len(), SEEK_POINTS);
+        for (frame, offset, frames) in &points {
+            assert!(frame.is_multiple_of(BLOCK as u64));
+            assert_eq!(*frames as usize, BLOCK);
+            let start = *frame as usize;
+            assert_eq!(block_at(&bytes, *offset), samples[start..start + BLOCK]);
+        }
+        assert!(points.windows(2).all(|x| x[0].0 < x[1].0));
+        // No more than a few blocks between two points, even at the end.
+        let last = ((blocks - 1) * BLOCK) as u64;
+        let gaps = points
+            .windows(2)
+            .map(|x| x[1].0 - x[0].0)
+            .chain([last - points.last().unwrap().0]);
+        assert!(gaps.into_iter().all(|x| x <= 4 * BLOCK as u64));
+    }
+}
@@ -0,0 +1,565 @@
+//! Offline regression checks of the effects against golden files.
+//!
+//! Every effect is built at fixed settings and run, block by block without any audio device,
+//! over a set of standard stimuli. Each output is compared with a WAV file kept in the
+//! repository, so that a refactor that changes how an effect sounds shows up. Setting
+//! [`BLESS_VARIABLE`] writes the outputs as the new golden files instead, for changes that are
+//! meant.
+//!
+//! Everything is deterministic: the noise stimulus comes from a fixed seed, and so do the random
+//! parts of the effects.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+
+use crate::audition::{Audition, Band, BandSolo};
+use crate::compressor::{Compressor, CompressorSettings};
+use crate::effects::{
+    AdaptiveNotch, Agc, AgcSpec, BandSpec, BassManager, Biquad, CabIrs, CabSim, CabSource,
+    ChannelDelay, ChannelDelaySpec, Crossover, CrossoverSpec, Dynamics, Effect, EffectChain,
+    EqBand, FeedbackSuppressor, FilterKind, Fir, Gain, Gate, GateSpec, LearnTrigger, Limiter,
+    LimiterSpec, NoiseReducer, NotchWindow, ParametricEq, RumbleFilter, RumbleSpec, ScriptEffect,
+    Upmix, BUTTERWORTH_Q,
+};
+use crate::strip::{ChannelStrip, StripPreset};
+use crate::wav::{self, WavWriter};
+
+/// Environment variable which, set to 1, makes [`check`] rewrite the golden files.
+pub const BLESS_VARIABLE: &str = "DSP_BLESS";
+
+pub const SAMPLE_RATE: u32 = 48_000;
+const RATE: f32 = SAMPLE_RATE as f32;
+pub const CHANNELS: usize = 2;
+/// Length of the stimuli.
+pub const FRAMES: usize = 4_096;
+/// Frames per block the effects are run in, as from an output callback.
+const BLOCK: usize = 256;
+/// Largest difference from a golden sample that still passes, about -100 dBFS, far above the
+/// rounding differences between builds.
+const TOLERANCE: f32 = 1e-5;
+
+/// The script of the script case: a one-pole low-pass in both channels, with an echo in the
+/// first, scaled by a parameter.
+const SCRIPT: &str = r#"
+fn init() { #{ z: [0.0, 0.0], echo: delay(480) } }
+fn params() { #{ gain: 0.8 } }
+fn process(block, channels, sample_rate) {
+    for i in 0..block.len() {
+        let c = i % channels;
+        this.z[c] += 0.2 * (block[i] - this.z[c]);
+        let y = this.z[c];
+        if c == 0 {
+            y += 0.5 * this.echo.process(y);
+        }
+        block[i] = this.gain * y;
+    }
+    block
+}
+"#;
+
+/// The signals the effects are run over, in stereo with the same signal in both channels but for
+/// the noise.
+#[derive(Clone, Copy, Debug)]
+pub enum Stimulus {
+    /// A full-scale sample in the first frame.
+    Impulse,
+    /// A logarithmic sweep from 20 Hz to 20 kHz at -6 dBFS.
+    Sweep,
+    /// Uniform white noise at -12 dBFS peak, independent in each channel.
+    Noise,
+    Silence,
+}
+
+impl Stimulus {
+    pub const ALL: [Stimulus; 4] = [
+        Stimulus::Impulse,
+        Stimulus::Sweep,
+        Stimulus::Noise,
+        Stimulus::Silence,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stimulus::Impulse => "impulse",
+            Stimulus::Sweep => "sweep",
+            Stimulus::Noise => "noise",
+            Stimulus::Silence => "silence",
+        }
+    }
+
+    /// The interleaved samples of the stimulus.
+    pub fn render(self) -> Vec<f32> {
+        let mut samples = vec![0.0; FRAMES * CHANNELS];
+        match self {
+            Stimulus::Impulse => samples[..CHANNELS].fill(1.0),
+            Stimulus::Sweep => {
+                let (low, high) = (20.0f64, 20_000.0f64);
+                let duration = FRAMES as f64 / SAMPLE_RATE as f64;
+                let rate = (high / low).ln() / duration;
+                for (n, frame) in samples.chunks_exact_mut(CHANNELS).enumerate() {
+                    let t = n as f64 / SAMPLE_RATE as f64;
+                    let phase = std::f64::consts::TAU * low * ((rate * t).exp() - 1.0) / rate;
+                    frame.fill((0.5 * phase.sin()) as f32);
+                }
+            }
+            Stimulus::Noise => {
+                let mut state = 0x2545_f491u32;
+                for x in &mut samples {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    *x = ((state >> 8) as f32 / (1 << 24) as f32 - 0.5) * 0.5;
+                }
+            }
+            Stimulus::Silence => {}
+        }
+        samples
+    }
+}
+
+/// Processes interleaved blocks in place.
+pub type Processor = Box<dyn FnMut(&mut [f32]) + Send>;
+
+/// An effect at fixed settings.
+pub struct Case {
+    pub name: &'static str,
+    pub build: fn() -> anyhow::Result<Processor>,
+}
+
+/// Runs a chain of the single `effect`.
+fn chain(effect: impl Effect + 'static) -> anyhow::Result<Processor> {
+    let mut chain = EffectChain::new(CHANNELS);
+    chain.push(effect);
+    Ok(Box::new(move |data| chain.process(data)))
+}
+
+/// Runs the channel strip of `preset`.
+fn strip(preset: StripPreset) -> anyhow::Result<Processor> {
+    strip_of(preset.strip(), None)
+}
+
+/// Runs `strip`, with a gain of `gain_db` if given between its stages and its limiter, where the
+/// chain sets the level.
+fn strip_of(strip: ChannelStrip, gain_db: Option<f32>) -> anyhow::Result<Processor> {
+    let mut chain: EffectChain = EffectChain::new(CHANNELS);
+    strip.build(&mut chain, RATE, CHANNELS, None)?;
+    if let Some(db) = gain_db {
+        chain.push(Gain::new(db));
+    }
+    strip.build_limiter(&mut chain, RATE);
+    Ok(Box::new(move |data| chain.process(data)))
+}
+
+/// Runs an upmix of the stereo to quad, with the rears as the output: the fronts pass as they
+/// come, and are the stimulus.
+fn upmix() -> anyhow::Result<Processor> {
+    const QUAD: usize = 4;
+    let mut chain: EffectChain = EffectChain::new(QUAD);
+    chain.push(Upmix::new([2, 3], -3.0, RATE));
+    chain.prepare(BLOCK);
+    let mut quad = vec![0.0; BLOCK * QUAD];
+    Ok(Box::new(move |data| {
+        let frames = data.len() / CHANNELS;
+        quad.resize(frames * QUAD, 0.0);
+        for (quad, stereo) in quad.chunks_exact_mut(QUAD).zip(data.chunks_exact(CHANNELS)) {
+            quad.fill(0.0);
+            quad[..CHANNELS].copy_from_slice(stereo);
+        }
+        chain.process(&mut quad);
+        for (stereo, quad) in data.chunks_exact_mut(CHANNELS).zip(quad.chunks_exact(QUAD)) {
+            stereo.copy_from_slice(&quad[2..]);
+        }
+    }))
+}
+
+/// Runs a band solo around a chain that passes the block as it is, soloing from the start and
+/// going back halfway through, so both crossfades are heard.
+fn band_solo() -> anyhow::Result<Processor> {
+    let shared = Arc::new(BandSolo::default());
+    shared.solo(Band {
+        center: 450.0,
+        q: 1.0,
+    });
+    let limiter = LimiterSpec {
+        ceiling_db: -6.0,
+        release_ms: 50.0,
+    };
+    let mut audition = Audition::new(shared.clone(), CHANNELS, SAMPLE_RATE, Some(limiter));
+    audition.prepare(BLOCK);
+    let mut frames = 0;
+    Ok(Box::new(move |data| {
+        if frames >= FRAMES / 2 {
+            shared.off();
+        }
+        frames += data.len() / CHANNELS;
+        audition.process(data, &mut |_: &mut [f32]| {});
+    }))
+}
+
+/// Every effect, at settings within its usual range.
+pub fn cases() -> Vec<Case> {
+    vec![
+        Case {
+            name: "gain",
+            build: || chain(Gain::new(-6.0)),
+        },
+        Case {
+            name: "lowpass",
+            build: || {
+                let filter =
+                    Biquad::new(FilterKind::LowPass, 1_000.0, BUTTERWORTH_Q, RATE, CHANNELS);
+                chain(filter)
+            },
+        },
+        Case {
+            name: "highpass",
+            build: || {
+                let filter =
+                    Biquad::new(FilterKind::HighPass, 200.0, BUTTERWORTH_Q, RATE, CHANNELS);
+                chain(filter)
+            },
+        },
+        Case {
+            name: "notch",
+            build: || chain(Biquad::new(FilterKind::Notch, 1_000.0, 4.0, RATE, CHANNELS)),
+        },
+        Case {
+            name: "allpass",
+            build: || {
+                chain(Biquad::new(
+                    FilterKind::AllPass,
+                    1_000.0,
+                    0.7,
+                    RATE,
+                    CHANNELS,
+                ))
+            },
+        },
+        Case {
+            name: "fir-direct",
+            build: || {
+                let taps: Vec<f32> = (0..32).map(|n| 0.8f32.powi(n) * 0.2).collect();
+                chain(Fir::new(&[taps.clone(), taps], CHANNELS))
+            },
+        },
+        Case {
+            name: "fir-partitioned",
+            build: || {
+                let taps: Vec<f32> = (0..2_000).map(|n| 0.999f32.powi(n) * 0.01).collect();
+                chain(Fir::new(&[taps.clone(), taps], CHANNELS))
+            },
+        },
+        Case {
+            name: "cab-close",
+            build: || {
+                let irs = CabIrs::load(&CabSource::BuiltIn("v30"))?;
+                chain(CabSim::new(irs, 0.0, RATE, CHANNELS)?)
+            },
+        },
+        Case {
+            name: "cab-blend",
+            build: || {
+                let irs = CabIrs::load(&CabSource::BuiltIn("v30"))?;
+                chain(CabSim::new(irs, 0.5, RATE, CHANNELS)?)
+            },
+        },
+        Case {
+            name: "crossover",
+            build: || {
+                let spec: CrossoverSpec = "2way:2000".parse().map_err(anyhow::Error::msg)?;
+                let bands: Vec<BandSpec> = ["low:channels=0", "high:channels=1"]
+                    .iter()
+                    .map(|x| x.parse())
+                    .collect::<Result<_, String>>()
+                    .map_err(anyhow::Error::msg)?;
+                let routes = spec.routes(&bands)?;
+                chain(Crossover::new(&spec, &routes, RATE, CHANNELS)?)
+            },
+        },
+        Case {
+            name: "bass-manager",
+            build: || chain(BassManager::new(1, 80.0, 0.5, true, RATE, CHANNELS)?),
+        },
+        Case {
+            name: "channel-delay",
+            build: || {
+                let delay = ChannelDelaySpec {
+                    channel: 1,
+                    milliseconds: 1.23,
+                };
+                chain(ChannelDelay::new(&[delay], 5.0, RATE, CHANNELS)?)
+            },
+        },
+        Case {
+            name: "feedback-suppressor",
+            build: || chain(FeedbackSuppressor::new(RATE, CHANNELS)),
+        },
+        Case {
+            name: "adaptive-notch",
+            build: || {
+                let window = NotchWindow {
+                    low: 500.0,
+                    high: 2_000.0,
+                };
+                chain(AdaptiveNotch::new(window, RATE, CHANNELS))
+            },
+        },
+        Case {
+            name: "noise-reducer",
+            build: || {
+                // Learns from the start of the stimulus.
+                let trigger = LearnTrigger::default();
+                trigger.request();
+                chain(NoiseReducer::new(12.0, 0.02, trigger, RATE, CHANNELS))
+            },
+        },
+        Case {
+            name: "compressor",
+            build: || {
+                let settings = CompressorSettings {
+                    threshold_db: -20.0,
+                    ratio: 4.0,
+                    attack_ms: 5.0,
+                    release_ms: 100.0,
+                };
+                let mut compressor = Compressor::new(settings, CHANNELS, SAMPLE_RATE);
+                Ok(Box::new(move |data| compressor.process(data, None)))
+            },
+        },
+        Case {
+            name: "agc",
+            build: || {
+                let spec = AgcSpec {
+                    target_db: -12.0,
+                    max_gain_db: 12.0,
+                    rate_db: 60.0,
+                    gate_db: -60.0,
+                };
+                chain(Agc::new(spec, RATE, None))
+            },
+        },
+        Case {
+            name: "gate",
+            build: || chain(Gate::new(GateSpec::default(), RATE)),
+        },
+        Case {
+            name: "limiter",
+            build: || {
+                let spec = LimiterSpec {
+                    ceiling_db: -6.0,
+                    release_ms: 50.0,
+                };
+                chain(Limiter::new(spec, RATE))
+            },
+        },
+        Case {
+            name: "rumble-filter",
+            build: || {
+                let spec = RumbleSpec {
+                    frequency: 60.0,
+                    slope: 48,
+                };
+                chain(RumbleFilter::new(spec, RATE, CHANNELS)?)
+            },
+        },
+        Case {
+            name: "eq-dynamic",
+            build: || {
+                let dynamics = Dynamics {
+                    threshold_db: -40.0,
+                    ratio: 4.0,
+                    attack_ms: 1.0,
+                    release_ms: 50.0,
+                };
+                let bands = [
+                    EqBand::peak(250.0, 3.0, 0.7),
+                    EqBand::dynamic(3_000.0, -9.0, 1.4, dynamics),
+                ];
+                chain(ParametricEq::new(&bands, RATE, CHANNELS, None)?)
+            },
+        },
+        Case {
+            name: "upmix",
+            build: upmix,
+        },
+        Case {
+            name: "script",
+            build: || {
+                let script = ScriptEffect::compile(SCRIPT, "the golden script", CHANNELS, RATE)?;
+                chain(script.offline())
+            },
+        },
+        Case {
+            name: "channel-strip-vocal",
+            build: || strip(StripPreset::Vocal),
+        },
+        Case {
+            name: "channel-strip-podcast",
+            build: || strip(StripPreset::Podcast),
+        },
+        Case {
+            name: "channel-strip-guitar",
+            build: || strip(StripPreset::Guitar),
+        },
+        Case {
+            name: "channel-strip-vocal-flags",
+            build: || {
+                let flags = ChannelStrip {
+                    gate: Some(GateSpec {
+                        threshold_db: -30.0,
+                        ..GateSpec::default()
+                    }),
+                    eq: vec![EqBand::peak(1_000.0, 6.0, 2.0)],
+                    limiter: Some(LimiterSpec {
+                        ceiling_db: -12.0,
+                        release_ms: 20.0,
+                    }),
+                    ..ChannelStrip::default()
+                };
+                strip_of(StripPreset::Vocal.strip().with(flags), None)
+            },
+        },
+        Case {
+            name: "channel-strip-vocal-gain",
+            build: || strip_of(StripPreset::Vocal.strip(), Some(18.0)),
+        },
+        Case {
+            name: "band-solo",
+            build: band_solo,
+        },
+    ]
+}
+
+/// Runs `processor` over `stimulus` in blocks.
+pub fn run(processor: &mut Processor, stimulus: Stimulus) -> Vec<f32> {
+    let mut samples = stimulus.render();
+    for block in samples.chunks_mut(BLOCK * CHANNELS) {
+        processor(block);
+    }
+    samples
+}
+
+/// The golden file of a case and stimulus in `directory`.
+pub fn golden_path(directory: &Path, case: &str, stimulus: Stimulus) -> PathBuf {
+    directory.join(format!("{}-{}.wav", case, stimulus.name()))
+}
+
+/// How an output differs from its golden file.
+pub enum Mismatch {
+    Missing,
+    /// The golden file has another length or number of channels.
+    Shape,
+    /// The largest difference of a sample, and the frame it's at.
+    Samples {
+        difference: f32,
+        frame: usize,
+    },
+}
+
+/// Compares `output` with the golden samples.
+pub fn compare(output: &[f32], golden: &wav::WavData) -> Option<Mismatch> {
+    if golden.channels as usize != CHANNELS || golden.samples.len() != output.len() {
+        return Some(Mismatch::Shape);
+    }
+    let (index, difference) = output
+        .iter()
+        .zip(&golden.samples)
+        .map(|(x, y)| (x - y).abs())
+        .enumerate()
+        .fold((0, 0.0), |best, x| if x.1 > best.1 { x } else { best });
+    (difference > TOLERANCE).then_some(Mismatch::Samples {
+        difference,
+        frame: index / CHANNELS,
+    })
+}
+
+/// Runs every case over every stimulus and compares the outputs with the golden files in
+/// `directory`, or writes them there if `bless`. Returns the failures, as messages.
+pub fn check(directory: &Path, bless: bool) -> anyhow::Result<Vec<String>> {
+    if bless {
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("failed to create \"{}\"", directory.display()))?;
+    }
+    let mut failures = Vec::new();
+    for case in cases() {
+        for stimulus in Stimulus::ALL {
+            let mut processor = (case.build)()
+                .with_context(|| format!("failed to build the {} case", case.name))?;
+            let output = run(&mut processor, stimulus);
+            let path = golden_path(directory, case.name, stimulus);
+            if output.iter().any(|x| !x.is_finite()) {
+                failures.push(format!("{}: the output isn't finite", path.display()));
+                continue;
+            }
+            if bless {
+                let mut writer = WavWriter::create(&path, CHANNELS as u16, SAMPLE_RATE)?;
+                writer.write(&output)?;
+                writer.finish()?;
+                continue;
+            }
+            let mismatch = match wav::read(&path) {
+                Ok(golden) => compare(&output, &golden),
+                Err(_) => Some(Mismatch::Missing),
+            };
+            match mismatch {
+                None => {}
+                Some(Mismatch::Missing) => {
+                    failures.push(format!("{}: no golden file", path.display()))
+                }
+                Some(Mismatch::Shape) => failures.push(format!(
+                    "{}: the golden file has another length or channel count",
+                    path.display()
+                )),
+                Some(Mismatch::Samples { difference, frame }) => failures.push(format!(
+                    "{}: differs by up to {:.2e} at frame {}",
+                    path.display(),
+                    difference,
+                    frame
+                )),
+            }
+        }
+    }
+    Ok(failures)
+}
+
+/// Whether [`BLESS_VARIABLE`] asks for the golden files to be rewritten.
+pub fn bless_requested() -> bool {
+    std::env::var(BLESS_VARIABLE).is_ok_and(|x| x == "1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The golden files of the repository, blessed again with [`BLESS_VARIABLE`] set.
+    #[test]
+    fn every_output_matches_its_golden_file() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("goldens");
+        let failures = check(&directory, bless_requested()).unwrap();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn a_changed_output_is_a_mismatch() {
+        let mut processor = (cases()[0].build)().unwrap();
+        let output = run(&mut processor, Stimulus::Noise);
+        let golden = |samples: Vec<f32>| wav::WavData {
+            channels: CHANNELS as u16,
+            sample_rate: SAMPLE_RATE,
+            samples,
+        };
+        assert!(compare(&output, &golden(output.clone())).is_none());
+        let mut changed = output.clone();
+        changed[10] += 1e-3;
+        assert!(matches!(
+            compare(&output, &golden(changed)),
+            Some(Mismatch::Samples { frame: 5, .. })
+        ));
+        assert!(matches!(
+            compare(&output, &golden(output[2..].to_vec())),
+            Some(Mismatch::Shape)
+        ));
+    }
+}
@@ -0,0 +1,164 @@
+//! Goniometer of the first two channels of the first input: their pairs of samples as points,
+//! for a vectorscope drawn by a client of the control socket.
+//!
+//! Rotated to mid and side, mid points up and side across: a centred mono signal is a vertical
+//! line, one panned hard left or right a diagonal leaning that way, and channels in opposite
+//! phase a horizontal line. Unrotated, the left channel goes across and the right one up.
+//!
+//! Each frame of pairs is decimated to [`POINTS`] by keeping, of each run of consecutive pairs,
+//! the one farthest from the centre, so that transients stay visible where striding would skip
+//! them.
+
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::sync::{Arc, Mutex};
+
+/// Length of a frame, in seconds.
+pub const FRAME_SECONDS: f64 = 0.05;
+/// Points kept of each frame.
+pub const POINTS: usize = 256;
+
+/// Keeps the pair farthest from the centre of each of `count` runs of about the same length,
+/// in order, or all of them if there are fewer.
+pub fn select_points(pairs: &[[f32; 2]], count: usize, points: &mut Vec<[f32; 2]>) {
+    points.clear();
+    if pairs.len() <= count {
+        points.extend_from_slice(pairs);
+        return;
+    }
+    let radius = |x: &[f32; 2]| x[0] * x[0] + x[1] * x[1];
+    for run in 0..count {
+        let (from, to) = (run * pairs.len() / count, (run + 1) * pairs.len() / count);
+        let farthest = pairs[from..to]
+            .iter()
+            .max_by(|x, y| radius(x).total_cmp(&radius(y)));
+        points.extend(farthest);
+    }
+}
+
+/// A pair of left and right samples as side across and mid up, with the left channel alone
+/// leaning left.
+pub fn to_mid_side([left, right]: [f32; 2]) -> [f32; 2] {
+    [
+        (right - left) * FRAC_1_SQRT_2,
+        (left + right) * FRAC_1_SQRT_2,
+    ]
+}
+
+/// The latest frame's points, shared with the control socket.
+#[derive(Default)]
+pub struct GoniometerFrame {
+    points: Mutex<Vec<[f32; 2]>>,
+}
+
+impl GoniometerFrame {
+    /// The points, as left and right samples.
+    pub fn points(&self) -> Vec<[f32; 2]> {
+        self.points.lock().unwrap().clone()
+    }
+}
+
+/// Gathers the pairs of a frame and publishes its points.
+pub struct Goniometer {
+    pairs: Vec<[f32; 2]>,
+    length: usize,
+    points: Vec<[f32; 2]>,
+    shared: Arc<GoniometerFrame>,
+}
+
+impl Goniometer {
+    pub fn new(sample_rate: u32, shared: Arc<GoniometerFrame>) -> Self {
+        let length = ((FRAME_SECONDS * sample_rate as f64) as usize).max(1);
+        Goniometer {
+            pairs: Vec::with_capacity(length),
+            length,
+            points: Vec::with_capacity(POINTS),
+            shared,
+        }
+    }
+
+    pub fn push(&mut self, left: f32, right: f32) {
+        self.pairs.push([left, right]);
+        if self.pairs.len() < self.length {
+            return;
+        }
+        select_points(&self.pairs, POINTS, &mut self.points);
+        self.pairs.clear();
+        let mut shared = self.shared.points.lock().unwrap();
+        shared.clear();
+        shared.extend_from_slice(&self.points);
+    }
+}
+
+/// Draws points in a grid of characters, scaled to the farthest one, with the axes through the
+/// centre.
+pub fn render_ascii(points: &[[f32; 2]], width: usize, height: usize) -> String {
+    let (width, height) = (width.max(3), height.max(3));
+    let mut grid = vec![vec![' '; width]; height];
+    let (centre_x, centre_y) = (width / 2, height / 2);
+    for row in &mut grid {
+        row[centre_x] = '|';
+    }
+    grid[centre_y].fill('-');
+    grid[centre_y][centre_x] = '+';
+    let scale = points
+        .iter()
+        .flat_map(|x| [x[0].abs(), x[1].abs()])
+        .fold(f32::MIN_POSITIVE, f32::max);
+    for [x, y] in points {
+        let column = ((x / scale + 1.0) / 2.0 * (width - 1) as f32).round() as usize;
+        let row = ((1.0 - y / scale) / 2.0 * (height - 1) as f32).round() as usize;
+        grid[row.min(height - 1)][column.min(width - 1)] = '*';
+    }
+    let lines: Vec<String> = grid.into_iter().map(String::from_iter).collect();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_run_keeps_its_farthest_pair() {
+        let pairs: Vec<[f32; 2]> = (0..10).map(|n| [0.0, (n % 5) as f32 * 0.1]).collect();
+        let mut points = Vec::new();
+        select_points(&pairs, 2, &mut points);
+        assert_eq!(points, [[0.0, 0.4], [0.0, 0.4]]);
+        select_points(&pairs[..3], 4, &mut points);
+        assert_eq!(points, pairs[..3]);
+    }
+
+    #[test]
+    fn rotating_puts_mid_up_and_side_across() {
+        let [side, mid] = to_mid_side([0.5, 0.5]);
+        assert_eq!(side, 0.0);
+        assert!((mid - 0.5 * 2f32.sqrt()).abs() < 1e-6);
+        let [side, mid] = to_mid_side([1.0, 0.0]);
+        assert!(side < 0.0 && (side + mid).abs() < 1e-6);
+        let [side, mid] = to_mid_side([0.5, -0.5]);
+        assert!(side < 0.0 && mid == 0.0);
+    }
+
+    #[test]
+    fn frames_are_published_once_full() {
+        let shared = Arc::new(GoniometerFrame::default());
+        // Frames of 50 pairs at 1 kHz, fewer than the points kept.
+        let mut goniometer = Goniometer::new(1_000, shared.clone());
+        for n in 0..70 {
+            goniometer.push(n as f32, 0.0);
+        }
+        let points = shared.points();
+        assert_eq!(points.len(), 50);
+        assert_eq!(points[49], [49.0, 0.0]);
+        for n in 70..100 {
+            goniometer.push(n as f32, 0.0);
+        }
+        assert_eq!(shared.points()[0], [50.0, 0.0]);
+    }
+
+    #[test]
+    fn points_are_drawn_scaled_to_the_farthest() {
+        let drawing = render_ascii(&[[1.0, 1.0], [-0.5, 0.0], [0.0, -1.0]], 5, 3);
+        assert_eq!(drawing, "  | *\n-*+--\n  *  ");
+        assert_eq!(render_ascii(&[], 1, 1), " | \n-+-\n | ");
+    }
+}
@@ -0,0 +1,281 @@
+//! The guard of the outputs against NaNs and infinities.
+//!
+//! A bad setting, e.g. a filter pushed past what it's stable at, can make an effect produce
+//! non-finite samples, which some interfaces latch into full-scale noise until they're
+//! power-cycled. The output callback taps the block at the boundaries between its stages, and the
+//! chain after each of its effects, with a vectorised check. Once a block has anything but finite
+//! samples, they're replaced with silence and the output mutes with a fast ramp, until it has
+//! been finite again for [`RECOVERY`].
+//!
+//! The first time, the stage and effect that first turned the block non-finite are kept, with the
+//! parameters of the chain at that moment, for the main thread to report.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::simd;
+
+/// How long the output takes to mute, or to come back.
+pub const RAMP: Duration = Duration::from_millis(5);
+/// How long the blocks must stay finite before the output comes back.
+pub const RECOVERY: Duration = Duration::from_secs(1);
+
+/// The boundaries of the output callback the blocks are checked at, in its order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// The inputs mixed at their gains, with the network source, looper and track.
+    Mix,
+    /// After the effect chain.
+    Chain,
+    /// After the compressor keyed by the sidechain.
+    Compressor,
+}
+
+impl Stage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Mix => "mix",
+            Stage::Chain => "chain",
+            Stage::Compressor => "compressor",
+        }
+    }
+}
+
+/// What the guard found the first time it scrubbed a block.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnosis {
+    /// When it happened, into the stream.
+    pub seconds: f32,
+    /// The first stage whose block wasn't finite, or `None` if only the last check caught it.
+    pub stage: Option<Stage>,
+    /// The kind and instance of the effect of the chain that first made the block non-finite.
+    pub effect: Option<(&'static str, usize)>,
+    /// The parameters of the chain when it did, in the order of its layout.
+    pub params: Vec<f32>,
+}
+
+/// Shared between the output callback, its chain and the main thread.
+pub struct GuardStats {
+    /// Label of the output stream.
+    pub label: &'static str,
+    /// Blocks that had non-finite samples.
+    pub scrubbed: AtomicUsize,
+    first: Mutex<First>,
+}
+
+/// The first diagnosis, filled in by the chain and then the output callback.
+struct First {
+    diagnosis: Diagnosis,
+    complete: bool,
+    taken: bool,
+}
+
+impl GuardStats {
+    /// The stats of an output whose chain has `params` parameters.
+    pub fn new(label: &'static str, params: usize) -> Self {
+        GuardStats {
+            label,
+            scrubbed: AtomicUsize::new(0),
+            first: Mutex::new(First {
+                diagnosis: Diagnosis {
+                    seconds: 0.0,
+                    stage: None,
+                    effect: None,
+                    params: vec![0.0; params],
+                },
+                complete: false,
+                taken: false,
+            }),
+        }
+    }
+
+    /// Blames the effect `effect` of the chain, whose parameters are `params`, unless a block was
+    /// diagnosed before. Never blocks, nor allocates.
+    pub fn blame(&self, effect: (&'static str, usize), params: &[f32]) {
+        let Ok(mut first) = self.first.try_lock() else {
+            return;
+        };
+        if first.complete || first.diagnosis.effect.is_some() {
+            return;
+        }
+        let diagnosis = &mut first.diagnosis;
+        diagnosis.effect = Some(effect);
+        let length = params.len().min(diagnosis.params.len());
+        diagnosis.params[..length].copy_from_slice(&params[..length]);
+    }
+
+    /// Completes the first diagnosis with when and where it happened.
+    fn complete(&self, seconds: f32, stage: Option<Stage>) {
+        let Ok(mut first) = self.first.try_lock() else {
+            return;
+        };
+        if !first.complete {
+            first.diagnosis.seconds = seconds;
+            first.diagnosis.stage = stage;
+            first.complete = true;
+        }
+    }
+
+    /// The first diagnosis, the first time it's asked for once complete.
+    pub fn take_diagnosis(&self) -> Option<Diagnosis> {
+        let mut first = self.first.lock().unwrap();
+        if !first.complete || first.taken {
+            return None;
+        }
+        first.taken = true;
+        Some(first.diagnosis.clone())
+    }
+}
+
+/// Checks and scrubs the blocks of one output in its callback.
+pub struct NanGuard {
+    stats: Arc<GuardStats>,
+    sample_rate: u32,
+    /// The first stage of the current block that wasn't finite.
+    stage: Option<Stage>,
+    gain: f32,
+    step: f32,
+    /// Finite frames left before the output comes back, while it's muted.
+    recovering: usize,
+    frames: u64,
+}
+
+impl NanGuard {
+    pub fn new(stats: Arc<GuardStats>, sample_rate: u32) -> Self {
+        NanGuard {
+            stats,
+            sample_rate,
+            stage: None,
+            gain: 1.0,
+            step: step(sample_rate),
+            recovering: 0,
+            frames: 0,
+        }
+    }
+
+    /// Ramps and recovers in frames at `sample_rate` from now on.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        // Frames at the new rate that last as long as those so far, for the times reported.
+        self.frames = (self.frames as f64 * sample_rate as f64 / self.sample_rate as f64) as u64;
+        self.recovering =
+            (self.recovering as f64 * sample_rate as f64 / self.sample_rate as f64) as usize;
+        self.sample_rate = sample_rate;
+        self.step = step(sample_rate);
+    }
+
+    /// Checks the block after `stage`, unless an earlier stage already failed in this block.
+    pub fn tap(&mut self, stage: Stage, data: &[f32]) {
+        if self.stage.is_none() && !simd::all_finite(data) {
+            self.stage = Some(stage);
+        }
+    }
+
+    /// Replaces the non-finite samples of the block with silence, muting the output if there
+    /// were any and until the blocks have stayed finite for [`RECOVERY`], and starts the next
+    /// block.
+    pub fn scrub(&mut self, data: &mut [f32], channels: usize) {
+        let stage = self.stage.take();
+        let frames = data.len() / channels.max(1);
+        let seconds = self.frames as f32 / self.sample_rate as f32;
+        self.frames += frames as u64;
+        let finite = stage.is_none() && simd::all_finite(data);
+        if !finite {
+            self.stats.scrubbed.fetch_add(1, Ordering::Relaxed);
+            self.stats.complete(seconds, stage);
+            self.recovering = (RECOVERY.as_secs_f64() * self.sample_rate as f64) as usize;
+        } else if self.recovering > 0 {
+            self.recovering = self.recovering.saturating_sub(frames);
+        } else if self.gain == 1.0 {
+            return;
+        }
+        let target = if self.recovering > 0 { 0.0 } else { 1.0 };
+        for frame in data.chunks_mut(channels.max(1)) {
+            self.gain = match self.gain < target {
+                true => (self.gain + self.step).min(target),
+                false => (self.gain - self.step).max(target),
+            };
+            for x in frame {
+                *x = match x.is_finite() {
+                    true => *x * self.gain,
+                    false => 0.0,
+                };
+            }
+        }
+    }
+}
+
+/// The step of the gain per frame of a ramp at `sample_rate`.
+fn step(sample_rate: u32) -> f32 {
+    (1.0 / (RAMP.as_secs_f64() * sample_rate as f64).max(1.0)) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A guard at 1 kHz, so that the ramp is 5 frames and the recovery 1000.
+    fn guard() -> (NanGuard, Arc<GuardStats>) {
+        let stats = Arc::new(GuardStats::new("output stream", 2));
+        (NanGuard::new(stats.clone(), 1_000), stats)
+    }
+
+    #[test]
+    fn finite_blocks_pass_untouched() {
+        let (mut guard, stats) = guard();
+        let mut data = [0.5, -0.5, 0.25, -0.25];
+        guard.tap(Stage::Mix, &data);
+        guard.scrub(&mut data, 2);
+        assert_eq!(data, [0.5, -0.5, 0.25, -0.25]);
+        assert_eq!(stats.scrubbed.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.take_diagnosis(), None);
+    }
+
+    #[test]
+    fn non_finite_blocks_are_silenced_and_the_output_ramps_back() {
+        let (mut guard, stats) = guard();
+        let mut data = [1.0; 10];
+        data[3] = f32::NAN;
+        guard.scrub(&mut data, 1);
+        let expected = [0.8, 0.6, 0.4, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        assert!(data.iter().zip(expected).all(|(x, y)| (x - y).abs() < 1e-6));
+        assert_eq!(stats.scrubbed.load(Ordering::Relaxed), 1);
+
+        // Muted for a second of finite blocks, then back over the ramp.
+        for _ in 0..99 {
+            let mut data = [1.0; 10];
+            guard.scrub(&mut data, 1);
+            assert_eq!(data, [0.0; 10]);
+        }
+        let mut data = [1.0; 10];
+        guard.scrub(&mut data, 1);
+        assert!((data[0] - 0.2).abs() < 1e-6);
+        assert_eq!(data[4..], [1.0; 6]);
+    }
+
+    #[test]
+    fn the_first_stage_that_failed_is_diagnosed_once() {
+        let (mut guard, stats) = guard();
+        let mut data = [f32::INFINITY, 0.0];
+        guard.tap(Stage::Chain, &data);
+        guard.tap(Stage::Compressor, &data);
+        stats.blame(("biquad", 1), &[1_000.0, 40.0]);
+        guard.scrub(&mut data, 2);
+        let expected = Diagnosis {
+            seconds: 0.0,
+            stage: Some(Stage::Chain),
+            effect: Some(("biquad", 1)),
+            params: vec![1_000.0, 40.0],
+        };
+        assert_eq!(stats.take_diagnosis(), Some(expected));
+        assert_eq!(stats.take_diagnosis(), None);
+
+        // Later blocks are counted, but diagnosed no more.
+        stats.blame(("gain", 0), &[0.0, 0.0]);
+        let mut data = [f32::NAN, 0.0];
+        guard.tap(Stage::Mix, &data);
+        guard.scrub(&mut data, 2);
+        assert_eq!(stats.scrubbed.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.take_diagnosis(), None);
+    }
+}
@@ -0,0 +1,307 @@
+//! Headroom of the monitor feed at the boundaries between its stages, to catch gain staging that
+//! leaves too little of it, e.g. an EQ boost driving everything after it close to full scale.
+//!
+//! The feed is metered where its level is set: after the trims of the inputs, after the EQ,
+//! after the compressor and before the limiter. The output callback meters the peak of each
+//! block of its own stages, and [`attach`] taps the chain for the others. A stage is flagged once
+//! its peak has stayed over the threshold in every window of [`WINDOW_SECONDS`] for longer than
+//! [`HOLD_SECONDS`], so that a lone transient doesn't set it off.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::buffer::AudioBuffer;
+use crate::effects::{EffectChain, Tap};
+use crate::level;
+use crate::sample::Sample;
+use crate::stats::AtomicF32;
+
+/// Length of the windows the peaks are taken over, in seconds.
+pub const WINDOW_SECONDS: f64 = 0.1;
+/// How long a stage's peaks must stay over the threshold before it's flagged, in seconds.
+pub const HOLD_SECONDS: f64 = 1.0;
+
+/// The boundaries the feed is metered at, in the order of the callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// The inputs mixed at their gains.
+    Trim,
+    /// After the parametric EQ of the channel strip.
+    Eq,
+    /// After the compressor, in the chain or after it.
+    Compressor,
+    /// After everything else that sets the level, before the limiter, or at the end of a chain
+    /// without one.
+    Limiter,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Trim, Stage::Eq, Stage::Compressor, Stage::Limiter];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Trim => "post-trim",
+            Stage::Eq => "post-EQ",
+            Stage::Compressor => "post-compressor",
+            Stage::Limiter => "pre-limiter",
+        }
+    }
+}
+
+/// The meters of one stage.
+struct StagePeak {
+    /// Peak of the last window, or NaN until the stage is metered.
+    peak: AtomicF32,
+    over: AtomicBool,
+}
+
+/// Shared with the status line.
+pub struct HeadroomStats {
+    /// Label of the output stream.
+    label: &'static str,
+    threshold_db: f32,
+    stages: [StagePeak; 4],
+}
+
+impl HeadroomStats {
+    pub fn new(label: &'static str, threshold_db: f32) -> Self {
+        HeadroomStats {
+            label,
+            threshold_db,
+            stages: std::array::from_fn(|_| StagePeak {
+                peak: AtomicF32::new(f32::NAN),
+                over: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Peak of the last window at `stage`, in dBFS, or `None` if the stage isn't metered.
+    pub fn peak_db(&self, stage: Stage) -> Option<f32> {
+        let peak = self.stages[stage as usize].peak.load();
+        (!peak.is_nan()).then(|| level::gain_to_db(peak))
+    }
+
+    /// Whether the peaks at `stage` have stayed over the threshold for longer than
+    /// [`HOLD_SECONDS`].
+    pub fn is_over(&self, stage: Stage) -> bool {
+        self.stages[stage as usize].over.load(Ordering::Relaxed)
+    }
+
+    /// The first stage flagged, where the gain staging went wrong, with its peak in dBFS.
+    pub fn first_over(&self) -> Option<(Stage, f32)> {
+        Stage::ALL
+            .into_iter()
+            .find(|x| self.is_over(*x))
+            .map(|x| (x, self.peak_db(x).unwrap_or(f32::NAN)))
+    }
+}
+
+/// The field of the status line for the outputs, and whether it's a warning: the first stage
+/// flagged on any of them.
+pub fn describe(outputs: &[Arc<HeadroomStats>]) -> (String, bool) {
+    let over = outputs
+        .iter()
+        .find_map(|x| x.first_over().map(|(stage, peak)| (x, stage, peak)));
+    match over {
+        Some((stats, stage, peak)) => (
+            format!(
+                "headroom: {} of the {} at {:+.1} dBFS",
+                stage.name(),
+                stats.label,
+                peak
+            ),
+            true,
+        ),
+        None => ("headroom: ok".to_string(), false),
+    }
+}
+
+/// The window being measured at one stage.
+#[derive(Default)]
+struct Window {
+    frames: usize,
+    peak: f32,
+    /// Frames since the peaks went over the threshold, 0 while they're under.
+    over_frames: usize,
+}
+
+/// Meters the stages of one output in its callback.
+pub struct HeadroomMeter {
+    stats: Arc<HeadroomStats>,
+    threshold: f32,
+    window: usize,
+    hold: usize,
+    stages: [Window; 4],
+}
+
+impl HeadroomMeter {
+    pub fn new(stats: Arc<HeadroomStats>, sample_rate: u32) -> Self {
+        let mut meter = HeadroomMeter {
+            threshold: level::db_to_gain(stats.threshold_db),
+            stats,
+            window: 1,
+            hold: 0,
+            stages: Default::default(),
+        };
+        meter.set_sample_rate(sample_rate);
+        meter
+    }
+
+    /// Meters windows of the same length at `sample_rate` from now on.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let rate = sample_rate as f64;
+        self.window = ((WINDOW_SECONDS * rate) as usize).max(1);
+        self.hold = (HOLD_SECONDS * rate) as usize;
+    }
+
+    /// Meters the interleaved block `data`, with `channels` samples per frame, at `stage`.
+    pub fn measure(&mut self, stage: Stage, data: &[f32], channels: usize) {
+        let peak = data.iter().fold(0f32, |peak, x| peak.max(x.abs()));
+        self.measure_peak(stage, peak, data.len() / channels.max(1));
+    }
+
+    /// Meters a block of `frames` frames whose peak is `peak` at `stage`.
+    fn measure_peak(&mut self, stage: Stage, peak: f32, frames: usize) {
+        let window = &mut self.stages[stage as usize];
+        window.peak = window.peak.max(peak);
+        window.frames += frames;
+        if window.frames < self.window {
+            return;
+        }
+        let shared = &self.stats.stages[stage as usize];
+        shared.peak.store(window.peak);
+        window.over_frames = match window.peak > self.threshold {
+            true => window.over_frames + window.frames,
+            false => 0,
+        };
+        shared
+            .over
+            .store(window.over_frames > self.hold, Ordering::Relaxed);
+        window.frames = 0;
+        window.peak = 0.0;
+    }
+}
+
+/// Meters one stage of a chain.
+struct StageTap {
+    meter: HeadroomMeter,
+    stage: Stage,
+}
+
+impl<S: Sample> Tap<S> for StageTap {
+    fn tap(&mut self, buffer: &AudioBuffer<S>) {
+        self.meter
+            .measure_peak(self.stage, buffer.peak(), buffer.frames());
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.meter.set_sample_rate(sample_rate);
+    }
+}
+
+/// Taps `chain`, of a stream at `sample_rate`, after its EQ and compressor if it has them and
+/// before its limiter, or at its end without one, metering into `stats`.
+pub fn attach<S: Sample>(chain: &mut EffectChain<S>, stats: &Arc<HeadroomStats>, sample_rate: u32) {
+    let points = [
+        (Stage::Eq, chain.position("eq").map(|x| x + 1)),
+        (
+            Stage::Compressor,
+            chain.position("compressor").map(|x| x + 1),
+        ),
+        (
+            Stage::Limiter,
+            Some(chain.position("limiter").unwrap_or(chain.len())),
+        ),
+    ];
+    for (stage, index) in points {
+        if let Some(index) = index {
+            let meter = HeadroomMeter::new(stats.clone(), sample_rate);
+            chain.tap(index, StageTap { meter, stage });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::{Compressor, CompressorSettings};
+    use crate::effects::{EqBand, Gain, Interleaved, Limiter, LimiterSpec, ParametricEq};
+
+    const RATE: u32 = 48_000;
+
+    /// A strip of a 1 kHz peak of `boost_db` in the EQ, a compressor and a limiter at -1 dBFS,
+    /// with a gain of `gain_db` between the compressor and the limiter.
+    fn chain(boost_db: f32, gain_db: f32) -> EffectChain {
+        let rate = RATE as f32;
+        let mut chain = EffectChain::new(2);
+        let band = EqBand::peak(1_000.0, boost_db, 1.0);
+        chain.push(ParametricEq::new(&[band], rate, 2, None).unwrap());
+        let settings = CompressorSettings {
+            threshold_db: -20.0,
+            ratio: 4.0,
+            attack_ms: 5.0,
+            release_ms: 100.0,
+        };
+        chain.push(Interleaved::new(Compressor::new(settings, 2, RATE)));
+        chain.push(Gain::new(gain_db));
+        let spec = LimiterSpec {
+            ceiling_db: -1.0,
+            release_ms: 50.0,
+        };
+        chain.push(Limiter::new(spec, rate));
+        chain
+    }
+
+    /// Meters two seconds of a 1 kHz tone at -12 dBFS through `chain`, at -3 dBFS, returning
+    /// the stats and the peak of what came out.
+    fn run(mut chain: EffectChain) -> (Arc<HeadroomStats>, f32) {
+        let stats = Arc::new(HeadroomStats::new("output", -3.0));
+        let mut meter = HeadroomMeter::new(stats.clone(), RATE);
+        attach(&mut chain, &stats, RATE);
+        let amplitude = level::db_to_gain(-12.0);
+        let mut peak = 0f32;
+        for block in 0..(2 * RATE as usize / 480) {
+            let mut data: Vec<f32> = (0..960)
+                .map(|x| {
+                    let t = (block * 480 + x / 2) as f32 / RATE as f32;
+                    amplitude * (std::f32::consts::TAU * 1_000.0 * t).sin()
+                })
+                .collect();
+            meter.measure(Stage::Trim, &data, 2);
+            chain.process(&mut data);
+            peak = data.iter().fold(peak, |peak, x| peak.max(x.abs()));
+        }
+        (stats, peak)
+    }
+
+    #[test]
+    fn a_boost_of_the_eq_is_flagged_after_the_eq() {
+        let (stats, _) = run(chain(12.0, 0.0));
+        assert!(!stats.is_over(Stage::Trim));
+        assert!(stats.is_over(Stage::Eq));
+        assert!(!stats.is_over(Stage::Compressor));
+        assert!(!stats.is_over(Stage::Limiter));
+        let (stage, peak) = stats.first_over().unwrap();
+        assert_eq!(stage, Stage::Eq);
+        assert!((peak - 0.0).abs() < 0.5, "{}", peak);
+    }
+
+    #[test]
+    fn a_gain_after_the_compressor_is_flagged_before_the_limiter() {
+        let (stats, peak) = run(chain(0.0, 18.0));
+        assert_eq!(stats.first_over().map(|x| x.0), Some(Stage::Limiter));
+        assert!(stats.peak_db(Stage::Limiter).unwrap() > 0.0);
+        // The limiter holds what goes out under its ceiling all the same.
+        assert!(peak <= level::db_to_gain(-1.0) + 1e-6, "{}", peak);
+    }
+
+    #[test]
+    fn a_chain_without_a_limiter_is_metered_at_its_end() {
+        let mut chain: EffectChain = EffectChain::new(2);
+        chain.push(Gain::new(12.0));
+        let (stats, _) = run(chain);
+        assert_eq!(stats.first_over().map(|x| x.0), Some(Stage::Limiter));
+        assert_eq!(stats.peak_db(Stage::Eq), None);
+        assert_eq!(stats.peak_db(Stage::Compressor), None);
+    }
+}
@@ -0,0 +1,683 @@
+//! A hardware insert: the monitor feed goes out to an external effect on spare output channels
+//! and comes back from it on spare input channels, at a point of the effect chain.
+//!
+//! The [`HwInsert`] effect sits at the insert point of the first output's chain. It hands what
+//! reaches it to the output callback through a small ring buffer of its own, and the callback
+//! writes it to the send channels once the chain is done, so that no effect after the insert
+//! point touches the send. The first input's callback takes the return channels out of what
+//! goes anywhere else, and pushes them into another ring buffer for the insert to take back.
+//!
+//! How late the return comes back is measured once, at the start: the insert sends a click,
+//! captures the return for up to [`MAX_LOOP`], and the main thread finds the click in it with the
+//! correlator of the null test. Until then, the feed passes as it is. From then on, the insert
+//! delays the dry signal and every other channel by as much as the loop, so that everything stays
+//! in time with the return. A return that stays silent for the timeout, e.g. of hardware that
+//! was turned off, makes the insert crossfade to the delayed dry signal until it's back, and a
+//! click that never came back bypasses the insert for the rest of the run. The latency budget
+//! doesn't include the loop, which is only known once the streams run. When the streams change
+//! their rate, the loop measured, the timeout and the crossfades are scaled to it.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ringbuf::traits::{Consumer, Producer, Split};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+
+use crate::buffer::{AudioBuffer, MAX_BLOCK_FRAMES};
+use crate::effects::{Effect, ScratchArena};
+use crate::level;
+use crate::null;
+use crate::sample::Sample;
+
+/// Longest loop through the hardware the measurement finds.
+pub const MAX_LOOP: Duration = Duration::from_millis(250);
+/// How long the insert waits for a silent return by default, before falling back to the dry
+/// signal.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long switching between the return and the dry signal takes.
+pub const CROSSFADE: Duration = Duration::from_millis(10);
+
+/// The measurement click: a Hann pulse of so many frames, at so many dBFS peak.
+const CLICK_FRAMES: usize = 64;
+const CLICK_LEVEL_DB: f32 = -12.0;
+/// Peak under which the return counts as silent, in dBFS.
+const SILENCE_DB: f32 = -70.0;
+/// Silence the return ring starts with, against the jitter of the input callbacks.
+const RETURN_PREFILL: Duration = Duration::from_millis(10);
+
+/// Values of [`InsertStats`]' latency until the loop is measured, and once no click came back.
+const MEASURING: usize = usize::MAX;
+const NO_RETURN: usize = usize::MAX - 1;
+
+/// The hardware insert on the command line, as `key=value` pairs, e.g.
+/// `send=2,3:return=4,5:after=eq`:
+///
+/// - `send`: output channels the feed goes out on, from 0.
+/// - `return`: input channels of the first input it comes back on, as many as sent.
+/// - `after`: the effect of the chain the insert comes after, e.g. "eq", or "filter.1" for the
+///   second one, at the end of the chain by default.
+/// - `timeout`: how long a silent return lasts before the insert falls back to the dry signal,
+///   in milliseconds, 500 by default.
+///
+/// The first of the feed's channels go out on the send channels, in order, and the return
+/// replaces them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InsertSpec {
+    pub send: Vec<usize>,
+    pub returns: Vec<usize>,
+    pub after: Option<String>,
+    pub timeout: Duration,
+}
+
+impl FromStr for InsertSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let channels = |key: &str, value: &str| {
+            value
+                .split(',')
+                .map(|x| {
+                    x.parse::<usize>()
+                        .map_err(|_| format!("invalid hardware insert {} channel \"{}\"", key, x))
+                })
+                .collect::<Result<Vec<usize>, String>>()
+        };
+        let mut spec = InsertSpec {
+            send: Vec::new(),
+            returns: Vec::new(),
+            after: None,
+            timeout: DEFAULT_TIMEOUT,
+        };
+        for pair in s.split(':').filter(|x| !x.is_empty()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!(
+                    "expected `key=value` in the hardware insert, got \"{}\"",
+                    pair
+                ));
+            };
+            match key {
+                "send" => spec.send = channels(key, value)?,
+                "return" => spec.returns = channels(key, value)?,
+                "after" => spec.after = Some(value.to_string()),
+                "timeout" => {
+                    spec.timeout = value
+                        .parse::<f32>()
+                        .ok()
+                        .and_then(|x| Duration::try_from_secs_f32(x / 1_000.0).ok())
+                        .ok_or_else(|| format!("invalid hardware insert timeout \"{}\"", value))?
+                }
+                _ => return Err(format!("unknown hardware insert setting \"{}\"", key)),
+            }
+        }
+        if spec.send.is_empty() || spec.send.len() != spec.returns.len() {
+            return Err(format!(
+                "the hardware insert needs as many return channels as send channels, got {} and \
+                 {}",
+                spec.send.len(),
+                spec.returns.len()
+            ));
+        }
+        for list in [&spec.send, &spec.returns] {
+            if (1..list.len()).any(|x| list[..x].contains(&list[x])) {
+                return Err("a hardware insert channel is given twice".to_string());
+            }
+        }
+        if let Some(channel) = spec.send.iter().find(|x| **x < spec.send.len()) {
+            return Err(format!(
+                "the hardware insert sends channels 0 to {} of the feed, so it can't send on \
+                 channel {}",
+                spec.send.len() - 1,
+                channel
+            ));
+        }
+        Ok(spec)
+    }
+}
+
+impl InsertSpec {
+    /// Channels of the feed that go through the hardware.
+    pub fn channels(&self) -> usize {
+        self.send.len()
+    }
+}
+
+/// The measured loop and the state of the return, shared with the main thread and the status
+/// line.
+pub struct InsertStats {
+    sample_rate: AtomicU32,
+    /// The capture of the click, handed over by the insert for the main thread to measure.
+    capture: Mutex<Option<Vec<f32>>>,
+    /// Frames the loop takes, or [`MEASURING`] or [`NO_RETURN`].
+    latency: AtomicUsize,
+    /// Whether the return is what's playing, rather than the dry signal.
+    returning: AtomicBool,
+}
+
+impl InsertStats {
+    pub fn new(sample_rate: u32) -> Self {
+        InsertStats {
+            sample_rate: AtomicU32::new(sample_rate),
+            capture: Mutex::new(None),
+            latency: AtomicUsize::new(MEASURING),
+            returning: AtomicBool::new(false),
+        }
+    }
+
+    /// Finds the click in the capture once the insert handed it over. Returns `None` until then
+    /// and after, and then the frames the loop takes, or `Some(None)` if no click came back.
+    pub fn measure(&self) -> Option<Option<usize>> {
+        let captured = self.capture.lock().unwrap().take()?;
+        let latency = loop_latency(&captured);
+        self.latency
+            .store(latency.unwrap_or(NO_RETURN), Ordering::Relaxed);
+        Some(latency)
+    }
+
+    /// Frames in milliseconds.
+    pub fn milliseconds(&self, frames: usize) -> f64 {
+        frames as f64 * 1_000.0 / self.sample_rate.load(Ordering::Relaxed) as f64
+    }
+
+    /// The field of the status line, e.g. "insert: 4.2 ms", or "insert: 4.2 ms, dry" while the
+    /// return is silent.
+    pub fn describe(&self) -> String {
+        match self.latency.load(Ordering::Relaxed) {
+            MEASURING => "insert: measuring".to_string(),
+            NO_RETURN => "insert: bypassed, no return".to_string(),
+            frames => match self.returning.load(Ordering::Relaxed) {
+                true => format!("insert: {:.1} ms", self.milliseconds(frames)),
+                false => format!("insert: {:.1} ms, dry", self.milliseconds(frames)),
+            },
+        }
+    }
+
+    fn latency(&self) -> Option<usize> {
+        match self.latency.load(Ordering::Relaxed) {
+            MEASURING => None,
+            frames => Some(frames),
+        }
+    }
+}
+
+/// The measurement click.
+pub fn click() -> Vec<f32> {
+    let amplitude = level::db_to_gain(CLICK_LEVEL_DB);
+    (0..CLICK_FRAMES)
+        .map(|n| {
+            let phase = std::f32::consts::TAU * n as f32 / (CLICK_FRAMES - 1) as f32;
+            amplitude * 0.5 * (1.0 - phase.cos())
+        })
+        .collect()
+}
+
+/// The frame of `captured`, mono, at which the [`click`] sent at frame 0 comes back, or `None`
+/// if it's silent.
+///
+/// The loudest frame tells roughly where the click is, and the correlator finds it exactly
+/// around there.
+pub fn loop_latency(captured: &[f32]) -> Option<usize> {
+    let (peak, loudest) =
+        captured
+            .iter()
+            .enumerate()
+            .fold((0, 0.0f32), |(peak, loudest), (n, x)| {
+                match x.abs() > loudest {
+                    true => (n, x.abs()),
+                    false => (peak, loudest),
+                }
+            });
+    if loudest < level::db_to_gain(SILENCE_DB) {
+        return None;
+    }
+    // The correlator compares the frames from the largest lag on, so the click is given some
+    // silence before it, and the capture more, for the comparison to cover all of the click at
+    // every lag searched.
+    let search = CLICK_FRAMES / 2;
+    let mut reference = vec![0.0; search * 2];
+    reference.extend(click());
+    let mut output = vec![0.0; search * 3];
+    output.extend_from_slice(captured);
+    let nominal = (peak + search * 3).saturating_sub(CLICK_FRAMES / 2 + search * 2);
+    let lag = null::align(&reference, &output, nominal + search, nominal, search)?;
+    Some((lag + search * 2).saturating_sub(search * 3))
+}
+
+/// The rings of an insert, and the ends of them that the callbacks hold.
+pub struct InsertLink {
+    channels: usize,
+    sample_rate: u32,
+    timeout: Duration,
+    send: HeapProd<f32>,
+    returns: HeapCons<f32>,
+    stats: Arc<InsertStats>,
+}
+
+/// Creates the rings of an insert of `spec` between the first input and the first output, both
+/// at `sample_rate`. `quantum` is the processing quantum of the output, if any, which the send
+/// ring starts with as much silence as.
+pub fn link(
+    spec: &InsertSpec,
+    input_channels: usize,
+    sample_rate: u32,
+    quantum: Option<usize>,
+) -> (InsertLink, InsertSend, InsertReturn, Arc<InsertStats>) {
+    let channels = spec.channels();
+    let capacity = |frames: usize| (frames + MAX_BLOCK_FRAMES * 2) * channels;
+    let (mut send_producer, send_consumer) = HeapRb::<f32>::new(capacity(0)).split();
+    send_producer.push_iter(std::iter::repeat_n(0.0, quantum.unwrap_or(0) * channels));
+    let max_loop = (MAX_LOOP.as_secs_f64() * sample_rate as f64) as usize;
+    let (mut return_producer, return_consumer) = HeapRb::<f32>::new(capacity(max_loop)).split();
+    let prefill = (RETURN_PREFILL.as_secs_f64() * sample_rate as f64) as usize;
+    return_producer.push_iter(std::iter::repeat_n(0.0, prefill * channels));
+    let stats = Arc::new(InsertStats::new(sample_rate));
+    let link = InsertLink {
+        channels,
+        sample_rate,
+        timeout: spec.timeout,
+        send: send_producer,
+        returns: return_consumer,
+        stats: stats.clone(),
+    };
+    let send = InsertSend {
+        channels: spec.send.clone(),
+        consumer: send_consumer,
+        frames: Vec::new(),
+    };
+    let returns = InsertReturn {
+        input_channels,
+        channels: spec.returns.clone(),
+        producer: return_producer,
+        frames: Vec::new(),
+        rest: Vec::new(),
+    };
+    (link, send, returns, stats)
+}
+
+/// The output callback's end of the send: writes what reached the insert to the send channels.
+pub struct InsertSend {
+    channels: Vec<usize>,
+    consumer: HeapCons<f32>,
+    frames: Vec<f32>,
+}
+
+impl InsertSend {
+    /// Allocates what blocks of up to `max_frames` need, so that the callback doesn't. The send
+    /// channels of a larger block are only written for its first `max_frames`.
+    pub fn prepare(&mut self, max_frames: usize) {
+        if self.frames.len() < max_frames * self.channels.len() {
+            self.frames.resize(max_frames * self.channels.len(), 0.0);
+        }
+    }
+
+    /// Writes the send into the interleaved block `data` of `channels` channels, silence for
+    /// what the insert didn't send.
+    pub fn write(&mut self, data: &mut [f32], channels: usize) {
+        let length = (data.len() / channels * self.channels.len()).min(self.frames.len());
+        let frames = &mut self.frames[..length];
+        let popped = self.consumer.pop_slice(frames);
+        frames[popped..].fill(0.0);
+        let sent = frames.chunks_exact(self.channels.len());
+        for (frame, sent) in data.chunks_exact_mut(channels).zip(sent) {
+            for (channel, x) in self.channels.iter().zip(sent) {
+                frame[*channel] = *x;
+            }
+        }
+    }
+}
+
+/// The input callback's end of the return: takes the return channels out of the input.
+pub struct InsertReturn {
+    input_channels: usize,
+    channels: Vec<usize>,
+    producer: HeapProd<f32>,
+    frames: Vec<f32>,
+    /// The input without the return.
+    rest: Vec<f32>,
+}
+
+impl InsertReturn {
+    /// Allocates what blocks of up to `max_frames` need, so that the callback doesn't. Only the
+    /// first `max_frames` of a larger block are taken, and the rest is dropped.
+    pub fn prepare(&mut self, max_frames: usize) {
+        if self.rest.capacity() < max_frames * self.input_channels {
+            self.frames = Vec::with_capacity(max_frames * self.channels.len());
+            self.rest = Vec::with_capacity(max_frames * self.input_channels);
+        }
+    }
+
+    /// Sends the return channels of the interleaved block `data` to the insert, and returns the
+    /// block with them silenced, for everything else. A full ring only means the output is far
+    /// behind, and the return is dropped.
+    pub fn take<'a>(&'a mut self, data: &[f32]) -> &'a [f32] {
+        self.frames.clear();
+        self.rest.clear();
+        let length = data
+            .len()
+            .min(self.rest.capacity() / self.input_channels * self.input_channels);
+        self.rest.extend_from_slice(&data[..length]);
+        for frame in self.rest.chunks_exact_mut(self.input_channels) {
+            for channel in &self.channels {
+                self.frames.push(frame[*channel]);
+                frame[*channel] = 0.0;
+            }
+        }
+        self.producer.push_slice(&self.frames);
+        &self.rest
+    }
+}
+
+/// Where the insert is in its run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    /// Sending the click, and capturing the return, so many frames in.
+    Measuring(usize),
+    /// Waiting for the main thread to measure the capture.
+    Waiting,
+    /// Running with the loop taking so many frames.
+    Running(usize),
+    /// No click came back: the feed passes as it is.
+    Bypassed,
+}
+
+/// The insert point in the chain of the first output. See the [module](self) documentation.
+pub struct HwInsert<S = f32> {
+    link: InsertLink,
+    phase: Phase,
+    click: Vec<f32>,
+    capture: Vec<f32>,
+    /// The last frames of every channel, as long as the longest loop, and where the next goes.
+    history: Vec<Vec<S>>,
+    position: usize,
+    /// Frames into the crossfade from the feed as it is to the delayed one, once running.
+    entering: usize,
+    /// Gain of the return against the dry signal, and where it's going.
+    wet: f32,
+    /// Frames the return has been silent for, and how many make it time out.
+    silent: usize,
+    timeout: usize,
+    /// Change of the gains per frame, for the crossfades.
+    step: f32,
+    crossfade: usize,
+    sent: Vec<f32>,
+    returned: Vec<f32>,
+}
+
+impl<S: Sample> HwInsert<S> {
+    /// Creates the insert of `link` for a chain of `channels` channels, at least as many as go
+    /// through the hardware.
+    pub fn new(link: InsertLink, channels: usize) -> Self {
+        let mut insert = HwInsert {
+            phase: Phase::Measuring(0),
+            click: click(),
+            capture: Vec::new(),
+            history: vec![Vec::new(); channels],
+            position: 0,
+            entering: 0,
+            wet: 0.0,
+            silent: 0,
+            timeout: 0,
+            step: 0.0,
+            crossfade: 0,
+            sent: Vec::with_capacity(MAX_BLOCK_FRAMES * link.channels),
+            returned: vec![0.0; MAX_BLOCK_FRAMES * link.channels],
+            link,
+        };
+        insert.derive();
+        insert
+    }
+
+    /// Sizes the capture and the history, and derives the timeout and the crossfades, for the
+    /// rate of the link.
+    fn derive(&mut self) {
+        let sample_rate = self.link.sample_rate as f64;
+        let frames = |x: Duration| (x.as_secs_f64() * sample_rate) as usize;
+        let max_loop = frames(MAX_LOOP);
+        if matches!(self.phase, Phase::Measuring(_)) {
+            self.capture = vec![0.0; max_loop];
+        }
+        for history in &mut self.history {
+            *history = vec![S::default(); max_loop + 1];
+        }
+        self.position = 0;
+        self.timeout = frames(self.link.timeout);
+        self.crossfade = frames(CROSSFADE).max(1);
+        self.step = 1.0 / self.crossfade as f32;
+    }
+
+    /// Moves on to running once the main thread measured the loop.
+    fn poll(&mut self) {
+        match self.link.stats.latency() {
+            None => {}
+            Some(NO_RETURN) => self.phase = Phase::Bypassed,
+            Some(frames) => {
+                self.phase = Phase::Running(frames.min(self.history[0].len() - 1));
+                self.link.stats.returning.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<S: Sample> Effect<S> for HwInsert<S> {
+    fn process(&mut self, buffer: &mut AudioBuffer<S>, _scratch: &mut ScratchArena<S>) {
+        if self.phase == Phase::Waiting {
+            self.poll();
+        }
+        let channels = self.link.channels;
+        let frames = buffer.frames();
+        // Only blocks larger than the rings are sized for make it grow.
+        if self.returned.len() < frames * channels {
+            self.returned.resize(frames * channels, 0.0);
+        }
+        let returned = &mut self.returned[..frames * channels];
+        let popped = self.link.returns.pop_slice(returned);
+        returned[popped..].fill(0.0);
+        self.sent.clear();
+        let length = self.history[0].len();
+        let silence = level::db_to_gain(SILENCE_DB);
+        for n in 0..frames {
+            let returned = &self.returned[n * channels..][..channels];
+            for (channel, history) in self.history.iter_mut().enumerate() {
+                history[self.position] = buffer.channel(channel)[n];
+            }
+            match self.phase {
+                Phase::Measuring(frame) => {
+                    let click = self.click.get(frame).copied().unwrap_or(0.0);
+                    self.sent.extend(std::iter::repeat_n(click, channels));
+                    self.capture[frame] = returned.iter().sum::<f32>() / channels as f32;
+                    if frame + 1 < self.capture.len() {
+                        self.phase = Phase::Measuring(frame + 1);
+                    } else {
+                        let captured = std::mem::take(&mut self.capture);
+                        // Not the loop of a capture before a change of rate.
+                        let stats = &self.link.stats;
+                        stats.latency.store(MEASURING, Ordering::Relaxed);
+                        *stats.capture.lock().unwrap() = Some(captured);
+                        self.phase = Phase::Waiting;
+                    }
+                }
+                Phase::Waiting => {
+                    let feed = (0..channels).map(|x| buffer.channel(x)[n].to_sample::<f32>());
+                    self.sent.extend(feed);
+                }
+                Phase::Bypassed => self.sent.extend(std::iter::repeat_n(0.0, channels)),
+                Phase::Running(latency) => {
+                    let feed = (0..channels).map(|x| buffer.channel(x)[n].to_sample::<f32>());
+                    self.sent.extend(feed);
+                    let silent = returned.iter().all(|x| x.abs() < silence);
+                    self.silent = if silent { self.silent + 1 } else { 0 };
+                    let target = if self.silent < self.timeout { 1.0 } else { 0.0 };
+                    if self.wet != target {
+                        self.wet =
+                            (self.wet + (target - self.wet).signum() * self.step).clamp(0.0, 1.0);
+                        self.link
+                            .stats
+                            .returning
+                            .store(target == 1.0, Ordering::Relaxed);
+                    }
+                    let entering = self.entering as f32 / self.crossfade as f32;
+                    self.entering = (self.entering + 1).min(self.crossfade);
+                    let delayed = (self.position + length - latency) % length;
+                    let data = buffer.channels_mut().zip(&self.history).enumerate();
+                    for (channel, (data, history)) in data {
+                        let dry = history[delayed].to_sample::<f32>();
+                        let y = match returned.get(channel) {
+                            Some(wet) => self.wet * wet + (1.0 - self.wet) * dry,
+                            None => dry,
+                        };
+                        data[n] = match entering < 1.0 {
+                            true => {
+                                let x = data[n].to_sample::<f32>();
+                                S::from_sample(x + (y - x) * entering)
+                            }
+                            false => S::from_sample(y),
+                        };
+                    }
+                }
+            }
+            self.position = (self.position + 1) % length;
+        }
+        // A full ring only means the output callback is far behind, and the send is dropped.
+        self.link.send.push_slice(&self.sent);
+    }
+
+    fn name(&self) -> &'static str {
+        "insert"
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        let ratio = sample_rate as f64 / self.link.sample_rate as f64;
+        self.link.sample_rate = sample_rate as u32;
+        let stats = self.link.stats.clone();
+        stats
+            .sample_rate
+            .store(sample_rate as u32, Ordering::Relaxed);
+        // A click captured at the old rate, all of it or some, is sent again at the new one.
+        if let Phase::Measuring(_) | Phase::Waiting = self.phase {
+            self.phase = Phase::Measuring(0);
+        }
+        self.derive();
+        if let Phase::Running(latency) = self.phase {
+            let latency =
+                ((latency as f64 * ratio).round() as usize).min(self.history[0].len() - 1);
+            stats.latency.store(latency, Ordering::Relaxed);
+            self.phase = Phase::Running(latency);
+            // The history starts over silent, which the delayed signal fades in from.
+            self.entering = 0;
+        }
+        self.silent = (self.silent as f64 * ratio) as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+    const BLOCK: usize = 256;
+
+    /// Runs the insert of one channel in a two-channel chain, sent on output channel 1 and
+    /// returned on input channel 1, through hardware that delays the send by `delay` frames and
+    /// scales it by `gain`, until the main thread measured the loop.
+    fn measure(delay: usize, gain: f32) -> (HwInsert, Arc<InsertStats>, Option<usize>) {
+        let spec: InsertSpec = "send=1:return=1".parse().unwrap();
+        let (link, mut send, mut returns, stats) = link(&spec, 2, RATE, None);
+        send.prepare(BLOCK);
+        returns.prepare(BLOCK);
+        let mut insert = HwInsert::new(link, 2);
+        let mut scratch = ScratchArena::new(0);
+        let mut buffer = AudioBuffer::new(2, BLOCK);
+        let mut hardware = vec![0.0f32; delay];
+        let mut output = vec![0.0f32; BLOCK * 2];
+        for _ in 0..RATE as usize / BLOCK {
+            buffer.deinterleave(&vec![0.0; BLOCK * 2]);
+            insert.process(&mut buffer, &mut scratch);
+            send.write(&mut output, 2);
+            hardware.extend(output.chunks_exact(2).map(|x| x[1] * gain));
+            let input: Vec<f32> = hardware.drain(..BLOCK).flat_map(|x| [0.0, x]).collect();
+            returns.take(&input);
+            if let Some(latency) = stats.measure() {
+                return (insert, stats, latency);
+            }
+        }
+        panic!("the insert never handed the capture over");
+    }
+
+    #[test]
+    fn the_loop_is_measured_from_the_click() {
+        let prefill = (RETURN_PREFILL.as_secs_f64() * RATE as f64) as usize;
+        for delay in [0, 37, 1_000] {
+            let (_, stats, latency) = measure(delay, 0.5);
+            assert_eq!(latency, Some(prefill + delay));
+            assert!(stats.describe().starts_with("insert: "));
+        }
+    }
+
+    #[test]
+    fn a_click_that_never_comes_back_bypasses_the_insert() {
+        let (mut insert, stats, latency) = measure(10, 0.0);
+        assert_eq!(latency, None);
+        assert_eq!(stats.describe(), "insert: bypassed, no return");
+        let mut buffer = AudioBuffer::new(2, BLOCK);
+        buffer.deinterleave(&vec![0.25; BLOCK * 2]);
+        insert.process(&mut buffer, &mut ScratchArena::new(0));
+        assert_eq!(insert.phase, Phase::Bypassed);
+        assert!(buffer.channel(0).iter().all(|x| *x == 0.25));
+    }
+
+    #[test]
+    fn a_change_of_rate_scales_the_loop_and_the_timeout() {
+        let (mut insert, stats, latency) = measure(100, 0.5);
+        let latency = latency.unwrap();
+        let mut buffer = AudioBuffer::new(2, BLOCK);
+        buffer.deinterleave(&vec![0.0; BLOCK * 2]);
+        insert.process(&mut buffer, &mut ScratchArena::new(0));
+        assert_eq!(insert.phase, Phase::Running(latency));
+        let before = stats.describe();
+        Effect::<f32>::set_sample_rate(&mut insert, RATE as f32 * 2.0);
+        assert_eq!(insert.phase, Phase::Running(latency * 2));
+        assert_eq!(insert.timeout, DEFAULT_TIMEOUT.as_millis() as usize * 96);
+        assert_eq!(
+            insert.history[0].len(),
+            MAX_LOOP.as_millis() as usize * 96 + 1
+        );
+        assert_eq!(stats.describe(), before);
+    }
+
+    #[test]
+    fn a_change_of_rate_while_measuring_sends_the_click_again() {
+        let spec: InsertSpec = "send=1:return=1".parse().unwrap();
+        let (link, ..) = link(&spec, 2, RATE, None);
+        let mut insert = HwInsert::<f32>::new(link, 2);
+        let mut buffer = AudioBuffer::new(2, BLOCK);
+        buffer.deinterleave(&vec![0.0; BLOCK * 2]);
+        insert.process(&mut buffer, &mut ScratchArena::new(0));
+        assert_eq!(insert.phase, Phase::Measuring(BLOCK));
+        Effect::<f32>::set_sample_rate(&mut insert, 44_100.0);
+        assert_eq!(insert.phase, Phase::Measuring(0));
+        assert_eq!(insert.capture.len(), 11_025);
+    }
+
+    #[test]
+    fn blocks_larger_than_prepared_for_are_only_sent_and_taken_that_far() {
+        let spec: InsertSpec = "send=1:return=1".parse().unwrap();
+        let (_link, mut send, mut returns, _) = link(&spec, 2, RATE, None);
+        send.prepare(2);
+        returns.prepare(2);
+        let input = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        assert_eq!(returns.take(&input), [0.1, 0.0, 0.3, 0.0]);
+        let mut output = [1.0; 6];
+        send.write(&mut output, 2);
+        assert_eq!(output, [1.0, 0.0, 1.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn the_spec_needs_a_return_for_every_send() {
+        assert!("send=2,3:return=4".parse::<InsertSpec>().is_err());
+        assert!("send=0:return=1".parse::<InsertSpec>().is_err());
+        let spec: InsertSpec = "send=2,3:return=4,5:after=eq:timeout=250".parse().unwrap();
+        assert_eq!(spec.after.as_deref(), Some("eq"));
+        assert_eq!(spec.timeout, Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,384 @@
+//! Minimal JSON values, for the line-based protocol of the control socket and the session log.
+//!
+//! Objects keep their keys in order, numbers are f64, and a value prints as compact JSON on a
+//! single line, so each message of the protocol is one line.
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Builds an object from its members.
+    pub fn object<'a>(members: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Value::Object(
+            members
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Parses a JSON text, failing with the position of the first error.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            position: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        match parser.position == parser.bytes.len() {
+            true => Ok(value),
+            false => Err(parser.error("expected the end of the text")),
+        }
+    }
+
+    /// The member `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|x| x.0 == key).map(|x| &x.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(x) => write!(f, "{}", x),
+            // JSON has no infinities nor NaN.
+            Value::Number(x) if !x.is_finite() => write!(f, "null"),
+            Value::Number(x) => write!(f, "{}", x),
+            Value::String(x) => write_string(f, x),
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(members) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at byte {}", message, self.position)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.position) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.position).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        match self.peek() {
+            Some(x) if x == byte => {
+                self.position += 1;
+                Ok(())
+            }
+            _ => Err(self.error(&format!("expected '{}'", byte as char))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => {
+                for (word, value) in [
+                    ("true", Value::Bool(true)),
+                    ("false", Value::Bool(false)),
+                    ("null", Value::Null),
+                ] {
+                    if self.bytes[self.position..].starts_with(word.as_bytes()) {
+                        self.position += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("expected a value"))
+            }
+            None => Err(self.error("unexpected end of the text")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            members.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b'}') => {
+                    self.position += 1;
+                    return Ok(Value::Object(members));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => {
+                    self.position += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut text = String::new();
+        loop {
+            let start = self.position;
+            while let Some(x) = self.bytes.get(self.position) {
+                if *x == b'"' || *x == b'\\' {
+                    break;
+                }
+                self.position += 1;
+            }
+            // The input is a str, and the stops are ASCII, so the run is valid UTF-8.
+            text.push_str(std::str::from_utf8(&self.bytes[start..self.position]).unwrap());
+            match self.bytes.get(self.position) {
+                Some(b'"') => {
+                    self.position += 1;
+                    return Ok(text);
+                }
+                Some(_) => {
+                    self.position += 1;
+                    text.push(self.escape()?);
+                }
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, String> {
+        let byte = *self
+            .bytes
+            .get(self.position)
+            .ok_or_else(|| self.error("unterminated string"))?;
+        self.position += 1;
+        Ok(match byte {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let unit = self.hex()?;
+                if (0xd800..0xdc00).contains(&unit) {
+                    // A surrogate pair.
+                    if !self.bytes[self.position..].starts_with(b"\\u") {
+                        return Err(self.error("unpaired surrogate"));
+                    }
+                    self.position += 2;
+                    let low = self.hex()?;
+                    let c = 0x10000 + ((unit - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                    char::from_u32(c).ok_or_else(|| self.error("invalid surrogate pair"))?
+                } else {
+                    char::from_u32(unit).ok_or_else(|| self.error("invalid escape"))?
+                }
+            }
+            _ => return Err(self.error("invalid escape")),
+        })
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let digits = self
+            .bytes
+            .get(self.position..self.position + 4)
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .and_then(|x| u32::from_str_radix(x, 16).ok())
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.position += 4;
+        Ok(digits)
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.position;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') =
+            self.bytes.get(self.position)
+        {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.position])
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .map(Value::Number)
+            .ok_or_else(|| {
+                self.position = start;
+                self.error("invalid number")
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_print_back_as_they_were_parsed() {
+        let text = r#"{"command":"set","value":-6.5,"on":true,"list":[1,null,"x"],"empty":{}}"#;
+        let value = Value::parse(text).unwrap();
+        assert_eq!(value.get("command").and_then(Value::as_str), Some("set"));
+        assert_eq!(value.get("value").and_then(Value::as_f64), Some(-6.5));
+        assert_eq!(value.get("on").and_then(Value::as_bool), Some(true));
+        assert_eq!(value.get("missing"), None);
+        assert_eq!(value.to_string(), text);
+    }
+
+    #[test]
+    fn whitespace_and_exponents_are_read() {
+        let value = Value::parse(" { \"a\" : [ 1e3 , -2.5E-1 ] }\n").unwrap();
+        assert_eq!(value.to_string(), r#"{"a":[1000,-0.25]}"#);
+    }
+
+    #[test]
+    fn strings_are_escaped_both_ways() {
+        let value = Value::parse(r#""a\"b\\c\nd\te\u0001é🎵""#).unwrap();
+        assert_eq!(value.as_str(), Some("a\"b\\c\nd\te\u{1}é🎵"));
+        assert_eq!(value.to_string(), r#""a\"b\\c\nd\te\u0001é🎵""#);
+    }
+
+    #[test]
+    fn numbers_json_cannot_hold_print_as_null() {
+        let value = Value::Array(vec![f64::NAN.into(), f64::INFINITY.into(), 1.5.into()]);
+        assert_eq!(value.to_string(), "[null,null,1.5]");
+    }
+
+    #[test]
+    fn malformed_text_names_its_position() {
+        let error = |text: &str| Value::parse(text).unwrap_err();
+        assert_eq!(error("{} x"), "expected the end of the text at byte 3");
+        assert_eq!(error(""), "unexpected end of the text at byte 0");
+        assert_eq!(error("[1,]"), "expected a value at byte 3");
+        assert_eq!(error("{1:2}"), "expected a key at byte 1");
+        assert_eq!(error(r#"{"a":1 "b":2}"#), "expected ',' or '}' at byte 7");
+        assert_eq!(error(r#""abc"#), "unterminated string at byte 4");
+        assert_eq!(error(r#""\x""#), "invalid escape at byte 3");
+        assert_eq!(error(r#""\ud83c""#), "unpaired surrogate at byte 7");
+        assert_eq!(error(r#""\u12g4""#), "invalid \\u escape at byte 3");
+        assert_eq!(error("-"), "invalid number at byte 0");
+    }
+}
@@ -0,0 +1,149 @@
+//! The latency from the inputs to an output, shared between the ring buffers and the effects.
+//!
+//! `--latency` is the delay from input to output as a whole. Effects that delay the signal, such
+//! as long FIR filters, noise reduction or plugins, report it through
+//! [`Effect::latency_frames`](crate::effects::Effect::latency_frames), and the rings get what the
+//! chain leaves of the latency, so that the total stays as asked. A ring can't hold less than one
+//! block of the output, so a chain that takes more than that leaves goes over the budget, and
+//! lengthens the total instead.
+
+use cpal::StreamConfig;
+
+use crate::error::EngineError;
+use crate::ring;
+
+/// How the latency to one output is spent, in frames of the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencyBudget {
+    /// The latency asked for.
+    target: usize,
+    /// The least the rings can delay by, one block.
+    block: usize,
+    chain: usize,
+    sample_rate: u32,
+}
+
+impl LatencyBudget {
+    /// The budget of `latency` milliseconds to an output of `output`, whose effects delay the
+    /// signal by `chain` frames. Fails for latencies that aren't a positive number, or are under
+    /// one block of the output.
+    pub fn new(latency: f32, output: &StreamConfig, chain: usize) -> Result<Self, EngineError> {
+        if !(latency >= 0.0 && latency.is_finite()) {
+            return Err(EngineError::InvalidArgument(format!(
+                "the latency must be a positive number of milliseconds, got {}",
+                latency
+            )));
+        }
+        let rate = output.sample_rate.0;
+        let target = (latency as f64 / 1_000.0 * rate as f64) as usize;
+        let block = ring::block_frames(output);
+        if target < block {
+            return Err(EngineError::InvalidArgument(format!(
+                "a latency of {} ms is under one block of the output, {} frames or {:.1} ms: \
+                 raise `--latency` or lower `--buffer-size`",
+                latency,
+                block,
+                block as f64 * 1_000.0 / rate as f64
+            )));
+        }
+        Ok(LatencyBudget {
+            target,
+            block,
+            chain,
+            sample_rate: rate,
+        })
+    }
+
+    /// Frames the effects delay the signal by.
+    pub fn chain_frames(&self) -> usize {
+        self.chain
+    }
+
+    /// Frames the rings delay the signal by.
+    pub fn ring_frames(&self) -> usize {
+        self.target.saturating_sub(self.chain).max(self.block)
+    }
+
+    /// Frames from the inputs to the output.
+    pub fn total_frames(&self) -> usize {
+        self.ring_frames() + self.chain
+    }
+
+    /// Whether the effects leave the rings less than a block, so that the total is over the
+    /// latency asked for.
+    pub fn is_over(&self) -> bool {
+        self.total_frames() > self.target
+    }
+
+    /// Milliseconds of the output that `frames` last.
+    pub fn milliseconds(&self, frames: usize) -> f64 {
+        frames as f64 * 1_000.0 / self.sample_rate as f64
+    }
+
+    /// The budget in words, e.g. "150.0 ms: 139.3 ms in the rings and 10.7 ms in the effects".
+    pub fn describe(&self) -> String {
+        format!(
+            "{:.1} ms: {:.1} ms in the rings and {:.1} ms in the effects",
+            self.milliseconds(self.total_frames()),
+            self.milliseconds(self.ring_frames()),
+            self.milliseconds(self.chain)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An output at 48 kHz, of blocks of 512 frames.
+    fn output() -> StreamConfig {
+        StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(48_000),
+            buffer_size: cpal::BufferSize::Fixed(512),
+        }
+    }
+
+    #[test]
+    fn the_rings_get_what_the_chain_leaves() {
+        let budget = LatencyBudget::new(150.0, &output(), 512).unwrap();
+        assert_eq!(budget.chain_frames(), 512);
+        assert_eq!(budget.ring_frames(), 7_200 - 512);
+        assert_eq!(budget.total_frames(), 7_200);
+        assert!(!budget.is_over());
+        assert_eq!(
+            budget.describe(),
+            "150.0 ms: 139.3 ms in the rings and 10.7 ms in the effects"
+        );
+    }
+
+    #[test]
+    fn chains_longer_than_the_latency_go_over_it() {
+        let budget = LatencyBudget::new(20.0, &output(), 2_048).unwrap();
+        assert_eq!(budget.ring_frames(), 512);
+        assert_eq!(budget.total_frames(), 2_560);
+        assert!(budget.is_over());
+    }
+
+    #[test]
+    fn latencies_that_cannot_be_had_are_rejected() {
+        let error = |latency| {
+            LatencyBudget::new(latency, &output(), 0)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(-1.0),
+            "the latency must be a positive number of milliseconds, got -1"
+        );
+        assert_eq!(
+            error(f32::NAN),
+            "the latency must be a positive number of milliseconds, got NaN"
+        );
+        assert_eq!(
+            error(10.0),
+            "a latency of 10 ms is under one block of the output, 512 frames or 10.7 ms: \
+             raise `--latency` or lower `--buffer-size`"
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! Conversions between decibels and linear gain.
+
+/// Converts a gain in decibels to a linear factor.
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Converts a linear factor to a gain in decibels, -inf for 0.
+pub fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decibels_and_gains_convert_both_ways() {
+        assert_eq!(db_to_gain(0.0), 1.0);
+        assert!((db_to_gain(-20.0) - 0.1).abs() < 1e-7);
+        assert!((gain_to_db(0.5) + 6.0206).abs() < 1e-4);
+        assert_eq!(gain_to_db(0.0), f32::NEG_INFINITY);
+        for db in [-60.0, -6.0, 0.0, 12.0] {
+            assert!((gain_to_db(db_to_gain(db)) - db).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! Low-frequency oscillators modulating the parameters of an effect chain.
+//!
+//! An LFO is described on the command line as colon-separated `key=value` pairs, e.g.
+//! `rate=0.2:shape=triangle:target=filter.0.freq:depth=800:center=1200`:
+//!
+//! - `rate`: frequency in Hz.
+//! - `shape`: `sine` (default), `triangle`, `saw`, `square` or `random` (a random value held for
+//!   each cycle).
+//! - `target`: one or more comma-separated parameter names, as in
+//!   [`ParamLayout`](crate::params::ParamLayout).
+//! - `depth`: amplitude of the swing, in the units of the targets.
+//! - `center`: value the targets swing around. By default they swing around their current value,
+//!   which the command line or automation sets.
+//!
+//! The chain evaluates its LFOs once per block on the audio thread and offsets the targets before
+//! passing them to the effects, which glide to them as to any other change.
+
+use std::f64::consts::TAU;
+use std::str::FromStr;
+
+use anyhow::bail;
+
+use crate::params::ParamLayout;
+
+/// Waveform of an [`Lfo`].
+#[derive(Clone, Copy, Debug)]
+pub enum Shape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// A new random value at the start of every cycle, held until the next.
+    Random,
+}
+
+impl FromStr for Shape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sine" => Ok(Shape::Sine),
+            "triangle" => Ok(Shape::Triangle),
+            "saw" => Ok(Shape::Saw),
+            "square" => Ok(Shape::Square),
+            "random" => Ok(Shape::Random),
+            _ => Err(format!(
+                "unknown LFO shape \"{}\", expected sine, triangle, saw, square or random",
+                s
+            )),
+        }
+    }
+}
+
+/// An LFO as given on the command line, before its targets are resolved.
+#[derive(Clone, Debug)]
+pub struct LfoSpec {
+    pub rate: f32,
+    pub shape: Shape,
+    pub targets: Vec<String>,
+    pub depth: f32,
+    pub center: Option<f32>,
+}
+
+impl FromStr for LfoSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut rate, mut shape, mut targets, mut depth, mut center) =
+            (None, Shape::Sine, Vec::new(), None, None);
+        let number = |key: &str, value: &str| {
+            value
+                .parse::<f32>()
+                .ok()
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| format!("invalid LFO {} \"{}\"", key, value))
+        };
+        for pair in s.split(':') {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(format!("expected `key=value` in LFO, got \"{}\"", pair));
+            };
+            match key {
+                "rate" => rate = Some(number(key, value)?),
+                "shape" => shape = value.parse()?,
+                "target" => targets.extend(value.split(',').map(str::to_string)),
+                "depth" => depth = Some(number(key, value)?),
+                "center" => center = Some(number(key, value)?),
+                _ => return Err(format!("unknown LFO setting \"{}\"", key)),
+            }
+        }
+        let rate = rate.ok_or("missing LFO rate")?;
+        if rate <= 0.0 {
+            return Err(format!("LFO rate must be positive, got {}", rate));
+        }
+        if targets.is_empty() {
+            return Err("missing LFO target".to_string());
+        }
+        Ok(LfoSpec {
+            rate,
+            shape,
+            targets,
+            depth: depth.ok_or("missing LFO depth")?,
+            center,
+        })
+    }
+}
+
+/// An oscillator offsetting some parameters of a chain.
+pub struct Lfo {
+    shape: Shape,
+    depth: f32,
+    center: Option<f32>,
+    targets: Vec<usize>,
+    /// Cycles per second, and advanced per frame.
+    rate: f32,
+    step: f64,
+    /// Position in the current cycle, in [0, 1).
+    phase: f64,
+    held: f32,
+    random: u32,
+}
+
+impl Lfo {
+    /// Creates an LFO for a stream at `sample_rate`, failing if a target isn't in `layout`.
+    pub fn new(spec: &LfoSpec, layout: &ParamLayout, sample_rate: u32) -> anyhow::Result<Self> {
+        let mut targets = Vec::new();
+        for target in &spec.targets {
+            match layout.find(target) {
+                Some(index) => targets.push(index),
+                None if layout.is_empty() => {
+                    bail!(
+                        "unknown LFO target \"{}\": the effect chain has no parameters",
+                        target
+                    )
+                }
+                None => bail!(
+                    "unknown LFO target \"{}\", the parameters of the effect chain are {}",
+                    target,
+                    layout
+                        .names()
+                        .iter()
+                        .map(|x| format!("\"{}\"", x))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        }
+        let mut lfo = Lfo {
+            shape: spec.shape,
+            depth: spec.depth,
+            center: spec.center,
+            targets,
+            rate: spec.rate,
+            step: spec.rate as f64 / sample_rate as f64,
+            phase: 0.0,
+            held: 0.0,
+            random: 0x9e37_79b9,
+        };
+        lfo.held = lfo.next_random();
+        Ok(lfo)
+    }
+
+    /// Keeps the rate of the LFO on a stream now at `sample_rate`, and its phase.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.step = self.rate as f64 / sample_rate as f64;
+    }
+
+    /// Positions of the targets in the layout.
+    pub fn targets(&self) -> &[usize] {
+        &self.targets
+    }
+
+    /// The value the targets swing around instead of their own, if any.
+    pub fn center(&self) -> Option<f32> {
+        self.center
+    }
+
+    /// Current offset of the targets, between `-depth` and `depth`.
+    pub fn offset(&self) -> f32 {
+        let phase = self.phase as f32;
+        let value = match self.shape {
+            Shape::Sine => (self.phase * TAU).sin() as f32,
+            Shape::Triangle => 1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs(),
+            Shape::Saw => 2.0 * phase - 1.0,
+            Shape::Square if phase < 0.5 => 1.0,
+            Shape::Square => -1.0,
+            Shape::Random => self.held,
+        };
+        value * self.depth
+    }
+
+    /// Moves the oscillator `frames` frames forward.
+    pub fn advance(&mut self, frames: usize) {
+        self.phase += self.step * frames as f64;
+        if self.phase >= 1.0 {
+            self.phase = self.phase.fract();
+            self.held = self.next_random();
+        }
+    }
+
+    /// A xorshift value in [-1, 1], the same sequence on every run.
+    fn next_random(&mut self) -> f32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> ParamLayout {
+        let mut layout = ParamLayout::default();
+        layout.add("filter", 0, &["freq", "q"]);
+        layout.add("gain", 0, &["db"]);
+        layout
+    }
+
+    /// An LFO of `shape` on a stream of 4 frames a second, a quarter of a cycle per frame.
+    fn quarters(shape: &str) -> Vec<f32> {
+        let spec = format!("rate=1:shape={}:target=gain:depth=2", shape);
+        let mut lfo = Lfo::new(&spec.parse().unwrap(), &layout(), 4).unwrap();
+        (0..4)
+            .map(|_| {
+                let offset = lfo.offset();
+                lfo.advance(1);
+                offset
+            })
+            .collect()
+    }
+
+    #[test]
+    fn specs_parse_every_setting() {
+        let text = "rate=0.2:shape=triangle:target=filter.0.freq,gain:depth=800:center=1200";
+        let spec: LfoSpec = text.parse().unwrap();
+        assert_eq!(spec.rate, 0.2);
+        assert!(matches!(spec.shape, Shape::Triangle));
+        assert_eq!(spec.targets, ["filter.0.freq", "gain"]);
+        assert_eq!((spec.depth, spec.center), (800.0, Some(1200.0)));
+        let spec: LfoSpec = "target=gain:depth=1:rate=2".parse().unwrap();
+        assert!(matches!(spec.shape, Shape::Sine));
+        assert_eq!(spec.center, None);
+    }
+
+    #[test]
+    fn malformed_specs_say_what_is_wrong() {
+        let err = |x: &str| x.parse::<LfoSpec>().unwrap_err();
+        assert_eq!(err("rate=1:depth=1"), "missing LFO target");
+        assert_eq!(err("target=gain:depth=1"), "missing LFO rate");
+        assert_eq!(err("rate=1:target=gain"), "missing LFO depth");
+        assert_eq!(
+            err("rate=0:target=gain:depth=1"),
+            "LFO rate must be positive, got 0"
+        );
+        assert_eq!(err("rate=fast"), "invalid LFO rate \"fast\"");
+        assert_eq!(err("rate=1:depth=inf"), "invalid LFO depth \"inf\"");
+        assert_eq!(err("rate=1:speed=2"), "unknown LFO setting \"speed\"");
+        assert_eq!(
+            err("rate=1:gain"),
+            "expected `key=value` in LFO, got \"gain\""
+        );
+        assert!(err("rate=1:shape=wobble").starts_with("unknown LFO shape \"wobble\""));
+    }
+
+    #[test]
+    fn shapes_swing_between_minus_and_plus_the_depth() {
+        let close = |a: Vec<f32>, b: [f32; 4]| a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-6);
+        assert!(close(quarters("sine"), [0.0, 2.0, 0.0, -2.0]));
+        assert!(close(quarters("triangle"), [0.0, 2.0, 0.0, -2.0]));
+        assert!(close(quarters("saw"), [-2.0, -1.0, 0.0, 1.0]));
+        assert!(close(quarters("square"), [2.0, 2.0, -2.0, -2.0]));
+    }
+
+    #[test]
+    fn random_values_hold_for_a_cycle() {
+        let spec = "rate=1:shape=random:target=gain:depth=3".parse().unwrap();
+        let mut lfo = Lfo::new(&spec, &layout(), 4).unwrap();
+        let mut cycles = Vec::new();
+        for _ in 0..8 {
+            let first = lfo.offset();
+            for _ in 0..4 {
+                assert_eq!(lfo.offset(), first);
+                lfo.advance(1);
+            }
+            assert!(first.abs() <= 3.0);
+            cycles.push(first);
+        }
+        cycles.dedup();
+        assert_eq!(cycles.len(), 8);
+    }
+
+    #[test]
+    fn targets_resolve_against_the_layout() {
+        let spec = "rate=1:target=filter.q,gain:depth=1".parse().unwrap();
+        assert_eq!(
+            Lfo::new(&spec, &layout(), 48_000).unwrap().targets(),
+            [1, 2]
+        );
+        let spec = "rate=1:target=delay:depth=1".parse().unwrap();
+        let err = Lfo::new(&spec, &layout(), 48_000).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "unknown LFO target \"delay\", the parameters of the effect chain are \
+             \"filter.0.freq\", \"filter.0.q\", \"gain.0.db\""
+        );
+        let err = Lfo::new(&spec, &ParamLayout::default(), 48_000)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "unknown LFO target \"delay\": the effect chain has no parameters"
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Building blocks of the monitor: device selection, stream configuration, the ring buffers
+//! between the streams and the effect chain applied to the monitor feed.
+
+pub mod adapter;
+pub mod aec;
+pub mod audition;
+pub mod automation;
+pub mod binaural;
+pub mod buffer;
+pub mod bypass;
+pub mod click;
+pub mod compressor;
+pub mod config;
+pub mod control;
+pub mod convolve;
+pub mod correlation;
+#[cfg(unix)]
+pub mod daemon;
+pub mod denormal;
+pub mod devices;
+pub mod dither;
+pub mod downmix;
+pub mod ducker;
+pub mod effects;
+pub mod envelope;
+pub mod error;
+pub mod events;
+pub mod fade;
+pub mod fanout;
+pub mod fft;
+pub mod flac;
+pub mod golden;
+pub mod goniometer;
+pub mod guard;
+pub mod headroom;
+pub mod insert;
+pub mod json;
+pub mod latency;
+pub mod level;
+pub mod lfo;
+pub mod logging;
+pub mod loopback;
+pub mod looper;
+pub mod loudness;
+pub mod meter;
+pub mod mixer;
+pub mod net;
+pub mod null;
+pub mod overload;
+pub mod params;
+pub mod playback;
+pub mod png;
+pub mod priority;
+pub mod profile;
+pub mod quantum;
+pub mod rate;
+pub mod record;
+pub mod response;
+pub mod retro;
+pub mod ring;
+pub mod routing;
+pub mod rt60;
+pub mod sample;
+pub mod selftest;
+pub mod session;
+pub mod sidechain;
+pub mod simd;
+pub mod stats;
+pub mod status;
+pub mod stretch;
+pub mod strip;
+pub mod sweep;
+pub mod switch;
+pub mod takes;
+pub mod thdn;
+pub mod watchdog;
+pub mod wav;
@@ -0,0 +1,363 @@
+//! Logging through `tracing`, to stderr, so that stdout only carries the status line and the
+//! results of the measurements. `RUST_LOG` filters it, at `info` by default. The warnings also go
+//! to the session log, if there is one, whatever the filter. Quiet runs only log the warnings and
+//! the errors until the run ends, and then the summary as well.
+//!
+//! Audio threads never log themselves, since formatting and writing can block. They push
+//! [`AudioEvent`]s to an [`AudioLog`], a small ring of their own, and a logging thread drains
+//! every ring into `tracing`. Events that don't fit in a full ring are dropped.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use ringbuf::traits::{Consumer, Producer, Split};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+use crate::session::{Session, SessionLayer};
+
+/// Events every ring holds until the logging thread comes by.
+const CAPACITY: usize = 64;
+/// How often the logging thread drains the rings.
+const INTERVAL: Duration = Duration::from_millis(50);
+
+/// How log lines are written: "pretty" for people, or "json" for one object per line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format \"{}\", expected pretty or json",
+                s
+            )),
+        }
+    }
+}
+
+/// The filter of `RUST_LOG`, or `info`.
+fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Installs the global subscriber, writing in `format` to stderr, and the warnings to `session`,
+/// only the warnings and the errors while `quiet`.
+pub fn init(
+    format: LogFormat,
+    session: Option<Arc<Session>>,
+    quiet: bool,
+) -> anyhow::Result<Quiet> {
+    let filter = match quiet {
+        true => EnvFilter::new("warn"),
+        false => env_filter(),
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_target(false);
+    let layer = match format {
+        LogFormat::Pretty => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .with(session.map(|x| SessionLayer(x).with_filter(LevelFilter::WARN)))
+        .try_init()
+        .map_err(anyhow::Error::msg)?;
+    Ok(Quiet(quiet.then_some(handle)))
+}
+
+/// The filter of a quiet run, to let the summary through once the run ends.
+pub struct Quiet(Option<reload::Handle<EnvFilter, Registry>>);
+
+impl Quiet {
+    /// Logs what `RUST_LOG` lets through again, if the run was quiet.
+    pub fn end(&self) {
+        if let Some(handle) = &self.0 {
+            let _ = handle.reload(env_filter());
+        }
+    }
+}
+
+/// Something an audio thread reports, copied into its ring without allocating.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioEvent {
+    /// An output found the ring buffer of an input empty before the end of its block.
+    Underrun {
+        input: &'static str,
+        output: &'static str,
+    },
+    /// An input found the ring buffer of an output full.
+    Overrun {
+        input: &'static str,
+        output: &'static str,
+    },
+    /// The recorder's ring buffer was too full for a block of the input.
+    RecorderBehind { input: &'static str },
+    /// The sidechain stopped sending, and the output keys from its own signal.
+    SidechainLost { output: &'static str },
+    /// The sidechain is sending again.
+    SidechainBack { output: &'static str },
+    /// The feedback suppressor placed a notch, `seconds` into the stream.
+    FeedbackNotch {
+        seconds: f32,
+        frequency: f32,
+        notch: usize,
+        notches: usize,
+    },
+    /// The feedback suppressor released a notch that had gone quiet.
+    FeedbackReleased { seconds: f32, frequency: f32 },
+    /// The feedback suppressor ran out of notches, and released its oldest.
+    FeedbackEvicted { seconds: f32, frequency: f32 },
+    /// The noise reducer learned a profile from `seconds` of input.
+    NoiseLearned { seconds: f32 },
+    /// The script effect ran over its time budget, and the block was passed through.
+    ScriptOverrun { seconds: f32 },
+    /// The script effect ran over its time budget too often, and is bypassed from now on.
+    ScriptBypassed { seconds: f32 },
+    /// The script effect failed, and is bypassed from now on.
+    ScriptFailed { seconds: f32 },
+    /// An effect took more scratch than it declared, and got what it did.
+    ScratchOverdrawn {
+        effect: &'static str,
+        samples: usize,
+        budget: usize,
+    },
+    /// A stream called back with a block of more frames than its callback was prepared for, and
+    /// only the first `prepared` went all the way through.
+    OversizeBlock {
+        stream: &'static str,
+        frames: usize,
+        prepared: usize,
+    },
+}
+
+impl AudioEvent {
+    /// Logs the event, from a thread allowed to.
+    pub fn emit(&self) {
+        match *self {
+            AudioEvent::Underrun { input, output } => tracing::warn!(
+                input,
+                output,
+                "the input fell behind the output: try increasing latency"
+            ),
+            AudioEvent::Overrun { input, output } => tracing::warn!(
+                input,
+                output,
+                "the output fell behind the input: try increasing latency"
+            ),
+            AudioEvent::RecorderBehind { input } => {
+                tracing::warn!(input, "the recorder fell behind the input")
+            }
+            AudioEvent::SidechainLost { output } => tracing::warn!(
+                output,
+                "no sidechain signal for the output, keying from its own signal"
+            ),
+            AudioEvent::SidechainBack { output } => {
+                tracing::info!(output, "the sidechain signal is back")
+            }
+            AudioEvent::FeedbackNotch {
+                seconds,
+                frequency,
+                notch,
+                notches,
+            } => tracing::info!(
+                seconds,
+                frequency,
+                notch,
+                notches,
+                "placed a notch on feedback"
+            ),
+            AudioEvent::FeedbackReleased { seconds, frequency } => {
+                tracing::info!(seconds, frequency, "released a feedback notch")
+            }
+            AudioEvent::FeedbackEvicted { seconds, frequency } => tracing::info!(
+                seconds,
+                frequency,
+                "out of feedback notches, released the oldest"
+            ),
+            AudioEvent::NoiseLearned { seconds } => {
+                tracing::info!(seconds, "learned a noise profile")
+            }
+            AudioEvent::ScriptOverrun { seconds } => tracing::warn!(
+                seconds,
+                "the script ran over its time budget, passing the block through"
+            ),
+            AudioEvent::ScriptBypassed { seconds } => tracing::warn!(
+                seconds,
+                "the script ran over its time budget again, bypassing it"
+            ),
+            AudioEvent::ScriptFailed { seconds } => {
+                tracing::warn!(seconds, "the script failed, bypassing it")
+            }
+            AudioEvent::ScratchOverdrawn {
+                effect,
+                samples,
+                budget,
+            } => tracing::warn!(
+                effect,
+                samples,
+                budget,
+                "an effect took more scratch than it declared, and got less than it asked for"
+            ),
+            AudioEvent::OversizeBlock {
+                stream,
+                frames,
+                prepared,
+            } => tracing::warn!(
+                stream,
+                frames,
+                prepared,
+                "the stream called back with a larger block than prepared for, and lost the rest"
+            ),
+        }
+    }
+}
+
+/// The ring an audio thread pushes its events to.
+pub struct AudioLog {
+    producer: Option<HeapProd<AudioEvent>>,
+}
+
+impl AudioLog {
+    /// A log that drops everything, e.g. for chains built only to be inspected.
+    pub fn disabled() -> Self {
+        AudioLog { producer: None }
+    }
+
+    pub fn push(&mut self, event: AudioEvent) {
+        if let Some(producer) = &mut self.producer {
+            let _ = producer.try_push(event);
+        }
+    }
+}
+
+/// The rings of every audio log, for the logging thread.
+#[derive(Default)]
+pub struct AudioLogs {
+    consumers: Vec<HeapCons<AudioEvent>>,
+}
+
+impl AudioLogs {
+    /// A new log, drained along with the others.
+    pub fn log(&mut self) -> AudioLog {
+        let (producer, consumer) = HeapRb::new(CAPACITY).split();
+        self.consumers.push(consumer);
+        AudioLog {
+            producer: Some(producer),
+        }
+    }
+
+    /// Logs every event waiting, returning how many there were.
+    pub fn drain(&mut self) -> usize {
+        let mut count = 0;
+        for consumer in &mut self.consumers {
+            while let Some(event) = consumer.try_pop() {
+                event.emit();
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Drains the logs on a thread of their own until it's stopped.
+    pub fn spawn(mut self) -> LogThread {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = running.clone();
+            std::thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    self.drain();
+                    std::thread::sleep(INTERVAL);
+                }
+                self.drain();
+            })
+        };
+        LogThread { running, thread }
+    }
+}
+
+/// The thread draining the audio logs.
+pub struct LogThread {
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl LogThread {
+    /// Stops the thread once it has logged what's left.
+    pub fn stop(self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::traits::Observer;
+
+    use super::*;
+
+    const UNDERRUN: AudioEvent = AudioEvent::Underrun {
+        input: "input stream",
+        output: "output stream",
+    };
+
+    #[test]
+    fn log_formats_parse() {
+        assert_eq!("pretty".parse(), Ok(LogFormat::Pretty));
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!(
+            "xml".parse::<LogFormat>(),
+            Err("unknown log format \"xml\", expected pretty or json".to_string())
+        );
+    }
+
+    #[test]
+    fn every_log_is_drained() {
+        let mut logs = AudioLogs::default();
+        let (mut first, mut second) = (logs.log(), logs.log());
+        first.push(UNDERRUN);
+        second.push(UNDERRUN);
+        second.push(AudioEvent::RecorderBehind {
+            input: "input stream",
+        });
+        assert_eq!(logs.drain(), 3);
+        assert_eq!(logs.drain(), 0);
+    }
+
+    #[test]
+    fn events_past_a_full_ring_are_dropped() {
+        let mut logs = AudioLogs::default();
+        let mut log = logs.log();
+        for _ in 0..CAPACITY * 2 {
+            log.push(UNDERRUN);
+        }
+        assert_eq!(logs.drain(), CAPACITY);
+        AudioLog::disabled().push(UNDERRUN);
+    }
+
+    #[test]
+    fn the_thread_logs_what_is_left_once_stopped() {
+        let mut logs = AudioLogs::default();
+        let mut log = logs.log();
+        let thread = logs.spawn();
+        log.push(UNDERRUN);
+        thread.stop();
+        let producer = log.producer.as_ref().unwrap();
+        assert_eq!(producer.occupied_len(), 0);
+    }
+}
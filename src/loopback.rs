@@ -0,0 +1,134 @@
+//! Measurements of an interface whose output is looped back to its input, e.g. with a cable:
+//! a test signal plays on the output device while the input device is captured.
+
+use std::f64::consts::TAU;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::StreamConfig;
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
+use ringbuf::HeapRb;
+
+use crate::events::{self, EngineEvent};
+use crate::fade;
+use crate::level;
+
+/// Length of the fades of the test signals, which keep them from clicking.
+const FADE: Duration = Duration::from_millis(10);
+
+/// A sine at a constant level, faded in and out.
+pub struct Tone {
+    /// Angular frequency, in radians per frame.
+    omega: f64,
+    amplitude: f32,
+    frame: usize,
+    /// Frames the tone lasts, and those each fade takes.
+    length: usize,
+    fade: usize,
+}
+
+impl Tone {
+    /// A tone of `frequency` Hz and `level_db` dBFS peak, lasting `duration` at `sample_rate`.
+    pub fn new(frequency: f64, level_db: f32, duration: Duration, sample_rate: u32) -> Self {
+        let frames = |x: Duration| (x.as_secs_f64() * sample_rate as f64) as usize;
+        Tone {
+            omega: TAU * frequency / sample_rate as f64,
+            amplitude: level::db_to_gain(level_db),
+            frame: 0,
+            length: frames(duration),
+            fade: frames(FADE).max(1),
+        }
+    }
+
+    /// Fills a block of interleaved frames with the same signal in every channel, and with
+    /// silence once the tone is over.
+    pub fn fill(&mut self, data: &mut [f32], channels: usize) {
+        for frame in data.chunks_mut(channels) {
+            let remaining = self.length.saturating_sub(self.frame);
+            let position = self.frame.min(remaining).min(self.fade) as f32 / self.fade as f32;
+            let phase = self.omega * self.frame as f64;
+            let value = self.amplitude * fade::gain(position) * phase.sin() as f32;
+            frame.fill(value);
+            self.frame += 1;
+        }
+    }
+}
+
+/// Plays `signal` on the output device for `duration`, capturing the input device for as long,
+/// and returns the interleaved input samples.
+pub fn play_and_capture(
+    output: (&cpal::Device, &StreamConfig),
+    input: (&cpal::Device, &StreamConfig),
+    duration: Duration,
+    mut signal: impl FnMut(&mut [f32], usize) + Send + 'static,
+) -> anyhow::Result<Vec<f32>> {
+    let (input_device, input_config) = input;
+    let (output_device, output_config) = output;
+    let samples = (duration.as_secs_f64() * input_config.sample_rate.0 as f64) as usize
+        * input_config.channels as usize;
+    let (mut producer, mut consumer) = HeapRb::<f32>::new(samples.max(1)).split();
+    let (events, errors) = mpsc::channel();
+
+    let input_stream = input_device.build_input_stream(
+        input_config,
+        move |data: &[f32], _: &cpal::InputCallbackInfo| {
+            producer.push_slice(data);
+        },
+        events::error_callback("input stream", events.clone()),
+        None,
+    )?;
+    let channels = output_config.channels as usize;
+    let output_stream = output_device.build_output_stream(
+        output_config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| signal(data, channels),
+        events::error_callback("output stream", events),
+        None,
+    )?;
+    input_stream.play()?;
+    output_stream.play()?;
+    std::thread::sleep(duration);
+    drop(output_stream);
+    drop(input_stream);
+    for event in errors.try_iter() {
+        let EngineEvent::StreamError {
+            stream, message, ..
+        } = event;
+        tracing::warn!(
+            stream,
+            "an error occurred during the measurement: {}",
+            message
+        );
+    }
+
+    let mut captured = vec![0.0; consumer.occupied_len()];
+    let popped = consumer.pop_slice(&mut captured);
+    captured.truncate(popped);
+    Ok(captured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tones_fade_in_and_out_then_fall_silent() {
+        // 100 ms at 1 kHz, with fades of 10 frames.
+        let mut tone = Tone::new(250.0, -6.0, Duration::from_millis(100), 1_000);
+        let mut data = vec![0.0; 2 * 120];
+        tone.fill(&mut data, 2);
+        let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+        let right: Vec<f32> = data.iter().skip(1).step_by(2).copied().collect();
+        assert_eq!(left, right);
+
+        // A quarter of a period per frame, 0, 1, 0, -1, times the fade.
+        let amplitude = level::db_to_gain(-6.0);
+        let expected = |n: usize| (TAU * 0.25 * n as f64).sin() as f32 * amplitude;
+        assert!((left[5] - expected(5) * fade::gain(0.5)).abs() < 1e-6);
+        for n in (12..88).step_by(4) {
+            assert!((left[n + 1] - amplitude).abs() < 1e-6, "{}", left[n + 1]);
+        }
+        assert!(left[97].abs() < amplitude * fade::gain(0.4));
+        assert!(left[100..].iter().all(|x| *x == 0.0));
+    }
+}
@@ -0,0 +1,426 @@
+//! A looper: record a loop of the live signal, play it back under it, and overdub layers.
+//!
+//! The control thread sends the presses of the loop keys to the audio thread through a small
+//! ring buffer, and the audio thread applies them in order at the start of its next block, so
+//! presses are never lost nor reordered, however fast they come:
+//!
+//! - `l` starts recording when the looper is empty, closes the loop and plays it when recording,
+//!   and toggles overdubbing when playing.
+//! - `L` clears the loop.
+//!
+//! The buffer is allocated up front for the longest loop. A loop is exactly as long as the
+//! recording was: the frames after it are crossfaded into its start while it plays the first
+//! time, so that it wraps around without a click, and overdubs fade in and out too. A stream
+//! that changes its rate resamples the loop to it, which allocates the buffer again.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+
+use ringbuf::traits::{Consumer, Producer, Split};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+
+use crate::adapter::Resampler;
+
+/// Length of the crossfades, in seconds.
+const FADE: f64 = 0.01;
+/// Presses that can wait for the audio thread.
+const QUEUE: usize = 64;
+
+/// What the loop keys ask for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// `l`: the next step of record, play, overdub.
+    Next,
+    /// `L`: back to an empty looper.
+    Clear,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Empty,
+    Recording,
+    Playing,
+    Overdubbing,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Empty => "empty",
+            State::Recording => "recording",
+            State::Playing => "playing",
+            State::Overdubbing => "overdubbing",
+        }
+    }
+}
+
+const STATES: [State; 4] = [
+    State::Empty,
+    State::Recording,
+    State::Playing,
+    State::Overdubbing,
+];
+
+/// State, length and rate of the loop, published by the audio thread.
+#[derive(Default)]
+struct Shared {
+    state: AtomicU8,
+    frames: AtomicU64,
+    sample_rate: AtomicU32,
+}
+
+/// The control thread's end of a [`Looper`].
+pub struct LoopRemote {
+    producer: HeapProd<Command>,
+    shared: Arc<Shared>,
+}
+
+impl LoopRemote {
+    /// Sends a press to the looper, failing if too many are waiting.
+    pub fn send(&mut self, command: Command) -> Result<(), String> {
+        self.producer
+            .try_push(command)
+            .map_err(|_| "the looper is not keeping up with the presses".to_string())
+    }
+
+    /// Describes the looper for the status line, e.g. "loop playing 4.0 s".
+    pub fn describe(&self) -> String {
+        let state = STATES[self.shared.state.load(Ordering::Relaxed) as usize];
+        let sample_rate = self.shared.sample_rate.load(Ordering::Relaxed);
+        let seconds = self.shared.frames.load(Ordering::Relaxed) as f64 / sample_rate as f64;
+        format!("loop {} {:.1} s", state.name(), seconds)
+    }
+}
+
+/// Records and plays back a loop of an interleaved stream.
+pub struct Looper {
+    channels: usize,
+    sample_rate: u32,
+    buffer: Vec<f32>,
+    fade_frames: usize,
+    feedback: f32,
+    state: State,
+    /// Frames in the loop, or recorded so far.
+    length: usize,
+    /// Frame played or recorded next.
+    position: usize,
+    /// Frames of the first pass whose crossfade with the frames after the loop is still due.
+    splice: usize,
+    /// How much of the input goes into the loop while overdubbing, ramping to 0 or 1.
+    dub: f32,
+    commands: Option<HeapCons<Command>>,
+    shared: Arc<Shared>,
+}
+
+impl Looper {
+    /// Creates a looper for a stream of `channels` channels at `sample_rate`, holding loops of up
+    /// to `max_seconds`. Overdubbing scales the layers already in the loop by `feedback`.
+    pub fn new(max_seconds: f32, feedback: f32, channels: usize, sample_rate: u32) -> Self {
+        let frames = (max_seconds.max(0.0) as f64 * sample_rate as f64) as usize;
+        Looper {
+            channels,
+            sample_rate,
+            buffer: vec![0.0; frames * channels],
+            fade_frames: ((FADE * sample_rate as f64) as usize).max(1),
+            feedback,
+            state: State::Empty,
+            length: 0,
+            position: 0,
+            splice: 0,
+            dub: 0.0,
+            commands: None,
+            shared: Arc::new(Shared {
+                sample_rate: AtomicU32::new(sample_rate),
+                ..Shared::default()
+            }),
+        }
+    }
+
+    /// Resamples the loop, or what's recorded of it, to `sample_rate`, and holds as long a loop
+    /// as before at it.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let ratio = sample_rate as f64 / self.sample_rate as f64;
+        let capacity = ((self.buffer.len() / self.channels) as f64 * ratio) as usize;
+        let mut buffer = Vec::with_capacity(capacity * self.channels);
+        if self.length > 0 {
+            let mut resampler = Resampler::new(self.channels, self.sample_rate, sample_rate);
+            resampler.process(&self.buffer[..self.length * self.channels], &mut buffer);
+        }
+        buffer.truncate(capacity * self.channels);
+        self.length = buffer.len() / self.channels;
+        buffer.resize(capacity * self.channels, 0.0);
+        self.buffer = buffer;
+        self.sample_rate = sample_rate;
+        self.fade_frames = ((FADE * sample_rate as f64) as usize).max(1);
+        self.splice = ((self.splice as f64 * ratio) as usize).min(self.fade_frames);
+        match self.state {
+            State::Recording if self.length * self.channels >= self.buffer.len() => {
+                self.state = self.close()
+            }
+            State::Playing | State::Overdubbing if self.length == 0 => self.state = State::Empty,
+            State::Playing | State::Overdubbing => {
+                self.position = ((self.position as f64 * ratio) as usize).min(self.length - 1)
+            }
+            _ => {}
+        }
+        self.shared
+            .sample_rate
+            .store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// Opens the way for another thread to send the presses.
+    pub fn remote(&mut self) -> LoopRemote {
+        let (producer, consumer) = HeapRb::new(QUEUE).split();
+        self.commands = Some(consumer);
+        LoopRemote {
+            producer,
+            shared: self.shared.clone(),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Frames in the loop, or recorded so far.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Applies a press right away.
+    pub fn apply(&mut self, command: Command) {
+        self.state = match (command, self.state) {
+            (Command::Clear, _) => {
+                self.length = 0;
+                State::Empty
+            }
+            (Command::Next, State::Empty) if self.buffer.is_empty() => State::Empty,
+            (Command::Next, State::Empty) => {
+                self.length = 0;
+                self.position = 0;
+                State::Recording
+            }
+            (Command::Next, State::Recording) => self.close(),
+            (Command::Next, State::Playing) => State::Overdubbing,
+            (Command::Next, State::Overdubbing) => State::Playing,
+        };
+    }
+
+    /// Ends the recording. A loop too short to crossfade is dropped.
+    fn close(&mut self) -> State {
+        if self.length < self.fade_frames * 2 {
+            self.length = 0;
+            return State::Empty;
+        }
+        self.position = 0;
+        self.splice = self.fade_frames;
+        self.dub = 0.0;
+        State::Playing
+    }
+
+    /// Records from and plays the loop into the interleaved block `data`, after applying the
+    /// presses sent since the last block.
+    pub fn process(&mut self, data: &mut [f32]) {
+        while let Some(command) = self.commands.as_mut().and_then(|x| x.try_pop()) {
+            self.apply(command);
+        }
+        for frame in data.chunks_exact_mut(self.channels) {
+            match self.state {
+                State::Empty => {}
+                State::Recording => self.record(frame),
+                State::Playing | State::Overdubbing => self.play(frame),
+            }
+        }
+        let state = STATES.iter().position(|x| *x == self.state).unwrap_or(0);
+        self.shared.state.store(state as u8, Ordering::Relaxed);
+        self.shared
+            .frames
+            .store(self.length as u64, Ordering::Relaxed);
+    }
+
+    fn record(&mut self, frame: &[f32]) {
+        let start = self.length * self.channels;
+        self.buffer[start..start + self.channels].copy_from_slice(frame);
+        self.length += 1;
+        // The loop closes by itself when the buffer is full.
+        if self.length * self.channels == self.buffer.len() {
+            self.state = self.close();
+        }
+    }
+
+    fn play(&mut self, frame: &mut [f32]) {
+        let start = self.position * self.channels;
+        let stored = &mut self.buffer[start..start + self.channels];
+        let target = if self.state == State::Overdubbing {
+            1.0
+        } else {
+            0.0
+        };
+        let ramp = 1.0 / self.fade_frames as f32;
+        self.dub = (self.dub + (target - self.dub).clamp(-ramp, ramp)).clamp(0.0, 1.0);
+        if self.splice > 0 {
+            // The first pass fades the start of the loop in, and the input carrying on from its
+            // end out over it, for the next passes.
+            let fade_in = (self.fade_frames - self.splice) as f32 / self.fade_frames as f32;
+            for (x, y) in frame.iter_mut().zip(stored.iter_mut()) {
+                let head = *y * fade_in;
+                *y = head + *x * (1.0 - fade_in);
+                *x += head;
+            }
+            self.splice -= 1;
+        } else {
+            let keep = 1.0 - self.dub * (1.0 - self.feedback);
+            for (x, y) in frame.iter_mut().zip(stored.iter_mut()) {
+                let played = *y;
+                *y = played * keep + *x * self.dub;
+                *x += played;
+            }
+        }
+        self.position = (self.position + 1) % self.length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A looper at 1 kHz, whose crossfades are 10 frames, holding up to 100 frames.
+    fn looper(feedback: f32) -> Looper {
+        Looper::new(0.1, feedback, 1, 1_000)
+    }
+
+    /// Records `frames` of `value`, and closes the loop.
+    fn record(looper: &mut Looper, value: f32, frames: usize) {
+        looper.apply(Command::Next);
+        looper.process(&mut vec![value; frames]);
+        looper.apply(Command::Next);
+    }
+
+    #[test]
+    fn the_loop_key_steps_through_record_play_and_overdub() {
+        let mut looper = looper(1.0);
+        looper.apply(Command::Next);
+        assert_eq!(looper.state(), State::Recording);
+        looper.process(&mut [0.5; 40]);
+        looper.apply(Command::Next);
+        assert_eq!((looper.state(), looper.length()), (State::Playing, 40));
+        looper.apply(Command::Next);
+        assert_eq!(looper.state(), State::Overdubbing);
+        looper.apply(Command::Next);
+        assert_eq!(looper.state(), State::Playing);
+        looper.apply(Command::Clear);
+        assert_eq!((looper.state(), looper.length()), (State::Empty, 0));
+    }
+
+    #[test]
+    fn loops_too_short_to_crossfade_are_dropped() {
+        let mut looper = looper(1.0);
+        record(&mut looper, 0.5, 19);
+        assert_eq!(looper.state(), State::Empty);
+        let mut looper = Looper::new(0.0, 1.0, 1, 1_000);
+        looper.apply(Command::Next);
+        assert_eq!(looper.state(), State::Empty);
+    }
+
+    #[test]
+    fn a_full_buffer_closes_the_loop() {
+        let mut looper = looper(1.0);
+        looper.apply(Command::Next);
+        looper.process(&mut [0.5; 150]);
+        assert_eq!((looper.state(), looper.length()), (State::Playing, 100));
+    }
+
+    #[test]
+    fn the_loop_plays_under_the_input_once_spliced() {
+        let mut looper = looper(1.0);
+        looper.apply(Command::Next);
+        looper.process(&mut (0..40).map(|x| x as f32).collect::<Vec<_>>());
+        looper.apply(Command::Next);
+        // The first pass crossfades the frames that carry on from the end into the start.
+        let mut first = vec![1.0; 40];
+        looper.process(&mut first);
+        assert_eq!(first[..2], [1.0, 1.1]);
+        assert_eq!(
+            first[10..],
+            (10..40).map(|x| x as f32 + 1.0).collect::<Vec<_>>()
+        );
+        let mut second = vec![0.0; 40];
+        looper.process(&mut second);
+        assert_eq!(second[0], 1.0);
+        assert_eq!(second[10..], (10..40).map(|x| x as f32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn overdubs_add_layers_over_the_loop_scaled_by_the_feedback() {
+        let mut looper = looper(0.5);
+        record(&mut looper, 0.5, 40);
+        looper.process(&mut [0.5; 40]);
+        looper.apply(Command::Next);
+        looper.process(&mut [0.25; 40]);
+        looper.apply(Command::Next);
+        // Past the ramps in and out of the overdub.
+        looper.process(&mut [0.0; 40]);
+        let mut data = [0.0; 40];
+        looper.process(&mut data);
+        assert!((data[20] - (0.5 * 0.5 + 0.25)).abs() < 1e-6, "{}", data[20]);
+    }
+
+    #[test]
+    fn presses_are_applied_in_order_at_the_next_block() {
+        let mut looper = looper(1.0);
+        let mut remote = looper.remote();
+        remote.send(Command::Next).unwrap();
+        assert_eq!(looper.state(), State::Empty);
+        looper.process(&mut [0.5; 40]);
+        assert_eq!(remote.describe(), "loop recording 0.0 s");
+        remote.send(Command::Next).unwrap();
+        remote.send(Command::Next).unwrap();
+        looper.process(&mut [0.5; 1]);
+        assert_eq!(looper.state(), State::Overdubbing);
+        for _ in 0..QUEUE {
+            remote.send(Command::Clear).unwrap();
+        }
+        assert_eq!(
+            remote.send(Command::Clear).unwrap_err(),
+            "the looper is not keeping up with the presses"
+        );
+    }
+
+    #[test]
+    fn a_change_of_rate_resamples_the_loop() {
+        let mut looper = Looper::new(1.0, 1.0, 1, 48_000);
+        let remote = looper.remote();
+        looper.apply(Command::Next);
+        let ramp: Vec<f32> = (0..4_800).map(|n| n as f32 / 4_800.0).collect();
+        looper.process(&mut ramp.clone());
+        looper.apply(Command::Next);
+        assert_eq!(looper.state(), State::Playing);
+        looper.process(&mut vec![0.0; 2_400]);
+        assert_eq!(remote.describe(), "loop playing 0.1 s");
+
+        looper.set_sample_rate(96_000);
+        assert_eq!(looper.state(), State::Playing);
+        assert!((9_590..=9_600).contains(&looper.length()));
+        assert_eq!(looper.buffer.len(), 96_000);
+        assert_eq!(looper.position, 4_800);
+        assert_eq!(looper.fade_frames, 960);
+        assert_eq!(remote.describe(), "loop playing 0.1 s");
+        // Halfway through the ramp, past the crossfade.
+        let mut silence = vec![0.0; 1];
+        looper.process(&mut silence);
+        assert!((silence[0] - 0.5).abs() < 1e-3, "{}", silence[0]);
+    }
+
+    #[test]
+    fn a_recording_carries_on_at_the_new_rate_up_to_the_same_length() {
+        let mut looper = Looper::new(0.1, 1.0, 1, 48_000);
+        looper.apply(Command::Next);
+        looper.process(&mut vec![0.5; 4_000]);
+        looper.set_sample_rate(44_100);
+        assert_eq!(looper.state(), State::Recording);
+        assert_eq!(looper.buffer.len(), 4_410);
+        looper.process(&mut vec![0.5; 1_000]);
+        assert_eq!(looper.state(), State::Playing);
+        assert_eq!(looper.length(), 4_410);
+    }
+}
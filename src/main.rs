@@ -3,175 +3,4422 @@
 //! Assumes that the input and output devices can use the same stream configuration and that they
 //! support the f32 sample format.
 //!
-//! Uses a delay of `LATENCY_MS` milliseconds in case the default input and output streams are not
+//! Uses a delay of `--latency` milliseconds in case the default input and output streams are not
 //! precisely synchronised.
 
-use clap::Parser;
-use cpal::{BufferSize, FrameCount};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use ringbuf::traits::{Consumer, Producer};
-use ringbuf::HeapRb;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use clap::{ArgGroup, Parser, Subcommand};
+use cpal::traits::DeviceTrait;
+use cpal::{BufferSize, SampleFormat, SampleRate, StreamConfig};
 use ringbuf::traits::Split;
+use ringbuf::traits::{Observer, Producer};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+
+use rust_dsp_experiments::adapter::Converter;
+use rust_dsp_experiments::aec::EchoCanceller;
+use rust_dsp_experiments::audition::{self, Audition, Band, BandSolo};
+use rust_dsp_experiments::automation::Automation;
+use rust_dsp_experiments::binaural::{Binaural, HrirSet};
+use rust_dsp_experiments::buffer::MAX_BLOCK_FRAMES;
+use rust_dsp_experiments::bypass::{AutoBypass, BypassSettings};
+use rust_dsp_experiments::click::Click;
+use rust_dsp_experiments::compressor::{Compressor, CompressorSettings};
+use rust_dsp_experiments::config::{self, Preferences};
+use rust_dsp_experiments::control::{self, Controls};
+use rust_dsp_experiments::correlation::{self, Correlation, CorrelationStats, CorrelationTap};
+#[cfg(unix)]
+use rust_dsp_experiments::daemon::{self, ControlSocket, Engine};
+use rust_dsp_experiments::denormal;
+use rust_dsp_experiments::devices::{self, DeviceSelector};
+use rust_dsp_experiments::dither::DitherMode;
+use rust_dsp_experiments::downmix::{Downmix, Layout};
+use rust_dsp_experiments::ducker::{DuckSettings, Ducker};
+use rust_dsp_experiments::effects::{
+    parse_rears, AdaptiveNotch, Agc, AgcSpec, AgcStats, BandSpec, BassManager, Biquad, CabIrs,
+    CabSim, CabSpec, ChannelDelay, ChannelDelaySpec, Crossover, CrossoverSpec, EffectChain, EqBand,
+    EqStats, FeedbackSuppressor, FilterKind, Fir, FirTaps, Gain, GateSpec, LearnTrigger,
+    LimiterSpec, NoiseReducer, NotchWindow, Route, RumbleFilter, RumbleSpec, ScriptEffect, Upmix,
+    UpmixLayout, BUTTERWORTH_Q, MAX_CAB_TAPS,
+};
+#[cfg(feature = "clap-plugins")]
+use rust_dsp_experiments::effects::{ClapPlugin, PluginSpec};
+use rust_dsp_experiments::error::EngineError;
+use rust_dsp_experiments::events::{Action, EngineEvent, ErrorPolicy};
+use rust_dsp_experiments::fade::{Drain, FadeOut, Fader, Tail};
+use rust_dsp_experiments::fanout::FanOut;
+use rust_dsp_experiments::flac;
+use rust_dsp_experiments::golden;
+use rust_dsp_experiments::goniometer::{Goniometer, GoniometerFrame};
+use rust_dsp_experiments::guard::{self, Diagnosis, GuardStats, NanGuard};
+use rust_dsp_experiments::headroom::{self, HeadroomMeter, HeadroomStats, Stage};
+use rust_dsp_experiments::insert::{
+    self, HwInsert, InsertLink, InsertReturn, InsertSend, InsertSpec, InsertStats,
+};
+use rust_dsp_experiments::json::Value;
+use rust_dsp_experiments::latency::LatencyBudget;
+use rust_dsp_experiments::level;
+use rust_dsp_experiments::lfo::{Lfo, LfoSpec};
+use rust_dsp_experiments::logging::{self, AudioEvent, AudioLog, AudioLogs, LogFormat, Quiet};
+use rust_dsp_experiments::loopback::{self, Tone};
+use rust_dsp_experiments::looper::{self, Looper};
+use rust_dsp_experiments::meter::{self, LevelStats};
+use rust_dsp_experiments::mixer::Mixer;
+use rust_dsp_experiments::net::{self, NetSource};
+use rust_dsp_experiments::null::{self, NullMeter, NullStats, NullTap};
+use rust_dsp_experiments::overload::{self, OverloadDetector, OverloadStats};
+use rust_dsp_experiments::params::{ParamLayout, ParamStore, ParamWriter};
+use rust_dsp_experiments::playback::{self, Boundary, LoopRegion, Region, Track};
+use rust_dsp_experiments::priority::{self, Promotion};
+use rust_dsp_experiments::profile::ProfileStats;
+use rust_dsp_experiments::quantum::Quantum;
+use rust_dsp_experiments::rate::{RateWatch, StreamRate};
+use rust_dsp_experiments::record::{
+    self, ChannelPick, CueMarkers, Format, GateSettings, Normalize, RateChange, RecordFeed,
+    RecordSettings, RecordSummary,
+};
+use rust_dsp_experiments::response;
+use rust_dsp_experiments::retro::RetroBuffer;
+use rust_dsp_experiments::ring::{self, FillBand, FillMeter, FillStats, Prime, RingSize};
+use rust_dsp_experiments::routing::Solo;
+use rust_dsp_experiments::rt60;
+use rust_dsp_experiments::sample::Sample;
+use rust_dsp_experiments::selftest::SelfTest;
+use rust_dsp_experiments::session::{self, Session, StreamEntry};
+use rust_dsp_experiments::sidechain::{self, KeyReceiver, KeySender};
+use rust_dsp_experiments::simd;
+use rust_dsp_experiments::stats::{AtomicF32, XrunCounters};
+use rust_dsp_experiments::status::{self, StatusLine};
+use rust_dsp_experiments::stretch;
+use rust_dsp_experiments::strip::{ChannelStrip, StripPreset};
+use rust_dsp_experiments::sweep::{self, Sweep, SweepPlayer};
+use rust_dsp_experiments::switch::{
+    DeviceOpener, Direction, InputPort, OutputPort, Phase, Port, StreamBuilder, Switch,
+    SwitchRequest,
+};
+use rust_dsp_experiments::takes::{TakeControl, TakeEvent, TakeState, Takes};
+use rust_dsp_experiments::thdn;
+use rust_dsp_experiments::watchdog::{Restartable, Watchdog};
+use rust_dsp_experiments::wav;
+
+/// How long the monitor runs before closing.
+const RUN_TIME: Duration = Duration::from_secs(3);
+
+#[cfg(any(debug_assertions, feature = "allocation-counter"))]
+#[global_allocator]
+static ALLOCATOR: rust_dsp_experiments::selftest::CountingAllocator =
+    rust_dsp_experiments::selftest::CountingAllocator;
+
+/// How much the recorder thread can lag behind the input before samples are lost.
+const RECORD_BUFFER: Duration = Duration::from_secs(2);
+
+/// How long to wait for the outputs to drain or fade out beyond the time it takes, e.g. for a
+/// stream that calls back with long blocks.
+const FADE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How much the correlation thread can lag behind the first input before blocks are dropped.
+const CORRELATION_BUFFER: Duration = Duration::from_millis(500);
+
+/// Size of the goniometer `ctl goniometer --ascii` draws, in characters.
+#[cfg(unix)]
+const GONIOMETER_WIDTH: usize = 41;
+#[cfg(unix)]
+const GONIOMETER_HEIGHT: usize = 21;
+
+/// How much the null test thread can lag behind the first output before blocks are dropped.
+const NULL_BUFFER: Duration = Duration::from_secs(2);
+
+/// The commands soloing the first channels of the first input, and how they're listed.
+const SOLO_KEYS: [&str; 8] = ["1", "2", "3", "4", "5", "6", "7", "8"];
+const SOLO_USAGES: [&str; 8] = ["1", "1-2", "1-3", "1-4", "1-5", "1-6", "1-7", "1-8"];
+
+/// How long a measurement plays before capturing, for the loopback latency and the fade-in.
+const MEASURE_SETTLE: Duration = Duration::from_secs(1);
+
+/// Windows THD+N is averaged over, and their length.
+const THDN_WINDOWS: usize = 8;
+const THDN_WINDOW: Duration = Duration::from_millis(250);
+
+/// The sweep of the impulse response measurements, and how long the capture goes on after it,
+/// for the loopback latency and the decay.
+const SWEEP_LENGTH: Duration = Duration::from_secs(3);
+const SWEEP_TAIL: Duration = Duration::from_secs(2);
+/// Band the responses are given over. The sweep goes a little beyond, so that its fades don't
+/// show at the ends.
+const RESPONSE_LOW: f64 = 20.0;
+const RESPONSE_HIGH: f64 = 20_000.0;
+
+/// Frequency the gain of a response is given at, and its -3 dB points found from.
+const REFERENCE_HZ: f64 = 1_000.0;
+
+/// How much the network thread can lag behind the first output before blocks are dropped.
+const NET_BUFFER: Duration = Duration::from_millis(500);
+
+/// The exit codes [`exit_code`] gives, for `--help`.
+const EXIT_CODES: &str = "Exit codes:
+  0  success
+  1  any other failure, e.g. a file that can't be read
+  2  invalid arguments
+  3  a device or driver not found
+  4  a configuration the device doesn't support
+  5  a stream that failed to build, to start, or to keep running";
 
-// TODO: use dasp for more powerful DSP
 // TODO: Add link to CPAL README for ASIO setup
 // TODO: Add `cargo run --release --features jack (or asio)` to doc
 
-enum Driver {
-    Default,
-    #[cfg(target_os = "windows")]
-    Asio,
-    #[cfg(target_os = "linux")]
-    Jack,
+/// Sample type the effect chains run in.
+#[derive(Clone, Copy)]
+enum Precision {
+    Single,
+    #[cfg(feature = "double-precision")]
+    Double,
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(Precision::Single),
+            #[cfg(feature = "double-precision")]
+            "double" => Ok(Precision::Double),
+            #[cfg(not(feature = "double-precision"))]
+            "double" => Err("double precision needs the `double-precision` feature".to_string()),
+            _ => Err(format!("unsupported precision \"{}\"", s)),
+        }
+    }
+}
+
+/// What `--measure` measures.
+#[derive(Clone, Copy)]
+enum Measurement {
+    Thdn,
+    Response,
+    Rt60,
+}
+
+impl FromStr for Measurement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "thdn" => Ok(Measurement::Thdn),
+            "response" => Ok(Measurement::Response),
+            "rt60" => Ok(Measurement::Rt60),
+            _ => Err(format!("unsupported measurement \"{}\"", s)),
+        }
+    }
+}
+
+/// File format of the recordings.
+#[derive(Clone, Copy)]
+enum RecordFormat {
+    Wav,
+    Flac,
+}
+
+impl FromStr for RecordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wav" => Ok(RecordFormat::Wav),
+            "flac" => Ok(RecordFormat::Flac),
+            _ => Err(format!("unsupported recording format \"{}\"", s)),
+        }
+    }
+}
+
+struct Input {
+    label: &'static str,
+    device: cpal::Device,
+    config: StreamConfig,
+    format: SampleFormat,
+    gain: f32,
+}
+
+/// Files the effect chains need, read once for all of them.
+struct ChainFiles {
+    correction: Option<FirTaps>,
+    fir_effect: Option<FirTaps>,
+    cab: Option<CabIrs>,
+}
+
+impl ChainFiles {
+    fn load(settings: &Settings) -> anyhow::Result<Self> {
+        let correction = match &settings.fir {
+            Some(path) => {
+                let mut taps = load_fir(path, settings.fir_max_taps)?;
+                if let Some(right) = &settings.fir_right {
+                    let right = load_fir(right, settings.fir_max_taps)?;
+                    if taps.channels.len() != 1 || right.channels.len() != 1 {
+                        return Err(EngineError::InvalidArgument(
+                            "`--fir` and `--fir-right` must each hold a single filter".to_string(),
+                        )
+                        .into());
+                    }
+                    if taps.sample_rate != right.sample_rate {
+                        anyhow::bail!(
+                            "the left and right correction filters have different sample rates"
+                        );
+                    }
+                    taps.channels.extend(right.channels);
+                }
+                tracing::info!(
+                    "correcting the outputs with {} taps from \"{}\"",
+                    taps.len(),
+                    path.display()
+                );
+                Some(taps)
+            }
+            None => None,
+        };
+        let fir_effect = match &settings.fir_effect {
+            Some(path) => {
+                let mut taps = load_fir(path, settings.fir_max_taps)?;
+                let gain = level::db_to_gain(settings.fir_gain);
+                for x in taps.channels.iter_mut().flatten() {
+                    *x *= gain;
+                }
+                tracing::info!(
+                    "filtering the monitor feed with {} taps from \"{}\"",
+                    taps.len(),
+                    path.display()
+                );
+                Some(taps)
+            }
+            None => None,
+        };
+        let cab = match &settings.cab {
+            Some(spec) => {
+                let irs = CabIrs::load(&spec.source)?;
+                if irs.close.len() > MAX_CAB_TAPS {
+                    tracing::warn!(
+                        "the cabinet IR has {} taps, cut to {}",
+                        irs.close.len(),
+                        MAX_CAB_TAPS
+                    );
+                }
+                Some(irs)
+            }
+            None => None,
+        };
+        Ok(ChainFiles {
+            correction,
+            fir_effect,
+            cab,
+        })
+    }
 }
 
+/// What only the first output's chain gets: the stats it publishes, and the hardware insert.
+#[derive(Default)]
+struct ChainLinks {
+    agc: Option<Arc<AgcStats>>,
+    eq: Option<Arc<EqStats>>,
+    insert: Option<InsertLink>,
+    headroom: Option<Arc<HeadroomStats>>,
+}
+
+/// Reads an FIR filter, failing if it's longer than `max_taps`.
+fn load_fir(path: &Path, max_taps: usize) -> anyhow::Result<FirTaps> {
+    let taps = FirTaps::load(path)?;
+    if taps.len() > max_taps {
+        anyhow::bail!(
+            "\"{}\" has {} taps, more than the maximum of {}: shorten the filter when generating \
+             it, e.g. with a shorter window, or raise `--fir-max-taps`",
+            path.display(),
+            taps.len(),
+            max_taps
+        );
+    }
+    Ok(taps)
+}
+
+struct Output {
+    label: &'static str,
+    device: cpal::Device,
+    config: StreamConfig,
+    format: SampleFormat,
+}
+
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, after_help = EXIT_CODES)]
+#[command(group(ArgGroup::new("recording").args(["record", "record_dir"])))]
 struct Settings {
-    buffer_size: i32,
-    input_device: String,
-    output_device: String,
-    driver: Driver,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Buffer size of all streams, in frames, if the devices support it.
+    #[arg(long, default_value_t = 128)]
+    buffer_size: u32,
+    /// Sample rate all streams must run at, in Hz. By default each device picks its own.
+    #[arg(long)]
+    sample_rate: Option<u32>,
+    /// Delay between input and output, in milliseconds.
+    #[arg(long, default_value_t = 150.0)]
+    latency: f32,
+    /// How much of the latency the ring buffers start with in silence: "full", "half", or "none"
+    /// for the least delay, with underruns until the input catches up.
+    #[arg(long, default_value = "full")]
+    prime: Prime,
+    /// Fill the ring buffers should stay within, `<low-ms>:<high-ms>`, e.g. "120:180": a ring
+    /// whose mean fill stays outside it for a few seconds is warned about, with the way it
+    /// drifts, before it xruns. The fill is taken at every `--stats-interval`.
+    #[arg(long)]
+    ring_band: Option<FillBand>,
+    /// Run the effect chains on blocks of exactly this many frames, whatever sizes the devices
+    /// call back with, at the cost of as much latency.
+    #[arg(long)]
+    quantum: Option<usize>,
+    /// Input device: "default", a name, or an index from `--list-devices` such as "#3".
+    #[arg(long, default_value = "default")]
+    input_device: DeviceSelector,
+    /// Second input device, mixed with the first one into the monitor feed.
+    #[arg(long)]
+    input_device_2: Option<DeviceSelector>,
+    /// Gain applied to the second input device, in dB.
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    input_gain_2: f32,
+    /// Mix inputs of more than two channels down to stereo before the effects, from this
+    /// layout: "quad", "5.0", "5.1" or "7.1", in the WAVE channel order. Inputs with another
+    /// number of channels are mixed with equal weights.
+    #[arg(long)]
+    downmix: Option<Layout>,
+    /// Gain of the LFE in the downmix, in dB. By default it's left out.
+    #[arg(long, requires = "downmix", allow_negative_numbers = true)]
+    downmix_lfe: Option<f32>,
+    /// Render inputs for headphones instead, as speakers around the listener: every channel
+    /// through the left and right ear responses of its speaker, in the WAVE channel order of
+    /// stereo, quad, 5.0, 5.1 or 7.1. A spherical head model is used unless `--hrir-dir` is
+    /// given.
+    #[arg(long, conflicts_with = "downmix")]
+    binaural: bool,
+    /// Directory of measured HRIRs for `--binaural`: a stereo WAV file per speaker, of the left
+    /// and right ears, named after its azimuth in degrees counterclockwise from the front, e.g.
+    /// "30.wav" for the front left and "-110.wav" for the right surround.
+    #[arg(long, requires = "binaural")]
+    hrir_dir: Option<PathBuf>,
+    /// Output device: "default", a name, or an index from `--list-devices` such as "#3".
+    #[arg(long, default_value = "default")]
+    output_device: DeviceSelector,
+    /// Second output device receiving a copy of the monitor feed, e.g. for a recorder.
+    #[arg(long)]
+    output_device_2: Option<DeviceSelector>,
+    /// Audio host to use: "default", or one of `--list-drivers`, e.g. "jack", "alsa", "asio",
+    /// "coreaudio" or "wasapi".
+    #[arg(long, default_value = "default")]
+    driver: String,
+    /// Gain applied to the monitor feed, in dB.
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    gain: f32,
+    /// Cutoff of a 12 dB/octave high-pass filter on the monitor feed, in Hz.
+    #[arg(long)]
+    highpass: Option<f32>,
+    /// A steeper high-pass against rumble, first in the chain: `<Hz>[:<dB/octave>]`, e.g.
+    /// "60:48", of 12, 24 or 48 dB/octave, 24 by default.
+    #[arg(long)]
+    rumble: Option<RumbleSpec>,
+    /// Cutoff of a 12 dB/octave low-pass filter on the monitor feed, in Hz.
+    #[arg(long)]
+    lowpass: Option<f32>,
+    /// Speaker cabinet simulation for guitar, after the filters: `<cab>[:<mic>]`, e.g.
+    /// "v30:close". The cabinet is "v30" or "greenback", or a WAV or text file of the IR of a
+    /// close mic, or of a close and a far one as two channels. The mic is "close", "far", or a
+    /// blend between them from 0 to 1, which automation can move as `cab.blend`.
+    #[arg(long)]
+    cab: Option<CabSpec>,
+    /// A band of the parametric EQ of the monitor feed, after the filters, repeatable. Static
+    /// bands are `[peak:]<Hz>:<dB>:<Q>`, e.g. "3000:2:0.7". Dynamic bands only boost or cut as
+    /// the level in the band goes over a threshold, as `dyn:<Hz>:<dB>:<Q>` followed by
+    /// `threshold` in dBFS (-30), `ratio` (2), `attack` and `release` in ms (5 and 100), e.g.
+    /// "dyn:250:-6:1.4:threshold=-30:ratio=3".
+    #[arg(long, allow_hyphen_values = true)]
+    eq: Vec<EqBand>,
+    /// Insert an external hardware effect into the chain of the first output, through spare
+    /// channels, as `key=value` pairs: the output channels to `send` on and the input channels of
+    /// the first input to `return` from, from 0, the effect to insert it `after`, at the end of
+    /// the chain by default, and the `timeout` in ms (500) after which a silent return falls back
+    /// to the dry signal, e.g. "send=2,3:return=4,5:after=eq". The loop is measured with a click
+    /// at the start, and the rest of the feed delayed to match.
+    #[arg(long)]
+    hw_insert: Option<InsertSpec>,
+    /// FIR filter applied to the monitor feed, in the same formats as `--fir`.
+    #[arg(long)]
+    fir_effect: Option<PathBuf>,
+    /// Gain of the `--fir-effect` filter, in dB.
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        allow_negative_numbers = true,
+        requires = "fir_effect"
+    )]
+    fir_gain: f32,
+    /// CLAP plugin applied to the monitor feed after the FIR filter, as `path` or `path:index`
+    /// for bundles of several plugins. Repeat for more, in order.
+    #[cfg(feature = "clap-plugins")]
+    #[arg(long)]
+    plugin: Vec<PluginSpec>,
+    /// Rhai script applied to the monitor feed after the plugins, defining
+    /// `process(block, channels, sample_rate)`. See `src/effects/script.rs`.
+    #[arg(long)]
+    script: Option<PathBuf>,
+    /// Reduce stationary noise on the monitor feed by up to this much, in dB, once a noise
+    /// profile has been learned. Type `n` and Enter during the run to learn one.
+    #[arg(long)]
+    noise_reduction: Option<f32>,
+    /// Learn the noise profile from this many seconds at startup, which should be noise only.
+    /// Also how long learning lasts when requested with `n`.
+    #[arg(long, requires = "noise_reduction")]
+    learn_noise: Option<f32>,
+    /// Notch that finds the strongest narrowband component between two frequencies and follows
+    /// it as it drifts, as `<low-Hz>:<high-Hz>`, e.g. "800:1600".
+    #[arg(long)]
+    adaptive_notch: Option<NotchWindow>,
+    /// Detect feedback howl on the monitor feed and suppress it with automatic notch filters.
+    #[arg(long)]
+    feedback_suppress: bool,
+    /// Spread the stereo of the monitor feed to more speakers: "quad" sends left and right to
+    /// the fronts, and the ambience between them to the rears. Outputs of two channels get plain
+    /// stereo.
+    #[arg(long, conflicts_with = "crossover")]
+    upmix: Option<UpmixLayout>,
+    /// Output channels of the left and right rears of the upmix, from 0.
+    #[arg(long, default_value = "2,3", requires = "upmix", value_parser = parse_rears)]
+    upmix_rears: [usize; 2],
+    /// Level of the rears of the upmix, in dB relative to the ambience.
+    #[arg(
+        long,
+        default_value_t = -6.0,
+        allow_negative_numbers = true,
+        requires = "upmix"
+    )]
+    upmix_rear_level: f32,
+    /// Split the monitor feed into bands on separate output channels with Linkwitz–Riley
+    /// 24 dB/octave filters, as `2way:<Hz>` or `3way:<low-Hz>:<high-Hz>`. Band `n` goes to
+    /// channels `2n` and `2n + 1` unless `--crossover-band` says otherwise.
+    #[arg(long)]
+    crossover: Option<CrossoverSpec>,
+    /// Routing, gain and polarity of a crossover band, as
+    /// `<band>[:channels=<n>,...][:gain=<dB>][:invert]`, e.g. "high:channels=2,3:gain=-1.5".
+    /// Bands are `low` and `high`, plus `mid` for 3 ways. Can be repeated.
+    #[arg(long, requires = "crossover", allow_hyphen_values = true)]
+    crossover_band: Vec<BandSpec>,
+    /// Output channel of a subwoofer getting the sum of the other channels, low-passed with a
+    /// Linkwitz–Riley 24 dB/octave filter.
+    #[arg(long)]
+    sub_channel: Option<usize>,
+    /// Cutoff of the subwoofer's low-pass, in Hz.
+    #[arg(long, default_value_t = 80.0, requires = "sub_channel")]
+    sub_lowpass: f32,
+    /// Gain of the subwoofer, in dB.
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        allow_negative_numbers = true,
+        requires = "sub_channel"
+    )]
+    sub_gain: f32,
+    /// Invert the polarity of the subwoofer.
+    #[arg(long, requires = "sub_channel")]
+    sub_invert: bool,
+    /// High-pass the other channels at the subwoofer's cutoff, so that the bass only comes out of
+    /// the subwoofer.
+    #[arg(long, requires = "sub_channel")]
+    sub_highpass_mains: bool,
+    /// Delay an output channel to time-align its speaker, as `<channel>:<milliseconds>`, e.g.
+    /// "2:5.8" for a subwoofer two meters behind the mains. Can be repeated.
+    #[arg(long)]
+    channel_delay: Vec<ChannelDelaySpec>,
+    /// Longest delay `--channel-delay` accepts, in milliseconds.
+    #[arg(long, default_value_t = 50.0)]
+    max_channel_delay: f32,
+    /// Correction filters for the output channels, e.g. exported from REW: a WAV file with one
+    /// filter per channel, or a text file of coefficients, one per line and comma-separated for
+    /// several channels. A single filter applies to every channel.
+    #[arg(long)]
+    fir: Option<PathBuf>,
+    /// Correction filter of the right channel, in the same formats, making `--fir` the filter of
+    /// the left channel only.
+    #[arg(long, requires = "fir")]
+    fir_right: Option<PathBuf>,
+    /// Longest filter `--fir` and `--fir-effect` accept, in taps.
+    #[arg(long, default_value_t = 16_384)]
+    fir_max_taps: usize,
+    /// Sample type of the effect chain: "single" (f32) or "double" (f64, needs the
+    /// `double-precision` feature). Devices always use f32.
+    #[arg(long, default_value = "single")]
+    precision: Precision,
+    /// CSV file of `time_seconds,parameter,value` rows changing parameters during the run, e.g.
+    /// `5.0,gain,-12`. Parameters are named like `gain` or `filter.1.freq`.
+    #[arg(long)]
+    automation: Option<PathBuf>,
+    /// LFO modulating parameters, e.g.
+    /// "rate=0.2:shape=triangle:target=filter.0.freq:depth=800:center=1200". Can be repeated.
+    #[arg(long)]
+    lfo: Vec<LfoSpec>,
+    /// Record the first input device to this file, as set by `--record-format`.
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Record the first input device to takes in this directory instead, numbered
+    /// "take_001.wav" and on after those already there, even while other instances record to it.
+    /// Type `r` and Enter to start a take and again to keep it, or `d` to discard it.
+    #[arg(long, conflicts_with = "record")]
+    record_dir: Option<PathBuf>,
+    /// Format of the recording: "wav", in 32-bit float, or "flac", in `--record-bits`.
+    #[arg(long, default_value = "wav")]
+    record_format: RecordFormat,
+    /// Bits per sample of FLAC recordings, 16 or 24.
+    #[arg(long, default_value_t = 24)]
+    record_bits: u32,
+    /// Compression level of FLAC recordings, from 0, the fastest, to 8, the smallest.
+    #[arg(long, default_value_t = 5)]
+    record_compression: u32,
+    /// Round 16-bit samples, of FLAC recordings and of `--net-encoding i16`, instead of
+    /// dithering them.
+    #[arg(long)]
+    no_dither: bool,
+    /// Shape the dither noise of 16-bit samples towards the treble, where it's less audible.
+    #[arg(long, conflicts_with = "no_dither")]
+    noise_shaping: bool,
+    /// Pause the recording once the input stays below a level for a while, as
+    /// `<dBFS>:<hold-seconds>`, e.g. "-50:2". It resumes as soon as the level is reached again.
+    #[arg(long, requires = "recording", allow_hyphen_values = true)]
+    record_gate: Option<GateSettings>,
+    /// Audio from before the gate reopens that is kept in the recording, in milliseconds.
+    #[arg(long, default_value_t = 250.0)]
+    record_preroll: f32,
+    /// Write each segment between pauses of the gate to its own numbered file.
+    #[arg(long, requires = "record_gate")]
+    record_split: bool,
+    /// Go on recording in the next numbered file after this long, e.g. "30m" or "2h".
+    #[arg(long, requires = "recording", value_parser = record::parse_duration)]
+    record_split_every: Option<Duration>,
+    /// Go on recording in the next numbered file once a file reaches this size, e.g. "2G". Files
+    /// always split before the 4 GB WAV files can hold.
+    #[arg(long, requires = "recording", value_parser = record::parse_size)]
+    record_split_size: Option<u64>,
+    /// Normalize the recording to this integrated loudness, in LUFS, once it ends, as far as
+    /// keeps its true peak under -1 dBTP. FLAC recordings go through a float WAV meanwhile,
+    /// whose size `--record-split-size` limits.
+    #[arg(long, requires = "recording", allow_negative_numbers = true)]
+    record_normalize: Option<f32>,
+    /// Normalize the files of a split recording with one gain, from their joint loudness,
+    /// rather than each on its own.
+    #[arg(long, requires = "record_normalize")]
+    record_normalize_joint: bool,
+    /// Record only these channels of the first input, from 0, in this order, e.g. "2,3", whatever
+    /// the monitor plays.
+    #[arg(long, requires = "recording")]
+    record_channels: Option<ChannelPick>,
+    /// Wait for `r` and Enter to start recording, keeping meanwhile this many seconds of the
+    /// input, with which the file starts, or with which every take starts.
+    #[arg(long, requires = "recording")]
+    pre_roll: Option<f32>,
+    /// Keep the last seconds of the first input, in 16-bit, and save them to a timestamped WAV
+    /// file in the current directory when `s` and Enter are typed.
+    #[arg(long)]
+    retro_buffer: Option<f32>,
+    /// Send what the first output plays, after the effects, to another instance over UDP, e.g.
+    /// "192.168.1.20:5004".
+    #[arg(long)]
+    net_send: Option<String>,
+    /// Encoding of the samples sent over the network: "f32", or "i16" for half the bandwidth.
+    #[arg(long, default_value = "f32")]
+    net_encoding: net::Encoding,
+    /// Play the audio another instance sends with `--net-send` on the first output, received
+    /// on this address, e.g. "0.0.0.0:5004".
+    #[arg(long)]
+    net_receive: Option<String>,
+    /// How late packets received over the network may be, in milliseconds. As much audio is
+    /// buffered.
+    #[arg(long, default_value_t = 20.0)]
+    jitter_ms: f32,
+    /// Play only the audio received over the network on the first output, without the inputs.
+    #[arg(long, requires = "net_receive")]
+    net_only: bool,
+    /// WAV file played into the monitor feed from the start, e.g. a backing track, once unless
+    /// `--loop`.
+    #[arg(long)]
+    play: Option<PathBuf>,
+    /// Play the playback file at this speed, from 0.5 to 1.5, without changing its pitch. Type
+    /// `p <speed>` and Enter during the run to change it.
+    #[arg(long, requires = "play")]
+    playback_speed: Option<f32>,
+    /// Play only this time range of the playback file, as `<start-s>:<end-s>`, e.g. "34.5:42.0".
+    /// Type `[-`, `[+`, `]-` or `]+` and Enter during the run to move its start or end 100 ms
+    /// earlier or later.
+    #[arg(long, requires = "play", conflicts_with = "playback_speed")]
+    playback_region: Option<Region>,
+    /// Loop the playback file, or its `--playback-region`, instead of playing it once.
+    #[arg(long = "loop", requires = "play", conflicts_with = "playback_speed")]
+    looping: bool,
+    /// Length of the crossfade at the seam of the looped playback file, in milliseconds.
+    #[arg(long, default_value_t = playback::DEFAULT_CROSSFADE_MS, requires = "looping")]
+    loop_crossfade: f32,
+    /// Duck the playback file while the live input is active, as
+    /// `<threshold-dBFS>:<amount-dB>:<attack-ms>:<release-ms>`, e.g. "-35:-10:10:400".
+    #[arg(long, allow_hyphen_values = true)]
+    duck: Option<DuckSettings>,
+    /// Compress the monitor feed, before the limiter, as
+    /// `<threshold-dBFS>:<ratio>:<attack-ms>:<release-ms>`, e.g. "-20:4:5:100". Keyed by
+    /// `--sidechain-device` without a preset, it comes after the chain instead.
+    #[arg(long, allow_hyphen_values = true)]
+    compress: Option<CompressorSettings>,
+    /// Noise gate on the monitor feed, after the high-pass, as `key=value` pairs: `threshold` in
+    /// dBFS (-45), `range` in dB (40), `attack`, `release` and `hold` in ms (1, 100 and 50), e.g.
+    /// "threshold=-50:hold=80".
+    #[arg(long, allow_hyphen_values = true)]
+    gate: Option<GateSpec>,
+    /// Limit the peaks of the monitor feed, after everything else that sets its level, as
+    /// `<ceiling-dBFS>[:<release-ms>]`, e.g. "-1".
+    #[arg(long, allow_hyphen_values = true)]
+    limit: Option<LimiterSpec>,
+    /// Run the monitor feed through the channel strip of a preset, `vocal`, `podcast` or
+    /// `guitar`: a high-pass, gate, EQ, compressor and limiter, in that order. `--highpass`,
+    /// `--gate`, `--eq`, `--compress` and `--limit` replace the stages of the preset, whose
+    /// compressor is keyed by its own signal.
+    #[arg(long)]
+    channel_strip: Option<StripPreset>,
+    /// Hold the level of the monitor feed, e.g. of speech, with automatic gain control, as
+    /// `key=value` pairs: `target` RMS level in dBFS (-20), `max` gain in dB (20), `rate` of
+    /// adaptation in dB per second (6) and `gate` in dBFS (-50), under which the gain is held,
+    /// e.g. "target=-20:max=18".
+    #[arg(long, allow_hyphen_values = true)]
+    agc: Option<AgcSpec>,
+    /// Input device keying the compressor and the ducker instead of their own signals. Its
+    /// signal never reaches the outputs.
+    #[arg(long)]
+    sidechain_device: Option<DeviceSelector>,
+    /// Channel of the sidechain device used as the key, from 0.
+    #[arg(long, default_value_t = 0, requires = "sidechain_device")]
+    sidechain_channel: usize,
+    /// Play a metronome click at this tempo, in beats per minute, into the first output. Type
+    /// `t <BPM>` and Enter during the run to change it.
+    #[arg(long)]
+    click: Option<f32>,
+    /// Level of the click, in dBFS.
+    #[arg(long, default_value_t = -12.0, allow_negative_numbers = true)]
+    click_level: f32,
+    /// Beats per bar, the first of which gets a higher click. 1 accents none.
+    #[arg(long, default_value_t = 4, requires = "click")]
+    click_beats: u32,
+    /// Loop the live signal on the first output: type `l` and Enter to record a loop, again to
+    /// play it back, and again to toggle overdubbing. `L` clears it.
+    #[arg(long)]
+    looper: bool,
+    /// Longest loop, in seconds, which the looper allocates up front.
+    #[arg(long, default_value_t = 60.0, requires = "looper")]
+    loop_max: f32,
+    /// How much of the loop is kept under each overdubbed layer, from 0 to 1.
+    #[arg(long, default_value_t = 1.0, requires = "looper")]
+    loop_feedback: f32,
+    /// Cancel the echo of the first output picked up by the first input, e.g. speakers heard by
+    /// the microphone.
+    #[arg(long)]
+    aec: bool,
+    /// Keep the audio threads at normal priority instead of raising them to real-time priority.
+    #[arg(long)]
+    no_rt: bool,
+    /// Don't time the effects of the chains, whose CPU shares are in the status line otherwise.
+    #[arg(long)]
+    no_profiling: bool,
+    /// Don't bypass an effect that takes too much of the blocks' time while the outputs xrun.
+    /// Type `e <effect>` and Enter during the run to bring back one that was.
+    #[arg(long)]
+    no_auto_bypass: bool,
+    /// Share of the block duration, in percent, an effect may take before it's bypassed.
+    #[arg(long, default_value_t = 70.0)]
+    auto_bypass_load: f32,
+    /// How long, in seconds, an effect has to take more than that, with xruns, to be bypassed.
+    #[arg(long, default_value_t = 3.0)]
+    auto_bypass_seconds: f64,
+    /// Don't check the outputs for NaNs and infinities, e.g. to benchmark without the checks.
+    #[arg(long)]
+    no_nan_guard: bool,
+    /// Restart the streams when their callbacks stop coming, e.g. from a wedged USB interface.
+    /// Streams whose device goes away are restarted either way.
+    #[arg(long)]
+    watchdog: bool,
+    /// Restarts of the streams, after stalls or lost devices, before giving up.
+    #[arg(long, default_value_t = 3)]
+    watchdog_retries: usize,
+    /// Backend errors a stream can report before giving up on the streams.
+    #[arg(long, default_value_t = 100)]
+    max_backend_errors: usize,
+    /// How log lines are written to stderr: "pretty", or "json" for one object per line.
+    /// `RUST_LOG` sets what gets logged, e.g. "debug" or "warn".
+    #[arg(long, default_value = "pretty")]
+    log_format: LogFormat,
+    /// How often the status line is printed and its meters taken, in milliseconds, at least 100,
+    /// or 0 for no status line. The session log is written as often.
+    #[arg(long, default_value_t = status::DEFAULT_INTERVAL.as_millis() as u64)]
+    stats_interval: u64,
+    /// Only log the warnings and the errors, without the status line, until the run ends and its
+    /// summary is logged, e.g. for long runs logged to a file.
+    #[arg(long)]
+    quiet: bool,
+    /// Write a summary of the session to this file, in the `--log-format`, kept up to date as it
+    /// runs: the devices, the latency, every warning, the recordings, the device switches and
+    /// recoveries, and the final stats.
+    #[arg(long)]
+    session_log: Option<PathBuf>,
+    /// Meter the correlation between the first two channels of the first input, e.g. of a pair of
+    /// mics, in the status line: from +1 for the same signal in both, to -1 for channels in
+    /// opposite phase.
+    #[arg(long)]
+    correlation: bool,
+    /// Keep goniometer points of the same two channels, for `ctl goniometer`.
+    #[cfg(unix)]
+    #[arg(long, requires = "control_socket")]
+    goniometer: bool,
+    /// Meter the peaks of every output where its level is set: after the trims of the inputs,
+    /// after the EQ, after the compressor and before the limiter. The status line warns about
+    /// the first stage whose peaks stay over `--headroom-threshold` for more than a second.
+    #[arg(long)]
+    headroom: bool,
+    /// Peak level over which a stage has too little headroom, in dBFS.
+    #[arg(
+        long,
+        default_value_t = -3.0,
+        allow_negative_numbers = true,
+        requires = "headroom"
+    )]
+    headroom_threshold: f32,
+    /// Watch the inputs for overloads of their analog front ends, which flatten the tops of the
+    /// waveform before it reaches full scale. The status line shows "OVL" for 3 seconds after
+    /// one, and the summary counts them apart from the hard clips.
+    #[arg(long)]
+    overload: bool,
+    /// How readily a flattened top counts as an overload, from 0 to 1.
+    #[arg(long, default_value_t = overload::DEFAULT_SENSITIVITY, requires = "overload")]
+    overload_sensitivity: f32,
+    /// Compare the first output with its input, aligned by the latency of the chain, and print
+    /// the residual relative to the input.
+    #[arg(long)]
+    null_test: bool,
+    /// Fail the run if the residual of the null test is above this level, in dB.
+    #[arg(long, requires = "null_test", allow_negative_numbers = true)]
+    null_fail_above: Option<f32>,
+    /// Fade the outputs in over this long at the start, in milliseconds.
+    #[arg(long, default_value_t = 50.0)]
+    fade_in: f32,
+    /// Fade the outputs out over this long before closing, in milliseconds.
+    #[arg(long, default_value_t = 50.0)]
+    fade_out: f32,
+    /// Longest the effects may keep sounding once the inputs stopped, e.g. a reverb decaying,
+    /// before fading out, in milliseconds.
+    #[arg(long, default_value_t = 2_000.0)]
+    drain_tail: f32,
+    /// Measure the interface, with its output looped back to its input by a cable, instead of
+    /// monitoring: "thdn" plays a sine and prints the THD+N of what comes back on each input
+    /// channel, "response" plays a sweep and prints the frequency response of `--input-channel`,
+    /// and "rt60" prints the reverberation time of the response to a sweep, or of `--ir`.
+    #[arg(long)]
+    measure: Option<Measurement>,
+    /// Frequency of the test tone, in Hz.
+    #[arg(long, default_value_t = 1_000.0, requires = "measure")]
+    freq: f64,
+    /// Peak level of the test tone, in dBFS.
+    #[arg(
+        long,
+        default_value_t = -6.0,
+        allow_negative_numbers = true,
+        requires = "measure"
+    )]
+    level: f32,
+    /// Impulse response `--measure rt60` analyses instead of measuring one, as a WAV file.
+    #[arg(long, requires = "measure")]
+    ir: Option<PathBuf>,
+    /// Input channel the impulse response is measured on, from 0, or channel of `--ir`.
+    #[arg(long, default_value_t = 0, requires = "measure")]
+    input_channel: usize,
+    /// Length of the impulse response the frequency response is taken from, in milliseconds.
+    /// Shorter leaves out more of the noise floor and of the room, at the cost of resolution in
+    /// the bass.
+    #[arg(long, default_value_t = 500.0, requires = "measure")]
+    ir_window: f32,
+    /// Smoothing of the frequency response, as a fraction of an octave, e.g. 6 for 1/6 octave,
+    /// or 0 for none.
+    #[arg(long, default_value_t = 6.0, requires = "measure")]
+    smoothing: f64,
+    /// File the frequency response is written to, as CSV.
+    #[arg(long, default_value = "response.csv", requires = "measure")]
+    response_csv: PathBuf,
+    /// Also plot the frequency response to this PNG file.
+    #[arg(long, requires = "measure")]
+    response_plot: Option<PathBuf>,
+    /// Print the available devices with their indices, then exit.
+    #[arg(long)]
+    list_devices: bool,
+    /// Print the drivers available on this machine, then exit.
+    #[arg(long)]
+    list_drivers: bool,
+    /// Run the pipeline with the effects asked for on simulated streams, at `--sample-rate` and
+    /// `--buffer-size`, without any device, print which invariants held, then exit.
+    #[arg(long)]
+    self_test: bool,
+    /// Run without a terminal until told to shut down over the control socket, or until a
+    /// SIGTERM.
+    #[cfg(unix)]
+    #[arg(long, requires = "control_socket")]
+    daemon: bool,
+    /// Accept commands on this Unix socket, e.g. "/run/dsp.sock", as sent by `ctl`.
+    #[cfg(unix)]
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+    /// Permissions of the control socket, in octal.
+    #[cfg(unix)]
+    #[arg(long, default_value = "660", value_parser = daemon::parse_mode, requires = "control_socket")]
+    socket_mode: u32,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run every effect over the standard stimuli without any audio device, and compare the
+    /// outputs with the golden files. With `DSP_BLESS=1` the outputs become the golden files.
+    Golden {
+        /// Directory of the golden files.
+        #[arg(long, default_value = "goldens")]
+        dir: PathBuf,
+    },
+    /// Control an instance running with `--control-socket`.
+    #[cfg(unix)]
+    Ctl {
+        /// The control socket of the instance.
+        #[arg(long, default_value = "/run/dsp.sock")]
+        socket: PathBuf,
+        #[command(subcommand)]
+        request: Request,
+    },
+}
+
+#[cfg(unix)]
+#[derive(Subcommand)]
+enum Request {
+    /// Print the status line and the parameter values.
+    Status,
+    /// Set a parameter, e.g. `set gain -6`, or move the first output or input to another
+    /// device, e.g. `set output-device "USB Headset"`.
+    Set {
+        param: String,
+        #[arg(allow_hyphen_values = true)]
+        value: String,
+    },
+    /// Silence the outputs.
+    Mute,
+    /// Undo `mute`.
+    Unmute,
+    /// Apply a preset file of `parameter,value` rows.
+    Preset { path: PathBuf },
+    /// Print the latest goniometer points of an instance running with `--goniometer`, rotated
+    /// to side across and mid up.
+    Goniometer {
+        /// Left across and right up instead.
+        #[arg(long)]
+        lr: bool,
+        /// Draw the points in the terminal instead of printing them.
+        #[arg(long)]
+        ascii: bool,
+    },
+    /// Solo a channel of the first input, from 1, to every output channel, or un-solo it.
+    Solo { channel: usize },
+    /// Listen to a band of the monitor feed alone, as `<center-Hz>[:<Q>]`, e.g. `band-solo
+    /// 450:1.0`, or go back with `band-solo off`.
+    BandSolo { band: String },
+    /// End the run.
+    Shutdown,
+}
+
+#[cfg(unix)]
+impl Request {
+    fn to_json(&self) -> Value {
+        let command = |name: &str| ("command", Value::from(name));
+        match self {
+            Request::Status => Value::object([command("status")]),
+            Request::Set { param, value } => Value::object([
+                command("set"),
+                ("param", param.as_str().into()),
+                (
+                    "value",
+                    match value.parse::<f64>() {
+                        Ok(number) => number.into(),
+                        Err(_) => value.as_str().into(),
+                    },
+                ),
+            ]),
+            Request::Mute => Value::object([command("mute")]),
+            Request::Unmute => Value::object([command("unmute")]),
+            // The instance runs in another directory, so the path is made absolute here.
+            Request::Preset { path } => {
+                let path = std::path::absolute(path).unwrap_or(path.clone());
+                Value::object([
+                    command("preset"),
+                    ("path", path.to_string_lossy().as_ref().into()),
+                ])
+            }
+            Request::Goniometer { lr, .. } => Value::object([
+                command("goniometer"),
+                ("mode", if *lr { "lr" } else { "ms" }.into()),
+            ]),
+            Request::Solo { channel } => {
+                Value::object([command("solo"), ("channel", (*channel as f64).into())])
+            }
+            Request::BandSolo { band } => {
+                Value::object([command("band-solo"), ("band", band.as_str().into())])
+            }
+            Request::Shutdown => Value::object([command("shutdown")]),
+        }
+    }
+}
+
+/// Checks the effects against the golden files in `directory`, or blesses them.
+fn check_goldens(directory: &Path) -> anyhow::Result<()> {
+    let bless = golden::bless_requested();
+    let failures = golden::check(directory, bless)?;
+    let cases = golden::cases().len() * golden::Stimulus::ALL.len();
+    if bless {
+        println!(
+            "Wrote {} golden files to \"{}\".",
+            cases,
+            directory.display()
+        );
+        return Ok(());
+    }
+    for failure in &failures {
+        eprintln!("{}", failure);
+    }
+    if !failures.is_empty() {
+        anyhow::bail!(
+            "{} of {} outputs differ from the golden files, run with {}=1 to accept them",
+            failures.len(),
+            cases,
+            golden::BLESS_VARIABLE
+        );
+    }
+    println!("All {} outputs match the golden files.", cases);
+    Ok(())
+}
+
+/// Plays a tone on the output and prints the THD+N of each channel of the input, averaged over
+/// a few windows once the loopback has settled.
+fn measure_thdn(
+    settings: &Settings,
+    input: (&cpal::Device, &StreamConfig),
+    output: (&cpal::Device, &StreamConfig),
+) -> anyhow::Result<()> {
+    let rate = input.1.sample_rate.0;
+    let nyquist = rate.min(output.1.sample_rate.0) as f64 / 2.0;
+    if !(settings.freq >= thdn::LOWEST && settings.freq < nyquist) {
+        return Err(EngineError::InvalidArgument(format!(
+            "the frequency of the tone must be from {} Hz to under {} Hz, got {}",
+            thdn::LOWEST,
+            nyquist,
+            settings.freq
+        ))
+        .into());
+    }
+    if settings.level > 0.0 {
+        return Err(EngineError::InvalidArgument(
+            "the level of the tone can't be above 0 dBFS".to_string(),
+        )
+        .into());
+    }
+    let measured = THDN_WINDOW * THDN_WINDOWS as u32;
+    let duration = MEASURE_SETTLE + measured;
+    tracing::info!(
+        "playing a {} Hz tone at {} dBFS for {:.2} seconds",
+        settings.freq,
+        settings.level,
+        duration.as_secs_f32()
+    );
+    let mut tone = Tone::new(
+        settings.freq,
+        settings.level,
+        duration,
+        output.1.sample_rate.0,
+    );
+    let captured = loopback::play_and_capture(output, input, duration, move |data, channels| {
+        tone.fill(data, channels)
+    })?;
+
+    let channels = input.1.channels as usize;
+    let settle = (MEASURE_SETTLE.as_secs_f64() * rate as f64) as usize * channels;
+    let captured = captured.get(settle..).unwrap_or_default();
+    let window = (THDN_WINDOW.as_secs_f64() * rate as f64) as usize;
+    for channel in 0..channels {
+        let samples: Vec<f32> = captured
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .copied()
+            .collect();
+        let Some((analysis, windows)) = thdn::analyze_windows(&samples, window, rate) else {
+            println!("Input channel {}: no tone.", channel);
+            continue;
+        };
+        println!(
+            "Input channel {}: {:.2} Hz at {:.2} dBFS, THD+N {:.4}% ({:.1} dB) over {} windows.",
+            channel,
+            analysis.frequency,
+            analysis.level_db(),
+            analysis.percent(),
+            analysis.db(),
+            windows
+        );
+        // Far off tones are those of another output, or of the wrong cable.
+        if (analysis.frequency / settings.freq - 1.0).abs() > 0.01 {
+            tracing::warn!(
+                "input channel {} gets {:.1} Hz instead of {} Hz: check the routing",
+                channel,
+                analysis.frequency,
+                settings.freq
+            );
+        }
+        let difference = analysis.level_db() - settings.level as f64;
+        if difference.abs() > 3.0 {
+            tracing::warn!(
+                "input channel {} gets the tone {:.1} dB {} than it's played: check the \
+                 gains of the interface and the routing",
+                channel,
+                difference.abs(),
+                if difference > 0.0 {
+                    "louder"
+                } else {
+                    "quieter"
+                }
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Plays a sweep on the output and returns the impulse response deconvolved from
+/// `--input-channel`, with the sweep.
+fn capture_ir(
+    settings: &Settings,
+    input: (&cpal::Device, &StreamConfig),
+    output: (&cpal::Device, &StreamConfig),
+) -> anyhow::Result<(Vec<f32>, Sweep)> {
+    let channels = input.1.channels as usize;
+    if settings.input_channel >= channels {
+        return Err(EngineError::InvalidArgument(format!(
+            "the input has no channel {}, only {}",
+            settings.input_channel, channels
+        ))
+        .into());
+    }
+    if settings.level > 0.0 {
+        return Err(EngineError::InvalidArgument(
+            "the level of the sweep can't be above 0 dBFS".to_string(),
+        )
+        .into());
+    }
+    // The sweep is rendered at the input's rate for the deconvolution, so both must match.
+    let rate = input.1.sample_rate.0;
+    if output.1.sample_rate.0 != rate {
+        anyhow::bail!(
+            "the input runs at {} Hz and the output at {} Hz: set `--sample-rate`",
+            rate,
+            output.1.sample_rate.0
+        );
+    }
+    let sweep = Sweep {
+        low: RESPONSE_LOW / 2.0,
+        high: (RESPONSE_HIGH * 1.25).min(rate as f64 * 0.48),
+        duration: SWEEP_LENGTH,
+        level_db: settings.level,
+        sample_rate: rate,
+    };
+    println!(
+        "Playing a sweep from {} Hz to {} Hz at {} dBFS for {:.1} seconds.",
+        sweep.low,
+        sweep.high,
+        sweep.level_db,
+        SWEEP_LENGTH.as_secs_f32()
+    );
+    let mut player = SweepPlayer::new(sweep);
+    let captured = loopback::play_and_capture(
+        output,
+        input,
+        SWEEP_LENGTH + SWEEP_TAIL,
+        move |data, channels| player.fill(data, channels),
+    )?;
+    let samples: Vec<f32> = captured
+        .iter()
+        .skip(settings.input_channel)
+        .step_by(channels)
+        .copied()
+        .collect();
+    if simd::peak(&samples) < level::db_to_gain(-60.0) {
+        anyhow::bail!(
+            "input channel {} gets nothing of the sweep: check the cable and the routing",
+            settings.input_channel
+        );
+    }
+    Ok((sweep::deconvolve(&sweep.render(), &samples), sweep))
+}
+
+/// Measures the frequency response of the loopback, printing its -3 dB points and its ripple
+/// and writing it to a CSV file and, if asked, a plot.
+fn measure_response(
+    settings: &Settings,
+    input: (&cpal::Device, &StreamConfig),
+    output: (&cpal::Device, &StreamConfig),
+) -> anyhow::Result<()> {
+    if settings.ir_window <= 0.0 || settings.smoothing < 0.0 {
+        return Err(EngineError::InvalidArgument(
+            "the window of the impulse response and the smoothing must be positive".to_string(),
+        )
+        .into());
+    }
+    let (ir, sweep) = capture_ir(settings, input, output)?;
+    let rate = sweep.sample_rate;
+    let length = (settings.ir_window as f64 / 1_000.0 * rate as f64) as usize;
+    let (power, bin) = response::spectrum(&response::window(&ir, length, rate), rate);
+    let (low, high) = (RESPONSE_LOW, RESPONSE_HIGH.min(rate as f64 * 0.45));
+    let curve = response::smooth(&power, bin, low, high, settings.smoothing);
+
+    let reference = REFERENCE_HZ.clamp(low, high);
+    if let Some(gain) = response::gain_at(&curve, reference) {
+        println!("Gain at {} Hz: {:.2} dB.", reference, gain);
+    }
+    let (below, above) = response::edges(&curve, reference);
+    let describe = |edge: Option<f64>, bound: f64, side: &str| match edge {
+        Some(frequency) => format!("{:.1} Hz", frequency),
+        None => format!("{} {} Hz", side, bound),
+    };
+    println!(
+        "-3 dB points: {} and {}.",
+        describe(below, low, "under"),
+        describe(above, high, "over")
+    );
+    let (from, to) = (below.unwrap_or(low), above.unwrap_or(high));
+    println!(
+        "Ripple from {:.1} Hz to {:.1} Hz: {:.2} dB.",
+        from,
+        to,
+        response::ripple(&curve, from, to)
+    );
+
+    response::write_csv(&settings.response_csv, &curve)
+        .with_context(|| format!("failed to write \"{}\"", settings.response_csv.display()))?;
+    println!(
+        "Wrote the frequency response to \"{}\".",
+        settings.response_csv.display()
+    );
+    if let Some(path) = &settings.response_plot {
+        response::plot(path, &curve)
+            .with_context(|| format!("failed to write \"{}\"", path.display()))?;
+        println!("Plotted the frequency response to \"{}\".", path.display());
+    }
+    Ok(())
+}
+
+/// Prints the reverberation time of an impulse response starting at its peak, broadband and
+/// in octave bands.
+fn print_rt60(ir: &[f32], sample_rate: u32) -> anyhow::Result<()> {
+    if ir.is_empty() {
+        anyhow::bail!("the impulse response is empty");
+    }
+    let describe = |fit: rt60::Fit| match fit {
+        rt60::Fit::Seconds(seconds) => format!("{:.2} s", seconds),
+        rt60::Fit::Unreliable => "unreliable".to_string(),
+    };
+    println!(
+        "{:>10}  {:>10}  {:>10}  {:>13}",
+        "Band", "T20", "T30", "Dyn. range"
+    );
+    let print = |label: String, decay: rt60::Decay| {
+        println!(
+            "{:>10}  {:>10}  {:>10}  {:>10.1} dB",
+            label,
+            describe(decay.t20),
+            describe(decay.t30),
+            decay.dynamic_range
+        );
+    };
+    for centre in rt60::BANDS {
+        if let Some(band) = rt60::octave_band(ir, centre, sample_rate) {
+            print(format!("{} Hz", centre), rt60::analyze(&band, sample_rate));
+        }
+    }
+    print("Broadband".to_string(), rt60::analyze(ir, sample_rate));
+    Ok(())
+}
+
+/// Sends a request to a running instance, printing its response.
+#[cfg(unix)]
+fn send_request(socket: &Path, request: &Request) -> anyhow::Result<()> {
+    let response = daemon::request(socket, &request.to_json())?;
+    if response.get("ok").and_then(Value::as_bool) != Some(true) {
+        let error = response.get("error").and_then(Value::as_str);
+        anyhow::bail!(
+            "{}",
+            error.unwrap_or("the instance failed without a reason")
+        );
+    }
+    if let Request::Goniometer { ascii: true, .. } = request {
+        let points: Vec<[f32; 2]> = match response.get("points") {
+            Some(Value::Array(points)) => points
+                .iter()
+                .filter_map(|x| match x {
+                    Value::Array(xy) => Some([xy.first()?.as_f64()?, xy.get(1)?.as_f64()?]),
+                    _ => None,
+                })
+                .map(|[x, y]| [x as f32, y as f32])
+                .collect(),
+            _ => anyhow::bail!("the instance sent no points"),
+        };
+        println!(
+            "{}",
+            rust_dsp_experiments::goniometer::render_ascii(
+                &points,
+                GONIOMETER_WIDTH,
+                GONIOMETER_HEIGHT
+            )
+        );
+        return Ok(());
+    }
+    println!("{}", response);
+    Ok(())
+}
+
+/// Runs the pipeline on simulated stereo streams and prints the report, failing if an invariant
+/// didn't hold.
+fn self_test(settings: &Settings) -> anyhow::Result<()> {
+    let test = SelfTest {
+        channels: 2,
+        sample_rate: settings.sample_rate.unwrap_or(48_000),
+        block: settings.buffer_size,
+        latency: settings.latency,
+    };
+    let files = ChainFiles::load(settings)?;
+    let chain = build_chain::<f32>(
+        settings,
+        &test.config(),
+        &files,
+        &LearnTrigger::default(),
+        ChainLinks::default(),
+        None,
+    )?;
+    let report = test.run(chain)?;
+    report.print();
+    if !report.passed() {
+        anyhow::bail!("the self-test failed");
+    }
+    Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
+
+/// The exit code of the process after `err`, from the first [`EngineError`] in its chain, as
+/// listed in [`EXIT_CODES`].
+fn exit_code(err: &anyhow::Error) -> u8 {
+    let engine = err.chain().find_map(|x| x.downcast_ref::<EngineError>());
+    match engine {
+        Some(EngineError::InvalidArgument(_)) => 2,
+        Some(
+            EngineError::DeviceNotFound { .. }
+            | EngineError::DeviceIndexOutOfRange { .. }
+            | EngineError::HostUnavailable { .. },
+        ) => 3,
+        Some(EngineError::UnsupportedConfig { .. }) => 4,
+        Some(
+            EngineError::Devices(_)
+            | EngineError::SupportedConfigs(_)
+            | EngineError::StreamBuild { .. }
+            | EngineError::StreamPlay { .. }
+            | EngineError::StreamFailed(_),
+        ) => 5,
+        Some(EngineError::Io(_)) | None => 1,
+    }
+}
+
+fn run() -> anyhow::Result<()> {
     // Get settings
-    let settings = Settings {
-        buffer_size: 128,
-        input_device: "default".to_string(),
-        output_device: "default".to_string(),
-        driver: Driver::Default,
-    };
-
-    // Conditionally compile with jack if the feature is specified.
-    #[cfg(all(
-    any(
-    target_os = "linux",
-    target_os = "dragonfly",
-    target_os = "freebsd",
-    target_os = "netbsd"
-    ),
-    feature = "jack"
-    ))]
-        // Manually check for flags. Can be passed through cargo with -- e.g.
-        // cargo run --release --example beep --features jack -- --jack
-        let host = if settings.jack {
-        cpal::host_from_id(cpal::available_hosts()
-            .into_iter()
-            .find(|id| *id == cpal::HostId::Jack)
-            .expect(
-                "make sure --features jack is specified. only works on OSes where jack is available",
-            )).expect("jack host unavailable")
-    } else {
-        cpal::default_host()
+    let settings = Settings::parse();
+    let interval =
+        status::interval(settings.stats_interval).map_err(EngineError::InvalidArgument)?;
+    let session = Arc::new(match &settings.session_log {
+        Some(path) => Session::new(
+            path.clone(),
+            settings.log_format,
+            interval.unwrap_or(session::FLUSH_INTERVAL),
+        ),
+        None => Session::disabled(),
+    });
+    let quiet = logging::init(
+        settings.log_format,
+        settings.session_log.is_some().then(|| session.clone()),
+        settings.quiet,
+    )?;
+    let result = run_session(settings, interval, &session, &quiet);
+    let outcome = match &result {
+        Ok(()) => "done".to_string(),
+        Err(err) => format!("failed: {:#}", err),
     };
+    if let Err(err) = session.finish(outcome) {
+        tracing::warn!("failed to write the session log: {}", err);
+    }
+    result
+}
+
+fn run_session(
+    settings: Settings,
+    interval: Option<Duration>,
+    session: &Session,
+    quiet: &Quiet,
+) -> anyhow::Result<()> {
+    match &settings.command {
+        #[cfg(unix)]
+        Some(Command::Ctl { socket, request }) => return send_request(socket, request),
+        Some(Command::Golden { dir }) => return check_goldens(dir),
+        None => {}
+    }
+    if let (Some(Measurement::Rt60), Some(path)) = (settings.measure, &settings.ir) {
+        let file =
+            wav::read(path).with_context(|| format!("failed to read \"{}\"", path.display()))?;
+        let channels = file.channels as usize;
+        if settings.input_channel >= channels {
+            return Err(EngineError::InvalidArgument(format!(
+                "\"{}\" has no channel {}, only {}",
+                path.display(),
+                settings.input_channel,
+                channels
+            ))
+            .into());
+        }
+        let ir: Vec<f32> = file
+            .samples
+            .iter()
+            .skip(settings.input_channel)
+            .step_by(channels)
+            .copied()
+            .collect();
+        return print_rt60(&rt60::from_peak(&ir, ir.len()), file.sample_rate);
+    }
+    if settings.quantum == Some(0) {
+        return Err(EngineError::InvalidArgument(
+            "the quantum must be at least one frame".to_string(),
+        )
+        .into());
+    }
+    if settings.fade_in < 0.0 || settings.fade_out < 0.0 || settings.drain_tail < 0.0 {
+        return Err(
+            EngineError::InvalidArgument("fades and tails can't be negative".to_string()).into(),
+        );
+    }
 
-    #[cfg(target_os = "windows")]
-    let host = cpal::host_from_id(cpal::HostId::Asio).expect("failed to initialise ASIO host");
+    if settings.list_drivers {
+        devices::list_drivers();
+        return Ok(());
+    }
+    if settings.self_test {
+        return self_test(&settings);
+    }
+    let host = devices::resolve_host(&settings.driver)?;
 
-    #[cfg(any(
-    not(any(
-    target_os = "linux",
-    target_os = "dragonfly",
-    target_os = "freebsd",
-    target_os = "netbsd",
-    target_os = "windows"
-    )),
-    not(feature = "jack")
-    ))]
-        let host = cpal::default_host();
+    if settings.list_devices {
+        return Ok(devices::list_devices(&host)?);
+    }
 
     // Find devices.
-    let input_device = if settings.input_device == "default" {
-        host.default_input_device()
-    } else {
-        host.input_devices()?
-            .find(|x| x.name().map(|y| y == settings.input_device).unwrap_or(false))
+    let resolving = tracing::info_span!("resolve_devices").entered();
+    let input_device = devices::input_device(&host, &settings.input_device)?;
+    let output_device = devices::output_device(&host, &settings.output_device)?;
+
+    tracing::info!(device = %input_device.name()?, "using input device");
+    tracing::info!(device = %output_device.name()?, "using output device");
+
+    // Each stream gets its own configuration, and the adapters bridge between them.
+    // The outputs must have a channel for every way of the crossover, and for the sub.
+    let routes = match &settings.crossover {
+        Some(spec) => spec.routes(&settings.crossover_band)?,
+        None => Vec::new(),
+    };
+    let output_channels = Route::channels_needed(&routes)
+        .max(settings.sub_channel.map_or(0, |x| x + 1))
+        .max(1);
+    let prefs = Preferences {
+        buffer_size: settings.buffer_size,
+        sample_rate: settings.sample_rate.map(cpal::SampleRate),
+        output_channels: output_channels as u16,
+    };
+    let configs = config::negotiate_configs(&input_device, &output_device, &prefs)?;
+    match configs.adaptations.is_empty() {
+        true => tracing::info!("the input and output streams share a configuration"),
+        false => {
+            let adaptations: Vec<String> =
+                configs.adaptations.iter().map(|x| x.to_string()).collect();
+            tracing::info!(
+                "no configuration common to the input and output devices, {}",
+                adaptations.join(", ")
+            );
+        }
+    }
+    if let Some(measurement) = settings.measure {
+        let input = (&input_device, &configs.input);
+        let output = (&output_device, &configs.output);
+        return match measurement {
+            Measurement::Thdn => measure_thdn(&settings, input, output),
+            Measurement::Response => measure_response(&settings, input, output),
+            Measurement::Rt60 => {
+                let (ir, sweep) = capture_ir(&settings, input, output)?;
+                let length = (SWEEP_TAIL.as_secs_f64() * sweep.sample_rate as f64) as usize;
+                print_rt60(&rt60::from_peak(&ir, length), sweep.sample_rate)
+            }
+        };
     }
-        .expect("failed to find input device");
+    let mut inputs = vec![Input {
+        label: "input stream",
+        device: input_device,
+        config: configs.input,
+        format: configs.input_format,
+        gain: 1.0,
+    }];
+    let mut outputs = vec![Output {
+        label: "output stream",
+        device: output_device,
+        config: configs.output,
+        format: configs.output_format,
+    }];
 
-    let output_device = if settings.output_device == "default" {
-        host.default_output_device()
-    } else {
-        host.output_devices()?
-            .find(|x| x.name().map(|y| y == settings.output_device).unwrap_or(false))
+    // The second devices are optional, so failing to find them only degrades the session.
+    if let Some(selector) = &settings.input_device_2 {
+        let preferred_rate = Some(outputs[0].config.sample_rate);
+        let input = devices::input_device(&host, selector).and_then(|device| {
+            let config = config::input_config(&device, &prefs, preferred_rate)?;
+            Ok((device, config))
+        });
+        match input {
+            Ok((device, config)) => {
+                tracing::info!(device = %device.name()?, "using second input device");
+                inputs.push(Input {
+                    label: "second input stream",
+                    device,
+                    config,
+                    format: SampleFormat::F32,
+                    gain: level::db_to_gain(settings.input_gain_2),
+                });
+            }
+            Err(err) => tracing::warn!("continuing without the second input device: {}", err),
+        }
     }
-        .expect("failed to find output device");
 
-    println!("Using input device: \"{}\"", input_device.name()?);
-    println!("Using output device: \"{}\"", output_device.name()?);
+    if let Some(selector) = &settings.output_device_2 {
+        let preferred_rate = Some(inputs[0].config.sample_rate);
+        let output = devices::output_device(&host, selector).and_then(|device| {
+            let config = config::output_config(&device, &prefs, preferred_rate)?;
+            Ok((device, config))
+        });
+        match output {
+            Ok((device, config)) => {
+                tracing::info!(device = %device.name()?, "using second output device");
+                outputs.push(Output {
+                    label: "second output stream",
+                    device,
+                    config,
+                    format: SampleFormat::F32,
+                });
+            }
+            Err(err) => tracing::warn!("continuing without the second output device: {}", err),
+        }
+    }
 
-    // We'll try and use the same configuration between streams to keep it simple.
-    let mut config: cpal::StreamConfig = input_device.default_input_config()?.into();
-    config.buffer_size = BufferSize::Fixed(FrameCount {});
+    // The sidechain is optional too, and the detectors key from their own signal without it.
+    let sidechain = match &settings.sidechain_device {
+        Some(_) if keyed_compressor(&settings).is_none() && settings.duck.is_none() => {
+            tracing::warn!("ignoring `--sidechain-device` without `--compress` or `--duck`");
+            None
+        }
+        Some(selector) => {
+            let preferred_rate = Some(outputs[0].config.sample_rate);
+            let sidechain = devices::input_device(&host, selector).and_then(|device| {
+                let config = config::input_config(&device, &prefs, preferred_rate)?;
+                if settings.sidechain_channel >= config.channels as usize {
+                    return Err(EngineError::InvalidArgument(format!(
+                        "it has no channel {}, only {}",
+                        settings.sidechain_channel, config.channels
+                    )));
+                }
+                Ok((device, config))
+            });
+            match sidechain {
+                Ok((device, config)) => {
+                    tracing::info!(device = %device.name()?, "using sidechain device");
+                    Some((device, config))
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "continuing without the sidechain device, keying from the \
+                         signals themselves: {}",
+                        err
+                    );
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    drop(resolving);
 
-    // Create a delay in case the input and output devices aren't synced.
-    let latency_frames = (settings.latency / 1_000.0) * config.sample_rate.0 as f32;
-    let latency_samples = latency_frames as usize * config.channels as usize;
+    if let Some(spec) = &settings.crossover {
+        for route in &routes {
+            tracing::info!(
+                "crossover band \"{}\" to output channels {:?}, at {:.1} dB{}",
+                spec.bands()[route.band],
+                route.channels,
+                level::gain_to_db(route.gain.abs()),
+                if route.gain < 0.0 { ", inverted" } else { "" }
+            );
+        }
+    }
+    for stream in &inputs {
+        log_config(stream.label, &stream.config);
+    }
+    for stream in &outputs {
+        log_config(stream.label, &stream.config);
+    }
+    if let Some((_, config)) = &sidechain {
+        log_config("sidechain stream", config);
+    }
+    session.note(|log| {
+        let inputs = inputs.iter().map(|x| (x.label, &x.device, &x.config));
+        let outputs = outputs.iter().map(|x| (x.label, &x.device, &x.config));
+        let sidechain = sidechain
+            .iter()
+            .map(|(device, config)| ("sidechain stream", device, config));
+        for (label, device, config) in inputs.chain(outputs).chain(sidechain) {
+            log.streams.push(StreamEntry {
+                label: label.to_string(),
+                device: device.name().unwrap_or_else(|_| "unknown".to_string()),
+                config: config::describe(config),
+            });
+        }
+    });
+    session.flush().context("failed to write the session log")?;
 
-    // The buffer to share samples
-    let ring = HeapRb::<f32>::new(latency_samples * 2);
-    let (mut producer, mut consumer) = ring.split();
+    let noise_learning = LearnTrigger::default();
+    if settings.noise_reduction.is_some() {
+        let latency = NoiseReducer::LATENCY as f32 / outputs[0].config.sample_rate.0 as f32;
+        tracing::info!(
+            "noise reduction adds {:.1} milliseconds of latency",
+            latency * 1_000.0
+        );
+        if settings.learn_noise.is_some() {
+            noise_learning.request();
+        }
+    }
+    let files = ChainFiles::load(&settings)?;
+    let filters = [
+        ("The FIR filter adds", &files.fir_effect),
+        ("Correction filters add", &files.correction),
+    ];
+    for (label, taps) in filters {
+        let Some(taps) = taps else { continue };
+        let latency =
+            Fir::<f32>::latency(taps.len()) as f32 / outputs[0].config.sample_rate.0 as f32;
+        tracing::info!("{} {:.1} milliseconds of latency", label, latency * 1_000.0);
+    }
+    // Loaded once here too, so that a broken plugin fails before any stream is built.
+    #[cfg(feature = "clap-plugins")]
+    for spec in &settings.plugin {
+        let output = &outputs[0].config;
+        let plugin = ClapPlugin::load(spec, output.channels as usize, output.sample_rate.0)?;
+        let latency = plugin.latency() as f32 / output.sample_rate.0 as f32;
+        tracing::info!(
+            "plugin \"{}\" adds {:.1} milliseconds of latency",
+            plugin.plugin_name(),
+            latency * 1_000.0
+        );
+    }
+    if let Some(sub) = settings.sub_channel {
+        tracing::info!(
+            "sending the bass under {} Hz to output channel {}",
+            settings.sub_lowpass,
+            sub
+        );
+    }
+    for delay in &settings.channel_delay {
+        tracing::info!(
+            "output channel {} is delayed by {:.2} milliseconds",
+            delay.channel,
+            delay.milliseconds
+        );
+    }
 
-    // Fill the samples with 0.0 equal to the length of the delay.
-    for _ in 0..latency_samples {
-        // The ring buffer has twice as much space as necessary to add latency here,
-        // so this should never fail
-        producer.try_push(0.0).unwrap()
+    // Every output has the same chain, so any of them tells which parameters exist, whether the
+    // LFOs target existing ones, and how late the effects make the signal.
+    let probe = build_chain::<f32>(
+        &settings,
+        &outputs[0].config,
+        &files,
+        &noise_learning,
+        ChainLinks::default(),
+        None,
+    )?;
+    let layout = probe.layout().clone();
+    // The quantum delays the chain by one of its blocks.
+    let chain_latency = probe.latency_frames() + settings.quantum.unwrap_or(0);
+    drop(probe);
+    let mut automation = match &settings.automation {
+        Some(path) => Automation::load(path, &layout)?,
+        None => Automation::default(),
+    };
+    // A daemon runs until it's told to stop.
+    let run_time = if daemon_mode(&settings) {
+        Duration::MAX
+    } else {
+        RUN_TIME
+    };
+    let ignored = automation.truncate(run_time.as_secs_f64());
+    if ignored > 0 {
+        tracing::info!(
+            "ignoring {} automation moves after the end of the run",
+            ignored
+        );
     }
 
-    let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
-        let mut output_fell_behind = false;
-        for &sample in data {
-            if producer.try_push(sample).is_err() { // It's recommended to push entire slices, as you lock threads at every push
-                output_fell_behind = true;
+    let track = match &settings.play {
+        Some(path) => {
+            let track = Track::load(path)?;
+            tracing::info!(
+                "playing \"{}\": {} channels at {} Hz, {:.1} seconds",
+                path.display(),
+                track.config().channels,
+                track.config().sample_rate.0,
+                track.seconds()
+            );
+            Some(track)
+        }
+        None => None,
+    };
+    // The region, shared by every output, is what the bracket commands move.
+    let region = match &track {
+        Some(track) if settings.playback_region.is_some() || settings.looping => {
+            let seconds = track.seconds();
+            let asked = settings.playback_region.unwrap_or(Region {
+                start: 0.0,
+                end: seconds,
+            });
+            let region = asked.within(seconds).ok_or_else(|| {
+                EngineError::InvalidArgument(format!(
+                    "the playback region starts after the end of the file, at {:.3} s",
+                    seconds
+                ))
+            })?;
+            if region != asked {
+                tracing::warn!(
+                    "the playback file ends at {:.3} s, so the region does too",
+                    seconds
+                );
             }
+            let crossfade = Duration::try_from_secs_f32(settings.loop_crossfade / 1_000.0)
+                .map_err(|_| {
+                    EngineError::InvalidArgument(format!(
+                        "invalid loop crossfade {} ms",
+                        settings.loop_crossfade
+                    ))
+                })?;
+            tracing::info!(
+                "{} {}",
+                if settings.looping {
+                    "looping"
+                } else {
+                    "playing"
+                },
+                playback::describe(region)
+            );
+            Some(Arc::new(LoopRegion::new(
+                region,
+                seconds,
+                settings.looping,
+                crossfade,
+            )))
         }
-        if output_fell_behind {
-            eprintln!("output stream fell behind: try increasing latency");
+        _ => None,
+    };
+    if settings.duck.is_some() && track.is_none() {
+        tracing::warn!("ignoring `--duck` without a playback file to duck");
+    }
+    let playback_speed = match settings.playback_speed {
+        Some(speed) if !stretch::is_valid_speed(speed) => {
+            return Err(EngineError::InvalidArgument(format!(
+                "the playback speed must be from {} to {}, got {}",
+                stretch::MIN_SPEED,
+                stretch::MAX_SPEED,
+                speed
+            ))
+            .into())
         }
+        Some(speed) => Some(Arc::new(AtomicF32::new(speed))),
+        None => None,
     };
 
-    let output_data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-        let mut input_fell_behind = false;
-        for sample in data {
-            *sample = match consumer.try_pop() {
-                Some(s) => s,
-                None => {
-                    input_fell_behind = true;
-                    0.0
-                }
+    // Inputs of more than two channels are mixed down to stereo, the same way for every output.
+    let downmixes: Vec<Option<Downmix>> = inputs
+        .iter()
+        .map(|input| {
+            let layout = settings.downmix?;
+            let channels = input.config.channels as usize;
+            if channels <= 2 {
+                return None;
+            }
+            if channels != layout.channels() {
+                tracing::warn!(
+                    "the {} has {} channels, not the {} of the layout: mixing them \
+                     down with equal weights",
+                    input.label,
+                    channels,
+                    layout.channels()
+                );
+                return Some(Downmix::equal(channels));
+            }
+            Some(Downmix::new(layout, settings.downmix_lfe))
+        })
+        .collect();
+    let hrirs = match &settings.hrir_dir {
+        Some(directory) => Some(HrirSet::load(directory)?),
+        None => settings.binaural.then_some(HrirSet::Model),
+    };
+    if let Some(hrirs) = &hrirs {
+        for input in &inputs {
+            // Checked here, so that a missing HRIR fails before any stream starts.
+            let binaural = Binaural::new(
+                hrirs,
+                input.config.channels as usize,
+                input.config.sample_rate.0,
+            )
+            .with_context(|| format!("can't render the {} binaurally", input.label))?;
+            tracing::info!(
+                "rendering the {} binaurally adds {:.1} milliseconds of latency",
+                input.label,
+                binaural.latency() as f32 * 1_000.0 / input.config.sample_rate.0 as f32
+            );
+        }
+    }
+
+    // Every input feeds every output through its own ring buffer, so that each pair of clocks
+    // drifts independently and a stalled stream only affects its own buffers.
+    let mut producers: Vec<Vec<(HeapProd<f32>, Converter)>> =
+        inputs.iter().map(|_| Vec::new()).collect();
+    // What every ring starts with, to prime them again once their input moved.
+    let mut prefills: Vec<Vec<usize>> = inputs.iter().map(|_| Vec::new()).collect();
+    let mut consumers: Vec<Vec<(HeapCons<f32>, f32)>> = Vec::new();
+    // The channels of the first input can be soloed to every output.
+    let mut solo = Solo::new(inputs[0].config.channels as usize);
+    // The effects take their latency out of the rings', so that the total stays as asked.
+    let budgets = outputs
+        .iter()
+        .map(|x| LatencyBudget::new(settings.latency, &x.config, chain_latency))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (output, budget) in outputs.iter().zip(&budgets) {
+        match budget.is_over() {
+            true => tracing::warn!(
+                "the effects leave less than a block of the latency to the rings, so the {} plays \
+                 {}",
+                output.label,
+                budget.describe()
+            ),
+            false => tracing::info!("the {} plays {}", output.label, budget.describe()),
+        }
+        session.note(|log| {
+            log.latency
+                .push((output.label.to_string(), budget.describe()))
+        });
+    }
+    for (output, budget) in outputs.iter().zip(&budgets) {
+        if settings.upmix.is_some() && output.config.channels <= 2 {
+            tracing::info!(
+                "playing plain stereo on the {}, which has no channels for the rears of the upmix",
+                output.label
+            );
+        }
+        // Create a delay in case the input and output devices aren't synced.
+        let mut sources = Vec::new();
+        for (index, input) in inputs.iter().enumerate() {
+            let size = RingSize::new(
+                budget.ring_frames(),
+                settings.prime,
+                &input.config,
+                &output.config,
+            )?;
+            let (producer, consumer) = size.ring()?;
+            let mut converter = Converter::new(&input.config, &output.config);
+            if let Some(downmix) = &downmixes[index] {
+                converter = converter.downmixing(downmix.clone());
+            }
+            if let Some(hrirs) = &hrirs {
+                let channels = input.config.channels as usize;
+                converter = converter.rendering_binaural(Binaural::new(
+                    hrirs,
+                    channels,
+                    input.config.sample_rate.0,
+                )?);
+            }
+            if !converter.is_identity() {
+                tracing::info!("adapting the {} to the {}", input.label, output.label);
+            }
+            if index == 0 && solo.channels() > 1 {
+                let channels = output.config.channels as usize;
+                converter = converter.routing(solo.router(channels, output.config.sample_rate.0));
+            }
+            producers[index].push((producer, converter));
+            prefills[index].push(size.prefill);
+            sources.push((consumer, input.gain));
+        }
+        consumers.push(sources);
+    }
+    if milliseconds(settings.latency) > ring::LONG_LATENCY {
+        tracing::warn!(
+            "a latency of {:.1} seconds holds that much in every ring buffer",
+            settings.latency / 1_000.0
+        );
+    }
+    // The sidechain feeds every output through its own small ring buffer too, but only their
+    // detectors read from it.
+    let mut key_producers = Vec::new();
+    let mut key_receivers: Vec<_> = outputs.iter().map(|_| None).collect();
+    if let Some((_, config)) = &sidechain {
+        for (output, receiver) in outputs.iter().zip(&mut key_receivers) {
+            let (producer, mut consumer) = sidechain::key_ring(output.config.sample_rate.0);
+            consumer.prepare(max_block_frames(&output.config));
+            let converter = Converter::new(
+                &sidechain::key_config(config.sample_rate),
+                &sidechain::key_config(output.config.sample_rate),
+            );
+            key_producers.push((producer, converter));
+            *receiver = Some(consumer);
+        }
+    }
+
+    // Build streams. The first input and output are required, the others are skipped on failure.
+    let building = tracing::info_span!("build_streams").entered();
+    tracing::info!("attempting to build all streams with f32 samples");
+    let (mut streams, event_receiver) = Streams::new(&settings, &inputs, &outputs);
+
+    let mut status = StatusLine::default();
+    let mut controls = Controls::default();
+
+    let solo = (solo.channels() > 1).then(|| Arc::new(Mutex::new(solo)));
+    if let Some(solo) = &solo {
+        let keys = solo.lock().unwrap().channels().min(SOLO_KEYS.len());
+        for (index, key) in SOLO_KEYS[..keys].iter().enumerate() {
+            // Only the first key is listed, for all of them.
+            let usage = match index {
+                0 => SOLO_USAGES[keys - 1],
+                _ => "",
             };
+            let solo = solo.clone();
+            controls.add(
+                key,
+                usage,
+                "solo an input channel, or un-solo it",
+                move |_| solo.lock().unwrap().toggle(index + 1).map(|_| ()),
+            );
+        }
+        let solo = solo.clone();
+        status.add(move || solo.lock().unwrap().describe());
+    }
+
+    // A band can be soloed on every output, from the terminal or the control socket.
+    let band_solo = Arc::new(BandSolo::default());
+    {
+        let shared = band_solo.clone();
+        controls.add(
+            "b",
+            "b <Hz>[:<Q>]",
+            "solo a band of the monitor feed, or go back with `b off`",
+            move |x| {
+                match x {
+                    "off" => shared.off(),
+                    _ => shared.solo(x.parse::<Band>()?),
+                }
+                tracing::info!("{}", shared.describe());
+                Ok(())
+            },
+        );
+        // Arrow keys, followed by Enter like any command.
+        let sweep = audition::SWEEP_OCTAVES;
+        let keys = [
+            (
+                "\u{1b}[D",
+                "← →",
+                "sweep the soloed band down or up",
+                -sweep,
+                1.0,
+            ),
+            ("\u{1b}[C", "", "", sweep, 1.0),
+            (
+                "\u{1b}[A",
+                "↑ ↓",
+                "narrow or widen the soloed band",
+                0.0,
+                audition::Q_STEP,
+            ),
+            ("\u{1b}[B", "", "", 0.0, 1.0 / audition::Q_STEP),
+        ];
+        for (name, usage, help, octaves, factor) in keys {
+            let shared = band_solo.clone();
+            controls.add(name, usage, help, move |_| {
+                let band = shared.nudge(octaves, factor);
+                tracing::info!("band: {}", audition::describe(band));
+                Ok(())
+            });
+        }
+    }
+
+    // The recorder thread gets the first input's samples through its own ring buffer.
+    let (recorder, recording) = start_recording(&settings, &inputs[0], &mut controls)?.unzip();
+
+    // The click only plays into the first output, so that a second one can record without it.
+    let tempo = match settings.click {
+        Some(bpm) if !(bpm > 0.0 && bpm.is_finite()) => {
+            return Err(EngineError::InvalidArgument(format!(
+                "the tempo of the click must be positive, got {}",
+                bpm
+            ))
+            .into())
         }
-        if input_fell_behind {
-            eprintln!("input stream fell behind: try increasing latency");
+        Some(bpm) => {
+            let tempo = Arc::new(AtomicF32::new(bpm));
+            let shared = tempo.clone();
+            controls.add(
+                "t",
+                "t <BPM>",
+                "change the tempo of the click",
+                move |x| match x.parse::<f32>() {
+                    Ok(bpm) if bpm > 0.0 && bpm.is_finite() => {
+                        shared.store(bpm);
+                        tracing::info!("click at {} BPM", bpm);
+                        Ok(())
+                    }
+                    _ => Err(format!("invalid tempo \"{}\"", x)),
+                },
+            );
+            Some(tempo)
         }
+        None => None,
     };
 
-    // Build streams.
-    println!(
-        "Attempting to build both streams with f32 samples and `{:?}`.",
-        config
-    );
-    let input_stream = input_device.build_input_stream(&config, input_data_fn, err_fn, None)?;
-    let output_stream = output_device.build_output_stream(&config, output_data_fn, err_fn, None)?;
-    println!("Successfully built streams.");
+    if let Some(region) = &region {
+        let nudge = playback::NUDGE.as_secs_f64();
+        let commands = [
+            (
+                "[-",
+                "[- [+",
+                "move the start of the playback region earlier or later",
+                Boundary::Start,
+                -nudge,
+            ),
+            ("[+", "", "", Boundary::Start, nudge),
+            (
+                "]-",
+                "]- ]+",
+                "move the end of the playback region earlier or later",
+                Boundary::End,
+                -nudge,
+            ),
+            ("]+", "", "", Boundary::End, nudge),
+        ];
+        for (name, usage, help, boundary, seconds) in commands {
+            let shared = region.clone();
+            controls.add(name, usage, help, move |_| {
+                let region = shared.nudge(boundary, seconds);
+                tracing::info!("playback region: {}", playback::describe(region));
+                Ok(())
+            });
+        }
+    }
+
+    if let Some(speed) = &playback_speed {
+        let shared = speed.clone();
+        controls.add(
+            "p",
+            "p <speed>",
+            "change the speed of the playback file",
+            move |x| match x.parse::<f32>() {
+                Ok(speed) if stretch::is_valid_speed(speed) => {
+                    shared.store(speed);
+                    tracing::info!("playing at {}x", speed);
+                    Ok(())
+                }
+                _ => Err(format!(
+                    "invalid speed \"{}\", expected {} to {}",
+                    x,
+                    stretch::MIN_SPEED,
+                    stretch::MAX_SPEED
+                )),
+            },
+        );
+    }
+
+    let mut taps = set_up_taps(
+        &settings,
+        &inputs,
+        &outputs,
+        &budgets,
+        &streams.output_rates,
+        &mut controls,
+        &mut status,
+    )?;
+    taps.input.recorder = recorder;
+    taps.threads.recording = recording;
+
+    let mut watchdog = Watchdog::new(settings.watchdog_retries);
+    // The first input and output can move to other devices during the run.
+    let first_input = (inputs[0].label, inputs[0].config.clone());
+    let first_output = (outputs[0].label, outputs[0].config.clone());
+    let meters = set_up_meters(&settings, interval, &inputs, &outputs, &mut status)?;
+    let Taps {
+        input: first_in,
+        output: first_out,
+        insert_stats,
+        goniometer: goniometer_frame,
+        threads,
+    } = taps;
+    streams.build_inputs(&settings, inputs, producers, prefills, first_in, &meters)?;
+    streams.build_sidechain(&settings, sidechain, key_producers);
+
+    let muted = Arc::new(AtomicBool::new(false));
+    let fade_out = Arc::new(FadeOut::default());
+    let drain = Arc::new(Drain::default());
+    let mut chains = Chains {
+        files: &files,
+        noise_learning: &noise_learning,
+        layout: &layout,
+        bypass: bypass_settings(&settings)?,
+        writers: Vec::new(),
+        guards: Vec::new(),
+        auto_bypasses: Vec::new(),
+        status: &mut status,
+    };
+    let monitor = Monitor {
+        band_solo: band_solo.clone(),
+        track: track.as_ref(),
+        playback_speed: playback_speed.clone(),
+        region: region.clone(),
+        tempo: tempo.clone(),
+        muted: muted.clone(),
+        fade_out: fade_out.clone(),
+        drain: drain.clone(),
+        meters: &meters,
+    };
+    let sources = consumers.into_iter().zip(key_receivers).collect();
+    streams.build_outputs(
+        &settings,
+        outputs,
+        sources,
+        first_out,
+        &mut chains,
+        &monitor,
+    )?;
+    let Chains {
+        writers,
+        guards,
+        mut auto_bypasses,
+        ..
+    } = chains;
+    let Streams {
+        rt,
+        events,
+        audio_logs,
+        reports,
+        counters,
+        output_labels,
+        inputs: mut input_streams,
+        outputs: mut output_streams,
+        input_port,
+        output_port,
+        ..
+    } = streams;
+    tracing::info!("successfully built streams");
+    drop(building);
+    let log_thread = audio_logs.spawn();
 
     // Play the streams.
-    println!(
-        "Starting the input and output streams with `{}` milliseconds of latency.",
+    tracing::info!(
+        "starting the input and output streams with `{}` milliseconds of latency",
         settings.latency
     );
-    input_stream.play()?;
-    output_stream.play()?;
-
-    // Run for 3 seconds before closing.
-    println!("Playing for 3 seconds... ");
-    std::thread::sleep(std::time::Duration::from_secs(3));
-    drop(input_stream);
-    drop(output_stream);
-    println!("Done!");
-    Ok(())
-}
+    for stream in &mut input_streams {
+        stream.play()?;
+    }
+    for (_, stream) in &mut output_streams {
+        stream.play()?;
+    }
+    let start = Instant::now();
+    if rt {
+        report_priorities(&reports);
+    }
+
+    if settings.noise_reduction.is_some() {
+        let trigger = noise_learning.clone();
+        let seconds = learn_seconds(&settings);
+        controls.add("n", "n", "learn the noise profile", move |_| {
+            tracing::info!("learning the noise profile for {} seconds", seconds);
+            trigger.request();
+            Ok(())
+        });
+    }
+    let writers = Arc::new(Mutex::new(writers));
+    let status = Arc::new(status);
+    let stop = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    control::stop_on_signals(stop.clone());
+    let quit = stop.clone();
+    controls.add("q", "q", "fade out and quit", move |_| {
+        quit.store(true, Ordering::Relaxed);
+        Ok(())
+    });
+    let (switches, switch_receiver) = mpsc::channel();
+    let moves = [
+        (
+            "o",
+            "o <DEVICE>",
+            "move the first output to another device",
+            Direction::Output,
+        ),
+        (
+            "i",
+            "i <DEVICE>",
+            "move the first input to another device",
+            Direction::Input,
+        ),
+    ];
+    for (name, usage, help, direction) in moves {
+        let switches: mpsc::Sender<SwitchRequest> = switches.clone();
+        controls.add(name, usage, help, move |device| {
+            if device.is_empty() {
+                return Err(format!("`{}` needs the name or index of a device", name));
+            }
+            let request = SwitchRequest {
+                direction,
+                device: device.to_string(),
+            };
+            switches
+                .send(request)
+                .map_err(|_| "the run is over".to_string())
+        });
+    }
+    if !auto_bypasses.is_empty() {
+        let profiles: Vec<Arc<ProfileStats>> =
+            auto_bypasses.iter().map(|x| x.stats().clone()).collect();
+        controls.add(
+            "e",
+            "e <effect>",
+            "bring back an effect bypassed for its CPU time, e.g. `e fir`",
+            move |name| {
+                let mut found = false;
+                for stats in &profiles {
+                    if let Some(index) = stats.position(name).filter(|x| stats.is_bypassed(*x)) {
+                        stats.bypass(index, false);
+                        found = true;
+                    }
+                }
+                match found {
+                    true => {
+                        tracing::info!("brought back {}", name);
+                        Ok(())
+                    }
+                    false => Err(format!("no effect \"{}\" is bypassed", name)),
+                }
+            },
+        );
+    }
+    // Commands read the terminal from now on, so devices not found can't be picked on it.
+    devices::disable_picker();
+    #[cfg(unix)]
+    let _socket = match &settings.control_socket {
+        Some(path) => {
+            let engine = Engine {
+                writers: writers.clone(),
+                layout: layout.clone(),
+                muted: muted.clone(),
+                status: status.clone(),
+                goniometer: goniometer_frame.clone(),
+                stop: stop.clone(),
+                switches: switches.clone(),
+                solo: solo.clone(),
+                band_solo: band_solo.clone(),
+                start,
+            };
+            let socket = ControlSocket::bind(path, settings.socket_mode, Arc::new(engine))?;
+            tracing::info!("listening for commands on \"{}\"", path.display());
+            Some(socket)
+        }
+        None => None,
+    };
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
-}
\ No newline at end of file
+    // Run for a while before closing, applying the automation meanwhile.
+    // A daemon and a quiet run only take the meters at every interval, for the control socket.
+    let print = !daemon_mode(&settings) && !settings.quiet;
+    if daemon_mode(&settings) {
+        #[cfg(unix)]
+        tracing::info!("running until shut down");
+    } else {
+        controls.spawn(!settings.quiet);
+        tracing::info!("playing for {} seconds", RUN_TIME.as_secs());
+    }
+    let reporter = interval
+        .filter(|_| !status.is_empty())
+        .map(|x| status.clone().spawn(start, x, print));
+    let mut policy = ErrorPolicy::new(settings.max_backend_errors);
+    let mut gave_up = None;
+    let mut output_switch = None;
+    let mut input_switch = None;
+    let mut session_failed = false;
+    // The rates of the streams, the inputs first, as last logged.
+    let mut known_rates: Vec<u32> = input_streams
+        .iter()
+        .chain(output_streams.iter().map(|(_, x)| x))
+        .map(|x| x.rate().get())
+        .collect();
+    automation.play(&writers, start, run_time, &stop, || {
+        if let Err(err) = session.flush() {
+            if !std::mem::replace(&mut session_failed, true) {
+                tracing::warn!("failed to write the session log: {}", err);
+            }
+        }
+        if gave_up.is_some() {
+            return;
+        }
+        match insert_stats
+            .as_ref()
+            .and_then(|x| x.measure().map(|y| (x, y)))
+        {
+            Some((stats, Some(frames))) => {
+                let line = format!(
+                    "{:.1} ms through the hardware insert, {} frames",
+                    stats.milliseconds(frames),
+                    frames
+                );
+                tracing::info!("{}", line);
+                session.note(|log| log.latency.push(("hardware insert".to_string(), line)));
+            }
+            Some((_, None)) => tracing::warn!(
+                "no click came back from the hardware insert, bypassing it: check the cables and \
+                 the routing"
+            ),
+            None => {}
+        }
+        for auto_bypass in &mut auto_bypasses {
+            if let Some(name) = auto_bypass.poll() {
+                let label = auto_bypass.stats().label();
+                tracing::warn!(
+                    "bypassing {} on the {}, which took too much of the blocks' time while \
+                     they xran: type `e {}` and Enter to bring it back",
+                    name,
+                    label,
+                    name
+                );
+                session.change(format!("bypassed {} on the {}", name, label));
+            }
+        }
+        for guard in &guards {
+            if let Some(diagnosis) = guard.take_diagnosis() {
+                report_non_finite(guard.label, &diagnosis, &layout);
+            }
+        }
+        for request in switch_receiver.try_iter() {
+            let now = Instant::now();
+            let (busy, kind) = match request.direction {
+                Direction::Output => (output_switch.is_some(), "output"),
+                Direction::Input => (input_switch.is_some(), "input"),
+            };
+            if busy {
+                tracing::warn!(
+                    device = %request.device,
+                    "the first {} is already moving to another device",
+                    kind
+                );
+                continue;
+            }
+            tracing::info!(device = %request.device, "moving the first {}", kind);
+            match request.direction {
+                Direction::Output => {
+                    output_switch = output_port
+                        .clone()
+                        .map(|port| Switch::new(port, request.device, now))
+                }
+                Direction::Input => {
+                    input_switch = input_port
+                        .clone()
+                        .map(|port| Switch::new(port, request.device, now))
+                }
+            }
+        }
+        if output_switch.is_some() {
+            let mut opener = DeviceOpener {
+                host: &host,
+                label: first_output.0,
+                config: StreamConfig {
+                    sample_rate: SampleRate(output_streams[0].1.rate().get()),
+                    ..first_output.1.clone()
+                },
+                dither: dither_mode(&settings),
+                events: events.clone(),
+            };
+            advance_switch(
+                &mut output_switch,
+                &mut opener,
+                &mut output_streams[0].1,
+                session,
+            );
+        }
+        if input_switch.is_some() {
+            let mut opener = DeviceOpener {
+                host: &host,
+                label: first_input.0,
+                config: StreamConfig {
+                    sample_rate: SampleRate(input_streams[0].rate().get()),
+                    ..first_input.1.clone()
+                },
+                dither: dither_mode(&settings),
+                events: events.clone(),
+            };
+            advance_switch(
+                &mut input_switch,
+                &mut opener,
+                &mut input_streams[0],
+                session,
+            );
+        }
+        let mut streams: Vec<&mut Restartable> = input_streams
+            .iter_mut()
+            .chain(output_streams.iter_mut().map(|(_, x)| x))
+            .collect();
+        let mut result = Ok(());
+        let restarts = watchdog.restarts();
+        for event in event_receiver.try_iter() {
+            let (action, line) = policy.handle(&event);
+            tracing::warn!("{}", line);
+            result = match action {
+                Action::Continue => Ok(()),
+                Action::Restart(reason) => watchdog.restart(&mut streams, &reason),
+                Action::Abort(reason) => Err(EngineError::StreamFailed(reason)),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() && settings.watchdog {
+            result = watchdog.poll(&mut streams);
+        }
+        if watchdog.restarts() > restarts {
+            session.change(format!(
+                "restarted the streams, {} of {} restarts",
+                watchdog.restarts(),
+                settings.watchdog_retries
+            ));
+            for (stream, known) in streams.iter().zip(&mut known_rates) {
+                let rate = stream.rate().get();
+                if rate != *known {
+                    tracing::warn!(
+                        from = *known,
+                        to = rate,
+                        "the {} now runs at {} Hz",
+                        stream.label(),
+                        rate
+                    );
+                    session.change(format!("the {} now runs at {} Hz", stream.label(), rate));
+                    *known = rate;
+                }
+            }
+        }
+        if let Err(err) = result {
+            tracing::error!("giving up on the streams: {:#}", err);
+            gave_up = Some(err);
+            stop.store(true, Ordering::Relaxed);
+        }
+    });
+    // The inputs stop first, so that the outputs play all they have before fading out.
+    drop(input_streams);
+    let latency = budgets
+        .iter()
+        .map(|x| Duration::from_secs_f64(x.milliseconds(x.total_frames()) / 1_000.0))
+        .max()
+        .unwrap_or_default();
+    if !drain.wait(latency + milliseconds(settings.drain_tail) + FADE_TIMEOUT) {
+        tracing::warn!("fading out before the outputs drained");
+    }
+    fade_out.start();
+    if !fade_out.wait(milliseconds(settings.fade_out) + FADE_TIMEOUT) {
+        tracing::warn!("closing the streams before they faded out");
+    }
+    if let Some(reporter) = reporter {
+        reporter.stop();
+    }
+    quiet.end();
+    for (index, stream) in output_streams {
+        drop(stream);
+        let xruns = &counters[index];
+        session.note(|log| {
+            log.stats
+                .push((output_labels[index].to_string(), xruns.summary()))
+        });
+        tracing::info!(
+            stream = output_labels[index],
+            overruns = xruns.overruns.load(Ordering::Relaxed),
+            underruns = xruns.underruns.load(Ordering::Relaxed),
+            "{}",
+            xruns.summary()
+        );
+    }
+    for stats in &meters.overload {
+        let line = stats.summary();
+        tracing::info!(stream = stats.label(), "{}", line);
+        session.note(|log| log.stats.push((stats.label().to_string(), line)));
+    }
+    if let Some(stats) = &meters.eq {
+        let line = stats.summary();
+        tracing::info!("dynamic EQ went as far as {}", line);
+        session.note(|log| log.stats.push(("dynamic EQ".to_string(), line)));
+    }
+    join_taps(threads, &settings, session)?;
+    log_thread.stop();
+    if let Some(err) = gave_up {
+        return Err(anyhow::Error::from(err).context("the streams failed"));
+    }
+    tracing::info!("done");
+    Ok(())
+}
+
+/// What the first input and output feed besides the monitor, set up before their streams.
+struct Taps {
+    input: FirstInput,
+    output: FirstOutput,
+    insert_stats: Option<Arc<InsertStats>>,
+    goniometer: Option<Arc<GoniometerFrame>>,
+    threads: TapThreads,
+}
+
+/// The threads the taps feed, joined at the end of the run, with their stats.
+struct TapThreads {
+    recording: Option<JoinHandle<anyhow::Result<RecordSummary>>>,
+    correlation: Option<(JoinHandle<()>, Arc<CorrelationStats>)>,
+    null_test: Option<(JoinHandle<NullMeter>, Arc<NullStats>)>,
+    sending: Option<(JoinHandle<()>, Arc<net::SendStats>)>,
+    receiving: Option<(JoinHandle<()>, Arc<net::ReceiveStats>)>,
+}
+
+/// Sets up the echo canceller, looper, hardware insert, retro buffer, correlation meter, null
+/// test and network streams of `settings`, on the first of `inputs` and `outputs`, whose rings
+/// hold `budgets` and whose rates are `output_rates`. Adds their commands to `controls` and their
+/// fields to `status`. The recorder is left to [`start_recording`].
+fn set_up_taps(
+    settings: &Settings,
+    inputs: &[Input],
+    outputs: &[Output],
+    budgets: &[LatencyBudget],
+    output_rates: &[Arc<StreamRate>],
+    controls: &mut Controls,
+    status: &mut StatusLine,
+) -> anyhow::Result<Taps> {
+    // The first output sends what it plays back to the first input as the echo reference, in mono
+    // at the input's rate.
+    let (echo_reference, echo_canceller) = match settings.aec {
+        true => {
+            let input = &inputs[0].config;
+            let reference = StreamConfig {
+                channels: 1,
+                sample_rate: input.sample_rate,
+                buffer_size: BufferSize::Default,
+            };
+            let (producer, consumer) = HeapRb::<f32>::new(input.sample_rate.0 as usize).split();
+            let erle = Arc::new(AtomicF32::default());
+            let mut canceller = EchoCanceller::new(
+                input.channels as usize,
+                input.sample_rate.0,
+                consumer,
+                erle.clone(),
+            );
+            canceller.prepare(max_block_frames(input));
+            status.add(move || format!("AEC: {:.1} dB ERLE", erle.load()));
+            let converter = Converter::new(&outputs[0].config, &reference);
+            (Some((producer, converter)), Some(canceller))
+        }
+        false => (None, None),
+    };
+
+    // Like the click, the looper only plays into the first output.
+    let looper = match settings.looper {
+        true => {
+            let output = &outputs[0].config;
+            let mut looper = Looper::new(
+                settings.loop_max,
+                settings.loop_feedback.clamp(0.0, 1.0),
+                output.channels as usize,
+                output.sample_rate.0,
+            );
+            let remote = Arc::new(Mutex::new(looper.remote()));
+            for (name, help, command) in [
+                (
+                    "l",
+                    "record, play or overdub the loop",
+                    looper::Command::Next,
+                ),
+                ("L", "clear the loop", looper::Command::Clear),
+            ] {
+                let remote = remote.clone();
+                controls.add(name, name, help, move |_| {
+                    remote.lock().unwrap().send(command)
+                });
+            }
+            status.add(move || remote.lock().unwrap().describe());
+            Some(looper)
+        }
+        false => None,
+    };
+
+    // The first output's chain sends to the hardware insert, and the first input returns from it.
+    let (insert_link, insert_send, insert_return, insert_stats) = match &settings.hw_insert {
+        Some(spec) => {
+            let (input, output) = (&inputs[0].config, &outputs[0].config);
+            if let Some(channel) = spec.send.iter().find(|x| **x >= output.channels as usize) {
+                return Err(EngineError::InvalidArgument(format!(
+                    "the hardware insert sends on channel {}, but the {} has {}",
+                    channel, outputs[0].label, output.channels
+                ))
+                .into());
+            }
+            if let Some(channel) = spec.returns.iter().find(|x| **x >= input.channels as usize) {
+                return Err(EngineError::InvalidArgument(format!(
+                    "the hardware insert returns on channel {}, but the {} has {}",
+                    channel, inputs[0].label, input.channels
+                ))
+                .into());
+            }
+            if input.sample_rate != output.sample_rate {
+                anyhow::bail!(
+                    "the hardware insert needs the {} and the {} at the same rate, not {} Hz \
+                         and {} Hz: set `--sample-rate`",
+                    inputs[0].label,
+                    outputs[0].label,
+                    input.sample_rate.0,
+                    output.sample_rate.0
+                );
+            }
+            let (link, mut send, mut returns, stats) = insert::link(
+                spec,
+                input.channels as usize,
+                output.sample_rate.0,
+                settings.quantum,
+            );
+            send.prepare(max_block_frames(output));
+            returns.prepare(max_block_frames(input));
+            let shared = stats.clone();
+            status.add(move || shared.describe());
+            (Some(link), Some(send), Some(returns), Some(stats))
+        }
+        None => (None, None, None, None),
+    };
+
+    let retro = settings.retro_buffer.map(|seconds| {
+        let input = &inputs[0].config;
+        let buffer = Arc::new(RetroBuffer::new(
+            seconds,
+            input.channels,
+            input.sample_rate.0,
+        ));
+        let shared = buffer.clone();
+        controls.add(
+            "s",
+            "s",
+            "save the last seconds of the input",
+            move |_| match shared.save(Path::new(".")) {
+                Ok((path, seconds)) => {
+                    tracing::info!("saved {:.1} seconds to \"{}\"", seconds, path.display());
+                    Ok(())
+                }
+                Err(err) => Err(format!("{:#}", err)),
+            },
+        );
+        buffer
+    });
+
+    // The correlation thread gets the first input's first two channels through its own ring
+    // buffer, and keeps the goniometer's points too.
+    let goniometer_frame = goniometer_enabled(settings).then(Arc::<GoniometerFrame>::default);
+    let (correlation_tap, correlation) = match settings.correlation || goniometer_frame.is_some() {
+        true if inputs[0].config.channels < 2 => {
+            tracing::warn!(
+                "no correlation or goniometer on the {}, which is mono",
+                inputs[0].label
+            );
+            (None, None)
+        }
+        true => {
+            let config = &inputs[0].config;
+            let rate = config.sample_rate.0 as f64;
+            let ring = HeapRb::<f32>::new((CORRELATION_BUFFER.as_secs_f64() * rate) as usize * 2);
+            let (producer, consumer) = ring.split();
+            let stats = Arc::new(CorrelationStats::default());
+            let meter = Correlation::new((correlation::WINDOW_SECONDS * rate) as usize);
+            let goniometer = goniometer_frame
+                .clone()
+                .map(|x| Goniometer::new(config.sample_rate.0, x));
+            let thread = correlation::spawn(consumer, meter, stats.clone(), goniometer);
+            if settings.correlation {
+                let shared = stats.clone();
+                status.add_flagged(move || shared.describe());
+            }
+            let tap = CorrelationTap::new(producer, config.channels as usize, stats.clone());
+            (Some(tap), Some((thread, stats)))
+        }
+        false => (None, None),
+    };
+
+    // The null test thread gets the first output's input and output through its own ring buffer.
+    let (null_tap, null_test) = match settings.null_test {
+        true => {
+            let output = &outputs[0].config;
+            let ring = HeapRb::<f32>::new(
+                (NULL_BUFFER.as_secs_f64() * output.sample_rate.0 as f64) as usize
+                    * output.channels as usize
+                    * 2,
+            );
+            let (producer, consumer) = ring.split();
+            let stats = Arc::new(NullStats::default());
+            let meter = NullMeter::new(
+                output.channels as usize,
+                output.sample_rate.0,
+                budgets[0].chain_frames(),
+            );
+            let thread = null::spawn(consumer, meter, stats.clone());
+            let shared = stats.clone();
+            status.add(move || shared.describe());
+            (
+                Some(NullTap::new(producer, stats.clone())),
+                Some((thread, stats)),
+            )
+        }
+        false => (None, None),
+    };
+
+    // The network thread gets the first output's samples through its own ring buffer.
+    let (net_sender, sending) = match &settings.net_send {
+        Some(address) => {
+            let output = &outputs[0].config;
+            let address = net::resolve(address)?;
+            let ring = HeapRb::<f32>::new(
+                (NET_BUFFER.as_secs_f64() * output.sample_rate.0 as f64) as usize
+                    * output.channels as usize,
+            );
+            let (producer, consumer) = ring.split();
+            let stats = Arc::new(net::SendStats::default());
+            let thread = net::spawn_sender(
+                address,
+                consumer,
+                output.channels,
+                output_rates[0].clone(),
+                settings.net_encoding,
+                dither_mode(settings),
+                stats.clone(),
+            )?;
+            tracing::info!("sending the {} to {}", outputs[0].label, address);
+            let shared = stats.clone();
+            status.add(move || shared.describe());
+            (Some((producer, stats.clone())), Some((thread, stats)))
+        }
+        None => (None, None),
+    };
+
+    // The network thread reorders the packets received, and the first output plays them.
+    let (net_source, receiving) = match &settings.net_receive {
+        Some(address) => {
+            let output = &outputs[0].config;
+            let address = net::resolve(address)?;
+            let jitter =
+                Duration::try_from_secs_f32(settings.jitter_ms / 1_000.0).map_err(|_| {
+                    EngineError::InvalidArgument(format!(
+                        "invalid jitter {} ms",
+                        settings.jitter_ms
+                    ))
+                })?;
+            // Room for the jitter and the latency, at any sender's rate up to 192 kHz.
+            let room = (NET_BUFFER + jitter * 2).as_secs_f64() * 192_000.0;
+            let ring = HeapRb::<f32>::new(room as usize * output.channels as usize);
+            let (producer, consumer) = ring.split();
+            let stats = Arc::new(net::ReceiveStats::default());
+            let thread =
+                net::spawn_receiver(address, producer, output.channels, jitter, stats.clone())?;
+            tracing::info!("receiving on {} for the {}", address, outputs[0].label);
+            let shared = stats.clone();
+            status.add(move || shared.describe());
+            let source = NetSource::new(
+                consumer,
+                output.channels as usize,
+                output.sample_rate.0,
+                jitter,
+                stats.clone(),
+            );
+            (Some(source), Some((thread, stats)))
+        }
+        None => (None, None),
+    };
+    Ok(Taps {
+        input: FirstInput {
+            recorder: None,
+            echo_canceller,
+            retro,
+            insert_return,
+            correlation_tap,
+        },
+        output: FirstOutput {
+            echo_reference,
+            looper,
+            net_sender,
+            net_source,
+            null_tap,
+            insert_send,
+            insert_link,
+        },
+        insert_stats,
+        goniometer: goniometer_frame,
+        threads: TapThreads {
+            recording: None,
+            correlation,
+            null_test,
+            sending,
+            receiving,
+        },
+    })
+}
+
+/// Joins the threads of the taps once the streams are closed, logging their summaries and noting
+/// them in `session`. Fails if one panicked, or if the null test failed.
+fn join_taps(threads: TapThreads, settings: &Settings, session: &Session) -> anyhow::Result<()> {
+    let TapThreads {
+        recording,
+        correlation,
+        null_test,
+        sending,
+        receiving,
+    } = threads;
+    if let Some((thread, stats)) = receiving {
+        let _ = thread.join();
+        let load = |x: &std::sync::atomic::AtomicU64| x.load(Ordering::Relaxed);
+        let line = format!(
+            "{} packets: {} late, {} lost, of which {} concealed, {} duplicates",
+            load(&stats.received),
+            load(&stats.late),
+            load(&stats.lost),
+            load(&stats.concealed),
+            load(&stats.duplicates)
+        );
+        tracing::info!("received {}", line);
+        session.note(|log| log.stats.push(("received".to_string(), line)));
+    }
+    if let Some((thread, stats)) = sending {
+        let _ = thread.join();
+        let line = format!(
+            "{} packets, with {} send errors",
+            stats.packets.load(Ordering::Relaxed),
+            stats.errors.load(Ordering::Relaxed)
+        );
+        tracing::info!("sent {}", line);
+        session.note(|log| log.stats.push(("sent".to_string(), line)));
+    }
+    if let Some(thread) = recording {
+        let summary = thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("the recorder thread panicked"))??;
+        let line = match &settings.record_dir {
+            Some(_) => format!(
+                "{:.1} seconds to {} file(s), discarding {} take(s)",
+                summary.seconds,
+                summary.files.len(),
+                summary.discarded
+            ),
+            None => format!(
+                "{:.1} seconds to {} file(s)",
+                summary.seconds,
+                summary.files.len()
+            ),
+        };
+        tracing::info!("recorded {}", line);
+        session.note(|log| {
+            log.stats.push(("recorded".to_string(), line));
+            log.recordings.extend(summary.files.iter().cloned());
+        });
+        for file in &summary.files {
+            tracing::info!(
+                path = %file.path.display(),
+                seconds = file.seconds,
+                true_peak_dbtp = file.true_peak_db,
+                markers = file.markers,
+                lufs = file.lufs,
+                "recorded file"
+            );
+        }
+        // The takes kept, their files together.
+        let mut takes: Vec<(usize, f64, f32)> = Vec::new();
+        for file in &summary.files {
+            let Some(number) = file.take else { continue };
+            match takes.last_mut() {
+                Some(take) if take.0 == number => {
+                    take.1 += file.seconds;
+                    take.2 = take.2.max(file.true_peak_db);
+                }
+                _ => takes.push((number, file.seconds, file.true_peak_db)),
+            }
+        }
+        for (number, seconds, peak) in takes {
+            tracing::info!(
+                take = number,
+                seconds,
+                true_peak_dbtp = peak,
+                "kept take {:03}: {:.1} s, peaking at {:.1} dBTP",
+                number,
+                seconds,
+                peak
+            );
+        }
+    }
+    if let Some((thread, stats)) = correlation {
+        thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("the correlation thread panicked"))?;
+        let dropped = stats.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            tracing::warn!("the correlation meter skipped {} blocks", dropped);
+        }
+    }
+    if let Some((thread, stats)) = null_test {
+        let meter = thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("the null test thread panicked"))?;
+        let dropped = stats.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            tracing::warn!("the null test skipped {} blocks", dropped);
+        }
+        match (meter.total_db(), meter.delay()) {
+            (Some(residual), Some(delay)) => {
+                let line = format!(
+                    "{:.1} dB of residual relative to the input, with the output {} frames behind",
+                    residual, delay
+                );
+                tracing::info!("null test: {}", line);
+                session.note(|log| log.stats.push(("null test".to_string(), line)));
+                if let Some(limit) = settings.null_fail_above.filter(|x| residual > *x) {
+                    anyhow::bail!(
+                        "the null test failed: {:.1} dB of residual is above {:.1} dB",
+                        residual,
+                        limit
+                    );
+                }
+            }
+            _ => {
+                tracing::info!("null test: the input was silent");
+                session.note(|log| {
+                    log.stats
+                        .push(("null test".to_string(), "the input was silent".to_string()))
+                });
+                if settings.null_fail_above.is_some() {
+                    anyhow::bail!("the null test failed: there was no input to compare");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Starts the recorder of `settings`, if any, whose thread gets the samples of `input` through
+/// the returned feed, adding its commands to `controls`.
+fn start_recording(
+    settings: &Settings,
+    input: &Input,
+    controls: &mut Controls,
+) -> anyhow::Result<Option<(RecordFeed, JoinHandle<anyhow::Result<RecordSummary>>)>> {
+    let Some(path) = settings.record.as_ref().or(settings.record_dir.as_ref()) else {
+        return Ok(None);
+    };
+    let config = &input.config;
+    let channels = match &settings.record_channels {
+        Some(pick) => {
+            pick.check(config.channels).map_err(|x| {
+                EngineError::InvalidArgument(format!("cannot record the {}: {}", input.label, x))
+            })?;
+            pick.0.len() as u16
+        }
+        None => config.channels,
+    };
+    let seconds = RECORD_BUFFER.as_secs() as usize;
+    let ring = HeapRb::<f32>::new(seconds * config.sample_rate.0 as usize * channels as usize);
+    let (producer, consumer) = ring.split();
+    let format = match settings.record_format {
+        RecordFormat::Wav => Format::Wav,
+        RecordFormat::Flac => {
+            if settings.record_compression > flac::MAX_LEVEL {
+                return Err(EngineError::InvalidArgument(format!(
+                    "the compression level of FLAC recordings is at most {}",
+                    flac::MAX_LEVEL
+                ))
+                .into());
+            }
+            flac::check(channels, config.sample_rate.0, settings.record_bits)
+                .map_err(|x| anyhow::anyhow!("cannot record the {}: {}", input.label, x))?;
+            Format::Flac {
+                bits: settings.record_bits,
+                level: settings.record_compression,
+                dither: dither_mode(settings),
+            }
+        }
+    };
+    let markers = Arc::new(CueMarkers::default());
+    let shared = markers.clone();
+    let rate = config.sample_rate.0 as f64;
+    controls.add("k", "k", "drop a marker into the recording", move |_| {
+        let frame = shared.mark();
+        tracing::info!("marker at {:.3} s of the input", frame as f64 / rate);
+        Ok(())
+    });
+    let rate_change = Arc::new(RateChange::default());
+    let pre_roll =
+        match settings.pre_roll {
+            Some(seconds) => Some(Duration::try_from_secs_f32(seconds).map_err(|_| {
+                EngineError::InvalidArgument(format!("invalid pre-roll {}", seconds))
+            })?),
+            None => None,
+        };
+    let takes = settings.record_dir.as_ref().map(|dir| {
+        Arc::new(Takes {
+            dir: dir.clone(),
+            extension: match format {
+                Format::Wav => "wav",
+                Format::Flac { .. } => "flac",
+            },
+            pre_roll: pre_roll.unwrap_or_default(),
+            control: TakeControl::default(),
+        })
+    });
+    if let Some(takes) = &takes {
+        let shared = takes.clone();
+        controls.add("r", "r", "start a take, or stop and keep it", move |_| {
+            let event = match shared.control.state() {
+                TakeState::Idle => TakeEvent::Start,
+                TakeState::Recording => TakeEvent::Keep,
+            };
+            shared
+                .control
+                .send(event)
+                .map(|_| ())
+                .map_err(str::to_string)
+        });
+        let shared = takes.clone();
+        controls.add("d", "d", "stop the take and discard it", move |_| {
+            shared
+                .control
+                .send(TakeEvent::Discard)
+                .map(|_| ())
+                .map_err(str::to_string)
+        });
+    }
+    let record_settings = RecordSettings {
+        path: path.clone(),
+        format,
+        gate: settings.record_gate,
+        preroll: Duration::from_secs_f32(settings.record_preroll.max(0.0) / 1_000.0),
+        split: settings.record_split,
+        split_every: settings.record_split_every,
+        split_size: settings.record_split_size,
+        normalize: settings.record_normalize.map(|target_lufs| Normalize {
+            target_lufs,
+            joint: settings.record_normalize_joint,
+        }),
+        arm: match pre_roll {
+            Some(duration) if takes.is_none() => {
+                let armed = Arc::new(AtomicBool::new(false));
+                let shared = armed.clone();
+                controls.add("r", "r", "start recording", move |_| {
+                    match shared.swap(true, Ordering::Relaxed) {
+                        true => Err("already recording".to_string()),
+                        false => Ok(()),
+                    }
+                });
+                Some((armed, duration))
+            }
+            _ => None,
+        },
+        markers: Some(markers.clone()),
+        rate_change: Some(rate_change.clone()),
+        takes: takes.clone(),
+    };
+    let thread = record::spawn(record_settings, consumer, channels, config.sample_rate.0);
+    let mut feed = RecordFeed::new(producer, config.channels, settings.record_channels.clone())
+        .marking(markers)
+        .splitting(rate_change);
+    if let Some(takes) = takes {
+        feed = feed.taking(takes);
+    }
+    Ok(Some((feed, thread)))
+}
+
+/// The meters of the streams, each with its field in the status line.
+struct Meters {
+    /// Of every input, with `--overload`.
+    overload: Vec<Arc<OverloadStats>>,
+    /// Of every input, when the status line has an interval.
+    levels: Vec<Arc<LevelStats>>,
+    /// Of every output, with `--headroom`.
+    headroom: Vec<Arc<HeadroomStats>>,
+    /// Of the AGC and the dynamic EQ bands of the first output's chain.
+    agc: Option<Arc<AgcStats>>,
+    eq: Option<Arc<EqStats>>,
+    /// Of every ring, by output and then by input.
+    fills: Vec<Vec<Arc<FillStats>>>,
+}
+
+/// Sets up the meters of `inputs` and `outputs`, adding their fields to `status`, which is
+/// printed at `interval` if it has one.
+fn set_up_meters(
+    settings: &Settings,
+    interval: Option<Duration>,
+    inputs: &[Input],
+    outputs: &[Output],
+    status: &mut StatusLine,
+) -> anyhow::Result<Meters> {
+    // Every input watches its own front end, and the status line holds "OVL" for any of them.
+    if !(0.0..=1.0).contains(&settings.overload_sensitivity) {
+        return Err(EngineError::InvalidArgument(format!(
+            "invalid overload sensitivity {}, expected 0 to 1",
+            settings.overload_sensitivity
+        ))
+        .into());
+    }
+    let overload_stats: Vec<Arc<OverloadStats>> = match settings.overload {
+        true => inputs
+            .iter()
+            .map(|x| Arc::new(OverloadStats::new(x.label)))
+            .collect(),
+        false => Vec::new(),
+    };
+    if !overload_stats.is_empty() {
+        let shared = overload_stats.clone();
+        status.add_flagged(move || overload::describe(&shared));
+    }
+    // The levels of the inputs over every interval of the status line, if it has any.
+    let level_stats: Vec<Arc<LevelStats>> = match interval {
+        Some(_) => inputs
+            .iter()
+            .map(|x| Arc::new(LevelStats::new(x.label)))
+            .collect(),
+        None => Vec::new(),
+    };
+    if !level_stats.is_empty() {
+        let shared = level_stats.clone();
+        status.on_interval(move |elapsed| {
+            for stats in &shared {
+                stats.take(elapsed);
+            }
+        });
+        let shared = level_stats.clone();
+        status.add(move || meter::describe(&shared));
+    }
+    // Every output meters its own stages, and the status line warns about the first one over.
+    let headroom_stats: Vec<Arc<HeadroomStats>> = match settings.headroom {
+        true => outputs
+            .iter()
+            .map(|x| Arc::new(HeadroomStats::new(x.label, settings.headroom_threshold)))
+            .collect(),
+        false => Vec::new(),
+    };
+    if !headroom_stats.is_empty() {
+        let shared = headroom_stats.clone();
+        status.add_flagged(move || headroom::describe(&shared));
+    }
+
+    // The gain of the first output's AGC goes in the status line.
+    let agc_stats = settings.agc.map(|_| Arc::new(AgcStats::default()));
+    if let Some(stats) = &agc_stats {
+        let shared = stats.clone();
+        status.add(move || shared.describe());
+    }
+    // And so do the gains of its dynamic EQ bands.
+    let eq_stats = EqStats::new(&channel_strip(settings).eq).map(Arc::new);
+    if let Some(stats) = &eq_stats {
+        let shared = stats.clone();
+        status.add(move || shared.describe());
+    }
+
+    // The delay the rings actually add, from how full the outputs find them at every interval.
+    let fills: Vec<Vec<Arc<FillStats>>> = outputs
+        .iter()
+        .map(|output| {
+            inputs
+                .iter()
+                .map(|input| {
+                    Arc::new(FillStats::new(
+                        input.label,
+                        output.label,
+                        &output.config,
+                        settings.ring_band,
+                    ))
+                })
+                .collect()
+        })
+        .collect();
+    {
+        let shared = fills.clone();
+        status.on_interval(move |elapsed| {
+            for stats in shared.iter().flatten() {
+                let (_, Some(alarm)) = stats.take(elapsed) else {
+                    continue;
+                };
+                tracing::warn!(
+                    input = stats.input,
+                    output = stats.output,
+                    "the ring from the {} to the {} has been {} its band for {:.1} s, at {:.1} ms \
+                     and {}: {} may follow, try increasing latency",
+                    stats.input,
+                    stats.output,
+                    if alarm.over { "over" } else { "under" },
+                    alarm.seconds,
+                    alarm.fill,
+                    alarm.describe_trend(),
+                    if alarm.over { "overruns" } else { "underruns" }
+                );
+            }
+        });
+        let shared = fills.clone();
+        let band = settings.ring_band;
+        status.add_flagged(move || {
+            let fields: Vec<(String, bool)> =
+                shared.iter().flatten().map(|x| x.describe()).collect();
+            let texts: Vec<&str> = fields.iter().map(|x| x.0.as_str()).collect();
+            let mut text = format!("buffer: {}", texts.join(" / "));
+            if let Some(band) = band {
+                text += &format!(", band {:.1} to {:.1} ms", band.low, band.high);
+            }
+            (text, fields.iter().any(|x| x.1))
+        });
+    }
+
+    Ok(Meters {
+        overload: overload_stats,
+        levels: level_stats,
+        headroom: headroom_stats,
+        agc: agc_stats,
+        eq: eq_stats,
+        fills,
+    })
+}
+
+/// What only the first input's callback feeds: the recorder, and the processing and taps of the
+/// input.
+#[derive(Default)]
+struct FirstInput {
+    recorder: Option<RecordFeed>,
+    echo_canceller: Option<EchoCanceller>,
+    retro: Option<Arc<RetroBuffer>>,
+    insert_return: Option<InsertReturn>,
+    correlation_tap: Option<CorrelationTap>,
+}
+
+/// What only the first output's callback plays or sends.
+#[derive(Default)]
+struct FirstOutput {
+    echo_reference: Option<(HeapProd<f32>, Converter)>,
+    looper: Option<Looper>,
+    net_sender: Option<(HeapProd<f32>, Arc<net::SendStats>)>,
+    net_source: Option<NetSource>,
+    null_tap: Option<NullTap>,
+    insert_send: Option<InsertSend>,
+    insert_link: Option<InsertLink>,
+}
+
+/// What the chains of the outputs are built from, and what building them collects for the main
+/// thread, with the status line their profiles report to.
+struct Chains<'a> {
+    files: &'a ChainFiles,
+    noise_learning: &'a LearnTrigger,
+    layout: &'a ParamLayout,
+    bypass: Option<BypassSettings>,
+    writers: Vec<ParamWriter<Vec<f32>>>,
+    guards: Vec<Arc<GuardStats>>,
+    auto_bypasses: Vec<AutoBypass>,
+    status: &'a mut StatusLine,
+}
+
+/// The rings an output plays the inputs from, with their gains, and the one keying it from the
+/// sidechain.
+type OutputSources = (Vec<(HeapCons<f32>, f32)>, Option<KeyReceiver>);
+
+/// What every output plays over the inputs, the controls of the feed it shares with the main
+/// thread, and its meters.
+struct Monitor<'a> {
+    band_solo: Arc<BandSolo>,
+    track: Option<&'a Track>,
+    playback_speed: Option<Arc<AtomicF32>>,
+    region: Option<Arc<LoopRegion>>,
+    /// Of the click, which only plays into the first output.
+    tempo: Option<Arc<AtomicF32>>,
+    muted: Arc<AtomicBool>,
+    fade_out: Arc<FadeOut>,
+    drain: Arc<Drain>,
+    meters: &'a Meters,
+}
+
+/// The streams of a session as they're built, with what their callbacks share.
+struct Streams {
+    /// Whether the callbacks promote their threads.
+    rt: bool,
+    /// The error callbacks of every stream report to the main thread, which restarts them.
+    events: mpsc::Sender<EngineEvent>,
+    /// Callbacks and their effects log through rings of their own, drained on another thread.
+    audio_logs: AudioLogs,
+    reports: Vec<(&'static str, Arc<priority::Report>)>,
+    /// The rates the streams run at, which change when the watchdog builds them again at others.
+    input_rates: Vec<Arc<StreamRate>>,
+    output_rates: Vec<Arc<StreamRate>>,
+    counters: Vec<Arc<XrunCounters>>,
+    input_labels: Vec<&'static str>,
+    output_labels: Vec<&'static str>,
+    inputs: Vec<Restartable>,
+    /// With the index of their output, since the ones after the first may fail to build.
+    outputs: Vec<(usize, Restartable)>,
+    input_port: Option<InputPort>,
+    output_port: Option<OutputPort>,
+}
+
+impl Streams {
+    /// Starts building the streams of `inputs` and `outputs`, returning the receiver of their
+    /// events.
+    fn new(
+        settings: &Settings,
+        inputs: &[Input],
+        outputs: &[Output],
+    ) -> (Self, mpsc::Receiver<EngineEvent>) {
+        let (events, event_receiver) = mpsc::channel();
+        let streams = Streams {
+            rt: !settings.no_rt,
+            events,
+            audio_logs: AudioLogs::default(),
+            reports: Vec::new(),
+            input_rates: inputs
+                .iter()
+                .map(|x| StreamRate::new(x.config.sample_rate.0))
+                .collect(),
+            output_rates: outputs
+                .iter()
+                .map(|x| StreamRate::new(x.config.sample_rate.0))
+                .collect(),
+            counters: outputs
+                .iter()
+                .map(|_| Arc::new(XrunCounters::default()))
+                .collect(),
+            input_labels: inputs.iter().map(|x| x.label).collect(),
+            output_labels: outputs.iter().map(|x| x.label).collect(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            input_port: None,
+            output_port: None,
+        };
+        (streams, event_receiver)
+    }
+
+    /// Builds the stream of every input, feeding the outputs through `producers`, primed with
+    /// `prefills`, with the first input feeding `first` too. Only the first input must build.
+    fn build_inputs(
+        &mut self,
+        settings: &Settings,
+        inputs: Vec<Input>,
+        producers: Vec<Vec<(HeapProd<f32>, Converter)>>,
+        prefills: Vec<Vec<usize>>,
+        mut first: FirstInput,
+        meters: &Meters,
+    ) -> anyhow::Result<()> {
+        let Streams {
+            rt,
+            events,
+            audio_logs,
+            reports,
+            input_rates,
+            output_rates,
+            counters,
+            output_labels,
+            inputs: input_streams,
+            input_port,
+            ..
+        } = self;
+        let rt = *rt;
+        let inputs = inputs.into_iter().zip(producers).zip(prefills);
+        for (index, ((input, producers), prefill)) in inputs.enumerate() {
+            let label = input.label;
+            let mut fan_out = FanOut::new(producers);
+            let counters = counters.clone();
+            let output_labels = output_labels.clone();
+            let FirstInput {
+                mut recorder,
+                mut echo_canceller,
+                retro,
+                mut insert_return,
+                mut correlation_tap,
+            } = match index {
+                0 => std::mem::take(&mut first),
+                _ => FirstInput::default(),
+            };
+            let prepared = max_block_frames(&input.config);
+            let input_channels = input.config.channels as usize;
+            let mut cancelled = match echo_canceller {
+                Some(_) => vec![0.0; prepared * input_channels],
+                None => Vec::new(),
+            };
+            let mut oversize = false;
+            let mut log = audio_logs.log();
+            let report = Arc::new(priority::Report::new());
+            reports.push((label, report.clone()));
+            let mut rate = input_rates[index].watch();
+            let mut output_watches: Vec<RateWatch> =
+                output_rates.iter().map(|x| x.watch()).collect();
+            let mut sample_rates: Vec<u32> = output_watches.iter().map(|x| x.rate()).collect();
+            let levels = meters.levels.get(index).cloned();
+            let mut overload = meters.overload.get(index).map(|x| {
+                OverloadDetector::new(
+                    x.clone(),
+                    settings.overload_sensitivity,
+                    input.config.channels as usize,
+                    input.config.sample_rate.0,
+                )
+            });
+            let input_data_fn = move |data: &[f32], moved: bool| {
+                denormal::protect_thread();
+                if rt {
+                    priority::promote_thread(&report);
+                }
+                if moved {
+                    fan_out.reprime(&prefill);
+                }
+                let frames = data.len() / input_channels;
+                if frames > prepared && !std::mem::replace(&mut oversize, true) {
+                    log.push(AudioEvent::OversizeBlock {
+                        stream: label,
+                        frames,
+                        prepared,
+                    });
+                }
+                // The streams were built again at other rates, the rings drained while they were.
+                let new_rate = rate.changed();
+                let mut changed = new_rate.is_some();
+                for watch in &mut output_watches {
+                    changed |= watch.changed().is_some();
+                }
+                if changed {
+                    for (sample_rate, watch) in sample_rates.iter_mut().zip(&output_watches) {
+                        *sample_rate = watch.rate();
+                    }
+                    fan_out.set_rates(rate.rate(), &sample_rates);
+                    fan_out.reprime(&prefill);
+                }
+                if let (Some(new_rate), Some(recorder)) = (new_rate, &recorder) {
+                    recorder.change_rate(new_rate);
+                }
+                if let (Some(new_rate), Some(detector)) = (new_rate, &mut overload) {
+                    detector.set_sample_rate(new_rate);
+                }
+                if let (Some(new_rate), Some(canceller)) = (new_rate, &mut echo_canceller) {
+                    canceller.set_sample_rate(new_rate);
+                }
+                // The front end overloads before anything touches the input.
+                if let Some(detector) = &mut overload {
+                    detector.process(data);
+                }
+                if let Some(levels) = &levels {
+                    levels.add(data);
+                }
+                // Everything downstream, monitor and recording alike, gets the input without echo.
+                let data = match &mut echo_canceller {
+                    Some(canceller) => {
+                        let length = data.len().min(cancelled.len());
+                        let cancelled = &mut cancelled[..length];
+                        cancelled.copy_from_slice(&data[..cancelled.len()]);
+                        canceller.process(cancelled);
+                        &*cancelled
+                    }
+                    None => data,
+                };
+                // Nothing else gets the return of the hardware insert.
+                let data = match &mut insert_return {
+                    Some(returns) => returns.take(data),
+                    None => data,
+                };
+                fan_out.push(data, |output| {
+                    counters[output].overruns.fetch_add(1, Ordering::Relaxed);
+                    log.push(AudioEvent::Overrun {
+                        input: label,
+                        output: output_labels[output],
+                    });
+                });
+                if let Some(retro) = &retro {
+                    retro.push(data);
+                }
+                if let Some(tap) = &mut correlation_tap {
+                    tap.send(data);
+                }
+                if let Some(recorder) = &mut recorder {
+                    if !recorder.push(data) {
+                        log.push(AudioEvent::RecorderBehind { input: label });
+                    }
+                }
+            };
+            let port = InputPort::new(&input.config, input_data_fn);
+            let mut callback = port.callback();
+            match Restartable::input(
+                label,
+                &input.device,
+                &input.config,
+                input.format,
+                move |data: &[f32], _: &cpal::InputCallbackInfo| callback(data),
+                events.clone(),
+            ) {
+                Ok(stream) => {
+                    input_streams.push(stream.sharing_rate(input_rates[index].clone()));
+                    input_port.get_or_insert(port);
+                }
+                Err(err) if index > 0 => {
+                    tracing::warn!("{:#}, continuing without it", anyhow::Error::from(err))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the stream of the sidechain, if any, keying the outputs through `producers`.
+    /// Failing to only leaves the detectors keyed by their own signals.
+    fn build_sidechain(
+        &mut self,
+        settings: &Settings,
+        sidechain: Option<(cpal::Device, StreamConfig)>,
+        key_producers: Vec<(HeapProd<f32>, Converter)>,
+    ) {
+        let Some((device, config)) = sidechain else {
+            return;
+        };
+        let Streams {
+            rt,
+            events,
+            reports,
+            inputs: input_streams,
+            ..
+        } = self;
+        let rt = *rt;
+        let label = "sidechain stream";
+        let mut sender = KeySender::new(
+            config.channels as usize,
+            settings.sidechain_channel,
+            key_producers,
+        );
+        sender.prepare(max_block_frames(&config));
+        let report = Arc::new(priority::Report::new());
+        reports.push((label, report.clone()));
+        let sidechain_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
+            denormal::protect_thread();
+            if rt {
+                priority::promote_thread(&report);
+            }
+            sender.push(data);
+        };
+        let format = SampleFormat::F32;
+        match Restartable::input(
+            label,
+            &device,
+            &config,
+            format,
+            sidechain_data_fn,
+            events.clone(),
+        ) {
+            Ok(stream) => input_streams.push(stream),
+            Err(err) => tracing::warn!("{:#}, continuing without it", anyhow::Error::from(err)),
+        }
+    }
+
+    /// Builds the stream of every output, with its chain, playing the inputs from the rings and
+    /// keyed from the sidechain of `sources`, with the first output playing and sending `first`
+    /// too. Only the first output must build.
+    fn build_outputs(
+        &mut self,
+        settings: &Settings,
+        outputs: Vec<Output>,
+        sources: Vec<OutputSources>,
+        mut first: FirstOutput,
+        chains: &mut Chains,
+        monitor: &Monitor,
+    ) -> anyhow::Result<()> {
+        let Streams {
+            rt,
+            events,
+            audio_logs,
+            reports,
+            input_rates,
+            output_rates,
+            counters,
+            input_labels,
+            outputs: output_streams,
+            output_port,
+            ..
+        } = self;
+        let rt = *rt;
+        let Monitor {
+            band_solo,
+            track,
+            playback_speed,
+            region,
+            tempo,
+            muted,
+            fade_out,
+            drain,
+            meters,
+        } = monitor;
+        let status = &mut *chains.status;
+        for (index, (output, (sources, mut key_receiver))) in
+            outputs.into_iter().zip(sources).enumerate()
+        {
+            let label = output.label;
+            let FirstOutput {
+                mut echo_reference,
+                mut looper,
+                mut net_sender,
+                mut net_source,
+                mut null_tap,
+                mut insert_send,
+                insert_link,
+            } = match index {
+                0 => std::mem::take(&mut first),
+                _ => FirstOutput::default(),
+            };
+            let mut mixer = Mixer::new(sources);
+            mixer.prepare(max_block_frames(&output.config) * output.config.channels as usize);
+            let links = ChainLinks {
+                agc: meters.agc.clone().filter(|_| index == 0),
+                eq: meters.eq.clone().filter(|_| index == 0),
+                insert: insert_link,
+                headroom: meters.headroom.get(index).cloned(),
+            };
+            let profile = (!settings.no_profiling).then_some((label, output.config.sample_rate.0));
+            let guard_stats = (!settings.no_nan_guard)
+                .then(|| Arc::new(GuardStats::new(label, chains.layout.len())));
+            chains.guards.extend(guard_stats.clone());
+            let (mut chain, profile) = match settings.precision {
+                Precision::Single => connect(
+                    build_chain::<f32>(
+                        settings,
+                        &output.config,
+                        chains.files,
+                        chains.noise_learning,
+                        links,
+                        Some(audio_logs),
+                    )?,
+                    &mut chains.writers,
+                    profile,
+                    guard_stats.clone(),
+                    output_rates[index].watch(),
+                ),
+                #[cfg(feature = "double-precision")]
+                Precision::Double => connect(
+                    build_chain::<f64>(
+                        settings,
+                        &output.config,
+                        chains.files,
+                        chains.noise_learning,
+                        links,
+                        Some(audio_logs),
+                    )?,
+                    &mut chains.writers,
+                    profile,
+                    guard_stats.clone(),
+                    output_rates[index].watch(),
+                ),
+            };
+            if let Some(frames) = settings.quantum {
+                let mut quantum = Quantum::new(frames, output.config.channels as usize);
+                let mut inner = chain;
+                chain = Box::new(move |data| quantum.process(data, &mut inner));
+            }
+            if let Some(stats) = profile {
+                if let Some(bypass) = chains.bypass {
+                    chains.auto_bypasses.push(AutoBypass::new(
+                        bypass,
+                        stats.clone(),
+                        counters[index].clone(),
+                    ));
+                }
+                status.add_flagged(move || {
+                    let bypassed = (0..stats.len()).any(|x| stats.is_bypassed(x));
+                    (stats.describe(), bypassed)
+                });
+            }
+            // The live signal reaching this output keys the ducking of the track.
+            let mut audition = Audition::new(
+                band_solo.clone(),
+                output.config.channels as usize,
+                output.config.sample_rate.0,
+                channel_strip(settings).limiter,
+            );
+            audition.prepare(max_block_frames(&output.config));
+            let mut playback = track.as_ref().map(|x| {
+                let mut playback =
+                    x.playback(&output.config, playback_speed.clone(), region.clone());
+                playback
+                    .prepare(max_block_frames(&output.config) * output.config.channels as usize);
+                playback
+            });
+            let mut ducker = settings.duck.filter(|_| track.is_some()).map(|duck| {
+                Ducker::new(
+                    duck,
+                    output.config.channels as usize,
+                    output.config.sample_rate.0,
+                )
+            });
+            let mut compressor = keyed_compressor(settings).map(|compress| {
+                Compressor::new(
+                    compress,
+                    output.config.channels as usize,
+                    output.config.sample_rate.0,
+                )
+            });
+            let mut headroom = meters
+                .headroom
+                .get(index)
+                .map(|x| HeadroomMeter::new(x.clone(), output.config.sample_rate.0));
+            let net_only = settings.net_only;
+            let muted = muted.clone();
+            let mut fader = Fader::new(
+                milliseconds(settings.fade_in),
+                milliseconds(settings.fade_out),
+                output.config.sample_rate.0,
+                fade_out.clone(),
+            );
+            let mut tail = Tail::new(
+                milliseconds(settings.drain_tail),
+                output.config.sample_rate.0,
+                drain.clone(),
+            );
+            let mut click = tempo.as_ref().filter(|_| index == 0).map(|tempo| {
+                Click::new(
+                    tempo.clone(),
+                    settings.click_beats,
+                    settings.click_level,
+                    output.config.sample_rate.0,
+                )
+            });
+            let channels = output.config.channels as usize;
+            let xruns = counters[index].clone();
+            let mut fill_meters: Vec<FillMeter> = meters.fills[index]
+                .iter()
+                .map(|x| FillMeter::new(x.clone()))
+                .collect();
+            // Rings that start empty run dry until the input catches up, which isn't worth a warning.
+            let mut settling = match settings.prime {
+                Prime::None => {
+                    (ring::SETTLE.as_secs_f64() * output.config.sample_rate.0 as f64) as usize
+                }
+                _ => 0,
+            };
+            let mut nan_guard = guard_stats.map(|x| NanGuard::new(x, output.config.sample_rate.0));
+            let input_labels = input_labels.clone();
+            let prepared = max_block_frames(&output.config);
+            let mut oversize = false;
+            let mut log = audio_logs.log();
+            let report = Arc::new(priority::Report::new());
+            reports.push((label, report.clone()));
+            let mut rate = output_rates[index].watch();
+            // The reference goes to the first input at its rate.
+            let mut reference_rate = input_rates[0].watch();
+            let output_data_fn = move |data: &mut [f32]| {
+                denormal::protect_thread();
+                if rt {
+                    priority::promote_thread(&report);
+                }
+                let new_rate = rate.changed();
+                let new_reference_rate = reference_rate.changed();
+                if let Some((_, converter)) = &mut echo_reference {
+                    if new_rate.is_some() || new_reference_rate.is_some() {
+                        converter.set_rates(rate.rate(), reference_rate.rate());
+                    }
+                }
+                // The chain follows on its own.
+                if let Some(rate) = new_rate {
+                    if let Some(playback) = &mut playback {
+                        playback.set_sample_rate(rate);
+                    }
+                    if let Some(looper) = &mut looper {
+                        looper.set_sample_rate(rate);
+                    }
+                    if let Some(source) = &mut net_source {
+                        source.set_sample_rate(rate);
+                    }
+                    audition.set_sample_rate(rate);
+                    if let Some(ducker) = &mut ducker {
+                        ducker.set_sample_rate(rate);
+                    }
+                    if let Some(compressor) = &mut compressor {
+                        compressor.set_sample_rate(rate);
+                    }
+                    if let Some(meter) = &mut headroom {
+                        meter.set_sample_rate(rate);
+                    }
+                    if let Some(guard) = &mut nan_guard {
+                        guard.set_sample_rate(rate);
+                    }
+                    if let Some(click) = &mut click {
+                        click.set_sample_rate(rate);
+                    }
+                    for meter in &mut fill_meters {
+                        meter.set_sample_rate(rate);
+                    }
+                    fader.set_sample_rate(rate);
+                    tail.set_sample_rate(rate);
+                }
+                let frames = data.len() / channels;
+                if frames > prepared && !std::mem::replace(&mut oversize, true) {
+                    log.push(AudioEvent::OversizeBlock {
+                        stream: label,
+                        frames,
+                        prepared,
+                    });
+                }
+                for (meter, occupied) in fill_meters.iter_mut().zip(mixer.occupied()) {
+                    meter.measure(occupied);
+                }
+                let settled = settling == 0;
+                settling = settling.saturating_sub(frames);
+                mixer.mix(data, |input| {
+                    xruns.underruns.fetch_add(1, Ordering::Relaxed);
+                    if settled {
+                        log.push(AudioEvent::Underrun {
+                            input: input_labels[input],
+                            output: label,
+                        });
+                    }
+                });
+                if let Some(meter) = &mut headroom {
+                    meter.measure(Stage::Trim, data, channels);
+                }
+                if let Some(tap) = &mut null_tap {
+                    tap.capture(data);
+                }
+                if let Some(source) = &mut net_source {
+                    source.mix_into(data, net_only);
+                }
+                if let Some(looper) = &mut looper {
+                    looper.process(data);
+                }
+                let key = key_receiver
+                    .as_mut()
+                    .and_then(|x| x.receive(data.len() / channels, label, &mut log));
+                if let Some(playback) = &mut playback {
+                    playback.mix_into(data, ducker.as_mut(), key);
+                }
+                if let Some(guard) = &mut nan_guard {
+                    guard.tap(guard::Stage::Mix, data);
+                }
+                audition.process(data, &mut chain);
+                if let Some(guard) = &mut nan_guard {
+                    guard.tap(guard::Stage::Chain, data);
+                }
+                if let Some(compressor) = &mut compressor {
+                    compressor.process(data, key);
+                    if let Some(meter) = &mut headroom {
+                        meter.measure(Stage::Compressor, data, channels);
+                    }
+                    if let Some(guard) = &mut nan_guard {
+                        guard.tap(guard::Stage::Compressor, data);
+                    }
+                }
+                if mixer.drained() {
+                    tail.process(data, channels);
+                }
+                // Before anything leaves for a device, the network or the canceller.
+                if let Some(guard) = &mut nan_guard {
+                    guard.scrub(data, channels);
+                }
+                // Blocks are sent whole or not at all, so that the channels stay in place.
+                if let Some((producer, stats)) = &mut net_sender {
+                    if producer.vacant_len() < data.len() {
+                        stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        producer.push_slice(data);
+                    }
+                }
+                if let Some(click) = &mut click {
+                    click.mix_into(data, channels);
+                }
+                if let Some(tap) = &mut null_tap {
+                    tap.send(data);
+                }
+                // Over whatever the chain left there, and muted and faded with the rest.
+                if let Some(send) = &mut insert_send {
+                    send.write(data, channels);
+                }
+                if muted.load(Ordering::Relaxed) {
+                    data.fill(0.0);
+                }
+                // Last, so that nothing comes in or goes out unfaded.
+                fader.process(data, channels);
+                // The canceller treats missing reference as silence, so what doesn't fit is dropped.
+                if let Some((producer, converter)) = &mut echo_reference {
+                    producer.push_slice(converter.process(data));
+                }
+            };
+            let port = OutputPort::new(&output.config, output_data_fn);
+            let mut callback = port.callback();
+            match Restartable::output(
+                label,
+                &output.device,
+                &output.config,
+                output.format,
+                dither_mode(settings),
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| callback(data),
+                events.clone(),
+            ) {
+                Ok(stream) => {
+                    output_streams.push((index, stream.sharing_rate(output_rates[index].clone())));
+                    output_port.get_or_insert(port);
+                }
+                Err(err) if index > 0 => {
+                    tracing::warn!("{:#}, continuing without it", anyhow::Error::from(err))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Moves `switch` on, replacing `stream` with the one of the new device once it's done.
+fn advance_switch<P: Port, B>(
+    switch: &mut Option<Switch<P, Restartable>>,
+    opener: &mut B,
+    stream: &mut Restartable,
+    session: &Session,
+) where
+    B: StreamBuilder<P::Callback, Stream = Restartable>,
+{
+    let Some(current) = switch else {
+        return;
+    };
+    match current.poll(opener, Instant::now()).clone() {
+        Phase::Done => {
+            let Some(done) = switch.take() else {
+                return;
+            };
+            let device = done.device().to_string();
+            if let Some(new) = done.into_stream() {
+                // Closes the stream of the old device.
+                *stream = new.sharing_rate(stream.rate().clone());
+            }
+            tracing::info!(device = %device, "moved to another device");
+            session.change(format!("the {} moved to \"{}\"", stream.label(), device));
+        }
+        Phase::RolledBack(reason) => {
+            tracing::warn!(
+                device = current.device(),
+                "failed to move to another device, staying on the old one: {}",
+                reason
+            );
+            session.change(format!(
+                "the {} failed to move to \"{}\", staying on the old one: {}",
+                stream.label(),
+                current.device(),
+                reason
+            ));
+            *switch = None;
+        }
+        Phase::Opening | Phase::Priming | Phase::Crossfading => {}
+    }
+}
+
+/// Logs the configuration a stream was given.
+fn log_config(label: &'static str, config: &StreamConfig) {
+    tracing::info!(
+        stream = label,
+        sample_rate = config.sample_rate.0,
+        channels = config.channels,
+        buffer_size = ?config.buffer_size,
+        "config of the {}: {}",
+        label,
+        config::describe(config)
+    );
+}
+
+/// Logs the priority each stream's thread got, once the streams have had time to call back.
+///
+/// Streams that failed to build never report, so they're only waited for up to a timeout.
+fn report_priorities(reports: &[(&'static str, Arc<priority::Report>)]) {
+    let deadline = Instant::now() + Duration::from_millis(500);
+    while Instant::now() < deadline && reports.iter().any(|(_, x)| x.get().is_none()) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    for (label, report) in reports {
+        match report.get() {
+            Some(promotion @ (Promotion::Denied(_) | Promotion::Unsupported)) => {
+                tracing::warn!("the {} runs at {}", label, promotion)
+            }
+            Some(promotion) => tracing::info!("priority of the {}: {}", label, promotion),
+            None => {}
+        }
+    }
+}
+
+/// When to bypass effects, unless `--no-auto-bypass` or `--no-profiling` turns it off.
+fn bypass_settings(settings: &Settings) -> Result<Option<BypassSettings>, EngineError> {
+    if settings.no_auto_bypass || settings.no_profiling {
+        return Ok(None);
+    }
+    let load = settings.auto_bypass_load;
+    if !(load > 0.0 && load <= 100.0) {
+        return Err(EngineError::InvalidArgument(format!(
+            "the auto-bypass load must be from 0 to 100%, got {}",
+            load
+        )));
+    }
+    let hold = settings.auto_bypass_seconds;
+    if !(hold > 0.0 && hold.is_finite()) {
+        return Err(EngineError::InvalidArgument(format!(
+            "the auto-bypass time must be positive, got {}",
+            hold
+        )));
+    }
+    Ok(Some(BypassSettings {
+        share: load / 100.0,
+        hold,
+    }))
+}
+
+/// The channel strip of the monitor feed: the preset's, with the stages given by their own flags
+/// in place of its own, or only those stages without a preset. The compressor is in the strip
+/// unless the sidechain keys it.
+fn channel_strip(settings: &Settings) -> ChannelStrip {
+    let flags = ChannelStrip {
+        highpass: settings.highpass,
+        gate: settings.gate,
+        eq: settings.eq.clone(),
+        compressor: settings
+            .compress
+            .filter(|_| keyed_compressor(settings).is_none()),
+        limiter: settings.limit,
+    };
+    match settings.channel_strip {
+        Some(preset) => preset.strip().with(flags),
+        None => flags,
+    }
+}
+
+/// The compressor keyed by the sidechain, which comes after the chain, where the key is. Only
+/// without a preset, whose strip keeps its compressor keyed by its own signal.
+fn keyed_compressor(settings: &Settings) -> Option<CompressorSettings> {
+    settings
+        .compress
+        .filter(|_| settings.sidechain_device.is_some() && settings.channel_strip.is_none())
+}
+
+/// Builds the effect chain of an output from the settings.
+///
+/// Fails if an LFO targets a parameter that isn't in the chain.
+fn build_chain<S: Sample>(
+    settings: &Settings,
+    config: &StreamConfig,
+    files: &ChainFiles,
+    noise_learning: &LearnTrigger,
+    links: ChainLinks,
+    mut audio_logs: Option<&mut AudioLogs>,
+) -> anyhow::Result<EffectChain<S>> {
+    // Chains built only to be inspected log nothing.
+    let mut log = || match &mut audio_logs {
+        Some(logs) => logs.log(),
+        None => AudioLog::disabled(),
+    };
+    let channels = config.channels as usize;
+    let sample_rate = config.sample_rate.0 as f32;
+    let mut chain = EffectChain::new(channels).logging(log());
+    // First, so that nothing downstream reacts to the rumble it takes out.
+    if let Some(spec) = settings.rumble {
+        chain.push(RumbleFilter::new(spec, sample_rate, channels)?);
+    }
+    // Then, so that the noise profile is the one of the inputs.
+    if let Some(reduction) = settings.noise_reduction {
+        chain.push(
+            NoiseReducer::new(
+                reduction,
+                learn_seconds(settings),
+                noise_learning.clone(),
+                sample_rate,
+                channels,
+            )
+            .logging(log()),
+        );
+    }
+    if let Some(window) = settings.adaptive_notch {
+        chain.push(AdaptiveNotch::new(window, sample_rate, channels));
+    }
+    if let Some(frequency) = settings.lowpass {
+        let kind = FilterKind::LowPass;
+        chain.push(Biquad::new(
+            kind,
+            frequency,
+            BUTTERWORTH_Q,
+            sample_rate,
+            channels,
+        ));
+    }
+    // Before the strip, which then works on the tone of the cabinet.
+    if let (Some(spec), Some(irs)) = (&settings.cab, &files.cab) {
+        chain.push(CabSim::new(irs.clone(), spec.blend, sample_rate, channels)?);
+    }
+    let strip = channel_strip(settings);
+    strip.build(&mut chain, sample_rate, channels, links.eq)?;
+    // After the filters, so that rumble doesn't count in the level.
+    if let Some(spec) = settings.agc {
+        chain.push(Agc::new(spec, sample_rate, links.agc));
+    }
+    if let Some(taps) = &files.fir_effect {
+        chain.push(fir(taps, "the FIR filter is", config)?);
+    }
+    #[cfg(feature = "clap-plugins")]
+    for spec in &settings.plugin {
+        chain.push(ClapPlugin::load(spec, channels, config.sample_rate.0)?);
+    }
+    if let Some(path) = &settings.script {
+        chain.push(ScriptEffect::load(path, channels, sample_rate)?.logging(log()));
+    }
+    // Automation, LFOs and the control socket may move the gain even when it starts at 0 dB.
+    if settings.gain != 0.0
+        || settings.automation.is_some()
+        || !settings.lfo.is_empty()
+        || control_socket(settings)
+    {
+        chain.push(Gain::new(settings.gain));
+    }
+    // Outputs of two channels have no rears, and get the stereo as it is.
+    if settings.upmix.is_some() && channels > 2 {
+        let rears = settings.upmix_rears;
+        if Upmix::<S>::channels_needed(rears) > channels {
+            return Err(EngineError::InvalidArgument(format!(
+                "the rears of the upmix are on channels {} and {}, but the output stream has {}",
+                rears[0], rears[1], channels
+            ))
+            .into());
+        }
+        chain.push(Upmix::new(rears, settings.upmix_rear_level, sample_rate));
+    }
+    // Last of what sets the level, rears included, so that the peaks stay under its ceiling.
+    strip.build_limiter(&mut chain, sample_rate);
+    // Last but for the crossover, so that it sees what actually reaches the speakers.
+    if settings.feedback_suppress {
+        chain.push(FeedbackSuppressor::new(sample_rate, channels).logging(log()));
+    }
+    if let Some(spec) = &settings.crossover {
+        let routes = spec.routes(&settings.crossover_band)?;
+        chain.push(Crossover::new(spec, &routes, sample_rate, channels)?);
+    }
+    if let Some(sub) = settings.sub_channel {
+        let sign = if settings.sub_invert { -1.0 } else { 1.0 };
+        chain.push(BassManager::new(
+            sub,
+            settings.sub_lowpass,
+            sign * level::db_to_gain(settings.sub_gain),
+            settings.sub_highpass_mains,
+            sample_rate,
+            channels,
+        )?);
+    }
+    // After the routing, so that each speaker feed is aligned as a whole.
+    if !settings.channel_delay.is_empty() {
+        chain.push(ChannelDelay::new(
+            &settings.channel_delay,
+            settings.max_channel_delay,
+            sample_rate,
+            channels,
+        )?);
+    }
+    // Last, to correct each speaker for all that comes before it.
+    if let Some(taps) = &files.correction {
+        chain.push(fir(taps, "the correction filters are", config)?);
+    }
+    // Wherever it is, every chain checks it, and only the first output's has it.
+    if let Some(spec) = &settings.hw_insert {
+        let index = match &spec.after {
+            Some(name) => chain.position(name).map(|x| x + 1).ok_or_else(|| {
+                EngineError::InvalidArgument(format!(
+                    "no effect \"{}\" in the chain to insert the hardware after",
+                    name
+                ))
+            })?,
+            None => chain.len(),
+        };
+        if let Some(link) = links.insert {
+            chain.insert(index, HwInsert::new(link, channels));
+        }
+    }
+    if let Some(stats) = &links.headroom {
+        headroom::attach(&mut chain, stats, config.sample_rate.0);
+    }
+    for spec in &settings.lfo {
+        let lfo = Lfo::new(spec, chain.layout(), config.sample_rate.0)?;
+        chain.modulate(lfo);
+    }
+    chain.prepare(max_block_frames(config));
+    Ok(chain)
+}
+
+/// Largest block the callbacks of a stream of `config` are sized for before it starts:
+/// [`MAX_BLOCK_FRAMES`], or its fixed buffer size if larger, since hosts may call back with more
+/// frames than they fixed.
+fn max_block_frames(config: &StreamConfig) -> usize {
+    match config.buffer_size {
+        BufferSize::Fixed(frames) => (frames as usize).max(MAX_BLOCK_FRAMES),
+        BufferSize::Default => MAX_BLOCK_FRAMES,
+    }
+}
+
+/// Creates the FIR effect of `taps` for an output, failing if they're for another sample rate
+/// than it starts at. A single filter applies to every channel.
+fn fir<S: Sample>(taps: &FirTaps, label: &str, config: &StreamConfig) -> anyhow::Result<Fir<S>> {
+    if let Some(rate) = taps.sample_rate.filter(|x| *x != config.sample_rate.0) {
+        anyhow::bail!(
+            "{} for {} Hz, but the output stream runs at {} Hz",
+            label,
+            rate,
+            config.sample_rate.0
+        );
+    }
+    let channels = config.channels as usize;
+    let filters = match &taps.channels[..] {
+        [filter] => vec![filter.clone(); channels],
+        filters => filters.to_vec(),
+    };
+    // Coefficients that don't say their rate are taken for the stream's, and follow it from there.
+    Ok(Fir::new(&filters, channels).designed_at(config.sample_rate.0))
+}
+
+/// A duration given in milliseconds.
+fn milliseconds(value: f32) -> Duration {
+    Duration::from_secs_f32(value / 1_000.0)
+}
+
+/// How samples are dithered when converted to 16 bits.
+fn dither_mode(settings: &Settings) -> DitherMode {
+    match (settings.no_dither, settings.noise_shaping) {
+        (true, _) => DitherMode::Off,
+        (false, false) => DitherMode::Tpdf,
+        (false, true) => DitherMode::Shaped,
+    }
+}
+
+/// Whether the run is headless, controlled over the socket only.
+fn daemon_mode(settings: &Settings) -> bool {
+    #[cfg(unix)]
+    return settings.daemon;
+    #[cfg(not(unix))]
+    return false;
+}
+
+/// Whether the run keeps goniometer points.
+fn goniometer_enabled(settings: &Settings) -> bool {
+    #[cfg(unix)]
+    return settings.goniometer;
+    #[cfg(not(unix))]
+    return false;
+}
+
+/// Whether the run accepts commands over a control socket.
+fn control_socket(settings: &Settings) -> bool {
+    #[cfg(unix)]
+    return settings.control_socket.is_some();
+    #[cfg(not(unix))]
+    return false;
+}
+
+/// How long learning a noise profile lasts.
+fn learn_seconds(settings: &Settings) -> f32 {
+    settings.learn_noise.unwrap_or(2.0)
+}
+
+/// Logs where an output first went non-finite, with the parameters of its chain then.
+fn report_non_finite(output: &'static str, diagnosis: &Diagnosis, layout: &ParamLayout) {
+    let stage = match (diagnosis.effect, diagnosis.stage) {
+        (Some((effect, instance)), _) => format!("effect {}.{} of the chain", effect, instance),
+        (None, Some(stage)) => format!("the {} stage", stage.name()),
+        (None, None) => "the end of the callback".to_string(),
+    };
+    let params: Vec<String> = layout
+        .names()
+        .iter()
+        .zip(&diagnosis.params)
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect();
+    tracing::error!(
+        output,
+        seconds = diagnosis.seconds,
+        params = %params.join(" "),
+        "the output went non-finite at {}: muting it until it's finite again",
+        stage
+    );
+}
+
+/// An effect chain of any precision, processing interleaved f32 blocks.
+type ChainFn = Box<dyn FnMut(&mut [f32]) + Send>;
+
+/// Connects a chain to a new parameter store, adding its writer to `writers`, and erases the
+/// sample type of the chain, so that callbacks don't depend on the precision. The chain follows
+/// the rate of its stream through `rate`.
+fn connect<S: Sample>(
+    mut chain: EffectChain<S>,
+    writers: &mut Vec<ParamWriter<Vec<f32>>>,
+    profile: Option<(&'static str, u32)>,
+    guard: Option<Arc<GuardStats>>,
+    mut rate: RateWatch,
+) -> (ChainFn, Option<Arc<ProfileStats>>) {
+    if let Some(stats) = guard {
+        chain.guard(stats);
+    }
+    let (writer, reader) = ParamStore::new(chain.param_values()).split();
+    chain.connect(reader);
+    writers.push(writer);
+    let stats = profile
+        .filter(|_| !chain.is_empty())
+        .map(|(label, sample_rate)| chain.profile(label, sample_rate));
+    let process = move |data: &mut [f32]| {
+        if let Some(rate) = rate.changed() {
+            chain.set_sample_rate(rate);
+        }
+        chain.process(data)
+    };
+    (Box::new(process), stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::error::ErrorKind;
+    use clap::CommandFactory;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Settings, clap::Error> {
+        Settings::try_parse_from(["rust-dsp-experiments"].iter().chain(args))
+    }
+
+    #[test]
+    fn the_command_line_is_consistent() {
+        Settings::command().debug_assert();
+    }
+
+    #[test]
+    fn the_defaults_dither_and_bypass() {
+        let settings = parse(&[]).unwrap();
+        assert_eq!(settings.buffer_size, 128);
+        assert!(settings.record_compression <= flac::MAX_LEVEL);
+        assert_eq!(dither_mode(&settings), DitherMode::Tpdf);
+        let bypass = bypass_settings(&settings).unwrap().unwrap();
+        assert_eq!((bypass.share, bypass.hold), (0.7, 3.0));
+        assert_eq!(learn_seconds(&settings), 2.0);
+    }
+
+    #[test]
+    fn the_dither_flags_pick_a_mode() {
+        let mode = |args: &[&str]| dither_mode(&parse(args).unwrap());
+        assert_eq!(mode(&["--no-dither"]), DitherMode::Off);
+        assert_eq!(mode(&["--noise-shaping"]), DitherMode::Shaped);
+        let both = parse(&["--no-dither", "--noise-shaping"]);
+        assert_eq!(both.err().unwrap().kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn flags_that_need_others_are_refused_alone() {
+        let learn = parse(&["--learn-noise", "3"]);
+        assert_eq!(
+            learn.err().unwrap().kind(),
+            ErrorKind::MissingRequiredArgument
+        );
+        let settings = parse(&["--noise-reduction", "6", "--learn-noise", "3"]).unwrap();
+        assert_eq!(learn_seconds(&settings), 3.0);
+        let recording = parse(&["--record", "take.wav", "--record-dir", "takes"]);
+        assert_eq!(recording.err().unwrap().kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn the_auto_bypass_is_checked_or_off() {
+        for load in ["0", "120"] {
+            let settings = parse(&["--auto-bypass-load", load]).unwrap();
+            assert!(matches!(
+                bypass_settings(&settings),
+                Err(EngineError::InvalidArgument(_))
+            ));
+        }
+        let settings = parse(&["--auto-bypass-seconds", "0"]).unwrap();
+        assert!(bypass_settings(&settings).is_err());
+        for off in ["--no-auto-bypass", "--no-profiling"] {
+            let settings = parse(&[off, "--auto-bypass-load", "120"]).unwrap();
+            assert!(bypass_settings(&settings).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn a_keyed_compressor_leaves_the_strip() {
+        let compress = ["--compress", "-20:4:5:100"];
+        let settings = parse(&compress).unwrap();
+        assert!(keyed_compressor(&settings).is_none());
+        assert!(channel_strip(&settings).compressor.is_some());
+
+        let keyed = parse(&[&compress[..], &["--sidechain-device", "default"]].concat()).unwrap();
+        assert!(keyed_compressor(&keyed).is_some());
+        assert!(channel_strip(&keyed).compressor.is_none());
+
+        // A preset's compressor stays keyed by its own signal.
+        let preset = [&compress[..], &["--sidechain-device", "default"]].concat();
+        let preset = parse(&[&preset[..], &["--channel-strip", "vocal"]].concat()).unwrap();
+        assert!(keyed_compressor(&preset).is_none());
+        assert_eq!(
+            channel_strip(&preset).compressor.unwrap().threshold_db,
+            -20.0
+        );
+    }
+
+    #[test]
+    fn exit_codes_follow_the_first_engine_error() {
+        let invalid = anyhow::Error::from(EngineError::InvalidArgument("bad".to_string()));
+        assert_eq!(exit_code(&invalid), 2);
+        assert_eq!(exit_code(&invalid.context("while starting")), 2);
+        assert_eq!(exit_code(&anyhow::anyhow!("something else")), 1);
+    }
+
+    #[test]
+    fn blocks_are_prepared_for_at_least_the_largest_callback() {
+        let config = |buffer_size| StreamConfig {
+            channels: 2,
+            sample_rate: SampleRate(48_000),
+            buffer_size,
+        };
+        assert_eq!(
+            max_block_frames(&config(BufferSize::Default)),
+            MAX_BLOCK_FRAMES
+        );
+        assert_eq!(
+            max_block_frames(&config(BufferSize::Fixed(64))),
+            MAX_BLOCK_FRAMES
+        );
+        let large = MAX_BLOCK_FRAMES as u32 * 2;
+        assert_eq!(
+            max_block_frames(&config(BufferSize::Fixed(large))),
+            large as usize
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Peak and RMS levels of the inputs, for the status line, over the interval it's printed at.
+//!
+//! The input callback adds every block to the input's [`LevelStats`], and the reporting thread
+//! takes them once per interval, so that the RMS is the one of the whole interval and the peak
+//! the highest in it, whether the interval is 100 ms or 5 s: no peak falls between two lines.
+//! The peak shown holds, falling at [`PEAK_FALL_DB`] per second of the time gone by rather than
+//! per interval, so that it falls the same at every interval.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::level;
+
+/// How fast the held peak falls, in dB per second.
+pub const PEAK_FALL_DB: f32 = 12.0;
+/// Lowest level shown, e.g. for silence, in dBFS.
+pub const FLOOR_DB: f32 = -120.0;
+
+/// The levels of one interval, in dBFS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Level {
+    /// The held peak.
+    pub peak_db: f32,
+    pub rms_db: f32,
+}
+
+/// What the reporting thread took so far.
+struct Taken {
+    energy: f64,
+    samples: u64,
+    level: Option<Level>,
+}
+
+/// The levels of one input, shared between its callback and the status line.
+pub struct LevelStats {
+    label: &'static str,
+    /// Highest sample since the last interval, as the bits of a positive `f32`, which order the
+    /// same as it does.
+    peak: AtomicU32,
+    /// Sum of the squares of the samples so far, as the bits of an `f64`, and how many there
+    /// were, only written by the callback.
+    energy: AtomicU64,
+    samples: AtomicU64,
+    taken: Mutex<Taken>,
+}
+
+impl LevelStats {
+    pub fn new(label: &'static str) -> Self {
+        LevelStats {
+            label,
+            peak: AtomicU32::new(0),
+            energy: AtomicU64::new(0),
+            samples: AtomicU64::new(0),
+            taken: Mutex::new(Taken {
+                energy: 0.0,
+                samples: 0,
+                level: None,
+            }),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+
+    /// Adds the interleaved block `data`, from the callback.
+    pub fn add(&self, data: &[f32]) {
+        let (peak, energy) = data.iter().fold((0f32, 0f64), |(peak, energy), x| {
+            (peak.max(x.abs()), energy + (*x as f64) * (*x as f64))
+        });
+        self.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+        let total = f64::from_bits(self.energy.load(Ordering::Relaxed)) + energy;
+        self.energy.store(total.to_bits(), Ordering::Relaxed);
+        self.samples.fetch_add(data.len() as u64, Ordering::Release);
+    }
+
+    /// Ends an interval of `elapsed`, returning its levels, or `None` while no block came yet.
+    pub fn take(&self, elapsed: Duration) -> Option<Level> {
+        let peak = f32::from_bits(self.peak.swap(0, Ordering::Relaxed));
+        let samples = self.samples.load(Ordering::Acquire);
+        let energy = f64::from_bits(self.energy.load(Ordering::Relaxed));
+        let mut taken = self.taken.lock().unwrap();
+        let fallen = taken
+            .level
+            .map(|x| (x.peak_db - PEAK_FALL_DB * elapsed.as_secs_f32()).max(FLOOR_DB));
+        let level = match samples - taken.samples {
+            // An input that didn't call back keeps its RMS, and its peak falls.
+            0 => taken
+                .level
+                .zip(fallen)
+                .map(|(x, peak_db)| Level { peak_db, ..x }),
+            count => Some(Level {
+                peak_db: to_db(peak).max(fallen.unwrap_or(FLOOR_DB)),
+                rms_db: to_db(((energy - taken.energy).max(0.0) / count as f64).sqrt() as f32),
+            }),
+        };
+        *taken = Taken {
+            energy,
+            samples,
+            level,
+        };
+        level
+    }
+
+    /// The levels of the last interval.
+    pub fn level(&self) -> Option<Level> {
+        self.taken.lock().unwrap().level
+    }
+}
+
+fn to_db(gain: f32) -> f32 {
+    level::gain_to_db(gain).max(FLOOR_DB)
+}
+
+/// The field of the status line for the inputs, e.g. "level: -3.0/-12.1 dBFS", the peak and the
+/// RMS of each.
+pub fn describe(inputs: &[Arc<LevelStats>]) -> String {
+    let levels: Vec<String> = inputs
+        .iter()
+        .map(|x| match x.level() {
+            Some(level) => format!("{:.1}/{:.1}", level.peak_db, level.rms_db),
+            None => "-".to_string(),
+        })
+        .collect();
+    format!("level: {} dBFS", levels.join(", "))
+}
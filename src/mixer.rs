@@ -0,0 +1,164 @@
+//! Mixing of several input streams into one output stream.
+
+use ringbuf::traits::Consumer;
+
+use crate::simd;
+
+/// Sums the ring buffers of several input streams into the blocks of one output stream.
+///
+/// Each input runs on its own clock and fills its own ring buffer, so a source that runs dry only
+/// contributes silence for the missing samples, while the others keep playing.
+pub struct Mixer<C> {
+    sources: Vec<(C, f32)>,
+    scratch: Vec<f32>,
+}
+
+impl<C: Consumer<Item = f32>> Mixer<C> {
+    /// Creates a mixer from `(consumer, gain)` pairs, with linear gains.
+    pub fn new(sources: Vec<(C, f32)>) -> Self {
+        Mixer {
+            sources,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Allocates what blocks of up to `samples` need, so that the first ones don't. Only the first
+    /// `samples` of a larger block are mixed, the rest is silence.
+    pub fn prepare(&mut self, samples: usize) {
+        if self.scratch.len() < samples {
+            self.scratch.resize(samples, 0.0);
+        }
+    }
+
+    /// Fills `data` with the sum of all sources, each scaled by its gain, clamped to [-1, 1].
+    ///
+    /// `fell_behind` is called with the index of each source that ran out of samples before the
+    /// end of the block. Sources whose input stream no longer exists are skipped silently.
+    pub fn mix(&mut self, data: &mut [f32], mut fell_behind: impl FnMut(usize)) {
+        data.fill(0.0);
+        let length = data.len().min(self.scratch.len());
+        let scratch = &mut self.scratch[..length];
+        for (index, (consumer, gain)) in self.sources.iter_mut().enumerate() {
+            if !consumer.write_is_held() && consumer.is_empty() {
+                continue;
+            }
+            let popped = consumer.pop_slice(scratch);
+            // A source whose stream stopped is draining, not behind.
+            if popped < length && consumer.write_is_held() {
+                fell_behind(index);
+            }
+            simd::add_scaled(&mut data[..popped], &scratch[..popped], *gain);
+        }
+        for sample in data.iter_mut() {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+    }
+
+    /// The samples every source holds, in order.
+    pub fn occupied(&self) -> impl Iterator<Item = usize> + '_ {
+        self.sources
+            .iter()
+            .map(|(consumer, _)| consumer.occupied_len())
+    }
+
+    /// Whether every source's input stream is gone and its samples all played.
+    pub fn drained(&self) -> bool {
+        self.sources
+            .iter()
+            .all(|(consumer, _)| !consumer.write_is_held() && consumer.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ringbuf::traits::{Producer, Split};
+    use ringbuf::{HeapCons, HeapProd, HeapRb};
+
+    use crate::level;
+
+    fn ring(samples: &[f32]) -> (HeapProd<f32>, HeapCons<f32>) {
+        let (mut producer, consumer) = HeapRb::<f32>::new(64).split();
+        producer.push_slice(samples);
+        (producer, consumer)
+    }
+
+    #[test]
+    fn sources_are_summed_with_their_gains() {
+        let ((_first, first), (_second, second)) = (ring(&[0.5; 4]), ring(&[0.25; 4]));
+        let half = level::db_to_gain(-6.0206);
+        let mut mixer = Mixer::new(vec![(first, 1.0), (second, half)]);
+        mixer.prepare(4);
+        let mut data = [1.0; 4];
+        mixer.mix(&mut data, |x| panic!("source {} fell behind", x));
+        for sample in data {
+            assert!((sample - 0.625).abs() < 1e-4, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn the_sum_is_clamped() {
+        let ((_first, first), (_second, second)) = (ring(&[0.75, -0.75]), ring(&[0.75, -0.75]));
+        let mut mixer = Mixer::new(vec![(first, 1.0), (second, 1.0)]);
+        mixer.prepare(4);
+        let mut data = [0.0; 2];
+        mixer.mix(&mut data, |_| {});
+        assert_eq!(data, [1.0, -1.0]);
+    }
+
+    #[test]
+    fn a_source_that_runs_dry_contributes_silence() {
+        let ((_first, first), (_second, second)) = (ring(&[0.5; 4]), ring(&[0.25; 2]));
+        let mut mixer = Mixer::new(vec![(first, 1.0), (second, 1.0)]);
+        mixer.prepare(4);
+        let mut data = [0.0; 4];
+        let mut behind = Vec::new();
+        mixer.mix(&mut data, |x| behind.push(x));
+        assert_eq!(behind, [1]);
+        assert_eq!(data, [0.75, 0.75, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn sources_whose_stream_is_gone_are_not_behind() {
+        let ((first_in, first), (second_in, second)) = (ring(&[0.5; 2]), ring(&[]));
+        drop((first_in, second_in));
+        let mut mixer = Mixer::new(vec![(first, 1.0), (second, 1.0)]);
+        mixer.prepare(4);
+        let mut data = [0.0; 4];
+        mixer.mix(&mut data, |x| panic!("source {} fell behind", x));
+        assert_eq!(data, [0.5, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn sources_are_drained_once_gone_and_empty() {
+        let ((first_in, first), (second_in, second)) = (ring(&[0.5; 2]), ring(&[]));
+        let mut mixer = Mixer::new(vec![(first, 1.0), (second, 1.0)]);
+        mixer.prepare(4);
+        assert!(!mixer.drained());
+        drop(second_in);
+        let mut data = [0.0; 2];
+        mixer.mix(&mut data, |_| {});
+        assert!(!mixer.drained());
+        drop(first_in);
+        assert!(mixer.drained());
+    }
+
+    #[test]
+    fn blocks_larger_than_prepared_for_are_only_mixed_that_far() {
+        let (_input, consumer) = ring(&[0.5; 8]);
+        let mut mixer = Mixer::new(vec![(consumer, 1.0)]);
+        mixer.prepare(4);
+        let mut data = [1.0; 6];
+        mixer.mix(&mut data, |x| panic!("source {} fell behind", x));
+        assert_eq!(data, [0.5, 0.5, 0.5, 0.5, 0.0, 0.0]);
+        assert_eq!(mixer.occupied().collect::<Vec<_>>(), [4]);
+    }
+
+    #[test]
+    fn the_fill_of_every_source_is_reported_in_order() {
+        let ((_first, first), (_second, second)) = (ring(&[0.5; 4]), ring(&[0.25; 2]));
+        let mixer = Mixer::new(vec![(first, 1.0), (second, 1.0)]);
+        assert_eq!(mixer.occupied().collect::<Vec<_>>(), [4, 2]);
+    }
+}
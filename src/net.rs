@@ -0,0 +1,875 @@
+//! Audio over UDP, from one instance to another on the same network.
+//!
+//! A packet holds about [`PACKET_DURATION`] of an interleaved stream, after a header in network
+//! byte order:
+//!
+//! | Bytes  | Field                                                        |
+//! |--------|--------------------------------------------------------------|
+//! | 0..4   | [`MAGIC`]                                                    |
+//! | 4..8   | sequence number, counting packets from 0                     |
+//! | 8..16  | timestamp, the frame of the stream the packet starts at      |
+//! | 16..20 | sample rate                                                  |
+//! | 20..22 | channels                                                     |
+//! | 22     | encoding of the samples: 0 for f32, 1 for 16-bit integers    |
+//! | 23     | reserved, 0                                                  |
+//!
+//! The output callback only pushes its samples into a ring buffer, and a network thread cuts
+//! them into packets and sends them, so the audio thread never waits on the socket.
+//!
+//! On the receiving end, a network thread puts the packets back in order in a [`JitterBuffer`],
+//! which waits a while for the late ones and conceals the lost ones, and pushes the audio into
+//! a ring buffer. The output callback plays it through a [`NetSource`], which keeps that ring
+//! about as full as the jitter allows by resampling slightly faster or slower, since the clocks
+//! of the two machines never run at quite the same rate.
+
+use std::collections::VecDeque;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::Context;
+use ringbuf::traits::{Consumer, Observer, Producer};
+use ringbuf::{HeapCons, HeapProd};
+
+use crate::adapter::{ChannelAdapter, Resampler};
+use crate::dither::{Dither, DitherMode};
+use crate::rate::StreamRate;
+use crate::stats::AtomicF32;
+
+/// First bytes of every packet.
+pub const MAGIC: [u8; 4] = *b"RDSP";
+pub const HEADER_SIZE: usize = 24;
+/// Audio per packet.
+pub const PACKET_DURATION: Duration = Duration::from_millis(5);
+/// Largest payload of a UDP datagram over IPv4.
+const MAX_DATAGRAM: usize = 65_507;
+
+/// How the samples of a packet are stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    F32,
+    I16,
+}
+
+impl Encoding {
+    fn bytes(&self) -> usize {
+        match self {
+            Encoding::F32 => 4,
+            Encoding::I16 => 2,
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(Encoding::F32),
+            "i16" => Ok(Encoding::I16),
+            _ => Err(format!("unsupported encoding \"{}\"", s)),
+        }
+    }
+}
+
+/// The header of a packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub sequence: u32,
+    /// Frame of the stream the packet starts at.
+    pub timestamp: u64,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub encoding: Encoding,
+}
+
+/// Frames in a packet of a stream, about [`PACKET_DURATION`] of it, and never more than a
+/// datagram holds.
+pub fn packet_frames(channels: u16, sample_rate: u32, encoding: Encoding) -> usize {
+    let frames = (PACKET_DURATION.as_secs_f64() * sample_rate as f64).round() as usize;
+    let largest = (MAX_DATAGRAM - HEADER_SIZE) / (channels as usize * encoding.bytes()).max(1);
+    frames.clamp(1, largest.max(1))
+}
+
+/// Writes the packet of `header` and the interleaved `samples` into `packet`, replacing its
+/// contents. 16-bit samples are quantized by `dither`, which must have `header.channels`.
+pub fn encode(header: &Header, samples: &[f32], dither: &mut Dither, packet: &mut Vec<u8>) {
+    packet.clear();
+    packet.extend_from_slice(&MAGIC);
+    packet.extend_from_slice(&header.sequence.to_be_bytes());
+    packet.extend_from_slice(&header.timestamp.to_be_bytes());
+    packet.extend_from_slice(&header.sample_rate.to_be_bytes());
+    packet.extend_from_slice(&header.channels.to_be_bytes());
+    packet.push(match header.encoding {
+        Encoding::F32 => 0,
+        Encoding::I16 => 1,
+    });
+    packet.push(0);
+    match header.encoding {
+        Encoding::F32 => {
+            for x in samples {
+                packet.extend_from_slice(&x.to_be_bytes());
+            }
+        }
+        Encoding::I16 => {
+            for (index, x) in samples.iter().enumerate() {
+                let x = dither.quantize(*x, index % header.channels as usize);
+                packet.extend_from_slice(&x.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Reads a packet, putting its samples in `samples`, replacing its contents.
+pub fn decode(packet: &[u8], samples: &mut Vec<f32>) -> Result<Header, String> {
+    if packet.len() < HEADER_SIZE || packet[..4] != MAGIC {
+        return Err("not an audio packet".to_string());
+    }
+    let bytes = |range: std::ops::Range<usize>| &packet[range];
+    let header = Header {
+        sequence: u32::from_be_bytes(bytes(4..8).try_into().unwrap()),
+        timestamp: u64::from_be_bytes(bytes(8..16).try_into().unwrap()),
+        sample_rate: u32::from_be_bytes(bytes(16..20).try_into().unwrap()),
+        channels: u16::from_be_bytes(bytes(20..22).try_into().unwrap()),
+        encoding: match packet[22] {
+            0 => Encoding::F32,
+            1 => Encoding::I16,
+            other => return Err(format!("unknown sample encoding {}", other)),
+        },
+    };
+    let payload = &packet[HEADER_SIZE..];
+    let frame_size = header.channels as usize * header.encoding.bytes();
+    if header.channels == 0 || !payload.len().is_multiple_of(frame_size) {
+        return Err("the packet doesn't hold whole frames".to_string());
+    }
+    samples.clear();
+    match header.encoding {
+        Encoding::F32 => samples.extend(
+            payload
+                .chunks_exact(4)
+                .map(|x| f32::from_be_bytes([x[0], x[1], x[2], x[3]])),
+        ),
+        Encoding::I16 => samples.extend(
+            payload
+                .chunks_exact(2)
+                .map(|x| i16::from_be_bytes([x[0], x[1]]) as f32 / 32_768.0),
+        ),
+    }
+    Ok(header)
+}
+
+/// Counters of a sender, shared with the status line.
+#[derive(Default)]
+pub struct SendStats {
+    pub packets: AtomicU64,
+    pub errors: AtomicU64,
+    /// Blocks the output callback dropped because the network thread fell behind.
+    pub dropped: AtomicU64,
+}
+
+impl SendStats {
+    /// Describes the counters for the status line, e.g. "net: 2000 sent, 0 errors".
+    pub fn describe(&self) -> String {
+        let mut text = format!(
+            "net: {} sent, {} errors",
+            self.packets.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed)
+        );
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            text += &format!(", {} blocks dropped", dropped);
+        }
+        text
+    }
+}
+
+/// Resolves `address`, e.g. "192.168.1.20:5004", to its first socket address.
+pub fn resolve(address: &str) -> anyhow::Result<SocketAddr> {
+    address
+        .to_socket_addrs()
+        .with_context(|| format!("invalid address \"{}\"", address))?
+        .next()
+        .with_context(|| format!("\"{}\" has no address", address))
+}
+
+/// Starts the network thread sending the stream in `consumer`, of `channels` channels at the
+/// rate in `rate`, to `address`, 16-bit samples quantized with `dither`. The packets carry the
+/// rate the stream is at when they're sent. The thread finishes once the producer is dropped and
+/// the buffer is empty.
+pub fn spawn_sender(
+    address: SocketAddr,
+    mut consumer: HeapCons<f32>,
+    channels: u16,
+    rate: Arc<StreamRate>,
+    encoding: Encoding,
+    dither: DitherMode,
+    stats: Arc<SendStats>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let local: SocketAddr = match address {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local).context("failed to open the network socket")?;
+    socket
+        .connect(address)
+        .with_context(|| format!("cannot send to {}", address))?;
+    Ok(std::thread::spawn(move || {
+        let sample_rate = rate.get();
+        let mut frames = packet_frames(channels, sample_rate, encoding);
+        let mut samples = vec![0.0; frames * channels as usize];
+        let mut dither = Dither::new(dither, channels as usize);
+        let mut packet = Vec::with_capacity(HEADER_SIZE + samples.len() * encoding.bytes());
+        let mut header = Header {
+            sequence: 0,
+            timestamp: 0,
+            sample_rate,
+            channels,
+            encoding,
+        };
+        loop {
+            if rate.get() != header.sample_rate {
+                header.sample_rate = rate.get();
+                frames = packet_frames(channels, header.sample_rate, encoding);
+                samples = vec![0.0; frames * channels as usize];
+            }
+            if consumer.occupied_len() < samples.len() {
+                if !consumer.write_is_held() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            consumer.pop_slice(&mut samples);
+            encode(&header, &samples, &mut dither, &mut packet);
+            // A receiver that isn't listening yet refuses the packets, which is no reason to
+            // stop, so errors are only counted.
+            match socket.send(&packet) {
+                Ok(_) => stats.packets.fetch_add(1, Ordering::Relaxed),
+                Err(_) => stats.errors.fetch_add(1, Ordering::Relaxed),
+            };
+            header.sequence = header.sequence.wrapping_add(1);
+            header.timestamp += frames as u64;
+        }
+    }))
+}
+
+/// Packets so far away from the expected one that the sender must have restarted.
+const RESYNC: i32 = 200;
+/// Fastest the receiver plays faster or slower than the sender's rate to follow its clock.
+const MAX_DRIFT: f64 = 0.005;
+/// How much the playback rate changes per unit of relative error in the buffer's fill.
+const DRIFT_GAIN: f64 = 0.01;
+/// Time constant of the smoothing of the buffer's fill, in seconds.
+const FILL_SMOOTHING: f64 = 1.0;
+
+/// What a [`JitterBuffer`] did with the packets so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JitterCounts {
+    pub played: u64,
+    /// Packets that arrived after they were given up on.
+    pub late: u64,
+    pub lost: u64,
+    /// Lost packets replaced with the previous one, fading out.
+    pub concealed: u64,
+    pub duplicates: u64,
+}
+
+/// Puts the packets of a stream back in order by sequence number, waiting for a missing one
+/// until `depth` packets after it arrived.
+///
+/// Packets are played as soon as every packet before them was played or given up on. A single
+/// lost packet is replaced with the previous one fading out, longer gaps with silence, and the
+/// packet after a gap fades in. Packets arriving after they were given up on are dropped.
+pub struct JitterBuffer {
+    depth: usize,
+    channels: usize,
+    /// Sequence number of the first slot, once the first packet arrived.
+    next: Option<u32>,
+    /// The packets from `next` on, or `None` for those still missing.
+    slots: VecDeque<Option<Vec<f32>>>,
+    previous: Vec<f32>,
+    /// Packets given up on in a row.
+    lost_run: u64,
+    /// Which of the 64 packets before `next` were played, the latest in the lowest bit, to
+    /// tell duplicates from late packets.
+    played: u64,
+    scratch: Vec<f32>,
+    counts: JitterCounts,
+}
+
+impl JitterBuffer {
+    /// Creates a buffer for packets of interleaved frames of `channels` channels.
+    pub fn new(depth: usize, channels: usize) -> Self {
+        JitterBuffer {
+            depth: depth.max(1),
+            channels,
+            next: None,
+            slots: VecDeque::new(),
+            previous: Vec::new(),
+            lost_run: 0,
+            played: 0,
+            scratch: Vec::new(),
+            counts: JitterCounts::default(),
+        }
+    }
+
+    pub fn counts(&self) -> JitterCounts {
+        self.counts
+    }
+
+    /// Takes in packet `sequence`, then passes the packets now due to `play`, in order.
+    pub fn insert(&mut self, sequence: u32, samples: &[f32], mut play: impl FnMut(&[f32])) {
+        let next = *self.next.get_or_insert(sequence);
+        let mut offset = sequence.wrapping_sub(next) as i32;
+        if !(-RESYNC..RESYNC).contains(&offset) {
+            // Play what arrived, and start over from this packet.
+            while !self.slots.is_empty() {
+                self.release(&mut play, false);
+            }
+            self.next = Some(sequence);
+            offset = 0;
+        }
+        if offset < 0 {
+            match offset >= -64 && self.played >> (-offset - 1) & 1 == 1 {
+                true => self.counts.duplicates += 1,
+                false => self.counts.late += 1,
+            }
+            return;
+        }
+        // Give up on the oldest packets to make room.
+        let mut offset = offset as usize;
+        while offset >= self.depth {
+            self.release(&mut play, true);
+            offset -= 1;
+        }
+        if self.slots.len() <= offset {
+            self.slots.resize(offset + 1, None);
+        }
+        match &mut self.slots[offset] {
+            Some(_) => self.counts.duplicates += 1,
+            slot => *slot = Some(samples.to_vec()),
+        }
+        while let Some(Some(_)) = self.slots.front() {
+            self.release(&mut play, false);
+        }
+    }
+
+    /// Plays the first slot, or what replaces it if the packet is missing. Missing packets are
+    /// only given up on when `give_up`, and skipped otherwise.
+    fn release(&mut self, play: &mut impl FnMut(&[f32]), give_up: bool) {
+        let slot = self.slots.pop_front().flatten();
+        self.next = self.next.map(|x| x.wrapping_add(1));
+        self.played = self.played << 1 | slot.is_some() as u64;
+        let frames = |samples: &[f32]| samples.len() / self.channels.max(1);
+        match slot {
+            Some(mut samples) => {
+                if self.lost_run > 0 {
+                    let len = frames(&samples).max(1) as f32;
+                    for (index, frame) in samples.chunks_exact_mut(self.channels).enumerate() {
+                        frame.iter_mut().for_each(|x| *x *= index as f32 / len);
+                    }
+                }
+                play(&samples);
+                self.previous = samples;
+                self.lost_run = 0;
+                self.counts.played += 1;
+            }
+            None if give_up => {
+                self.counts.lost += 1;
+                self.lost_run += 1;
+                self.scratch.clear();
+                self.scratch.extend_from_slice(&self.previous);
+                if self.lost_run == 1 && !self.previous.is_empty() {
+                    let len = frames(&self.scratch).max(1) as f32;
+                    for (index, frame) in self.scratch.chunks_exact_mut(self.channels).enumerate() {
+                        frame
+                            .iter_mut()
+                            .for_each(|x| *x *= 1.0 - index as f32 / len);
+                    }
+                    self.counts.concealed += 1;
+                } else {
+                    self.scratch.fill(0.0);
+                }
+                play(&self.scratch);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Counters of a receiver, shared with the status line.
+#[derive(Default)]
+pub struct ReceiveStats {
+    pub received: AtomicU64,
+    pub invalid: AtomicU64,
+    pub late: AtomicU64,
+    pub lost: AtomicU64,
+    pub concealed: AtomicU64,
+    pub duplicates: AtomicU64,
+    /// Times the output ran out of received audio and waited for the buffer to fill again.
+    pub underruns: AtomicU64,
+    /// Sample rate of the sender, 0 until the first packet.
+    pub sample_rate: AtomicU32,
+    /// Audio received but not played yet, in milliseconds.
+    pub buffered_ms: AtomicF32,
+}
+
+impl ReceiveStats {
+    /// Describes the counters for the status line, e.g. "net in: 21.3 ms, 0 late, 0 lost".
+    pub fn describe(&self) -> String {
+        let load = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        if load(&self.received) == 0 {
+            return "net in: waiting".to_string();
+        }
+        let mut text = format!(
+            "net in: {:.1} ms, {} late, {} lost",
+            self.buffered_ms.load(),
+            load(&self.late),
+            load(&self.lost)
+        );
+        for (count, name) in [(&self.underruns, "underruns"), (&self.invalid, "invalid")] {
+            if load(count) > 0 {
+                text += &format!(", {} {}", load(count), name);
+            }
+        }
+        text
+    }
+
+    fn publish(&self, counts: JitterCounts) {
+        self.late.store(counts.late, Ordering::Relaxed);
+        self.lost.store(counts.lost, Ordering::Relaxed);
+        self.concealed.store(counts.concealed, Ordering::Relaxed);
+        self.duplicates.store(counts.duplicates, Ordering::Relaxed);
+    }
+}
+
+/// Starts the network thread receiving packets on `address`, and pushing them into `producer`
+/// mapped to `channels` channels, after waiting for late ones for up to `jitter`. The thread
+/// finishes once the consumer is dropped.
+pub fn spawn_receiver(
+    address: SocketAddr,
+    mut producer: HeapProd<f32>,
+    channels: u16,
+    jitter: Duration,
+    stats: Arc<ReceiveStats>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let socket =
+        UdpSocket::bind(address).with_context(|| format!("cannot listen on {}", address))?;
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    Ok(std::thread::spawn(move || {
+        let mut packet = vec![0; MAX_DATAGRAM];
+        let (mut samples, mut mapped) = (Vec::new(), Vec::new());
+        // The stream's format, and the buffer for it.
+        let mut format = None;
+        let mut buffer = JitterBuffer::new(1, channels as usize);
+        let mut counts = JitterCounts::default();
+        while producer.read_is_held() {
+            let Ok(len) = socket.recv(&mut packet) else {
+                continue;
+            };
+            let Ok(header) = decode(&packet[..len], &mut samples) else {
+                stats.invalid.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            stats.received.fetch_add(1, Ordering::Relaxed);
+            let frames = samples.len() / header.channels as usize;
+            if format != Some((header.channels, header.sample_rate, frames)) && frames > 0 {
+                // A new stream, or a sender that restarted with other settings.
+                format = Some((header.channels, header.sample_rate, frames));
+                let packet_duration = frames as f64 / header.sample_rate.max(1) as f64;
+                let depth = (jitter.as_secs_f64() / packet_duration).ceil() as usize;
+                counts = add(counts, buffer.counts());
+                buffer = JitterBuffer::new(depth, channels as usize);
+                stats
+                    .sample_rate
+                    .store(header.sample_rate, Ordering::Relaxed);
+            }
+            mapped.clear();
+            ChannelAdapter::new(header.channels as usize, channels as usize)
+                .process(&samples, &mut mapped);
+            // A full ring means the output stopped playing, so what doesn't fit is dropped.
+            buffer.insert(header.sequence, &mapped, |x| {
+                if producer.vacant_len() >= x.len() {
+                    producer.push_slice(x);
+                }
+            });
+            stats.publish(add(counts, buffer.counts()));
+        }
+    }))
+}
+
+fn add(a: JitterCounts, b: JitterCounts) -> JitterCounts {
+    JitterCounts {
+        played: a.played + b.played,
+        late: a.late + b.late,
+        lost: a.lost + b.lost,
+        concealed: a.concealed + b.concealed,
+        duplicates: a.duplicates + b.duplicates,
+    }
+}
+
+/// The output callback's end of a receiver: plays the received audio at the output's rate,
+/// following the sender's clock.
+pub struct NetSource {
+    consumer: HeapCons<f32>,
+    channels: usize,
+    sample_rate: u32,
+    jitter: Duration,
+    /// The sender's rate the resampler follows, once known.
+    sender_rate: u32,
+    resampler: Resampler,
+    /// Frames of the sender to keep buffered, and the smoothed relative error of the fill.
+    target: usize,
+    error: f64,
+    /// Whether playback waits for the buffer to fill, at the start or after running dry.
+    buffering: bool,
+    /// Samples resampled but not played yet.
+    pending: Vec<f32>,
+    chunk: Vec<f32>,
+    stats: Arc<ReceiveStats>,
+}
+
+impl NetSource {
+    /// Creates the source of an output of `channels` channels at `sample_rate`, fed by the
+    /// thread of [`spawn_receiver`], and keeping `jitter` of audio buffered.
+    pub fn new(
+        consumer: HeapCons<f32>,
+        channels: usize,
+        sample_rate: u32,
+        jitter: Duration,
+        stats: Arc<ReceiveStats>,
+    ) -> Self {
+        NetSource {
+            consumer,
+            channels,
+            sample_rate,
+            jitter,
+            sender_rate: 0,
+            resampler: Resampler::new(channels, sample_rate, sample_rate),
+            target: 0,
+            error: 0.0,
+            buffering: true,
+            pending: Vec::with_capacity(8_192 * channels),
+            chunk: vec![0.0; 64 * channels],
+            stats,
+        }
+    }
+
+    /// Plays at `sample_rate` from now on, buffering again from whatever was received.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        // Makes the next block take the sender's rate as new.
+        self.sender_rate = 0;
+    }
+
+    /// Adds the received audio to the interleaved block `data`, or writes it over it when
+    /// `replace`.
+    pub fn mix_into(&mut self, data: &mut [f32], replace: bool) {
+        if replace {
+            data.fill(0.0);
+        }
+        let sender_rate = self.stats.sample_rate.load(Ordering::Relaxed);
+        if sender_rate == 0 {
+            return;
+        }
+        if sender_rate != self.sender_rate {
+            self.sender_rate = sender_rate;
+            self.resampler = Resampler::new(self.channels, sender_rate, self.sample_rate);
+            self.target = (self.jitter.as_secs_f64() * sender_rate as f64).ceil() as usize;
+            self.error = 0.0;
+            self.pending.clear();
+            self.buffering = true;
+        }
+        let frames = data.len() / self.channels;
+        let fill = self.consumer.occupied_len() / self.channels;
+        if self.buffering && fill < self.target.max(1) {
+            self.publish(fill);
+            return;
+        }
+        self.buffering = false;
+        if fill > self.target * 4 + self.chunk.len() / self.channels {
+            // Far behind, e.g. after the output stalled: skip ahead rather than catching up
+            // slowly.
+            self.consumer.skip((fill - self.target) * self.channels);
+            self.error = 0.0;
+        }
+        let fill = self.consumer.occupied_len() / self.channels;
+
+        // Play faster when the buffer fills up, slower when it drains.
+        let error = (fill as f64 - self.target as f64) / self.target.max(1) as f64;
+        let smoothing = frames as f64 / (self.sample_rate as f64 * FILL_SMOOTHING);
+        self.error += (error - self.error) * smoothing.min(1.0);
+        let drift = (self.error * DRIFT_GAIN).clamp(-MAX_DRIFT, MAX_DRIFT);
+        self.resampler
+            .set_ratio(sender_rate as f64 / self.sample_rate as f64 * (1.0 + drift));
+
+        while self.pending.len() < data.len() {
+            let available = self.consumer.occupied_len().min(self.chunk.len());
+            let popped = self
+                .consumer
+                .pop_slice(&mut self.chunk[..available - available % self.channels]);
+            if popped == 0 {
+                break;
+            }
+            self.resampler
+                .process(&self.chunk[..popped], &mut self.pending);
+        }
+        let played = self.pending.len().min(data.len());
+        for (x, y) in data.iter_mut().zip(self.pending.drain(..played)) {
+            *x += y;
+        }
+        if played < data.len() {
+            self.stats.underruns.fetch_add(1, Ordering::Relaxed);
+            self.buffering = true;
+        }
+        self.publish(self.consumer.occupied_len() / self.channels);
+    }
+
+    fn publish(&self, fill: usize) {
+        let seconds = fill as f64 / self.sender_rate.max(1) as f64
+            + (self.pending.len() / self.channels) as f64 / self.sample_rate as f64;
+        self.stats.buffered_ms.store((seconds * 1_000.0) as f32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ringbuf::traits::Split;
+    use ringbuf::HeapRb;
+
+    fn header(encoding: Encoding) -> Header {
+        Header {
+            sequence: 7,
+            timestamp: 1 << 40,
+            sample_rate: 48_000,
+            channels: 2,
+            encoding,
+        }
+    }
+
+    fn encoded(header: &Header, samples: &[f32]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        let mut dither = Dither::new(DitherMode::Off, header.channels as usize);
+        encode(header, samples, &mut dither, &mut packet);
+        packet
+    }
+
+    #[test]
+    fn packets_decode_to_their_header_and_samples() {
+        let samples = [0.5, -0.25, 1.0, -1.0];
+        let header = header(Encoding::F32);
+        let packet = encoded(&header, &samples);
+        assert_eq!(packet.len(), HEADER_SIZE + 16);
+        assert_eq!(packet[..4], MAGIC);
+        let mut decoded = Vec::new();
+        assert_eq!(decode(&packet, &mut decoded), Ok(header));
+        assert_eq!(decoded, samples);
+
+        let header = Header {
+            encoding: Encoding::I16,
+            ..header
+        };
+        let packet = encoded(&header, &samples);
+        assert_eq!(packet.len(), HEADER_SIZE + 8);
+        assert_eq!(decode(&packet, &mut decoded), Ok(header));
+        for (x, y) in samples.iter().zip(&decoded) {
+            assert!((x - y).abs() <= 1.0 / 32_768.0, "{} {}", x, y);
+        }
+    }
+
+    #[test]
+    fn broken_packets_are_rejected() {
+        let mut samples = Vec::new();
+        let packet = encoded(&header(Encoding::F32), &[0.5, 0.5]);
+        let mut decode = |packet: &[u8]| decode(packet, &mut samples).unwrap_err();
+        assert_eq!(decode(&packet[..HEADER_SIZE - 1]), "not an audio packet");
+        let mut other = packet.clone();
+        other[0] = b'X';
+        assert_eq!(decode(&other), "not an audio packet");
+        other.clone_from(&packet);
+        other[22] = 9;
+        assert_eq!(decode(&other), "unknown sample encoding 9");
+        assert_eq!(
+            decode(&packet[..packet.len() - 1]),
+            "the packet doesn't hold whole frames"
+        );
+        other.clone_from(&packet);
+        other[20..22].fill(0);
+        assert_eq!(decode(&other), "the packet doesn't hold whole frames");
+    }
+
+    #[test]
+    fn packets_hold_5_ms_and_fit_a_datagram() {
+        assert_eq!(packet_frames(2, 48_000, Encoding::F32), 240);
+        assert_eq!(packet_frames(2, 44_100, Encoding::I16), 221);
+        let largest = (MAX_DATAGRAM - HEADER_SIZE) / (1_024 * 4);
+        assert_eq!(packet_frames(1_024, 192_000, Encoding::F32), largest);
+        assert_eq!("i16".parse(), Ok(Encoding::I16));
+        assert_eq!(
+            "f64".parse::<Encoding>().unwrap_err(),
+            "unsupported encoding \"f64\""
+        );
+    }
+
+    #[test]
+    fn a_loopback_receiver_gets_the_packets_in_sequence() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (mut producer, consumer) = HeapRb::<f32>::new(4_096).split();
+        producer.push_iter((0..240 * 3).map(|x| x as f32));
+        drop(producer);
+        let stats = Arc::new(SendStats::default());
+        let sender = spawn_sender(
+            socket.local_addr().unwrap(),
+            consumer,
+            1,
+            StreamRate::new(48_000),
+            Encoding::F32,
+            DitherMode::Off,
+            stats.clone(),
+        )
+        .unwrap();
+        sender.join().unwrap();
+        let mut packet = vec![0; MAX_DATAGRAM];
+        let mut samples = Vec::new();
+        for sequence in 0..3 {
+            let len = socket.recv(&mut packet).unwrap();
+            let header = decode(&packet[..len], &mut samples).unwrap();
+            assert_eq!(
+                (header.sequence, header.timestamp),
+                (sequence, sequence as u64 * 240)
+            );
+            assert_eq!(samples[0], sequence as f32 * 240.0);
+        }
+        assert_eq!(stats.describe(), "net: 3 sent, 0 errors");
+    }
+
+    /// Inserts packets of 4 mono frames of their sequence number into `buffer`, in the order
+    /// of `sequences`, returning the first sample of every packet played.
+    fn jitter(buffer: &mut JitterBuffer, sequences: &[u32]) -> Vec<Vec<f32>> {
+        let mut played = Vec::new();
+        for &sequence in sequences {
+            buffer.insert(sequence, &[sequence as f32; 4], |x| played.push(x.to_vec()));
+        }
+        played
+    }
+
+    #[test]
+    fn packets_out_of_order_are_played_in_order() {
+        let mut buffer = JitterBuffer::new(3, 1);
+        let played = jitter(&mut buffer, &[0, 2, 1, 3]);
+        let firsts: Vec<f32> = played.iter().map(|x| x[0]).collect();
+        assert_eq!(firsts, [0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(buffer.counts().played, 4);
+    }
+
+    #[test]
+    fn a_lost_packet_fades_the_previous_one_out_and_the_next_one_in() {
+        let mut buffer = JitterBuffer::new(2, 1);
+        let played = jitter(&mut buffer, &[0, 2, 3]);
+        assert_eq!(
+            played,
+            [
+                vec![0.0; 4],
+                vec![0.0, 0.0, 0.0, 0.0],
+                vec![0.0, 0.5, 1.0, 1.5],
+                vec![3.0; 4],
+            ]
+        );
+        let mut buffer = JitterBuffer::new(2, 1);
+        let played = jitter(&mut buffer, &[1, 3, 4]);
+        assert_eq!(played[1], [1.0, 0.75, 0.5, 0.25]);
+        let counts = buffer.counts();
+        assert_eq!((counts.lost, counts.concealed), (1, 1));
+    }
+
+    #[test]
+    fn longer_gaps_are_silent() {
+        let mut buffer = JitterBuffer::new(1, 1);
+        let played = jitter(&mut buffer, &[1, 4]);
+        assert_eq!(played[1], [1.0, 0.75, 0.5, 0.25]);
+        assert_eq!(played[2], [0.0; 4]);
+        assert_eq!(played[3], [0.0, 1.0, 2.0, 3.0]);
+        let counts = buffer.counts();
+        assert_eq!((counts.lost, counts.concealed), (2, 1));
+    }
+
+    #[test]
+    fn late_packets_and_duplicates_are_dropped_and_counted() {
+        let mut buffer = JitterBuffer::new(1, 1);
+        let played = jitter(&mut buffer, &[1, 3, 2, 3, 1]);
+        assert_eq!(played.len(), 3);
+        let counts = buffer.counts();
+        assert_eq!((counts.late, counts.duplicates, counts.lost), (1, 2, 1));
+    }
+
+    #[test]
+    fn a_sender_far_off_the_sequence_starts_over() {
+        let mut buffer = JitterBuffer::new(4, 1);
+        let played = jitter(&mut buffer, &[10, 12, 5_000, 5_001]);
+        let firsts: Vec<f32> = played.iter().map(|x| x[0]).collect();
+        assert_eq!(firsts, [10.0, 12.0, 5_000.0, 5_001.0]);
+        assert_eq!(buffer.counts().lost, 0);
+    }
+
+    #[test]
+    fn the_source_waits_for_the_jitter_and_again_after_running_dry() {
+        let (mut producer, consumer) = HeapRb::<f32>::new(48_000).split();
+        let stats = Arc::new(ReceiveStats::default());
+        assert_eq!(stats.describe(), "net in: waiting");
+        stats.sample_rate.store(48_000, Ordering::Relaxed);
+        let jitter = Duration::from_millis(10);
+        let mut source = NetSource::new(consumer, 1, 48_000, jitter, stats.clone());
+        producer.push_iter(std::iter::repeat_n(0.25, 400));
+        let mut data = vec![0.0; 240];
+        source.mix_into(&mut data, true);
+        assert!(data.iter().all(|x| *x == 0.0));
+        producer.push_iter(std::iter::repeat_n(0.25, 200));
+        source.mix_into(&mut data, true);
+        assert!(data[100..].iter().all(|x| (x - 0.25).abs() < 1e-6));
+        // Less than a block left.
+        source.mix_into(&mut data, true);
+        source.mix_into(&mut data, true);
+        assert_eq!(stats.underruns.load(Ordering::Relaxed), 1);
+        producer.push_iter(std::iter::repeat_n(0.25, 100));
+        source.mix_into(&mut data, true);
+        assert!(data.iter().all(|x| *x == 0.0));
+        stats.received.store(5, Ordering::Relaxed);
+        assert_eq!(
+            stats.describe(),
+            format!(
+                "net in: {:.1} ms, 0 late, 0 lost, 1 underruns",
+                stats.buffered_ms.load()
+            )
+        );
+    }
+
+    #[test]
+    fn a_source_at_another_rate_takes_the_sender_at_the_new_ratio() {
+        let (mut producer, consumer) = HeapRb::<f32>::new(48_000).split();
+        let stats = Arc::new(ReceiveStats::default());
+        stats.sample_rate.store(48_000, Ordering::Relaxed);
+        let jitter = Duration::from_millis(10);
+        let mut source = NetSource::new(consumer, 1, 48_000, jitter, stats.clone());
+        // Within four times the jitter, which the source doesn't skip.
+        producer.push_iter(std::iter::repeat_n(0.25, 1_900));
+        let taken = |source: &mut NetSource, producer: &HeapProd<f32>| {
+            let before = producer.occupied_len();
+            source.mix_into(&mut vec![0.0; 480], true);
+            before - producer.occupied_len()
+        };
+        let at_48k = taken(&mut source, &producer);
+        assert!((480..480 + 64).contains(&at_48k), "{}", at_48k);
+
+        source.set_sample_rate(96_000);
+        let at_96k = taken(&mut source, &producer);
+        assert!((240..240 + 64).contains(&at_96k), "{}", at_96k);
+    }
+}
@@ -0,0 +1,390 @@
+//! Null test of the first output: how much its final signal differs from its input.
+//!
+//! The output callback sends the mix of the inputs as it comes out of the ring buffers, and the
+//! final output, sample by sample, to a thread of its own through a ring buffer. There the output
+//! is aligned with the input by the known latency of the chain, adjusted by the lag of the
+//! largest cross-correlation around it, and the input is subtracted from it. The residual is
+//! reported in dB relative to the input: a transparent chain nulls to nothing, and an effect
+//! leaves a residual as loud as it changes the signal.
+//!
+//! Silent stretches of the input are left out, since there's nothing to compare.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use ringbuf::traits::{Consumer, Observer, Producer};
+use ringbuf::{HeapCons, HeapProd};
+
+use crate::stats::AtomicF32;
+
+/// Length of the stretches the residual is measured over, in seconds.
+const WINDOW_SECONDS: f64 = 0.5;
+/// How far from the known latency the alignment looks, at first and then from window to window,
+/// in frames.
+const SEARCH: usize = 1_024;
+const FINE_SEARCH: usize = 8;
+/// Mean square of the input under which a window counts as silent, about -80 dBFS.
+const SILENCE: f64 = 1e-8;
+
+/// The lag from `nominal - search` to `nominal + search` by which `output` best follows
+/// `reference`, both mono, as the frame of `output` that `reference[0]` comes out at. The
+/// comparison covers the frames of `output` from `start`, which must be at least the largest lag.
+/// Returns `None` if no lag correlates positively.
+pub fn align(
+    reference: &[f32],
+    output: &[f32],
+    start: usize,
+    nominal: usize,
+    search: usize,
+) -> Option<usize> {
+    let lags = nominal.saturating_sub(search)..=(nominal + search).min(start);
+    let mut best = None;
+    let mut best_score = 0.0;
+    for lag in lags {
+        let (mut product, mut energy) = (0.0f64, 0.0f64);
+        for (n, y) in output.iter().enumerate().skip(start) {
+            let Some(x) = reference.get(n - lag) else {
+                break;
+            };
+            product += *x as f64 * *y as f64;
+            energy += *x as f64 * *x as f64;
+        }
+        // Normalised by the input only, so that louder stretches of it don't win by themselves.
+        let score = product / energy.sqrt().max(f64::MIN_POSITIVE);
+        if score > best_score {
+            best_score = score;
+            best = Some(lag);
+        }
+    }
+    best
+}
+
+/// Energies of the residual and of the input over the frames of interleaved `output` from
+/// `start`, with `reference` `delay` frames earlier.
+pub fn residual(
+    reference: &[f32],
+    output: &[f32],
+    channels: usize,
+    start: usize,
+    delay: usize,
+) -> (f64, f64) {
+    let (mut residual, mut energy) = (0.0, 0.0);
+    let offset = (start - delay) * channels;
+    let reference = reference.get(offset..).unwrap_or_default();
+    for (x, y) in reference.iter().zip(&output[start * channels..]) {
+        let difference = *y as f64 - *x as f64;
+        residual += difference * difference;
+        energy += *x as f64 * *x as f64;
+    }
+    (residual, energy)
+}
+
+/// A ratio of energies in dB.
+pub fn ratio_db(residual: f64, energy: f64) -> f32 {
+    (10.0 * (residual / energy).log10()) as f32
+}
+
+/// Measures the residual window by window, as samples come in.
+pub struct NullMeter {
+    channels: usize,
+    window: usize,
+    nominal: usize,
+    /// The history of both signals, interleaved, as far back as the longest lag.
+    reference: Vec<f32>,
+    output: Vec<f32>,
+    delay: Option<usize>,
+    residual: f64,
+    energy: f64,
+}
+
+impl NullMeter {
+    /// Creates a meter of `channels` channels at `sample_rate`, of a chain whose known latency
+    /// is `latency` frames.
+    pub fn new(channels: usize, sample_rate: u32, latency: usize) -> Self {
+        NullMeter {
+            channels,
+            window: (WINDOW_SECONDS * sample_rate as f64) as usize,
+            nominal: latency,
+            reference: Vec::new(),
+            output: Vec::new(),
+            delay: None,
+            residual: 0.0,
+            energy: 0.0,
+        }
+    }
+
+    fn history(&self) -> usize {
+        self.nominal + SEARCH
+    }
+
+    /// Takes a block of both signals, returning the residual of each window it completes, in
+    /// dB, unless the input was silent.
+    pub fn push(&mut self, reference: &[f32], output: &[f32]) -> Vec<f32> {
+        self.reference.extend_from_slice(reference);
+        self.output.extend_from_slice(output);
+        let mut results = Vec::new();
+        let start = self.history();
+        while self.output.len() / self.channels >= start + self.window {
+            let end = (start + self.window) * self.channels;
+            if let Some(db) = self.measure(start, end) {
+                results.push(db);
+            }
+            let used = self.window * self.channels;
+            self.reference.drain(..used);
+            self.output.drain(..used);
+        }
+        results
+    }
+
+    fn measure(&mut self, start: usize, end: usize) -> Option<f32> {
+        let frames = |x: &[f32]| -> Vec<f32> {
+            x.chunks_exact(self.channels)
+                .map(|x| x.iter().sum())
+                .collect()
+        };
+        let energy: f64 = self.reference[start * self.channels..end]
+            .iter()
+            .map(|x| *x as f64 * *x as f64)
+            .sum();
+        if energy / ((end / self.channels - start) * self.channels) as f64 <= SILENCE {
+            return None;
+        }
+        let reference = frames(&self.reference[..end]);
+        let output = frames(&self.output[..end]);
+        let (around, search) = match self.delay {
+            Some(delay) => (delay, FINE_SEARCH),
+            None => (self.nominal, SEARCH),
+        };
+        self.delay = align(&reference, &output, start, around, search).or(self.delay);
+        let (residual, energy) = residual(
+            &self.reference[..end],
+            &self.output[..end],
+            self.channels,
+            start,
+            self.delay.unwrap_or(self.nominal),
+        );
+        self.residual += residual;
+        self.energy += energy;
+        Some(ratio_db(residual, energy))
+    }
+
+    /// The residual over all the windows so far, in dB, unless the input was always silent.
+    pub fn total_db(&self) -> Option<f32> {
+        (self.energy > 0.0).then(|| ratio_db(self.residual, self.energy))
+    }
+
+    /// Frames the output is found to be behind the input.
+    pub fn delay(&self) -> Option<usize> {
+        self.delay
+    }
+}
+
+/// Shared with the status line.
+pub struct NullStats {
+    /// Residual of the last window with signal, in dB, or NaN before the first one.
+    pub residual_db: AtomicF32,
+    /// Blocks that didn't fit in the ring buffer.
+    pub dropped: AtomicU64,
+}
+
+impl Default for NullStats {
+    fn default() -> Self {
+        NullStats {
+            residual_db: AtomicF32::new(f32::NAN),
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+impl NullStats {
+    pub fn describe(&self) -> String {
+        match self.residual_db.load() {
+            x if x.is_nan() => "null: no signal".to_string(),
+            x => format!("null: {:.1} dB", x),
+        }
+    }
+}
+
+/// Sends both signals of each block of the output callback to the null test thread.
+pub struct NullTap {
+    producer: HeapProd<f32>,
+    reference: Vec<f32>,
+    stats: Arc<NullStats>,
+}
+
+impl NullTap {
+    pub fn new(producer: HeapProd<f32>, stats: Arc<NullStats>) -> Self {
+        NullTap {
+            producer,
+            reference: Vec::new(),
+            stats,
+        }
+    }
+
+    /// Keeps the input of the block, before any processing.
+    pub fn capture(&mut self, data: &[f32]) {
+        self.reference.clear();
+        self.reference.extend_from_slice(data);
+    }
+
+    /// Sends the block whole, as pairs of input and output samples, or not at all.
+    pub fn send(&mut self, output: &[f32]) {
+        if self.producer.vacant_len() < output.len() * 2 {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let pairs = self
+            .reference
+            .iter()
+            .zip(output)
+            .flat_map(|(x, y)| [*x, *y]);
+        self.producer.push_iter(pairs);
+    }
+}
+
+/// Starts the thread measuring the pairs of samples in `consumer`, until the producer is dropped
+/// and the buffer is empty, when it returns its meter.
+pub fn spawn(
+    mut consumer: HeapCons<f32>,
+    mut meter: NullMeter,
+    stats: Arc<NullStats>,
+) -> JoinHandle<NullMeter> {
+    std::thread::spawn(move || {
+        let mut pairs = vec![0.0; 8_192];
+        let (mut reference, mut output) = (Vec::new(), Vec::new());
+        loop {
+            // Whole pairs only, in case a block is still being pushed.
+            let available = (consumer.occupied_len() & !1).min(pairs.len());
+            let popped = consumer.pop_slice(&mut pairs[..available]);
+            if popped == 0 {
+                if !consumer.write_is_held() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(5));
+                continue;
+            }
+            reference.clear();
+            output.clear();
+            for pair in pairs[..popped].chunks_exact(2) {
+                reference.push(pair[0]);
+                output.push(pair[1]);
+            }
+            if let Some(db) = meter.push(&reference, &output).last() {
+                stats.residual_db.store(*db);
+            }
+        }
+        meter
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ringbuf::traits::Split;
+    use ringbuf::HeapRb;
+
+    fn noise(len: usize) -> Vec<f32> {
+        let mut state = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as f32 / u32::MAX as f32 - 0.5
+            })
+            .collect()
+    }
+
+    /// `input` with `delay` frames of silence in front, of `channels` channels, times `gain`.
+    fn delayed(input: &[f32], channels: usize, delay: usize, gain: f32) -> Vec<f32> {
+        let mut output = vec![0.0; delay * channels];
+        output.extend(input.iter().map(|x| x * gain));
+        output.truncate(input.len());
+        output
+    }
+
+    #[test]
+    fn the_lag_of_the_best_correlation_is_found() {
+        let reference = noise(4_000);
+        let output = delayed(&reference, 1, 37, 1.0);
+        assert_eq!(align(&reference, &output, 100, 30, 10), Some(37));
+        assert_eq!(align(&reference, &[0.0; 4_000], 100, 30, 10), None);
+    }
+
+    #[test]
+    fn a_delayed_copy_nulls_to_nothing() {
+        let input = noise(2 * 8_000);
+        let output = delayed(&input, 2, 150, 1.0);
+        // The chain reports 100 frames, but the output comes 50 later.
+        let mut meter = NullMeter::new(2, 1_000, 100);
+        let mut results = Vec::new();
+        for (x, y) in input.chunks(256).zip(output.chunks(256)) {
+            results.extend(meter.push(x, y));
+        }
+        assert_eq!(results.len(), 13);
+        assert!(results.iter().all(|x| *x < -100.0), "{:?}", results);
+        assert_eq!(meter.delay(), Some(150));
+        assert!(meter.total_db().unwrap() < -100.0);
+    }
+
+    #[test]
+    fn a_change_of_level_leaves_a_residual() {
+        let input = noise(8_000);
+        let output = delayed(&input, 1, 20, 0.5);
+        let mut meter = NullMeter::new(1, 1_000, 20);
+        let results = meter.push(&input, &output);
+        assert!(!results.is_empty());
+        for db in results {
+            assert!((db + 6.02).abs() < 0.01, "{}", db);
+        }
+    }
+
+    #[test]
+    fn silence_is_left_out() {
+        let mut meter = NullMeter::new(1, 1_000, 0);
+        assert!(meter.push(&[0.0; 4_000], &[0.1; 4_000]).is_empty());
+        assert_eq!(meter.total_db(), None);
+        assert_eq!(meter.delay(), None);
+        let stats = NullStats::default();
+        assert_eq!(stats.describe(), "null: no signal");
+        stats.residual_db.store(-42.04);
+        assert_eq!(stats.describe(), "null: -42.0 dB");
+    }
+
+    #[test]
+    fn the_thread_measures_the_pairs_tapped() {
+        let (producer, consumer) = HeapRb::<f32>::new(1_024).split();
+        let stats = Arc::new(NullStats::default());
+        let thread = spawn(consumer, NullMeter::new(1, 1_000, 0), stats.clone());
+        let mut tap = NullTap::new(producer, stats.clone());
+        let input = noise(4_000);
+        for block in input.chunks(100) {
+            tap.capture(block);
+            while tap.producer.vacant_len() < block.len() * 2 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            tap.send(block);
+        }
+        drop(tap);
+        let meter = thread.join().unwrap();
+        assert!(meter.total_db().unwrap() < -100.0);
+        assert!(stats.residual_db.load() < -100.0);
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn blocks_that_do_not_fit_are_dropped_whole() {
+        let (producer, consumer) = HeapRb::<f32>::new(300).split();
+        let stats = Arc::new(NullStats::default());
+        let mut tap = NullTap::new(producer, stats.clone());
+        for _ in 0..2 {
+            tap.capture(&[0.5; 100]);
+            tap.send(&[0.25; 100]);
+        }
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(consumer.occupied_len(), 200);
+    }
+}
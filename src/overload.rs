@@ -0,0 +1,311 @@
+//! Overload of the analog front end of an input, which flattens the tops of the waveform some dB
+//! before digital full scale, where no sample reaches it.
+//!
+//! Each half-cycle of each channel, from one zero crossing to the next, is checked for a flat
+//! top once it ends: the run of consecutive samples around its peak that are within
+//! [`FLATNESS`] of it. A sine spends about a tenth of its half-cycle there, whatever its
+//! frequency, while one squashed by an overloading front end spends a good share more, so a
+//! half-cycle peaking over [`LEVEL_DB`] counts as overloaded once its flat top takes a larger
+//! share than the sensitivity allows. Half-cycles reaching full scale are hard clips instead,
+//! counted apart.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::level;
+
+/// Level a half-cycle has to peak over to be checked, in dBFS.
+pub const LEVEL_DB: f32 = -12.0;
+/// How far under its peak a sample may be and still belong to the flat top, relative to it.
+pub const FLATNESS: f32 = 0.01;
+/// Shortest flat top that counts, in samples, since a few samples at the top of a short
+/// half-cycle are always within [`FLATNESS`] of each other.
+pub const MIN_RUN: usize = 4;
+/// Peak from which a sample counts as a hard clip, just under full scale for the converters
+/// whose largest value is a step short of it.
+pub const CLIP_LEVEL: f32 = 0.999;
+/// How long the status line keeps showing an overload after the last one.
+pub const HOLD: Duration = Duration::from_secs(3);
+/// Sensitivity of the detector when none is given.
+pub const DEFAULT_SENSITIVITY: f32 = 0.5;
+/// Shares of its half-cycle a flat top has to take to count, at sensitivities 0 and 1. Sines
+/// never take more than 0.125, the most when the top of a short half-cycle just fits in
+/// [`MIN_RUN`] samples.
+const LEAST_SENSITIVE: f32 = 0.5;
+const MOST_SENSITIVE: f32 = 0.16;
+/// Longest half-cycle checked: longer ones are silence, or an offset, rather than a waveform.
+const MAX_HALF_CYCLE_SECONDS: f64 = 0.05;
+
+/// The share of its half-cycle a flat top has to take to count as an overload at `sensitivity`,
+/// from 0 to 1.
+pub fn share(sensitivity: f32) -> f32 {
+    LEAST_SENSITIVE + (MOST_SENSITIVE - LEAST_SENSITIVE) * sensitivity.clamp(0.0, 1.0)
+}
+
+/// Whether the samples of one `half_cycle`, all of the same sign, peak over [`LEVEL_DB`] with a
+/// flat top taking more than `share` of them, as [`share`] gives it.
+pub fn is_flat_topped(half_cycle: &[f32], share: f32) -> bool {
+    let Some((peak_at, peak)) = half_cycle
+        .iter()
+        .map(|x| x.abs())
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+    else {
+        return false;
+    };
+    if peak < level::db_to_gain(LEVEL_DB) {
+        return false;
+    }
+    let floor = peak * (1.0 - FLATNESS);
+    let flat = |x: &f32| x.abs() >= floor;
+    let before = half_cycle[..peak_at]
+        .iter()
+        .rev()
+        .take_while(|x| flat(x))
+        .count();
+    let after = half_cycle[peak_at + 1..]
+        .iter()
+        .take_while(|x| flat(x))
+        .count();
+    let run = before + 1 + after;
+    run >= MIN_RUN && run as f32 > share * half_cycle.len() as f32
+}
+
+/// Overloads and clips of one input, shared with the status line and the summary.
+pub struct OverloadStats {
+    /// Label of the input stream.
+    label: &'static str,
+    /// Blocks in which a half-cycle ended overloaded, without clipping.
+    overloads: AtomicUsize,
+    /// Blocks with a sample at full scale.
+    clips: AtomicUsize,
+    /// Frames left before the indicator goes off.
+    held: AtomicUsize,
+}
+
+impl OverloadStats {
+    pub fn new(label: &'static str) -> Self {
+        OverloadStats {
+            label,
+            overloads: AtomicUsize::new(0),
+            clips: AtomicUsize::new(0),
+            held: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether the input overloaded in the last [`HOLD`].
+    pub fn is_held(&self) -> bool {
+        self.held.load(Ordering::Relaxed) > 0
+    }
+
+    /// The counts for the summary, e.g. "3 overloaded blocks, 0 clipped blocks".
+    pub fn summary(&self) -> String {
+        format!(
+            "{} overloaded blocks, {} clipped blocks",
+            self.overloads.load(Ordering::Relaxed),
+            self.clips.load(Ordering::Relaxed)
+        )
+    }
+
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+}
+
+/// The field of the status line for the inputs, and whether it's a warning: "OVL" with the
+/// inputs that overloaded in the last [`HOLD`].
+pub fn describe(inputs: &[Arc<OverloadStats>]) -> (String, bool) {
+    let held: Vec<&str> = inputs
+        .iter()
+        .filter(|x| x.is_held())
+        .map(|x| x.label)
+        .collect();
+    match held.is_empty() {
+        true => ("overload: none".to_string(), false),
+        false => (format!("OVL: {}", held.join(", ")), true),
+    }
+}
+
+/// The half-cycle being collected on one channel.
+struct HalfCycle {
+    samples: Vec<f32>,
+    /// Whether it grew longer than the longest checked, so that it won't be.
+    too_long: bool,
+}
+
+/// Checks the blocks of one input in its callback.
+pub struct OverloadDetector {
+    stats: Arc<OverloadStats>,
+    share: f32,
+    /// The channels' half-cycles, each with room for the longest checked.
+    channels: Vec<HalfCycle>,
+    longest: usize,
+    hold: usize,
+}
+
+impl OverloadDetector {
+    /// Creates the detector of an input of `channels` channels at `sample_rate`, at
+    /// `sensitivity` from 0 to 1.
+    pub fn new(
+        stats: Arc<OverloadStats>,
+        sensitivity: f32,
+        channels: usize,
+        sample_rate: u32,
+    ) -> Self {
+        let mut detector = OverloadDetector {
+            stats,
+            share: share(sensitivity),
+            channels: (0..channels)
+                .map(|_| HalfCycle {
+                    samples: Vec::new(),
+                    too_long: false,
+                })
+                .collect(),
+            longest: 0,
+            hold: 0,
+        };
+        detector.set_sample_rate(sample_rate);
+        detector
+    }
+
+    /// Checks half-cycles of the same durations at `sample_rate` from now on.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let rate = sample_rate as f64;
+        self.longest = ((MAX_HALF_CYCLE_SECONDS * rate) as usize).max(MIN_RUN);
+        self.hold = (HOLD.as_secs_f64() * rate) as usize;
+        for channel in &mut self.channels {
+            channel.samples = Vec::with_capacity(self.longest);
+            channel.too_long = false;
+        }
+    }
+
+    /// Checks the interleaved block `data`.
+    pub fn process(&mut self, data: &[f32]) {
+        let channels = self.channels.len();
+        if channels == 0 {
+            return;
+        }
+        let mut overloaded = false;
+        let mut clipped = false;
+        for frame in data.chunks_exact(channels) {
+            for (half_cycle, &x) in self.channels.iter_mut().zip(frame) {
+                clipped |= x.abs() >= CLIP_LEVEL;
+                let crossed = half_cycle
+                    .samples
+                    .first()
+                    .is_some_and(|first| (*first >= 0.0) != (x >= 0.0));
+                if crossed {
+                    overloaded |=
+                        !half_cycle.too_long && is_flat_topped(&half_cycle.samples, self.share);
+                    half_cycle.samples.clear();
+                    half_cycle.too_long = false;
+                }
+                // Never grows past the room it was made with.
+                match half_cycle.samples.len() < self.longest {
+                    true => half_cycle.samples.push(x),
+                    false => half_cycle.too_long = true,
+                }
+            }
+        }
+        let stats = &self.stats;
+        if clipped {
+            stats.clips.fetch_add(1, Ordering::Relaxed);
+        } else if overloaded {
+            stats.overloads.fetch_add(1, Ordering::Relaxed);
+        }
+        let frames = data.len() / channels;
+        let held = match overloaded && !clipped {
+            true => self.hold,
+            false => stats.held.load(Ordering::Relaxed).saturating_sub(frames),
+        };
+        stats.held.store(held, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// `frames` of a stereo sine at `frequency` of `amplitude`, with its tops cut at `ceiling`.
+    fn sine(frequency: f32, amplitude: f32, ceiling: f32, frames: usize) -> Vec<f32> {
+        let omega = std::f32::consts::TAU * frequency / SAMPLE_RATE as f32;
+        (0..frames)
+            .flat_map(|n| [(amplitude * (omega * n as f32).sin()).clamp(-ceiling, ceiling); 2])
+            .collect()
+    }
+
+    fn detector() -> (OverloadDetector, Arc<OverloadStats>) {
+        let stats = Arc::new(OverloadStats::new("input"));
+        let detector = OverloadDetector::new(stats.clone(), DEFAULT_SENSITIVITY, 2, SAMPLE_RATE);
+        (detector, stats)
+    }
+
+    #[test]
+    fn the_sensitivity_sets_the_share_of_the_flat_top() {
+        assert_eq!(share(0.0), LEAST_SENSITIVE);
+        assert_eq!(share(1.0), MOST_SENSITIVE);
+        assert_eq!(share(2.0), MOST_SENSITIVE);
+        assert!((share(0.5) - 0.33).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sines_are_never_flat_topped() {
+        for frequency in [50.0, 440.0, 1_000.0, 5_000.0] {
+            let half_cycle: Vec<f32> = sine(frequency, 0.5, 1.0, SAMPLE_RATE as usize)
+                .iter()
+                .step_by(2)
+                .skip(1)
+                .take_while(|x| **x > 0.0)
+                .copied()
+                .collect();
+            assert!(!is_flat_topped(&half_cycle, share(1.0)), "{} Hz", frequency);
+        }
+    }
+
+    #[test]
+    fn squashed_tops_are_flat_over_the_level_only() {
+        let half_cycle = |amplitude: f32, ceiling: f32| -> Vec<f32> {
+            (1..48)
+                .map(|n| (amplitude * (std::f32::consts::PI * n as f32 / 48.0).sin()).min(ceiling))
+                .collect()
+        };
+        // Two thirds of it at the ceiling.
+        assert!(is_flat_topped(&half_cycle(1.0, 0.5), share(0.0)));
+        assert!(!is_flat_topped(&half_cycle(0.4, 0.2), share(1.0)));
+        assert!(!is_flat_topped(&[], share(1.0)));
+    }
+
+    #[test]
+    fn overloads_are_counted_and_held() {
+        let (mut detector, stats) = detector();
+        let inputs = [stats.clone()];
+        detector.process(&sine(1_000.0, 0.5, 1.0, 4_800));
+        assert_eq!(describe(&inputs), ("overload: none".to_string(), false));
+
+        detector.process(&sine(1_000.0, 1.0, 0.5, 4_800));
+        assert!(stats.is_held());
+        assert_eq!(describe(&inputs), ("OVL: input".to_string(), true));
+        // The last squashed half-cycle ends with the next block, and the overload is held for a
+        // while after it.
+        let clean = sine(1_000.0, 0.5, 1.0, 4_800);
+        detector.process(&clean);
+        for _ in 0..29 {
+            detector.process(&clean);
+        }
+        assert!(stats.is_held());
+        detector.process(&clean);
+        assert!(!stats.is_held());
+        assert_eq!(stats.summary(), "2 overloaded blocks, 0 clipped blocks");
+    }
+
+    #[test]
+    fn full_scale_counts_as_a_clip_instead() {
+        let (mut detector, stats) = detector();
+        detector.process(&sine(1_000.0, 2.0, 1.0, 4_800));
+        assert!(!stats.is_held());
+        assert_eq!(stats.summary(), "0 overloaded blocks, 1 clipped blocks");
+    }
+}
@@ -0,0 +1,288 @@
+//! Parameters shared between the control thread and the audio callbacks.
+//!
+//! The control thread builds a complete snapshot of the parameters and publishes it through a
+//! [`ParamStore`], and the audio callback loads the latest snapshot once per block. Snapshots go
+//! through a triple buffer, so neither side ever waits for the other, the audio side never
+//! allocates, and a snapshot is never read while it's being written: all the fields of an update
+//! are seen together or not at all.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Set in the back index when it holds a snapshot the reader hasn't seen yet.
+const FRESH: usize = 0b100;
+const INDEX: usize = 0b011;
+
+/// A published set of parameters.
+pub struct Snapshot<T> {
+    generation: u64,
+    value: T,
+}
+
+impl<T> Snapshot<T> {
+    /// Number of snapshots published before this one, so a change shows as a new generation.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+}
+
+/// The three slots of the triple buffer: one owned by the writer, one by the reader, and the
+/// back one exchanged between them.
+struct Shared<T> {
+    slots: [UnsafeCell<Snapshot<T>>; 3],
+    back: AtomicUsize,
+}
+
+// Safety: each slot is only accessed by the side that currently owns its index, and ownership
+// changes hands through the atomic swaps of `back`, which order the accesses.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// A triple buffer of parameter snapshots, to be split into its two ends.
+pub struct ParamStore<T> {
+    shared: Arc<Shared<T>>,
+    initial: T,
+}
+
+impl<T: Clone + Send> ParamStore<T> {
+    /// Creates a store whose snapshots all start as `initial`, at generation 0.
+    pub fn new(initial: T) -> Self {
+        let slot = || {
+            UnsafeCell::new(Snapshot {
+                generation: 0,
+                value: initial.clone(),
+            })
+        };
+        ParamStore {
+            shared: Arc::new(Shared {
+                slots: [slot(), slot(), slot()],
+                back: AtomicUsize::new(1),
+            }),
+            initial,
+        }
+    }
+
+    /// Splits the store into the control thread's end and the audio callback's end.
+    pub fn split(self) -> (ParamWriter<T>, ParamReader<T>) {
+        let writer = ParamWriter {
+            shared: self.shared.clone(),
+            index: 2,
+            current: self.initial,
+            generation: 0,
+        };
+        let reader = ParamReader {
+            shared: self.shared,
+            index: 0,
+        };
+        (writer, reader)
+    }
+}
+
+/// The control thread's end of a [`ParamStore`].
+pub struct ParamWriter<T> {
+    shared: Arc<Shared<T>>,
+    index: usize,
+    current: T,
+    generation: u64,
+}
+
+impl<T: Clone + Send> ParamWriter<T> {
+    /// The last published value.
+    pub fn get(&self) -> &T {
+        &self.current
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Publishes a complete new snapshot, replacing any the reader hasn't loaded yet.
+    pub fn publish(&mut self, value: T) {
+        self.generation += 1;
+        // Safety: the writer owns the slot at `index` until it swaps it into the back.
+        let slot = unsafe { &mut *self.shared.slots[self.index].get() };
+        slot.generation = self.generation;
+        slot.value.clone_from(&value);
+        let back = self.shared.back.swap(self.index | FRESH, Ordering::AcqRel);
+        self.index = back & INDEX;
+        self.current = value;
+    }
+
+    /// Publishes the last published value as changed by `change`.
+    pub fn update(&mut self, change: impl FnOnce(&mut T)) {
+        let mut value = self.current.clone();
+        change(&mut value);
+        self.publish(value);
+    }
+}
+
+/// The audio callback's end of a [`ParamStore`].
+pub struct ParamReader<T> {
+    shared: Arc<Shared<T>>,
+    index: usize,
+}
+
+impl<T: Send> ParamReader<T> {
+    /// Returns the latest published snapshot, without locking or allocating.
+    pub fn load(&mut self) -> &Snapshot<T> {
+        if self.shared.back.load(Ordering::Relaxed) & FRESH != 0 {
+            let back = self.shared.back.swap(self.index, Ordering::AcqRel);
+            self.index = back & INDEX;
+        }
+        // Safety: the reader owns the slot at `index` until it swaps it into the back.
+        unsafe { &*self.shared.slots[self.index].get() }
+    }
+}
+
+/// Names of the parameters of an effect chain, in the order of their values in a snapshot.
+///
+/// Parameters are named `<effect>.<n>.<parameter>`, where `n` counts the effects of the same
+/// kind from 0, e.g. `filter.1.freq` for the frequency of the second filter. The parameter can be
+/// left out for the effect's first one, and the index for the first effect of a kind, so `gain`
+/// is `gain.0.db`.
+#[derive(Clone, Debug, Default)]
+pub struct ParamLayout {
+    names: Vec<String>,
+    /// The effect kind and instance of each parameter, with its position in the effect.
+    owners: Vec<(String, usize, usize)>,
+}
+
+impl ParamLayout {
+    /// Adds the parameters of the `instance`th effect of a kind.
+    pub fn add(&mut self, effect: &str, instance: usize, params: &[&str]) {
+        for (position, param) in params.iter().enumerate() {
+            self.names
+                .push(format!("{}.{}.{}", effect, instance, param));
+            self.owners.push((effect.to_string(), instance, position));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Finds the position of the parameter named `path`, in full or shortened form.
+    pub fn find(&self, path: &str) -> Option<usize> {
+        let mut parts = path.split('.');
+        let effect = parts.next()?;
+        let (instance, param) = match (parts.next(), parts.next(), parts.next()) {
+            (None, _, _) => (0, None),
+            (Some(part), None, _) => match part.parse() {
+                Ok(instance) => (instance, None),
+                Err(_) => (0, Some(part)),
+            },
+            (Some(instance), Some(param), None) => (instance.parse().ok()?, Some(param)),
+            _ => return None,
+        };
+        let index = match param {
+            Some(param) => {
+                let name = format!("{}.{}.{}", effect, instance, param);
+                self.names.iter().position(|x| *x == name)?
+            }
+            None => self.owners.iter().position(|(kind, n, position)| {
+                kind == effect && *n == instance && *position == 0
+            })?,
+        };
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::effects::{EffectChain, Gain};
+
+    #[test]
+    fn the_reader_sees_the_latest_snapshot() {
+        let (mut writer, mut reader) = ParamStore::new(0).split();
+        assert_eq!((reader.load().generation(), *reader.load().value()), (0, 0));
+        writer.publish(1);
+        assert_eq!((reader.load().generation(), *reader.load().value()), (1, 1));
+        // Snapshots published between two loads are replaced by the last one.
+        writer.publish(2);
+        writer.update(|x| *x += 1);
+        assert_eq!((writer.generation(), *writer.get()), (3, 3));
+        assert_eq!((reader.load().generation(), *reader.load().value()), (3, 3));
+        assert_eq!(reader.load().generation(), 3);
+    }
+
+    #[test]
+    fn snapshots_are_never_seen_half_written() {
+        let (mut writer, mut reader) = ParamStore::new(vec![0u64; 64]).split();
+        let writing = std::thread::spawn(move || {
+            for generation in 1..=20_000 {
+                writer.publish(vec![generation; 64]);
+            }
+        });
+        let mut last = 0;
+        while last < 20_000 {
+            let snapshot = reader.load();
+            let value = snapshot.value();
+            assert!(value.iter().all(|x| *x == value[0]), "torn snapshot");
+            assert_eq!(value[0], snapshot.generation());
+            assert!(snapshot.generation() >= last);
+            last = snapshot.generation();
+        }
+        writing.join().unwrap();
+    }
+
+    fn layout() -> ParamLayout {
+        let mut layout = ParamLayout::default();
+        layout.add("filter", 0, &["freq", "q"]);
+        layout.add("gain", 0, &["db"]);
+        layout.add("filter", 1, &["freq", "q"]);
+        layout
+    }
+
+    #[test]
+    fn parameters_are_found_in_full_or_shortened_form() {
+        let layout = layout();
+        assert_eq!(layout.names()[3], "filter.1.freq");
+        assert_eq!(layout.find("filter.1.q"), Some(4));
+        assert_eq!(layout.find("filter.1"), Some(3));
+        assert_eq!(layout.find("filter.q"), Some(1));
+        assert_eq!(layout.find("filter"), Some(0));
+        assert_eq!(layout.find("gain"), Some(2));
+    }
+
+    #[test]
+    fn unknown_parameters_are_not_found() {
+        let layout = layout();
+        assert_eq!(layout.find("filter.2"), None);
+        assert_eq!(layout.find("filter.0.gain"), None);
+        assert_eq!(layout.find("filter.x.q"), None);
+        assert_eq!(layout.find("gain.0.db.extra"), None);
+        assert_eq!(layout.find("delay"), None);
+    }
+
+    #[test]
+    fn published_values_reach_the_effects_gliding_over_a_block() {
+        let mut chain: EffectChain = EffectChain::new(1);
+        chain.push(Gain::new(0.0));
+        let (mut writer, reader) = ParamStore::new(chain.param_values()).split();
+        chain.connect(reader);
+        writer.publish(vec![-6.0206]);
+        let mut data = vec![1.0; 4];
+        chain.process(&mut data);
+        // The gain ramps from unity to a half over the block, then holds.
+        for (sample, expected) in data.iter().zip([0.875, 0.75, 0.625, 0.5]) {
+            assert!((sample - expected).abs() < 1e-4, "{:?}", data);
+        }
+        let mut data = vec![1.0; 4];
+        chain.process(&mut data);
+        assert!(data.iter().all(|x| (x - 0.5).abs() < 1e-4), "{:?}", data);
+    }
+}
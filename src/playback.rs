@@ -0,0 +1,537 @@
+//! Playback of an audio file into the monitor feed, e.g. a backing track.
+//!
+//! The file is loaded and converted to the configuration of each output up front, so the output
+//! callbacks only copy samples. An output that changes its rate converts it again, on its first
+//! block at the new one. It plays once from the start of the streams, optionally
+//! time-stretched to another speed. The stretching comes after the conversion, at the rate of the
+//! output, so the speed and the pitch stay those of the file whatever the rates.
+//!
+//! It can play a [`Region`] of the file instead, once or in a loop, e.g. to practice a solo over
+//! a few bars. Each lap is exactly as long as the region, the last [`LoopRegion::crossfade`] of
+//! it fading into what comes just before its start, so that the seam continues into the start
+//! as the file does. The region can move during the run, and since the whole file is in memory,
+//! jumping to where it starts never waits on anything.
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use cpal::{BufferSize, SampleRate, StreamConfig};
+
+use crate::adapter::Converter;
+use crate::ducker::Ducker;
+use crate::sidechain::Key;
+use crate::stats::AtomicF32;
+use crate::stretch::Stretcher;
+use crate::wav;
+
+/// How far a nudge moves a boundary of the region.
+pub const NUDGE: Duration = Duration::from_millis(100);
+/// Length of the crossfade at the seam of a loop when none is given, in milliseconds.
+pub const DEFAULT_CROSSFADE_MS: f32 = 30.0;
+/// Shortest region nudges leave, in seconds.
+const MIN_REGION: f64 = 0.1;
+
+/// A time range of the file, `<start-s>:<end-s>` on the command line, e.g. "34.5:42.0".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid region \"{}\", expected <start-s>:<end-s>", s);
+        let (start, end) = s.split_once(':').ok_or_else(invalid)?;
+        let start = start.parse::<f64>().map_err(|_| invalid())?;
+        let end = end.parse::<f64>().map_err(|_| invalid())?;
+        if !(0.0..end).contains(&start) || !end.is_finite() {
+            return Err(format!(
+                "invalid region \"{}\", expected a start from 0 and before the end",
+                s
+            ));
+        }
+        Ok(Region { start, end })
+    }
+}
+
+impl Region {
+    /// The region within a file of `seconds`, or `None` if none of it is.
+    pub fn within(self, seconds: f64) -> Option<Region> {
+        let end = self.end.min(seconds);
+        (self.start < end).then_some(Region {
+            start: self.start,
+            end,
+        })
+    }
+
+    pub fn seconds(&self) -> f64 {
+        self.end - self.start
+    }
+}
+
+/// A boundary of the region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boundary {
+    Start,
+    End,
+}
+
+/// The region the file plays, shared between the controls and the outputs.
+pub struct LoopRegion {
+    /// The boundaries, in seconds, as the bits of f64s.
+    start: AtomicU64,
+    end: AtomicU64,
+    /// Length of the file.
+    seconds: f64,
+    looping: bool,
+    crossfade: Duration,
+}
+
+impl LoopRegion {
+    /// Shares `region` of a file of `seconds`, within which it must be, looping it with a
+    /// `crossfade` at the seam if `looping`, or else playing it once.
+    pub fn new(region: Region, seconds: f64, looping: bool, crossfade: Duration) -> Self {
+        LoopRegion {
+            start: AtomicU64::new(region.start.to_bits()),
+            end: AtomicU64::new(region.end.to_bits()),
+            seconds,
+            looping,
+            crossfade,
+        }
+    }
+
+    pub fn get(&self) -> Region {
+        Region {
+            start: f64::from_bits(self.start.load(Ordering::Relaxed)),
+            end: f64::from_bits(self.end.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Moves `boundary` by `seconds`, earlier if negative, as far as the file and the other
+    /// boundary let it, returning the region.
+    pub fn nudge(&self, boundary: Boundary, seconds: f64) -> Region {
+        let mut region = self.get();
+        match boundary {
+            Boundary::Start => {
+                region.start = (region.start + seconds).clamp(0.0, region.end - MIN_REGION);
+                self.start.store(region.start.to_bits(), Ordering::Relaxed);
+            }
+            Boundary::End => {
+                region.end = (region.end + seconds).clamp(region.start + MIN_REGION, self.seconds);
+                self.end.store(region.end.to_bits(), Ordering::Relaxed);
+            }
+        }
+        region
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.looping
+    }
+
+    pub fn crossfade(&self) -> Duration {
+        self.crossfade
+    }
+}
+
+/// A region for people, e.g. "34.500 to 42.000 s".
+pub fn describe(region: Region) -> String {
+    format!("{:.3} to {:.3} s", region.start, region.end)
+}
+
+/// A loaded file, in its own configuration.
+pub struct Track {
+    config: StreamConfig,
+    samples: Arc<[f32]>,
+}
+
+impl Track {
+    /// Loads a WAV file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data =
+            wav::read(path).with_context(|| format!("failed to read \"{}\"", path.display()))?;
+        Ok(Track {
+            config: StreamConfig {
+                channels: data.channels,
+                sample_rate: SampleRate(data.sample_rate),
+                buffer_size: BufferSize::Default,
+            },
+            samples: data.samples.into(),
+        })
+    }
+
+    pub fn config(&self) -> &StreamConfig {
+        &self.config
+    }
+
+    pub fn seconds(&self) -> f64 {
+        let frames = self.samples.len() / self.config.channels as usize;
+        frames as f64 / self.config.sample_rate.0 as f64
+    }
+
+    /// Creates the playback of the track for an output stream with configuration `output`, at
+    /// the speed in `speed` if given, or as it is, of the whole track or of `region` if given.
+    pub fn playback(
+        &self,
+        output: &StreamConfig,
+        speed: Option<Arc<AtomicF32>>,
+        region: Option<Arc<LoopRegion>>,
+    ) -> Playback {
+        let mut converter = Converter::new(&self.config, output);
+        let channels = output.channels as usize;
+        let sample_rate = output.sample_rate.0 as f64;
+        let position = region.as_ref().map_or(0, |x| {
+            (x.get().start * sample_rate).round() as usize * channels
+        });
+        Playback {
+            file: self.samples.clone(),
+            file_config: self.config.clone(),
+            output: output.clone(),
+            samples: converter.process(&self.samples).to_vec(),
+            position,
+            scratch: Vec::new(),
+            stretcher: speed.map(|x| Stretcher::new(x, channels, output.sample_rate.0)),
+            region,
+            channels,
+            sample_rate,
+            finished: false,
+        }
+    }
+}
+
+/// The samples of a track in the configuration of one output, and how far it has played.
+pub struct Playback {
+    /// The file as loaded, which the samples are converted from, for the output.
+    file: Arc<[f32]>,
+    file_config: StreamConfig,
+    output: StreamConfig,
+    samples: Vec<f32>,
+    position: usize,
+    scratch: Vec<f32>,
+    stretcher: Option<Stretcher>,
+    region: Option<Arc<LoopRegion>>,
+    channels: usize,
+    sample_rate: f64,
+    /// Whether a region played once has ended.
+    finished: bool,
+}
+
+impl Playback {
+    /// Converts the file again for the output at `sample_rate`, carrying on from the same time.
+    /// Takes as long as converting the whole file does, and allocates.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let ratio = sample_rate as f64 / self.sample_rate;
+        self.output.sample_rate = SampleRate(sample_rate);
+        self.samples = Converter::new(&self.file_config, &self.output)
+            .process(&self.file)
+            .to_vec();
+        let frame = ((self.position / self.channels) as f64 * ratio).round() as usize;
+        self.position = (frame * self.channels).min(self.samples.len());
+        self.sample_rate = sample_rate as f64;
+        if let Some(stretcher) = &mut self.stretcher {
+            stretcher.set_sample_rate(sample_rate);
+        }
+    }
+
+    /// Allocates what blocks of up to `samples` need, so that the first ones don't. The track only
+    /// plays into the first `samples` of a larger block.
+    pub fn prepare(&mut self, samples: usize) {
+        if self.scratch.len() < samples {
+            self.scratch.resize(samples, 0.0);
+        }
+    }
+
+    /// Adds the next block of the track to `data` and clamps the sum to [-1, 1].
+    ///
+    /// With a `ducker`, the track is ducked under the signal already in `data`, or under
+    /// `sidechain` if given.
+    pub fn mix_into(
+        &mut self,
+        data: &mut [f32],
+        ducker: Option<&mut Ducker>,
+        sidechain: Option<&[f32]>,
+    ) {
+        let wanted = data.len().min(self.scratch.len());
+        let length = match (&mut self.stretcher, &self.region) {
+            (Some(stretcher), _) => stretcher.read(&self.samples, &mut self.scratch[..wanted]),
+            (None, Some(_)) => self.read_region(wanted),
+            (None, None) => {
+                let length = wanted.min(self.samples.len() - self.position);
+                self.scratch[..length]
+                    .copy_from_slice(&self.samples[self.position..self.position + length]);
+                self.position += length;
+                length
+            }
+        };
+        if length == 0 {
+            return;
+        }
+        let block = &mut self.scratch[..length];
+        if let Some(ducker) = ducker {
+            let key = match sidechain {
+                Some(key) => Key::Mono(key),
+                None => Key::Frames(&data[..length]),
+            };
+            ducker.process(key, block);
+        }
+        for (x, &track) in data.iter_mut().zip(block.iter()) {
+            *x = (*x + track).clamp(-1.0, 1.0);
+        }
+    }
+
+    /// Fills the first `length` samples of the scratch with the next ones of the region,
+    /// returning how many it wrote, fewer only at the end of a region played once.
+    fn read_region(&mut self, length: usize) -> usize {
+        let Some(region) = &self.region else {
+            return 0;
+        };
+        let channels = self.channels;
+        let frames = self.samples.len() / channels;
+        let bounds = region.get();
+        let to_frame = |seconds: f64| ((seconds * self.sample_rate).round() as usize).min(frames);
+        let (start, end) = (to_frame(bounds.start), to_frame(bounds.end));
+        let looping = region.is_looping() && start < end;
+        let crossfade = (region.crossfade().as_secs_f64() * self.sample_rate) as usize;
+        let crossfade = crossfade.min(end - start);
+        let mut position = self.position / channels;
+        let mut written = 0;
+        for frame in self.scratch[..length].chunks_exact_mut(channels) {
+            if position >= end {
+                if !looping {
+                    self.finished = true;
+                    break;
+                }
+                position = start;
+            }
+            let at = |n: usize, channel: usize| self.samples[n * channels + channel];
+            match (position + crossfade).checked_sub(end) {
+                // The last frames of the lap fade into those leading up to the start.
+                Some(n) if looping => {
+                    let fade = (n as f32 + 0.5) / crossfade as f32;
+                    let into = 0.5 - 0.5 * (std::f32::consts::PI * fade).cos();
+                    let lead = (start + n).checked_sub(crossfade);
+                    for (channel, x) in frame.iter_mut().enumerate() {
+                        let lead = lead.map_or(0.0, |m| at(m, channel));
+                        *x = at(position, channel) * (1.0 - into) + lead * into;
+                    }
+                }
+                _ => {
+                    for (channel, x) in frame.iter_mut().enumerate() {
+                        *x = at(position, channel);
+                    }
+                }
+            }
+            position += 1;
+            written += channels;
+        }
+        self.position = position * channels;
+        written
+    }
+
+    /// Whether the whole track, or the region played once, has been played.
+    pub fn is_finished(&self) -> bool {
+        match (&self.stretcher, &self.region) {
+            (Some(stretcher), _) => stretcher.is_finished(),
+            (None, Some(_)) => self.finished,
+            (None, None) => self.position == self.samples.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(sample_rate: u32) -> StreamConfig {
+        StreamConfig {
+            channels: 1,
+            sample_rate: SampleRate(sample_rate),
+            buffer_size: BufferSize::Default,
+        }
+    }
+
+    /// A second of a ramp from 0 to 0.5, at 48 kHz.
+    fn ramp() -> Track {
+        Track {
+            config: config(48_000),
+            samples: (0..48_000).map(|n| n as f32 / 96_000.0).collect(),
+        }
+    }
+
+    #[test]
+    fn a_change_of_rate_carries_on_from_the_same_time() {
+        let track = ramp();
+        let mut playback = track.playback(&config(48_000), None, None);
+        playback.prepare(48_000);
+        playback.mix_into(&mut vec![0.0; 24_000], None, None);
+        playback.set_sample_rate(96_000);
+        assert_eq!(playback.samples.len(), 95_998);
+        let mut data = vec![0.0; 4];
+        playback.mix_into(&mut data, None, None);
+        assert!((data[0] - 0.25).abs() < 1e-4, "{}", data[0]);
+        assert!((data[2] - data[0] - 1.0 / 96_000.0).abs() < 1e-6);
+        playback.mix_into(&mut vec![0.0; 48_000], None, None);
+        assert!(playback.is_finished());
+    }
+
+    #[test]
+    fn a_stretched_playback_carries_on_from_the_same_time() {
+        let track = ramp();
+        let speed = Arc::new(AtomicF32::new(1.0));
+        let mut playback = track.playback(&config(48_000), Some(speed), None);
+        playback.prepare(48_000);
+        playback.mix_into(&mut vec![0.0; 24_000], None, None);
+        playback.set_sample_rate(96_000);
+        playback.mix_into(&mut vec![0.0; 48_000], None, None);
+        assert!(!playback.is_finished());
+        playback.mix_into(&mut vec![0.0; 4_000], None, None);
+        assert!(playback.is_finished());
+    }
+
+    #[test]
+    fn the_track_is_added_to_the_block_and_clamped() {
+        let track = ramp();
+        let mut playback = track.playback(&config(48_000), None, None);
+        playback.prepare(48_000);
+        let mut data = vec![0.75; 48_000];
+        playback.mix_into(&mut data, None, None);
+        assert_eq!(data[0], 0.75);
+        assert!((data[24_000] - 1.0).abs() < 1e-6);
+        assert_eq!(data[47_999], 1.0);
+        assert!(playback.is_finished());
+        // Past the end the live signal goes through alone.
+        let mut data = vec![0.5; 4];
+        playback.mix_into(&mut data, None, None);
+        assert_eq!(data, [0.5; 4]);
+    }
+
+    #[test]
+    fn the_track_ducks_under_the_live_signal() {
+        let track = Track {
+            config: config(48_000),
+            samples: vec![0.5; 4_800].into(),
+        };
+        let settings = "-30:-12:0:100".parse().unwrap();
+        let mut ducker = Ducker::new(settings, 1, 48_000);
+        let mut playback = track.playback(&config(48_000), None, None);
+        playback.prepare(48_000);
+        let mut silence = vec![0.0; 2_400];
+        playback.mix_into(&mut silence, Some(&mut ducker), None);
+        assert!(silence.iter().all(|x| *x == 0.5));
+        let mut live = vec![0.25; 2_400];
+        playback.mix_into(&mut live, Some(&mut ducker), None);
+        let ducked = 0.25 + 0.5 * crate::level::db_to_gain(-12.0);
+        assert!((live[2_399] - ducked).abs() < 1e-4, "{}", live[2_399]);
+    }
+
+    #[test]
+    fn the_track_only_plays_into_the_prepared_part_of_a_larger_block() {
+        let track = ramp();
+        let mut playback = track.playback(&config(48_000), None, None);
+        playback.prepare(4);
+        let mut data = vec![0.0; 8];
+        playback.mix_into(&mut data, None, None);
+        assert!(data[1..4].iter().all(|x| *x > 0.0));
+        assert_eq!(data[4..], [0.0; 4]);
+        // What didn't fit plays in the next block.
+        let mut data = vec![0.0; 4];
+        playback.mix_into(&mut data, None, None);
+        assert_eq!(data[0], 4.0 / 96_000.0);
+    }
+
+    /// The region from 0.5 to 0.6 s of the ramp, looped with a crossfade of 10 ms if `looping`.
+    fn region(looping: bool) -> Arc<LoopRegion> {
+        let region = Region {
+            start: 0.5,
+            end: 0.6,
+        };
+        let crossfade = Duration::from_millis(10);
+        Arc::new(LoopRegion::new(region, 1.0, looping, crossfade))
+    }
+
+    #[test]
+    fn regions_parse_and_fit_the_file() {
+        let region = |start, end| Region { start, end };
+        assert_eq!("34.5:42".parse(), Ok(region(34.5, 42.0)));
+        assert_eq!(
+            "42".parse::<Region>(),
+            Err("invalid region \"42\", expected <start-s>:<end-s>".to_string())
+        );
+        for s in ["5:2", "-1:2", "1:inf"] {
+            assert_eq!(
+                s.parse::<Region>(),
+                Err(format!(
+                    "invalid region \"{}\", expected a start from 0 and before the end",
+                    s
+                ))
+            );
+        }
+        assert_eq!(region(1.0, 5.0).within(3.0), Some(region(1.0, 3.0)));
+        assert_eq!(region(1.0, 5.0).within(0.5), None);
+        assert_eq!(region(1.0, 3.0).seconds(), 2.0);
+    }
+
+    #[test]
+    fn nudges_keep_the_region_within_the_file() {
+        let region = Region {
+            start: 1.0,
+            end: 2.0,
+        };
+        let shared = LoopRegion::new(region, 3.0, true, Duration::ZERO);
+        assert_eq!(
+            describe(shared.nudge(Boundary::End, 5.0)),
+            "1.000 to 3.000 s"
+        );
+        assert_eq!(
+            describe(shared.nudge(Boundary::Start, -5.0)),
+            "0.000 to 3.000 s"
+        );
+        assert_eq!(
+            describe(shared.nudge(Boundary::Start, 10.0)),
+            "2.900 to 3.000 s"
+        );
+        assert_eq!(
+            describe(shared.nudge(Boundary::End, -10.0)),
+            "2.900 to 3.000 s"
+        );
+        assert_eq!(describe(shared.get()), "2.900 to 3.000 s");
+    }
+
+    #[test]
+    fn a_region_played_once_ends_with_it() {
+        let track = ramp();
+        let mut playback = track.playback(&config(48_000), None, Some(region(false)));
+        playback.prepare(48_000);
+        let mut data = vec![0.0; 9_600];
+        playback.mix_into(&mut data, None, None);
+        assert!((data[0] - 0.25).abs() < 1e-6);
+        assert!((data[4_799] - 28_799.0 / 96_000.0).abs() < 1e-6);
+        assert!(data[4_800..].iter().all(|x| *x == 0.0));
+        assert!(playback.is_finished());
+    }
+
+    #[test]
+    fn loops_are_as_long_as_the_region_and_fade_into_its_start() {
+        let track = ramp();
+        let mut playback = track.playback(&config(48_000), None, Some(region(true)));
+        playback.prepare(48_000);
+        let mut data = vec![0.0; 3 * 4_800];
+        playback.mix_into(&mut data, None, None);
+        for lap in 0..3 {
+            assert!((data[lap * 4_800] - 0.25).abs() < 1e-6);
+        }
+        // Until the crossfade the lap is the file as it is.
+        assert!((data[4_319] - 28_319.0 / 96_000.0).abs() < 1e-6);
+        // The seam is no larger a step than those of the crossfade.
+        let steps: Vec<f32> = data.windows(2).map(|x| (x[1] - x[0]).abs()).collect();
+        let largest = steps[4_320..4_800].iter().copied().fold(0.0, f32::max);
+        assert!(largest < 2e-4, "{}", largest);
+        assert!(steps[4_799] <= largest, "{}", steps[4_799]);
+        assert!(!playback.is_finished());
+    }
+}
@@ -0,0 +1,174 @@
+//! Minimal PNG writing, for plots.
+//!
+//! Images are 8-bit RGB, stored in uncompressed deflate blocks: the files are larger than they
+//! could be, but plots are small, and any viewer reads them.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Largest stored deflate block.
+const STORED_BLOCK: usize = 65_535;
+
+/// An RGB image, row by row from the top.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    /// An image filled with `background`.
+    pub fn new(width: usize, height: usize, background: [u8; 3]) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![background; width * height],
+        }
+    }
+
+    /// Sets a pixel, unless it's outside the image.
+    pub fn set(&mut self, x: i64, y: i64, color: [u8; 3]) {
+        if (0..self.width as i64).contains(&x) && (0..self.height as i64).contains(&y) {
+            self.pixels[y as usize * self.width + x as usize] = color;
+        }
+    }
+
+    /// Draws a line between two points, one pixel wide.
+    pub fn line(&mut self, from: (i64, i64), to: (i64, i64), color: [u8; 3]) {
+        let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).max(1);
+        for step in 0..=steps {
+            let t = step as f64 / steps as f64;
+            let x = from.0 as f64 + (to.0 - from.0) as f64 * t;
+            let y = from.1 as f64 + (to.1 - from.1) as f64 * t;
+            self.set(x.round() as i64, y.round() as i64, color);
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, deflate, adaptive filtering, no interlacing.
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut out, b"IHDR", &header)?;
+
+        // Every row starts with its filter, none.
+        let mut raw = Vec::with_capacity(self.height * (self.width * 3 + 1));
+        for row in self.pixels.chunks(self.width.max(1)) {
+            raw.push(0);
+            raw.extend(row.iter().flatten());
+        }
+        write_chunk(&mut out, b"IDAT", &zlib_stored(&raw))?;
+        write_chunk(&mut out, b"IEND", &[])?;
+        out.flush()
+    }
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data));
+    out.write_all(&crc.to_be_bytes())
+}
+
+/// A zlib stream holding `data` in stored blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32 KB window, and the check bits making the header a multiple of 31.
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(last as u8);
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in bytes {
+        a = (a + *byte as u32) % 65_521;
+        b = (b + a) % 65_521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_the_reference_values() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn data_is_stored_in_blocks_of_at_most_64_kib() {
+        assert_eq!(
+            zlib_stored(&[]),
+            [0x78, 0x01, 1, 0, 0, 0xff, 0xff, 0, 0, 0, 1]
+        );
+        let data = vec![7; STORED_BLOCK + 10];
+        let stream = zlib_stored(&data);
+        assert_eq!(stream.len(), 2 + 5 + STORED_BLOCK + 5 + 10 + 4);
+        assert_eq!(stream[2..7], [0, 0xff, 0xff, 0, 0]);
+        let second = 7 + STORED_BLOCK;
+        assert_eq!(stream[second..second + 5], [1, 10, 0, 0xf5, 0xff]);
+    }
+
+    #[test]
+    fn lines_are_drawn_within_the_image() {
+        let mut image = Image::new(4, 3, [0; 3]);
+        image.line((-2, 1), (6, 1), [9; 3]);
+        image.set(10, 10, [5; 3]);
+        let row: Vec<[u8; 3]> = image.pixels[4..8].to_vec();
+        assert_eq!(row, [[9; 3]; 4]);
+        assert!(image.pixels[..4]
+            .iter()
+            .chain(&image.pixels[8..])
+            .all(|x| *x == [0; 3]));
+    }
+
+    #[test]
+    fn images_are_written_as_png_chunks() {
+        let path = std::env::temp_dir().join(format!("image-{}.png", std::process::id()));
+        Image::new(3, 2, [255, 0, 0]).write(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes[..8], *b"\x89PNG\r\n\x1a\n");
+        assert_eq!(bytes[8..16], [0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(bytes[16..29], [0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        // Two rows of a filter byte and three pixels, in one stored block.
+        let length = 2 + 5 + 2 * (1 + 3 * 3) + 4;
+        let idat = 8 + 4 + 4 + 13 + 4;
+        assert_eq!(
+            bytes[idat..idat + 8],
+            [0, 0, 0, length, b'I', b'D', b'A', b'T']
+        );
+        assert!(bytes.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+    }
+}
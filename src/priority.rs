@@ -0,0 +1,282 @@
+//! Promotion of the audio callback threads to real-time priority.
+//!
+//! cpal owns the threads running the stream callbacks, so each callback promotes its own thread
+//! the first time it runs there, and reports the outcome through a [`Report`] that the main
+//! thread prints once the streams are running. Failing to promote a thread is never an error:
+//! the monitor keeps running at normal priority, only more prone to xruns.
+//!
+//! The outcome belongs to the thread, which is only ever promoted once, and a stream's report
+//! holds the one of the thread it last called back on: a stream whose callbacks move to another
+//! thread reports that thread's, and streams sharing a thread all report its outcome. Each
+//! outcome lives for the rest of the process, so a report only swaps a pointer to it, without
+//! locking or allocating in the callback.
+
+use std::cell::OnceCell;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// Outcome of promoting the thread a stream calls back on, shared with the main thread.
+#[derive(Default)]
+pub struct Report {
+    /// The outcome of the thread the stream last called back on, null before its first callback.
+    promotion: AtomicPtr<Promotion>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The outcome for the thread the stream last called back on, once it did.
+    pub fn get(&self) -> Option<&'static Promotion> {
+        // Safety: the pointer is either null or one of the outcomes leaked by `promote_with`,
+        // which are never freed.
+        unsafe { self.promotion.load(Ordering::Acquire).as_ref() }
+    }
+}
+
+/// SCHED_FIFO priority requested on Unix, below the kernel's own threads but above most
+/// applications. JACK clients already run at the server's priority and are left alone.
+#[cfg(unix)]
+const REQUESTED_PRIORITY: i32 = 80;
+
+/// What happened when promoting a thread.
+#[derive(Debug)]
+pub enum Promotion {
+    /// The thread now runs at real-time priority, e.g. "SCHED_FIFO priority 80".
+    RealTime(String),
+    /// The thread already ran at real-time priority, e.g. under JACK, and was left as it was.
+    AlreadyRealTime(String),
+    /// The OS refused, for this reason.
+    Denied(String),
+    /// The target has no supported way to promote threads.
+    Unsupported,
+}
+
+impl fmt::Display for Promotion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Promotion::RealTime(priority) => write!(f, "{}", priority),
+            Promotion::AlreadyRealTime(priority) => write!(f, "{} (set by the host)", priority),
+            Promotion::Denied(reason) => write!(f, "normal priority: {}", reason),
+            Promotion::Unsupported => write!(f, "normal priority: not supported on this platform"),
+        }
+    }
+}
+
+thread_local! {
+    /// The outcome of promoting the thread, once it was, leaked so that reports can point to it.
+    static PROMOTION: OnceCell<&'static Promotion> = const { OnceCell::new() };
+}
+
+/// Promotes the calling thread if it hasn't been already, recording its outcome in `report` if
+/// the stream last called back on another thread.
+///
+/// Only the first call on each thread does any work, promoting it and allocating its outcome, so
+/// this is cheap enough to call at the start of every callback.
+pub fn promote_thread(report: &Report) {
+    promote_with(report, promote_current_thread);
+}
+
+/// [`promote_thread`], promoting the thread with `promote`.
+fn promote_with(report: &Report, promote: impl FnOnce() -> Promotion) {
+    let promotion = PROMOTION.with(|x| *x.get_or_init(|| Box::leak(Box::new(promote()))));
+    let promotion = promotion as *const Promotion as *mut Promotion;
+    if report.promotion.load(Ordering::Relaxed) != promotion {
+        report.promotion.store(promotion, Ordering::Release);
+    }
+}
+
+/// Raises the calling thread to real-time priority where the OS allows it.
+#[cfg(unix)]
+pub fn promote_current_thread() -> Promotion {
+    // Safety: these calls only read and change the scheduling of the calling thread, and the
+    // out-parameters are valid for writes.
+    unsafe {
+        let thread = libc::pthread_self();
+        let mut policy = 0;
+        let mut param: libc::sched_param = std::mem::zeroed();
+        if libc::pthread_getschedparam(thread, &mut policy, &mut param) == 0
+            && (policy == libc::SCHED_FIFO || policy == libc::SCHED_RR)
+        {
+            return Promotion::AlreadyRealTime(describe(policy, param.sched_priority));
+        }
+
+        let max = libc::sched_get_priority_max(libc::SCHED_FIFO);
+        negotiate(REQUESTED_PRIORITY.min(max), rtprio_limit(), |priority| {
+            let param = libc::sched_param {
+                sched_priority: priority,
+            };
+            match libc::pthread_setschedparam(thread, libc::SCHED_FIFO, &param) {
+                0 => Ok(()),
+                code => Err(io::Error::from_raw_os_error(code)),
+            }
+        })
+    }
+}
+
+/// Joins the thread to the "Pro Audio" MMCSS task, which raises it to real-time priority.
+#[cfg(windows)]
+pub fn promote_current_thread() -> Promotion {
+    #[link(name = "avrt")]
+    extern "system" {
+        fn AvSetMmThreadCharacteristicsW(task: *const u16, index: *mut u32) -> isize;
+    }
+
+    let task: Vec<u16> = "Pro Audio".encode_utf16().chain([0]).collect();
+    let mut index = 0;
+    // Safety: `task` is a null-terminated UTF-16 string and `index` is valid for writes. The
+    // handle is never reverted, the thread keeps the task until it exits.
+    let handle = unsafe { AvSetMmThreadCharacteristicsW(task.as_ptr(), &mut index) };
+    if handle == 0 {
+        Promotion::Denied(io::Error::last_os_error().to_string())
+    } else {
+        Promotion::RealTime("MMCSS \"Pro Audio\" task".to_string())
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn promote_current_thread() -> Promotion {
+    Promotion::Unsupported
+}
+
+/// Sets the SCHED_FIFO `requested` priority through `set`, falling back to the highest priority
+/// the rtprio `limit` allows when the OS refuses.
+///
+/// `limit` is the RLIMIT_RTPRIO of the process if known, which caps the priority unprivileged
+/// processes may ask for.
+#[cfg(unix)]
+pub fn negotiate(
+    requested: i32,
+    limit: Option<i32>,
+    mut set: impl FnMut(i32) -> io::Result<()>,
+) -> Promotion {
+    let fifo = |priority| describe(libc::SCHED_FIFO, priority);
+    match set(requested) {
+        Ok(()) => return Promotion::RealTime(fifo(requested)),
+        Err(err) if err.kind() != io::ErrorKind::PermissionDenied => {
+            return Promotion::Denied(err.to_string())
+        }
+        Err(_) => {}
+    }
+    match limit {
+        Some(limit) if (1..requested).contains(&limit) => match set(limit) {
+            Ok(()) => Promotion::RealTime(fifo(limit)),
+            Err(err) => Promotion::Denied(err.to_string()),
+        },
+        _ => Promotion::Denied(
+            "permission denied, raise the rtprio limit of the user (e.g. in \
+             /etc/security/limits.conf) or pass --no-rt"
+                .to_string(),
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn describe(policy: i32, priority: i32) -> String {
+    let policy = match policy {
+        libc::SCHED_FIFO => "SCHED_FIFO",
+        libc::SCHED_RR => "SCHED_RR",
+        _ => "policy",
+    };
+    format!("{} priority {}", policy, priority)
+}
+
+/// The soft RLIMIT_RTPRIO of the process, if the OS has one and it's finite.
+#[cfg(target_os = "linux")]
+fn rtprio_limit() -> Option<i32> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safety: `limit` is valid for writes.
+    if unsafe { libc::getrlimit(libc::RLIMIT_RTPRIO, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    i32::try_from(limit.rlim_cur).ok()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn rtprio_limit() -> Option<i32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Runs `f` on a thread of its own, as a callback moved there would.
+    fn on_thread(f: impl FnOnce() + Send) {
+        std::thread::scope(|scope| {
+            scope.spawn(f);
+        });
+    }
+
+    fn outcome(report: &Report) -> Option<String> {
+        report.get().map(|x| x.to_string())
+    }
+
+    #[test]
+    fn a_stream_moved_to_another_thread_reports_that_thread() {
+        let report = Report::new();
+        assert!(report.get().is_none());
+        on_thread(|| promote_with(&report, || Promotion::RealTime("first".to_string())));
+        assert_eq!(outcome(&report).as_deref(), Some("first"));
+        on_thread(|| promote_with(&report, || Promotion::Denied("second".to_string())));
+        assert_eq!(outcome(&report).as_deref(), Some("normal priority: second"));
+    }
+
+    #[test]
+    fn streams_sharing_a_thread_report_its_only_promotion() {
+        let promotions = AtomicUsize::new(0);
+        let promote = || {
+            promotions.fetch_add(1, Ordering::Relaxed);
+            Promotion::RealTime("shared".to_string())
+        };
+        let (input, output) = (Report::new(), Report::new());
+        on_thread(|| {
+            for _ in 0..3 {
+                promote_with(&input, promote);
+                promote_with(&output, promote);
+            }
+        });
+        assert_eq!(promotions.load(Ordering::Relaxed), 1);
+        assert_eq!(outcome(&input).as_deref(), Some("shared"));
+        assert_eq!(outcome(&output).as_deref(), Some("shared"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn negotiation_falls_back_to_the_rtprio_limit() {
+        let mut asked = Vec::new();
+        let promotion = negotiate(80, Some(40), |priority| {
+            asked.push(priority);
+            match priority {
+                80 => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(asked, [80, 40]);
+        assert_eq!(promotion.to_string(), "SCHED_FIFO priority 40");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn negotiation_gives_up_without_a_usable_limit() {
+        let denied = || Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        for limit in [None, Some(0), Some(80)] {
+            let promotion = negotiate(80, limit, |_| denied());
+            assert!(
+                matches!(&promotion, Promotion::Denied(x) if x.contains("--no-rt")),
+                "{:?}",
+                promotion
+            );
+        }
+        let promotion = negotiate(80, Some(40), |_| Err(io::Error::other("no such thread")));
+        assert!(matches!(promotion, Promotion::Denied(x) if x == "no such thread"));
+    }
+}
@@ -0,0 +1,199 @@
+//! CPU time of every effect of a chain, as a share of the time its blocks last, to find which
+//! one is behind the xruns of a long chain.
+//!
+//! The chain reads the monotonic clock around every effect, and the shares are smoothed over
+//! about [`TIME_CONSTANT`] seconds of blocks, whatever their size. Effects can be bypassed
+//! through the stats too, e.g. by the [`AutoBypass`](crate::bypass::AutoBypass), and then count
+//! as taking no time.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::stats::AtomicF32;
+
+/// Time constant of the averages, in seconds.
+pub const TIME_CONSTANT: f32 = 1.0;
+
+/// Shared with the status line.
+pub struct ProfileStats {
+    /// Label of the output stream.
+    label: &'static str,
+    /// Effects of the chain, with the instance after the name for all but the first of a kind.
+    names: Vec<String>,
+    /// Average share of the block duration each effect takes, from 0 to 1.
+    loads: Vec<AtomicF32>,
+    bypassed: Vec<AtomicBool>,
+}
+
+impl ProfileStats {
+    pub fn new(label: &'static str, names: Vec<String>) -> Self {
+        ProfileStats {
+            label,
+            loads: names.iter().map(|_| AtomicF32::new(0.0)).collect(),
+            bypassed: names.iter().map(|_| AtomicBool::new(false)).collect(),
+            names,
+        }
+    }
+
+    /// Average share of the block duration the effect at `index` takes.
+    pub fn load(&self, index: usize) -> f32 {
+        self.loads[index].load()
+    }
+
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+
+    /// Number of effects.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Name of the effect at `index`, e.g. "fir" or "filter.1".
+    pub fn name(&self, index: usize) -> &str {
+        &self.names[index]
+    }
+
+    /// Index of the effect named `name`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|x| x == name)
+    }
+
+    /// Bypasses the effect at `index`, or brings it back, which the chain crossfades to.
+    pub fn bypass(&self, index: usize, bypassed: bool) {
+        self.bypassed[index].store(bypassed, Ordering::Relaxed);
+    }
+
+    pub fn is_bypassed(&self, index: usize) -> bool {
+        self.bypassed[index].load(Ordering::Relaxed)
+    }
+
+    /// The field of the status line, e.g. "CPU of the output: fir: 34% | gain: 1%", with
+    /// "bypassed" for the load of a bypassed effect.
+    pub fn describe(&self) -> String {
+        let loads: Vec<String> = self
+            .names
+            .iter()
+            .zip(&self.loads)
+            .zip(&self.bypassed)
+            .map(
+                |((name, load), bypassed)| match bypassed.load(Ordering::Relaxed) {
+                    true => format!("{}: bypassed", name),
+                    false => format!("{}: {:.0}%", name, load.load() * 100.0),
+                },
+            )
+            .collect();
+        format!("CPU of the {}: {}", self.label, loads.join(" | "))
+    }
+}
+
+/// Averages the times of the effects of one chain in its callback.
+pub struct Profiler {
+    stats: Arc<ProfileStats>,
+    sample_rate: f32,
+    averages: Vec<f32>,
+    /// Frames of the last block, and the smoothing coefficient for blocks of that size.
+    frames: usize,
+    coefficient: f32,
+}
+
+impl Profiler {
+    pub fn new(stats: Arc<ProfileStats>, sample_rate: u32) -> Self {
+        Profiler {
+            averages: vec![0.0; stats.loads.len()],
+            stats,
+            sample_rate: sample_rate as f32,
+            frames: 0,
+            coefficient: 0.0,
+        }
+    }
+
+    pub fn stats(&self) -> &ProfileStats {
+        &self.stats
+    }
+
+    /// Measures the blocks of a stream now at `sample_rate`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate as f32;
+        self.frames = 0;
+    }
+
+    /// Adds the `elapsed` time of the effect at `index` on a block of `frames` frames.
+    pub fn record(&mut self, index: usize, elapsed: Duration, frames: usize) {
+        if frames == 0 {
+            return;
+        }
+        let block = frames as f32 / self.sample_rate;
+        if frames != self.frames {
+            self.frames = frames;
+            self.coefficient = 1.0 - (-block / TIME_CONSTANT).exp();
+        }
+        let average = &mut self.averages[index];
+        *average += (elapsed.as_secs_f32() / block - *average) * self.coefficient;
+        self.stats.loads[index].store(*average);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> Arc<ProfileStats> {
+        let names = ["fir", "gain", "gain.1"].map(String::from).to_vec();
+        Arc::new(ProfileStats::new("output", names))
+    }
+
+    /// Records `seconds` of blocks of `frames` at 1 kHz, each taking half its duration.
+    fn run(profiler: &mut Profiler, frames: usize, seconds: f32) {
+        let block = Duration::from_secs_f32(frames as f32 / 1_000.0);
+        for _ in 0..(seconds * 1_000.0) as usize / frames {
+            profiler.record(0, block / 2, frames);
+        }
+    }
+
+    #[test]
+    fn loads_settle_over_the_time_constant_whatever_the_blocks() {
+        for frames in [10, 100] {
+            let stats = stats();
+            let mut profiler = Profiler::new(stats.clone(), 1_000);
+            run(&mut profiler, frames, TIME_CONSTANT);
+            // 1 - 1/e of the way there.
+            assert!((stats.load(0) - 0.316).abs() < 0.01, "{}", stats.load(0));
+            run(&mut profiler, frames, 10.0 * TIME_CONSTANT);
+            assert!((stats.load(0) - 0.5).abs() < 1e-3, "{}", stats.load(0));
+            assert_eq!(stats.load(1), 0.0);
+        }
+    }
+
+    #[test]
+    fn empty_blocks_are_left_out() {
+        let stats = stats();
+        let mut profiler = Profiler::new(stats.clone(), 1_000);
+        profiler.record(0, Duration::from_secs(1), 0);
+        assert_eq!(stats.load(0), 0.0);
+        profiler.set_sample_rate(2_000);
+        profiler.record(0, Duration::from_millis(1), 2);
+        assert!(stats.load(0) > 0.0);
+    }
+
+    #[test]
+    fn effects_are_described_by_name() {
+        let stats = stats();
+        assert_eq!((stats.len(), stats.label()), (3, "output"));
+        assert_eq!(stats.position("gain.1"), Some(2));
+        assert_eq!(stats.position("delay"), None);
+        assert_eq!(stats.name(1), "gain");
+        stats.loads[0].store(0.344);
+        stats.bypass(2, true);
+        assert!(stats.is_bypassed(2));
+        assert_eq!(
+            stats.describe(),
+            "CPU of the output: fir: 34% | gain: 0% | gain.1: bypassed"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! A fixed processing quantum between the callbacks and the effect chain.
+//!
+//! Devices don't always call back with the same number of frames, e.g. WASAPI in shared mode,
+//! and some effects work best on blocks of one size. A [`Quantum`] buffers what the callback
+//! delivers until it has a whole quantum, runs the chain on it, and hands the results back in
+//! the sizes the callback asks for. Its output starts with one quantum of silence, so that every
+//! callback, of any size, finds enough: it delays the signal by exactly one quantum, whatever the
+//! callback sizes, and never drifts.
+
+use std::collections::VecDeque;
+
+/// Runs a chain on blocks of a fixed number of frames, for callbacks of any size.
+pub struct Quantum {
+    /// Samples of a quantum.
+    samples: usize,
+    /// Samples waiting for a whole quantum, fewer than one.
+    pending: Vec<f32>,
+    /// Processed samples waiting for a callback, one quantum ahead of it.
+    ready: VecDeque<f32>,
+}
+
+impl Quantum {
+    /// A quantum of `frames` frames of `channels` channels.
+    pub fn new(frames: usize, channels: usize) -> Self {
+        let samples = frames * channels;
+        Quantum {
+            samples,
+            pending: Vec::with_capacity(samples),
+            ready: std::iter::repeat_n(0.0, samples).collect(),
+        }
+    }
+
+    /// Passes the interleaved block `data` through `chain` in quanta, replacing it with what
+    /// comes out one quantum later. Only allocates for callbacks larger than any before.
+    pub fn process(&mut self, data: &mut [f32], mut chain: impl FnMut(&mut [f32])) {
+        let mut input = &data[..];
+        while !input.is_empty() {
+            let taken = (self.samples - self.pending.len()).min(input.len());
+            self.pending.extend_from_slice(&input[..taken]);
+            input = &input[taken..];
+            if self.pending.len() == self.samples {
+                chain(&mut self.pending);
+                self.ready.extend(self.pending.drain(..));
+            }
+        }
+        let length = data.len();
+        for (x, y) in data.iter_mut().zip(self.ready.drain(..length)) {
+            *x = y;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn callbacks_of_any_size_are_delayed_by_one_quantum() {
+        let mut quantum = Quantum::new(4, 2);
+        let mut sizes = Vec::new();
+        let (mut output, mut next) = (Vec::new(), 0.0);
+        for frames in [3, 1, 7, 4, 2, 5, 10, 1] {
+            let mut data: Vec<f32> = (0..frames * 2)
+                .map(|_| {
+                    next += 1.0;
+                    next
+                })
+                .collect();
+            quantum.process(&mut data, |x| sizes.push(x.len()));
+            output.extend(data);
+        }
+        assert!(sizes.iter().all(|x| *x == 8));
+        assert_eq!(sizes.len(), 33 / 4);
+        let expected: Vec<f32> = std::iter::repeat_n(0.0, 8)
+            .chain((1..).map(|x| x as f32))
+            .take(output.len())
+            .collect();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn the_chain_processes_every_quantum() {
+        let mut quantum = Quantum::new(2, 1);
+        let double = |x: &mut [f32]| x.iter_mut().for_each(|x| *x *= 2.0);
+        let mut data = [1.0, 2.0, 3.0];
+        quantum.process(&mut data, double);
+        assert_eq!(data, [0.0, 0.0, 2.0]);
+        let mut data = [4.0];
+        quantum.process(&mut data, double);
+        assert_eq!(data, [4.0]);
+        let mut data = [5.0, 6.0, 7.0];
+        quantum.process(&mut data, double);
+        assert_eq!(data, [6.0, 8.0, 10.0]);
+    }
+}
@@ -0,0 +1,211 @@
+//! The sample rates of the streams, and their changes by the system during a run.
+//!
+//! Changing the rate of a device under a running stream, e.g. in the sound control panel of
+//! Windows, either fails the stream, with an error the [`events`](crate::events) sort out, or
+//! leaves it calling back with blocks at the new rate, which the engine would take for the old
+//! one. A [`RateDetector`] catches that from the frames the callbacks get over the time they
+//! take. Either way the [`Watchdog`](crate::watchdog::Watchdog) builds the streams again at the
+//! rate their devices are at now, which it sets in the [`StreamRate`] of each stream.
+//!
+//! The callbacks follow their [`StreamRate`]s through [`RateWatch`]es, re-deriving whatever
+//! depends on the rate on the first block at a new one: filter coefficients, delay lengths,
+//! envelope and LFO increments. The rates only change while the streams are closed, so no block
+//! is ever run at the wrong one.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The rates devices run at, which an estimate is rounded to.
+pub const STANDARD_RATES: [u32; 11] = [
+    8_000, 11_025, 16_000, 22_050, 32_000, 44_100, 48_000, 88_200, 96_000, 176_400, 192_000,
+];
+/// How far the rate a stream seems to run at may be from its own before it counts as changed.
+pub const TOLERANCE: f64 = 0.03;
+/// How long the frames are counted for each estimate.
+pub const WINDOW: Duration = Duration::from_secs(2);
+/// Estimates in a row that have to agree on a change.
+const CONFIRMATIONS: u32 = 2;
+
+/// The rate a stream runs at, shared between the main thread and the callbacks.
+pub struct StreamRate(AtomicU32);
+
+impl StreamRate {
+    pub fn new(rate: u32) -> Arc<Self> {
+        Arc::new(StreamRate(AtomicU32::new(rate)))
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn set(&self, rate: u32) {
+        self.0.store(rate, Ordering::Release);
+    }
+
+    /// A watch of the rate, which has seen the current one.
+    pub fn watch(self: &Arc<Self>) -> RateWatch {
+        RateWatch {
+            shared: self.clone(),
+            seen: self.get(),
+        }
+    }
+}
+
+/// The end of a [`StreamRate`] a callback checks on every block.
+pub struct RateWatch {
+    shared: Arc<StreamRate>,
+    seen: u32,
+}
+
+impl RateWatch {
+    /// The new rate, if it changed since the last call.
+    pub fn changed(&mut self) -> Option<u32> {
+        let rate = self.shared.get();
+        (rate != self.seen).then(|| {
+            self.seen = rate;
+            rate
+        })
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.seen
+    }
+}
+
+/// The standard rate closest to `rate`, or `rate` rounded if none is within the tolerance.
+pub fn nearest_standard(rate: f64) -> u32 {
+    STANDARD_RATES
+        .into_iter()
+        .min_by(|a, b| {
+            (*a as f64 - rate)
+                .abs()
+                .total_cmp(&(*b as f64 - rate).abs())
+        })
+        .filter(|x| (*x as f64 / rate - 1.0).abs() <= TOLERANCE)
+        .unwrap_or(rate.round() as u32)
+}
+
+/// Tells a stream calling back at another rate than its own from the frame counts seen at each
+/// check.
+///
+/// The counts only move once a period, so an estimate counts over [`WINDOW`] or 40 periods,
+/// whichever is longer, to stay well within the tolerance.
+pub struct RateDetector {
+    expected: u32,
+    window: Duration,
+    /// The count and the time the current estimate started at, once the stream called back.
+    start: Option<(u64, Instant)>,
+    /// The last count, and when it moved to it.
+    moved: (u64, Instant),
+    last_check: Instant,
+    /// The rate of the last estimates that were off, and how many in a row.
+    off: Option<(u32, u32)>,
+}
+
+impl RateDetector {
+    /// Watches a stream running at `expected`, calling back every `period`, from `now`.
+    pub fn new(expected: u32, period: Duration, now: Instant) -> Self {
+        RateDetector {
+            expected,
+            window: (period * 40).max(WINDOW),
+            start: None,
+            moved: (0, now),
+            last_check: now,
+            off: None,
+        }
+    }
+
+    /// Starts over from `now`, for a stream just built at `expected`.
+    pub fn rearm(&mut self, expected: u32, now: Instant) {
+        self.expected = expected;
+        self.start = None;
+        self.last_check = now;
+        self.off = None;
+    }
+
+    /// Takes the `frames` counted so far at `now`, returning the rate the stream runs at once
+    /// enough estimates in a row found it off its own.
+    pub fn check(&mut self, frames: u64, now: Instant) -> Option<u32> {
+        if frames != self.moved.0 {
+            self.moved = (frames, now);
+        }
+        // Checks held up, and a stream that stopped calling back, say nothing about the rate.
+        let held_up = now.saturating_duration_since(self.last_check) > self.window / 4;
+        let stopped = now.saturating_duration_since(self.moved.1) > self.window / 4;
+        self.last_check = now;
+        let (count, since) = match self.start {
+            Some(start) if !held_up && !stopped => start,
+            _ => {
+                self.start = Some((frames, now));
+                return None;
+            }
+        };
+        let elapsed = now.saturating_duration_since(since);
+        if elapsed < self.window {
+            return None;
+        }
+        self.start = Some((frames, now));
+        let observed = (frames - count) as f64 / elapsed.as_secs_f64();
+        if (observed / self.expected as f64 - 1.0).abs() <= TOLERANCE {
+            self.off = None;
+            return None;
+        }
+        let rate = nearest_standard(observed);
+        let estimates = match self.off {
+            Some((previous, estimates)) if previous == rate => estimates + 1,
+            _ => 1,
+        };
+        self.off = Some((rate, estimates));
+        (estimates >= CONFIRMATIONS).then_some(rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks a detector every 10 ms of a stream calling back at `actual` Hz, in periods of
+    /// 480 frames, returning the rate it found and after how long, if it did within 10 s.
+    fn detect(expected: u32, actual: u32) -> Option<(u32, Duration)> {
+        let start = Instant::now();
+        let period = Duration::from_millis(10);
+        let mut detector = RateDetector::new(expected, period, start);
+        for check in 1..1_000u32 {
+            let elapsed = period * check;
+            let frames = (elapsed.as_secs_f64() * actual as f64) as u64 / 480 * 480;
+            if let Some(rate) = detector.check(frames, start + elapsed) {
+                return Some((rate, elapsed));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn a_stream_calling_back_at_another_rate_is_found_after_two_windows() {
+        let (rate, after) = detect(48_000, 96_000).unwrap();
+        assert_eq!(rate, 96_000);
+        assert!(after >= WINDOW * 2 && after < WINDOW * 3, "{:?}", after);
+        assert_eq!(detect(48_000, 44_100).map(|x| x.0), Some(44_100));
+    }
+
+    #[test]
+    fn a_stream_at_its_own_rate_is_left_alone() {
+        assert_eq!(detect(48_000, 48_000), None);
+        // Within the tolerance of a clock that drifts.
+        assert_eq!(detect(48_000, 48_500), None);
+    }
+
+    #[test]
+    fn watches_see_each_change_once() {
+        let rate = StreamRate::new(48_000);
+        let mut watch = rate.watch();
+        assert_eq!(watch.changed(), None);
+        rate.set(96_000);
+        assert_eq!(watch.changed(), Some(96_000));
+        assert_eq!(watch.changed(), None);
+        assert_eq!(watch.rate(), 96_000);
+        assert_eq!(nearest_standard(47_950.0), 48_000);
+        assert_eq!(nearest_standard(60_000.0), 60_000);
+    }
+}
@@ -0,0 +1,1686 @@
+//! Recording of an input stream to WAV or FLAC files, with an optional silence gate.
+//!
+//! The input callback only pushes its samples into a ring buffer. A recorder thread drains it,
+//! runs the samples through the [`RecordGate`] and writes what the gate lets through, so the
+//! audio thread never touches the file system.
+//!
+//! With the gate, recording pauses once the input stays below a threshold for a hold time, and
+//! resumes as soon as it goes above it again. The last moments before resuming are kept in a
+//! pre-roll history and written first, so that attacks aren't clipped. The recorded segments go
+//! either one after another in a single file, or to numbered files when splitting.
+//!
+//! Recording can also wait until it's armed, e.g. by a keypress, keeping the last seconds of the
+//! input meanwhile in a [`PreRoll`]. Once armed, the file starts with them, so it begins that
+//! long before the keypress, and carries on with the live samples right after them.
+//!
+//! Markers can be dropped during the recording, e.g. by a keypress, to find moments again. Each
+//! lands on the frame the input was feeding the recorder right then, and a WAV file keeps its
+//! markers in a cue chunk, which editors show on the timeline. The markers of a FLAC file go to a
+//! CSV file next to it instead, e.g. "take.cues.csv". A marker dropped while the gate pauses the
+//! recording lands on the next frame recorded.
+//!
+//! Recording can go in [`Takes`] to a directory instead, each started and ended by a keypress,
+//! and kept or discarded as it ends. Takes wait to be started as recording waits to be armed, so
+//! each starts with the pre-roll too, and a discarded take's files are deleted once they're
+//! finished, never while they're written.
+//!
+//! Should the input change its sample rate during the recording, e.g. once the system changed the
+//! rate of the device, the recording goes on in the next numbered file at the new rate, from the
+//! first frame at it, so that no file mixes two rates.
+//!
+//! Once the recording ends, its files can be normalized to a target loudness, measured while
+//! they were written. FLAC files are then recorded to a float WAV first, and encoded at the
+//! right gain from it, since their samples are already rounded. Either way, every file's
+//! loudness and true peak are measured, for the summary.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use anyhow::Context;
+use ringbuf::traits::{Consumer, Observer, Producer};
+use ringbuf::{HeapCons, HeapProd};
+
+use crate::dither::DitherMode;
+use crate::flac::FlacWriter;
+use crate::level;
+use crate::loudness::{self, LoudnessMeter};
+use crate::takes::{self, TakeEvent, Takes};
+use crate::wav::{self, WavWriter};
+
+/// When the gate pauses the recording: `<dBFS>:<hold-seconds>` on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct GateSettings {
+    /// Level under which the input counts as silence, in dBFS.
+    pub threshold_db: f32,
+    /// How long the input must stay silent before recording pauses.
+    pub hold: Duration,
+}
+
+impl FromStr for GateSettings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected `<dBFS>:<hold-seconds>`, got \"{}\"", s);
+        let (threshold, hold) = s.split_once(':').ok_or_else(invalid)?;
+        let threshold_db: f32 = threshold.parse().map_err(|_| invalid())?;
+        let hold = hold
+            .parse()
+            .ok()
+            .and_then(|x| Duration::try_from_secs_f32(x).ok())
+            .ok_or_else(invalid)?;
+        Ok(GateSettings { threshold_db, hold })
+    }
+}
+
+/// What the gate decides for the samples passing through it, in order.
+#[derive(Debug, PartialEq)]
+pub enum Action<'a> {
+    /// Samples to record, and the frame of the first one, counted from the start of the stream.
+    Write(&'a [f32], u64),
+    /// Recording pauses at this frame, counted from the start of the stream.
+    Pause(u64),
+    /// Recording resumes at this frame, with the pre-roll written right after.
+    Resume(u64),
+}
+
+/// Frame by frame state machine deciding which samples of a stream get recorded.
+pub struct RecordGate {
+    channels: usize,
+    threshold: f32,
+    hold_frames: u64,
+    preroll_samples: usize,
+    /// Frames of the stream seen so far.
+    position: u64,
+    recording: bool,
+    /// Consecutive silent frames while recording.
+    quiet_frames: u64,
+    /// The most recent samples while paused, at most `preroll_samples` of them.
+    history: VecDeque<f32>,
+}
+
+impl RecordGate {
+    /// Creates a gate for a stream of `channels` channels at `sample_rate`, paused until the
+    /// input first goes above the threshold.
+    pub fn new(
+        settings: GateSettings,
+        preroll: Duration,
+        channels: usize,
+        sample_rate: u32,
+    ) -> Self {
+        let frames = |duration: Duration| (duration.as_secs_f64() * sample_rate as f64) as u64;
+        let preroll_samples = frames(preroll) as usize * channels;
+        RecordGate {
+            channels,
+            threshold: level::db_to_gain(settings.threshold_db),
+            hold_frames: frames(settings.hold),
+            preroll_samples,
+            position: 0,
+            recording: false,
+            quiet_frames: 0,
+            history: VecDeque::with_capacity(preroll_samples),
+        }
+    }
+
+    /// Runs the interleaved block `data`, which starts at `frame` of the stream and carries on
+    /// from the last one, through the gate, passing its decisions to `act`.
+    pub fn process(&mut self, data: &[f32], frame: u64, mut act: impl FnMut(Action)) {
+        self.position = frame;
+        // Start of the samples to write at the next decision, while recording, and its frame.
+        let (mut start, mut start_frame) = (0, frame);
+        for (index, frame) in data.chunks_exact(self.channels).enumerate() {
+            let loud = frame.iter().any(|x| x.abs() >= self.threshold);
+            let offset = index * self.channels;
+            if self.recording {
+                self.quiet_frames = if loud { 0 } else { self.quiet_frames + 1 };
+                if self.quiet_frames > self.hold_frames {
+                    act(Action::Write(&data[start..offset], start_frame));
+                    act(Action::Pause(self.position));
+                    self.recording = false;
+                }
+            } else if loud {
+                act(Action::Resume(self.position));
+                let history_frames = (self.history.len() / self.channels) as u64;
+                act(Action::Write(
+                    self.history.make_contiguous(),
+                    self.position - history_frames,
+                ));
+                self.history.clear();
+                self.recording = true;
+                self.quiet_frames = 0;
+                (start, start_frame) = (offset, self.position);
+            } else if self.preroll_samples > 0 {
+                if self.history.len() == self.preroll_samples {
+                    self.history.drain(..self.channels);
+                }
+                self.history.extend(frame);
+            }
+            self.position += 1;
+        }
+        if self.recording {
+            act(Action::Write(&data[start..], start_frame));
+        }
+    }
+}
+
+/// Holds the last moments of a stream until recording is armed, then lets everything through.
+pub struct PreRoll {
+    channels: usize,
+    capacity: usize,
+    history: VecDeque<f32>,
+    armed: bool,
+}
+
+impl PreRoll {
+    /// Creates a pre-roll keeping `duration` of a stream of `channels` channels at `sample_rate`.
+    pub fn new(duration: Duration, channels: usize, sample_rate: u32) -> Self {
+        let frames = (duration.as_secs_f64() * sample_rate as f64) as usize;
+        PreRoll {
+            channels,
+            capacity: frames * channels,
+            history: VecDeque::with_capacity(frames * channels),
+            armed: false,
+        }
+    }
+
+    /// Passes the interleaved block `data`, which starts at `frame` of the stream, to `write`
+    /// with its frame once `armed`, after the history the first time, and keeps it in the
+    /// history before.
+    pub fn process(
+        &mut self,
+        data: &[f32],
+        frame: u64,
+        armed: bool,
+        mut write: impl FnMut(&[f32], u64),
+    ) {
+        if self.armed {
+            write(data, frame);
+        } else if armed {
+            self.armed = true;
+            let history_frames = (self.history.len() / self.channels) as u64;
+            write(self.history.make_contiguous(), frame - history_frames);
+            self.history = VecDeque::new();
+            write(data, frame);
+        } else {
+            // Both hold whole frames, so the channels stay in place.
+            let excess = (self.history.len() + data.len()).saturating_sub(self.capacity);
+            let from_history = excess.min(self.history.len());
+            self.history.drain(..from_history);
+            self.history.extend(&data[excess - from_history..]);
+        }
+    }
+}
+
+/// Input channels to record, in the order of the file's channels: `2,3` on the command line,
+/// from 0, or `3,2` to swap them.
+#[derive(Clone, Debug)]
+pub struct ChannelPick(pub Vec<usize>);
+
+impl FromStr for ChannelPick {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|x| x.trim().parse().ok())
+            .collect::<Option<Vec<_>>>()
+            .map(ChannelPick)
+            .ok_or_else(|| format!("expected channels such as `2,3`, got \"{}\"", s))
+    }
+}
+
+impl ChannelPick {
+    /// Checks that every channel is one of a stream of `channels` channels.
+    pub fn check(&self, channels: u16) -> Result<(), String> {
+        match self.0.iter().find(|x| **x >= channels as usize) {
+            Some(channel) => Err(format!(
+                "channel {} is out of range, the input has {} channels, from 0",
+                channel, channels
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The input callback's end of the recorder's ring buffer, keeping only the recorded channels
+/// of each block.
+pub struct RecordFeed {
+    producer: HeapProd<f32>,
+    channels: usize,
+    pick: Option<ChannelPick>,
+    markers: Option<Arc<CueMarkers>>,
+    rate_change: Option<Arc<RateChange>>,
+    takes: Option<Arc<Takes>>,
+    /// Frames fed so far.
+    fed: u64,
+}
+
+impl RecordFeed {
+    /// Feeds `producer` from blocks of `channels` channels, of which only those of `pick`, in
+    /// its order, if any. Their indices must have been checked.
+    pub fn new(producer: HeapProd<f32>, channels: u16, pick: Option<ChannelPick>) -> Self {
+        RecordFeed {
+            producer,
+            channels: channels as usize,
+            pick,
+            markers: None,
+            rate_change: None,
+            takes: None,
+            fed: 0,
+        }
+    }
+
+    /// Counts the frames fed for `markers`, which must be those of the recorder's settings.
+    pub fn marking(mut self, markers: Arc<CueMarkers>) -> Self {
+        self.markers = Some(markers);
+        self
+    }
+
+    /// Tells the recorder of changes of the rate through `change`, which must be that of the
+    /// recorder's settings.
+    pub fn splitting(mut self, change: Arc<RateChange>) -> Self {
+        self.rate_change = Some(change);
+        self
+    }
+
+    /// Counts the frames fed for the commands of `takes`, which must be those of the recorder's
+    /// settings.
+    pub fn taking(mut self, takes: Arc<Takes>) -> Self {
+        self.takes = Some(takes);
+        self
+    }
+
+    /// Starts the next file at `rate`, from the next frame fed.
+    pub fn change_rate(&self, rate: u32) {
+        if let Some(change) = &self.rate_change {
+            change.rate.store(rate, Ordering::Relaxed);
+            change.at.store(self.fed, Ordering::Release);
+        }
+    }
+
+    /// Pushes the recorded channels of the interleaved block `data` whole, so that the channels
+    /// stay in place, returning false if the ring buffer can't take them.
+    pub fn push(&mut self, data: &[f32]) -> bool {
+        let frames = data.len() / self.channels;
+        match &self.pick {
+            None => {
+                if self.producer.vacant_len() < data.len() {
+                    return false;
+                }
+                self.producer.push_slice(data);
+            }
+            Some(ChannelPick(pick)) => {
+                if self.producer.vacant_len() < frames * pick.len() {
+                    return false;
+                }
+                let samples = data
+                    .chunks_exact(self.channels)
+                    .flat_map(|frame| pick.iter().map(|x| frame[*x]));
+                self.producer.push_iter(samples);
+            }
+        }
+        if let Some(markers) = &self.markers {
+            markers.fed.fetch_add(frames as u64, Ordering::Release);
+        }
+        if let Some(takes) = &self.takes {
+            takes.control.feed(frames as u64);
+        }
+        self.fed += frames as u64;
+        true
+    }
+}
+
+/// A change of the sample rate of the input recorded, from a frame on.
+///
+/// The input callback sets it before feeding the first frame at the new rate, and the recorder
+/// thread records no further than that frame until it took the change, so no file mixes rates.
+/// Changes are seconds apart at least, since the streams are built again for each, so only the
+/// last one is kept.
+pub struct RateChange {
+    /// The first frame at the new rate, or `u64::MAX` if no change is pending.
+    at: AtomicU64,
+    rate: AtomicU32,
+}
+
+impl Default for RateChange {
+    fn default() -> Self {
+        RateChange {
+            at: AtomicU64::new(u64::MAX),
+            rate: AtomicU32::new(0),
+        }
+    }
+}
+
+impl RateChange {
+    /// The frame and the rate of the change pending, if any.
+    fn pending(&self) -> Option<(u64, u32)> {
+        let at = self.at.load(Ordering::Acquire);
+        (at != u64::MAX).then(|| (at, self.rate.load(Ordering::Relaxed)))
+    }
+
+    fn clear(&self) {
+        self.at.store(u64::MAX, Ordering::Relaxed);
+    }
+}
+
+/// Markers dropped during a recording, each at the frame the input was feeding the recorder
+/// right then.
+///
+/// The input callback counts the frames it feeds, and a marker takes the count, so it lands on
+/// the next frame however many are still queued for the recorder thread. The thread takes the
+/// markers with the count, and records no further than the count the markers came with, so the
+/// markers of a frame always reach it before the frame.
+#[derive(Default)]
+pub struct CueMarkers {
+    /// Frames fed to the recorder so far.
+    fed: AtomicU64,
+    /// Markers not taken by the recorder thread yet.
+    pending: Mutex<Vec<u64>>,
+}
+
+impl CueMarkers {
+    /// Drops a marker, returning the frame of the input it lands on.
+    pub fn mark(&self) -> u64 {
+        let mut pending = self.pending.lock().unwrap();
+        let frame = self.fed.load(Ordering::Acquire);
+        pending.push(frame);
+        frame
+    }
+
+    /// Moves the markers dropped since the last call to `into`, returning how many frames had
+    /// been fed by then.
+    fn take(&self, into: &mut VecDeque<u64>) -> u64 {
+        let mut pending = self.pending.lock().unwrap();
+        into.extend(pending.drain(..));
+        self.fed.load(Ordering::Acquire)
+    }
+}
+
+/// Largest WAV file, whose sizes are 32-bit.
+const WAV_MAX_SIZE: u64 = u32::MAX as u64;
+/// Highest true peak normalization may bring a file to, in dBTP.
+pub const MAX_TRUE_PEAK_DB: f32 = -1.0;
+
+/// The format of the recorded files.
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    /// 32-bit float WAV.
+    Wav,
+    /// FLAC of `bits`-bit samples, at compression `level`, with `dither` if they're 16-bit.
+    Flac {
+        bits: u32,
+        level: u32,
+        dither: DitherMode,
+    },
+}
+
+/// Where and how to record.
+pub struct RecordSettings {
+    /// The file, or the first of the numbered ones, unless recording takes.
+    pub path: PathBuf,
+    pub format: Format,
+    pub gate: Option<GateSettings>,
+    pub preroll: Duration,
+    /// Whether each segment between pauses goes to its own numbered file.
+    pub split: bool,
+    /// Whether recording has started, when it waits to be armed, with how much of the input
+    /// from before to keep.
+    pub arm: Option<(Arc<AtomicBool>, Duration)>,
+    /// Longest file, after which recording goes on in the next numbered file.
+    pub split_every: Option<Duration>,
+    /// Largest file, in bytes. WAV files never outgrow the 4 GB their sizes can describe, and
+    /// FLAC ones may outgrow it by one block.
+    pub split_size: Option<u64>,
+    pub normalize: Option<Normalize>,
+    /// Markers to drop into the files, fed by a [`RecordFeed`] marking them.
+    pub markers: Option<Arc<CueMarkers>>,
+    /// Changes of the rate of the input, told by a [`RecordFeed`] splitting on them.
+    pub rate_change: Option<Arc<RateChange>>,
+    /// Takes to record instead of `path`, commanded through a [`RecordFeed`] taking them. They
+    /// replace `arm`.
+    pub takes: Option<Arc<Takes>>,
+}
+
+/// Normalization of the recorded files, once the recording ends.
+#[derive(Clone, Copy, Debug)]
+pub struct Normalize {
+    /// Integrated loudness to bring the files to, in LUFS.
+    pub target_lufs: f32,
+    /// Whether the files of a split recording get one gain, from their joint loudness, rather
+    /// than each its own.
+    pub joint: bool,
+}
+
+/// A file the recorder wrote, as it ended up once normalized.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedFile {
+    pub path: PathBuf,
+    /// Number of the take the file is of, when recording takes.
+    pub take: Option<usize>,
+    pub seconds: f64,
+    /// Markers dropped into the file.
+    pub markers: usize,
+    /// True peak, in dBTP.
+    pub true_peak_db: f32,
+    /// Integrated loudness, in LUFS, or `None` if the file was too quiet to measure.
+    pub lufs: Option<f64>,
+}
+
+/// What the recorder thread wrote.
+pub struct RecordSummary {
+    /// Every file written.
+    pub files: Vec<RecordedFile>,
+    /// Duration of everything written.
+    pub seconds: f64,
+    /// Takes discarded, whose files are gone.
+    pub discarded: usize,
+}
+
+/// Parses a duration such as "90s", "30m" or "2h", or a number of seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.strip_suffix(['s', 'm', 'h']) {
+        Some(number) => (number, &s[number.len()..]),
+        None => (s, "s"),
+    };
+    let scale = match unit {
+        "h" => 3_600.0,
+        "m" => 60.0,
+        _ => 1.0,
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|x| *x > 0.0)
+        .and_then(|x| Duration::try_from_secs_f64(x * scale).ok())
+        .ok_or_else(|| {
+            format!(
+                "expected a duration such as \"90s\", \"30m\" or \"2h\", got \"{}\"",
+                s
+            )
+        })
+}
+
+/// Parses a size in bytes such as "500M" or "2G", or a number of bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, scale) = match s.strip_suffix(['k', 'K', 'M', 'G']) {
+        Some(number) => match &s[number.len()..] {
+            "G" => (number, 1 << 30),
+            "M" => (number, 1 << 20),
+            _ => (number, 1 << 10),
+        },
+        None => (s, 1),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|x| *x > 0.0)
+        .map(|x| (x * scale as f64) as u64)
+        .ok_or_else(|| format!("expected a size such as \"500M\" or \"2G\", got \"{}\"", s))
+}
+
+/// Starts the recorder thread draining `consumer`, which holds a stream of `channels` channels at
+/// `sample_rate`. The thread finishes once the producer is dropped and the buffer is empty.
+pub fn spawn(
+    settings: RecordSettings,
+    mut consumer: HeapCons<f32>,
+    channels: u16,
+    sample_rate: u32,
+) -> JoinHandle<anyhow::Result<RecordSummary>> {
+    std::thread::spawn(move || {
+        let mut recorder = Recorder {
+            settings: &settings,
+            channels,
+            sample_rate,
+            writer: None,
+            path: PathBuf::new(),
+            files: Vec::new(),
+            seconds: 0.0,
+            origin: (0, 0.0),
+            rate_split: false,
+            file_limit: file_limit(&settings, channels, sample_rate),
+            size_limit: settings.split_size.unwrap_or(u64::MAX),
+            meter: None,
+            measured: Vec::new(),
+            markers: VecDeque::new(),
+            cues: Vec::new(),
+            take: None,
+            discarded: 0,
+        };
+        let new_gate = |rate| {
+            settings
+                .gate
+                .map(|gate| RecordGate::new(gate, settings.preroll, channels as usize, rate))
+        };
+        // Takes wait to start as recording waits to be armed.
+        let new_pre_roll = |rate| match &settings.takes {
+            Some(takes) => Some(PreRoll::new(takes.pre_roll, channels as usize, rate)),
+            None => settings
+                .arm
+                .as_ref()
+                .map(|(_, duration)| PreRoll::new(*duration, channels as usize, rate)),
+        };
+        let mut gate = new_gate(sample_rate);
+        let mut pre_roll = new_pre_roll(sample_rate);
+        if gate.is_none() && pre_roll.is_none() {
+            recorder.open()?;
+        }
+        let mut take_events = VecDeque::new();
+
+        let mut block = vec![0.0; 4_096 * channels as usize];
+        // Frames taken from the buffer so far.
+        let mut frame = 0;
+        loop {
+            // Once the producer is gone, the markers taken after are the last ones.
+            let held = consumer.write_is_held();
+            let mut available = consumer.occupied_len().min(block.len());
+            if let Some(markers) = &settings.markers {
+                let fed = markers.take(&mut recorder.markers);
+                available = available.min(((fed - frame) * channels as u64) as usize);
+            }
+            // Nothing past a change of rate until the files were split at it.
+            if let Some(change) = &settings.rate_change {
+                match change.pending() {
+                    Some((at, rate)) if at == frame => {
+                        recorder.change_rate(rate, frame)?;
+                        gate = new_gate(rate);
+                        pre_roll = new_pre_roll(rate);
+                        if gate.is_none() && pre_roll.is_none() {
+                            recorder.open()?;
+                        }
+                        change.clear();
+                    }
+                    Some((at, _)) => {
+                        available = available.min(((at - frame) * channels as u64) as usize);
+                    }
+                    None => {}
+                }
+            }
+            // Nothing past a command for the takes until it was carried out, as for a marker.
+            if let Some(takes) = &settings.takes {
+                let fed = takes.control.take(&mut take_events);
+                available = available.min(((fed - frame) * channels as u64) as usize);
+                while let Some((at, event)) = take_events.front().copied() {
+                    if at > frame {
+                        available = available.min(((at - frame) * channels as u64) as usize);
+                        break;
+                    }
+                    take_events.pop_front();
+                    if recorder.take_event(event, frame)? {
+                        gate = new_gate(recorder.sample_rate);
+                        pre_roll = new_pre_roll(recorder.sample_rate);
+                    }
+                }
+            }
+            // Whole frames only, so that the channels stay in place.
+            let popped =
+                consumer.pop_slice(&mut block[..available - available % channels as usize]);
+            if popped == 0 {
+                if !held {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            let data = &block[..popped];
+            let armed = match (&settings.takes, &settings.arm) {
+                (Some(_), _) => Some(recorder.take.is_some()),
+                (None, Some((armed, _))) => Some(armed.load(Ordering::Relaxed)),
+                (None, None) => None,
+            };
+            let mut result = Ok(());
+            let mut record = |data: &[f32], frame: u64| {
+                if result.is_err() || data.is_empty() {
+                    return;
+                }
+                result = match &mut gate {
+                    Some(gate) => {
+                        let mut result = Ok(());
+                        gate.process(data, frame, |action| {
+                            if result.is_ok() {
+                                result = recorder.act(action);
+                            }
+                        });
+                        result
+                    }
+                    None => recorder.write(data, frame),
+                };
+            };
+            match (&mut pre_roll, armed) {
+                (Some(pre_roll), Some(armed)) => pre_roll.process(data, frame, armed, &mut record),
+                _ => record(data, frame),
+            }
+            result?;
+            frame += (popped / channels as usize) as u64;
+        }
+        // A take still going when the input stops is kept.
+        match recorder.take {
+            Some(_) => {
+                recorder.take_event(TakeEvent::Keep, frame)?;
+            }
+            None => recorder.close()?,
+        }
+        if !recorder.markers.is_empty() {
+            tracing::info!(
+                "ignoring {} markers after the last frame recorded",
+                recorder.markers.len()
+            );
+        }
+        recorder.normalize()?;
+        Ok(RecordSummary {
+            files: recorder.files,
+            seconds: recorder.seconds,
+            discarded: recorder.discarded,
+        })
+    })
+}
+
+/// Whole frames that fit a file at `sample_rate`, at most, so that files split between frames.
+/// The size of FLAC files can only be checked as they grow.
+fn file_limit(settings: &RecordSettings, channels: u16, sample_rate: u32) -> u64 {
+    let frame_bytes = channels as u64 * 4;
+    let size = settings.split_size.unwrap_or(u64::MAX);
+    let by_size = match settings.format {
+        _ if settings.normalize.is_some() => {
+            size.min(WAV_MAX_SIZE)
+                .saturating_sub(wav::HEADER_SIZE as u64)
+                / frame_bytes
+        }
+        Format::Wav => {
+            size.min(WAV_MAX_SIZE)
+                .saturating_sub(wav::HEADER_SIZE as u64)
+                / frame_bytes
+        }
+        Format::Flac { .. } => u64::MAX,
+    };
+    let by_duration = settings
+        .split_every
+        .map_or(u64::MAX, |x| (x.as_secs_f64() * sample_rate as f64) as u64);
+    by_size.min(by_duration).max(1)
+}
+
+/// The files of a recording, opened and closed as the gate and the file limits decide.
+struct Recorder<'a> {
+    settings: &'a RecordSettings,
+    channels: u16,
+    sample_rate: u32,
+    writer: Option<Writer>,
+    /// Path of the file being written.
+    path: PathBuf,
+    /// The files closed so far.
+    files: Vec<RecordedFile>,
+    /// Seconds recorded so far.
+    seconds: f64,
+    /// The frame of the stream the rate last changed at, and its time in seconds.
+    origin: (u64, f64),
+    /// Whether the rate changed, after which every file is numbered.
+    rate_split: bool,
+    /// Frames after which a file is closed, and recording goes on in the next one, and bytes.
+    file_limit: u64,
+    size_limit: u64,
+    /// Loudness of the file being written.
+    meter: Option<LoudnessMeter>,
+    /// The files closed so far, with their loudness, when normalizing.
+    measured: Vec<Measured>,
+    /// Frames of the stream markers were dropped at, which haven't been recorded yet.
+    markers: VecDeque<u64>,
+    /// Frames of the file being written the markers recorded so far landed on.
+    cues: Vec<u64>,
+    /// The take being recorded, when recording takes.
+    take: Option<TakeFile>,
+    discarded: usize,
+}
+
+/// A take being recorded.
+struct TakeFile {
+    number: usize,
+    /// Its file, reserved when it started.
+    path: PathBuf,
+    /// Index of its first file in the files closed.
+    first_file: usize,
+}
+
+/// A file closed, with its loudness.
+struct Measured {
+    path: PathBuf,
+    /// The float WAV of a FLAC file, which is encoded from it once normalized.
+    part: Option<PathBuf>,
+    sample_rate: u32,
+    blocks: Vec<f64>,
+    true_peak: f32,
+}
+
+impl Recorder<'_> {
+    fn act(&mut self, action: Action) -> anyhow::Result<()> {
+        match action {
+            Action::Write(data, frame) => self.write(data, frame)?,
+            Action::Pause(frame) => {
+                tracing::info!(at = %self.timestamp(frame), "recording paused on silence");
+                if self.settings.split {
+                    self.close()?;
+                }
+            }
+            Action::Resume(frame) => {
+                tracing::info!(at = %self.timestamp(frame), "recording resumed");
+                if self.writer.is_none() {
+                    self.open()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&mut self) -> anyhow::Result<()> {
+        let (base, first_file) = match &self.take {
+            Some(take) => (&take.path, take.first_file),
+            None => (&self.settings.path, 0),
+        };
+        let number = self.files.len() - first_file + 1;
+        let numbered_files = self.settings.split
+            || self.rate_split
+            || self.settings.split_every.is_some()
+            || self.settings.split_size.is_some();
+        let path = match numbered_files {
+            true => numbered(base, number),
+            false => base.clone(),
+        };
+        let normalize = self.settings.normalize.is_some();
+        let writer = match self.settings.format {
+            Format::Wav => {
+                WavWriter::create(&path, self.channels, self.sample_rate).map(Writer::Wav)
+            }
+            Format::Flac { .. } if normalize => {
+                WavWriter::create(&part_path(&path), self.channels, self.sample_rate)
+                    .map(Writer::Wav)
+            }
+            Format::Flac {
+                bits,
+                level,
+                dither,
+            } => FlacWriter::create(&path, self.channels, self.sample_rate, bits, level, dither)
+                .map(|x| Writer::Flac(Box::new(x))),
+        }
+        .with_context(|| format!("failed to create \"{}\"", path.display()))?;
+        tracing::info!(path = %path.display(), "recording");
+        self.meter = Some(LoudnessMeter::new(self.channels as usize, self.sample_rate));
+        self.writer = Some(writer);
+        self.path = path;
+        Ok(())
+    }
+
+    /// Writes whole frames, starting at `frame` of the stream, opening a file if none is open,
+    /// and moving on to the next one whenever a file reaches its limit. The markers up to the
+    /// last frame land on theirs, or on the first if they came before it.
+    fn write(&mut self, mut data: &[f32], mut frame: u64) -> anyhow::Result<()> {
+        let channels = self.channels as usize;
+        while !data.is_empty() {
+            if self.writer.is_none() {
+                self.open()?;
+            }
+            let writer = self.writer.as_mut().unwrap();
+            let room = (self.file_limit - writer.frames()) as usize;
+            let (now, later) = data.split_at(data.len().min(room.saturating_mul(channels)));
+            let end = frame + (now.len() / channels) as u64;
+            while let Some(marker) = self.markers.front().copied().filter(|x| *x < end) {
+                self.cues
+                    .push(writer.frames() + marker.saturating_sub(frame));
+                self.markers.pop_front();
+            }
+            frame = end;
+            writer.write(now).context("failed to write the recording")?;
+            if let Some(meter) = &mut self.meter {
+                meter.process(now);
+            }
+            self.seconds += (now.len() / channels) as f64 / self.sample_rate as f64;
+            if writer.frames() >= self.file_limit || writer.size() >= self.size_limit {
+                self.close()?;
+            }
+            data = later;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            let seconds = writer.frames() as f64 / self.sample_rate as f64;
+            let cues = std::mem::take(&mut self.cues);
+            match (&mut writer, self.settings.format) {
+                (Writer::Wav(writer), Format::Wav) => {
+                    for &cue in &cues {
+                        writer.cue(cue as u32);
+                    }
+                }
+                _ if cues.is_empty() => {}
+                _ => self
+                    .write_cues(&cues)
+                    .context("failed to write the markers")?,
+            }
+            writer.finish().context("failed to finish the recording")?;
+            let meter = self.meter.take();
+            let (true_peak, lufs) = meter
+                .as_ref()
+                .map_or((0.0, None), |x| (x.true_peak(), x.integrated()));
+            if let (Some(meter), Some(_)) = (meter, self.settings.normalize) {
+                let part = matches!(self.settings.format, Format::Flac { .. })
+                    .then(|| part_path(&self.path));
+                self.measured.push(Measured {
+                    path: self.path.clone(),
+                    part,
+                    sample_rate: self.sample_rate,
+                    blocks: meter.blocks().to_vec(),
+                    true_peak,
+                });
+            }
+            self.files.push(RecordedFile {
+                path: std::mem::take(&mut self.path),
+                take: self.take.as_ref().map(|x| x.number),
+                seconds,
+                markers: cues.len(),
+                true_peak_db: level::gain_to_db(true_peak),
+                lufs,
+            });
+        }
+        Ok(())
+    }
+
+    /// Starts or ends a take at `frame` of the stream, returning whether one ended.
+    fn take_event(&mut self, event: TakeEvent, frame: u64) -> anyhow::Result<bool> {
+        let settings = self.settings;
+        let Some(takes) = &settings.takes else {
+            return Ok(false);
+        };
+        if event == TakeEvent::Start {
+            let (number, path) = takes::reserve(&takes.dir, takes.extension)?;
+            tracing::info!(at = %self.timestamp(frame), take = number, "take started");
+            self.take = Some(TakeFile {
+                number,
+                path,
+                first_file: self.files.len(),
+            });
+            // The files of a take are only numbered for a change of rate during it.
+            self.rate_split = false;
+            return Ok(false);
+        }
+        self.close()?;
+        let Some(take) = self.take.take() else {
+            return Ok(false);
+        };
+        // The file reserved stays empty when the take was numbered, or never opened one.
+        if !self.files[take.first_file..]
+            .iter()
+            .any(|x| x.path == take.path)
+        {
+            remove_if_exists(&take.path)
+                .with_context(|| format!("failed to remove \"{}\"", take.path.display()))?;
+        }
+        if event == TakeEvent::Keep {
+            tracing::info!(at = %self.timestamp(frame), take = take.number, "take kept");
+            return Ok(true);
+        }
+        // Every file of the take is finished by now.
+        let files = self.files.split_off(take.first_file);
+        for file in &files {
+            for path in [
+                part_path(&file.path),
+                cues_path(&file.path),
+                file.path.clone(),
+            ] {
+                remove_if_exists(&path)
+                    .with_context(|| format!("failed to remove \"{}\"", path.display()))?;
+            }
+            self.seconds -= file.seconds;
+        }
+        self.measured
+            .retain(|x| !files.iter().any(|file| file.path == x.path));
+        self.discarded += 1;
+        tracing::info!(at = %self.timestamp(frame), take = take.number, "take discarded");
+        Ok(true)
+    }
+
+    /// Closes the file being written at `frame` of the stream, for the next one at `rate`.
+    fn change_rate(&mut self, rate: u32, frame: u64) -> anyhow::Result<()> {
+        tracing::info!(
+            at = %self.timestamp(frame),
+            sample_rate = rate,
+            "recording goes on in the next file at the new rate"
+        );
+        self.close()?;
+        self.origin = (frame, self.seconds_at(frame));
+        self.sample_rate = rate;
+        self.file_limit = file_limit(self.settings, self.channels, rate);
+        self.rate_split = true;
+        Ok(())
+    }
+
+    /// Normalizes the files closed, all at once or each on its own, and the summary of them.
+    fn normalize(&mut self) -> anyhow::Result<()> {
+        let Some(settings) = self.settings.normalize else {
+            return Ok(());
+        };
+        let measured = std::mem::take(&mut self.measured);
+        // Every file closed was measured, in order.
+        let gains = match settings.joint {
+            true if !measured.is_empty() => {
+                let gain_db = self.normalize_group(&measured, settings.target_lufs)?;
+                vec![gain_db; measured.len()]
+            }
+            true => Vec::new(),
+            false => measured
+                .iter()
+                .map(|x| self.normalize_group(std::slice::from_ref(x), settings.target_lufs))
+                .collect::<anyhow::Result<_>>()?,
+        };
+        for (file, gain_db) in self.files.iter_mut().zip(gains) {
+            file.true_peak_db += gain_db;
+            file.lufs = file.lufs.map(|x| x + gain_db as f64);
+        }
+        Ok(())
+    }
+
+    /// Brings the joint loudness of `files` to `target` LUFS with one gain, or as close as
+    /// keeps their true peak under [`MAX_TRUE_PEAK_DB`], returning the gain in dB.
+    fn normalize_group(&self, files: &[Measured], target: f32) -> anyhow::Result<f32> {
+        let name = match files {
+            [file] => format!("\"{}\"", file.path.display()),
+            files => format!("the {} files", files.len()),
+        };
+        let blocks: Vec<f64> = files
+            .iter()
+            .flat_map(|x| x.blocks.iter().copied())
+            .collect();
+        let true_peak = level::gain_to_db(files.iter().map(|x| x.true_peak).fold(0.0, f32::max));
+        let gain_db = match loudness::integrated(&blocks) {
+            Some(lufs) => {
+                let lufs = lufs as f32;
+                let gain_db = (target - lufs).min(MAX_TRUE_PEAK_DB - true_peak);
+                match gain_db < target - lufs {
+                    true => tracing::info!(
+                        recording = %name,
+                        from_lufs = lufs,
+                        to_lufs = lufs + gain_db,
+                        target_lufs = target,
+                        true_peak_dbtp = true_peak,
+                        "normalized short of the target, as far as the true peak allows"
+                    ),
+                    false => tracing::info!(
+                        recording = %name,
+                        from_lufs = lufs,
+                        to_lufs = target,
+                        "normalized"
+                    ),
+                }
+                gain_db
+            }
+            None => {
+                tracing::info!(
+                    recording = %name,
+                    "left as it is, too quiet to measure its loudness"
+                );
+                0.0
+            }
+        };
+        let gain = level::db_to_gain(gain_db);
+        for file in files {
+            self.apply_gain(file, gain)
+                .with_context(|| format!("failed to normalize \"{}\"", file.path.display()))?;
+        }
+        Ok(gain_db)
+    }
+
+    fn apply_gain(&self, file: &Measured, gain: f32) -> anyhow::Result<()> {
+        let Some(part) = &file.part else {
+            return Ok(wav::scale(&file.path, gain)?);
+        };
+        let Format::Flac {
+            bits,
+            level,
+            dither,
+        } = self.settings.format
+        else {
+            unreachable!("only FLAC files are recorded to a part first");
+        };
+        let mut writer = FlacWriter::create(
+            &file.path,
+            self.channels,
+            file.sample_rate,
+            bits,
+            level,
+            dither,
+        )?;
+        let mut scaled = Vec::new();
+        wav::for_each_block(part, |block| {
+            scaled.clear();
+            scaled.extend(block.iter().map(|x| x * gain));
+            writer.write(&scaled)
+        })?;
+        writer.finish()?;
+        std::fs::remove_file(part)?;
+        Ok(())
+    }
+
+    /// Writes the markers of the file being written to its CSV file, one row per marker with its
+    /// frame and time in seconds.
+    fn write_cues(&self, cues: &[u64]) -> std::io::Result<()> {
+        let mut csv = "marker,frame,seconds\n".to_string();
+        for (index, cue) in cues.iter().enumerate() {
+            let seconds = *cue as f64 / self.sample_rate as f64;
+            writeln!(csv, "{},{},{:.6}", index + 1, cue, seconds).unwrap();
+        }
+        std::fs::write(cues_path(&self.path), csv)
+    }
+
+    /// Formats a stream position as seconds, e.g. "12.345 s".
+    fn timestamp(&self, frame: u64) -> String {
+        format!("{:.3} s", self.seconds_at(frame))
+    }
+
+    /// The time of a stream position, in seconds, counting each stretch at its own rate.
+    fn seconds_at(&self, frame: u64) -> f64 {
+        let (origin, seconds) = self.origin;
+        seconds + frame.saturating_sub(origin) as f64 / self.sample_rate as f64
+    }
+}
+
+/// A file being recorded.
+enum Writer {
+    Wav(WavWriter),
+    Flac(Box<FlacWriter>),
+}
+
+impl Writer {
+    fn write(&mut self, data: &[f32]) -> std::io::Result<()> {
+        match self {
+            Writer::Wav(writer) => writer.write(data),
+            Writer::Flac(writer) => writer.write(data),
+        }
+    }
+
+    fn frames(&self) -> u64 {
+        match self {
+            Writer::Wav(writer) => writer.frames() as u64,
+            Writer::Flac(writer) => writer.frames(),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            Writer::Wav(writer) => writer.size(),
+            Writer::Flac(writer) => writer.size(),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Writer::Wav(writer) => writer.finish(),
+            Writer::Flac(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Removes the file at `path`, if there is one.
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// The float WAV a FLAC file is recorded to before it's normalized, e.g. "take.flac.part".
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// The CSV file of the markers of a file, e.g. "take.cues.csv" for "take.flac".
+pub fn cues_path(path: &Path) -> PathBuf {
+    path.with_extension("cues.csv")
+}
+
+/// Inserts a segment number before the extension, e.g. "take.wav" to "take-002.wav".
+pub fn numbered(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{:03}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}-{:03}", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ringbuf::traits::Split;
+    use ringbuf::HeapRb;
+
+    use crate::takes::{TakeControl, LOCK_NAME};
+
+    /// What a gate decides, with the samples of each write.
+    #[derive(Debug, PartialEq)]
+    enum Decision {
+        Write(Vec<f32>, u64),
+        Pause(u64),
+        Resume(u64),
+    }
+
+    fn run(gate: &mut RecordGate, data: &[f32], frame: u64) -> Vec<Decision> {
+        let mut decisions = Vec::new();
+        gate.process(data, frame, |action| {
+            decisions.push(match action {
+                Action::Write(data, frame) => Decision::Write(data.to_vec(), frame),
+                Action::Pause(frame) => Decision::Pause(frame),
+                Action::Resume(frame) => Decision::Resume(frame),
+            })
+        });
+        // Empty writes change nothing in the file.
+        decisions.retain(|x| !matches!(x, Decision::Write(data, _) if data.is_empty()));
+        decisions
+    }
+
+    /// A gate at -20 dBFS holding for 2 frames, with a pre-roll of 2 frames, at 1 kHz.
+    fn gate(channels: usize) -> RecordGate {
+        let settings = GateSettings {
+            threshold_db: -20.0,
+            hold: Duration::from_millis(2),
+        };
+        RecordGate::new(settings, Duration::from_millis(2), channels, 1_000)
+    }
+
+    #[test]
+    fn gate_settings_parse_a_threshold_and_a_hold() {
+        let settings: GateSettings = "-50:1.5".parse().unwrap();
+        assert_eq!(settings.threshold_db, -50.0);
+        assert_eq!(settings.hold, Duration::from_millis(1_500));
+        for text in ["-50", "loud:1", "-50:-1", "-50:soon"] {
+            assert_eq!(
+                text.parse::<GateSettings>().unwrap_err(),
+                format!("expected `<dBFS>:<hold-seconds>`, got \"{}\"", text)
+            );
+        }
+    }
+
+    #[test]
+    fn the_gate_resumes_on_the_first_loud_frame_with_the_pre_roll() {
+        let mut gate = gate(1);
+        let block = [0.01, 0.02, 0.03, 0.5, 0.01];
+        assert_eq!(
+            run(&mut gate, &block, 0),
+            [
+                Decision::Resume(3),
+                Decision::Write(vec![0.02, 0.03], 1),
+                Decision::Write(vec![0.5, 0.01], 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_gate_pauses_once_quiet_past_the_hold() {
+        let mut gate = gate(1);
+        run(&mut gate, &[0.5], 0);
+        // Two quiet frames are held, the third pauses.
+        let block = [0.01, 0.01, 0.01, 0.01];
+        assert_eq!(
+            run(&mut gate, &block, 1),
+            [Decision::Write(vec![0.01, 0.01], 1), Decision::Pause(3)]
+        );
+        let block = [0.01, 0.6];
+        assert_eq!(
+            run(&mut gate, &block, 5),
+            [
+                Decision::Resume(6),
+                Decision::Write(vec![0.01, 0.01], 4),
+                Decision::Write(vec![0.6], 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_single_loud_channel_keeps_the_gate_open() {
+        let mut gate = gate(2);
+        let block = [0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        assert_eq!(
+            run(&mut gate, &block, 0),
+            [
+                Decision::Resume(0),
+                Decision::Write(vec![0.0, 0.5, 0.0, 0.0, 0.0, 0.0], 0),
+                Decision::Pause(3),
+            ]
+        );
+    }
+
+    /// Feeds a counting ramp of `channels` channels through `pre_roll` in blocks of `block`
+    /// frames, arming it at the block starting past `armed_at`, and returns what was written.
+    fn pre_roll(
+        pre_roll: &mut PreRoll,
+        channels: usize,
+        block: u64,
+        armed_at: u64,
+    ) -> Vec<(f32, u64)> {
+        let mut written = Vec::new();
+        for start in (0..200).step_by(block as usize) {
+            let data: Vec<f32> = (start..start + block)
+                .flat_map(|x| std::iter::repeat_n(x as f32, channels))
+                .collect();
+            pre_roll.process(&data, start, start >= armed_at, |data, frame| {
+                written.extend(
+                    data.iter()
+                        .step_by(channels)
+                        .enumerate()
+                        .map(|(i, x)| (*x, frame + i as u64)),
+                );
+            });
+        }
+        written
+    }
+
+    #[test]
+    fn the_pre_roll_starts_the_file_before_arming_without_a_gap() {
+        let mut roll = PreRoll::new(Duration::from_millis(20), 2, 1_000);
+        let written = pre_roll(&mut roll, 2, 7, 100);
+        // Armed at the block of frame 105, the file starts 20 frames before it.
+        assert_eq!(written[0], (85.0, 85));
+        for (x, frame) in &written {
+            assert_eq!(*x, *frame as f32);
+        }
+        assert!(written.windows(2).all(|x| x[1].1 == x[0].1 + 1));
+        assert_eq!(written.last().unwrap().1, 202);
+    }
+
+    #[test]
+    fn a_pre_roll_armed_early_holds_what_came_so_far() {
+        let mut roll = PreRoll::new(Duration::from_secs(1), 1, 1_000);
+        let written = pre_roll(&mut roll, 1, 10, 30);
+        assert_eq!(written[0], (0.0, 0));
+        assert_eq!(written.len(), 200);
+    }
+
+    fn settings(path: PathBuf) -> RecordSettings {
+        RecordSettings {
+            path,
+            format: Format::Wav,
+            gate: None,
+            preroll: Duration::ZERO,
+            split: false,
+            arm: None,
+            split_every: None,
+            split_size: None,
+            normalize: None,
+            markers: None,
+            rate_change: None,
+            takes: None,
+        }
+    }
+
+    /// A directory of its own for the files of a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    /// Records `frames` of a stereo counter at 1 kHz, pushed in 37-frame blocks, with
+    /// `settings`.
+    fn record_counter(settings: RecordSettings, frames: usize) -> RecordSummary {
+        let (mut producer, consumer) = HeapRb::<f32>::new(frames * 2).split();
+        let recorder = spawn(settings, consumer, 2, 1_000);
+        let counter: Vec<f32> = (0..frames).flat_map(|x| [x as f32, -(x as f32)]).collect();
+        for block in counter.chunks(37 * 2) {
+            producer.push_slice(block);
+        }
+        drop(producer);
+        recorder.join().unwrap().unwrap()
+    }
+
+    #[test]
+    fn durations_and_sizes_parse_with_their_units() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7_200)));
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(
+            parse_duration("0m"),
+            Err("expected a duration such as \"90s\", \"30m\" or \"2h\", got \"0m\"".to_string())
+        );
+        assert!(parse_duration("2d").is_err());
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("1.5M"), Ok(3 << 19));
+        assert_eq!(parse_size("4k"), Ok(4_096));
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(
+            parse_size("-1G"),
+            Err("expected a size such as \"500M\" or \"2G\", got \"-1G\"".to_string())
+        );
+    }
+
+    #[test]
+    fn wav_files_split_before_4_gib_whatever_the_limits() {
+        let mut settings = settings(PathBuf::from("take.wav"));
+        let frames = (u32::MAX as u64 - wav::HEADER_SIZE as u64) / 8;
+        assert_eq!(file_limit(&settings, 2, 48_000), frames);
+        settings.split_size = Some(8 << 30);
+        assert_eq!(file_limit(&settings, 2, 48_000), frames);
+        settings.split_size = Some(wav::HEADER_SIZE as u64 + 8_000);
+        assert_eq!(file_limit(&settings, 2, 48_000), 1_000);
+        settings.split_every = Some(Duration::from_millis(10));
+        assert_eq!(file_limit(&settings, 2, 48_000), 480);
+    }
+
+    #[test]
+    fn rotated_files_hold_every_frame_once_and_in_order() {
+        let directory = temp_dir("record-rotate");
+        let mut by_duration = settings(directory.join("take.wav"));
+        by_duration.split_every = Some(Duration::from_millis(1_500));
+        let mut by_size = settings(directory.join("size.wav"));
+        by_size.split_size = Some(wav::HEADER_SIZE as u64 + 1_000 * 8);
+        for (settings, lengths) in [(by_duration, [1_500, 1_500, 1_000]), (by_size, [1_000; 3])] {
+            let frames = lengths.iter().sum();
+            let summary = record_counter(settings, frames);
+            assert_eq!(summary.files.len(), 3);
+            let mut joined = Vec::new();
+            for (file, length) in summary.files.iter().zip(lengths) {
+                let data = wav::read(&file.path).unwrap();
+                assert_eq!(data.samples.len(), length * 2);
+                assert_eq!(file.seconds, length as f64 / 1_000.0);
+                joined.extend(data.samples);
+            }
+            let counter: Vec<f32> = (0..frames).flat_map(|x| [x as f32, -(x as f32)]).collect();
+            assert_eq!(joined, counter);
+        }
+        let mut names: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(
+            names,
+            [
+                "size-001.wav",
+                "size-002.wav",
+                "size-003.wav",
+                "take-001.wav",
+                "take-002.wav",
+                "take-003.wav"
+            ]
+        );
+    }
+
+    /// Records `seconds` of a stereo sine of 997 Hz at 48 kHz with `settings`, of the amplitude
+    /// given by the time, returning the loudness and true peak of each file.
+    fn record_sine(
+        settings: RecordSettings,
+        seconds: f32,
+        amplitude: impl Fn(f32) -> f32,
+    ) -> Vec<(f64, f32)> {
+        let frames = (seconds * 48_000.0) as usize;
+        let (mut producer, consumer) = HeapRb::<f32>::new(frames * 2).split();
+        let recorder = spawn(settings, consumer, 2, 48_000);
+        let omega = std::f32::consts::TAU * 997.0 / 48_000.0;
+        let sine = (0..frames).flat_map(|n| {
+            let amplitude = amplitude(n as f32 / 48_000.0);
+            [amplitude * (omega * n as f32).sin(); 2]
+        });
+        producer.push_iter(sine);
+        drop(producer);
+        let summary = recorder.join().unwrap().unwrap();
+        summary
+            .files
+            .iter()
+            .map(|file| {
+                let data = wav::read(&file.path).unwrap();
+                std::fs::remove_file(&file.path).unwrap();
+                let mut meter = LoudnessMeter::new(2, 48_000);
+                meter.process(&data.samples);
+                (meter.integrated().unwrap(), meter.true_peak())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recordings_are_normalized_to_the_target() {
+        let directory = temp_dir("record-normalize");
+        let mut quiet = settings(directory.join("quiet.wav"));
+        quiet.normalize = Some(Normalize {
+            target_lufs: -23.0,
+            joint: false,
+        });
+        let files = record_sine(quiet, 1.0, |_| 0.01);
+        assert_eq!(files.len(), 1);
+        assert!((files[0].0 + 23.0).abs() < 0.05, "{:?}", files);
+
+        // In both channels, the sine peaks at its loudness, so over -1 dBTP at 0 LUFS.
+        let mut loud = settings(directory.join("loud.wav"));
+        loud.normalize = Some(Normalize {
+            target_lufs: 0.0,
+            joint: false,
+        });
+        let files = record_sine(loud, 1.0, |_| 0.5);
+        std::fs::remove_dir_all(&directory).unwrap();
+        let true_peak = level::gain_to_db(files[0].1);
+        assert!((true_peak - MAX_TRUE_PEAK_DB).abs() < 0.05, "{}", true_peak);
+        assert!((files[0].0 + 1.0).abs() < 0.1, "{:?}", files);
+    }
+
+    #[test]
+    fn split_recordings_can_share_one_gain() {
+        let directory = temp_dir("record-joint");
+        for joint in [false, true] {
+            let mut settings = settings(directory.join(format!("{}.wav", joint)));
+            settings.split_every = Some(Duration::from_millis(1_000));
+            settings.normalize = Some(Normalize {
+                target_lufs: -23.0,
+                joint,
+            });
+            // The second file is 6 dB under the first, which one gain keeps, bringing their
+            // mean power, 5/8 of the first's, to the target.
+            let files = record_sine(settings, 2.0, |t| if t < 1.0 { 0.1 } else { 0.05 });
+            assert_eq!(files.len(), 2);
+            let first = -23.0 + 10.0 * 1.6f64.log10();
+            let expected = match joint {
+                true => [first, first - 6.02],
+                false => [-23.0, -23.0],
+            };
+            for ((lufs, _), expected) in files.iter().zip(expected) {
+                assert!(
+                    (lufs - expected).abs() < 0.05,
+                    "{} against {}",
+                    lufs,
+                    expected
+                );
+            }
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn the_summary_gives_the_loudness_of_every_file_as_written() {
+        let directory = temp_dir("record-summary");
+        for normalize in [None, Some(-23.0)] {
+            let mut settings = settings(directory.join("summary.wav"));
+            settings.normalize = normalize.map(|target_lufs| Normalize {
+                target_lufs,
+                joint: false,
+            });
+            let (mut producer, consumer) = HeapRb::<f32>::new(96_000).split();
+            let recorder = spawn(settings, consumer, 2, 48_000);
+            let omega = std::f32::consts::TAU * 997.0 / 48_000.0;
+            producer.push_iter((0..48_000).flat_map(|n| [0.1 * (omega * n as f32).sin(); 2]));
+            drop(producer);
+            let summary = recorder.join().unwrap().unwrap();
+            let file = &summary.files[0];
+            let data = wav::read(&file.path).unwrap();
+            let mut meter = LoudnessMeter::new(2, 48_000);
+            meter.process(&data.samples);
+            let lufs = file.lufs.unwrap();
+            assert!(
+                (lufs - meter.integrated().unwrap()).abs() < 0.05,
+                "{}",
+                lufs
+            );
+            let true_peak = level::gain_to_db(meter.true_peak());
+            assert!((file.true_peak_db - true_peak).abs() < 0.05);
+            assert!((file.seconds - 1.0).abs() < 1e-9);
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn channel_picks_parse_and_check_their_range() {
+        let pick: ChannelPick = "3, 2".parse().unwrap();
+        assert_eq!(pick.0, [3, 2]);
+        assert_eq!(pick.check(4), Ok(()));
+        assert_eq!(
+            pick.check(3),
+            Err("channel 3 is out of range, the input has 3 channels, from 0".to_string())
+        );
+        assert_eq!(
+            "2,".parse::<ChannelPick>().unwrap_err(),
+            "expected channels such as `2,3`, got \"2,\""
+        );
+    }
+
+    #[test]
+    fn feeds_keep_the_picked_channels_in_their_order() {
+        let (producer, mut consumer) = HeapRb::<f32>::new(8).split();
+        let mut feed = RecordFeed::new(producer, 4, Some(ChannelPick(vec![3, 1])));
+        assert!(feed.push(&[0.0, 1.0, 2.0, 3.0, 10.0, 11.0, 12.0, 13.0]));
+        assert_eq!(
+            consumer.pop_iter().collect::<Vec<_>>(),
+            [3.0, 1.0, 13.0, 11.0]
+        );
+        // Blocks go in whole, or not at all.
+        assert!(feed.push(&[0.0; 12]));
+        assert!(!feed.push(&[0.0; 8]));
+        assert_eq!(consumer.occupied_len(), 6);
+
+        let (producer, mut consumer) = HeapRb::<f32>::new(8).split();
+        let mut feed = RecordFeed::new(producer, 2, None);
+        assert!(feed.push(&[0.5, -0.5]));
+        assert!(!feed.push(&[0.0; 8]));
+        assert_eq!(consumer.pop_iter().collect::<Vec<_>>(), [0.5, -0.5]);
+    }
+
+    /// Records 1500 frames of a stereo counter at 1 kHz, marked after each of `marks` frames.
+    fn record_marked(mut settings: RecordSettings, marks: &[usize]) -> RecordSummary {
+        let markers = Arc::new(CueMarkers::default());
+        settings.markers = Some(markers.clone());
+        let (producer, consumer) = HeapRb::<f32>::new(1_500 * 2).split();
+        let recorder = spawn(settings, consumer, 2, 1_000);
+        let mut feed = RecordFeed::new(producer, 2, None).marking(markers.clone());
+        let counter: Vec<f32> = (0..1_500).flat_map(|x| [x as f32, -(x as f32)]).collect();
+        let mut fed = 0;
+        for &mark in marks {
+            assert!(feed.push(&counter[fed * 2..mark * 2]));
+            assert_eq!(markers.mark(), mark as u64);
+            fed = mark;
+        }
+        assert!(feed.push(&counter[fed * 2..]));
+        drop(feed);
+        recorder.join().unwrap().unwrap()
+    }
+
+    #[test]
+    fn markers_land_in_the_cue_chunk_of_the_file_they_fall_in() {
+        let directory = temp_dir("record-cues");
+        let settings = RecordSettings {
+            split_every: Some(Duration::from_secs(1)),
+            ..settings(directory.join("take.wav"))
+        };
+        let summary = record_marked(settings, &[100, 250, 1_200]);
+        let cues: Vec<(usize, Vec<u32>)> = summary
+            .files
+            .iter()
+            .map(|file| {
+                let bytes = std::fs::read(&file.path).unwrap();
+                (file.markers, wav::parse_cues(&bytes).unwrap())
+            })
+            .collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(cues, [(2, vec![100, 250]), (1, vec![200])]);
+    }
+
+    #[test]
+    fn the_markers_of_a_flac_file_go_to_a_csv_file() {
+        let directory = temp_dir("record-flac-cues");
+        let path = directory.join("take.flac");
+        let settings = RecordSettings {
+            format: Format::Flac {
+                bits: 24,
+                level: 5,
+                dither: DitherMode::Off,
+            },
+            ..settings(path.clone())
+        };
+        let summary = record_marked(settings, &[500]);
+        let csv = std::fs::read_to_string(cues_path(&path)).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(summary.files[0].markers, 1);
+        assert_eq!(csv, "marker,frame,seconds\n1,500,0.500000\n");
+    }
+
+    #[test]
+    fn takes_are_kept_or_discarded_and_numbered_from_the_directory() {
+        let directory = temp_dir("record-takes");
+        let takes = Arc::new(Takes {
+            dir: directory.clone(),
+            extension: "wav",
+            pre_roll: Duration::from_millis(50),
+            control: TakeControl::default(),
+        });
+        let settings = RecordSettings {
+            takes: Some(takes.clone()),
+            ..settings(directory.join("unused.wav"))
+        };
+        let (producer, consumer) = HeapRb::<f32>::new(1_000 * 2).split();
+        let recorder = spawn(settings, consumer, 2, 1_000);
+        let mut feed = RecordFeed::new(producer, 2, None).taking(takes.clone());
+        let counter: Vec<f32> = (0..1_000).flat_map(|x| [x as f32, -(x as f32)]).collect();
+        let events = [
+            (100, TakeEvent::Start),
+            (300, TakeEvent::Keep),
+            (400, TakeEvent::Start),
+            (500, TakeEvent::Discard),
+            (600, TakeEvent::Start),
+        ];
+        let mut fed = 0;
+        for (at, event) in events {
+            assert!(feed.push(&counter[fed * 2..at * 2]));
+            assert_eq!(takes.control.send(event), Ok(at as u64));
+            fed = at;
+        }
+        // The last take is kept as the input stops.
+        assert!(feed.push(&counter[fed * 2..]));
+        drop(feed);
+        let summary = recorder.join().unwrap().unwrap();
+
+        let files: Vec<(Option<usize>, String, f32, usize)> = summary
+            .files
+            .iter()
+            .map(|file| {
+                let data = wav::read(&file.path).unwrap();
+                let name = file.path.file_name().unwrap().to_string_lossy().to_string();
+                (file.take, name, data.samples[0], data.samples.len() / 2)
+            })
+            .collect();
+        let mut names: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        std::fs::remove_dir_all(&directory).unwrap();
+        // Each starts with the pre-roll, and the discarded take's number is free again.
+        assert_eq!(
+            files,
+            [
+                (Some(1), "take_001.wav".to_string(), 50.0, 250),
+                (Some(2), "take_002.wav".to_string(), 550.0, 450),
+            ]
+        );
+        assert_eq!(summary.discarded, 1);
+        assert_eq!(names, [LOCK_NAME, "take_001.wav", "take_002.wav"]);
+    }
+}
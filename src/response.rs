@@ -0,0 +1,311 @@
+//! Magnitude responses from impulse responses, with fractional-octave smoothing.
+//!
+//! The impulse response is windowed around its peak before the transform: the window ends
+//! before the noise floor of the measurement takes over, with a half-Hann fade so that its end
+//! doesn't ripple the response, and it starts a little before the peak, leaving out what comes
+//! earlier, such as the distortion products of a sweep.
+
+use std::f64::consts::PI;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::fft::Fft;
+use crate::png::Image;
+
+/// How much of the impulse response the window keeps before its peak, in seconds.
+const PRE_PEAK: f64 = 0.001;
+/// Part of the window faded out at its end.
+const FADE_OUT: f64 = 0.25;
+/// Smallest transform of the windowed response, which sets how finely the response is sampled.
+const MIN_FFT: usize = 65_536;
+/// Points of the curve per octave.
+pub const POINTS_PER_OCTAVE: usize = 48;
+
+/// A point of a magnitude response.
+#[derive(Clone, Copy, Debug)]
+pub struct Point {
+    /// In Hz.
+    pub frequency: f64,
+    /// Gain, in dB.
+    pub db: f64,
+}
+
+/// Cuts `length` frames out of `ir` around its largest sample, wrapping around its end, and
+/// fades both ends of the cut.
+pub fn window(ir: &[f32], length: usize, sample_rate: u32) -> Vec<f32> {
+    let Some(peak) = (0..ir.len()).max_by(|x, y| ir[*x].abs().total_cmp(&ir[*y].abs())) else {
+        return Vec::new();
+    };
+    let length = length.min(ir.len());
+    let pre = ((PRE_PEAK * sample_rate as f64) as usize).min(length / 2);
+    let fade_out = ((length - pre) as f64 * FADE_OUT) as usize;
+    let start = peak + ir.len() - pre;
+    (0..length)
+        .map(|n| {
+            let x = ir[(start + n) % ir.len()];
+            // Half-Hann fades, rising before the peak and falling over the end of the window.
+            let gain = if n < pre {
+                0.5 - 0.5 * (PI * n as f64 / pre as f64).cos()
+            } else if n >= length - fade_out {
+                let position = (n - (length - fade_out)) as f64 / fade_out as f64;
+                0.5 + 0.5 * (PI * position).cos()
+            } else {
+                1.0
+            };
+            x * gain as f32
+        })
+        .collect()
+}
+
+/// The power of each bin of the transform of `ir` from 0 Hz to half the rate, zero-padded, with
+/// the width of the bins in Hz.
+pub fn spectrum(ir: &[f32], sample_rate: u32) -> (Vec<f64>, f64) {
+    let size = ir.len().max(MIN_FFT).next_power_of_two();
+    let mut re = ir.to_vec();
+    re.resize(size, 0.0);
+    let mut im = vec![0.0; size];
+    Fft::new(size).forward(&mut re, &mut im);
+    let power = (0..=size / 2)
+        .map(|k| re[k] as f64 * re[k] as f64 + im[k] as f64 * im[k] as f64)
+        .collect();
+    (power, sample_rate as f64 / size as f64)
+}
+
+/// The response from `low` to `high` Hz on a logarithmic grid, each point the mean power of the
+/// bins within `1 / fraction` of an octave around it, or read between the two nearest bins for
+/// a `fraction` of 0.
+pub fn smooth(power: &[f64], bin: f64, low: f64, high: f64, fraction: f64) -> Vec<Point> {
+    let octaves = (high / low).log2();
+    let points = (octaves * POINTS_PER_OCTAVE as f64).ceil() as usize + 1;
+    let last = power.len().saturating_sub(1);
+    (0..points)
+        .map(|n| {
+            let frequency = low * 2f64.powf(n as f64 / POINTS_PER_OCTAVE as f64);
+            let frequency = frequency.min(high);
+            let position = frequency / bin;
+            let value = if fraction > 0.0 {
+                let half = 2f64.powf(0.5 / fraction);
+                let from = ((position / half).ceil() as usize).min(last);
+                let to = ((position * half).floor() as usize).clamp(from, last);
+                power[from..=to].iter().sum::<f64>() / (to - from + 1) as f64
+            } else {
+                let index = (position.floor() as usize).min(last);
+                let next = (index + 1).min(last);
+                let t = position - index as f64;
+                power[index] * (1.0 - t) + power[next] * t
+            };
+            Point {
+                frequency,
+                db: 10.0 * value.max(f64::MIN_POSITIVE).log10(),
+            }
+        })
+        .collect()
+}
+
+/// The gain of `curve` at `frequency`, read between the nearest points.
+pub fn gain_at(curve: &[Point], frequency: f64) -> Option<f64> {
+    let index = curve.windows(2).position(|x| x[1].frequency >= frequency)?;
+    let (a, b) = (curve[index], curve[index + 1]);
+    if frequency < a.frequency {
+        return None;
+    }
+    let t = (frequency / a.frequency).ln() / (b.frequency / a.frequency).ln();
+    Some(a.db + (b.db - a.db) * t.clamp(0.0, 1.0))
+}
+
+/// Where the response falls 3 dB under its gain at `reference` Hz, below and above it, found
+/// from `reference` outwards. Either is `None` if the response doesn't fall that far within the
+/// curve.
+pub fn edges(curve: &[Point], reference: f64) -> (Option<f64>, Option<f64>) {
+    let Some(level) = gain_at(curve, reference) else {
+        return (None, None);
+    };
+    let threshold = level - 3.0;
+    let start = curve.partition_point(|x| x.frequency < reference);
+    // Interpolated on the logarithmic frequency axis, between the last point above the
+    // threshold and the first one under it.
+    let crossing = |a: &Point, b: &Point| {
+        let t = (a.db - threshold) / (a.db - b.db);
+        a.frequency * (b.frequency / a.frequency).powf(t)
+    };
+    let below = (1..start.min(curve.len()))
+        .rev()
+        .find(|n| curve[*n - 1].db < threshold && curve[*n].db >= threshold)
+        .map(|n| crossing(&curve[n], &curve[n - 1]));
+    let above = (start.max(1)..curve.len())
+        .find(|n| curve[*n].db < threshold && curve[*n - 1].db >= threshold)
+        .map(|n| crossing(&curve[n - 1], &curve[n]));
+    (below, above)
+}
+
+/// The spread of the gain from `low` to `high` Hz, in dB.
+pub fn ripple(curve: &[Point], low: f64, high: f64) -> f64 {
+    let gains = curve
+        .iter()
+        .filter(|x| x.frequency >= low && x.frequency <= high)
+        .map(|x| x.db);
+    let (min, max) = gains.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), x| {
+        (min.min(x), max.max(x))
+    });
+    (max - min).max(0.0)
+}
+
+/// Writes `curve` as CSV rows of `frequency_hz,gain_db`, after a header.
+pub fn write_csv(path: &Path, curve: &[Point]) -> io::Result<()> {
+    let mut out = BufWriter::new(std::fs::File::create(path)?);
+    writeln!(out, "frequency_hz,gain_db")?;
+    for point in curve {
+        writeln!(out, "{:.3},{:.3}", point.frequency, point.db)?;
+    }
+    out.flush()
+}
+
+/// Plots `curve` on a logarithmic frequency axis, with lines at 1, 2 and 5 of every decade and
+/// every 10 dB, darker at the decades, over the 50 dB under the top of the curve.
+pub fn plot(path: &Path, curve: &[Point]) -> io::Result<()> {
+    const WIDTH: usize = 800;
+    const HEIGHT: usize = 400;
+    const RANGE: f64 = 50.0;
+    let (Some(first), Some(last)) = (curve.first(), curve.last()) else {
+        return Image::new(WIDTH, HEIGHT, WHITE).write(path);
+    };
+    let top =
+        (curve.iter().map(|x| x.db).fold(f64::NEG_INFINITY, f64::max) / 10.0 + 0.5).ceil() * 10.0;
+    let span = (last.frequency / first.frequency)
+        .ln()
+        .max(f64::MIN_POSITIVE);
+    let x = |frequency: f64| {
+        ((frequency / first.frequency).ln() / span * (WIDTH - 1) as f64).round() as i64
+    };
+    let y = |db: f64| ((top - db) / RANGE * (HEIGHT - 1) as f64).round() as i64;
+
+    let mut image = Image::new(WIDTH, HEIGHT, WHITE);
+    let mut decade = 10f64.powf(first.frequency.log10().floor());
+    while decade <= last.frequency {
+        for (multiple, color) in [(1.0, DARK), (2.0, LIGHT), (5.0, LIGHT)] {
+            let position = x(decade * multiple);
+            image.line((position, 0), (position, HEIGHT as i64), color);
+        }
+        decade *= 10.0;
+    }
+    for step in 0..=(RANGE / 10.0) as usize {
+        let position = y(top - step as f64 * 10.0);
+        image.line((0, position), (WIDTH as i64, position), LIGHT);
+    }
+    for pair in curve.windows(2) {
+        let from = (x(pair[0].frequency), y(pair[0].db));
+        let to = (x(pair[1].frequency), y(pair[1].db));
+        image.line(from, to, CURVE);
+        image.line((from.0, from.1 + 1), (to.0, to.1 + 1), CURVE);
+    }
+    image.write(path)
+}
+
+const WHITE: [u8; 3] = [255, 255, 255];
+const LIGHT: [u8; 3] = [220, 220, 220];
+const DARK: [u8; 3] = [150, 150, 150];
+const CURVE: [u8; 3] = [20, 80, 200];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A band-pass from 100 Hz to 10 kHz, each edge of the first order, on the grid of
+    /// [`smooth`].
+    fn band_pass() -> Vec<Point> {
+        let flat = vec![1.0; 1_025];
+        smooth(&flat, 24.0, 10.0, 24_000.0, 0.0)
+            .into_iter()
+            .map(|Point { frequency, .. }| {
+                let power = 1.0
+                    / (1.0 + (frequency / 10_000.0).powi(2))
+                    / (1.0 + (100.0 / frequency).powi(2));
+                Point {
+                    frequency,
+                    db: 10.0 * power.log10(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_window_starts_a_little_before_the_peak() {
+        let mut ir = vec![0.0; 2_000];
+        ir[500] = -1.0;
+        ir[1_999] = 0.5;
+        let cut = window(&ir, 200, 48_000);
+        assert_eq!(cut.len(), 200);
+        assert_eq!(cut[48], -1.0);
+        assert!(cut.iter().enumerate().all(|(n, x)| n == 48 || *x == 0.0));
+
+        // A peak at the start takes what comes before it from the end.
+        ir[500] = 0.0;
+        ir[10] = 1.0;
+        let cut = window(&ir, 200, 48_000);
+        assert_eq!(cut[48], 1.0);
+        assert!(cut[37] > 0.0 && cut[37] < 0.5, "{}", cut[37]);
+        assert!(window(&[], 200, 48_000).is_empty());
+    }
+
+    #[test]
+    fn an_impulse_has_a_flat_response() {
+        let (power, bin) = spectrum(&[1.0], 48_000);
+        assert_eq!(power.len(), MIN_FFT / 2 + 1);
+        assert_eq!(bin, 48_000.0 / MIN_FFT as f64);
+        let curve = smooth(&power, bin, 20.0, 20_000.0, 3.0);
+        assert_eq!(curve.len(), 480);
+        assert_eq!(curve[0].frequency, 20.0);
+        assert_eq!(curve[479].frequency, 20_000.0);
+        assert!(curve.iter().all(|x| x.db.abs() < 1e-6));
+        assert_eq!(ripple(&curve, 20.0, 20_000.0), 0.0);
+    }
+
+    #[test]
+    fn smoothing_averages_over_a_fraction_of_an_octave() {
+        // Every other bin at 0 dB, the rest silent, which averages to -3 dB.
+        let power: Vec<f64> = (0..4_097).map(|k| (k % 2) as f64).collect();
+        let curve = smooth(&power, 1.0, 100.0, 1_000.0, 3.0);
+        assert!(
+            curve.iter().all(|x| (x.db + 3.0).abs() < 0.25),
+            "{:?}",
+            curve[0]
+        );
+        let curve = smooth(&power, 1.0, 101.0, 101.0, 0.0);
+        assert!(curve[0].db.abs() < 1e-9);
+    }
+
+    #[test]
+    fn the_band_edges_are_found_3_db_down() {
+        let curve = band_pass();
+        assert!(gain_at(&curve, 1_000.0).unwrap().abs() < 0.1);
+        assert_eq!(gain_at(&curve, 5.0), None);
+        assert_eq!(gain_at(&curve, 30_000.0), None);
+        let (below, above) = edges(&curve, 1_000.0);
+        let (below, above) = (below.unwrap(), above.unwrap());
+        assert!((below / 100.0 - 1.0).abs() < 0.03, "{}", below);
+        assert!((above / 10_000.0 - 1.0).abs() < 0.03, "{}", above);
+        assert_eq!(edges(&curve[200..], 1_000.0), (None, Some(above)));
+        assert!((ripple(&curve, 100.0, 10_000.0) - 3.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn curves_are_written_as_csv_and_plotted() {
+        let curve = band_pass();
+        let directory = std::env::temp_dir();
+        let csv = directory.join(format!("response-{}.csv", std::process::id()));
+        write_csv(&csv, &curve[..2]).unwrap();
+        let text = std::fs::read_to_string(&csv).unwrap();
+        std::fs::remove_file(&csv).unwrap();
+        let expected = format!(
+            "frequency_hz,gain_db\n10.000,{:.3}\n10.145,{:.3}\n",
+            curve[0].db, curve[1].db
+        );
+        assert_eq!(text, expected);
+
+        let png = directory.join(format!("response-{}.png", std::process::id()));
+        plot(&png, &curve).unwrap();
+        let bytes = std::fs::read(&png).unwrap();
+        std::fs::remove_file(&png).unwrap();
+        assert_eq!(bytes[16..24], [0, 0, 3, 32, 0, 0, 1, 144]);
+    }
+}
@@ -0,0 +1,241 @@
+//! Retroactive capture: a rolling window of the input that can be saved after the fact.
+//!
+//! The input callback writes every block into a ring of 16-bit samples, which halves the memory
+//! of long windows, and another thread can copy the whole window out at any time without
+//! stopping it. Both sides only use atomics: the callback announces how far it's about to write
+//! before writing, and publishes how far it wrote after, so a copy that raced with the callback
+//! knows which of its oldest samples may have been overwritten meanwhile, and drops them.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{fence, AtomicI16, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::wav::WavWriter;
+
+/// The last seconds of an interleaved stream.
+pub struct RetroBuffer {
+    samples: Box<[AtomicI16]>,
+    /// Samples the writer has started writing, and finished writing, since the start.
+    writing: AtomicU64,
+    written: AtomicU64,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl RetroBuffer {
+    /// Allocates a window of `seconds` of a stream of `channels` channels at `sample_rate`.
+    pub fn new(seconds: f32, channels: u16, sample_rate: u32) -> Self {
+        let frames = (seconds.max(0.0) as f64 * sample_rate as f64) as usize;
+        RetroBuffer {
+            samples: (0..frames.max(1) * channels as usize)
+                .map(|_| AtomicI16::new(0))
+                .collect(),
+            writing: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            channels,
+            sample_rate,
+        }
+    }
+
+    /// Writes an interleaved block of whole frames. Only one thread may write.
+    pub fn push(&self, data: &[f32]) {
+        let start = self.written.load(Ordering::Relaxed);
+        let end = start + data.len() as u64;
+        self.writing.store(end, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let len = self.samples.len() as u64;
+        for (index, x) in (start..end).zip(data) {
+            let sample = (x.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+            self.samples[(index % len) as usize].store(sample, Ordering::Relaxed);
+        }
+        self.written.store(end, Ordering::Release);
+    }
+
+    /// Copies the window as it is, from its oldest consistent frame up to the last block
+    /// written.
+    pub fn snapshot(&self) -> Vec<f32> {
+        let len = self.samples.len() as u64;
+        let end = self.written.load(Ordering::Acquire);
+        let start = end.saturating_sub(len);
+        let copy: Vec<i16> = (start..end)
+            .map(|index| self.samples[(index % len) as usize].load(Ordering::Relaxed))
+            .collect();
+        fence(Ordering::Acquire);
+        // The samples the writer may have reached meanwhile overwrote the oldest ones.
+        let overwritten = self.writing.load(Ordering::Relaxed).saturating_sub(len);
+        let mut valid = overwritten.max(start) - start;
+        valid += (self.channels as u64 - valid % self.channels as u64) % self.channels as u64;
+        copy[(valid as usize).min(copy.len())..]
+            .iter()
+            .map(|x| *x as f32 / i16::MAX as f32)
+            .collect()
+    }
+
+    /// Saves the window to a timestamped WAV file in `directory`, returning its path and the
+    /// seconds it holds. Saves within the same second get numbered files, e.g.
+    /// "retro-20240131-235959-2.wav", rather than replacing the first.
+    pub fn save(&self, directory: &Path) -> anyhow::Result<(PathBuf, f64)> {
+        self.save_at(directory, SystemTime::now())
+    }
+
+    /// [`save`](Self::save), stamping the file with `time`.
+    fn save_at(&self, directory: &Path, time: SystemTime) -> anyhow::Result<(PathBuf, f64)> {
+        let samples = self.snapshot();
+        let stamp = timestamp(time);
+        let mut number = 1;
+        let (path, mut writer) = loop {
+            let path = directory.join(file_name(&stamp, number));
+            match WavWriter::create_new(&path, self.channels, self.sample_rate) {
+                Ok(writer) => break (path, writer),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => number += 1,
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("failed to create \"{}\"", path.display()))
+                }
+            }
+        };
+        let write = || {
+            writer.write(&samples)?;
+            let frames = writer.frames();
+            writer.finish().map(|_| frames)
+        };
+        let frames = write().with_context(|| format!("failed to write \"{}\"", path.display()))?;
+        let seconds = frames as f64 / self.sample_rate as f64;
+        Ok((path, seconds))
+    }
+}
+
+/// The name of the `number`th file saved at `stamp`, numbered from the second.
+fn file_name(stamp: &str, number: usize) -> String {
+    match number {
+        1 => format!("retro-{}.wav", stamp),
+        _ => format!("retro-{}-{}.wav", stamp, number),
+    }
+}
+
+/// Formats a time as a UTC date and time for file names, e.g. "20240131-235959".
+fn timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+    // Civil date from the days since 1970-01-01, after Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The 16-bit steps of `samples`.
+    fn steps(samples: &[f32]) -> Vec<i16> {
+        samples
+            .iter()
+            .map(|x| (x * i16::MAX as f32).round() as i16)
+            .collect()
+    }
+
+    #[test]
+    fn the_window_holds_the_last_samples_in_16_bits() {
+        let buffer = RetroBuffer::new(0.004, 1, 1_000);
+        buffer.push(&[0.5, 2.0]);
+        assert_eq!(steps(&buffer.snapshot()), [16_384, i16::MAX]);
+        buffer.push(&[-2.0, 0.0, 0.25]);
+        assert_eq!(
+            steps(&buffer.snapshot()),
+            [i16::MAX, i16::MIN + 1, 0, 8_192]
+        );
+    }
+
+    #[test]
+    fn samples_the_writer_may_have_reached_are_dropped_by_whole_frames() {
+        let buffer = RetroBuffer::new(0.004, 2, 1_000);
+        buffer.push(&[0.0; 8]);
+        // As if the writer had announced 3 more samples while the copy was made.
+        buffer.writing.store(11, Ordering::Relaxed);
+        assert_eq!(buffer.snapshot().len(), 4);
+    }
+
+    #[test]
+    fn snapshots_racing_the_writer_stay_consistent() {
+        let buffer = std::sync::Arc::new(RetroBuffer::new(0.1, 2, 1_000));
+        let writer = {
+            let buffer = buffer.clone();
+            std::thread::spawn(move || {
+                for block in 0..2_000u32 {
+                    let data: Vec<f32> = (0..16)
+                        .flat_map(|x| {
+                            let step = ((block * 16 + x) % 30_000) as f32;
+                            [step / i16::MAX as f32; 2]
+                        })
+                        .collect();
+                    buffer.push(&data);
+                }
+            })
+        };
+        while !writer.is_finished() {
+            let snapshot = steps(&buffer.snapshot());
+            assert_eq!(snapshot.len() % 2, 0);
+            for frames in snapshot.chunks(2).collect::<Vec<_>>().windows(2) {
+                assert_eq!(frames[0][0], frames[0][1]);
+                assert_eq!((frames[0][0] + 1) % 30_000, frames[1][0]);
+            }
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn saves_in_the_same_second_get_their_own_files() {
+        let directory = std::env::temp_dir().join(format!("retro-save-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let buffer = RetroBuffer::new(0.01, 1, 1_000);
+        buffer.push(&[0.5; 10]);
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1_706_745_599);
+        let names: Vec<String> = (0..3)
+            .map(|_| buffer.save_at(&directory, time).unwrap().0)
+            .map(|x| x.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(
+            names,
+            [
+                "retro-20240131-235959.wav",
+                "retro-20240131-235959-2.wav",
+                "retro-20240131-235959-3.wav",
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamps_are_utc_dates() {
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1_706_745_599);
+        assert_eq!(timestamp(time), "20240131-235959");
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101-000000");
+    }
+}
@@ -0,0 +1,614 @@
+//! The ring buffers between every input and output, sized from their share of the latency.
+//!
+//! A ring starts with the latency in silence, and has room for as much again, which the output
+//! can fall behind by while the input's clock runs fast, as it can get ahead by while it runs
+//! slow. On top of that it takes the worst burst of callbacks: two blocks of the input in a row
+//! before the output reads, and two of the output the other way round.
+//!
+//! How much of the latency a ring starts with is its [`Prime`]: all of it by default, or less to
+//! start with less delay, at the cost of underruns until the input catches up. Either way, the
+//! output measures the fill of its rings as it plays, which is the delay they actually add.
+//!
+//! The fill is read before every block, and taken at every interval of the status line as its
+//! lowest, highest and mean over the interval. A fill that drifts, as it does when the clocks of
+//! the input and the output keep apart, gets closer to an xrun at every interval: with a
+//! [`FillBand`] given, a ring whose mean stays outside it for longer than [`ALARM_AFTER`] is
+//! warned about once, with the way it goes, until it comes back well inside.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cpal::{BufferSize, StreamConfig};
+use ringbuf::traits::{Producer, Split};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+
+use crate::error::EngineError;
+use crate::watchdog;
+
+/// Latency above which the delay is more likely a typo than wanted.
+pub const LONG_LATENCY: Duration = Duration::from_secs(5);
+/// Largest ring, in bytes, so that a huge latency or channel count fails instead of swapping.
+pub const MAX_RING_BYTES: usize = 512 << 20;
+/// How long the underruns of rings that start empty, or nearly, are expected rather than
+/// reported.
+pub const SETTLE: Duration = Duration::from_secs(1);
+/// How long the mean fill of a ring has to stay outside its band before it's warned about.
+pub const ALARM_AFTER: Duration = Duration::from_secs(3);
+/// How far inside the band a fill has to come back, as a share of its width, before it's warned
+/// about again, so that a fill hovering on an edge isn't warned about at every crossing.
+pub const HYSTERESIS: f32 = 0.1;
+
+/// How much of the latency a ring starts with in silence: "full", "half" or "none".
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Prime {
+    #[default]
+    Full,
+    Half,
+    /// Nothing, for the least delay, with underruns until the input catches up.
+    None,
+}
+
+impl FromStr for Prime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Prime::Full),
+            "half" => Ok(Prime::Half),
+            "none" => Ok(Prime::None),
+            _ => Err(format!(
+                "expected \"full\", \"half\" or \"none\", got \"{}\"",
+                s
+            )),
+        }
+    }
+}
+
+impl Prime {
+    /// The frames of silence a ring delayed by `frames` starts with.
+    pub fn frames(self, frames: usize) -> usize {
+        match self {
+            Prime::Full => frames,
+            Prime::Half => frames / 2,
+            Prime::None => 0,
+        }
+    }
+}
+
+/// Samples of a ring from one input to one output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RingSize {
+    /// Silence the ring starts with, the latency.
+    pub prefill: usize,
+    pub capacity: usize,
+}
+
+impl RingSize {
+    /// Sizes the ring from an input of `input` to an output of `output`, delayed by `frames` of
+    /// the output, of which it starts with `prime`. Fails for delays under one block of the
+    /// output, or rings too big.
+    pub fn new(
+        frames: usize,
+        prime: Prime,
+        input: &StreamConfig,
+        output: &StreamConfig,
+    ) -> Result<Self, EngineError> {
+        let rate = output.sample_rate.0 as f64;
+        let channels = output.channels as usize;
+        let latency = frames as f64 * 1_000.0 / rate;
+        let block = block_frames(output);
+        if frames < block {
+            return Err(EngineError::InvalidArgument(format!(
+                "a latency of {:.1} ms is under one block of the output, {} frames or {:.1} ms: \
+                 raise `--latency` or lower `--buffer-size`",
+                latency,
+                block,
+                block as f64 * 1_000.0 / rate
+            )));
+        }
+        // Blocks of the input, in frames of the output once resampled.
+        let input_block =
+            (block_frames(input) as f64 * rate / input.sample_rate.0 as f64).ceil() as usize;
+        let burst = 2 * (input_block + block);
+        let size = (2 * frames)
+            .checked_add(burst)
+            .and_then(|x| x.checked_mul(channels))
+            .filter(|x| x.saturating_mul(size_of::<f32>()) <= MAX_RING_BYTES);
+        let Some(capacity) = size else {
+            return Err(EngineError::InvalidArgument(format!(
+                "a latency of {:.1} ms on {} channels at {} Hz needs rings of more than {} MiB",
+                latency,
+                channels,
+                output.sample_rate.0,
+                MAX_RING_BYTES >> 20
+            )));
+        };
+        Ok(RingSize {
+            prefill: prime.frames(frames) * channels,
+            capacity,
+        })
+    }
+
+    /// Creates the ring, prefilled.
+    pub fn ring(&self) -> Result<(HeapProd<f32>, HeapCons<f32>), EngineError> {
+        let (mut producer, consumer) = HeapRb::<f32>::new(self.capacity).split();
+        let pushed = producer.push_slice(&vec![0.0; self.prefill]);
+        if pushed < self.prefill {
+            return Err(EngineError::InvalidArgument(format!(
+                "a ring of {} samples can't hold the {} of the latency",
+                self.capacity, self.prefill
+            )));
+        }
+        Ok((producer, consumer))
+    }
+}
+
+/// Milliseconds of a stream of `config` that `samples` interleaved samples hold.
+pub fn fill_milliseconds(samples: usize, config: &StreamConfig) -> f32 {
+    let frames = samples as f64 / config.channels as f64;
+    (frames * 1_000.0 / config.sample_rate.0 as f64) as f32
+}
+
+/// The fill the rings should stay within, `<low-ms>:<high-ms>` on the command line, e.g. "8:14".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FillBand {
+    pub low: f32,
+    pub high: f32,
+}
+
+impl FromStr for FillBand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid fill band \"{}\", expected <low-ms>:<high-ms>", s);
+        let (low, high) = s.split_once(':').ok_or_else(invalid)?;
+        let low = low.parse::<f32>().map_err(|_| invalid())?;
+        let high = high.parse::<f32>().map_err(|_| invalid())?;
+        if !(0.0 <= low && low < high && high.is_finite()) {
+            return Err(format!(
+                "invalid fill band \"{}\", expected a low under the high, from 0 ms",
+                s
+            ));
+        }
+        Ok(FillBand { low, high })
+    }
+}
+
+impl FillBand {
+    /// Whether `milliseconds` is over the band, `Some(true)`, under it, `Some(false)`, or within.
+    fn side(&self, milliseconds: f32) -> Option<bool> {
+        match milliseconds {
+            x if x > self.high => Some(true),
+            x if x < self.low => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Whether `milliseconds` is inside the band by [`HYSTERESIS`] of its width.
+    fn is_well_inside(&self, milliseconds: f32) -> bool {
+        let margin = HYSTERESIS * (self.high - self.low);
+        (self.low + margin..=self.high - margin).contains(&milliseconds)
+    }
+}
+
+/// A ring whose fill stayed outside its band for longer than [`ALARM_AFTER`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Alarm {
+    /// Whether the fill is over the band, heading for overruns, or under it, for underruns.
+    pub over: bool,
+    /// The mean fill of the last interval, in milliseconds.
+    pub fill: f32,
+    /// How long it has been outside the band.
+    pub seconds: f64,
+    /// How fast it went since it left the band, in milliseconds per second.
+    pub trend: f32,
+}
+
+impl Alarm {
+    /// The way the fill goes, e.g. "rising by 0.40 ms/s".
+    pub fn describe_trend(&self) -> String {
+        match self.trend {
+            x if x > 0.0 => format!("rising by {:.2} ms/s", x),
+            x if x < 0.0 => format!("falling by {:.2} ms/s", -x),
+            _ => "holding".to_string(),
+        }
+    }
+}
+
+/// A fill outside its band.
+#[derive(Clone, Copy, Debug)]
+struct Outside {
+    over: bool,
+    /// How long it has been outside, counting the whole interval it left in.
+    seconds: f64,
+    /// Its first fill outside, and the time since.
+    from: f32,
+    since: f64,
+}
+
+/// Warns once about a fill outside its band for longer than [`ALARM_AFTER`].
+#[derive(Clone, Debug)]
+pub struct BandAlarm {
+    band: FillBand,
+    outside: Option<Outside>,
+    /// Whether it was warned about since it was last well inside the band.
+    warned: bool,
+}
+
+impl BandAlarm {
+    pub fn new(band: FillBand) -> Self {
+        BandAlarm {
+            band,
+            outside: None,
+            warned: false,
+        }
+    }
+
+    pub fn band(&self) -> FillBand {
+        self.band
+    }
+
+    /// Takes the mean `fill` of an interval of `elapsed`, in milliseconds, returning the alarm
+    /// if it's time for it.
+    pub fn update(&mut self, fill: f32, elapsed: Duration) -> Option<Alarm> {
+        let Some(over) = self.band.side(fill) else {
+            self.outside = None;
+            self.warned &= !self.band.is_well_inside(fill);
+            return None;
+        };
+        let outside = match self.outside {
+            Some(outside) if outside.over == over => Outside {
+                seconds: outside.seconds + elapsed.as_secs_f64(),
+                since: outside.since + elapsed.as_secs_f64(),
+                ..outside
+            },
+            _ => Outside {
+                over,
+                seconds: elapsed.as_secs_f64(),
+                from: fill,
+                since: 0.0,
+            },
+        };
+        self.outside = Some(outside);
+        if self.warned || outside.seconds <= ALARM_AFTER.as_secs_f64() {
+            return None;
+        }
+        self.warned = true;
+        Some(Alarm {
+            over,
+            fill,
+            seconds: outside.seconds,
+            trend: match outside.since > 0.0 {
+                true => ((fill - outside.from) as f64 / outside.since) as f32,
+                false => 0.0,
+            },
+        })
+    }
+}
+
+/// The fill of a ring over an interval, in interleaved samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FillWindow {
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+}
+
+/// What the reporting thread took so far.
+struct Taken {
+    window: Option<FillWindow>,
+    alarm: Option<BandAlarm>,
+}
+
+/// The fill of a ring from one input to one output, shared between the output's callback and
+/// the status line.
+pub struct FillStats {
+    pub input: &'static str,
+    pub output: &'static str,
+    channels: usize,
+    /// The rate the output reads at.
+    sample_rate: AtomicU32,
+    /// The lowest, the highest and the sum of the fills since the last interval, and how many.
+    min: AtomicUsize,
+    max: AtomicUsize,
+    sum: AtomicU64,
+    reads: AtomicU64,
+    taken: Mutex<Taken>,
+}
+
+impl FillStats {
+    /// The stats of the ring from `input` to `output` of `config`, warned about outside `band`.
+    pub fn new(
+        input: &'static str,
+        output: &'static str,
+        config: &StreamConfig,
+        band: Option<FillBand>,
+    ) -> Self {
+        FillStats {
+            input,
+            output,
+            channels: config.channels as usize,
+            sample_rate: AtomicU32::new(config.sample_rate.0),
+            min: AtomicUsize::new(usize::MAX),
+            max: AtomicUsize::new(0),
+            sum: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+            taken: Mutex::new(Taken {
+                window: None,
+                alarm: band.map(BandAlarm::new),
+            }),
+        }
+    }
+
+    /// Adds a read of `occupied` samples, from the callback.
+    pub fn record(&self, occupied: usize) {
+        self.min.fetch_min(occupied, Ordering::Relaxed);
+        self.max.fetch_max(occupied, Ordering::Relaxed);
+        self.sum.fetch_add(occupied as u64, Ordering::Relaxed);
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Ends an interval of `elapsed`, returning its fill, and the alarm if it's time for it. An
+    /// interval without reads keeps the fill of the last one.
+    pub fn take(&self, elapsed: Duration) -> (Option<FillWindow>, Option<Alarm>) {
+        let reads = self.reads.swap(0, Ordering::Relaxed);
+        let sum = self.sum.swap(0, Ordering::Relaxed);
+        let min = self.min.swap(usize::MAX, Ordering::Relaxed);
+        let max = self.max.swap(0, Ordering::Relaxed);
+        let mut taken = self.taken.lock().unwrap();
+        if reads > 0 {
+            taken.window = Some(FillWindow {
+                min: min.min(max),
+                max,
+                mean: sum as f64 / reads as f64,
+            });
+        }
+        let window = taken.window;
+        let fill = window.map(|x| self.milliseconds(x.mean));
+        let alarm = taken
+            .alarm
+            .as_mut()
+            .zip(fill)
+            .and_then(|(alarm, fill)| alarm.update(fill, elapsed));
+        (window, alarm)
+    }
+
+    /// Milliseconds that `samples` interleaved samples of the ring hold.
+    pub fn milliseconds(&self, samples: f64) -> f32 {
+        let frames = samples / self.channels.max(1) as f64;
+        (frames * 1_000.0 / self.sample_rate.load(Ordering::Relaxed) as f64) as f32
+    }
+
+    /// The ring's field of the status line, e.g. "1028 samples, 10.7 ms (9.8 to 11.5)", and
+    /// whether its fill is outside the band.
+    pub fn describe(&self) -> (String, bool) {
+        let taken = self.taken.lock().unwrap();
+        let Some(window) = taken.window else {
+            return ("-".to_string(), false);
+        };
+        let mean = self.milliseconds(window.mean);
+        let text = format!(
+            "{:.0} samples, {:.1} ms ({:.1} to {:.1})",
+            window.mean,
+            mean,
+            self.milliseconds(window.min as f64),
+            self.milliseconds(window.max as f64)
+        );
+        let outside = taken
+            .alarm
+            .as_ref()
+            .is_some_and(|x| x.band().side(mean).is_some());
+        (text, outside)
+    }
+}
+
+/// Reads the fill of a ring before every block an output reads from it.
+pub struct FillMeter {
+    stats: Arc<FillStats>,
+}
+
+impl FillMeter {
+    pub fn new(stats: Arc<FillStats>) -> Self {
+        FillMeter { stats }
+    }
+
+    /// Measures a ring read at `sample_rate` from now on.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.stats.sample_rate.store(sample_rate, Ordering::Relaxed);
+    }
+
+    /// Takes the `occupied` samples of the ring before a block is read.
+    pub fn measure(&mut self, occupied: usize) {
+        self.stats.record(occupied);
+    }
+}
+
+/// Frames of every callback of a stream of `config`, as far as it's known.
+pub fn block_frames(config: &StreamConfig) -> usize {
+    match config.buffer_size {
+        BufferSize::Fixed(frames) => frames as usize,
+        BufferSize::Default => {
+            (watchdog::period(config).as_secs_f64() * config.sample_rate.0 as f64) as usize
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::traits::Observer;
+
+    use super::*;
+
+    fn config(channels: u16, sample_rate: u32, frames: u32) -> StreamConfig {
+        StreamConfig {
+            channels,
+            sample_rate: cpal::SampleRate(sample_rate),
+            buffer_size: BufferSize::Fixed(frames),
+        }
+    }
+
+    #[test]
+    fn rings_hold_the_latency_twice_and_the_bursts() {
+        let (input, output) = (config(1, 44_100, 441), config(2, 48_000, 256));
+        let size = RingSize::new(480, Prime::Full, &input, &output).unwrap();
+        // Two blocks each of the input, 480 frames once resampled, and of the output.
+        let burst = 2 * (480 + 256);
+        assert_eq!(
+            size,
+            RingSize {
+                prefill: 960,
+                capacity: (2 * 480 + burst) * 2,
+            }
+        );
+        let (producer, consumer) = size.ring().unwrap();
+        assert_eq!(consumer.occupied_len(), 960);
+        assert_eq!(producer.capacity().get(), size.capacity);
+    }
+
+    #[test]
+    fn latencies_under_a_block_are_rejected() {
+        let output = config(2, 48_000, 512);
+        let err = RingSize::new(480, Prime::Full, &output, &output).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "a latency of 10.0 ms is under one block of the output, 512 frames or 10.7 ms: \
+             raise `--latency` or lower `--buffer-size`"
+        );
+    }
+
+    #[test]
+    fn rings_too_big_are_rejected() {
+        let output = config(64, 192_000, 256);
+        let err = RingSize::new(192_000 * 60, Prime::Full, &output, &output).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "a latency of 60000.0 ms on 64 channels at 192000 Hz needs rings of more than 512 MiB"
+        );
+        let err = RingSize::new(usize::MAX / 2, Prime::Full, &output, &output).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("needs rings of more than 512 MiB"));
+    }
+
+    #[test]
+    fn blocks_of_the_default_size_take_the_assumed_period() {
+        let mut output = config(2, 48_000, 256);
+        assert_eq!(block_frames(&output), 256);
+        output.buffer_size = BufferSize::Default;
+        assert_eq!(block_frames(&output), 2400);
+    }
+
+    #[test]
+    fn primes_parse_and_take_their_share_of_the_latency() {
+        assert_eq!("half".parse(), Ok(Prime::Half));
+        assert_eq!(
+            "most".parse::<Prime>(),
+            Err("expected \"full\", \"half\" or \"none\", got \"most\"".to_string())
+        );
+        let output = config(2, 48_000, 256);
+        let prefill = |prime| RingSize::new(481, prime, &output, &output).unwrap();
+        assert_eq!(prefill(Prime::Half).prefill, 480);
+        assert_eq!(prefill(Prime::None).prefill, 0);
+        // The room for the whole latency stays.
+        assert_eq!(prefill(Prime::None).capacity, prefill(Prime::Full).capacity);
+    }
+
+    #[test]
+    fn fills_are_measured_in_milliseconds() {
+        assert_eq!(fill_milliseconds(960, &config(2, 48_000, 256)), 10.0);
+    }
+
+    #[test]
+    fn fill_bands_parse_from_a_low_under_the_high() {
+        assert_eq!(
+            "8:14".parse(),
+            Ok(FillBand {
+                low: 8.0,
+                high: 14.0
+            })
+        );
+        assert_eq!(
+            "8".parse::<FillBand>(),
+            Err("invalid fill band \"8\", expected <low-ms>:<high-ms>".to_string())
+        );
+        for s in ["14:8", "-1:8", "8:inf"] {
+            assert_eq!(
+                s.parse::<FillBand>(),
+                Err(format!(
+                    "invalid fill band \"{}\", expected a low under the high, from 0 ms",
+                    s
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn fills_outside_the_band_for_long_are_warned_about_once() {
+        let mut alarm = BandAlarm::new("8:14".parse().unwrap());
+        let second = Duration::from_secs(1);
+        assert_eq!(alarm.update(11.0, second), None);
+        for fill in [15.0, 16.0, 17.0] {
+            assert_eq!(alarm.update(fill, second), None);
+        }
+        let warning = alarm.update(18.0, second).unwrap();
+        assert_eq!(
+            warning,
+            Alarm {
+                over: true,
+                fill: 18.0,
+                seconds: 4.0,
+                trend: 1.0,
+            }
+        );
+        assert_eq!(warning.describe_trend(), "rising by 1.00 ms/s");
+        assert_eq!(alarm.update(19.0, second), None);
+        // Back inside, but not by the hysteresis, is still the same drift.
+        assert_eq!(alarm.update(13.8, second), None);
+        for _ in 0..5 {
+            assert_eq!(alarm.update(15.0, second), None);
+        }
+        // Well inside, it's warned about again.
+        assert_eq!(alarm.update(11.0, second), None);
+        for fill in [7.5, 7.0, 6.5] {
+            assert_eq!(alarm.update(fill, second), None);
+        }
+        let warning = alarm.update(6.0, second).unwrap();
+        assert!(!warning.over);
+        assert_eq!(warning.describe_trend(), "falling by 0.50 ms/s");
+    }
+
+    #[test]
+    fn fill_stats_take_the_lowest_highest_and_mean_of_an_interval() {
+        let band = "8:14".parse().unwrap();
+        let stats = Arc::new(FillStats::new(
+            "input",
+            "output",
+            &config(2, 48_000, 256),
+            Some(band),
+        ));
+        assert_eq!(stats.describe(), ("-".to_string(), false));
+        let mut meter = FillMeter::new(stats.clone());
+        for occupied in [960, 1_920, 1_440] {
+            meter.measure(occupied);
+        }
+        let window = FillWindow {
+            min: 960,
+            max: 1_920,
+            mean: 1_440.0,
+        };
+        assert_eq!(stats.take(Duration::from_secs(1)), (Some(window), None));
+        assert_eq!(
+            stats.describe(),
+            ("1440 samples, 15.0 ms (10.0 to 20.0)".to_string(), true)
+        );
+        // An interval without reads keeps the last fill, read at the new rate from then on.
+        meter.set_sample_rate(96_000);
+        assert_eq!(stats.take(Duration::from_secs(1)), (Some(window), None));
+        assert_eq!(
+            stats.describe(),
+            ("1440 samples, 7.5 ms (5.0 to 10.0)".to_string(), true)
+        );
+    }
+}
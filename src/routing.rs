@@ -0,0 +1,282 @@
+//! The routing of an input's channels to an output, swappable during a run, e.g. to solo
+//! channels.
+//!
+//! The active routing is a [`Matrix`]: the weight of the normal routing, the one the
+//! [`Converter`](crate::adapter::Converter) sets up from the configurations, and a gain from
+//! every input channel to every output channel on top. The control thread publishes a new one
+//! through a [`ParamStore`], and the input callback crossfades to it over [`CROSSFADE`], so that
+//! switching never clicks. While the normal routing is the whole of it, the callback does nothing
+//! more than check for a new one.
+//!
+//! [`Solo`] builds on it: soloing input channels routes each of them to every output channel,
+//! summed, and soloing none restores the normal routing exactly.
+
+use std::time::Duration;
+
+use crate::params::{ParamReader, ParamStore, ParamWriter};
+
+/// How long a change of routing takes.
+pub const CROSSFADE: Duration = Duration::from_millis(10);
+
+/// Routing from `inputs` channels to `outputs` channels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Matrix {
+    inputs: usize,
+    outputs: usize,
+    /// Weight of the normal routing.
+    normal: f32,
+    /// Gain from input channel `i` to output channel `o` at `o * inputs + i`.
+    gains: Vec<f32>,
+}
+
+impl Matrix {
+    /// The normal routing alone.
+    pub fn normal(inputs: usize, outputs: usize) -> Self {
+        Matrix {
+            inputs,
+            outputs,
+            normal: 1.0,
+            gains: vec![0.0; inputs * outputs],
+        }
+    }
+
+    /// Every channel of `channels` to every output channel, summed, and nothing else.
+    pub fn soloed(inputs: usize, outputs: usize, channels: &[usize]) -> Self {
+        let mut matrix = Matrix {
+            normal: 0.0,
+            ..Matrix::normal(inputs, outputs)
+        };
+        for output in 0..outputs {
+            for &input in channels {
+                matrix.gains[output * inputs + input] = 1.0;
+            }
+        }
+        matrix
+    }
+
+    pub fn is_normal(&self) -> bool {
+        self.normal == 1.0 && self.gains.iter().all(|x| *x == 0.0)
+    }
+
+    /// The gain from input channel `input` to output channel `output`.
+    pub fn gain(&self, input: usize, output: usize) -> f32 {
+        self.gains[output * self.inputs + input]
+    }
+
+    /// Sets `self` to the way from `from` to `to` at `position`, from 0 to 1. All three have the
+    /// same channels.
+    fn blend(&mut self, from: &Matrix, to: &Matrix, position: f32) {
+        self.normal = from.normal + (to.normal - from.normal) * position;
+        for ((x, from), to) in self.gains.iter_mut().zip(&from.gains).zip(&to.gains) {
+            *x = from + (to - from) * position;
+        }
+    }
+
+    /// Routes the frame `input`, whose normal routing is `normal`, into `output`.
+    fn route(&self, input: &[f32], normal: &[f32], output: &mut [f32]) {
+        for (index, (y, normal)) in output.iter_mut().zip(normal).enumerate() {
+            let gains = &self.gains[index * self.inputs..][..self.inputs];
+            *y = self.normal * normal + gains.iter().zip(input).map(|(g, x)| g * x).sum::<f32>();
+        }
+    }
+}
+
+/// The input callback's end of a swappable routing.
+pub struct Router {
+    reader: ParamReader<Matrix>,
+    generation: u64,
+    /// The routing as it was when the crossfade started, and as it is now.
+    from: Matrix,
+    current: Matrix,
+    /// Frames into the crossfade, and in all of it.
+    position: usize,
+    length: usize,
+    routed: Vec<f32>,
+}
+
+impl Router {
+    fn new(reader: ParamReader<Matrix>, initial: Matrix, sample_rate: u32) -> Self {
+        let length = (CROSSFADE.as_secs_f64() * sample_rate as f64).max(1.0) as usize;
+        Router {
+            reader,
+            generation: 0,
+            from: initial.clone(),
+            current: initial,
+            position: length,
+            length,
+            routed: Vec::new(),
+        }
+    }
+
+    /// Routes the frames of `input` whose normal routing is `normal`, returning `normal` itself
+    /// while the routing is the normal one. Only allocates for blocks larger than any before.
+    pub fn process<'a>(&'a mut self, input: &[f32], normal: &'a [f32]) -> &'a [f32] {
+        let snapshot = self.reader.load();
+        let target = snapshot.value();
+        if snapshot.generation() != self.generation {
+            self.generation = snapshot.generation();
+            self.from.clone_from(&self.current);
+            self.position = 0;
+        }
+        if self.position == self.length && target.is_normal() {
+            return normal;
+        }
+        let (inputs, outputs) = (target.inputs, target.outputs);
+        self.routed.resize(normal.len(), 0.0);
+        let frames = input.chunks_exact(inputs).zip(normal.chunks_exact(outputs));
+        for ((input, normal), output) in frames.zip(self.routed.chunks_exact_mut(outputs)) {
+            if self.position < self.length {
+                self.position += 1;
+                match self.position == self.length {
+                    true => self.current.clone_from(target),
+                    false => {
+                        let position = self.position as f32 / self.length as f32;
+                        self.current.blend(&self.from, target, position);
+                    }
+                }
+            }
+            self.current.route(input, normal, output);
+        }
+        &self.routed
+    }
+}
+
+/// Solos of the channels of an input, for every output it goes to.
+pub struct Solo {
+    inputs: usize,
+    /// The channels soloed, in the order they were.
+    soloed: Vec<usize>,
+    writers: Vec<ParamWriter<Matrix>>,
+}
+
+impl Solo {
+    /// Solos of an input of `inputs` channels, none soloed yet.
+    pub fn new(inputs: usize) -> Self {
+        Solo {
+            inputs,
+            soloed: Vec::new(),
+            writers: Vec::new(),
+        }
+    }
+
+    /// The router of an output of `outputs` channels at `sample_rate`.
+    pub fn router(&mut self, outputs: usize, sample_rate: u32) -> Router {
+        let matrix = self.matrix(outputs);
+        let (writer, reader) = ParamStore::new(matrix.clone()).split();
+        self.writers.push(writer);
+        Router::new(reader, matrix, sample_rate)
+    }
+
+    /// Channels of the input.
+    pub fn channels(&self) -> usize {
+        self.inputs
+    }
+
+    /// Solos the input channel `channel`, from 1, or un-solos it if it was. Returns whether it's
+    /// soloed now.
+    pub fn toggle(&mut self, channel: usize) -> Result<bool, String> {
+        if channel == 0 || channel > self.inputs {
+            return Err(format!(
+                "no input channel {} to solo, expected 1 to {}",
+                channel, self.inputs
+            ));
+        }
+        let soloed = match self.soloed.iter().position(|x| *x == channel - 1) {
+            Some(index) => {
+                self.soloed.remove(index);
+                false
+            }
+            None => {
+                self.soloed.push(channel - 1);
+                true
+            }
+        };
+        for index in 0..self.writers.len() {
+            let outputs = self.writers[index].get().outputs;
+            let matrix = self.matrix(outputs);
+            self.writers[index].publish(matrix);
+        }
+        Ok(soloed)
+    }
+
+    /// The channels soloed, from 1.
+    pub fn soloed(&self) -> Vec<usize> {
+        self.soloed.iter().map(|x| x + 1).collect()
+    }
+
+    /// The field of the status line, e.g. "solo: 1+3", or "solo: off".
+    pub fn describe(&self) -> String {
+        match self.soloed.is_empty() {
+            true => "solo: off".to_string(),
+            false => {
+                let channels: Vec<String> = self.soloed().iter().map(|x| x.to_string()).collect();
+                format!("solo: {}", channels.join("+"))
+            }
+        }
+    }
+
+    fn matrix(&self, outputs: usize) -> Matrix {
+        match self.soloed.is_empty() {
+            true => Matrix::normal(self.inputs, outputs),
+            false => Matrix::soloed(self.inputs, outputs, &self.soloed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soloed_channels_go_to_every_output() {
+        let matrix = Matrix::soloed(3, 2, &[0, 2]);
+        assert!(!matrix.is_normal());
+        let gains: Vec<f32> = (0..2)
+            .flat_map(|output| (0..3).map(move |input| (input, output)))
+            .map(|(input, output)| matrix.gain(input, output))
+            .collect();
+        assert_eq!(gains, [1.0, 0.0, 1.0, 1.0, 0.0, 1.0]);
+        assert!(Matrix::normal(3, 2).is_normal());
+    }
+
+    #[test]
+    fn routers_crossfade_to_a_new_routing_and_back() {
+        let mut solo = Solo::new(2);
+        // A crossfade of 10 frames at 1 kHz.
+        let mut router = solo.router(2, 1_000);
+        let input: Vec<f32> = [1.0, 0.5].repeat(20);
+        assert_eq!(router.process(&input, &input), &input[..]);
+
+        assert_eq!(solo.toggle(2), Ok(true));
+        let routed = router.process(&input, &input).to_vec();
+        // The left output goes from the left input to the right one, the right one stays.
+        assert!((routed[0] - 0.95).abs() < 1e-6);
+        assert!(routed.chunks(2).all(|x| (x[1] - 0.5).abs() < 1e-6));
+        assert!(routed[18..].chunks(2).all(|x| x[0] == 0.5));
+
+        assert_eq!(solo.toggle(2), Ok(false));
+        let routed = router.process(&input, &input).to_vec();
+        assert!((routed[0] - 0.55).abs() < 1e-6);
+        // Back to the normal routing, untouched, once the crossfade is over.
+        assert_eq!(routed[18..], input[18..]);
+        assert_eq!(router.process(&input, &input), &input[..]);
+    }
+
+    #[test]
+    fn solos_are_toggled_by_channel_from_one() {
+        let mut solo = Solo::new(4);
+        assert_eq!(solo.describe(), "solo: off");
+        solo.toggle(3).unwrap();
+        solo.toggle(1).unwrap();
+        assert_eq!(solo.soloed(), [3, 1]);
+        assert_eq!(solo.describe(), "solo: 3+1");
+        assert_eq!(
+            solo.toggle(0),
+            Err("no input channel 0 to solo, expected 1 to 4".to_string())
+        );
+        assert_eq!(
+            solo.toggle(5),
+            Err("no input channel 5 to solo, expected 1 to 4".to_string())
+        );
+    }
+}
@@ -0,0 +1,268 @@
+//! Reverberation time from an impulse response, broadband and in octave bands.
+//!
+//! The energy decay curve is the Schroeder backward integral of the squared response, from the
+//! point where its decay meets the noise floor, which would otherwise flatten the curve's end.
+//! RT60 is extrapolated from the slope of a least-squares line through the curve, from -5 to
+//! -25 dB for T20 and to -35 dB for T30. A fit is only reliable if the bottom of its range
+//! stays 10 dB over the noise floor.
+//!
+//! The bands are filtered with Butterworth high- and low-pass biquads, twice each, at half an
+//! octave either side of their centre.
+
+use std::f64::consts::SQRT_2;
+
+use crate::effects::{Biquad, EffectChain, FilterKind, BUTTERWORTH_Q};
+
+/// Centres of the octave bands, in Hz.
+pub const BANDS: [f64; 8] = [
+    63.0, 125.0, 250.0, 500.0, 1_000.0, 2_000.0, 4_000.0, 8_000.0,
+];
+/// Length of the blocks the envelope is smoothed over to find the noise floor, in seconds.
+const BLOCK_SECONDS: f64 = 0.01;
+/// Part of the end of the response the noise floor is measured over.
+const NOISE_PART: usize = 10;
+/// How far over the noise floor the decay counts as having reached it, in dB.
+const NOISE_MARGIN: f64 = 5.0;
+/// How far over the noise floor the bottom of a fit must stay, in dB.
+const FIT_MARGIN: f64 = 10.0;
+
+/// Decay times of a response, in seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct Decay {
+    pub t20: Fit,
+    pub t30: Fit,
+    /// From the loudest block of the response to its noise floor, in dB, infinite without any
+    /// noise.
+    pub dynamic_range: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    Seconds(f64),
+    /// The noise floor is too high for the fit, or the curve never gets that low.
+    Unreliable,
+}
+
+/// The decay times of `ir`, from its start.
+pub fn analyze(ir: &[f32], sample_rate: u32) -> Decay {
+    let energy: Vec<f64> = ir.iter().map(|x| *x as f64 * *x as f64).collect();
+    let block = ((BLOCK_SECONDS * sample_rate as f64) as usize).max(1);
+    let blocks: Vec<f64> = energy
+        .chunks(block)
+        .map(|x| x.iter().sum::<f64>() / x.len() as f64)
+        .collect();
+    let tail = &energy[energy.len() - energy.len() / NOISE_PART..];
+    let noise = match tail.len() {
+        0 => 0.0,
+        n => tail.iter().sum::<f64>() / n as f64,
+    };
+    let loudest = (0..blocks.len()).max_by(|x, y| blocks[*x].total_cmp(&blocks[*y]));
+    let dynamic_range = match (loudest, noise) {
+        (None, _) => 0.0,
+        (Some(_), noise) if noise <= 0.0 => f64::INFINITY,
+        (Some(index), noise) => 10.0 * (blocks[index] / noise).log10(),
+    };
+    // The integral ends where the decay, after the loudest block, reaches the noise floor.
+    let floor = noise * 10f64.powf(NOISE_MARGIN / 10.0);
+    let end = loudest
+        .and_then(|start| (start..blocks.len()).find(|x| blocks[*x] <= floor))
+        .map_or(energy.len(), |x| x * block);
+    let curve = schroeder(&energy[..end]);
+    let fit = |bottom: f64| match dynamic_range >= -bottom + FIT_MARGIN {
+        true => fit(&curve, -5.0, bottom, sample_rate).map_or(Fit::Unreliable, Fit::Seconds),
+        false => Fit::Unreliable,
+    };
+    Decay {
+        t20: fit(-25.0),
+        t30: fit(-35.0),
+        dynamic_range,
+    }
+}
+
+/// The energy decay curve of squared samples, in dB relative to the total energy.
+pub fn schroeder(energy: &[f64]) -> Vec<f64> {
+    let total: f64 = energy.iter().sum();
+    let mut remaining = total;
+    energy
+        .iter()
+        .map(|x| {
+            let db = 10.0 * (remaining / total).max(f64::MIN_POSITIVE).log10();
+            remaining -= x;
+            db
+        })
+        .collect()
+}
+
+/// Extrapolates the time `curve` takes to decay by 60 dB from the least-squares line through it
+/// from `top` to `bottom` dB.
+pub fn fit(curve: &[f64], top: f64, bottom: f64, sample_rate: u32) -> Option<f64> {
+    let start = curve.iter().position(|x| *x <= top)?;
+    let end = start + curve[start..].iter().position(|x| *x <= bottom)?;
+    if end - start < 2 {
+        return None;
+    }
+    let points = &curve[start..end];
+    let count = points.len() as f64;
+    let mean_t = (count - 1.0) / 2.0;
+    let mean_db = points.iter().sum::<f64>() / count;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (n, db) in points.iter().enumerate() {
+        covariance += (n as f64 - mean_t) * (db - mean_db);
+        variance += (n as f64 - mean_t) * (n as f64 - mean_t);
+    }
+    let slope = covariance / variance * sample_rate as f64;
+    (slope < 0.0).then(|| -60.0 / slope)
+}
+
+/// The octave band of `ir` around `centre` Hz, or `None` if its upper edge is too close to
+/// half the sample rate.
+pub fn octave_band(ir: &[f32], centre: f64, sample_rate: u32) -> Option<Vec<f32>> {
+    let rate = sample_rate as f32;
+    let (low, high) = ((centre / SQRT_2) as f32, (centre * SQRT_2) as f32);
+    if high >= rate * 0.45 {
+        return None;
+    }
+    let mut chain: EffectChain = EffectChain::new(1);
+    for _ in 0..2 {
+        chain.push(Biquad::new(
+            FilterKind::HighPass,
+            low,
+            BUTTERWORTH_Q,
+            rate,
+            1,
+        ));
+        chain.push(Biquad::new(
+            FilterKind::LowPass,
+            high,
+            BUTTERWORTH_Q,
+            rate,
+            1,
+        ));
+    }
+    let mut band = ir.to_vec();
+    chain.process(&mut band);
+    Some(band)
+}
+
+/// `ir` from its largest sample on, for `length` samples at most, wrapping around its end as
+/// deconvolved ones do.
+pub fn from_peak(ir: &[f32], length: usize) -> Vec<f32> {
+    let Some(peak) = (0..ir.len()).max_by(|x, y| ir[*x].abs().total_cmp(&ir[*y].abs())) else {
+        return Vec::new();
+    };
+    (0..length.min(ir.len()))
+        .map(|n| ir[(peak + n) % ir.len()])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 8_000;
+
+    /// Noise decaying by 60 dB in `rt60` seconds, over a floor of noise at `floor_db`, lasting
+    /// `seconds`.
+    fn reverb(rt60: f64, floor_db: f64, seconds: f64) -> Vec<f32> {
+        let mut state = 0x2545_f491u32;
+        let mut noise = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as f64 / u32::MAX as f64 - 0.5) * 3f64.sqrt() * 2.0
+        };
+        let floor = 10f64.powf(floor_db / 20.0);
+        (0..(seconds * RATE as f64) as usize)
+            .map(|n| {
+                let t = n as f64 / RATE as f64;
+                let envelope = 10f64.powf(-3.0 * t / rt60);
+                (envelope * noise() + floor * noise()) as f32
+            })
+            .collect()
+    }
+
+    fn seconds(fit: Fit) -> f64 {
+        match fit {
+            Fit::Seconds(x) => x,
+            Fit::Unreliable => panic!("the fit is unreliable"),
+        }
+    }
+
+    #[test]
+    fn decays_are_measured_down_to_the_noise_floor() {
+        let decay = analyze(&reverb(0.5, -80.0, 2.0), RATE);
+        assert!(
+            (seconds(decay.t20) / 0.5 - 1.0).abs() < 0.05,
+            "{:?}",
+            decay.t20
+        );
+        assert!(
+            (seconds(decay.t30) / 0.5 - 1.0).abs() < 0.05,
+            "{:?}",
+            decay.t30
+        );
+        assert!(
+            (decay.dynamic_range - 80.0).abs() < 3.0,
+            "{}",
+            decay.dynamic_range
+        );
+    }
+
+    #[test]
+    fn fits_too_close_to_the_noise_are_unreliable() {
+        let decay = analyze(&reverb(0.5, -40.0, 2.0), RATE);
+        assert!(
+            (seconds(decay.t20) / 0.5 - 1.0).abs() < 0.1,
+            "{:?}",
+            decay.t20
+        );
+        assert_eq!(decay.t30, Fit::Unreliable);
+        let decay = analyze(&reverb(0.5, -30.0, 2.0), RATE);
+        assert_eq!(decay.t20, Fit::Unreliable);
+
+        let decay = analyze(&[0.0; 100], RATE);
+        assert_eq!(decay.t30, Fit::Unreliable);
+        assert_eq!(analyze(&[], RATE).dynamic_range, 0.0);
+    }
+
+    #[test]
+    fn the_curve_is_the_energy_left() {
+        let curve = schroeder(&[1.0, 1.0, 2.0, 0.0]);
+        let expected = [0.0, 10.0 * 0.75f64.log10(), 10.0 * 0.5f64.log10()];
+        for (x, y) in curve.iter().zip(expected) {
+            assert!((x - y).abs() < 1e-12, "{} against {}", x, y);
+        }
+        assert!(curve[3] < -300.0);
+    }
+
+    #[test]
+    fn the_slope_is_extrapolated_to_60_db() {
+        // 1 dB per sample, so 60 samples.
+        let curve: Vec<f64> = (0..100).map(|n| -(n as f64)).collect();
+        assert!((fit(&curve, -5.0, -25.0, RATE).unwrap() - 60.0 / RATE as f64).abs() < 1e-12);
+        assert_eq!(fit(&curve, -5.0, -200.0, RATE), None);
+        assert_eq!(fit(&[0.0, -10.0, -30.0], -5.0, -25.0, RATE), None);
+    }
+
+    #[test]
+    fn bands_keep_their_octave() {
+        let tone = |frequency: f64| -> Vec<f32> {
+            let omega = std::f64::consts::TAU * frequency / RATE as f64;
+            (0..RATE).map(|n| (omega * n as f64).sin() as f32).collect()
+        };
+        let peak = |x: Vec<f32>| x[4_000..].iter().fold(0f32, |x, y| x.max(y.abs()));
+        let inside = peak(octave_band(&tone(500.0), 500.0, RATE).unwrap());
+        let outside = peak(octave_band(&tone(2_000.0), 500.0, RATE).unwrap());
+        // The edges are half an octave away, where each filter is already a few dB down.
+        assert!(inside > 0.6, "{}", inside);
+        assert!(outside < 1e-2, "{}", outside);
+        assert!(octave_band(&tone(500.0), 4_000.0, RATE).is_none());
+    }
+
+    #[test]
+    fn responses_start_at_their_peak() {
+        assert_eq!(from_peak(&[0.1, 0.2, -0.9, 0.3], 3), [-0.9, 0.3, 0.1]);
+        assert_eq!(from_peak(&[0.5, 0.1], 10), [0.5, 0.1]);
+        assert!(from_peak(&[], 10).is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+//! Sample types the effect chain can run in.
+//!
+//! Devices always exchange f32 samples. The chain converts them to its own [`Sample`] type when a
+//! block is deinterleaved and back when it's interleaved, so effects can run in f64 where f32
+//! rounding would accumulate, e.g. in long filter cascades.
+
+use dasp::sample::FloatSample;
+use dasp::Frame;
+
+use crate::simd;
+
+/// A floating-point sample type effects can be written against.
+///
+/// A sample is also a mono dasp frame, so channel slices work with `dasp::slice`.
+pub trait Sample: FloatSample + Frame<Sample = Self> + Default + Send + Sync + 'static {
+    /// Multiplies every sample by `gain`.
+    fn apply_gain(data: &mut [Self], gain: Self);
+
+    /// Whether every sample is finite.
+    fn all_finite(data: &[Self]) -> bool;
+}
+
+impl Sample for f32 {
+    fn apply_gain(data: &mut [Self], gain: Self) {
+        simd::apply_gain(data, gain);
+    }
+
+    fn all_finite(data: &[Self]) -> bool {
+        simd::all_finite(data)
+    }
+}
+
+impl Sample for f64 {
+    fn apply_gain(data: &mut [Self], gain: Self) {
+        for x in data {
+            *x *= gain;
+        }
+    }
+
+    fn all_finite(data: &[Self]) -> bool {
+        data.iter().all(|x| x.is_finite())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::effects::{Biquad, EffectChain, FilterKind, Gain, BUTTERWORTH_Q};
+
+    fn chain<S: Sample>() -> EffectChain<S> {
+        let mut chain = EffectChain::new(2);
+        for frequency in [20.0, 40.0, 80.0, 160.0] {
+            chain.push(Biquad::new(
+                FilterKind::HighPass,
+                frequency,
+                BUTTERWORTH_Q,
+                48_000.0,
+                2,
+            ));
+        }
+        chain.push(Gain::new(-3.0));
+        chain
+    }
+
+    #[test]
+    fn both_sample_types_scale_and_check_the_same() {
+        let data = [0.5, -0.25, 1.0];
+        let (mut single, mut double) = (data, data.map(f64::from));
+        f32::apply_gain(&mut single, 0.5);
+        f64::apply_gain(&mut double, 0.5);
+        assert_eq!(double, single.map(f64::from));
+        assert!(f32::all_finite(&single) && f64::all_finite(&double));
+        assert!(!f64::all_finite(&[0.0, f64::NAN]));
+        assert!(!f64::all_finite(&[f64::INFINITY]));
+    }
+
+    #[test]
+    fn f64_chains_sound_like_f32_ones() {
+        let (mut single, mut double) = (chain::<f32>(), chain::<f64>());
+        let mut state = 0x9e37_79b9u32;
+        for _ in 0..16 {
+            let block: Vec<f32> = (0..1_024)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    (state >> 8) as f32 / (1 << 24) as f32 - 0.5
+                })
+                .collect();
+            let (mut a, mut b) = (block.clone(), block);
+            single.process(&mut a);
+            double.process(&mut b);
+            let worst = a
+                .iter()
+                .zip(&b)
+                .map(|(x, y)| (x - y).abs())
+                .fold(0.0, f32::max);
+            assert!(worst < 1e-4, "{}", worst);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! A run of the whole pipeline on simulated callbacks, without any audio device, for
+//! `--self-test`.
+//!
+//! A virtual input plays a tone into a ring buffer, as its callback would, and a virtual output
+//! mixes it out a block at a time, through the effect chain and the meters, while the recorder
+//! writes the input to a temporary file. The callbacks alternate, as a duplex device calls them.
+//! The run then checks what should always hold, whatever the settings: no NaNs, meters that read
+//! the tone at its level, a recording of every input sample, no xruns, a status line, and, in
+//! debug builds or built with the `allocation-counter` feature, no allocations in the block path.
+
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use ringbuf::traits::Split;
+use ringbuf::HeapRb;
+
+use crate::adapter::Converter;
+use crate::effects::EffectChain;
+use crate::fanout::FanOut;
+use crate::headroom::{self, HeadroomMeter, HeadroomStats, Stage};
+use crate::latency::LatencyBudget;
+use crate::loopback::Tone;
+use crate::mixer::Mixer;
+use crate::record::{self, Format, RecordFeed, RecordSettings};
+use crate::ring::{Prime, RingSize};
+use crate::stats::XrunCounters;
+use crate::status::StatusLine;
+use crate::wav;
+
+/// How long the simulated streams run.
+pub const DURATION: Duration = Duration::from_secs(3);
+/// Frequency of the tone of the virtual input, in Hz.
+const TONE_HZ: f64 = 997.0;
+/// Peak level of the tone, in dBFS.
+const TONE_DB: f32 = -12.0;
+/// Furthest the mix meter may read from the level of the tone, in dB.
+const METER_TOLERANCE_DB: f32 = 0.5;
+/// Highest peak before the limiter of the chain that is still plausible, in dBFS.
+const MAX_PEAK_DB: f32 = 24.0;
+/// Runs so far, numbering the temporary recording of each.
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+/// The simulated streams: both ends have the same configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct SelfTest {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Frames of every callback.
+    pub block: u32,
+    /// Delay from the input to the output, in milliseconds.
+    pub latency: f32,
+}
+
+/// One invariant the run checked.
+pub struct Check {
+    pub name: &'static str,
+    /// Whether it held, or `None` if it couldn't be checked in this build.
+    pub passed: Option<bool>,
+    pub detail: String,
+}
+
+/// What the run found.
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Whether no check failed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|x| x.passed != Some(false))
+    }
+
+    /// Prints a line per check, e.g. "PASS  no NaNs in the output: 288000 samples".
+    pub fn print(&self) {
+        for check in &self.checks {
+            let verdict = match check.passed {
+                Some(true) => "PASS",
+                Some(false) => "FAIL",
+                None => "SKIP",
+            };
+            println!("{}  {}: {}", verdict, check.name, check.detail);
+        }
+    }
+
+    fn check(&mut self, name: &'static str, passed: bool, detail: String) {
+        self.checks.push(Check {
+            name,
+            passed: Some(passed),
+            detail,
+        });
+    }
+}
+
+impl SelfTest {
+    /// The configuration of both simulated streams.
+    pub fn config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: SampleRate(self.sample_rate),
+            buffer_size: BufferSize::Fixed(self.block),
+        }
+    }
+
+    /// Runs `chain`, built for [`config`](Self::config), for [`DURATION`] of simulated
+    /// callbacks. Fails if the pipeline can't be set up, and reports the invariants otherwise.
+    pub fn run(&self, mut chain: EffectChain<f32>) -> anyhow::Result<Report> {
+        let config = self.config();
+        let channels = self.channels as usize;
+        let rate = self.sample_rate;
+        let block = self.block as usize;
+        let blocks = (DURATION.as_secs_f64() * rate as f64) as usize / block;
+
+        let budget = LatencyBudget::new(self.latency, &config, chain.latency_frames())?;
+        let size = RingSize::new(budget.ring_frames(), Prime::Full, &config, &config)?;
+        let (producer, consumer) = size.ring()?;
+        let mut fan_out = FanOut::new(vec![(producer, Converter::new(&config, &config))]);
+        let mut mixer = Mixer::new(vec![(consumer, 1.0)]);
+        mixer.prepare(block * channels);
+        chain.prepare(block);
+        let xruns = XrunCounters::default();
+
+        let path = std::env::temp_dir().join(format!(
+            "self-test-{}-{}-{}.wav",
+            std::process::id(),
+            self.block,
+            RUNS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let (producer, consumer) = HeapRb::<f32>::new(blocks * block * channels).split();
+        let mut recorder = RecordFeed::new(producer, self.channels, None);
+        let recording = record::spawn(
+            recorder_settings(path.clone()),
+            consumer,
+            self.channels,
+            rate,
+        );
+
+        let headroom = Arc::new(HeadroomStats::new("virtual output", 0.0));
+        let mut meter = HeadroomMeter::new(headroom.clone(), rate);
+        headroom::attach(&mut chain, &headroom, rate);
+        let profile = chain.profile("virtual output", rate);
+        let mut tone = Tone::new(TONE_HZ, TONE_DB, DURATION * 2, rate);
+
+        let mut input = vec![0.0; block * channels];
+        let mut output = vec![0.0; block * channels];
+        let mut recorder_behind = 0;
+        let mut non_finite = 0;
+        let mut allocations = 0;
+        for _ in 0..blocks {
+            tone.fill(&mut input, channels);
+            let counting = AllocationCount::start();
+            fan_out.push(&input, |_| {
+                xruns.overruns.fetch_add(1, Ordering::Relaxed);
+            });
+            if !recorder.push(&input) {
+                recorder_behind += 1;
+            }
+            mixer.mix(&mut output, |_| {
+                xruns.underruns.fetch_add(1, Ordering::Relaxed);
+            });
+            meter.measure(Stage::Trim, &output, channels);
+            chain.process(&mut output);
+            allocations += counting.stop();
+            non_finite += output.iter().filter(|x| !x.is_finite()).count();
+        }
+        drop(recorder);
+        let summary = recording
+            .join()
+            .map_err(|_| anyhow::anyhow!("the recorder thread panicked"))??;
+
+        let mut report = Report { checks: Vec::new() };
+        let samples = blocks * block * channels;
+        report.check(
+            "no NaNs or infinities in the output",
+            non_finite == 0,
+            format!("{} of {} samples", non_finite, samples),
+        );
+        let overruns = xruns.overruns.load(Ordering::Relaxed);
+        let underruns = xruns.underruns.load(Ordering::Relaxed);
+        report.check(
+            "the ring buffer never ran dry or over",
+            overruns == 0 && underruns == 0,
+            xruns.summary(),
+        );
+        let mix = headroom.peak_db(Stage::Trim).unwrap_or(f32::NAN);
+        report.check(
+            "the meters read the input at its level",
+            (mix - TONE_DB).abs() <= METER_TOLERANCE_DB,
+            format!("{:.2} dBFS for a tone of {:.2} dBFS", mix, TONE_DB),
+        );
+        let chain_peak = headroom.peak_db(Stage::Limiter).unwrap_or(f32::NAN);
+        report.check(
+            "the meters read a plausible output",
+            chain_peak <= MAX_PEAK_DB,
+            format!("{:.2} dBFS before the limiter", chain_peak),
+        );
+        let recorded = wav::read(&path)
+            .with_context(|| format!("failed to read the recording \"{}\"", path.display()));
+        let _ = std::fs::remove_file(&path);
+        let recorded = recorded?;
+        let expected: Vec<f32> = {
+            let mut tone = Tone::new(TONE_HZ, TONE_DB, DURATION * 2, rate);
+            let mut samples = vec![0.0; samples];
+            for block in samples.chunks_mut(input.len()) {
+                tone.fill(block, channels);
+            }
+            samples
+        };
+        report.check(
+            "the recorder wrote every input sample",
+            recorder_behind == 0 && recorded.samples == expected,
+            format!(
+                "{} of {} samples in {} file(s), {} blocks dropped",
+                recorded.samples.len(),
+                samples,
+                summary.files.len(),
+                recorder_behind
+            ),
+        );
+        let mut status = StatusLine::default();
+        status.add_flagged(move || headroom::describe(std::slice::from_ref(&headroom)));
+        status.add(move || profile.describe());
+        let line = status.line();
+        report.check(
+            "the status line reports the stats",
+            !line.is_empty() && !line.contains("NaN"),
+            format!("\"{}\"", line),
+        );
+        report.checks.push(match AllocationCount::ENABLED {
+            true if AllocationCount::installed() => Check {
+                name: "no allocations in the block path",
+                passed: Some(allocations == 0),
+                detail: format!("{} in {} blocks", allocations, blocks),
+            },
+            // Nothing counted, which would read as none made.
+            true => Check {
+                name: "no allocations in the block path",
+                passed: Some(false),
+                detail: "the counting allocator isn't the global allocator".to_string(),
+            },
+            false => Check {
+                name: "no allocations in the block path",
+                passed: None,
+                detail: "build in debug or with the `allocation-counter` feature to check"
+                    .to_string(),
+            },
+        });
+        Ok(report)
+    }
+}
+
+fn recorder_settings(path: PathBuf) -> RecordSettings {
+    RecordSettings {
+        path,
+        format: Format::Wav,
+        gate: None,
+        preroll: Duration::ZERO,
+        split: false,
+        arm: None,
+        split_every: None,
+        split_size: None,
+        normalize: None,
+        markers: None,
+        rate_change: None,
+        takes: None,
+    }
+}
+
+thread_local! {
+    /// Allocations of this thread since counting started, if it did.
+    static ALLOCATIONS: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Counts the allocations of this thread until it's stopped, with [`CountingAllocator`].
+struct AllocationCount;
+
+impl AllocationCount {
+    const ENABLED: bool = cfg!(any(debug_assertions, feature = "allocation-counter"));
+
+    fn start() -> Self {
+        ALLOCATIONS.with(|x| x.set(Some(0)));
+        AllocationCount
+    }
+
+    /// The allocations since the start.
+    fn stop(self) -> usize {
+        ALLOCATIONS.with(|x| x.take()).unwrap_or(0)
+    }
+
+    /// Whether [`CountingAllocator`] is the global allocator, i.e. whether an allocation is
+    /// counted.
+    fn installed() -> bool {
+        let counting = AllocationCount::start();
+        drop(std::hint::black_box(Box::new(0u8)));
+        counting.stop() > 0
+    }
+}
+
+/// The system allocator, counting the allocations of the threads that asked for it.
+///
+/// The binary makes it the global allocator in debug builds, and in release builds with the
+/// `allocation-counter` feature. Anything else running the self-test must too, or its check of
+/// the allocations fails.
+#[cfg(any(debug_assertions, feature = "allocation-counter"))]
+pub struct CountingAllocator;
+
+#[cfg(any(debug_assertions, feature = "allocation-counter"))]
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        count_allocation();
+        unsafe { std::alloc::System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        unsafe { std::alloc::System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        unsafe { std::alloc::System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[cfg(any(debug_assertions, feature = "allocation-counter"))]
+fn count_allocation() {
+    // Threads being torn down have no locals left, and aren't counting.
+    let _ = ALLOCATIONS.try_with(|x| x.set(x.get().map(|count| count + 1)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(debug_assertions, feature = "allocation-counter"))]
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn run(block: u32) -> Report {
+        let test = SelfTest {
+            channels: 2,
+            sample_rate: 48_000,
+            block,
+            latency: 20.0,
+        };
+        test.run(EffectChain::new(2)).unwrap()
+    }
+
+    #[test]
+    fn the_block_path_allocates_nothing() {
+        let report = run(256);
+        let check = report
+            .checks
+            .iter()
+            .find(|x| x.name == "no allocations in the block path")
+            .unwrap();
+        assert_eq!(
+            check.passed,
+            AllocationCount::ENABLED.then_some(true),
+            "{}",
+            check.detail
+        );
+    }
+
+    #[test]
+    fn every_check_passes_at_an_odd_block() {
+        let report = run(333);
+        for check in &report.checks {
+            assert_ne!(
+                check.passed,
+                Some(false),
+                "{}: {}",
+                check.name,
+                check.detail
+            );
+        }
+    }
+
+    #[test]
+    fn runs_at_once_record_apart() {
+        let runs: Vec<_> = (0..2).map(|_| std::thread::spawn(|| run(128))).collect();
+        for report in runs.into_iter().map(|x| x.join().unwrap()) {
+            for check in &report.checks {
+                assert_ne!(
+                    check.passed,
+                    Some(false),
+                    "{}: {}",
+                    check.name,
+                    check.detail
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn an_allocation_is_counted() {
+        assert_eq!(AllocationCount::installed(), AllocationCount::ENABLED);
+    }
+}
@@ -0,0 +1,539 @@
+//! The session log of `--session-log`: one file telling the story of a run.
+//!
+//! It gathers the devices and configurations the streams got, the latency they play at, every
+//! warning and error logged, with when it happened, the files recorded with their loudness, the
+//! device switches and recoveries, and the final stats. Warnings reach it through the same
+//! `tracing` subscriber everything logs to, including the events the logging thread drains from
+//! the audio threads, e.g. xruns, and the rest is noted by the main thread as it goes.
+//!
+//! The file is rewritten as the run goes, at most every interval of the status line, or every
+//! [`FLUSH_INTERVAL`] without one, to a temporary file renamed over it, so that a crash leaves the log of the run up to then. A log without an
+//! outcome is one of a run that didn't end.
+
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::field::{Field, Visit};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+use crate::json::Value;
+use crate::logging::LogFormat;
+use crate::record::RecordedFile;
+
+/// Shortest time between two writes of the file, while the run goes on without a status line.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Events the log keeps, after which it only counts them, e.g. in a storm of xruns.
+pub const MAX_EVENTS: usize = 10_000;
+
+/// Something that happened during the run, `seconds` into it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub seconds: f64,
+    pub text: String,
+}
+
+/// A warning or an error logged during the run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub seconds: f64,
+    /// "WARN" or "ERROR".
+    pub level: &'static str,
+    /// The message, followed by its fields, e.g. "restarting the streams (retries=3)".
+    pub message: String,
+}
+
+/// A stream, with the device and configuration it got.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamEntry {
+    pub label: String,
+    pub device: String,
+    pub config: String,
+}
+
+/// What the session log holds, rendered by [`render`](SessionLog::render).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionLog {
+    /// When the run started, in seconds since the Unix epoch.
+    pub started: u64,
+    /// How long the run lasted, so far.
+    pub seconds: f64,
+    pub streams: Vec<StreamEntry>,
+    /// How much latency every output plays at, e.g. "60.0 ms: 10.7 ms for the effects, ...".
+    pub latency: Vec<(String, String)>,
+    pub warnings: Vec<Warning>,
+    /// Warnings past [`MAX_EVENTS`], counted only.
+    pub dropped: usize,
+    /// The device switches and recoveries.
+    pub changes: Vec<Event>,
+    pub recordings: Vec<RecordedFile>,
+    /// The final stats, by name, e.g. "output stream" and "0 overruns, 2 underruns".
+    pub stats: Vec<(String, String)>,
+    /// How the run ended, e.g. "done", or `None` while it goes on.
+    pub outcome: Option<String>,
+}
+
+impl SessionLog {
+    /// Adds a warning, unless the log is full.
+    pub fn warn(&mut self, warning: Warning) {
+        match self.warnings.len() < MAX_EVENTS {
+            true => self.warnings.push(warning),
+            false => self.dropped += 1,
+        }
+    }
+
+    /// The log as a text in `format`: a JSON object, or sections for people to read.
+    pub fn render(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Json => format!("{}\n", self.to_json()),
+            LogFormat::Pretty => self.to_text(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let pairs = |pairs: &[(String, String)], key: &'static str| {
+            Value::Array(
+                pairs
+                    .iter()
+                    .map(|(name, value)| {
+                        Value::object([
+                            (key, name.as_str().into()),
+                            ("value", value.clone().into()),
+                        ])
+                    })
+                    .collect(),
+            )
+        };
+        let event = |x: &Event| {
+            Value::object([
+                ("seconds", x.seconds.into()),
+                ("text", x.text.clone().into()),
+            ])
+        };
+        Value::object([
+            ("started", (self.started as f64).into()),
+            ("seconds", self.seconds.into()),
+            (
+                "outcome",
+                self.outcome.clone().map_or(Value::Null, Value::from),
+            ),
+            (
+                "streams",
+                Value::Array(
+                    self.streams
+                        .iter()
+                        .map(|x| {
+                            Value::object([
+                                ("label", x.label.clone().into()),
+                                ("device", x.device.clone().into()),
+                                ("config", x.config.clone().into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            ("latency", pairs(&self.latency, "output")),
+            (
+                "warnings",
+                Value::Array(
+                    self.warnings
+                        .iter()
+                        .map(|x| {
+                            Value::object([
+                                ("seconds", x.seconds.into()),
+                                ("level", x.level.into()),
+                                ("message", x.message.clone().into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            ("dropped_warnings", (self.dropped as f64).into()),
+            (
+                "changes",
+                Value::Array(self.changes.iter().map(event).collect()),
+            ),
+            (
+                "recordings",
+                Value::Array(
+                    self.recordings
+                        .iter()
+                        .map(|x| {
+                            Value::object([
+                                ("path", x.path.display().to_string().into()),
+                                ("seconds", x.seconds.into()),
+                                ("true_peak_dbtp", (x.true_peak_db as f64).into()),
+                                ("lufs", x.lufs.map_or(Value::Null, Value::from)),
+                                ("markers", (x.markers as f64).into()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            ("stats", pairs(&self.stats, "name")),
+        ])
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = String::new();
+        let outcome = self
+            .outcome
+            .as_deref()
+            .unwrap_or("still running, or cut short");
+        let _ = writeln!(
+            text,
+            "Session started at {} (Unix time), {:.1} seconds: {}",
+            self.started, self.seconds, outcome
+        );
+        section(&mut text, "Streams", &self.streams, |text, x| {
+            writeln!(text, "  {}: \"{}\", {}", x.label, x.device, x.config)
+        });
+        section(&mut text, "Latency", &self.latency, |text, (output, x)| {
+            writeln!(text, "  {}: {}", output, x)
+        });
+        section(&mut text, "Warnings", &self.warnings, |text, x| {
+            writeln!(
+                text,
+                "  {:>10.3} s  {:<5}  {}",
+                x.seconds, x.level, x.message
+            )
+        });
+        if self.dropped > 0 {
+            let _ = writeln!(text, "  and {} more", self.dropped);
+        }
+        section(
+            &mut text,
+            "Switches and recoveries",
+            &self.changes,
+            |text, x| writeln!(text, "  {:>10.3} s  {}", x.seconds, x.text),
+        );
+        section(&mut text, "Recordings", &self.recordings, |text, x| {
+            let lufs = x.lufs.map_or("too quiet to measure".to_string(), |x| {
+                format!("{:.1} LUFS", x)
+            });
+            writeln!(
+                text,
+                "  \"{}\": {:.1} s, {:.1} dBTP, {}",
+                x.path.display(),
+                x.seconds,
+                x.true_peak_db,
+                lufs
+            )
+        });
+        section(&mut text, "Stats", &self.stats, |text, (name, x)| {
+            writeln!(text, "  {}: {}", name, x)
+        });
+        text
+    }
+}
+
+/// Writes a section of `items` with a title, unless there are none.
+fn section<T>(
+    text: &mut String,
+    title: &str,
+    items: &[T],
+    mut line: impl FnMut(&mut String, &T) -> std::fmt::Result,
+) {
+    if items.is_empty() {
+        return;
+    }
+    let _ = writeln!(text, "\n{}", title);
+    for item in items {
+        let _ = line(text, item);
+    }
+}
+
+/// The session log of a run, shared by the threads noting what happens, and its file.
+pub struct Session {
+    /// Where it's written, or `None` for a session that notes nothing.
+    path: Option<PathBuf>,
+    format: LogFormat,
+    /// Shortest time between two writes of the file.
+    interval: Duration,
+    start: Instant,
+    log: Mutex<SessionLog>,
+    dirty: AtomicBool,
+    /// When the file was last written.
+    flushed: Mutex<Option<Instant>>,
+}
+
+impl Session {
+    /// A session written to `path` in `format`, at most every `interval`, starting now.
+    pub fn new(path: PathBuf, format: LogFormat, interval: Duration) -> Self {
+        let started = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs());
+        Session {
+            path: Some(path),
+            format,
+            interval,
+            start: Instant::now(),
+            log: Mutex::new(SessionLog {
+                started,
+                ..SessionLog::default()
+            }),
+            dirty: AtomicBool::new(true),
+            flushed: Mutex::new(None),
+        }
+    }
+
+    /// A session that notes nothing, without `--session-log`.
+    pub fn disabled() -> Self {
+        Session {
+            path: None,
+            format: LogFormat::Pretty,
+            interval: FLUSH_INTERVAL,
+            start: Instant::now(),
+            log: Mutex::new(SessionLog::default()),
+            dirty: AtomicBool::new(false),
+            flushed: Mutex::new(None),
+        }
+    }
+
+    /// Seconds since the session started.
+    pub fn seconds(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    /// Changes the log with `change`, for the file to be written again.
+    pub fn note(&self, change: impl FnOnce(&mut SessionLog)) {
+        if self.path.is_none() {
+            return;
+        }
+        change(&mut self.log.lock().unwrap());
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Notes a device switch or a recovery, now.
+    pub fn change(&self, text: String) {
+        let seconds = self.seconds();
+        self.note(|log| log.changes.push(Event { seconds, text }));
+    }
+
+    /// Writes the file if anything changed, and it wasn't written for its interval.
+    pub fn flush(&self) -> std::io::Result<()> {
+        let mut flushed = self.flushed.lock().unwrap();
+        if flushed.is_some_and(|x| x.elapsed() < self.interval) {
+            return Ok(());
+        }
+        *flushed = Some(Instant::now());
+        drop(flushed);
+        self.write()
+    }
+
+    /// Notes how the run ended, and writes the file one last time.
+    pub fn finish(&self, outcome: String) -> std::io::Result<()> {
+        self.note(|log| log.outcome = Some(outcome));
+        self.write()
+    }
+
+    fn write(&self) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let text = {
+            let mut log = self.log.lock().unwrap();
+            log.seconds = self.seconds();
+            log.render(self.format)
+        };
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+        std::fs::write(&part, text)?;
+        std::fs::rename(&part, path)
+    }
+}
+
+/// The `tracing` layer noting every warning and error in a session, to be filtered at
+/// [`LevelFilter::WARN`](tracing_subscriber::filter::LevelFilter::WARN).
+pub struct SessionLayer(pub Arc<Session>);
+
+impl<S: Subscriber> Layer<S> for SessionLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+        let level = match *event.metadata().level() {
+            Level::ERROR => "ERROR",
+            Level::WARN => "WARN",
+            _ => return,
+        };
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let message = match fields.others.is_empty() {
+            true => fields.message,
+            false => format!("{} ({})", fields.message, fields.others.join(", ")),
+        };
+        let seconds = self.0.seconds();
+        self.0.note(|log| {
+            log.warn(Warning {
+                seconds,
+                level,
+                message,
+            })
+        });
+    }
+}
+
+/// The message of an event, and its other fields as "name=value".
+#[derive(Default)]
+struct Fields {
+    message: String,
+    others: Vec<String>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => self.others.push(format!("{}={}", name, value)),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            name => self.others.push(format!("{}={:?}", name, value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    fn log() -> SessionLog {
+        SessionLog {
+            started: 1_700_000_000,
+            seconds: 12.5,
+            streams: vec![StreamEntry {
+                label: "output stream".to_string(),
+                device: "Speakers".to_string(),
+                config: "2 channels at 48000 Hz, 256 frames".to_string(),
+            }],
+            warnings: vec![Warning {
+                seconds: 1.25,
+                level: "WARN",
+                message: "the input fell behind the output".to_string(),
+            }],
+            recordings: vec![RecordedFile {
+                path: PathBuf::from("take.wav"),
+                take: None,
+                seconds: 10.0,
+                markers: 0,
+                true_peak_db: -1.0,
+                lufs: None,
+            }],
+            stats: vec![("output stream".to_string(), "0 underruns".to_string())],
+            outcome: Some("done".to_string()),
+            ..SessionLog::default()
+        }
+    }
+
+    #[test]
+    fn logs_render_in_sections_for_people() {
+        let expected = [
+            "Session started at 1700000000 (Unix time), 12.5 seconds: done",
+            "",
+            "Streams",
+            "  output stream: \"Speakers\", 2 channels at 48000 Hz, 256 frames",
+            "",
+            "Warnings",
+            "       1.250 s  WARN   the input fell behind the output",
+            "",
+            "Recordings",
+            "  \"take.wav\": 10.0 s, -1.0 dBTP, too quiet to measure",
+            "",
+            "Stats",
+            "  output stream: 0 underruns",
+            "",
+        ];
+        assert_eq!(log().render(LogFormat::Pretty), expected.join("\n"));
+    }
+
+    #[test]
+    fn logs_render_as_json() {
+        let json = log().render(LogFormat::Json);
+        let value = Value::parse(json.trim_end()).unwrap();
+        assert_eq!(value.get("outcome").and_then(Value::as_str), Some("done"));
+        let warnings = value.get("warnings").unwrap().to_string();
+        assert_eq!(
+            warnings,
+            r#"[{"seconds":1.25,"level":"WARN","message":"the input fell behind the output"}]"#
+        );
+        let recording = value.get("recordings").unwrap().to_string();
+        assert!(recording.contains(r#""lufs":null"#), "{}", recording);
+    }
+
+    #[test]
+    fn warnings_past_the_limit_are_counted() {
+        let mut log = SessionLog::default();
+        for _ in 0..MAX_EVENTS + 2 {
+            log.warn(Warning {
+                seconds: 0.0,
+                level: "WARN",
+                message: String::new(),
+            });
+        }
+        assert_eq!((log.warnings.len(), log.dropped), (MAX_EVENTS, 2));
+        assert!(log.render(LogFormat::Pretty).ends_with("  and 2 more\n"));
+    }
+
+    #[test]
+    fn sessions_write_their_file_as_the_run_goes() {
+        let path = std::env::temp_dir().join(format!("session-{}.log", std::process::id()));
+        let session = Session::new(path.clone(), LogFormat::Pretty, Duration::from_secs(60));
+        session.change("moved the output to \"B\"".to_string());
+        session.flush().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("still running, or cut short"), "{}", text);
+        assert!(text.contains("Switches and recoveries\n"), "{}", text);
+
+        // Not again within the interval, but always at the end.
+        session.note(|log| log.stats.push(("a".to_string(), "b".to_string())));
+        session.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+        session.finish("done".to_string()).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("seconds: done\n") && text.contains("  a: b\n"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_layer_notes_warnings_with_their_fields() {
+        let path = std::env::temp_dir().join(format!("session-layer-{}.log", std::process::id()));
+        let session = Arc::new(Session::new(path, LogFormat::Pretty, FLUSH_INTERVAL));
+        let subscriber = tracing_subscriber::registry().with(SessionLayer(session.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("not noted");
+            tracing::warn!(retries = 3, "restarting the streams");
+            tracing::error!(stream = "output stream", "failed");
+        });
+        let log = session.log.lock().unwrap();
+        let noted: Vec<_> = log
+            .warnings
+            .iter()
+            .map(|x| (x.level, x.message.as_str()))
+            .collect();
+        assert_eq!(
+            noted,
+            [
+                ("WARN", "restarting the streams (retries=3)"),
+                ("ERROR", "failed (stream=output stream)"),
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_sessions_note_nothing() {
+        let session = Session::disabled();
+        session.change("moved".to_string());
+        session.finish("done".to_string()).unwrap();
+        assert_eq!(*session.log.lock().unwrap(), SessionLog::default());
+    }
+}
@@ -0,0 +1,249 @@
+//! External key signals for the compressor and the ducker.
+//!
+//! A sidechain is an extra input stream whose signal only feeds the detectors, never the audio
+//! path. Its callback picks one channel and pushes it into a small ring buffer per output,
+//! converted to the sample rate of that output, and the output callbacks take one key sample per
+//! frame from there.
+//!
+//! The sidechain runs on its own clock, so its ring slowly fills up or drains. A detector doesn't
+//! mind a few milliseconds of misalignment, so the ring may drift within [`DRIFT_TOLERANCE`] and
+//! skips ahead when it gets further behind. When no key arrives, the detectors key from their own
+//! signal until it's back.
+
+use std::time::Duration;
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+
+use crate::adapter::Converter;
+use crate::buffer::MAX_BLOCK_FRAMES;
+use crate::fanout::FanOut;
+use crate::logging::{AudioEvent, AudioLog};
+
+/// How far the key may lag behind the signal it controls.
+pub const DRIFT_TOLERANCE: Duration = Duration::from_millis(5);
+
+/// The key of a detector for one block.
+#[derive(Clone, Copy)]
+pub enum Key<'a> {
+    /// Interleaved frames of a signal, keyed by the peak of each frame.
+    Frames(&'a [f32]),
+    /// One sample per frame, from a sidechain.
+    Mono(&'a [f32]),
+}
+
+impl Key<'_> {
+    /// Number of frames of key, for signals of `channels` channels.
+    pub fn frames(&self, channels: usize) -> usize {
+        match self {
+            Key::Frames(data) => data.len() / channels,
+            Key::Mono(key) => key.len(),
+        }
+    }
+
+    /// Rectified level of the key at `frame`.
+    #[inline]
+    pub fn level(&self, frame: usize, channels: usize) -> f32 {
+        match self {
+            Key::Frames(data) => data[frame * channels..(frame + 1) * channels]
+                .iter()
+                .fold(0.0f32, |peak, x| peak.max(x.abs())),
+            Key::Mono(key) => key[frame].abs(),
+        }
+    }
+}
+
+/// Configuration of the key signal of a stream at `sample_rate`.
+pub fn key_config(sample_rate: SampleRate) -> StreamConfig {
+    StreamConfig {
+        channels: 1,
+        sample_rate,
+        buffer_size: BufferSize::Default,
+    }
+}
+
+/// Creates the ring buffer carrying the key to one output at `sample_rate`, prefilled with the
+/// drift tolerance of silence.
+pub fn key_ring(sample_rate: u32) -> (HeapProd<f32>, KeyReceiver) {
+    let tolerance = (DRIFT_TOLERANCE.as_secs_f64() * sample_rate as f64) as usize;
+    let ring = HeapRb::<f32>::new(tolerance * 4 + MAX_BLOCK_FRAMES * 2);
+    let (mut producer, consumer) = ring.split();
+    producer.push_iter(std::iter::repeat_n(0.0, tolerance));
+    let receiver = KeyReceiver {
+        consumer,
+        tolerance,
+        key: Vec::new(),
+        receiving: true,
+    };
+    (producer, receiver)
+}
+
+/// The sidechain callback's end: picks the key channel and sends it to every output.
+pub struct KeySender {
+    channels: usize,
+    channel: usize,
+    fan_out: FanOut<HeapProd<f32>>,
+    key: Vec<f32>,
+}
+
+impl KeySender {
+    /// Creates a sender taking `channel` of a stream of `channels` channels, to rings converting
+    /// from the sidechain's key configuration to each output's.
+    pub fn new(channels: usize, channel: usize, outputs: Vec<(HeapProd<f32>, Converter)>) -> Self {
+        KeySender {
+            channels,
+            channel,
+            fan_out: FanOut::new(outputs),
+            key: Vec::new(),
+        }
+    }
+
+    /// Allocates what blocks of up to `max_frames` need, so that the callback doesn't. Only the
+    /// first `max_frames` of a larger block are sent.
+    pub fn prepare(&mut self, max_frames: usize) {
+        if self.key.capacity() < max_frames {
+            self.key = Vec::with_capacity(max_frames);
+        }
+    }
+
+    /// Sends the key channel of the interleaved block `data`.
+    pub fn push(&mut self, data: &[f32]) {
+        self.key.clear();
+        self.key.extend(
+            data.chunks_exact(self.channels)
+                .take(self.key.capacity())
+                .map(|frame| frame[self.channel]),
+        );
+        // A full ring only means the output is far behind, and it skips ahead anyway.
+        self.fan_out.push(&self.key, |_| {});
+    }
+}
+
+/// An output callback's end of a sidechain.
+pub struct KeyReceiver {
+    consumer: HeapCons<f32>,
+    tolerance: usize,
+    key: Vec<f32>,
+    receiving: bool,
+}
+
+impl KeyReceiver {
+    /// Allocates what blocks of up to `max_frames` need, so that the callback doesn't. A larger
+    /// block only gets the key for its first `max_frames`.
+    pub fn prepare(&mut self, max_frames: usize) {
+        if self.key.len() < max_frames {
+            self.key.resize(max_frames, 0.0);
+        }
+    }
+
+    /// Takes the key for the next `frames` frames, or returns `None` when the sidechain sent
+    /// nothing, in which case the detectors should key from their own signal.
+    ///
+    /// `label` names the output in the events logged when the key stops and comes back.
+    pub fn receive(
+        &mut self,
+        frames: usize,
+        label: &'static str,
+        log: &mut AudioLog,
+    ) -> Option<&[f32]> {
+        let frames = frames.min(self.key.len());
+        let popped = self.consumer.pop_slice(&mut self.key[..frames]);
+        if popped == 0 {
+            if std::mem::replace(&mut self.receiving, false) {
+                log.push(AudioEvent::SidechainLost { output: label });
+            }
+            return None;
+        }
+        if !std::mem::replace(&mut self.receiving, true) {
+            log.push(AudioEvent::SidechainBack { output: label });
+        }
+        // A short block holds the last level until the sidechain catches up.
+        let last = self.key[popped - 1];
+        self.key[popped..frames].fill(last);
+        let occupied = self.consumer.occupied_len();
+        if occupied > self.tolerance * 2 + frames {
+            self.consumer.skip(occupied - self.tolerance);
+        }
+        Some(&self.key[..frames])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A receiver prepared for blocks of up to 240 frames, and the ring to it.
+    fn prepared() -> (HeapProd<f32>, KeyReceiver) {
+        let (producer, mut receiver) = key_ring(48_000);
+        receiver.prepare(240);
+        (producer, receiver)
+    }
+
+    fn receive(receiver: &mut KeyReceiver, frames: usize) -> Option<Vec<f32>> {
+        let mut log = AudioLog::disabled();
+        receiver
+            .receive(frames, "test", &mut log)
+            .map(<[f32]>::to_vec)
+    }
+
+    #[test]
+    fn frame_keys_take_the_peak_and_mono_keys_the_sample() {
+        let frames = Key::Frames(&[0.1, -0.4, 0.3, 0.2]);
+        assert_eq!(frames.frames(2), 2);
+        assert_eq!((frames.level(0, 2), frames.level(1, 2)), (0.4, 0.3));
+        let mono = Key::Mono(&[-0.5, 0.25]);
+        assert_eq!(mono.frames(8), 2);
+        assert_eq!(mono.level(0, 8), 0.5);
+    }
+
+    #[test]
+    fn the_sender_picks_its_channel_for_every_output() {
+        let key = key_config(SampleRate(48_000));
+        let (first, mut first_key) = prepared();
+        let (second, mut second_key) = prepared();
+        let mut sender = KeySender::new(
+            2,
+            1,
+            vec![
+                (first, Converter::new(&key, &key)),
+                (second, Converter::new(&key, &key)),
+            ],
+        );
+        sender.prepare(2);
+        // The rings start with the drift tolerance of silence.
+        assert_eq!(receive(&mut first_key, 240), Some(vec![0.0; 240]));
+        assert_eq!(receive(&mut second_key, 240), Some(vec![0.0; 240]));
+        sender.push(&[0.1, 0.9, 0.2, 0.8]);
+        assert_eq!(receive(&mut first_key, 2), Some(vec![0.9, 0.8]));
+        assert_eq!(receive(&mut second_key, 2), Some(vec![0.9, 0.8]));
+    }
+
+    #[test]
+    fn short_keys_hold_their_last_level_and_missing_ones_fall_back() {
+        let (mut producer, mut receiver) = prepared();
+        assert!(receive(&mut receiver, 240).is_some());
+        producer.push_slice(&[0.5, 0.7]);
+        assert_eq!(receive(&mut receiver, 4), Some(vec![0.5, 0.7, 0.7, 0.7]));
+        assert_eq!(receive(&mut receiver, 4), None);
+    }
+
+    #[test]
+    fn a_key_far_behind_skips_ahead_to_the_tolerance() {
+        let (mut producer, mut receiver) = prepared();
+        producer.push_iter(std::iter::repeat_n(1.0, 2_000));
+        assert!(receive(&mut receiver, 10).is_some());
+        assert_eq!(receiver.consumer.occupied_len(), 240);
+    }
+
+    #[test]
+    fn blocks_larger_than_prepared_for_get_a_shorter_key() {
+        let key = key_config(SampleRate(48_000));
+        let (producer, mut receiver) = prepared();
+        let mut sender = KeySender::new(1, 0, vec![(producer, Converter::new(&key, &key))]);
+        sender.prepare(2);
+        sender.push(&[0.1, 0.2, 0.3, 0.4]);
+        assert_eq!(receive(&mut receiver, 480).map(|x| x.len()), Some(240));
+        assert_eq!(receive(&mut receiver, 4), Some(vec![0.1, 0.2, 0.2, 0.2]));
+    }
+}
@@ -0,0 +1,40 @@
+//! Counters shared between the audio callbacks and the main thread.
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// Number of callbacks in which a ring buffer couldn't keep up, for a single output.
+#[derive(Default)]
+pub struct XrunCounters {
+    /// Input callbacks whose samples didn't all fit in the ring buffer.
+    pub overruns: AtomicUsize,
+    /// Output callbacks that found the ring buffer empty before the end of the block.
+    pub underruns: AtomicUsize,
+}
+
+impl XrunCounters {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} overruns, {} underruns",
+            self.overruns.load(Ordering::Relaxed),
+            self.underruns.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// An f32 that one thread stores and others load, e.g. a meter reading.
+#[derive(Default)]
+pub struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    pub fn new(value: f32) -> Self {
+        AtomicF32(AtomicU32::new(value.to_bits()))
+    }
+
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,159 @@
+//! The status line printed every interval while the monitor runs, once per second by default.
+//!
+//! Parts of the engine that have something to show add a field, a closure reading their shared
+//! meters, and a reporting thread prints all the fields on one line. The control socket answers
+//! status queries with the same line. Fields can flag themselves as warnings, which are printed
+//! in red on a terminal. Meters that aggregate over the interval, e.g. the input levels, are taken
+//! by the reporting thread at the end of each, before the line is printed, so they go at its
+//! cadence whether it prints or not.
+
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How often the status line is printed when no interval is given.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+/// Shortest interval the status line can be printed at.
+pub const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A field's text, and whether it's a warning.
+type Field = Box<dyn Fn() -> (String, bool) + Send + Sync>;
+/// Something run at the end of every interval, with how long it lasted.
+type Tick = Box<dyn Fn(Duration) + Send + Sync>;
+
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// The fields of the status line, before reporting starts.
+#[derive(Default)]
+pub struct StatusLine {
+    fields: Vec<Field>,
+    ticks: Vec<Tick>,
+}
+
+impl StatusLine {
+    /// Adds a field, e.g. "AEC: 18.2 dB ERLE".
+    pub fn add(&mut self, field: impl Fn() -> String + Send + Sync + 'static) {
+        self.fields.push(Box::new(move || (field(), false)));
+    }
+
+    /// Adds a field telling whether it's a warning, e.g. `("corr: -0.40", true)`.
+    pub fn add_flagged(&mut self, field: impl Fn() -> (String, bool) + Send + Sync + 'static) {
+        self.fields.push(Box::new(field));
+    }
+
+    /// Runs `tick` at the end of every interval, before the fields are read, with how long the
+    /// interval lasted, e.g. to take the meters aggregated over it.
+    pub fn on_interval(&mut self, tick: impl Fn(Duration) + Send + Sync + 'static) {
+        self.ticks.push(Box::new(tick));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The fields as they are now, joined into one line.
+    pub fn line(&self) -> String {
+        self.render(false)
+    }
+
+    /// The line, with the warnings in red if `color`.
+    fn render(&self, color: bool) -> String {
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|x| match x() {
+                (text, true) if color => format!("{}{}{}", RED, text, RESET),
+                (text, _) => text,
+            })
+            .collect();
+        fields.join(" | ")
+    }
+
+    /// Starts printing the line every `interval`, timed from `start`, or only ending the
+    /// intervals unless `print`, e.g. for the control socket of a daemon.
+    pub fn spawn(self: Arc<Self>, start: Instant, interval: Duration, print: bool) -> Reporter {
+        let running = Arc::new(AtomicBool::new(true));
+        let color = std::io::stdout().is_terminal();
+        let thread = {
+            let running = running.clone();
+            std::thread::spawn(move || {
+                let mut next = start + interval;
+                let mut last = start;
+                loop {
+                    // Sleep in short steps, so that stopping doesn't wait for a whole interval.
+                    while Instant::now() < next {
+                        if !running.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        let left = next.saturating_duration_since(Instant::now());
+                        std::thread::sleep(left.min(Duration::from_millis(50)));
+                    }
+                    let now = Instant::now();
+                    for tick in &self.ticks {
+                        tick(now - last);
+                    }
+                    last = now;
+                    if print {
+                        println!(
+                            "[{:.1} s] {}",
+                            start.elapsed().as_secs_f32(),
+                            self.render(color)
+                        );
+                    }
+                    next += interval;
+                }
+            })
+        };
+        Reporter { running, thread }
+    }
+}
+
+/// The interval of `--stats-interval`, in milliseconds, or `None` for 0, which disables the
+/// status line.
+pub fn interval(milliseconds: u64) -> Result<Option<Duration>, String> {
+    let interval = Duration::from_millis(milliseconds);
+    match milliseconds {
+        0 => Ok(None),
+        _ if interval < MIN_INTERVAL => Err(format!(
+            "invalid stats interval {} ms, expected 0 to disable it, or at least {} ms",
+            milliseconds,
+            MIN_INTERVAL.as_millis()
+        )),
+        _ => Ok(Some(interval)),
+    }
+}
+
+/// The running reporting thread.
+pub struct Reporter {
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Reporter {
+    pub fn stop(self) {
+        self.running.store(false, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warnings_are_red_only_in_color() {
+        let mut status = StatusLine::default();
+        assert!(status.is_empty());
+        status.add(|| "in: 48000 Hz".to_string());
+        status.add_flagged(|| ("corr: -0.40".to_string(), true));
+        status.add_flagged(|| ("corr: +0.40".to_string(), false));
+        assert_eq!(status.line(), "in: 48000 Hz | corr: -0.40 | corr: +0.40");
+        assert_eq!(
+            status.render(true),
+            "in: 48000 Hz | \x1b[31mcorr: -0.40\x1b[0m | corr: +0.40"
+        );
+    }
+}
@@ -0,0 +1,329 @@
+//! Time-stretching without a change of pitch, by waveform-similarity overlap-add (WSOLA).
+//!
+//! The output is built of windowed segments of the source, two hops long, one every hop. At
+//! speed `s`, each segment is due a hop times `s` further into the source than the last one; it's
+//! taken from within [`TOLERANCE`] of there where it best continues the last one, so that the
+//! periods of a tone line up across the overlap instead of beating. Slower than 1 repeats
+//! periods and faster drops some, without resampling any, so the pitch stays.
+//!
+//! Where the segments are due is kept apart from where they're taken, so the position in the
+//! source doesn't drift: `n` frames of output at speed `s` move it `n * s` frames, to within a
+//! hop. At a speed of 1 every segment is taken where it's due, and the output is the source.
+
+use std::sync::Arc;
+
+use crate::stats::AtomicF32;
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 1.5;
+
+/// Output between segments, in seconds.
+const HOP: f64 = 0.02;
+/// How far from where it's due a segment may be taken, in seconds. A little over half the period
+/// of the lowest tones lined up.
+const TOLERANCE: f64 = 0.01;
+/// Offsets apart in the first pass of the search, which only looks at every other frame too.
+/// The second pass tries every offset around the best of the first, over every frame.
+const COARSE_STEP: usize = 4;
+
+/// Whether `speed` is one the stretcher plays at.
+pub fn is_valid_speed(speed: f32) -> bool {
+    (MIN_SPEED..=MAX_SPEED).contains(&speed)
+}
+
+/// Stretches an interleaved source to the speed in a shared value, which may change at any time.
+pub struct Stretcher {
+    speed: Arc<AtomicF32>,
+    channels: usize,
+    sample_rate: u32,
+    hop: usize,
+    tolerance: usize,
+    /// A periodic Hann window of two hops, whose halves sum to 1 when overlapped.
+    window: Vec<f32>,
+    /// Source frame where the next segment is due.
+    due: f64,
+    /// Source frame following the first half of the last segment, which the first half of the
+    /// next one overlaps and should look like.
+    continuation: isize,
+    /// The second half of the last segment, windowed.
+    tail: Vec<f32>,
+    /// A hop of output, of which `read` frames have been read.
+    ready: Vec<f32>,
+    read: usize,
+    started: bool,
+    finished: bool,
+}
+
+impl Stretcher {
+    /// Creates a stretcher of a source of `channels` channels at `sample_rate`, from its start.
+    pub fn new(speed: Arc<AtomicF32>, channels: usize, sample_rate: u32) -> Self {
+        let hop = ((HOP * sample_rate as f64) as usize).max(COARSE_STEP);
+        let window = (0..2 * hop)
+            .map(|n| {
+                let phase = std::f64::consts::PI * n as f64 / hop as f64;
+                (0.5 - 0.5 * phase.cos()) as f32
+            })
+            .collect();
+        Stretcher {
+            speed,
+            channels,
+            sample_rate,
+            hop,
+            tolerance: (TOLERANCE * sample_rate as f64) as usize,
+            window,
+            due: 0.0,
+            continuation: 0,
+            tail: vec![0.0; hop * channels],
+            ready: vec![0.0; hop * channels],
+            read: hop,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Fills `out` with the next stretched samples of `source`, which must be the same every
+    /// call, and returns how many it wrote, fewer than fit only at the end of the source.
+    pub fn read(&mut self, source: &[f32], out: &mut [f32]) -> usize {
+        let channels = self.channels;
+        let frames = out.len() / channels;
+        let mut written = 0;
+        while written < frames {
+            if self.read == self.hop && !self.next_segment(source) {
+                break;
+            }
+            let length = (self.hop - self.read).min(frames - written);
+            out[written * channels..(written + length) * channels].copy_from_slice(
+                &self.ready[self.read * channels..(self.read + length) * channels],
+            );
+            self.read += length;
+            written += length;
+        }
+        written * channels
+    }
+
+    /// Carries on from the same time of a source now at `sample_rate`, e.g. one converted again
+    /// for an output at another rate. The output fades in again over a hop.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        let ratio = sample_rate as f64 / self.sample_rate as f64;
+        let mut stretcher = Stretcher::new(self.speed.clone(), self.channels, sample_rate);
+        stretcher.due = self.due * ratio;
+        stretcher.continuation = (self.continuation as f64 * ratio).round() as isize;
+        stretcher.started = self.started;
+        stretcher.finished = self.finished;
+        *self = stretcher;
+    }
+
+    /// Whether the whole source has been read.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Overlap-adds the next segment, for another hop of output, unless the source has ended.
+    fn next_segment(&mut self, source: &[f32]) -> bool {
+        let frames = (source.len() / self.channels) as isize;
+        let due = self.due.round() as isize;
+        if self.finished || (due >= frames && self.continuation >= frames) {
+            self.finished = true;
+            return false;
+        }
+        let (channels, hop) = (self.channels, self.hop);
+        if !self.started {
+            // As if a segment had been taken a hop before the start, so the output doesn't fade in.
+            for (index, tail) in self.tail.iter_mut().enumerate() {
+                let (n, channel) = (index / channels, index % channels);
+                *tail = self.window[hop + n] * sample(source, channels, n as isize, channel);
+            }
+            self.started = true;
+        }
+        let start = self.search(source, due, frames);
+        for n in 0..hop {
+            for channel in 0..channels {
+                let index = n * channels + channel;
+                let early = sample(source, channels, start + n as isize, channel);
+                let late = sample(source, channels, start + (hop + n) as isize, channel);
+                self.ready[index] = self.tail[index] + self.window[n] * early;
+                self.tail[index] = self.window[hop + n] * late;
+            }
+        }
+        self.continuation = start + hop as isize;
+        let speed = self.speed.load().clamp(MIN_SPEED, MAX_SPEED);
+        self.due += hop as f64 * speed as f64;
+        self.read = 0;
+        true
+    }
+
+    /// The start, within the tolerance of `due`, of the hop of `source` most like the one at the
+    /// continuation of the last segment. Ties go to the offset closest to `due`.
+    fn search(&self, source: &[f32], due: isize, frames: isize) -> isize {
+        let tolerance = self.tolerance as isize;
+        let (low, high) = ((due - tolerance).max(0), (due + tolerance).min(frames));
+        if high <= low {
+            return due;
+        }
+        let mut best = (due, self.similarity(source, due, 1));
+        let consider = |start: isize, stride: usize, best: &mut (isize, f32)| {
+            let score = self.similarity(source, start, stride);
+            let closer = (start - due).abs() < (best.0 - due).abs();
+            if score > best.1 || (score == best.1 && closer) {
+                *best = (start, score);
+            }
+        };
+        let step = COARSE_STEP as isize;
+        let mut coarse = (due, self.similarity(source, due, 2));
+        let mut offset = step;
+        while offset <= tolerance {
+            for start in [due - offset, due + offset] {
+                if (low..=high).contains(&start) {
+                    consider(start, 2, &mut coarse);
+                }
+            }
+            offset += step;
+        }
+        for start in (coarse.0 - step + 1).max(low)..(coarse.0 + step).min(high + 1) {
+            consider(start, 1, &mut best);
+        }
+        best.0
+    }
+
+    /// The correlation of the mono hop of `source` at `start` with the one at the continuation,
+    /// over the energy of the first, looking at every `stride`th frame.
+    fn similarity(&self, source: &[f32], start: isize, stride: usize) -> f32 {
+        let (mut product, mut energy) = (0.0, 0.0);
+        for n in (0..self.hop as isize).step_by(stride) {
+            let candidate = self.mono(source, start + n);
+            product += candidate * self.mono(source, self.continuation + n);
+            energy += candidate * candidate;
+        }
+        match energy > 0.0 {
+            true => product / energy.sqrt(),
+            false => 0.0,
+        }
+    }
+
+    fn mono(&self, source: &[f32], frame: isize) -> f32 {
+        (0..self.channels)
+            .map(|channel| sample(source, self.channels, frame, channel))
+            .sum()
+    }
+}
+
+/// A sample of `source`, silent outside it.
+fn sample(source: &[f32], channels: usize, frame: isize, channel: usize) -> f32 {
+    match usize::try_from(frame) {
+        Ok(frame) => source
+            .get(frame * channels + channel)
+            .copied()
+            .unwrap_or(0.0),
+        Err(_) => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    /// A second of a stereo sine at `frequency`, louder on the left.
+    fn sine(frequency: f32) -> Vec<f32> {
+        let omega = std::f32::consts::TAU * frequency / SAMPLE_RATE as f32;
+        (0..SAMPLE_RATE)
+            .flat_map(|n| {
+                let x = (omega * n as f32).sin();
+                [0.5 * x, 0.25 * x]
+            })
+            .collect()
+    }
+
+    /// All of `source` stretched at `speed`, read in blocks of 256 frames.
+    fn stretch(source: &[f32], speed: f32) -> Vec<f32> {
+        let mut stretcher = Stretcher::new(Arc::new(AtomicF32::new(speed)), 2, SAMPLE_RATE);
+        let (mut out, mut block) = (Vec::new(), vec![0.0; 512]);
+        while !stretcher.is_finished() {
+            let written = stretcher.read(source, &mut block);
+            out.extend_from_slice(&block[..written]);
+        }
+        out
+    }
+
+    /// The frequency of the left channel of `data`, by its rising zero crossings.
+    fn frequency(data: &[f32]) -> f32 {
+        let left: Vec<f32> = data.iter().step_by(2).copied().collect();
+        let crossings = left
+            .windows(2)
+            .filter(|x| x[0] < 0.0 && x[1] >= 0.0)
+            .count();
+        crossings as f32 * SAMPLE_RATE as f32 / left.len() as f32
+    }
+
+    #[test]
+    fn speeds_are_from_half_to_one_and_a_half() {
+        assert!(is_valid_speed(MIN_SPEED));
+        assert!(is_valid_speed(1.0));
+        assert!(is_valid_speed(MAX_SPEED));
+        assert!(!is_valid_speed(0.4));
+        assert!(!is_valid_speed(1.6));
+    }
+
+    #[test]
+    fn at_a_speed_of_one_the_output_is_the_source() {
+        let source = sine(440.0);
+        let out = stretch(&source, 1.0);
+        assert!(out.len() >= source.len());
+        assert!(source.iter().zip(&out).all(|(x, y)| (x - y).abs() < 1e-5));
+    }
+
+    #[test]
+    fn other_speeds_change_the_length_but_not_the_pitch() {
+        let source = sine(440.0);
+        let hop = (HOP * SAMPLE_RATE as f64) as usize;
+        for speed in [MIN_SPEED, 0.8, 1.25, MAX_SPEED] {
+            let out = stretch(&source, speed);
+            let expected = (SAMPLE_RATE as f32 / speed) as usize;
+            let frames = out.len() / 2;
+            assert!(
+                frames.abs_diff(expected) <= 2 * hop,
+                "{} frames at {}",
+                frames,
+                speed
+            );
+            // Away from the silence the last segments reach into past the end.
+            let pitch = frequency(&out[..out.len() * 9 / 10]);
+            assert!((pitch - 440.0).abs() < 5.0, "{} Hz at {}", pitch, speed);
+        }
+    }
+
+    #[test]
+    fn the_speed_can_change_during_the_run() {
+        let source = sine(440.0);
+        let speed = Arc::new(AtomicF32::new(MIN_SPEED));
+        let mut stretcher = Stretcher::new(speed.clone(), 2, SAMPLE_RATE);
+        let mut out = vec![0.0; SAMPLE_RATE as usize];
+        // Half a second of output at half speed reads a quarter of the source.
+        stretcher.read(&source, &mut out);
+        speed.store(MAX_SPEED);
+        let mut frames = SAMPLE_RATE as usize / 2;
+        while !stretcher.is_finished() {
+            frames += stretcher.read(&source, &mut out) / 2;
+        }
+        // The rest, three quarters of a second of it, at one and a half.
+        let expected = SAMPLE_RATE as usize / 2 + SAMPLE_RATE as usize / 2;
+        let hop = (HOP * SAMPLE_RATE as f64) as usize;
+        assert!(frames.abs_diff(expected) <= 2 * hop, "{} frames", frames);
+    }
+
+    #[test]
+    fn reads_only_come_short_at_the_end_of_the_source() {
+        let source = vec![0.1; 2 * 10_000];
+        let mut stretcher = Stretcher::new(Arc::new(AtomicF32::new(1.0)), 2, SAMPLE_RATE);
+        let mut block = vec![0.0; 2 * 300];
+        let mut reads = Vec::new();
+        while !stretcher.is_finished() {
+            reads.push(stretcher.read(&source, &mut block));
+        }
+        let (last, full) = reads.split_last().unwrap();
+        assert!(full.iter().all(|x| *x == block.len()));
+        assert!(*last < block.len());
+        assert_eq!(stretcher.read(&source, &mut block), 0);
+    }
+}
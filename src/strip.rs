@@ -0,0 +1,326 @@
+//! Channel strips: the stages of a mixing console's channel, from one flag.
+//!
+//! A strip runs, in order, a high-pass against rumble and handling noise, a noise gate, the
+//! parametric EQ, a compressor and a limiter, each left out when it has no settings. The
+//! limiter goes in on its own, after whatever else in the chain sets the level, e.g. the AGC or
+//! the gain, so that nothing after it takes the peaks over its ceiling. The
+//! presets of `--channel-strip` fill in all five for a kind of source, and the flags of the
+//! stages, e.g. `--gate` or `--eq`, override them one by one. Every stage that has parameters
+//! can then be moved during the run like any other effect, e.g. "gate.threshold".
+//!
+//! The compressor is one of its stages in the chain, keyed by its own signal, unless a
+//! sidechain keys it from after the chain.
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::bail;
+
+use crate::compressor::{Compressor, CompressorSettings};
+use crate::effects::{
+    Biquad, Dynamics, EffectChain, EqBand, EqStats, FilterKind, Gate, GateSpec, Interleaved,
+    Limiter, LimiterSpec, ParametricEq, BUTTERWORTH_Q,
+};
+use crate::sample::Sample;
+
+/// The strips of `--channel-strip`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StripPreset {
+    /// A voice on a close microphone, singing or speaking.
+    Vocal,
+    /// Speech to be listened to for a long time: a steadier level, and less boom.
+    Podcast,
+    /// An acoustic guitar, or a guitar amplifier on a microphone.
+    Guitar,
+}
+
+impl FromStr for StripPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vocal" => Ok(StripPreset::Vocal),
+            "podcast" => Ok(StripPreset::Podcast),
+            "guitar" => Ok(StripPreset::Guitar),
+            _ => Err(format!(
+                "unknown channel strip \"{}\", expected vocal, podcast or guitar",
+                s
+            )),
+        }
+    }
+}
+
+/// The settings of every stage of a preset.
+struct Preset {
+    highpass: f32,
+    gate: GateSpec,
+    eq: &'static [EqBand],
+    compressor: CompressorSettings,
+    limiter: LimiterSpec,
+}
+
+const VOCAL: Preset = Preset {
+    highpass: 80.0,
+    gate: GateSpec {
+        threshold_db: -45.0,
+        range_db: 30.0,
+        attack_ms: 1.0,
+        release_ms: 120.0,
+        hold_ms: 60.0,
+    },
+    // Less mud, more presence, and a little air.
+    eq: &[
+        EqBand::peak(300.0, -2.0, 1.0),
+        EqBand::peak(3_500.0, 3.0, 1.0),
+        EqBand::peak(10_000.0, 1.5, 0.7),
+    ],
+    compressor: CompressorSettings {
+        threshold_db: -18.0,
+        ratio: 3.0,
+        attack_ms: 5.0,
+        release_ms: 80.0,
+    },
+    limiter: LimiterSpec {
+        ceiling_db: -1.0,
+        release_ms: 50.0,
+    },
+};
+
+const PODCAST: Preset = Preset {
+    highpass: 90.0,
+    gate: GateSpec {
+        threshold_db: -50.0,
+        range_db: 20.0,
+        attack_ms: 2.0,
+        release_ms: 200.0,
+        hold_ms: 120.0,
+    },
+    // The boom of a voice close to the microphone only when it booms, and clarity.
+    eq: &[
+        EqBand::dynamic(
+            200.0,
+            -4.0,
+            1.2,
+            Dynamics {
+                threshold_db: -30.0,
+                ratio: 2.0,
+                attack_ms: 5.0,
+                release_ms: 100.0,
+            },
+        ),
+        EqBand::peak(4_000.0, 2.0, 0.9),
+    ],
+    compressor: CompressorSettings {
+        threshold_db: -22.0,
+        ratio: 4.0,
+        attack_ms: 10.0,
+        release_ms: 150.0,
+    },
+    limiter: LimiterSpec {
+        ceiling_db: -1.0,
+        release_ms: 80.0,
+    },
+};
+
+const GUITAR: Preset = Preset {
+    // Under the low E, at 82 Hz.
+    highpass: 70.0,
+    gate: GateSpec {
+        threshold_db: -55.0,
+        range_db: 40.0,
+        attack_ms: 0.5,
+        release_ms: 250.0,
+        hold_ms: 100.0,
+    },
+    // Less boom of the body, more pick attack.
+    eq: &[
+        EqBand::peak(200.0, -2.5, 0.8),
+        EqBand::peak(2_500.0, 2.0, 1.2),
+    ],
+    compressor: CompressorSettings {
+        threshold_db: -16.0,
+        ratio: 2.5,
+        attack_ms: 15.0,
+        release_ms: 150.0,
+    },
+    limiter: LimiterSpec {
+        ceiling_db: -1.0,
+        release_ms: 50.0,
+    },
+};
+
+impl StripPreset {
+    /// The strip of the preset, with every stage.
+    pub fn strip(self) -> ChannelStrip {
+        let preset = match self {
+            StripPreset::Vocal => &VOCAL,
+            StripPreset::Podcast => &PODCAST,
+            StripPreset::Guitar => &GUITAR,
+        };
+        ChannelStrip {
+            highpass: Some(preset.highpass),
+            gate: Some(preset.gate),
+            eq: preset.eq.to_vec(),
+            compressor: Some(preset.compressor),
+            limiter: Some(preset.limiter),
+        }
+    }
+}
+
+/// The stages of a strip, each left out when it's `None`, or the EQ when it has no bands.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelStrip {
+    /// Corner of the 12 dB/octave high-pass, in Hz.
+    pub highpass: Option<f32>,
+    pub gate: Option<GateSpec>,
+    pub eq: Vec<EqBand>,
+    pub compressor: Option<CompressorSettings>,
+    pub limiter: Option<LimiterSpec>,
+}
+
+impl ChannelStrip {
+    /// The strip with every stage of `overrides` in place of its own. EQ bands replace all of
+    /// the strip's.
+    pub fn with(self, overrides: ChannelStrip) -> Self {
+        ChannelStrip {
+            highpass: overrides.highpass.or(self.highpass),
+            gate: overrides.gate.or(self.gate),
+            eq: match overrides.eq.is_empty() {
+                true => self.eq,
+                false => overrides.eq,
+            },
+            compressor: overrides.compressor.or(self.compressor),
+            limiter: overrides.limiter.or(self.limiter),
+        }
+    }
+
+    /// Pushes the stages but the limiter onto `chain`, of a stream of `channels` channels at
+    /// `sample_rate`, with the EQ publishing the gains of its dynamic bands to `eq_stats`. Fails
+    /// if a frequency is too close to the Nyquist frequency to be stable.
+    pub fn build<S: Sample>(
+        &self,
+        chain: &mut EffectChain<S>,
+        sample_rate: f32,
+        channels: usize,
+        eq_stats: Option<Arc<EqStats>>,
+    ) -> anyhow::Result<()> {
+        if let Some(frequency) = self.highpass {
+            if frequency >= sample_rate * 0.49 {
+                bail!(
+                    "cannot put the high-pass at {} Hz in a stream at {} Hz",
+                    frequency,
+                    sample_rate
+                );
+            }
+            let kind = FilterKind::HighPass;
+            chain.push(Biquad::new(
+                kind,
+                frequency,
+                BUTTERWORTH_Q,
+                sample_rate,
+                channels,
+            ));
+        }
+        if let Some(spec) = self.gate {
+            chain.push(Gate::new(spec, sample_rate));
+        }
+        if !self.eq.is_empty() {
+            chain.push(ParametricEq::new(
+                &self.eq,
+                sample_rate,
+                channels,
+                eq_stats,
+            )?);
+        }
+        if let Some(settings) = self.compressor {
+            let compressor = Compressor::new(settings, channels, sample_rate as u32);
+            chain.push(Interleaved::new(compressor));
+        }
+        Ok(())
+    }
+
+    /// Pushes the limiter onto `chain`, of a stream at `sample_rate`, once the stages that set the
+    /// level are in.
+    pub fn build_limiter<S: Sample>(&self, chain: &mut EffectChain<S>, sample_rate: f32) {
+        if let Some(spec) = self.limiter {
+            chain.push(Limiter::new(spec, sample_rate));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::Gain;
+
+    #[test]
+    fn flags_replace_the_stages_of_the_preset() {
+        let gate = GateSpec {
+            threshold_db: -30.0,
+            ..GateSpec::default()
+        };
+        let compressor = CompressorSettings {
+            threshold_db: -10.0,
+            ratio: 8.0,
+            attack_ms: 1.0,
+            release_ms: 40.0,
+        };
+        let limiter = LimiterSpec {
+            ceiling_db: -6.0,
+            release_ms: 20.0,
+        };
+        let flags = ChannelStrip {
+            highpass: Some(120.0),
+            gate: Some(gate),
+            eq: vec![EqBand::peak(1_000.0, 6.0, 2.0)],
+            compressor: Some(compressor),
+            limiter: Some(limiter),
+        };
+        let strip = StripPreset::Vocal.strip().with(flags);
+        assert_eq!(strip.highpass, Some(120.0));
+        assert_eq!(strip.gate, Some(gate));
+        assert_eq!(strip.eq, vec![EqBand::peak(1_000.0, 6.0, 2.0)]);
+        assert_eq!(strip.compressor.map(|x| x.ratio), Some(8.0));
+        assert_eq!(strip.limiter, Some(limiter));
+    }
+
+    #[test]
+    fn stages_without_a_flag_keep_the_preset() {
+        let limiter = LimiterSpec {
+            ceiling_db: -6.0,
+            release_ms: 20.0,
+        };
+        let flags = ChannelStrip {
+            limiter: Some(limiter),
+            ..ChannelStrip::default()
+        };
+        let strip = StripPreset::Podcast.strip().with(flags);
+        assert_eq!(strip.highpass, Some(PODCAST.highpass));
+        assert_eq!(strip.gate, Some(PODCAST.gate));
+        assert_eq!(strip.eq, PODCAST.eq);
+        assert_eq!(
+            strip.compressor.map(|x| x.threshold_db),
+            Some(PODCAST.compressor.threshold_db)
+        );
+        assert_eq!(strip.limiter, Some(limiter));
+    }
+
+    #[test]
+    fn the_limiter_holds_its_ceiling_over_a_gain_after_the_stages() {
+        let strip = StripPreset::Guitar.strip();
+        let mut chain: EffectChain = EffectChain::new(2);
+        strip.build(&mut chain, 48_000.0, 2, None).unwrap();
+        chain.push(Gain::new(24.0));
+        strip.build_limiter(&mut chain, 48_000.0);
+        let mut peak = 0f32;
+        for block in 0..40 {
+            let mut data: Vec<f32> = (0..512)
+                .map(|x| 0.5 * ((block * 256 + x / 2) as f32 * 0.05).sin())
+                .collect();
+            chain.process(&mut data);
+            peak = data.iter().fold(peak, |peak, x| peak.max(x.abs()));
+        }
+        let ceiling = crate::level::db_to_gain(GUITAR.limiter.ceiling_db);
+        assert!(peak > 0.5 * ceiling && peak <= ceiling + 1e-6, "{}", peak);
+    }
+}
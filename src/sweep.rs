@@ -0,0 +1,193 @@
+//! Exponential sine sweeps, and the impulse responses deconvolved from what comes back of them.
+//!
+//! A sweep spends as long in every octave, so that each one gets the same energy, and its
+//! harmonic distortion lands before the linear response once deconvolved, where the window of
+//! the impulse response leaves it out. The deconvolution divides the spectrum of the capture by
+//! that of the sweep as played, regularised where the sweep has no energy.
+
+use std::f64::consts::TAU;
+use std::time::Duration;
+
+use crate::fade;
+use crate::fft::Fft;
+use crate::level;
+
+/// Length of the fades at both ends, which keep the sweep from clicking.
+const FADE: Duration = Duration::from_millis(10);
+/// Power of the regularisation relative to the peak power of the sweep's spectrum, -60 dB, far
+/// under that of the sweep in its band.
+const REGULARISATION: f32 = 1e-6;
+
+/// A sweep from `low` to `high` Hz.
+#[derive(Clone, Copy, Debug)]
+pub struct Sweep {
+    pub low: f64,
+    pub high: f64,
+    pub duration: Duration,
+    /// Peak level, in dBFS.
+    pub level_db: f32,
+    pub sample_rate: u32,
+}
+
+impl Sweep {
+    pub fn frames(&self) -> usize {
+        (self.duration.as_secs_f64() * self.sample_rate as f64) as usize
+    }
+
+    /// The sample at `frame`, silent after the end.
+    pub fn sample(&self, frame: usize) -> f32 {
+        let frames = self.frames();
+        if frame >= frames {
+            return 0.0;
+        }
+        let rate = self.sample_rate as f64;
+        let t = frame as f64 / rate;
+        // The time the frequency takes to rise by a factor of e.
+        let constant = self.duration.as_secs_f64() / (self.high / self.low).ln();
+        let phase = TAU * self.low * constant * ((t / constant).exp() - 1.0);
+        let fade = ((FADE.as_secs_f64() * rate) as usize).max(1);
+        let position = frame.min(frames - frame).min(fade) as f32 / fade as f32;
+        level::db_to_gain(self.level_db) * fade::gain(position) * phase.sin() as f32
+    }
+
+    /// The whole sweep, in mono.
+    pub fn render(&self) -> Vec<f32> {
+        (0..self.frames()).map(|n| self.sample(n)).collect()
+    }
+}
+
+/// Plays a sweep into interleaved blocks, the same signal in every channel.
+pub struct SweepPlayer {
+    sweep: Sweep,
+    frame: usize,
+}
+
+impl SweepPlayer {
+    pub fn new(sweep: Sweep) -> Self {
+        SweepPlayer { sweep, frame: 0 }
+    }
+
+    pub fn fill(&mut self, data: &mut [f32], channels: usize) {
+        for frame in data.chunks_mut(channels) {
+            frame.fill(self.sweep.sample(self.frame));
+            self.frame += 1;
+        }
+    }
+}
+
+/// The impulse response turning `played` into `captured`, both mono, as long as the FFT the
+/// deconvolution takes: a power of two fitting both. Responses that start before the capture,
+/// e.g. if the input started after the output, wrap around to the end.
+pub fn deconvolve(played: &[f32], captured: &[f32]) -> Vec<f32> {
+    let size = (played.len() + captured.len()).next_power_of_two();
+    let fft = Fft::new(size);
+    let transform = |x: &[f32]| {
+        let mut re = x.to_vec();
+        re.resize(size, 0.0);
+        let mut im = vec![0.0; size];
+        fft.forward(&mut re, &mut im);
+        (re, im)
+    };
+    let (xr, xi) = transform(played);
+    let (mut yr, mut yi) = transform(captured);
+    let peak = xr
+        .iter()
+        .zip(&xi)
+        .map(|(r, i)| r * r + i * i)
+        .fold(0.0, f32::max);
+    let epsilon = (peak * REGULARISATION).max(f32::MIN_POSITIVE);
+    for k in 0..size {
+        // Y · conj(X) / (|X|² + ε)
+        let power = xr[k] * xr[k] + xi[k] * xi[k] + epsilon;
+        let re = (yr[k] * xr[k] + yi[k] * xi[k]) / power;
+        let im = (yi[k] * xr[k] - yr[k] * xi[k]) / power;
+        yr[k] = re;
+        yi[k] = im;
+    }
+    fft.inverse(&mut yr, &mut yi);
+    yr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sweep() -> Sweep {
+        Sweep {
+            low: 20.0,
+            high: 20_000.0,
+            duration: Duration::from_millis(500),
+            level_db: -6.0,
+            sample_rate: 48_000,
+        }
+    }
+
+    #[test]
+    fn sweeps_fade_in_and_out_at_their_level() {
+        let sweep = sweep();
+        let samples = sweep.render();
+        assert_eq!(samples.len(), 24_000);
+        assert_eq!(samples[0], 0.0);
+        assert!(samples[23_999].abs() < 1e-3);
+        assert_eq!(sweep.sample(24_000), 0.0);
+        let peak = samples.iter().fold(0f32, |x, y| x.max(y.abs()));
+        assert!((peak - level::db_to_gain(-6.0)).abs() < 1e-3, "{}", peak);
+    }
+
+    #[test]
+    fn the_frequency_rises_exponentially() {
+        // Zero crossings per second around a time, for half a period each.
+        let samples = sweep().render();
+        let rate = |at: usize| {
+            let crossings = samples[at - 240..at + 240]
+                .windows(2)
+                .filter(|x| (x[0] < 0.0) != (x[1] < 0.0))
+                .count();
+            crossings as f64 * 100.0 / 2.0
+        };
+        // A factor of 1000 in 500 ms, so 10 times every third of it.
+        assert!((rate(8_000) / 200.0 - 1.0).abs() < 0.05, "{}", rate(8_000));
+        assert!(
+            (rate(16_000) / 2_000.0 - 1.0).abs() < 0.05,
+            "{}",
+            rate(16_000)
+        );
+    }
+
+    #[test]
+    fn players_fill_every_channel_with_the_sweep() {
+        let sweep = sweep();
+        let mut player = SweepPlayer::new(sweep);
+        let mut data = vec![0.0; 3 * 1_000];
+        player.fill(&mut data, 3);
+        player.fill(&mut data[..3], 3);
+        for (n, frame) in data[3..].chunks(3).enumerate() {
+            assert_eq!(frame, [sweep.sample(n + 1); 3]);
+        }
+        assert_eq!(data[..3], [sweep.sample(1_000); 3]);
+    }
+
+    #[test]
+    fn a_delayed_copy_deconvolves_to_a_delayed_impulse() {
+        let played = sweep().render();
+        let mut captured = vec![0.0; 100];
+        captured.extend(played.iter().map(|x| x * 0.5));
+        let ir = deconvolve(&played, &captured);
+        assert_eq!(ir.len(), 65_536);
+        let peak = (0..ir.len()).max_by(|x, y| ir[*x].abs().total_cmp(&ir[*y].abs()));
+        assert_eq!(peak, Some(100));
+        // The impulse is limited to the band of the sweep, as is that of the sweep itself.
+        let unit = deconvolve(&played, &played)[0];
+        assert!(
+            (ir[100] / unit - 0.5).abs() < 1e-3,
+            "{} against {}",
+            ir[100],
+            unit
+        );
+
+        // An input that started late wraps around.
+        let ir = deconvolve(&played, &played[10..]);
+        let peak = (0..ir.len()).max_by(|x, y| ir[*x].abs().total_cmp(&ir[*y].abs()));
+        assert_eq!(peak, Some(ir.len() - 10));
+    }
+}
@@ -0,0 +1,725 @@
+//! Moving a stream to another device during a run, without losing what its callback holds: the
+//! effect chains, the rings, the recording.
+//!
+//! The callback of a stream lives in a port, which the streams of every device it moves to
+//! share. To switch, the stream of the new device is opened next to the old one:
+//!
+//! - An output keeps running its callback on the old stream, which copies every block it plays
+//!   to a ring. The new stream waits for the ring to hold a few blocks, then plays from it,
+//!   fading in over [`CROSSFADE`] while the old one fades out. Then it plays what's left in the
+//!   ring and runs the callback itself.
+//! - An input sends what the new stream captures to the old one through a ring, and the old one
+//!   crossfades it with its own before the callback. Then the new stream runs the callback, told
+//!   that the input moved so that the rings it feeds can be primed again.
+//!
+//! A [`Switch`] goes through its [`Phase`]s on the main thread, opening the new stream through a
+//! [`StreamBuilder`]. A device that fails to open, or to start in [`TIMEOUT`], leaves the old
+//! stream as it was.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cpal::{SampleFormat, StreamConfig};
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+
+use crate::devices::{self, DeviceSelector};
+use crate::dither::DitherMode;
+use crate::error::EngineError;
+use crate::events::EngineEvent;
+use crate::fade;
+use crate::ring;
+use crate::watchdog::Restartable;
+
+/// How long the old and the new device play together.
+pub const CROSSFADE: Duration = Duration::from_millis(150);
+/// Longest a phase of a switch can take before it's rolled back.
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+/// Blocks the ring of a switch holds before the crossfade starts.
+const PRIME_BLOCKS: usize = 2;
+/// Room of the ring of a switch, in primes.
+const RING_PRIMES: usize = 4;
+
+/// Callback of an output port, filling a block.
+pub type OutputCallback = Box<dyn FnMut(&mut [f32]) + Send>;
+/// Callback of an input port, given a block and whether the input just moved to another device.
+pub type InputCallback = Box<dyn FnMut(&[f32], bool) + Send>;
+/// Callback of a stream of an input port, given the blocks it captures.
+pub type CaptureCallback = Box<dyn FnMut(&[f32]) + Send>;
+
+/// What a switch is doing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// About to open the stream of the new device.
+    Opening,
+    /// Waiting for the ring between the streams to fill.
+    Priming,
+    Crossfading,
+    /// The new stream runs the callback, and the old one can be closed.
+    Done,
+    /// The switch failed for the reason given, and the old stream runs as before.
+    RolledBack(String),
+}
+
+/// Which stream of the engine to move, e.g. the first output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+/// A request to move the first stream of `direction` to `device`, a name or an index.
+#[derive(Clone, Debug)]
+pub struct SwitchRequest {
+    pub direction: Direction,
+    pub device: String,
+}
+
+/// How far the streams of a switch got, shared by both and the main thread.
+#[derive(Default)]
+pub struct Progress {
+    primed: AtomicBool,
+    /// Frames of the crossfade played.
+    faded: AtomicU64,
+}
+
+impl Progress {
+    pub fn primed(&self) -> bool {
+        self.primed.load(Ordering::Acquire)
+    }
+
+    pub fn faded(&self) -> u64 {
+        self.faded.load(Ordering::Acquire)
+    }
+}
+
+/// The callback of a stream that can move, and what its streams share.
+struct Shared<F, T> {
+    callback: Mutex<F>,
+    /// Id of the stream running the callback.
+    driver: AtomicUsize,
+    next_id: AtomicUsize,
+    /// The end of the ring of the switch under way the driver uses.
+    tee: Mutex<Option<(T, Arc<Progress>)>>,
+    channels: usize,
+    /// Frames the ring holds before the crossfade.
+    prime: usize,
+    /// Frames of the crossfade.
+    fade: usize,
+}
+
+impl<F, T> Shared<F, T> {
+    fn new(config: &StreamConfig, callback: F) -> Self {
+        Shared {
+            callback: Mutex::new(callback),
+            driver: AtomicUsize::new(0),
+            next_id: AtomicUsize::new(1),
+            tee: Mutex::new(None),
+            channels: config.channels as usize,
+            prime: PRIME_BLOCKS * ring::block_frames(config).max(1),
+            fade: (CROSSFADE.as_secs_f64() * config.sample_rate.0 as f64) as usize,
+        }
+    }
+
+    fn is_driver(&self, id: usize) -> bool {
+        self.driver.load(Ordering::Acquire) == id
+    }
+
+    /// A new ring for a switch, with the progress of its crossfade.
+    fn ring(&self) -> (HeapProd<f32>, HeapCons<f32>, Arc<Progress>) {
+        let (producer, consumer) = HeapRb::new(RING_PRIMES * self.prime * self.channels).split();
+        (producer, consumer, Arc::default())
+    }
+
+    /// Gain at `frame` frames into the crossfade, of the stream fading in.
+    fn fade_in(&self, frame: u64) -> f32 {
+        fade::gain((frame as f32 / self.fade.max(1) as f32).min(1.0))
+    }
+}
+
+/// The ports streams move between devices with, for a [`Switch`].
+pub trait Port {
+    /// Callback of the stream of a new device.
+    type Callback;
+
+    /// Starts a switch, returning the callback of the new stream, its id and the progress of
+    /// the switch.
+    fn follow(&self) -> (Self::Callback, usize, Arc<Progress>);
+
+    /// Makes the stream `id` run the callback, ending the switch.
+    fn take_over(&self, id: usize);
+
+    /// Ends the switch under way, leaving the callback where it is.
+    fn abandon(&self);
+
+    /// Frames the crossfade lasts.
+    fn fade_frames(&self) -> usize;
+}
+
+/// The port of an output.
+#[derive(Clone)]
+pub struct OutputPort {
+    shared: Arc<Shared<OutputCallback, HeapProd<f32>>>,
+}
+
+impl OutputPort {
+    /// A port for `callback`, filling the blocks of a stream of `config`.
+    pub fn new(config: &StreamConfig, callback: impl FnMut(&mut [f32]) + Send + 'static) -> Self {
+        OutputPort {
+            shared: Arc::new(Shared::new(config, Box::new(callback))),
+        }
+    }
+
+    /// Callback of the stream running the port first.
+    pub fn callback(&self) -> OutputCallback {
+        let mut stream = OutputStream {
+            shared: self.shared.clone(),
+            id: 0,
+            follow: None,
+        };
+        Box::new(move |data| stream.process(data))
+    }
+}
+
+impl Port for OutputPort {
+    type Callback = OutputCallback;
+
+    fn follow(&self) -> (OutputCallback, usize, Arc<Progress>) {
+        let shared = &self.shared;
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (producer, consumer, progress) = shared.ring();
+        *shared.tee.lock().unwrap() = Some((producer, progress.clone()));
+        let mut stream = OutputStream {
+            shared: shared.clone(),
+            id,
+            follow: Some(OutputFollow {
+                consumer,
+                progress: progress.clone(),
+                started: false,
+            }),
+        };
+        (Box::new(move |data| stream.process(data)), id, progress)
+    }
+
+    fn take_over(&self, id: usize) {
+        self.shared.driver.store(id, Ordering::Release);
+        self.abandon();
+    }
+
+    fn abandon(&self) {
+        *self.shared.tee.lock().unwrap() = None;
+    }
+
+    fn fade_frames(&self) -> usize {
+        self.shared.fade
+    }
+}
+
+/// The ring an output stream plays from before it takes over.
+struct OutputFollow {
+    consumer: HeapCons<f32>,
+    progress: Arc<Progress>,
+    /// Whether the first callback came, dropping what the old stream sent before.
+    started: bool,
+}
+
+/// One stream of an output port.
+struct OutputStream {
+    shared: Arc<Shared<OutputCallback, HeapProd<f32>>>,
+    id: usize,
+    follow: Option<OutputFollow>,
+}
+
+impl OutputStream {
+    fn process(&mut self, data: &mut [f32]) {
+        let shared = &*self.shared;
+        let channels = shared.channels;
+        if !shared.is_driver(self.id) {
+            match &mut self.follow {
+                Some(follow) => follow.play(data, shared),
+                // Switched away from, until it's closed.
+                None => data.fill(0.0),
+            }
+            return;
+        }
+        // Just took over, with blocks of the old stream left to play first.
+        let mut start = 0;
+        if let Some(follow) = &mut self.follow {
+            start = follow.consumer.pop_slice(data);
+            if follow.consumer.is_empty() {
+                self.follow = None;
+            }
+        }
+        if start < data.len() {
+            match shared.callback.try_lock() {
+                Ok(mut callback) => callback(&mut data[start..]),
+                Err(_) => data[start..].fill(0.0),
+            }
+        }
+        let Ok(mut tee) = shared.tee.try_lock() else {
+            return;
+        };
+        let Some((producer, progress)) = tee.as_mut() else {
+            return;
+        };
+        // Blocks are sent whole or not at all, so that the channels stay in place.
+        if producer.vacant_len() >= data.len() {
+            producer.push_slice(data);
+        }
+        if progress.primed() {
+            let faded = progress.faded();
+            for (index, frame) in data.chunks_mut(channels).enumerate() {
+                let gain = 1.0 - shared.fade_in(faded + index as u64);
+                frame.iter_mut().for_each(|x| *x *= gain);
+            }
+        }
+    }
+}
+
+impl OutputFollow {
+    /// Plays the ring, once it's primed, fading in.
+    fn play(&mut self, data: &mut [f32], shared: &Shared<OutputCallback, HeapProd<f32>>) {
+        let channels = shared.channels;
+        if !self.started {
+            self.started = true;
+            self.consumer.clear();
+        }
+        if !self.progress.primed() {
+            if self.consumer.occupied_len() < shared.prime * channels {
+                data.fill(0.0);
+                return;
+            }
+            self.progress.primed.store(true, Ordering::Release);
+        }
+        let filled = self.consumer.pop_slice(data);
+        data[filled..].fill(0.0);
+        let faded = self.progress.faded();
+        for (index, frame) in data.chunks_mut(channels).enumerate() {
+            let gain = shared.fade_in(faded + index as u64);
+            frame.iter_mut().for_each(|x| *x *= gain);
+        }
+        let frames = (data.len() / channels) as u64;
+        self.progress.faded.fetch_add(frames, Ordering::Release);
+    }
+}
+
+/// The port of an input.
+#[derive(Clone)]
+pub struct InputPort {
+    shared: Arc<Shared<InputCallback, HeapCons<f32>>>,
+}
+
+impl InputPort {
+    /// A port for `callback`, given the blocks of a stream of `config`.
+    pub fn new(config: &StreamConfig, callback: impl FnMut(&[f32], bool) + Send + 'static) -> Self {
+        InputPort {
+            shared: Arc::new(Shared::new(config, Box::new(callback))),
+        }
+    }
+
+    /// Callback of the stream running the port first.
+    pub fn callback(&self) -> CaptureCallback {
+        let mut stream = InputStream::new(self.shared.clone(), 0, None);
+        Box::new(move |data| stream.process(data))
+    }
+}
+
+impl Port for InputPort {
+    type Callback = CaptureCallback;
+
+    fn follow(&self) -> (Self::Callback, usize, Arc<Progress>) {
+        let shared = &self.shared;
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (producer, consumer, progress) = shared.ring();
+        *shared.tee.lock().unwrap() = Some((consumer, progress.clone()));
+        let mut stream = InputStream::new(shared.clone(), id, Some(producer));
+        (Box::new(move |data| stream.process(data)), id, progress)
+    }
+
+    fn take_over(&self, id: usize) {
+        self.shared.driver.store(id, Ordering::Release);
+        self.abandon();
+    }
+
+    fn abandon(&self) {
+        *self.shared.tee.lock().unwrap() = None;
+    }
+
+    fn fade_frames(&self) -> usize {
+        self.shared.fade
+    }
+}
+
+/// One stream of an input port.
+struct InputStream {
+    shared: Arc<Shared<InputCallback, HeapCons<f32>>>,
+    id: usize,
+    /// The ring to the old stream, before this one takes over.
+    follow: Option<HeapProd<f32>>,
+    /// The crossfaded block.
+    mixed: Vec<f32>,
+}
+
+impl InputStream {
+    fn new(
+        shared: Arc<Shared<InputCallback, HeapCons<f32>>>,
+        id: usize,
+        follow: Option<HeapProd<f32>>,
+    ) -> Self {
+        let mixed = Vec::with_capacity(shared.prime * shared.channels);
+        InputStream {
+            shared,
+            id,
+            follow,
+            mixed,
+        }
+    }
+
+    fn process(&mut self, data: &[f32]) {
+        let shared = &*self.shared;
+        if !shared.is_driver(self.id) {
+            if let Some(producer) = &mut self.follow {
+                if producer.vacant_len() >= data.len() {
+                    producer.push_slice(data);
+                }
+            }
+            return;
+        }
+        let moved = self.follow.take().is_some();
+        let mut block = data;
+        if let Ok(mut tee) = shared.tee.try_lock() {
+            if let Some((consumer, progress)) = tee.as_mut() {
+                if !progress.primed() && consumer.occupied_len() >= shared.prime * shared.channels {
+                    progress.primed.store(true, Ordering::Release);
+                }
+                if progress.primed() {
+                    self.mixed.clear();
+                    let faded = progress.faded();
+                    for (index, frame) in data.chunks(shared.channels).enumerate() {
+                        let gain = shared.fade_in(faded + index as u64);
+                        for &x in frame {
+                            let y = consumer.try_pop().unwrap_or(0.0);
+                            self.mixed.push(x * (1.0 - gain) + y * gain);
+                        }
+                    }
+                    let frames = (data.len() / shared.channels) as u64;
+                    progress.faded.fetch_add(frames, Ordering::Release);
+                    block = &self.mixed;
+                }
+            }
+        }
+        if let Ok(mut callback) = shared.callback.try_lock() {
+            callback(block, moved);
+        }
+    }
+}
+
+/// Opens the stream of a device around a callback: the backend, or a mock.
+pub trait StreamBuilder<C> {
+    type Stream;
+
+    /// Opens the stream of `device` and starts it.
+    fn open(&mut self, device: &str, callback: C) -> Result<Self::Stream, EngineError>;
+}
+
+/// Opens streams of the backend, with the configuration of the stream they replace.
+pub struct DeviceOpener<'a> {
+    pub host: &'a cpal::Host,
+    pub label: &'static str,
+    pub config: StreamConfig,
+    /// Dither of the output streams, should the new device only take 16 bits.
+    pub dither: DitherMode,
+    pub events: Sender<EngineEvent>,
+}
+
+impl DeviceOpener<'_> {
+    fn selector(device: &str) -> Result<DeviceSelector, EngineError> {
+        device.parse().map_err(EngineError::InvalidArgument)
+    }
+}
+
+impl StreamBuilder<OutputCallback> for DeviceOpener<'_> {
+    type Stream = Restartable;
+
+    fn open(
+        &mut self,
+        device: &str,
+        mut callback: OutputCallback,
+    ) -> Result<Restartable, EngineError> {
+        let device = devices::output_device(self.host, &Self::selector(device)?)?;
+        let callback = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| callback(data);
+        let mut stream = Restartable::output(
+            self.label,
+            &device,
+            &self.config,
+            SampleFormat::F32,
+            self.dither,
+            callback,
+            self.events.clone(),
+        )?;
+        stream.play()?;
+        Ok(stream)
+    }
+}
+
+impl StreamBuilder<CaptureCallback> for DeviceOpener<'_> {
+    type Stream = Restartable;
+
+    fn open(
+        &mut self,
+        device: &str,
+        mut callback: CaptureCallback,
+    ) -> Result<Restartable, EngineError> {
+        let device = devices::input_device(self.host, &Self::selector(device)?)?;
+        let callback = move |data: &[f32], _: &cpal::InputCallbackInfo| callback(data);
+        let mut stream = Restartable::input(
+            self.label,
+            &device,
+            &self.config,
+            SampleFormat::F32,
+            callback,
+            self.events.clone(),
+        )?;
+        stream.play()?;
+        Ok(stream)
+    }
+}
+
+/// Moves the stream of a port to another device.
+pub struct Switch<P: Port, S> {
+    port: P,
+    device: String,
+    phase: Phase,
+    /// The stream of the new device, its id, and the progress of the switch, once opened.
+    stream: Option<(S, usize, Arc<Progress>)>,
+    /// When the phase started.
+    since: Instant,
+}
+
+impl<P: Port, S> Switch<P, S> {
+    /// A switch of `port` to `device`, opening it at the first poll.
+    pub fn new(port: P, device: impl Into<String>, now: Instant) -> Self {
+        Switch {
+            port,
+            device: device.into(),
+            phase: Phase::Opening,
+            stream: None,
+            since: now,
+        }
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    pub fn phase(&self) -> &Phase {
+        &self.phase
+    }
+
+    /// Moves the switch on at `now`, opening the new stream with `builder`.
+    pub fn poll<B>(&mut self, builder: &mut B, now: Instant) -> &Phase
+    where
+        B: StreamBuilder<P::Callback, Stream = S>,
+    {
+        match &self.phase {
+            Phase::Opening => {
+                let (callback, id, progress) = self.port.follow();
+                match builder.open(&self.device, callback) {
+                    Ok(stream) => {
+                        self.stream = Some((stream, id, progress));
+                        self.enter(Phase::Priming, now);
+                    }
+                    Err(err) => self.roll_back(format!("{:#}", anyhow::Error::from(err))),
+                }
+            }
+            Phase::Priming | Phase::Crossfading => {
+                let Some((_, id, progress)) = &self.stream else {
+                    unreachable!("a switch past opening has a stream");
+                };
+                let id = *id;
+                let fade = self.port.fade_frames() as u64;
+                if progress.faded() >= fade {
+                    self.port.take_over(id);
+                    self.enter(Phase::Done, now);
+                } else if progress.primed() && self.phase == Phase::Priming {
+                    self.enter(Phase::Crossfading, now);
+                } else if now.saturating_duration_since(self.since) > TIMEOUT {
+                    let reason = match self.phase {
+                        Phase::Priming => "the device sent nothing",
+                        _ => "the crossfade stalled",
+                    };
+                    self.roll_back(format!(
+                        "{} for {:.1} seconds",
+                        reason,
+                        TIMEOUT.as_secs_f32()
+                    ));
+                }
+            }
+            Phase::Done | Phase::RolledBack(_) => {}
+        }
+        &self.phase
+    }
+
+    /// The stream of the new device, once the switch is done.
+    pub fn into_stream(self) -> Option<S> {
+        match self.phase {
+            Phase::Done => self.stream.map(|(stream, ..)| stream),
+            _ => None,
+        }
+    }
+
+    fn enter(&mut self, phase: Phase, now: Instant) {
+        self.phase = phase;
+        self.since = now;
+    }
+
+    fn roll_back(&mut self, reason: String) {
+        self.port.abandon();
+        // Closed after the port stops sending to it.
+        self.stream = None;
+        self.phase = Phase::RolledBack(reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocks of 10 frames at 1 kHz, so that the ring primes with 20 frames and the crossfade
+    /// takes 150.
+    fn config() -> StreamConfig {
+        StreamConfig {
+            channels: 2,
+            sample_rate: cpal::SampleRate(1_000),
+            buffer_size: cpal::BufferSize::Fixed(10),
+        }
+    }
+
+    /// Opens nothing, keeping the callbacks for the test to call, or fails.
+    struct Builder<C> {
+        callbacks: Vec<C>,
+        fail: bool,
+    }
+
+    impl<C> Builder<C> {
+        fn new(fail: bool) -> Self {
+            Builder {
+                callbacks: Vec::new(),
+                fail,
+            }
+        }
+    }
+
+    impl<C> StreamBuilder<C> for Builder<C> {
+        type Stream = ();
+
+        fn open(&mut self, device: &str, callback: C) -> Result<(), EngineError> {
+            if self.fail {
+                return Err(EngineError::InvalidArgument(format!(
+                    "no device \"{}\"",
+                    device
+                )));
+            }
+            self.callbacks.push(callback);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn outputs_crossfade_to_the_new_device() {
+        let port = OutputPort::new(&config(), |data| data.fill(1.0));
+        let (mut old, now) = (port.callback(), Instant::now());
+        let mut switch = Switch::new(port, "B", now);
+        let mut builder = Builder::new(false);
+        assert_eq!(switch.poll(&mut builder, now), &Phase::Priming);
+        let mut new = builder.callbacks.pop().unwrap();
+        let (mut from, mut to) = ([0.0; 20], [0.0; 20]);
+        let mut blocks = 0;
+        while switch.phase() != &Phase::Done {
+            old(&mut from);
+            new(&mut to);
+            // The blocks the old stream played before the first callback of the new one are
+            // dropped, and the old one starts fading once the new one primed.
+            match blocks {
+                0 | 1 => assert_eq!((from, to), ([1.0; 20], [0.0; 20])),
+                2 => assert_eq!((from[19], to[0]), (1.0, 0.0)),
+                _ => assert!(from
+                    .iter()
+                    .zip(&to)
+                    .all(|(x, y)| (x + y - 1.0).abs() < 1e-6)),
+            }
+            switch.poll(&mut builder, now);
+            blocks += 1;
+        }
+        assert_eq!(blocks, 2 + 15);
+        assert!(to[18] > 0.99);
+        old(&mut from);
+        new(&mut to);
+        assert_eq!((from, to), ([0.0; 20], [1.0; 20]));
+        assert_eq!(switch.into_stream(), Some(()));
+    }
+
+    #[test]
+    fn inputs_crossfade_to_the_new_device() {
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let port = {
+            let blocks = blocks.clone();
+            InputPort::new(&config(), move |data, moved| {
+                blocks.lock().unwrap().push((data[0], moved));
+            })
+        };
+        let (mut old, now) = (port.callback(), Instant::now());
+        let mut switch = Switch::new(port, "B", now);
+        let mut builder = Builder::new(false);
+        switch.poll(&mut builder, now);
+        let mut new = builder.callbacks.pop().unwrap();
+        while switch.phase() != &Phase::Done {
+            new(&[2.0; 20]);
+            old(&[1.0; 20]);
+            switch.poll(&mut builder, now);
+        }
+        new(&[2.0; 20]);
+        old(&[1.0; 20]);
+        let blocks = blocks.lock().unwrap();
+        let (first, last) = (blocks[0], blocks[blocks.len() - 1]);
+        assert_eq!((first, last), ((1.0, false), (2.0, true)));
+        // Crossfading from one to two.
+        let crossfade = &blocks[1..blocks.len() - 1];
+        assert!(crossfade.windows(2).all(|x| x[0].0 <= x[1].0));
+        assert!(crossfade.iter().all(|x| (1.0..2.0).contains(&x.0) && !x.1));
+    }
+
+    #[test]
+    fn devices_that_fail_to_open_roll_back() {
+        let port = OutputPort::new(&config(), |data| data.fill(1.0));
+        let (mut old, now) = (port.callback(), Instant::now());
+        let mut switch = Switch::new(port, "B", now);
+        let phase = switch.poll(&mut Builder::new(true), now).clone();
+        assert_eq!(phase, Phase::RolledBack("no device \"B\"".to_string()));
+        let mut data = [0.0; 20];
+        old(&mut data);
+        assert_eq!(data, [1.0; 20]);
+        assert_eq!(switch.into_stream(), None);
+    }
+
+    #[test]
+    fn devices_that_send_nothing_roll_back_after_the_timeout() {
+        let port = OutputPort::new(&config(), |data| data.fill(1.0));
+        let (mut old, now) = (port.callback(), Instant::now());
+        let mut switch = Switch::new(port, "B", now);
+        let mut builder = Builder::new(false);
+        switch.poll(&mut builder, now);
+        let mut data = [0.0; 20];
+        old(&mut data);
+        assert_eq!(switch.poll(&mut builder, now + TIMEOUT), &Phase::Priming);
+        let phase = switch.poll(&mut builder, now + TIMEOUT * 2).clone();
+        assert_eq!(
+            phase,
+            Phase::RolledBack("the device sent nothing for 2.0 seconds".to_string())
+        );
+        old(&mut data);
+        assert_eq!(data, [1.0; 20]);
+    }
+}
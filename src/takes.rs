@@ -0,0 +1,243 @@
+//! Takes recorded to a directory, numbered `take_001.wav`, `take_002.wav` and on, each kept or
+//! discarded as it ends.
+//!
+//! A take's number is the highest in the directory plus one, found when it starts. Several
+//! instances may record to the same directory: each holds an exclusive lock on
+//! [`LOCK_NAME`] while it looks for the number and creates the file, which reserves it, so no two
+//! takes ever get the same one. The lock is the file system's, released however the process ends,
+//! so a crash can't leave the directory locked.
+//!
+//! The commands come from the terminal, each at the frame the input was feeding the recorder
+//! right then, as the [`CueMarkers`](crate::record::CueMarkers) do: a take starts with that
+//! frame and ends just before it.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+
+/// The file the instances recording to a directory lock while they number a take.
+pub const LOCK_NAME: &str = ".takes.lock";
+const PREFIX: &str = "take_";
+
+/// What happens to the current take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TakeEvent {
+    Start,
+    /// Stops it, keeping its files.
+    Keep,
+    /// Stops it, deleting its files once they're finished.
+    Discard,
+}
+
+/// Whether a take is being recorded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TakeState {
+    #[default]
+    Idle,
+    Recording,
+}
+
+impl TakeState {
+    /// The state after `event`, or why it can't happen now.
+    pub fn after(self, event: TakeEvent) -> Result<TakeState, &'static str> {
+        match (self, event) {
+            (TakeState::Idle, TakeEvent::Start) => Ok(TakeState::Recording),
+            (TakeState::Recording, TakeEvent::Keep | TakeEvent::Discard) => Ok(TakeState::Idle),
+            (TakeState::Recording, TakeEvent::Start) => Err("already recording a take"),
+            (TakeState::Idle, _) => Err("not recording a take"),
+        }
+    }
+}
+
+/// Where and how the takes are recorded.
+pub struct Takes {
+    pub dir: PathBuf,
+    /// Extension of the files, e.g. "wav".
+    pub extension: &'static str,
+    /// How much of the input from before a take starts it.
+    pub pre_roll: Duration,
+    pub control: TakeControl,
+}
+
+/// The commands for the takes, shared between the controls, the input callback and the recorder
+/// thread.
+#[derive(Default)]
+pub struct TakeControl {
+    /// Frames fed to the recorder so far.
+    fed: AtomicU64,
+    /// The state after the commands so far, and those not taken by the recorder thread yet, with
+    /// their frames.
+    state: Mutex<(TakeState, Vec<(u64, TakeEvent)>)>,
+}
+
+impl TakeControl {
+    /// Sends `event` for the next frame fed, returning the frame, or why it can't happen now.
+    pub fn send(&self, event: TakeEvent) -> Result<u64, &'static str> {
+        let mut state = self.state.lock().unwrap();
+        state.0 = state.0.after(event)?;
+        let frame = self.fed.load(Ordering::Acquire);
+        state.1.push((frame, event));
+        Ok(frame)
+    }
+
+    pub fn state(&self) -> TakeState {
+        self.state.lock().unwrap().0
+    }
+
+    /// Counts `frames` more fed, from the input callback.
+    pub fn feed(&self, frames: u64) {
+        self.fed.fetch_add(frames, Ordering::Release);
+    }
+
+    /// Moves the commands sent since the last call to `into`, returning how many frames had been
+    /// fed by then.
+    pub fn take(&self, into: &mut VecDeque<(u64, TakeEvent)>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        into.extend(state.1.drain(..));
+        self.fed.load(Ordering::Acquire)
+    }
+}
+
+/// The number of a take's file name, e.g. 12 for "take_012.wav" or for "take_012-002.flac", one
+/// of its numbered files.
+pub fn take_number(name: &str) -> Option<usize> {
+    let rest = name.strip_prefix(PREFIX)?;
+    let digits = rest.len() - rest.trim_start_matches(|x: char| x.is_ascii_digit()).len();
+    let (number, rest) = rest.split_at(digits);
+    match rest.chars().next() {
+        None | Some('.') | Some('-') => number.parse().ok(),
+        Some(_) => None,
+    }
+}
+
+/// The file of take `number` in `dir`, e.g. "take_012.wav".
+pub fn take_path(dir: &Path, number: usize, extension: &str) -> PathBuf {
+    dir.join(format!("{}{:03}.{}", PREFIX, number, extension))
+}
+
+/// The highest take number in `dir`, 0 if there are none.
+fn highest(dir: &Path) -> std::io::Result<usize> {
+    let mut highest = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(number) = name.to_str().and_then(take_number) {
+            highest = highest.max(number);
+        }
+    }
+    Ok(highest)
+}
+
+/// Numbers the next take in `dir`, creating it if needed, and reserves it by creating its empty
+/// file, returning its number and path.
+pub fn reserve(dir: &Path, extension: &str) -> anyhow::Result<(usize, PathBuf)> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create \"{}\"", dir.display()))?;
+    let lock_path = dir.join(LOCK_NAME);
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("failed to open \"{}\"", lock_path.display()))?;
+    // Waits for any other instance numbering a take, and lets go when dropped.
+    lock.lock()
+        .with_context(|| format!("failed to lock \"{}\"", lock_path.display()))?;
+    let mut number =
+        highest(dir).with_context(|| format!("failed to list \"{}\"", dir.display()))? + 1;
+    loop {
+        let path = take_path(dir, number, extension);
+        match File::create_new(&path) {
+            Ok(_) => return Ok((number, path)),
+            // Only a program that doesn't lock, writing to the directory too, gets in the way.
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => number += 1,
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to create \"{}\"", path.display()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn takes_start_then_end() {
+        let idle = TakeState::Idle;
+        assert_eq!(idle.after(TakeEvent::Start), Ok(TakeState::Recording));
+        assert_eq!(idle.after(TakeEvent::Keep), Err("not recording a take"));
+        assert_eq!(idle.after(TakeEvent::Discard), Err("not recording a take"));
+        let recording = TakeState::Recording;
+        assert_eq!(recording.after(TakeEvent::Keep), Ok(TakeState::Idle));
+        assert_eq!(recording.after(TakeEvent::Discard), Ok(TakeState::Idle));
+        assert_eq!(
+            recording.after(TakeEvent::Start),
+            Err("already recording a take")
+        );
+    }
+
+    #[test]
+    fn commands_land_on_the_next_frame_fed() {
+        let control = TakeControl::default();
+        control.feed(100);
+        assert_eq!(control.send(TakeEvent::Start), Ok(100));
+        assert_eq!(
+            control.send(TakeEvent::Start),
+            Err("already recording a take")
+        );
+        control.feed(50);
+        assert_eq!(control.send(TakeEvent::Keep), Ok(150));
+        assert_eq!(control.state(), TakeState::Idle);
+        let mut events = VecDeque::new();
+        assert_eq!(control.take(&mut events), 150);
+        assert_eq!(events, [(100, TakeEvent::Start), (150, TakeEvent::Keep)]);
+        assert_eq!(control.take(&mut events), 150);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn take_numbers_are_read_from_their_files() {
+        assert_eq!(take_number("take_012.wav"), Some(12));
+        assert_eq!(take_number("take_012-002.flac"), Some(12));
+        assert_eq!(take_number("take_1000.wav"), Some(1_000));
+        assert_eq!(take_number("take_012b.wav"), None);
+        assert_eq!(take_number("take_.wav"), None);
+        assert_eq!(take_number("mix_012.wav"), None);
+        assert_eq!(
+            take_path(Path::new("takes"), 7, "wav"),
+            Path::new("takes").join("take_007.wav")
+        );
+    }
+
+    #[test]
+    fn each_take_is_numbered_after_the_highest() {
+        let directory = temp_dir("takes-reserve");
+        let dir = directory.join("session");
+        let (number, path) = reserve(&dir, "wav").unwrap();
+        assert_eq!((number, path.clone()), (1, dir.join("take_001.wav")));
+        assert!(path.exists());
+        for name in [
+            "take_005.wav",
+            "take_012-002.flac",
+            "take_020.txt.bak2",
+            "notes.txt",
+        ] {
+            File::create(dir.join(name)).unwrap();
+        }
+        assert_eq!(reserve(&dir, "flac").unwrap().0, 21);
+        assert_eq!(reserve(&dir, "wav").unwrap().0, 22);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
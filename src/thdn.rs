@@ -0,0 +1,319 @@
+//! THD+N of a sine captured back from an interface, e.g. through a loopback cable.
+//!
+//! Each window of the capture is fitted with a sine, by least squares over its amplitude, phase,
+//! DC offset and frequency, starting from the peak of its spectrum. Subtracting the fit is a
+//! notch as deep as the fit is exact, and what's left is the distortion and the noise over the
+//! whole band. THD+N is the RMS of the residual relative to that of the fundamental.
+
+use std::f64::consts::TAU;
+
+use crate::fft::{self, Fft};
+
+/// Frequencies the fundamental may have, in Hz. Lower ones take too long a window to fit.
+pub const LOWEST: f64 = 10.0;
+/// Largest FFT the frequency is first estimated with.
+const MAX_FFT: usize = 65_536;
+/// Gauss–Newton steps refining the frequency, which converges in a few.
+const ITERATIONS: usize = 8;
+/// Amplitude under which there's no fundamental to measure, -120 dBFS.
+const SILENCE: f64 = 1e-6;
+
+/// The fundamental of a window and what's left around it.
+#[derive(Clone, Copy, Debug)]
+pub struct Analysis {
+    /// In Hz.
+    pub frequency: f64,
+    /// Peak amplitude, 1 at full scale.
+    pub amplitude: f64,
+    /// Mean square of the residual.
+    pub residual: f64,
+}
+
+impl Analysis {
+    /// Peak level of the fundamental, in dBFS.
+    pub fn level_db(&self) -> f64 {
+        20.0 * self.amplitude.log10()
+    }
+
+    /// RMS of the residual relative to that of the fundamental.
+    pub fn ratio(&self) -> f64 {
+        (self.residual / (self.amplitude * self.amplitude / 2.0)).sqrt()
+    }
+
+    pub fn percent(&self) -> f64 {
+        self.ratio() * 100.0
+    }
+
+    pub fn db(&self) -> f64 {
+        20.0 * self.ratio().log10()
+    }
+}
+
+/// Analyses one window of mono samples at `sample_rate`, or returns `None` if there's no sine
+/// in it.
+pub fn analyze(samples: &[f32], sample_rate: u32) -> Option<Analysis> {
+    let rate = sample_rate as f64;
+    let initial = estimate_frequency(samples, sample_rate)?;
+    if initial < LOWEST {
+        return None;
+    }
+    let fit = fit(samples, TAU * initial / rate)?;
+    let amplitude = fit.a.hypot(fit.b);
+    if amplitude < SILENCE {
+        return None;
+    }
+    let residual = samples
+        .iter()
+        .enumerate()
+        .map(|(n, y)| {
+            let r = *y as f64 - fit.value(n, samples.len());
+            r * r
+        })
+        .sum::<f64>()
+        / samples.len() as f64;
+    Some(Analysis {
+        frequency: fit.omega * rate / TAU,
+        amplitude,
+        residual,
+    })
+}
+
+/// Analyses consecutive windows of `window` samples, averaging the powers of the fundamental
+/// and of the residual over those that hold a sine. Returns the average and how many windows it
+/// covers.
+pub fn analyze_windows(
+    samples: &[f32],
+    window: usize,
+    sample_rate: u32,
+) -> Option<(Analysis, usize)> {
+    let windows: Vec<Analysis> = samples
+        .chunks_exact(window)
+        .filter_map(|x| analyze(x, sample_rate))
+        .collect();
+    if windows.is_empty() {
+        return None;
+    }
+    let count = windows.len() as f64;
+    let mean = |f: fn(&Analysis) -> f64| windows.iter().map(f).sum::<f64>() / count;
+    let average = Analysis {
+        frequency: mean(|x| x.frequency),
+        amplitude: mean(|x| x.amplitude * x.amplitude).sqrt(),
+        residual: mean(|x| x.residual),
+    };
+    Some((average, windows.len()))
+}
+
+/// The frequency of the highest peak of the spectrum, in Hz, interpolated between bins.
+pub fn estimate_frequency(samples: &[f32], sample_rate: u32) -> Option<f64> {
+    if samples.len() < 4 {
+        return None;
+    }
+    let size = match samples.len().min(MAX_FFT) {
+        x if x.is_power_of_two() => x,
+        x => x.next_power_of_two() / 2,
+    };
+    let window = fft::hann(size);
+    let mut re: Vec<f32> = samples.iter().zip(&window).map(|(x, w)| x * w).collect();
+    let mut im = vec![0.0; size];
+    Fft::new(size).forward(&mut re, &mut im);
+    let magnitude = |k: usize| (re[k] as f64).hypot(im[k] as f64);
+    let peak = (1..size / 2).max_by(|x, y| magnitude(*x).total_cmp(&magnitude(*y)))?;
+    if magnitude(peak) == 0.0 {
+        return None;
+    }
+    // A parabola through the log magnitudes around the peak.
+    let offset = match peak {
+        1 => 0.0,
+        k if k + 1 >= size / 2 => 0.0,
+        k => {
+            let [a, b, c] = [k - 1, k, k + 1].map(|x| magnitude(x).max(f64::MIN_POSITIVE).ln());
+            let denominator = a - 2.0 * b + c;
+            match denominator {
+                0.0 => 0.0,
+                d => (0.5 * (a - c) / d).clamp(-0.5, 0.5),
+            }
+        }
+    };
+    Some((peak as f64 + offset) * sample_rate as f64 / size as f64)
+}
+
+/// `a·cos(ω·t) + b·sin(ω·t) + c`, with `t` counted from the middle of the window, which keeps
+/// the fit well conditioned.
+struct Fit {
+    a: f64,
+    b: f64,
+    c: f64,
+    omega: f64,
+}
+
+impl Fit {
+    fn value(&self, n: usize, length: usize) -> f64 {
+        let t = centered(n, length);
+        self.a * (self.omega * t).cos() + self.b * (self.omega * t).sin() + self.c
+    }
+}
+
+fn centered(n: usize, length: usize) -> f64 {
+    n as f64 - (length as f64 - 1.0) / 2.0
+}
+
+/// Fits a sine to `samples` from an angular frequency `omega` close enough to it, in radians
+/// per sample: first its amplitude, phase and offset at `omega`, then all four together.
+fn fit(samples: &[f32], omega: f64) -> Option<Fit> {
+    let mut fit = {
+        let [a, b, c] = least_squares(samples, |t| [(omega * t).cos(), (omega * t).sin(), 1.0])?;
+        Fit { a, b, c, omega }
+    };
+    for _ in 0..ITERATIONS {
+        let (a, b, omega) = (fit.a, fit.b, fit.omega);
+        let [a, b, c, step] = least_squares(samples, |t| {
+            let (sin, cos) = (omega * t).sin_cos();
+            [cos, sin, 1.0, t * (b * cos - a * sin)]
+        })?;
+        fit = Fit {
+            a,
+            b,
+            c,
+            omega: omega + step,
+        };
+        if step.abs() < 1e-12 * omega {
+            break;
+        }
+    }
+    (fit.omega > 0.0 && fit.omega < TAU / 2.0).then_some(fit)
+}
+
+/// The coefficients of the columns `basis(t)` that best fit `samples`, from the normal
+/// equations.
+fn least_squares<const N: usize>(
+    samples: &[f32],
+    basis: impl Fn(f64) -> [f64; N],
+) -> Option<[f64; N]> {
+    let mut matrix = [[0.0; N]; N];
+    let mut vector = [0.0; N];
+    for (n, y) in samples.iter().enumerate() {
+        let row = basis(centered(n, samples.len()));
+        for i in 0..N {
+            for j in 0..N {
+                matrix[i][j] += row[i] * row[j];
+            }
+            vector[i] += row[i] * *y as f64;
+        }
+    }
+    solve(matrix, vector)
+}
+
+/// Solves `matrix · x = vector` by Gaussian elimination with partial pivoting.
+fn solve<const N: usize>(mut matrix: [[f64; N]; N], mut vector: [f64; N]) -> Option<[f64; N]> {
+    for column in 0..N {
+        let pivot = (column..N).max_by(|x, y| {
+            matrix[*x][column]
+                .abs()
+                .total_cmp(&matrix[*y][column].abs())
+        })?;
+        if matrix[pivot][column].abs() < f64::MIN_POSITIVE {
+            return None;
+        }
+        matrix.swap(column, pivot);
+        vector.swap(column, pivot);
+        for row in column + 1..N {
+            let factor = matrix[row][column] / matrix[column][column];
+            let pivot_row = matrix[column];
+            for (x, y) in matrix[row].iter_mut().zip(pivot_row).skip(column) {
+                *x -= factor * y;
+            }
+            vector[row] -= factor * vector[column];
+        }
+    }
+    let mut x = [0.0; N];
+    for row in (0..N).rev() {
+        let sum: f64 = (row + 1..N).map(|k| matrix[row][k] * x[k]).sum();
+        x[row] = (vector[row] - sum) / matrix[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    /// A sine of `frequency` and `amplitude`, with `harmonic` of it at three times the
+    /// frequency, and `offset` added.
+    fn sine(frequency: f64, amplitude: f64, harmonic: f64, offset: f64, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| {
+                let phase = TAU * frequency * n as f64 / RATE as f64 + 0.3;
+                let x = amplitude * (phase.sin() + harmonic * (3.0 * phase).sin()) + offset;
+                x as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_pure_sine_has_next_to_no_distortion() {
+        let analysis = analyze(&sine(997.0, 0.5, 0.0, 0.0, 9_600), RATE).unwrap();
+        assert!(
+            (analysis.frequency - 997.0).abs() < 1e-6,
+            "{}",
+            analysis.frequency
+        );
+        assert!(
+            (analysis.level_db() + 6.02).abs() < 0.01,
+            "{}",
+            analysis.level_db()
+        );
+        assert!(analysis.db() < -120.0, "{}", analysis.db());
+    }
+
+    #[test]
+    fn harmonics_and_offsets_are_told_apart() {
+        // 1% of third harmonic, with an offset that the fit takes out.
+        let analysis = analyze(&sine(1_000.0, 0.5, 0.01, 0.1, 9_600), RATE).unwrap();
+        assert!((analysis.frequency - 1_000.0).abs() < 1e-3);
+        assert!(
+            (analysis.percent() - 1.0).abs() < 1e-3,
+            "{}",
+            analysis.percent()
+        );
+        assert!((analysis.db() + 40.0).abs() < 0.01, "{}", analysis.db());
+    }
+
+    #[test]
+    fn the_frequency_is_estimated_between_bins() {
+        let samples = sine(1_234.5, 0.5, 0.0, 0.0, 8_192);
+        let estimate = estimate_frequency(&samples, RATE).unwrap();
+        // The bins are 5.9 Hz apart.
+        assert!((estimate - 1_234.5).abs() < 1.0, "{}", estimate);
+        assert_eq!(estimate_frequency(&[0.0; 8_192], RATE), None);
+        assert_eq!(estimate_frequency(&[0.5; 3], RATE), None);
+    }
+
+    #[test]
+    fn windows_without_a_sine_are_left_out() {
+        assert!(analyze(&[0.0; 4_800], RATE).is_none());
+        assert!(analyze(&sine(5.0, 0.5, 0.0, 0.0, 48_000), RATE).is_none());
+
+        let mut samples = sine(500.0, 0.5, 0.01, 0.0, 3 * 4_800);
+        samples[4_800..9_600].fill(0.0);
+        let (average, count) = analyze_windows(&samples, 4_800, RATE).unwrap();
+        assert_eq!(count, 2);
+        assert!((average.frequency - 500.0).abs() < 1e-3);
+        assert!(
+            (average.percent() - 1.0).abs() < 1e-3,
+            "{}",
+            average.percent()
+        );
+        assert!(analyze_windows(&[0.0; 9_600], 4_800, RATE).is_none());
+    }
+
+    #[test]
+    fn singular_systems_have_no_solution() {
+        assert_eq!(solve([[1.0, 2.0], [2.0, 4.0]], [1.0, 2.0]), None);
+        assert_eq!(
+            solve([[0.0, 2.0], [1.0, 1.0]], [4.0, 3.0]),
+            Some([1.0, 2.0])
+        );
+    }
+}
@@ -0,0 +1,766 @@
+//! A watchdog over the audio callbacks, for devices that wedge: their streams still exist, but
+//! the callbacks stop coming and the program would sit silent forever.
+//!
+//! Every callback bumps the [`Heartbeat`] of its stream. The main thread checks the heartbeats
+//! between its other chores, since the streams can't leave it on every host, and once a stream
+//! has been silent for [`TOLERANCE`] of its periods it tears every stream down and builds them
+//! again around the same callbacks, up to a number of times. Lost devices restart them the
+//! same way, on the events of the error callbacks.
+//!
+//! A pause of the whole process, or of the system, stops the callbacks legitimately. When the
+//! checks themselves were held up, the streams get the longer [`RESUME_TIMEOUT`] to come back
+//! before they count as stalled. Streams just built get it too, since opening a device can take
+//! a while.
+//!
+//! The heartbeats count frames as well, from which a [`RateDetector`] tells a stream whose device
+//! the system moved to another sample rate. The streams are then built again at the rate their
+//! devices run at, as they are when a build fails on its configuration after an error saying the
+//! configuration changed, and each sets its [`StreamRate`] for the callbacks to follow.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{BufferSize, FromSample, SampleFormat, SampleRate, SizedSample, StreamConfig};
+
+use crate::adapter::ChannelAdapter;
+use crate::config::{self, Adaptation, Rung};
+use crate::dither::{Dither, DitherMode};
+use crate::error::EngineError;
+use crate::events::{self, EngineEvent};
+use crate::rate::{RateDetector, StreamRate};
+
+/// Periods a stream can go without a callback before it counts as stalled.
+pub const TOLERANCE: u32 = 8;
+/// Shortest silence that counts as a stall, for streams of small or unknown periods.
+pub const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+/// Silence allowed after a pause of the checks, or once a stream is built.
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(5);
+/// Period assumed for streams of the host's default buffer size.
+const DEFAULT_PERIOD: Duration = Duration::from_millis(50);
+
+/// Counts the callbacks of a stream, and the frames of their blocks.
+#[derive(Default)]
+pub struct Heartbeat {
+    beats: AtomicU64,
+    frames: AtomicU64,
+}
+
+impl Heartbeat {
+    pub fn beat(&self, frames: usize) {
+        self.beats.fetch_add(1, Ordering::Relaxed);
+        self.frames.fetch_add(frames as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.beats.load(Ordering::Relaxed)
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+}
+
+/// Tells a stalled stream from the heartbeat counts seen at each check.
+pub struct StallDetector {
+    timeout: Duration,
+    count: u64,
+    /// When the count last moved, or the detector was rearmed.
+    last_beat: Instant,
+    last_check: Instant,
+    /// Whether the silence allowed is [`RESUME_TIMEOUT`], until the next beat.
+    resuming: bool,
+}
+
+impl StallDetector {
+    /// Watches a stream calling back every `period`, from `now`.
+    pub fn new(period: Duration, now: Instant) -> Self {
+        StallDetector {
+            timeout: (period * TOLERANCE).max(MIN_TIMEOUT),
+            count: 0,
+            last_beat: now,
+            last_check: now,
+            resuming: true,
+        }
+    }
+
+    /// Silence after which a running stream counts as stalled.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Starts over from `now`, for a stream just built.
+    pub fn rearm(&mut self, now: Instant) {
+        self.last_beat = now;
+        self.last_check = now;
+        self.resuming = true;
+    }
+
+    /// Takes the heartbeat `count` at `now`, returning how long the stream has been silent if
+    /// it counts as stalled.
+    pub fn check(&mut self, count: u64, now: Instant) -> Option<Duration> {
+        // Checks held up longer than a stall mean the whole process was paused, the callbacks
+        // with it.
+        if now.saturating_duration_since(self.last_check) > self.timeout {
+            self.last_beat = now;
+            self.resuming = true;
+        }
+        self.last_check = now;
+        if count != self.count {
+            self.count = count;
+            self.last_beat = now;
+            self.resuming = false;
+            return None;
+        }
+        let silent = now.saturating_duration_since(self.last_beat);
+        let allowed = match self.resuming {
+            true => RESUME_TIMEOUT,
+            false => self.timeout,
+        };
+        (silent > allowed).then_some(silent)
+    }
+}
+
+/// The period of a stream of `config`.
+pub fn period(config: &StreamConfig) -> Duration {
+    match config.buffer_size {
+        BufferSize::Fixed(frames) => {
+            Duration::from_secs_f64(frames as f64 / config.sample_rate.0 as f64)
+        }
+        BufferSize::Default => DEFAULT_PERIOD,
+    }
+}
+
+type Build = Box<dyn FnMut(&Rung) -> Result<cpal::Stream, cpal::BuildStreamError>>;
+/// The rate the device of a stream runs at by default now, if it says.
+type DeviceRate = Box<dyn Fn() -> Option<u32>>;
+
+/// A stream that can be built again around the same callback.
+pub struct Restartable {
+    label: &'static str,
+    build: Build,
+    /// The configuration the stream was built with, which it's built again with.
+    rung: Rung,
+    stream: Option<cpal::Stream>,
+    heartbeat: Arc<Heartbeat>,
+    detector: StallDetector,
+    rate: Arc<StreamRate>,
+    device_rate: DeviceRate,
+    rate_detector: RateDetector,
+    /// The rate the stream was found to call back at, which it's built again at.
+    pending_rate: Option<u32>,
+}
+
+impl Restartable {
+    /// Builds an input stream of `config` in samples of `format`, calling `callback`, sending
+    /// its errors to `events`.
+    pub fn input<F>(
+        label: &'static str,
+        device: &cpal::Device,
+        config: &StreamConfig,
+        format: SampleFormat,
+        callback: F,
+        events: Sender<EngineEvent>,
+    ) -> Result<Self, EngineError>
+    where
+        F: FnMut(&[f32], &cpal::InputCallbackInfo) + Send + 'static,
+    {
+        let callback = Arc::new(Mutex::new(callback));
+        let heartbeat = Arc::new(Heartbeat::default());
+        let supported: Vec<_> = device
+            .supported_input_configs()
+            .map(|x| x.collect())
+            .unwrap_or_default();
+        let default = device.clone();
+        let device_rate = move || {
+            default
+                .default_input_config()
+                .ok()
+                .map(|x| x.sample_rate().0)
+        };
+        let (device, channels, beat) = (device.clone(), config.channels, heartbeat.clone());
+        let build = move |rung: &Rung| {
+            let callback = callback.clone();
+            let (beat, errors) = (beat.clone(), events::error_callback(label, events.clone()));
+            if rung.format == SampleFormat::F32 && rung.config.channels == channels {
+                return device.build_input_stream(
+                    &rung.config,
+                    move |data: &[f32], info: &cpal::InputCallbackInfo| {
+                        beat.beat(data.len() / channels as usize);
+                        // Only held by the stream before, if it wedged inside the callback.
+                        if let Ok(mut callback) = callback.try_lock() {
+                            callback(data, info);
+                        }
+                    },
+                    errors,
+                    None,
+                );
+            }
+            let build = match rung.format {
+                SampleFormat::I16 => converted_input::<i16, F>,
+                SampleFormat::I32 => converted_input::<i32, F>,
+                _ => converted_input::<f32, F>,
+            };
+            build(&device, rung, channels, callback, beat, errors)
+        };
+        let (build, device_rate) = (Box::new(build), Box::new(device_rate));
+        let first = Rung {
+            config: config.clone(),
+            format,
+        };
+        Restartable::new(label, first, &supported, build, device_rate, heartbeat)
+    }
+
+    /// Builds an output stream of `config` in samples of `format`, calling `callback`, sending
+    /// its errors to `events`. Samples converted to 16 bits are dithered by `dither`.
+    pub fn output<F>(
+        label: &'static str,
+        device: &cpal::Device,
+        config: &StreamConfig,
+        format: SampleFormat,
+        dither: DitherMode,
+        callback: F,
+        events: Sender<EngineEvent>,
+    ) -> Result<Self, EngineError>
+    where
+        F: FnMut(&mut [f32], &cpal::OutputCallbackInfo) + Send + 'static,
+    {
+        let callback = Arc::new(Mutex::new(callback));
+        let heartbeat = Arc::new(Heartbeat::default());
+        let supported: Vec<_> = device
+            .supported_output_configs()
+            .map(|x| x.collect())
+            .unwrap_or_default();
+        let default = device.clone();
+        let device_rate = move || {
+            default
+                .default_output_config()
+                .ok()
+                .map(|x| x.sample_rate().0)
+        };
+        let (device, channels, beat) = (device.clone(), config.channels, heartbeat.clone());
+        let build = move |rung: &Rung| {
+            let callback = callback.clone();
+            let (beat, errors) = (beat.clone(), events::error_callback(label, events.clone()));
+            if rung.format == SampleFormat::F32 && rung.config.channels == channels {
+                return device.build_output_stream(
+                    &rung.config,
+                    move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                        beat.beat(data.len() / channels as usize);
+                        match callback.try_lock() {
+                            Ok(mut callback) => callback(data, info),
+                            Err(_) => data.fill(0.0),
+                        }
+                    },
+                    errors,
+                    None,
+                );
+            }
+            match rung.format {
+                SampleFormat::I16 => {
+                    let mut dither = Dither::new(dither, rung.config.channels as usize);
+                    let write = move |x: &[f32], y: &mut [i16]| dither.convert(x, y);
+                    converted_output(&device, rung, channels, callback, beat, errors, write)
+                }
+                SampleFormat::I32 => converted_output(
+                    &device,
+                    rung,
+                    channels,
+                    callback,
+                    beat,
+                    errors,
+                    convert::<i32>,
+                ),
+                _ => converted_output(
+                    &device,
+                    rung,
+                    channels,
+                    callback,
+                    beat,
+                    errors,
+                    convert::<f32>,
+                ),
+            }
+        };
+        let (build, device_rate) = (Box::new(build), Box::new(device_rate));
+        let first = Rung {
+            config: config.clone(),
+            format,
+        };
+        Restartable::new(label, first, &supported, build, device_rate, heartbeat)
+    }
+
+    /// Builds the stream with `first`, or the first of its fallbacks among the `supported`
+    /// ranges that builds. A device gone leaves the stream closed, for the recovery to build
+    /// once it's back.
+    fn new(
+        label: &'static str,
+        first: Rung,
+        supported: &[cpal::SupportedStreamConfigRange],
+        mut build: Build,
+        device_rate: DeviceRate,
+        heartbeat: Arc<Heartbeat>,
+    ) -> Result<Self, EngineError> {
+        let period = period(&first.config);
+        let ladder = config::fallback_ladder(&first, supported);
+        let (stream, rung) = match config::build_with_fallback(label, &ladder, &mut build) {
+            Ok((stream, rung)) => {
+                let adaptations = adaptations(label, &first, &rung);
+                if !adaptations.is_empty() {
+                    let adaptations: Vec<String> =
+                        adaptations.iter().map(|x| x.to_string()).collect();
+                    tracing::warn!("the {} is {}", label, adaptations.join(", "));
+                }
+                (Some(stream), rung)
+            }
+            Err(source) if events::is_config_failure(&source) => {
+                return Err(EngineError::StreamBuild {
+                    stream: label,
+                    source,
+                })
+            }
+            Err(cpal::BuildStreamError::StreamIdOverflow) => {
+                return Err(EngineError::StreamBuild {
+                    stream: label,
+                    source: cpal::BuildStreamError::StreamIdOverflow,
+                })
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "the {} lost its device while building ({}), waiting for it to come back",
+                    label,
+                    err
+                );
+                (None, first)
+            }
+        };
+        let now = Instant::now();
+        Ok(Restartable {
+            label,
+            stream,
+            build,
+            rate: StreamRate::new(rung.config.sample_rate.0),
+            rate_detector: RateDetector::new(rung.config.sample_rate.0, period, now),
+            rung,
+            heartbeat,
+            detector: StallDetector::new(period, now),
+            device_rate,
+            pending_rate: None,
+        })
+    }
+
+    /// Makes the stream set `rate`, e.g. one its callback already follows, rather than its own.
+    pub fn sharing_rate(mut self, rate: Arc<StreamRate>) -> Self {
+        rate.set(self.rung.config.sample_rate.0);
+        self.rate = rate;
+        self
+    }
+
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+
+    /// The rate the stream runs at, which changes when it's built again at another one.
+    pub fn rate(&self) -> &Arc<StreamRate> {
+        &self.rate
+    }
+
+    pub fn play(&mut self) -> Result<(), EngineError> {
+        let now = Instant::now();
+        self.detector.rearm(now);
+        self.rate_detector
+            .rearm(self.rung.config.sample_rate.0, now);
+        match &self.stream {
+            Some(stream) => stream.play().map_err(|source| EngineError::StreamPlay {
+                stream: self.label,
+                source,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Closes the stream, keeping what builds it again.
+    fn close(&mut self) {
+        self.stream = None;
+    }
+
+    /// Builds the stream again, and plays it: at the rate it was found to call back at, if it
+    /// was, or else at its own, or at the device's if the device no longer takes that one.
+    fn reopen(&mut self) -> Result<(), EngineError> {
+        if let Some(rate) = self.pending_rate.take() {
+            self.set_rate(rate);
+        }
+        let stream = match (self.build)(&self.rung) {
+            Err(err) if events::is_config_failure(&err) => {
+                match (self.device_rate)().filter(|x| *x != self.rung.config.sample_rate.0) {
+                    Some(rate) => {
+                        tracing::info!(
+                            stream = self.label,
+                            from = self.rung.config.sample_rate.0,
+                            to = rate,
+                            "renegotiating the sample rate with the device"
+                        );
+                        self.set_rate(rate);
+                        (self.build)(&self.rung)
+                    }
+                    None => Err(err),
+                }
+            }
+            result => result,
+        }
+        .map_err(|source| EngineError::StreamBuild {
+            stream: self.label,
+            source,
+        })?;
+        self.stream = Some(stream);
+        self.play()
+    }
+
+    /// Builds the stream at `rate` from now on. Only while it's closed, so that no callback
+    /// runs with the wrong one.
+    fn set_rate(&mut self, rate: u32) {
+        self.rung.config.sample_rate = SampleRate(rate);
+        self.rate.set(rate);
+    }
+}
+
+/// What the stream `label` of rung `rung` converts, to and from the engine's `first`.
+fn adaptations(label: &'static str, first: &Rung, rung: &Rung) -> Vec<Adaptation> {
+    let mut adaptations = Vec::new();
+    if rung.format != first.format {
+        adaptations.push(Adaptation::ConvertFormat {
+            stream: label,
+            format: rung.format,
+        });
+    }
+    let (engine, device) = (first.config.channels, rung.config.channels);
+    if engine != device {
+        adaptations.push(match label.contains("input") {
+            true => Adaptation::MapChannels {
+                from: device,
+                to: engine,
+            },
+            false => Adaptation::MapChannels {
+                from: engine,
+                to: device,
+            },
+        });
+    }
+    adaptations
+}
+
+/// Samples of a block of `config`, of a fixed buffer size, for the buffers of the conversions
+/// to start at.
+fn block_samples(config: &StreamConfig, channels: u16) -> usize {
+    let frames = match config.buffer_size {
+        BufferSize::Fixed(frames) => frames as usize,
+        BufferSize::Default => 0,
+    };
+    frames * config.channels.max(channels) as usize
+}
+
+/// Builds an input stream of `rung` in samples of `T`, handing `callback` blocks in f32 of
+/// `channels` channels.
+fn converted_input<T, F>(
+    device: &cpal::Device,
+    rung: &Rung,
+    channels: u16,
+    callback: Arc<Mutex<F>>,
+    beat: Arc<Heartbeat>,
+    errors: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + Send + 'static,
+    f32: FromSample<T>,
+    F: FnMut(&[f32], &cpal::InputCallbackInfo) + Send + 'static,
+{
+    let adapter = ChannelAdapter::new(rung.config.channels as usize, channels as usize);
+    let device_channels = rung.config.channels as usize;
+    let capacity = block_samples(&rung.config, channels);
+    let (mut samples, mut mapped) = (Vec::with_capacity(capacity), Vec::with_capacity(capacity));
+    device.build_input_stream(
+        &rung.config,
+        move |data: &[T], info: &cpal::InputCallbackInfo| {
+            beat.beat(data.len() / device_channels);
+            samples.clear();
+            samples.extend(data.iter().map(|x| x.to_sample::<f32>()));
+            let data = match adapter.is_identity() {
+                true => &samples,
+                false => {
+                    mapped.clear();
+                    adapter.process(&samples, &mut mapped);
+                    &mapped
+                }
+            };
+            if let Ok(mut callback) = callback.try_lock() {
+                callback(data, info);
+            }
+        },
+        errors,
+        None,
+    )
+}
+
+/// Builds an output stream of `rung` in samples of `T`, filled by `callback` with blocks in
+/// f32 of `channels` channels, which `write` converts to the device's.
+fn converted_output<T, F>(
+    device: &cpal::Device,
+    rung: &Rung,
+    channels: u16,
+    callback: Arc<Mutex<F>>,
+    beat: Arc<Heartbeat>,
+    errors: impl FnMut(cpal::StreamError) + Send + 'static,
+    mut write: impl FnMut(&[f32], &mut [T]) + Send + 'static,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + Send + 'static,
+    F: FnMut(&mut [f32], &cpal::OutputCallbackInfo) + Send + 'static,
+{
+    let adapter = ChannelAdapter::new(channels as usize, rung.config.channels as usize);
+    let device_channels = rung.config.channels as usize;
+    let capacity = block_samples(&rung.config, channels);
+    let (mut samples, mut mapped) = (Vec::with_capacity(capacity), Vec::with_capacity(capacity));
+    device.build_output_stream(
+        &rung.config,
+        move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+            beat.beat(data.len() / device_channels);
+            samples.clear();
+            samples.resize(data.len() / device_channels * channels as usize, 0.0);
+            if let Ok(mut callback) = callback.try_lock() {
+                callback(&mut samples, info);
+            }
+            let samples = match adapter.is_identity() {
+                true => &samples,
+                false => {
+                    mapped.clear();
+                    adapter.process(&samples, &mut mapped);
+                    &mapped
+                }
+            };
+            write(samples, data);
+        },
+        errors,
+        None,
+    )
+}
+
+/// Converts f32 samples to `T` as they are, over the length of the shorter slice.
+fn convert<T: SizedSample + FromSample<f32>>(source: &[f32], data: &mut [T]) {
+    for (y, x) in data.iter_mut().zip(source) {
+        *y = T::from_sample(*x);
+    }
+}
+
+/// Restarts the streams when one of them stalls or loses its device.
+pub struct Watchdog {
+    retries: usize,
+    restarts: usize,
+}
+
+impl Watchdog {
+    /// Restarts the streams up to `retries` times.
+    pub fn new(retries: usize) -> Self {
+        Watchdog {
+            retries,
+            restarts: 0,
+        }
+    }
+
+    /// Restarts so far.
+    pub fn restarts(&self) -> usize {
+        self.restarts
+    }
+
+    /// Checks `streams`, the inputs first, restarting all of them if one stalled or calls back
+    /// at another rate than its own.
+    pub fn poll(&mut self, streams: &mut [&mut Restartable]) -> Result<(), EngineError> {
+        let now = Instant::now();
+        let mut reason = None;
+        for stream in streams.iter_mut() {
+            let count = stream.heartbeat.count();
+            if let Some(silent) = stream.detector.check(count, now) {
+                reason.get_or_insert_with(|| {
+                    format!(
+                        "the {} stalled, without a callback for {:.1} seconds",
+                        stream.label,
+                        silent.as_secs_f32()
+                    )
+                });
+            }
+            let frames = stream.heartbeat.frames();
+            if let Some(rate) = stream.rate_detector.check(frames, now) {
+                stream.pending_rate = Some(rate);
+                reason.get_or_insert_with(|| {
+                    format!(
+                        "the {} calls back at {} Hz instead of {}",
+                        stream.label, rate, stream.rung.config.sample_rate.0
+                    )
+                });
+            }
+        }
+        match reason {
+            Some(reason) => self.restart(streams, &reason),
+            None => Ok(()),
+        }
+    }
+
+    /// Closes `streams`, the inputs first, and builds them again, for `reason`. Fails once out
+    /// of retries.
+    pub fn restart(
+        &mut self,
+        streams: &mut [&mut Restartable],
+        reason: &str,
+    ) -> Result<(), EngineError> {
+        if self.restarts == self.retries {
+            return Err(EngineError::StreamFailed(format!(
+                "{}, after {} restarts",
+                reason, self.restarts
+            )));
+        }
+        self.restarts += 1;
+        let _span = tracing::warn_span!("recovery", restart = self.restarts).entered();
+        tracing::warn!(reason, retries = self.retries, "restarting the streams");
+        for stream in streams.iter_mut() {
+            stream.close();
+        }
+        let now = Instant::now();
+        for stream in streams.iter_mut() {
+            // A device that isn't back yet stays closed, until the stall or the error of the
+            // next try.
+            if let Err(err) = stream.reopen() {
+                let err = anyhow::Error::from(err);
+                tracing::warn!(stream = stream.label, "failed to restart: {:#}", err);
+                stream.detector.rearm(now);
+                stream
+                    .rate_detector
+                    .rearm(stream.rung.config.sample_rate.0, now);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(channels: u16, buffer_size: BufferSize) -> StreamConfig {
+        StreamConfig {
+            channels,
+            sample_rate: SampleRate(48_000),
+            buffer_size,
+        }
+    }
+
+    #[test]
+    fn heartbeats_count_callbacks_and_frames() {
+        let heartbeat = Heartbeat::default();
+        heartbeat.beat(256);
+        heartbeat.beat(128);
+        assert_eq!(heartbeat.count(), 2);
+        assert_eq!(heartbeat.frames(), 384);
+    }
+
+    #[test]
+    fn periods_follow_the_buffer_size() {
+        let fixed = period(&config(2, BufferSize::Fixed(480)));
+        assert_eq!(fixed, Duration::from_millis(10));
+        assert_eq!(period(&config(2, BufferSize::Default)), DEFAULT_PERIOD);
+    }
+
+    #[test]
+    fn timeouts_span_the_tolerance_but_never_less_than_the_minimum() {
+        let now = Instant::now();
+        let detector = StallDetector::new(Duration::from_millis(100), now);
+        assert_eq!(detector.timeout(), Duration::from_millis(800));
+        let detector = StallDetector::new(Duration::from_millis(1), now);
+        assert_eq!(detector.timeout(), MIN_TIMEOUT);
+    }
+
+    #[test]
+    fn streams_stall_once_silent_past_the_timeout() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(Duration::from_millis(100), start);
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(detector.check(1, at(100)), None);
+        // Checked often enough that no pause is assumed.
+        for ms in (200..=900).step_by(100) {
+            assert_eq!(detector.check(1, at(ms)), None);
+        }
+        assert_eq!(
+            detector.check(1, at(1000)),
+            Some(Duration::from_millis(900))
+        );
+        assert_eq!(detector.check(2, at(1100)), None);
+    }
+
+    #[test]
+    fn streams_just_built_get_the_resume_timeout() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(Duration::from_millis(100), start);
+        let at = |ms| start + Duration::from_millis(ms);
+        for ms in (500..=5000).step_by(500) {
+            assert_eq!(detector.check(0, at(ms)), None);
+        }
+        assert_eq!(
+            detector.check(0, at(5500)),
+            Some(Duration::from_millis(5500))
+        );
+
+        detector.rearm(at(6000));
+        assert_eq!(detector.check(0, at(6500)), None);
+        assert_eq!(detector.check(1, at(7000)), None);
+        assert!(detector.check(1, at(7500)).is_none());
+        assert!(detector.check(1, at(7900)).is_some());
+    }
+
+    #[test]
+    fn checks_held_up_past_a_stall_resume_instead() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(Duration::from_millis(100), start);
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(detector.check(1, at(100)), None);
+        // The whole process was paused for ten seconds.
+        assert_eq!(detector.check(1, at(10_100)), None);
+        for ms in (10_600..=15_100).step_by(500) {
+            assert_eq!(detector.check(1, at(ms)), None);
+        }
+        assert_eq!(
+            detector.check(1, at(15_600)),
+            Some(Duration::from_millis(5500))
+        );
+    }
+
+    #[test]
+    fn adaptations_name_the_conversions_of_a_fallback() {
+        let rung = |channels, format| Rung {
+            config: config(channels, BufferSize::Default),
+            format,
+        };
+        let first = rung(2, SampleFormat::F32);
+        assert!(adaptations("output stream", &first, &first).is_empty());
+        assert_eq!(
+            adaptations("output stream", &first, &rung(4, SampleFormat::I16)),
+            [
+                Adaptation::ConvertFormat {
+                    stream: "output stream",
+                    format: SampleFormat::I16
+                },
+                Adaptation::MapChannels { from: 2, to: 4 },
+            ]
+        );
+        assert_eq!(
+            adaptations("input stream", &first, &rung(1, SampleFormat::F32)),
+            [Adaptation::MapChannels { from: 1, to: 2 }]
+        );
+    }
+
+    #[test]
+    fn conversion_buffers_fit_a_block_of_the_wider_side() {
+        assert_eq!(block_samples(&config(2, BufferSize::Fixed(256)), 6), 1536);
+        assert_eq!(block_samples(&config(8, BufferSize::Fixed(256)), 2), 2048);
+        assert_eq!(block_samples(&config(2, BufferSize::Default), 2), 0);
+    }
+}
@@ -0,0 +1,414 @@
+//! Minimal WAV file reading and writing.
+//!
+//! Files are written with 32-bit float samples. Reading also accepts 16, 24 and 32-bit integer
+//! samples, and converts everything to f32.
+//!
+//! The sizes in the header are written as zero first and patched when the writer is finished, so
+//! a file whose writer was never finished is still readable by most tools up to the last flush.
+//! Cue points, e.g. markers of a recording, go in a cue chunk after the samples, written when the
+//! writer is finished too.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const FORMAT_PCM: u16 = 1;
+/// WAVE_FORMAT_IEEE_FLOAT.
+const FORMAT_FLOAT: u16 = 3;
+/// WAVE_FORMAT_EXTENSIBLE, whose actual format is in the first bytes of the sub-format GUID.
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+const BITS_PER_SAMPLE: u16 = 32;
+/// Bytes before the first sample: RIFF, fmt, fact and data chunk headers.
+pub const HEADER_SIZE: u32 = 12 + 26 + 12 + 8;
+/// Bytes of a cue point in the cue chunk.
+const CUE_POINT_SIZE: u32 = 24;
+
+/// Writes interleaved f32 samples to a WAV file.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    channels: u16,
+    samples: u32,
+    cues: Vec<u32>,
+}
+
+impl WavWriter {
+    /// Creates the file at `path`, replacing any existing one.
+    pub fn create(path: &Path, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        Self::start(File::create(path)?, channels, sample_rate)
+    }
+
+    /// Creates the file at `path`, failing with [`io::ErrorKind::AlreadyExists`] if there's one.
+    pub fn create_new(path: &Path, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        Self::start(File::create_new(path)?, channels, sample_rate)
+    }
+
+    /// Writes the header to the new `file`.
+    fn start(file: File, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(file);
+        let block_align = channels * BITS_PER_SAMPLE / 8;
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_all(&18u32.to_le_bytes())?;
+        file.write_all(&FORMAT_FLOAT.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        file.write_all(&0u16.to_le_bytes())?;
+
+        // Non-PCM formats must say how many frames there are.
+        file.write_all(b"fact")?;
+        file.write_all(&4u32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter {
+            file,
+            channels,
+            samples: 0,
+            cues: Vec::new(),
+        })
+    }
+
+    /// Appends interleaved samples, which should be whole frames.
+    pub fn write(&mut self, data: &[f32]) -> io::Result<()> {
+        for sample in data {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += data.len() as u32;
+        Ok(())
+    }
+
+    /// Number of frames written so far.
+    pub fn frames(&self) -> u32 {
+        self.samples / self.channels as u32
+    }
+
+    /// Adds a cue point at `frame`, counted from the first.
+    pub fn cue(&mut self, frame: u32) {
+        self.cues.push(frame);
+    }
+
+    /// Size of the file so far, in bytes, without the cue chunk.
+    pub fn size(&self) -> u64 {
+        HEADER_SIZE as u64 + self.samples as u64 * 4
+    }
+
+    /// Writes the cue chunk, patches the sizes in the header and flushes the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        let data_size = self.samples * 4;
+        let mut cue_size = 0;
+        if !self.cues.is_empty() {
+            cue_size = 8 + 4 + self.cues.len() as u32 * CUE_POINT_SIZE;
+            self.file.write_all(b"cue ")?;
+            self.file.write_all(&(cue_size - 8).to_le_bytes())?;
+            self.file
+                .write_all(&(self.cues.len() as u32).to_le_bytes())?;
+            for (index, frame) in self.cues.iter().enumerate() {
+                // ID, position, the chunk of the samples, two offsets into it unused for a data
+                // chunk, and the frame.
+                self.file.write_all(&(index as u32 + 1).to_le_bytes())?;
+                self.file.write_all(&frame.to_le_bytes())?;
+                self.file.write_all(b"data")?;
+                self.file.write_all(&[0; 8])?;
+                self.file.write_all(&frame.to_le_bytes())?;
+            }
+        }
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + data_size + cue_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(46))?;
+        self.file.write_all(&self.frames().to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+/// Samples read or rewritten at once, at most, by [`for_each_block`] and [`scale`].
+const BLOCK_SAMPLES: usize = 16_384;
+
+/// Opens a file [`WavWriter`] wrote, at its first sample, with the samples of its blocks of
+/// whole frames and its number of samples.
+fn open_written(path: &Path, write: bool) -> io::Result<(File, usize, u64)> {
+    let mut file = File::options().read(true).write(write).open(path)?;
+    let mut header = [0; HEADER_SIZE as usize];
+    file.read_exact(&mut header)?;
+    let tag = u16::from_le_bytes([header[20], header[21]]);
+    let channels = u16::from_le_bytes([header[22], header[23]]) as usize;
+    if &header[0..4] != b"RIFF"
+        || tag != FORMAT_FLOAT
+        || channels == 0
+        || &header[50..54] != b"data"
+    {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "not a WAV file of this writer",
+        ));
+    }
+    let size = u32::from_le_bytes(header[54..58].try_into().unwrap());
+    let block = (BLOCK_SAMPLES / channels).max(1) * channels;
+    Ok((file, block, size as u64 / 4))
+}
+
+fn decode_floats(bytes: &[u8], samples: &mut Vec<f32>) {
+    samples.clear();
+    samples.extend(
+        bytes
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]])),
+    );
+}
+
+/// Reads a file [`WavWriter`] wrote in blocks of whole frames, passing each one to `f`.
+pub fn for_each_block(path: &Path, mut f: impl FnMut(&[f32]) -> io::Result<()>) -> io::Result<()> {
+    let (file, block, samples) = open_written(path, false)?;
+    let mut file = BufReader::new(file);
+    let (mut bytes, mut decoded) = (vec![0; block * 4], Vec::with_capacity(block));
+    let mut remaining = samples;
+    while remaining > 0 {
+        let count = remaining.min(block as u64) as usize;
+        file.read_exact(&mut bytes[..count * 4])?;
+        decode_floats(&bytes[..count * 4], &mut decoded);
+        f(&decoded)?;
+        remaining -= count as u64;
+    }
+    Ok(())
+}
+
+/// Multiplies the samples of a file [`WavWriter`] wrote by `gain`, in place.
+pub fn scale(path: &Path, gain: f32) -> io::Result<()> {
+    let (mut file, block, samples) = open_written(path, true)?;
+    let (mut bytes, mut decoded) = (vec![0; block * 4], Vec::with_capacity(block));
+    let mut position = HEADER_SIZE as u64;
+    let mut remaining = samples;
+    while remaining > 0 {
+        let count = remaining.min(block as u64) as usize;
+        let bytes = &mut bytes[..count * 4];
+        file.read_exact(bytes)?;
+        decode_floats(bytes, &mut decoded);
+        for (x, out) in decoded.iter().zip(bytes.chunks_exact_mut(4)) {
+            out.copy_from_slice(&(x * gain).to_le_bytes());
+        }
+        file.seek(SeekFrom::Start(position))?;
+        file.write_all(bytes)?;
+        position += bytes.len() as u64;
+        remaining -= count as u64;
+    }
+    file.flush()
+}
+
+/// The contents of a WAV file.
+pub struct WavData {
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// Interleaved samples.
+    pub samples: Vec<f32>,
+}
+
+/// Reads the WAV file at `path`.
+pub fn read(path: &Path) -> io::Result<WavData> {
+    let bytes = std::fs::read(path)?;
+    parse(&bytes)
+}
+
+/// Parses the bytes of a WAV file, skipping chunks other than the format and data ones.
+pub fn parse(bytes: &[u8]) -> io::Result<WavData> {
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_string());
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+    let u16_at = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+
+    let mut format = None;
+    let mut position = 12;
+    while position + 8 <= bytes.len() {
+        let id = &bytes[position..position + 4];
+        let size = u32_at(position + 4) as usize;
+        let body = position + 8;
+        let end = (body + size).min(bytes.len());
+        match id {
+            b"fmt " if size >= 16 && end - body >= 16 => {
+                let mut tag = u16_at(body);
+                if tag == FORMAT_EXTENSIBLE && size >= 26 {
+                    tag = u16_at(body + 24);
+                }
+                format = Some((tag, u16_at(body + 2), u32_at(body + 4), u16_at(body + 14)));
+            }
+            b"data" => {
+                let (tag, channels, sample_rate, bits) =
+                    format.ok_or_else(|| invalid("data chunk before the format chunk"))?;
+                if channels == 0 {
+                    return Err(invalid("no channels"));
+                }
+                let samples = decode(&bytes[body..end], tag, bits)
+                    .ok_or_else(|| invalid("unsupported sample format"))?;
+                return Ok(WavData {
+                    channels,
+                    sample_rate,
+                    samples,
+                });
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even size.
+        position = body + size + size % 2;
+    }
+    Err(invalid("no data chunk"))
+}
+
+/// The frames of the cue points of the bytes of a WAV file, in the order of the cue chunk, or
+/// none without one.
+pub fn parse_cues(bytes: &[u8]) -> io::Result<Vec<u32>> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a WAV file"));
+    }
+    let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    let mut position = 12;
+    while position + 8 <= bytes.len() {
+        let size = u32_at(position + 4) as usize;
+        let body = position + 8;
+        if &bytes[position..position + 4] == b"cue " && size >= 4 && body + size <= bytes.len() {
+            let count = (u32_at(body) as usize).min((size - 4) / CUE_POINT_SIZE as usize);
+            let points = (0..count).map(|index| body + 4 + index * CUE_POINT_SIZE as usize);
+            return Ok(points.map(|point| u32_at(point + 20)).collect());
+        }
+        // Chunks are padded to an even size.
+        position = body + size + size % 2;
+    }
+    Ok(Vec::new())
+}
+
+fn decode(data: &[u8], tag: u16, bits: u16) -> Option<Vec<f32>> {
+    let samples = match (tag, bits) {
+        (FORMAT_FLOAT, 32) => data
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect(),
+        (FORMAT_PCM, 16) => data
+            .chunks_exact(2)
+            .map(|x| i16::from_le_bytes([x[0], x[1]]) as f32 / 32_768.0)
+            .collect(),
+        (FORMAT_PCM, 24) => data
+            .chunks_exact(3)
+            .map(|x| i32::from_le_bytes([0, x[0], x[1], x[2]]) as f32 / 2_147_483_648.0)
+            .collect(),
+        (FORMAT_PCM, 32) => data
+            .chunks_exact(4)
+            .map(|x| i32::from_le_bytes([x[0], x[1], x[2], x[3]]) as f32 / 2_147_483_648.0)
+            .collect(),
+        _ => return None,
+    };
+    Some(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("wav-{}-{}.wav", name, std::process::id()))
+    }
+
+    #[test]
+    fn written_files_have_the_sizes_in_their_header() {
+        let path = temp_path("header");
+        let mut writer = WavWriter::create(&path, 2, 44_100).unwrap();
+        writer.write(&[0.5, -0.5, 0.25, -0.25, 1.0, -1.0]).unwrap();
+        assert_eq!(
+            (writer.frames(), writer.size()),
+            (3, HEADER_SIZE as u64 + 24)
+        );
+        writer.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(bytes.len(), HEADER_SIZE as usize + 24);
+        assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+        assert_eq!(u32_at(24), 44_100);
+        assert_eq!(u32_at(28), 44_100 * 8);
+        assert_eq!(u32_at(46), 3);
+        assert_eq!(u32_at(HEADER_SIZE as usize - 4), 24);
+    }
+
+    #[test]
+    fn written_files_read_back_bit_exact() {
+        let path = temp_path("round-trip");
+        let samples = [0.1, -0.7, f32::MIN_POSITIVE, 1.0];
+        let mut writer = WavWriter::create(&path, 1, 48_000).unwrap();
+        writer.write(&samples).unwrap();
+        writer.finish().unwrap();
+        let data = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((data.channels, data.sample_rate), (1, 48_000));
+        assert_eq!(data.samples, samples);
+    }
+
+    #[test]
+    fn written_files_are_read_and_scaled_in_blocks() {
+        let path = temp_path("scale");
+        let samples: Vec<f32> = (0..40_000).map(|x| x as f32).collect();
+        let mut writer = WavWriter::create(&path, 3, 48_000).unwrap();
+        writer.write(&samples[..39_999]).unwrap();
+        writer.finish().unwrap();
+        scale(&path, 0.5).unwrap();
+        let mut blocks = Vec::new();
+        let mut read = Vec::new();
+        for_each_block(&path, |block| {
+            blocks.push(block.len());
+            read.extend_from_slice(block);
+            Ok(())
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(blocks, [16_383, 16_383, 7_233]);
+        let halved: Vec<f32> = samples[..39_999].iter().map(|x| x * 0.5).collect();
+        assert_eq!(read, halved);
+    }
+
+    #[test]
+    fn other_files_are_not_scaled() {
+        let path = temp_path("foreign");
+        std::fs::write(&path, [0; HEADER_SIZE as usize]).unwrap();
+        let error = scale(&path, 0.5).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.to_string(), "not a WAV file of this writer");
+    }
+
+    #[test]
+    fn cue_points_follow_the_samples() {
+        let path = temp_path("cues");
+        let mut writer = WavWriter::create(&path, 1, 48_000).unwrap();
+        writer.write(&[0.5, -0.5, 0.25]).unwrap();
+        writer.cue(2);
+        writer.cue(0);
+        writer.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+        assert_eq!(parse_cues(&bytes).unwrap(), [2, 0]);
+        assert_eq!(parse(&bytes).unwrap().samples, [0.5, -0.5, 0.25]);
+    }
+
+    #[test]
+    fn files_without_a_cue_chunk_have_no_cue_points() {
+        let path = temp_path("no-cues");
+        let mut writer = WavWriter::create(&path, 1, 48_000).unwrap();
+        writer.write(&[0.5]).unwrap();
+        writer.finish().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(parse_cues(&bytes).unwrap().is_empty());
+        let err = parse_cues(b"RIFX").unwrap_err();
+        assert_eq!(err.to_string(), "not a WAV file");
+    }
+}
@@ -0,0 +1,111 @@
+//! Hosts the gain plugin of `tests/fixtures/clap-gain` the way `--plugin` does.
+
+#![cfg(feature = "clap-plugins")]
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::OnceLock;
+
+use rust_dsp_experiments::effects::{ClapPlugin, Effect, EffectChain, PluginSpec};
+
+const CHANNELS: usize = 2;
+const SAMPLE_RATE: u32 = 48_000;
+
+/// Builds the plugin, in the profile of the tests, and returns the path to its library.
+fn fixture() -> PathBuf {
+    static PATH: OnceLock<PathBuf> = OnceLock::new();
+    PATH.get_or_init(|| {
+        // The tests run from `target/<profile>/deps`, next to where the library goes.
+        let exe = std::env::current_exe().unwrap();
+        let directory = exe.parent().unwrap().parent().unwrap().to_path_buf();
+        let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+        let mut build = Command::new(cargo);
+        build.args(["build", "--quiet", "--package", "clap-gain"]);
+        if directory.ends_with("release") {
+            build.arg("--release");
+        }
+        assert!(
+            build.status().unwrap().success(),
+            "failed to build clap-gain"
+        );
+        directory.join(format!(
+            "{}clap_gain{}",
+            std::env::consts::DLL_PREFIX,
+            std::env::consts::DLL_SUFFIX
+        ))
+    })
+    .clone()
+}
+
+fn load(channels: usize, sample_rate: u32) -> anyhow::Result<ClapPlugin> {
+    let spec: PluginSpec = fixture().to_string_lossy().parse().unwrap();
+    ClapPlugin::load(&spec, channels, sample_rate)
+}
+
+/// Runs a block of a constant through a chain of `plugin`, returning the block.
+fn process(plugin: ClapPlugin) -> Vec<f32> {
+    let mut chain: EffectChain = EffectChain::new(CHANNELS);
+    chain.push(plugin);
+    let mut data = vec![0.5; 256 * CHANNELS];
+    chain.process(&mut data);
+    data
+}
+
+#[test]
+fn the_plugin_describes_itself() {
+    let plugin = load(CHANNELS, SAMPLE_RATE).unwrap();
+    assert_eq!(plugin.plugin_name(), "Gain");
+    assert_eq!(Effect::<f32>::params(&plugin), ["gain"]);
+    assert_eq!(Effect::<f32>::param(&plugin, 0), 0.0);
+    assert_eq!(plugin.latency(), 48);
+}
+
+#[test]
+fn the_plugin_applies_its_gain() {
+    let plugin = load(CHANNELS, SAMPLE_RATE).unwrap();
+    assert!(process(plugin).iter().all(|x| *x == 0.5));
+
+    let mut plugin = load(CHANNELS, SAMPLE_RATE).unwrap();
+    Effect::<f32>::set_param(&mut plugin, 0, -6.0);
+    let expected = 0.5 * 10f32.powf(-6.0 / 20.0);
+    assert!(process(plugin).iter().all(|x| (x - expected).abs() < 1e-6));
+}
+
+#[test]
+fn parameters_are_clamped_to_the_range_of_the_plugin() {
+    let mut plugin = load(CHANNELS, SAMPLE_RATE).unwrap();
+    Effect::<f32>::set_param(&mut plugin, 0, 100.0);
+    assert_eq!(Effect::<f32>::param(&plugin, 0), 24.0);
+}
+
+#[test]
+fn the_plugin_is_activated_again_at_a_new_rate() {
+    let mut plugin = load(CHANNELS, SAMPLE_RATE).unwrap();
+    Effect::<f32>::set_sample_rate(&mut plugin, 96_000.0);
+    assert_eq!(plugin.latency(), 96);
+    assert_eq!(Effect::<f32>::latency_frames(&plugin), 96);
+    Effect::<f32>::set_param(&mut plugin, 0, 6.0);
+    let expected = 0.5 * 10f32.powf(6.0 / 20.0);
+    assert!(process(plugin).iter().all(|x| (x - expected).abs() < 1e-6));
+}
+
+#[test]
+fn plugins_that_cannot_be_hosted_fail_cleanly() {
+    let err = load(4, SAMPLE_RATE).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "the plugin \"Gain\" takes 2 channels and gives 2, but the output stream has 4"
+    );
+
+    let spec: PluginSpec = format!("{}:1", fixture().display()).parse().unwrap();
+    let err = ClapPlugin::load(&spec, CHANNELS, SAMPLE_RATE)
+        .err()
+        .unwrap();
+    assert!(err.to_string().ends_with("has no plugin 1, only 1 of them"));
+
+    let spec: PluginSpec = "/nonexistent/gain.clap".parse().unwrap();
+    let err = ClapPlugin::load(&spec, CHANNELS, SAMPLE_RATE)
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "failed to load \"/nonexistent/gain.clap\"");
+}
@@ -0,0 +1,320 @@
+//! A CLAP plugin applying a gain to a stereo signal, for the tests of the host.
+//!
+//! Its one parameter is "Gain", in decibels from -60 to 24, 0 by default, set by parameter
+//! events. It reports a latency of a millisecond at the rate it's activated at, without delaying
+//! anything, so that the host's reading of it can be checked at every rate.
+
+use std::ffi::{c_char, c_void, CStr};
+use std::ptr;
+
+use clap_sys::entry::clap_plugin_entry;
+use clap_sys::events::{
+    clap_event_param_value, clap_input_events, CLAP_CORE_EVENT_SPACE_ID, CLAP_EVENT_PARAM_VALUE,
+};
+use clap_sys::ext::audio_ports::{
+    clap_audio_port_info, clap_plugin_audio_ports, CLAP_AUDIO_PORT_IS_MAIN, CLAP_EXT_AUDIO_PORTS,
+    CLAP_PORT_STEREO,
+};
+use clap_sys::ext::latency::{clap_plugin_latency, CLAP_EXT_LATENCY};
+use clap_sys::ext::params::{
+    clap_param_info, clap_plugin_params, CLAP_EXT_PARAMS, CLAP_PARAM_IS_AUTOMATABLE,
+};
+use clap_sys::factory::plugin_factory::{clap_plugin_factory, CLAP_PLUGIN_FACTORY_ID};
+use clap_sys::host::clap_host;
+use clap_sys::id::{clap_id, CLAP_INVALID_ID};
+use clap_sys::plugin::{clap_plugin, clap_plugin_descriptor};
+use clap_sys::plugin_features::{CLAP_PLUGIN_FEATURE_AUDIO_EFFECT, CLAP_PLUGIN_FEATURE_STEREO};
+use clap_sys::process::{clap_process, clap_process_status, CLAP_PROCESS_CONTINUE};
+use clap_sys::version::CLAP_VERSION;
+
+const CHANNELS: u32 = 2;
+const GAIN: clap_id = 0;
+const MIN_DB: f64 = -60.0;
+const MAX_DB: f64 = 24.0;
+
+/// The features of the descriptor, a null-terminated list.
+struct Features([*const c_char; 3]);
+
+// Pointers to static strings, never written.
+unsafe impl Sync for Features {}
+
+static FEATURES: Features = Features([
+    CLAP_PLUGIN_FEATURE_AUDIO_EFFECT.as_ptr(),
+    CLAP_PLUGIN_FEATURE_STEREO.as_ptr(),
+    ptr::null(),
+]);
+
+static DESCRIPTOR: clap_plugin_descriptor = clap_plugin_descriptor {
+    clap_version: CLAP_VERSION,
+    id: c"rust-dsp-experiments.gain".as_ptr(),
+    name: c"Gain".as_ptr(),
+    vendor: c"rust-dsp-experiments".as_ptr(),
+    url: c"".as_ptr(),
+    manual_url: c"".as_ptr(),
+    support_url: c"".as_ptr(),
+    version: c"0.1.0".as_ptr(),
+    description: c"A gain, for the tests of the host".as_ptr(),
+    features: &FEATURES.0 as *const _ as *const *const c_char,
+};
+
+/// An instance, whose `plugin` the host gets a pointer to.
+#[repr(C)]
+struct Gain {
+    plugin: clap_plugin,
+    gain_db: f64,
+    sample_rate: f64,
+}
+
+/// The instance of `plugin`.
+unsafe fn instance<'a>(plugin: *const clap_plugin) -> &'a mut Gain {
+    unsafe { &mut *((*plugin).plugin_data as *mut Gain) }
+}
+
+unsafe extern "C" fn init(_: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn destroy(plugin: *const clap_plugin) {
+    drop(unsafe { Box::from_raw((*plugin).plugin_data as *mut Gain) });
+}
+
+unsafe extern "C" fn activate(
+    plugin: *const clap_plugin,
+    sample_rate: f64,
+    _: u32,
+    _: u32,
+) -> bool {
+    unsafe { instance(plugin) }.sample_rate = sample_rate;
+    true
+}
+
+unsafe extern "C" fn deactivate(_: *const clap_plugin) {}
+
+unsafe extern "C" fn start_processing(_: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn stop_processing(_: *const clap_plugin) {}
+
+unsafe extern "C" fn reset(_: *const clap_plugin) {}
+
+unsafe extern "C" fn process(
+    plugin: *const clap_plugin,
+    process: *const clap_process,
+) -> clap_process_status {
+    let gain = unsafe { instance(plugin) };
+    let process = unsafe { &*process };
+    apply_events(gain, unsafe { &*process.in_events });
+    let factor = 10f64.powf(gain.gain_db / 20.0) as f32;
+    let (input, output) = unsafe { (&*process.audio_inputs, &*process.audio_outputs) };
+    let frames = process.frames_count as usize;
+    for channel in 0..CHANNELS as usize {
+        let (x, y) = unsafe {
+            (
+                std::slice::from_raw_parts(*input.data32.add(channel), frames),
+                std::slice::from_raw_parts_mut(*output.data32.add(channel), frames),
+            )
+        };
+        for (y, x) in y.iter_mut().zip(x) {
+            *y = x * factor;
+        }
+    }
+    CLAP_PROCESS_CONTINUE
+}
+
+fn apply_events(gain: &mut Gain, events: &clap_input_events) {
+    let count = events.size.map_or(0, |f| unsafe { f(events) });
+    for index in 0..count {
+        let Some(header) = events
+            .get
+            .and_then(|f| unsafe { f(events, index).as_ref() })
+        else {
+            continue;
+        };
+        if header.space_id == CLAP_CORE_EVENT_SPACE_ID && header.type_ == CLAP_EVENT_PARAM_VALUE {
+            let event = unsafe { &*(header as *const _ as *const clap_event_param_value) };
+            if event.param_id == GAIN {
+                gain.gain_db = event.value.clamp(MIN_DB, MAX_DB);
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn get_extension(_: *const clap_plugin, id: *const c_char) -> *const c_void {
+    let id = unsafe { CStr::from_ptr(id) };
+    if id == CLAP_EXT_AUDIO_PORTS {
+        &AUDIO_PORTS as *const _ as *const c_void
+    } else if id == CLAP_EXT_PARAMS {
+        &PARAMS as *const _ as *const c_void
+    } else if id == CLAP_EXT_LATENCY {
+        &LATENCY as *const _ as *const c_void
+    } else {
+        ptr::null()
+    }
+}
+
+unsafe extern "C" fn on_main_thread(_: *const clap_plugin) {}
+
+static AUDIO_PORTS: clap_plugin_audio_ports = clap_plugin_audio_ports {
+    count: Some(port_count),
+    get: Some(port_info),
+};
+
+unsafe extern "C" fn port_count(_: *const clap_plugin, _: bool) -> u32 {
+    1
+}
+
+unsafe extern "C" fn port_info(
+    _: *const clap_plugin,
+    index: u32,
+    _: bool,
+    info: *mut clap_audio_port_info,
+) -> bool {
+    if index != 0 {
+        return false;
+    }
+    let info = unsafe { &mut *info };
+    info.id = 0;
+    write_name(&mut info.name, c"main");
+    info.flags = CLAP_AUDIO_PORT_IS_MAIN;
+    info.channel_count = CHANNELS;
+    info.port_type = CLAP_PORT_STEREO.as_ptr();
+    info.in_place_pair = CLAP_INVALID_ID;
+    true
+}
+
+static PARAMS: clap_plugin_params = clap_plugin_params {
+    count: Some(param_count),
+    get_info: Some(param_info),
+    get_value: Some(param_value),
+    value_to_text: None,
+    text_to_value: None,
+    flush: Some(flush),
+};
+
+unsafe extern "C" fn param_count(_: *const clap_plugin) -> u32 {
+    1
+}
+
+unsafe extern "C" fn param_info(
+    _: *const clap_plugin,
+    index: u32,
+    info: *mut clap_param_info,
+) -> bool {
+    if index != 0 {
+        return false;
+    }
+    let info = unsafe { &mut *info };
+    info.id = GAIN;
+    info.flags = CLAP_PARAM_IS_AUTOMATABLE;
+    info.cookie = ptr::null_mut();
+    write_name(&mut info.name, c"Gain");
+    write_name(&mut info.module, c"");
+    info.min_value = MIN_DB;
+    info.max_value = MAX_DB;
+    info.default_value = 0.0;
+    true
+}
+
+unsafe extern "C" fn param_value(plugin: *const clap_plugin, id: clap_id, value: *mut f64) -> bool {
+    if id != GAIN {
+        return false;
+    }
+    unsafe { *value = instance(plugin).gain_db };
+    true
+}
+
+unsafe extern "C" fn flush(
+    plugin: *const clap_plugin,
+    events: *const clap_input_events,
+    _: *const clap_sys::events::clap_output_events,
+) {
+    apply_events(unsafe { instance(plugin) }, unsafe { &*events });
+}
+
+static LATENCY: clap_plugin_latency = clap_plugin_latency { get: Some(latency) };
+
+unsafe extern "C" fn latency(plugin: *const clap_plugin) -> u32 {
+    (unsafe { instance(plugin) }.sample_rate / 1_000.0) as u32
+}
+
+/// Copies `name` into a fixed-size, null-terminated field.
+fn write_name(field: &mut [c_char], name: &CStr) {
+    let bytes = name.to_bytes_with_nul();
+    for (x, y) in field.iter_mut().zip(bytes) {
+        *x = *y as c_char;
+    }
+}
+
+static FACTORY: clap_plugin_factory = clap_plugin_factory {
+    get_plugin_count: Some(plugin_count),
+    get_plugin_descriptor: Some(plugin_descriptor),
+    create_plugin: Some(create_plugin),
+};
+
+unsafe extern "C" fn plugin_count(_: *const clap_plugin_factory) -> u32 {
+    1
+}
+
+unsafe extern "C" fn plugin_descriptor(
+    _: *const clap_plugin_factory,
+    index: u32,
+) -> *const clap_plugin_descriptor {
+    match index {
+        0 => &DESCRIPTOR,
+        _ => ptr::null(),
+    }
+}
+
+unsafe extern "C" fn create_plugin(
+    _: *const clap_plugin_factory,
+    _: *const clap_host,
+    id: *const c_char,
+) -> *const clap_plugin {
+    if unsafe { CStr::from_ptr(id) } != unsafe { CStr::from_ptr(DESCRIPTOR.id) } {
+        return ptr::null();
+    }
+    let gain = Box::into_raw(Box::new(Gain {
+        plugin: clap_plugin {
+            desc: &DESCRIPTOR,
+            plugin_data: ptr::null_mut(),
+            init: Some(init),
+            destroy: Some(destroy),
+            activate: Some(activate),
+            deactivate: Some(deactivate),
+            start_processing: Some(start_processing),
+            stop_processing: Some(stop_processing),
+            reset: Some(reset),
+            process: Some(process),
+            get_extension: Some(get_extension),
+            on_main_thread: Some(on_main_thread),
+        },
+        gain_db: 0.0,
+        sample_rate: 0.0,
+    }));
+    unsafe {
+        (*gain).plugin.plugin_data = gain as *mut c_void;
+        &(*gain).plugin
+    }
+}
+
+unsafe extern "C" fn entry_init(_: *const c_char) -> bool {
+    true
+}
+
+unsafe extern "C" fn entry_deinit() {}
+
+unsafe extern "C" fn get_factory(id: *const c_char) -> *const c_void {
+    match unsafe { CStr::from_ptr(id) } == CLAP_PLUGIN_FACTORY_ID {
+        true => &FACTORY as *const _ as *const c_void,
+        false => ptr::null(),
+    }
+}
+
+#[export_name = "clap_entry"]
+pub static CLAP_ENTRY: clap_plugin_entry = clap_plugin_entry {
+    clap_version: CLAP_VERSION,
+    init: Some(entry_init),
+    deinit: Some(entry_deinit),
+    get_factory: Some(get_factory),
+};